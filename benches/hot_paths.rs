@@ -0,0 +1,122 @@
+//! Benchmarks for the requests the server handles most: the access check in front of every camera route, listing
+//! cameras, and cameras uploading snapshots. Each runs against users with more and more cameras, so work that grows
+//! with how many cameras a user has shows up as the numbers spreading apart.
+//!
+//! Runs against the database test_support uses: `cargo bench --features test-support`. The cache TTLs are set to 0 so
+//! every request does the work a cache miss would, and the rate limit and daily quotas are lifted so they don't cut
+//! the runs short. Uploaded snapshots are left in the media store.
+
+use camera_server::test_support::{TestCamera, TestServer, TestUser};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, Rgb, RgbImage};
+use rocket::http::{ContentType, Status};
+use std::env;
+
+/// How many cameras each benchmarked user has.
+const CAMERA_COUNTS: [usize; 3] = [1, 50, 500];
+
+/// A user with `count` cameras of their own.
+fn add_user_with_cameras(server: &TestServer, count: usize) -> (TestUser, Vec<TestCamera>) {
+    let user = server.add_user(&format!("bench-{}", count));
+    let cameras = (0..count)
+        .map(|number| server.add_camera(&user, &format!("Camera {}", number + 1)))
+        .collect();
+
+    (user, cameras)
+}
+
+/// A 640x480 JPEG, about the size of what cameras upload.
+fn snapshot() -> Vec<u8> {
+    let image = RgbImage::from_fn(640, 480, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    });
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 75)
+        .encode(&image, 640, 480, ColorType::Rgb8)
+        .expect("Failed to encode the snapshot!");
+    jpeg
+}
+
+fn hot_paths(c: &mut Criterion) {
+    env::set_var("CAMERA_SERVER_CACHE_TTL_SECONDS", "0");
+    env::set_var("CAMERA_SERVER_CACHE_CAMERA_LIST_TTL_SECONDS", "0");
+    env::set_var(
+        "CAMERA_SERVER_LIMITS_RATE_LIMIT_PER_MINUTE",
+        u32::MAX.to_string(),
+    );
+    env::set_var("CAMERA_SERVER_LIMITS_OWNER_DAILY_REQUESTS", "0");
+    env::set_var("CAMERA_SERVER_LIMITS_CAMERA_DAILY_REQUESTS", "0");
+
+    let server = TestServer::new();
+    let users: Vec<_> = CAMERA_COUNTS
+        .iter()
+        .map(|&count| (count, add_user_with_cameras(&server, count)))
+        .collect();
+
+    // GET /Cameras/<camera_id> does little past the access check, so it's what's timed
+    let mut group = c.benchmark_group("access_check");
+    for (count, (user, cameras)) in &users {
+        let path = server.path(&format!(
+            "/Cameras/{}",
+            cameras[cameras.len() - 1].camera_id
+        ));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &path, |b, path| {
+            b.iter(|| {
+                let response = server
+                    .client
+                    .get(path.as_str())
+                    .header(user.header())
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("list_cameras");
+    let path = server.path("/Cameras");
+    for (count, (user, _)) in &users {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &path, |b, path| {
+            b.iter(|| {
+                let response = server
+                    .client
+                    .get(path.as_str())
+                    .header(user.header())
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            })
+        });
+    }
+    group.finish();
+
+    let jpeg = snapshot();
+    let mut group = c.benchmark_group("upload_snapshot");
+    group.throughput(Throughput::Bytes(jpeg.len() as u64));
+    let path = server.path("/Device/Images");
+    for (count, (_, cameras)) in &users {
+        let camera = &cameras[0];
+        group.bench_with_input(BenchmarkId::from_parameter(count), &path, |b, path| {
+            b.iter(|| {
+                let response = server
+                    .client
+                    .post(path.as_str())
+                    .header(ContentType::JPEG)
+                    .header(camera.header())
+                    .body(&jpeg)
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Every sample makes real database round trips, so fewer of them keep a run to a few minutes
+    config = Criterion::default().sample_size(30);
+    targets = hot_paths
+}
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use std::fs;
+
+fn main() {
+    // embed_migrations!() reads the migrations at compile time, so new ones need a rebuild
+    println!("cargo:rerun-if-changed=migrations");
+
+    // The newest migration, for checking the database's schema against at startup. See schema_check.rs
+    let version = fs::read_dir("migrations")
+        .expect("Failed to read the migrations directory!")
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| Some(name.split('_').next()?.replace('-', "")))
+        .max()
+        .expect("There are no migrations!");
+    println!("cargo:rustc-env=EMBEDDED_SCHEMA_VERSION={}", version);
+
+    tonic_build::compile_protos("proto/camera_server.proto")
+        .expect("Failed to compile gRPC protos!");
+}
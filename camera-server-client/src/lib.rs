@@ -0,0 +1,226 @@
+/// The API's request and response bodies. These match what the server sends and accepts,
+/// without any of the database details the server's own structs carry.
+pub mod types;
+
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use types::*;
+
+/// Every API route is under this.
+pub const API_PREFIX: &str = "/api/v1";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request didn't get a response, or the response couldn't be read.
+    Http(reqwest::Error),
+    /// The server responded with an error.
+    Api { status: u16, body: ErrorBody },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(error) => write!(f, "{}", error),
+            Error::Api { status, body } => {
+                write!(f, "{} ({}): {}", status, body.code, body.message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Error {
+        Error::Http(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Which token requests are sent with. Users and cameras use different headers.
+#[derive(Debug, Clone, Copy)]
+pub enum Token {
+    User(uuid::Uuid),
+    Camera(uuid::Uuid),
+}
+
+/// A blocking client for the camera server, for CLI tools and camera firmware.
+pub struct Client {
+    /// Where the server is, e.g. http://localhost:8000. API_PREFIX is added to this.
+    pub base_url: String,
+    pub token: Option<Token>,
+    http: reqwest::blocking::Client,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: None,
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    pub fn with_token(mut self, token: Token) -> Client {
+        self.token = Some(token);
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, &format!("{}{}{}", self.base_url, API_PREFIX, path));
+
+        match self.token {
+            Some(Token::User(user_token)) => request.header("user_token", user_token.to_string()),
+            Some(Token::Camera(camera_token)) => {
+                request.header("camera_token", camera_token.to_string())
+            }
+            None => request,
+        }
+    }
+
+    /// Sends the request, turning error responses into Error::Api.
+    fn send(request: RequestBuilder) -> Result<Response> {
+        let response = request.send()?;
+        let status = response.status();
+
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let body = response.json::<ErrorBody>().unwrap_or_else(|_| ErrorBody {
+                code: String::from("unknown"),
+                message: status.to_string(),
+                details: Vec::new(),
+                request_id: None,
+            });
+
+            Err(Error::Api {
+                status: status.as_u16(),
+                body,
+            })
+        }
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.get_with_query(path, &[])
+    }
+
+    fn get_with_query<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T> {
+        Ok(Client::send(self.request(Method::GET, path).query(query))?.json()?)
+    }
+
+    fn send_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &B,
+    ) -> Result<T> {
+        Ok(Client::send(self.request(method, path).json(body))?.json()?)
+    }
+
+    /// Creates a user, and uses the new user's token for every request after this.
+    pub fn add_user(&mut self, new_user: &InsertableUser) -> Result<AuthentiationResult> {
+        let result: AuthentiationResult = self.send_json(Method::POST, "/Users", new_user)?;
+        self.token = Some(Token::User(result.user_token));
+        Ok(result)
+    }
+
+    /// Logs in, and uses the user's new token for every request after this.
+    pub fn login(&mut self, user_login: &InsertableUser) -> Result<AuthentiationResult> {
+        let result: AuthentiationResult = self.send_json(Method::POST, "/Login", user_login)?;
+        self.token = Some(Token::User(result.user_token));
+        Ok(result)
+    }
+
+    /// Returns a page of the user's cameras. Pass the previous page's next_cursor to get the next one.
+    pub fn list_cameras(&self, cursor: Option<&str>) -> Result<Page<Camera>> {
+        let query = cursor
+            .map(|cursor| ("cursor", cursor.to_string()))
+            .into_iter()
+            .collect::<Vec<(&str, String)>>();
+
+        self.get_with_query("/Cameras", &query)
+    }
+
+    pub fn get_camera(&self, camera_id: uuid::Uuid) -> Result<Camera> {
+        self.get(&format!("/Cameras/{}", camera_id))
+    }
+
+    /// Creates a camera, returning the token it should use.
+    pub fn add_camera(&self, camera: &InsertableCamera) -> Result<CameraToken> {
+        self.send_json(Method::POST, "/Cameras", camera)
+    }
+
+    pub fn update_camera(&self, camera_id: uuid::Uuid, update: &UpdateCamera) -> Result<Camera> {
+        self.send_json(Method::PATCH, &format!("/Cameras/{}", camera_id), update)
+    }
+
+    /// Deletes the camera for everyone who has access to it. An admin can bring it back until it's purged.
+    pub fn delete_camera(&self, camera_id: uuid::Uuid) -> Result<()> {
+        Client::send(self.request(Method::DELETE, &format!("/Cameras/{}", camera_id)))?;
+        Ok(())
+    }
+
+    pub fn get_config(&self, camera_id: uuid::Uuid) -> Result<Config> {
+        self.get(&format!("/Cameras/{}/Config", camera_id))
+    }
+
+    pub fn update_config(&self, camera_id: uuid::Uuid, config: &Config) -> Result<Config> {
+        self.send_json(
+            Method::PUT,
+            &format!("/Cameras/{}/Config", camera_id),
+            config,
+        )
+    }
+
+    /// Returns a page of the user's events, newest first.
+    pub fn get_events(&self, cursor: Option<&str>) -> Result<Page<Event>> {
+        let query = cursor
+            .map(|cursor| ("cursor", cursor.to_string()))
+            .into_iter()
+            .collect::<Vec<(&str, String)>>();
+
+        self.get_with_query("/Events", &query)
+    }
+
+    /// For cameras. Returns the camera's config, zones and whether it's armed.
+    pub fn get_device_config(&self) -> Result<CameraConfig> {
+        self.get("/Device/Config")
+    }
+
+    /// For cameras. Returns commands queued since the camera last asked, waiting up to `wait` seconds for one
+    /// if there aren't any yet.
+    pub fn get_commands(&self, wait: Option<u64>) -> Result<Vec<CameraCommand>> {
+        let query = wait
+            .map(|wait| ("wait", wait.to_string()))
+            .into_iter()
+            .collect::<Vec<(&str, String)>>();
+
+        self.get_with_query("/Device/Commands", &query)
+    }
+
+    /// For cameras. Returns the stored event, or None if it was outside the camera's zones.
+    pub fn report_event(&self, event: &ReportedEvent) -> Result<Option<Event>> {
+        self.send_json(Method::POST, "/Device/Events", event)
+    }
+
+    /// For cameras. Uploads a JPEG, returning its image ID.
+    pub fn upload_image(&self, jpeg: Vec<u8>) -> Result<String> {
+        Ok(Client::send(
+            self.request(Method::POST, "/Device/Images")
+                .header(CONTENT_TYPE, "image/jpeg")
+                .body(jpeg),
+        )?
+        .text()?)
+    }
+}
@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Camera {
+    pub camera_id: uuid::Uuid,
+    pub name: String,
+    pub online: bool,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// owned or shared. Only set in list_cameras().
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relationship: Option<String>,
+}
+
+/// Sent with POST /Cameras.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InsertableCamera {
+    pub name: String,
+}
+
+/// Sent with PATCH /Cameras/<camera_id>. Fields left as None aren't changed.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UpdateCamera {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CameraToken {
+    pub camera_token: uuid::Uuid,
+    pub camera_id: uuid::Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UsersCamera {
+    pub users_cameras_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InsertableUsersCamera {
+    pub camera_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+}
+
+/// Sent with POST /Users and POST /Login.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InsertableUser {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub user_id: uuid::Uuid,
+}
+
+/// Returned by POST /Users and POST /Login. The server spells it this way too.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthentiationResult {
+    pub user_info: UserInfo,
+    pub user_token: uuid::Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    pub camera_id: uuid::Uuid,
+    /// Seconds between images.
+    pub interval: i16,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Zone {
+    /// include or exclude.
+    pub kind: String,
+    pub points: Vec<Point>,
+}
+
+/// Returned by GET /Device/Config.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CameraConfig {
+    #[serde(flatten)]
+    pub config: Config,
+    pub zones: Vec<Zone>,
+    pub armed: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CameraCommand {
+    pub command_id: i32,
+    pub camera_id: uuid::Uuid,
+    /// snapshot or config_updated.
+    pub command: String,
+    pub delivered: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Event {
+    pub event_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    pub severity: String,
+    pub anonymised_at: Option<DateTime<Utc>>,
+    pub audio_id: Option<i32>,
+    pub tamper_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReportedDetection {
+    pub label: String,
+    pub confidence: f32,
+    pub bounding_box: BoundingBox,
+}
+
+/// Sent with POST /Device/Events.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReportedEvent {
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    pub audio_id: Option<i32>,
+    pub tamper_reason: Option<String>,
+    pub severity: Option<String>,
+    pub bounding_box: Option<BoundingBox>,
+    #[serde(default)]
+    pub detections: Vec<ReportedDetection>,
+}
+
+/// What every list endpoint returns.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    /// Pass this back as the cursor to get the next page. None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// What every error response looks like.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(default)]
+    pub details: Vec<FieldDetail>,
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FieldDetail {
+    pub field: String,
+    pub message: String,
+}
@@ -0,0 +1,513 @@
+//! Registers virtual cameras with a running server and has each of them heartbeat, upload snapshots and report
+//! motion at a steady rate, like a fleet of real cameras would. For demos and load testing.
+//!
+//! With --list-seconds, the cameras' user also lists them and looks each one up in turn, which covers the access
+//! check. How long each kind of request took is printed at the end, and it exits with 1 if any of them failed, so
+//! load-test.sh can be run against a staging server to catch slow or failing hot paths.
+
+use camera_server_client::types::{InsertableCamera, InsertableUser, ReportedEvent};
+use camera_server_client::{Client, Result, Token};
+use chrono::Utc;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, Rgb, RgbImage};
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: camera-simulator <server_url> [options]
+
+Options:
+    --cameras <count>             How many virtual cameras to register. Defaults to 10
+    --heartbeat-seconds <seconds> Seconds between each camera checking for commands. Defaults to 30
+    --snapshot-seconds <seconds>  Seconds between each camera uploading a snapshot. Defaults to 60
+    --motion-seconds <seconds>    Seconds between each camera reporting motion, with a snapshot. Defaults to 300
+    --list-seconds <seconds>      Seconds between the user listing their cameras and looking one up. Off by
+                                  default
+    --duration <seconds>          Stops after this long, rather than running until it's killed
+    --username <username>         Registers the cameras to this user, reading their password from
+                                  --password. Otherwise a new user is made for them
+
+Seconds can be fractions, e.g. 0.5, so a few cameras can be as busy as many.";
+
+const SNAPSHOT_WIDTH: u32 = 320;
+const SNAPSHOT_HEIGHT: u32 = 240;
+const JPEG_QUALITY: u8 = 75;
+
+/// How often the totals so far are printed.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Options {
+    server_url: String,
+    cameras: u32,
+    heartbeat_interval: Duration,
+    snapshot_interval: Duration,
+    motion_interval: Duration,
+    list_interval: Option<Duration>,
+    duration: Option<Duration>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// One kind of request, counted across every camera.
+#[derive(Default)]
+struct RequestStats {
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+    /// How long each request took, in microseconds, for the percentiles at the end.
+    latencies: Mutex<Vec<u64>>,
+}
+
+impl RequestStats {
+    fn time<T>(&self, request: impl FnOnce() -> Result<T>) -> Result<T> {
+        let started_at = Instant::now();
+        let result = request();
+        let latency = started_at.elapsed().as_micros() as u64;
+
+        match result {
+            Ok(_) => self.succeeded.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.failed.fetch_add(1, Ordering::Relaxed),
+        };
+        self.latencies
+            .lock()
+            .expect("Request latencies were poisoned")
+            .push(latency);
+
+        result
+    }
+
+    fn percentiles(&self) -> String {
+        let mut latencies = self
+            .latencies
+            .lock()
+            .expect("Request latencies were poisoned")
+            .clone();
+        if latencies.is_empty() {
+            return String::from("none sent");
+        }
+        latencies.sort_unstable();
+
+        let percentile = |percent: usize| {
+            let latency = latencies[(latencies.len() * percent / 100).min(latencies.len() - 1)];
+            format!("{:.1}ms", latency as f64 / 1000.0)
+        };
+        format!(
+            "p50 {}, p95 {}, p99 {}, max {}",
+            percentile(50),
+            percentile(95),
+            percentile(99),
+            percentile(100)
+        )
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    heartbeats: RequestStats,
+    snapshots: RequestStats,
+    motion_events: RequestStats,
+    listings: RequestStats,
+    lookups: RequestStats,
+    commands: AtomicU64,
+}
+
+impl Totals {
+    fn kinds(&self) -> [(&'static str, &RequestStats); 5] {
+        [
+            ("heartbeats", &self.heartbeats),
+            ("snapshots", &self.snapshots),
+            ("motion events", &self.motion_events),
+            ("camera listings", &self.listings),
+            ("camera lookups", &self.lookups),
+        ]
+    }
+
+    fn failures(&self) -> u64 {
+        self.kinds()
+            .iter()
+            .map(|(_, stats)| stats.failed.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    fn summary(&self) -> String {
+        let mut summary: Vec<String> = self
+            .kinds()
+            .iter()
+            .map(|(name, stats)| format!("{} {}", stats.succeeded.load(Ordering::Relaxed), name))
+            .collect();
+        summary.push(format!(
+            "{} commands received",
+            self.commands.load(Ordering::Relaxed)
+        ));
+        summary.push(format!("{} failed requests", self.failures()));
+
+        summary.join(", ")
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn parse_seconds(flag: &str, value: String) -> Duration {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 => Duration::from_secs_f64(seconds),
+        _ => fail(format!("{} must be a number of seconds above 0", flag)),
+    }
+}
+
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1);
+    let server_url = match args.next() {
+        Some(server_url) if !server_url.starts_with("--") => server_url,
+        _ => fail(String::from(USAGE)),
+    };
+
+    let mut options = Options {
+        server_url,
+        cameras: 10,
+        heartbeat_interval: Duration::from_secs(30),
+        snapshot_interval: Duration::from_secs(60),
+        motion_interval: Duration::from_secs(300),
+        list_interval: None,
+        duration: None,
+        username: None,
+        password: None,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .unwrap_or_else(|| fail(format!("{} needs a value", flag)));
+
+        match flag.as_str() {
+            "--cameras" => {
+                options.cameras = match value.parse::<u32>() {
+                    Ok(cameras) if cameras > 0 => cameras,
+                    _ => fail(String::from("--cameras must be a whole number above 0")),
+                }
+            }
+            "--heartbeat-seconds" => options.heartbeat_interval = parse_seconds(&flag, value),
+            "--snapshot-seconds" => options.snapshot_interval = parse_seconds(&flag, value),
+            "--motion-seconds" => options.motion_interval = parse_seconds(&flag, value),
+            "--list-seconds" => options.list_interval = Some(parse_seconds(&flag, value)),
+            "--duration" => options.duration = Some(parse_seconds(&flag, value)),
+            "--username" => options.username = Some(value),
+            "--password" => options.password = Some(value),
+            _ => fail(String::from(USAGE)),
+        }
+    }
+
+    if options.username.is_some() != options.password.is_some() {
+        fail(String::from(
+            "--username and --password must be given together",
+        ));
+    }
+
+    options
+}
+
+/// Logs in as the given user, or makes a new one with a random name and password.
+fn log_in(options: &Options) -> Client {
+    let mut client = Client::new(&options.server_url);
+
+    let result = match (&options.username, &options.password) {
+        (Some(username), Some(password)) => client.login(&InsertableUser {
+            username: username.clone(),
+            password: password.clone(),
+        }),
+        _ => {
+            let username = format!(
+                "simulator-{}",
+                &uuid::Uuid::new_v4().simple().to_string()[..8]
+            );
+            let result = client.add_user(&InsertableUser {
+                username: username.clone(),
+                password: uuid::Uuid::new_v4().to_string(),
+            });
+            if result.is_ok() {
+                println!("Made user {} for the virtual cameras", username);
+            }
+            result
+        }
+    };
+
+    if let Err(error) = result {
+        fail(format!("Failed to log in! The error was {}", error));
+    }
+
+    client
+}
+
+/// A grey frame with a bright square that moves a little each time, so every snapshot is different and reads as
+/// motion to anything comparing them.
+fn draw_snapshot(camera_number: u32, frame: u64) -> Vec<u8> {
+    let square = SNAPSHOT_HEIGHT / 4;
+    let step = frame as u32 * 8 + camera_number * 40;
+    let square_x = step % (SNAPSHOT_WIDTH - square);
+    let square_y = (step / 2) % (SNAPSHOT_HEIGHT - square);
+
+    let image = RgbImage::from_fn(SNAPSHOT_WIDTH, SNAPSHOT_HEIGHT, |x, y| {
+        if x >= square_x && x < square_x + square && y >= square_y && y < square_y + square {
+            Rgb([240, 240, 240])
+        } else {
+            let shade = 60 + (y * 60 / SNAPSHOT_HEIGHT) as u8;
+            Rgb([shade, shade, shade])
+        }
+    });
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+        .encode(&image, SNAPSHOT_WIDTH, SNAPSHOT_HEIGHT, ColorType::Rgb8)
+        .expect("Failed to encode snapshot!");
+    jpeg
+}
+
+struct VirtualCamera {
+    number: u32,
+    camera_id: uuid::Uuid,
+    client: Client,
+    frame: u64,
+}
+
+impl VirtualCamera {
+    fn failed(&self, what: &str, error: camera_server_client::Error) {
+        eprintln!(
+            "Camera {} ({}) failed to {}: {}",
+            self.number + 1,
+            self.camera_id,
+            what,
+            error
+        );
+    }
+
+    /// Uploads a snapshot, returning its image ID.
+    fn upload_snapshot(&mut self, totals: &Totals) -> Option<i64> {
+        self.frame += 1;
+
+        let snapshot = draw_snapshot(self.number, self.frame);
+        let client = &self.client;
+
+        match totals.snapshots.time(|| client.upload_image(snapshot)) {
+            Ok(image_id) => image_id.trim().parse::<i64>().ok(),
+            Err(error) => {
+                self.failed("upload a snapshot", error);
+                None
+            }
+        }
+    }
+
+    /// Checks for commands, which also tells the server the camera's online. Snapshot commands are answered
+    /// straight away, and anything else is only counted.
+    fn heartbeat(&mut self, totals: &Totals) {
+        let client = &self.client;
+
+        match totals.heartbeats.time(|| client.get_commands(None)) {
+            Ok(commands) => {
+                totals
+                    .commands
+                    .fetch_add(commands.len() as u64, Ordering::Relaxed);
+
+                if commands.iter().any(|command| command.command == "snapshot") {
+                    self.upload_snapshot(totals);
+                }
+            }
+            Err(error) => self.failed("check for commands", error),
+        }
+    }
+
+    fn report_motion(&mut self, totals: &Totals) {
+        let image_id = self.upload_snapshot(totals);
+
+        let event = ReportedEvent {
+            event_type: String::from("motion"),
+            occurred_at: Utc::now(),
+            // Spread out, so confidence thresholds let some through and not others
+            confidence: 0.5 + (self.frame % 5) as f32 * 0.1,
+            image_id,
+            audio_id: None,
+            tamper_reason: None,
+            severity: None,
+            bounding_box: None,
+            detections: Vec::new(),
+        };
+
+        let client = &self.client;
+        if let Err(error) = totals.motion_events.time(|| client.report_event(&event)) {
+            self.failed("report motion", error);
+        }
+    }
+}
+
+/// When something next happens. Cameras start spread out over each interval, so they don't all send at once.
+fn first_due(start: Instant, interval: Duration, camera_number: u32, cameras: u32) -> Instant {
+    start + interval.mul_f64(camera_number as f64 / cameras as f64)
+}
+
+fn run_camera(
+    mut camera: VirtualCamera,
+    options: Arc<Options>,
+    totals: Arc<Totals>,
+    stopping: Arc<AtomicBool>,
+) {
+    let start = Instant::now();
+    let mut next_heartbeat = first_due(
+        start,
+        options.heartbeat_interval,
+        camera.number,
+        options.cameras,
+    );
+    let mut next_snapshot = first_due(
+        start,
+        options.snapshot_interval,
+        camera.number,
+        options.cameras,
+    );
+    let mut next_motion = first_due(
+        start,
+        options.motion_interval,
+        camera.number,
+        options.cameras,
+    );
+
+    while !stopping.load(Ordering::Relaxed) {
+        let now = Instant::now();
+
+        if now >= next_heartbeat {
+            camera.heartbeat(&totals);
+            next_heartbeat += options.heartbeat_interval;
+        }
+        if now >= next_snapshot {
+            camera.upload_snapshot(&totals);
+            next_snapshot += options.snapshot_interval;
+        }
+        if now >= next_motion {
+            camera.report_motion(&totals);
+            next_motion += options.motion_interval;
+        }
+
+        // A slow server makes cameras fall behind rather than pile up requests
+        let now = Instant::now();
+        next_heartbeat = next_heartbeat.max(now);
+        next_snapshot = next_snapshot.max(now);
+        next_motion = next_motion.max(now);
+
+        let next = next_heartbeat.min(next_snapshot).min(next_motion);
+        thread::sleep(
+            next.saturating_duration_since(Instant::now())
+                .min(Duration::from_secs(1)),
+        );
+    }
+}
+
+/// Lists the user's cameras and looks one of them up every `interval`, like an app being used would.
+fn run_user(
+    client: Client,
+    camera_ids: Vec<uuid::Uuid>,
+    interval: Duration,
+    totals: Arc<Totals>,
+    stopping: Arc<AtomicBool>,
+) {
+    let mut next = Instant::now();
+
+    for camera_id in camera_ids.iter().cycle() {
+        if stopping.load(Ordering::Relaxed) {
+            break;
+        }
+
+        if let Err(error) = totals.listings.time(|| client.list_cameras(None)) {
+            eprintln!("Failed to list cameras: {}", error);
+        }
+        if let Err(error) = totals.lookups.time(|| client.get_camera(*camera_id)) {
+            eprintln!("Failed to look up camera {}: {}", camera_id, error);
+        }
+
+        next = (next + interval).max(Instant::now());
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn main() {
+    let options = Arc::new(parse_options());
+    let client = log_in(&options);
+
+    let mut cameras = Vec::new();
+    for number in 0..options.cameras {
+        let name = format!("Simulated camera {}", number + 1);
+        let camera_token = client
+            .add_camera(&InsertableCamera { name: name.clone() })
+            .unwrap_or_else(|error| {
+                fail(format!(
+                    "Failed to register {}! The error was {}",
+                    name, error
+                ))
+            });
+
+        cameras.push(VirtualCamera {
+            number,
+            camera_id: camera_token.camera_id,
+            client: Client::new(&options.server_url)
+                .with_token(Token::Camera(camera_token.camera_token)),
+            frame: 0,
+        });
+    }
+    println!("Registered {} virtual cameras", cameras.len());
+
+    let camera_ids = cameras.iter().map(|camera| camera.camera_id).collect();
+    let totals = Arc::new(Totals::default());
+    let stopping = Arc::new(AtomicBool::new(false));
+    let mut threads: Vec<_> = cameras
+        .into_iter()
+        .map(|camera| {
+            let options = options.clone();
+            let totals = totals.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || run_camera(camera, options, totals, stopping))
+        })
+        .collect();
+
+    if let Some(interval) = options.list_interval {
+        let totals = totals.clone();
+        let stopping = stopping.clone();
+        threads.push(thread::spawn(move || {
+            run_user(client, camera_ids, interval, totals, stopping)
+        }));
+    }
+
+    let started_at = Instant::now();
+    loop {
+        let remaining = options.duration.map(|duration| {
+            duration
+                .checked_sub(started_at.elapsed())
+                .unwrap_or_default()
+        });
+        if remaining == Some(Duration::from_secs(0)) {
+            break;
+        }
+
+        thread::sleep(
+            remaining.map_or(REPORT_INTERVAL, |remaining| remaining.min(REPORT_INTERVAL)),
+        );
+        println!(
+            "After {}s: {}",
+            started_at.elapsed().as_secs(),
+            totals.summary()
+        );
+    }
+
+    stopping.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().ok();
+    }
+    println!("Finished: {}", totals.summary());
+    for (name, stats) in totals.kinds().iter() {
+        println!("    {}: {}", name, stats.percentiles());
+    }
+
+    if totals.failures() > 0 {
+        process::exit(1);
+    }
+}
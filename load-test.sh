@@ -0,0 +1,20 @@
+#!/usr/bin/env bash
+# Puts a server under load with camera-simulator and fails if any request did, e.g. against staging before a
+# release. Compare the latencies it prints with an earlier run to spot regressions.
+#
+# Usage: ./load-test.sh [server_url]
+# The load can be changed with CAMERAS, HEARTBEAT_SECONDS, SNAPSHOT_SECONDS, MOTION_SECONDS, LIST_SECONDS and
+# DURATION_SECONDS. The server's rate_limit_per_minute and owner_daily_requests need to be high enough for it, as
+# every camera is counted on its own but the listings all come from one user.
+
+set -euo pipefail
+
+SERVER_URL=${1:-http://localhost:8000}
+
+cargo run --release -p camera-simulator -- "$SERVER_URL" \
+    --cameras "${CAMERAS:-100}" \
+    --heartbeat-seconds "${HEARTBEAT_SECONDS:-5}" \
+    --snapshot-seconds "${SNAPSHOT_SECONDS:-10}" \
+    --motion-seconds "${MOTION_SECONDS:-30}" \
+    --list-seconds "${LIST_SECONDS:-0.2}" \
+    --duration "${DURATION_SECONDS:-120}"
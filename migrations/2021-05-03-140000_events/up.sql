@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE events (
+    event_id SERIAL PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    event_type text NOT NULL,
+    occurred_at timestamptz NOT NULL,
+    confidence real NOT NULL,
+    image_id bigint,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX events_camera_id_occurred_at ON events (camera_id, occurred_at)
\ No newline at end of file
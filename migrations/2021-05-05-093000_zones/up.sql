@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE zones (
+    zone_id SERIAL PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    kind text NOT NULL,
+    points jsonb NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+)
\ No newline at end of file
@@ -0,0 +1,32 @@
+-- Your SQL goes here
+CREATE TABLE webhooks (
+    webhook_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    url text NOT NULL,
+    secret text NOT NULL,
+    event_types text[] NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+CREATE TABLE webhook_deliveries (
+    delivery_id SERIAL PRIMARY KEY,
+    webhook_id integer NOT NULL,
+    event_id integer NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    delivered boolean DEFAULT false NOT NULL,
+    next_attempt_at timestamptz,
+    last_status_code integer,
+    last_error text,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_webhook_id
+        FOREIGN KEY (webhook_id)
+            REFERENCES webhooks (webhook_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX webhook_deliveries_next_attempt_at ON webhook_deliveries (next_attempt_at) WHERE NOT delivered
\ No newline at end of file
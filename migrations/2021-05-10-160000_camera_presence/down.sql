@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE cameras
+    DROP COLUMN online,
+    DROP COLUMN last_seen_at
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE cameras
+    ADD COLUMN online boolean DEFAULT false NOT NULL,
+    ADD COLUMN last_seen_at timestamptz
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE notifications;
+DROP TABLE notification_preferences;
+DROP TABLE push_tokens
\ No newline at end of file
@@ -0,0 +1,49 @@
+-- Your SQL goes here
+CREATE TABLE push_tokens (
+    push_token_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    platform text NOT NULL,
+    token text NOT NULL UNIQUE,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+CREATE TABLE notification_preferences (
+    user_id uuid NOT NULL,
+    camera_id uuid NOT NULL,
+    push_enabled boolean DEFAULT true NOT NULL,
+    event_types text[] NOT NULL,
+    offline_alerts boolean DEFAULT true NOT NULL,
+    PRIMARY KEY (user_id, camera_id),
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+CREATE TABLE notifications (
+    notification_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    camera_id uuid NOT NULL,
+    channel text NOT NULL,
+    title text NOT NULL,
+    body text NOT NULL,
+    sent boolean DEFAULT false NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    next_attempt_at timestamptz,
+    last_error text,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX notifications_next_attempt_at ON notifications (next_attempt_at) WHERE NOT sent
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE notifications DROP COLUMN event_id;
+DROP TABLE email_alerts
\ No newline at end of file
@@ -0,0 +1,25 @@
+-- Your SQL goes here
+CREATE TABLE email_alerts (
+    user_id uuid NOT NULL,
+    camera_id uuid NOT NULL,
+    recipients text[] NOT NULL,
+    event_types text[] NOT NULL,
+    attach_snapshot boolean DEFAULT true NOT NULL,
+    throttle_minutes integer DEFAULT 10 NOT NULL,
+    last_alerted_at timestamptz,
+    PRIMARY KEY (user_id, camera_id),
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+ALTER TABLE notifications
+    ADD COLUMN event_id integer,
+    ADD CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE
\ No newline at end of file
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE rules (
+    rule_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    name text NOT NULL,
+    camera_id uuid,
+    event_types text[] NOT NULL,
+    min_confidence real DEFAULT 0 NOT NULL,
+    start_time time,
+    end_time time,
+    channels text[] NOT NULL,
+    enabled boolean DEFAULT true NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX rules_user_id ON rules (user_id)
\ No newline at end of file
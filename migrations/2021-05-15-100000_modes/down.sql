@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE notification_preferences DROP COLUMN armed_modes;
+ALTER TABLE rules DROP COLUMN modes;
+DROP TABLE mode_schedules;
+DROP TABLE user_modes
\ No newline at end of file
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE user_modes (
+    user_id uuid PRIMARY KEY,
+    mode text NOT NULL,
+    changed_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+CREATE TABLE mode_schedules (
+    schedule_id SERIAL PRIMARY KEY,
+    user_id uuid NOT NULL,
+    mode text NOT NULL,
+    at_time time NOT NULL,
+    days_of_week integer[] NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+ALTER TABLE rules ADD COLUMN modes text[] DEFAULT '{}' NOT NULL;
+ALTER TABLE notification_preferences ADD COLUMN armed_modes text[] DEFAULT '{home,away,night}' NOT NULL
\ No newline at end of file
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+CREATE TABLE detections (
+    detection_id SERIAL PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    event_id integer,
+    image_id bigint,
+    label text NOT NULL,
+    confidence real NOT NULL,
+    x real NOT NULL,
+    y real NOT NULL,
+    width real NOT NULL,
+    height real NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX detections_label_confidence ON detections (label, confidence);
+CREATE INDEX detections_event_id ON detections (event_id);
+CREATE INDEX detections_camera_id_image_id ON detections (camera_id, image_id)
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE analysis_jobs (
+    job_id SERIAL PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    image_id bigint NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    done boolean DEFAULT false NOT NULL,
+    next_attempt_at timestamptz,
+    last_error text,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+CREATE INDEX analysis_jobs_next_attempt_at ON analysis_jobs (next_attempt_at) WHERE NOT done
\ No newline at end of file
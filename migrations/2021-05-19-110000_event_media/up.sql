@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE event_media (
+    event_id integer NOT NULL,
+    image_id bigint NOT NULL,
+    PRIMARY KEY (event_id, image_id),
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE
+)
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE webhooks DROP COLUMN min_severity;
+ALTER TABLE email_alerts DROP COLUMN min_severity;
+ALTER TABLE notification_preferences DROP COLUMN min_severity;
+ALTER TABLE rules DROP COLUMN min_severity;
+ALTER TABLE events DROP COLUMN severity
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN severity text DEFAULT 'info' NOT NULL;
+ALTER TABLE rules ADD COLUMN min_severity text DEFAULT 'info' NOT NULL;
+ALTER TABLE notification_preferences ADD COLUMN min_severity text DEFAULT 'info' NOT NULL;
+ALTER TABLE email_alerts ADD COLUMN min_severity text DEFAULT 'info' NOT NULL;
+ALTER TABLE webhooks ADD COLUMN min_severity text DEFAULT 'info' NOT NULL;
+UPDATE events SET severity = 'warning' WHERE event_type IN ('person', 'doorbell')
\ No newline at end of file
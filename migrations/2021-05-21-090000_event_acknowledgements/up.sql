@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE event_acknowledgements (
+    user_id uuid NOT NULL,
+    event_id integer NOT NULL,
+    acknowledged_at timestamptz DEFAULT now() NOT NULL,
+    PRIMARY KEY (user_id, event_id),
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE
+)
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER detections_search_vector ON detections;
+DROP TRIGGER events_search_vector ON events;
+DROP FUNCTION detections_search_vector_trigger();
+DROP FUNCTION events_search_vector_trigger();
+DROP FUNCTION refresh_event_search_vector(integer);
+DROP INDEX events_search_vector_index;
+ALTER TABLE events DROP COLUMN search_vector;
\ No newline at end of file
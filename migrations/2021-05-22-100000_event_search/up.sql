@@ -0,0 +1,47 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN search_vector tsvector;
+
+CREATE FUNCTION refresh_event_search_vector(target_event_id integer) RETURNS void AS $$
+    UPDATE events
+    SET search_vector = to_tsvector('english',
+        events.event_type || ' ' || events.severity || ' ' || cameras.name || ' ' ||
+        coalesce((SELECT string_agg(detections.label, ' ') FROM detections WHERE detections.event_id = events.event_id), '')
+    )
+    FROM cameras
+    WHERE cameras.camera_id = events.camera_id AND events.event_id = target_event_id;
+$$ LANGUAGE sql;
+
+CREATE FUNCTION events_search_vector_trigger() RETURNS trigger AS $$
+BEGIN
+    PERFORM refresh_event_search_vector(NEW.event_id);
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION detections_search_vector_trigger() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        IF OLD.event_id IS NOT NULL THEN
+            PERFORM refresh_event_search_vector(OLD.event_id);
+        END IF;
+    ELSE
+        IF NEW.event_id IS NOT NULL THEN
+            PERFORM refresh_event_search_vector(NEW.event_id);
+        END IF;
+        IF TG_OP = 'UPDATE' AND OLD.event_id IS NOT NULL AND OLD.event_id IS DISTINCT FROM NEW.event_id THEN
+            PERFORM refresh_event_search_vector(OLD.event_id);
+        END IF;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER events_search_vector AFTER INSERT OR UPDATE OF event_type, severity ON events
+    FOR EACH ROW EXECUTE PROCEDURE events_search_vector_trigger();
+
+CREATE TRIGGER detections_search_vector AFTER INSERT OR UPDATE OR DELETE ON detections
+    FOR EACH ROW EXECUTE PROCEDURE detections_search_vector_trigger();
+
+SELECT refresh_event_search_vector(event_id) FROM events;
+
+CREATE INDEX events_search_vector_index ON events USING GIN (search_vector);
\ No newline at end of file
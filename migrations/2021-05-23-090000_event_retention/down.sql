@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX events_occurred_at;
+DROP TABLE event_holds;
+ALTER TABLE events DROP COLUMN anonymised_at;
\ No newline at end of file
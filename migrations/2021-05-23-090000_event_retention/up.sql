@@ -0,0 +1,20 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN anonymised_at timestamptz;
+
+CREATE TABLE event_holds (
+    hold_id serial PRIMARY KEY,
+    event_id integer NOT NULL,
+    user_id uuid NOT NULL,
+    reason text NOT NULL,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+            REFERENCES events (event_id)
+            ON DELETE CASCADE,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX events_occurred_at ON events (occurred_at);
\ No newline at end of file
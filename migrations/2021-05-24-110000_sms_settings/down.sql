@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX notifications_user_id_channel_created_at;
+DROP TABLE sms_settings;
\ No newline at end of file
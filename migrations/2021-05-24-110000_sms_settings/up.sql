@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE sms_settings (
+    user_id uuid PRIMARY KEY,
+    phone_number text,
+    enabled boolean DEFAULT false NOT NULL,
+    critical_alerts boolean DEFAULT true NOT NULL,
+    offline_alerts boolean DEFAULT true NOT NULL,
+    monthly_cap integer DEFAULT 50 NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX notifications_user_id_channel_created_at ON notifications (user_id, channel, created_at);
\ No newline at end of file
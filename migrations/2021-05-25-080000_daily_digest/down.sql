@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE camera_offline_periods;
+DROP TABLE digest_settings;
\ No newline at end of file
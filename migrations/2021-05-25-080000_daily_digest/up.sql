@@ -0,0 +1,26 @@
+-- Your SQL goes here
+CREATE TABLE digest_settings (
+    user_id uuid PRIMARY KEY,
+    enabled boolean DEFAULT false NOT NULL,
+    recipient text NOT NULL,
+    send_hour smallint DEFAULT 8 NOT NULL,
+    replaces_alerts boolean DEFAULT false NOT NULL,
+    last_sent_at timestamptz,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+);
+
+CREATE TABLE camera_offline_periods (
+    period_id serial PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    started_at timestamptz NOT NULL,
+    ended_at timestamptz,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+
+CREATE INDEX camera_offline_periods_camera_id_started_at ON camera_offline_periods (camera_id, started_at)
\ No newline at end of file
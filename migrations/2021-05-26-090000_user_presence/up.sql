@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE user_presence (
+    user_id uuid PRIMARY KEY,
+    home boolean NOT NULL,
+    changed_at timestamptz DEFAULT now() NOT NULL,
+    CONSTRAINT fk_user_id
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+            ON DELETE CASCADE
+)
\ No newline at end of file
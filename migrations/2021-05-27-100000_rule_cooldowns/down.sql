@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX notifications_rule_id_last_event_at;
+ALTER TABLE notifications DROP CONSTRAINT fk_rule_id;
+ALTER TABLE notifications DROP COLUMN last_event_at;
+ALTER TABLE notifications DROP COLUMN event_count;
+ALTER TABLE notifications DROP COLUMN rule_id;
+ALTER TABLE rules DROP COLUMN cooldown_seconds;
\ No newline at end of file
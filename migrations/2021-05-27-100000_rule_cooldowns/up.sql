@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE rules ADD COLUMN cooldown_seconds integer DEFAULT 300 NOT NULL;
+
+ALTER TABLE notifications ADD COLUMN rule_id integer;
+ALTER TABLE notifications ADD COLUMN event_count integer DEFAULT 1 NOT NULL;
+ALTER TABLE notifications ADD COLUMN last_event_at timestamptz DEFAULT now() NOT NULL;
+ALTER TABLE notifications
+    ADD CONSTRAINT fk_rule_id
+        FOREIGN KEY (rule_id)
+            REFERENCES rules (rule_id)
+            ON DELETE SET NULL;
+
+CREATE INDEX notifications_rule_id_last_event_at ON notifications (rule_id, last_event_at)
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE activity_baselines (
+    camera_id uuid NOT NULL,
+    hour smallint NOT NULL,
+    mean double precision NOT NULL,
+    standard_deviation double precision NOT NULL,
+    days integer NOT NULL,
+    computed_at timestamptz NOT NULL,
+    PRIMARY KEY (camera_id, hour),
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+)
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN audio_id;
+
+DROP TABLE audio_clips
\ No newline at end of file
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE audio_clips (
+    audio_id serial PRIMARY KEY,
+    camera_id uuid NOT NULL,
+    content_type text NOT NULL,
+    size_bytes bigint NOT NULL DEFAULT 0,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
+
+ALTER TABLE events ADD COLUMN audio_id integer;
+
+ALTER TABLE events ADD CONSTRAINT fk_audio_id
+    FOREIGN KEY (audio_id)
+        REFERENCES audio_clips (audio_id)
+        ON DELETE SET NULL
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE notification_preferences DROP COLUMN tamper_overrides;
+
+ALTER TABLE events DROP COLUMN tamper_reason
\ No newline at end of file
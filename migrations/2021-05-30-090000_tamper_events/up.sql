@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE events ADD COLUMN tamper_reason text;
+
+ALTER TABLE notification_preferences ADD COLUMN tamper_overrides boolean NOT NULL DEFAULT true
\ No newline at end of file
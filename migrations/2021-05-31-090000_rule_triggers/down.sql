@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rules DROP COLUMN trigger_url
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE rules ADD COLUMN trigger_url text
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE idempotency_keys (
+    client text NOT NULL,
+    idempotency_key text NOT NULL,
+    method text NOT NULL,
+    uri text NOT NULL,
+    status smallint,
+    content_type text,
+    body bytea,
+    created_at timestamptz DEFAULT now() NOT NULL,
+    PRIMARY KEY (client, idempotency_key)
+);
+
+CREATE INDEX idempotency_keys_created_at ON idempotency_keys (created_at);
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE mqtt_clients (
+    client_id text PRIMARY KEY,
+    camera_id uuid UNIQUE NOT NULL,
+    CONSTRAINT fk_camera_id
+        FOREIGN KEY (camera_id)
+            REFERENCES cameras (camera_id)
+            ON DELETE CASCADE
+);
\ No newline at end of file
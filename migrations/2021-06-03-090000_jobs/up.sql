@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE jobs (
+    job_id SERIAL PRIMARY KEY,
+    kind text NOT NULL,
+    payload jsonb DEFAULT '{}' NOT NULL,
+    status text DEFAULT 'queued' NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    max_attempts integer DEFAULT 5 NOT NULL,
+    run_at timestamptz DEFAULT now() NOT NULL,
+    started_at timestamptz,
+    finished_at timestamptz,
+    last_error text,
+    created_at timestamptz DEFAULT now() NOT NULL
+);
+CREATE INDEX jobs_queued_run_at ON jobs (run_at) WHERE status = 'queued';
+CREATE INDEX jobs_kind_status ON jobs (kind, status);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users_cameras DROP COLUMN deleted_at;
+ALTER TABLE cameras DROP COLUMN deleted_at;
+ALTER TABLE users DROP COLUMN deleted_at
\ No newline at end of file
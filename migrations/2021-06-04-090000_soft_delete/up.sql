@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN deleted_at timestamptz;
+ALTER TABLE cameras ADD COLUMN deleted_at timestamptz;
+ALTER TABLE users_cameras ADD COLUMN deleted_at timestamptz;
+CREATE INDEX users_deleted_at ON users (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX cameras_deleted_at ON cameras (deleted_at) WHERE deleted_at IS NOT NULL
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX events_updated_at;
+
+DROP TRIGGER set_updated_at ON webhooks;
+DROP TRIGGER set_updated_at ON rules;
+DROP TRIGGER set_updated_at ON events;
+DROP TRIGGER set_updated_at ON cameras;
+
+ALTER TABLE webhooks DROP COLUMN created_at, DROP COLUMN updated_at;
+ALTER TABLE rules DROP COLUMN created_at, DROP COLUMN updated_at;
+ALTER TABLE events DROP COLUMN created_at, DROP COLUMN updated_at;
+ALTER TABLE cameras DROP COLUMN created_at, DROP COLUMN updated_at
\ No newline at end of file
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+ALTER TABLE cameras
+    ADD COLUMN created_at timestamptz DEFAULT now() NOT NULL,
+    ADD COLUMN updated_at timestamptz DEFAULT now() NOT NULL;
+ALTER TABLE events
+    ADD COLUMN created_at timestamptz DEFAULT now() NOT NULL,
+    ADD COLUMN updated_at timestamptz DEFAULT now() NOT NULL;
+ALTER TABLE rules
+    ADD COLUMN created_at timestamptz DEFAULT now() NOT NULL,
+    ADD COLUMN updated_at timestamptz DEFAULT now() NOT NULL;
+ALTER TABLE webhooks
+    ADD COLUMN created_at timestamptz DEFAULT now() NOT NULL,
+    ADD COLUMN updated_at timestamptz DEFAULT now() NOT NULL;
+
+-- Existing events were stored around when they happened, which is closer than the migration running
+UPDATE events SET created_at = occurred_at, updated_at = occurred_at;
+
+-- updated_at is set whenever a row changes, unless the update changes it itself
+SELECT diesel_manage_updated_at('cameras');
+SELECT diesel_manage_updated_at('events');
+SELECT diesel_manage_updated_at('rules');
+SELECT diesel_manage_updated_at('webhooks');
+
+CREATE INDEX events_updated_at ON events (updated_at)
\ No newline at end of file
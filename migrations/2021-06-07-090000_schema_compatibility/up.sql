@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- There's only ever one row. Contract migrations set min_compatible_version to their own version, so servers
+-- older than it stop serving instead of running against a schema they don't understand. See schema_check.rs.
+CREATE TABLE schema_compatibility (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    min_compatible_version TEXT NOT NULL
+);
+
+INSERT INTO schema_compatibility (min_compatible_version) VALUES ('00000000000000');
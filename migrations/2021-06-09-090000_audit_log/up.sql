@@ -0,0 +1,28 @@
+-- Your SQL goes here
+CREATE TABLE audit_log (
+    audit_id BIGSERIAL PRIMARY KEY,
+    occurred_at timestamptz NOT NULL DEFAULT NOW(),
+    user_id UUID,
+    client TEXT,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    route TEXT,
+    status SMALLINT NOT NULL,
+    request_id TEXT,
+    before JSONB,
+    after JSONB
+);
+
+CREATE INDEX audit_log_occurred_at ON audit_log (occurred_at);
+CREATE INDEX audit_log_user_id ON audit_log (user_id, occurred_at);
+
+-- Entries can only be added, or deleted by the retention job once they're old enough
+CREATE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_append_only
+    BEFORE UPDATE OR TRUNCATE ON audit_log
+    FOR EACH STATEMENT EXECUTE PROCEDURE audit_log_append_only();
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE plans (
+    plan_id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    max_cameras INTEGER,
+    max_storage_gb INTEGER,
+    retention_days INTEGER,
+    max_streams INTEGER,
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE users ADD COLUMN plan_id INTEGER REFERENCES plans(plan_id) ON DELETE SET NULL;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE announcement_reads;
+DROP TABLE announcements;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+CREATE TABLE announcements (
+    announcement_id SERIAL PRIMARY KEY,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    starts_at timestamptz NOT NULL DEFAULT NOW(),
+    ends_at timestamptz,
+    created_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX announcements_starts_at ON announcements (starts_at);
+
+CREATE TABLE announcement_reads (
+    announcement_id INTEGER NOT NULL REFERENCES announcements(announcement_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    read_at timestamptz NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (announcement_id, user_id)
+);
+
+CREATE INDEX announcement_reads_user_id ON announcement_reads (user_id);
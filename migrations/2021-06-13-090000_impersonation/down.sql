@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE audit_log DROP COLUMN impersonated_by;
+DROP TABLE impersonation_tokens;
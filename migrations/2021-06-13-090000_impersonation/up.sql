@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE impersonation_tokens (
+    impersonation_token UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    admin_id UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    reason TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    expires_at timestamptz NOT NULL,
+    revoked_at timestamptz
+);
+
+CREATE INDEX impersonation_tokens_user_id ON impersonation_tokens (user_id);
+
+ALTER TABLE audit_log ADD COLUMN impersonated_by UUID;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE account_exports (
+    export_id SERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    include_media BOOLEAN NOT NULL DEFAULT FALSE,
+    status TEXT NOT NULL DEFAULT 'pending',
+    size_bytes BIGINT,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    completed_at timestamptz,
+    expires_at timestamptz
+);
+
+CREATE INDEX account_exports_user_id ON account_exports (user_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE storage_recounts;
+DROP TABLE storage_daily;
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE storage_daily (
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    media_type TEXT NOT NULL,
+    day DATE NOT NULL,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    files BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (camera_id, media_type, day)
+);
+
+CREATE TABLE storage_recounts (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    recounted_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP CONSTRAINT users_tenant_id_username_key;
+ALTER TABLE users ADD CONSTRAINT users_username_key UNIQUE (username);
+DROP INDEX users_tenant_id;
+ALTER TABLE users DROP COLUMN tenant_id;
+DROP TABLE tenants;
@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE tenants (
+    tenant_id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    slug TEXT NOT NULL UNIQUE,
+    hostname TEXT UNIQUE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Everyone so far shares the deployment, so they all start in the same tenant
+INSERT INTO tenants (tenant_id, name, slug) VALUES (1, 'Default', 'default');
+SELECT setval('tenants_tenant_id_seq', 1);
+
+ALTER TABLE users ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES tenants(tenant_id);
+CREATE INDEX users_tenant_id ON users (tenant_id);
+
+-- Usernames only have to be unique within a tenant
+ALTER TABLE users DROP CONSTRAINT users_username_key;
+ALTER TABLE users ADD CONSTRAINT users_tenant_id_username_key UNIQUE (tenant_id, username);
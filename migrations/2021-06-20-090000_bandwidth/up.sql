@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE bandwidth_daily (
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    upload_bytes BIGINT NOT NULL DEFAULT 0,
+    download_bytes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (camera_id, day)
+);
@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE stream_credentials (
+    credential_id SERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    activated_at timestamptz,
+    revoked_at timestamptz
+);
+
+CREATE INDEX stream_credentials_camera_id ON stream_credentials (camera_id, credential_id);
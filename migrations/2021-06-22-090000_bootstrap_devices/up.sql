@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE bootstrap_devices (
+    hardware_id TEXT PRIMARY KEY,
+    claim_token_hash TEXT NOT NULL,
+    imported_at timestamptz NOT NULL DEFAULT now(),
+    user_id UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    name TEXT,
+    camera_id UUID UNIQUE REFERENCES cameras(camera_id) ON DELETE SET NULL,
+    exchanged_at timestamptz
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE camera_firmware;
+DROP TABLE firmware_releases;
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+CREATE TABLE firmware_releases (
+    firmware_id SERIAL PRIMARY KEY,
+    model TEXT NOT NULL,
+    version TEXT NOT NULL,
+    size_bytes BIGINT NOT NULL DEFAULT 0,
+    sha256 TEXT NOT NULL DEFAULT '',
+    rollout_percent INTEGER NOT NULL DEFAULT 0 CHECK (rollout_percent BETWEEN 0 AND 100),
+    created_at timestamptz NOT NULL DEFAULT now(),
+    UNIQUE (model, version)
+);
+
+CREATE TABLE camera_firmware (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    model TEXT NOT NULL,
+    version TEXT NOT NULL,
+    firmware_id INTEGER REFERENCES firmware_releases(firmware_id) ON DELETE SET NULL,
+    status TEXT,
+    bytes_downloaded BIGINT,
+    error TEXT,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX camera_firmware_firmware_id ON camera_firmware (firmware_id);
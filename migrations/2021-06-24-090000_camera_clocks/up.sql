@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE camera_clocks (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    drift_ms BIGINT NOT NULL,
+    drifted BOOLEAN NOT NULL DEFAULT false,
+    checked_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE camera_logs (
+    log_id BIGSERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    logged_at timestamptz NOT NULL,
+    level TEXT NOT NULL,
+    message TEXT NOT NULL,
+    received_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX camera_logs_camera_id_logged_at ON camera_logs (camera_id, logged_at);
+CREATE INDEX camera_logs_received_at ON camera_logs (received_at);
@@ -0,0 +1,27 @@
+-- Your SQL goes here
+CREATE TABLE snapshot_schedules (
+    schedule_id SERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    expression TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    next_run_at timestamptz NOT NULL,
+    last_run_at timestamptz,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX snapshot_schedules_next_run_at ON snapshot_schedules (next_run_at) WHERE enabled;
+
+CREATE TABLE scheduled_snapshots (
+    snapshot_id SERIAL PRIMARY KEY,
+    schedule_id INTEGER NOT NULL REFERENCES snapshot_schedules(schedule_id) ON DELETE CASCADE,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    command_id INTEGER,
+    scheduled_for timestamptz NOT NULL,
+    requested_at timestamptz NOT NULL DEFAULT now(),
+    status TEXT NOT NULL DEFAULT 'pending',
+    image_id BIGINT,
+    checked_at timestamptz
+);
+
+CREATE INDEX scheduled_snapshots_schedule_id ON scheduled_snapshots (schedule_id, snapshot_id);
+CREATE INDEX scheduled_snapshots_pending ON scheduled_snapshots (requested_at) WHERE status = 'pending';
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+-- How far the primary has got through each stream it sends to the secondary
+CREATE TABLE replication_cursors (
+    stream TEXT PRIMARY KEY,
+    last_updated_at timestamptz,
+    last_id TEXT,
+    synced_at timestamptz,
+    last_error TEXT,
+    last_attempt_at timestamptz
+);
+
+-- Which local event each event replicated to the secondary became, as the primary's event IDs can clash with its own
+CREATE TABLE replicated_events (
+    origin TEXT NOT NULL,
+    origin_event_id INTEGER NOT NULL,
+    event_id INTEGER NOT NULL REFERENCES events(event_id) ON DELETE CASCADE,
+    PRIMARY KEY (origin, origin_event_id)
+);
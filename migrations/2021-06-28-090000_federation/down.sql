@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE remote_cameras;
+DROP TABLE remote_shares;
+DROP TABLE federation_peers;
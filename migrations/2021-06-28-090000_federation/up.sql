@@ -0,0 +1,42 @@
+-- Your SQL goes here
+-- Other servers this one has a handshake with. Each side keeps the token the other presents (hashed) and the one
+-- it presents to the other
+CREATE TABLE federation_peers (
+    peer_id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    url TEXT NOT NULL UNIQUE,
+    inbound_token_hash TEXT NOT NULL UNIQUE,
+    outbound_token TEXT,
+    status TEXT NOT NULL DEFAULT 'pending',
+    created_at timestamptz NOT NULL DEFAULT now(),
+    activated_at timestamptz
+);
+
+-- Cameras on this server shared with users on a peer
+CREATE TABLE remote_shares (
+    share_id SERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    peer_id INTEGER NOT NULL REFERENCES federation_peers(peer_id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    shared_by UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    access_token_hash TEXT NOT NULL UNIQUE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    revoked_at timestamptz
+);
+
+CREATE INDEX remote_shares_camera_id ON remote_shares (camera_id);
+
+-- Cameras on a peer shared with users on this server
+CREATE TABLE remote_cameras (
+    remote_camera_id SERIAL PRIMARY KEY,
+    peer_id INTEGER NOT NULL REFERENCES federation_peers(peer_id) ON DELETE CASCADE,
+    share_id INTEGER NOT NULL,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    camera_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    UNIQUE (peer_id, share_id)
+);
+
+CREATE INDEX remote_cameras_user_id ON remote_cameras (user_id);
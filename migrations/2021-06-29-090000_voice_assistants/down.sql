@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE camera_streams;
+DROP TABLE oauth_codes;
+DROP TABLE oauth_links;
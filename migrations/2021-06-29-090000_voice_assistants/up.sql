@@ -0,0 +1,30 @@
+-- Your SQL goes here
+-- Accounts linked to a voice assistant. Relinking replaces the link, so each user has at most one per assistant
+CREATE TABLE oauth_links (
+    link_id SERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    assistant TEXT NOT NULL,
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    access_token_hash TEXT NOT NULL UNIQUE,
+    access_token_expires_at timestamptz NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz,
+    UNIQUE (user_id, assistant)
+);
+
+-- Authorization codes waiting to be swapped for tokens. Each can only be used once
+CREATE TABLE oauth_codes (
+    code_hash TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    assistant TEXT NOT NULL,
+    redirect_uri TEXT NOT NULL,
+    expires_at timestamptz NOT NULL
+);
+
+-- Where a voice assistant can play the camera's live video from, set by its owner
+CREATE TABLE camera_streams (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    protocol TEXT NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- What NVRs log in to the ONVIF facade with. The password is kept as it is, since WS-Security and RTSP digests
+-- need it to check what the NVR sends
+CREATE TABLE onvif_credentials (
+    user_id UUID PRIMARY KEY REFERENCES users(user_id) ON DELETE CASCADE,
+    username TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
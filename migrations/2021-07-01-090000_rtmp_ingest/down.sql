@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE recordings;
+DROP TABLE stream_keys;
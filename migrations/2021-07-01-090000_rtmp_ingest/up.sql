@@ -0,0 +1,22 @@
+-- Your SQL goes here
+-- What encoders push a camera's video with. Only the hash is kept, the key is shown once when it's made
+CREATE TABLE stream_keys (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    stream_key_hash TEXT NOT NULL UNIQUE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz
+);
+
+-- Video pushed by a camera, a segment at a time. The video itself is stored on disk as
+-- <recordings_directory>/<camera_id>/<recording_id>. ended_at is NULL while the segment is still being written
+CREATE TABLE recordings (
+    recording_id SERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    source TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size_bytes BIGINT NOT NULL DEFAULT 0,
+    started_at timestamptz NOT NULL DEFAULT now(),
+    ended_at timestamptz
+);
+
+CREATE INDEX recordings_camera_id_started_at ON recordings (camera_id, started_at);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- What SRT callers encrypt a camera's video with. Kept as it is, not hashed, because the key that unwraps each
+-- connection's stream keys is derived from it
+CREATE TABLE srt_passphrases (
+    camera_id UUID PRIMARY KEY REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    passphrase TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    last_used_at timestamptz
+);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- What someone a camera is shared with can do besides watching. Owners can always do everything
+ALTER TABLE users_cameras ADD COLUMN can_talk BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Images in the archive have faces blurred. Stored images are left as they are
+ALTER TABLE account_exports ADD COLUMN blur_faces BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,23 @@
+-- Your SQL goes here
+-- Keeps footage from being deleted by plan retention or purging, e.g. for a legal hold. A hold is either on one
+-- item (image IDs are when they were taken, audio and recording IDs are theirs) or on everything between two times
+CREATE TABLE media_holds (
+    hold_id SERIAL PRIMARY KEY,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    user_id UUID REFERENCES users(user_id) ON DELETE SET NULL,
+    -- Only admins can release holds admins placed
+    placed_by_admin BOOLEAN NOT NULL DEFAULT false,
+    media_type TEXT,
+    media_id BIGINT,
+    starts_at timestamptz,
+    ends_at timestamptz,
+    reason TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT NOW(),
+    CHECK (
+        (media_type IS NOT NULL AND media_id IS NOT NULL AND starts_at IS NULL AND ends_at IS NULL)
+        OR (media_type IS NULL AND media_id IS NULL AND starts_at IS NOT NULL AND ends_at IS NOT NULL
+            AND starts_at <= ends_at)
+    )
+);
+
+CREATE INDEX media_holds_camera_id ON media_holds (camera_id);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE cameras DROP COLUMN tags;
+ALTER TABLE cameras DROP COLUMN location;
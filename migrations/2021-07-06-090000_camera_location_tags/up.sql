@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Where the camera is and how it's grouped, e.g. from an installer's list of cameras registered in bulk
+ALTER TABLE cameras ADD COLUMN location TEXT;
+ALTER TABLE cameras ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER notes_search_vector ON notes;
+DROP FUNCTION notes_search_vector_trigger();
+
+CREATE OR REPLACE FUNCTION refresh_event_search_vector(target_event_id integer) RETURNS void AS $$
+    UPDATE events
+    SET search_vector = to_tsvector('english',
+        events.event_type || ' ' || events.severity || ' ' || cameras.name || ' ' ||
+        coalesce((SELECT string_agg(detections.label, ' ') FROM detections WHERE detections.event_id = events.event_id), '')
+    )
+    FROM cameras
+    WHERE cameras.camera_id = events.camera_id AND events.event_id = target_event_id;
+$$ LANGUAGE sql;
+
+SELECT refresh_event_search_vector(event_id) FROM events WHERE event_id IN (SELECT event_id FROM notes);
+
+DROP TABLE notes;
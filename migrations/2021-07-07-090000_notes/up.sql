@@ -0,0 +1,55 @@
+-- Your SQL goes here
+-- Text users leave on a camera, on one of its events, or at a moment in its footage. Everyone with access to the
+-- camera can read them, only whoever wrote one can change it
+CREATE TABLE notes (
+    note_id SERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    camera_id UUID NOT NULL REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    -- Kept when the event is deleted by retention, as noted_at still says when it happened
+    event_id INTEGER REFERENCES events(event_id) ON DELETE SET NULL,
+    -- When in the footage the note is about. Set to the event's occurred_at for notes on events, NULL for notes on
+    -- the camera as a whole
+    noted_at timestamptz,
+    body TEXT NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX notes_camera_id_noted_at ON notes (camera_id, noted_at);
+CREATE INDEX notes_event_id ON notes (event_id);
+CREATE INDEX notes_search ON notes USING GIN (to_tsvector('english', body));
+
+SELECT diesel_manage_updated_at('notes');
+
+-- Notes on an event are searched along with it
+CREATE OR REPLACE FUNCTION refresh_event_search_vector(target_event_id integer) RETURNS void AS $$
+    UPDATE events
+    SET search_vector = to_tsvector('english',
+        events.event_type || ' ' || events.severity || ' ' || cameras.name || ' ' ||
+        coalesce((SELECT string_agg(detections.label, ' ') FROM detections WHERE detections.event_id = events.event_id), '') || ' ' ||
+        coalesce((SELECT string_agg(notes.body, ' ') FROM notes WHERE notes.event_id = events.event_id), '')
+    )
+    FROM cameras
+    WHERE cameras.camera_id = events.camera_id AND events.event_id = target_event_id;
+$$ LANGUAGE sql;
+
+CREATE FUNCTION notes_search_vector_trigger() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        IF OLD.event_id IS NOT NULL THEN
+            PERFORM refresh_event_search_vector(OLD.event_id);
+        END IF;
+    ELSE
+        IF NEW.event_id IS NOT NULL THEN
+            PERFORM refresh_event_search_vector(NEW.event_id);
+        END IF;
+        IF TG_OP = 'UPDATE' AND OLD.event_id IS NOT NULL AND OLD.event_id IS DISTINCT FROM NEW.event_id THEN
+            PERFORM refresh_event_search_vector(OLD.event_id);
+        END IF;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notes_search_vector AFTER INSERT OR UPDATE OF event_id, body OR DELETE ON notes
+    FOR EACH ROW EXECUTE PROCEDURE notes_search_vector_trigger();
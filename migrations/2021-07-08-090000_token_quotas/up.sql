@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- What each token used per day, for the quotas in [limits]. Tokens are kept as their SHA-256, in hex. Only tokens
+-- that were found are counted, and their rows go with the user or camera they're for
+CREATE TABLE token_usage_daily (
+    token_hash TEXT NOT NULL,
+    day DATE NOT NULL,
+    -- owner or camera
+    scope TEXT NOT NULL,
+    -- Who the token is for, the user for owner tokens and the camera for camera tokens
+    user_id UUID REFERENCES users(user_id) ON DELETE CASCADE,
+    camera_id UUID REFERENCES cameras(camera_id) ON DELETE CASCADE,
+    requests BIGINT NOT NULL DEFAULT 0,
+    bytes BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (token_hash, day),
+    CHECK ((scope = 'owner' AND user_id IS NOT NULL AND camera_id IS NULL)
+        OR (scope = 'camera' AND camera_id IS NOT NULL AND user_id IS NULL))
+);
+
+CREATE INDEX token_usage_daily_day ON token_usage_daily (day);
+CREATE INDEX token_usage_daily_user ON token_usage_daily (user_id);
+CREATE INDEX token_usage_daily_camera ON token_usage_daily (camera_id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+-- Passwords can't be got back from their HA1, so credentials have to be made again
+DELETE FROM onvif_credentials;
+ALTER TABLE onvif_credentials ADD COLUMN password TEXT NOT NULL;
+ALTER TABLE onvif_credentials DROP COLUMN ha1;
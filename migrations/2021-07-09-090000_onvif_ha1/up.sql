@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Only the HA1 of ONVIF passwords is kept, MD5 of username:realm:password, which RTSP digests are checked against.
+-- The realm is rtsp::REALM
+ALTER TABLE onvif_credentials ADD COLUMN ha1 TEXT;
+UPDATE onvif_credentials SET ha1 = md5(username || ':camera-server:' || password);
+ALTER TABLE onvif_credentials ALTER COLUMN ha1 SET NOT NULL;
+ALTER TABLE onvif_credentials DROP COLUMN password;
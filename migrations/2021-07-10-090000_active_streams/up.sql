@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Every stream open on any instance, for max_streams in plans. Each instance marks its own as seen every usage
+-- flush, so ones left behind by an instance that died stop counting once they haven't been seen for a while
+CREATE TABLE active_streams (
+    stream_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+    -- websocket, event_stream, talk or rtsp
+    kind TEXT NOT NULL,
+    started_at timestamptz NOT NULL DEFAULT now(),
+    seen_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX active_streams_user ON active_streams (user_id, seen_at);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE camera_commands
+    DROP COLUMN created_at,
+    DROP COLUMN expires_at,
+    DROP COLUMN image_id;
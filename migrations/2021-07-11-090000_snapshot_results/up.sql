@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Snapshots are looked up by their command, so the image the camera uploads for one is recorded on it. Commands
+-- that are only worth carrying out for a while, like snapshots, stop being handed out at expires_at
+ALTER TABLE camera_commands
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN expires_at timestamptz,
+    ADD COLUMN image_id BIGINT;
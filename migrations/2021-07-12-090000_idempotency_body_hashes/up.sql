@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Retries have to send the same body as the first request with their key. Keys claimed before this have no hash,
+-- and only have their method and URI checked
+ALTER TABLE idempotency_keys ADD COLUMN body_hash text;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Whether faces are blurred on the images a peer fetches for the share
+ALTER TABLE remote_shares ADD COLUMN blur_faces boolean NOT NULL DEFAULT false;
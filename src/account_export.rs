@@ -0,0 +1,889 @@
+use crate::{
+    api_error::ApiError,
+    audio,
+    custody::{self, Manifest},
+    event::{users_events_query, Event, EventFilter},
+    face_blur, jobs,
+    media_store::{media_store, MediaStore},
+    settings::settings,
+    timezone::{self, Attachment},
+    user_tokens::UserToken,
+    users_cameras::{in_owners_tenant, IN_OWNERS_TENANT},
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{account_exports, audio_clips, events, users, users_cameras};
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Text, Uuid as SqlUuid};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::http::{ContentType, Status};
+use rocket::response::{Content, Stream};
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::time::Duration;
+
+pub const PENDING_STATUS: &str = "pending";
+pub const READY_STATUS: &str = "ready";
+/// The last attempt to build it failed. It's tried again like any other job, so it can still become ready.
+pub const FAILED_STATUS: &str = "failed";
+/// It was ready, but it's past expires_at and has been deleted.
+pub const EXPIRED_STATUS: &str = "expired";
+
+/// How many events are loaded from the database at a time while exporting.
+pub const EXPORT_BATCH_SIZE: i64 = 500;
+
+/// The tables exported to tables/<table>.jsonl, and which of their columns. Nothing else is, so a new table or
+/// column isn't exported until it's added here. users is in profile.json instead, and passwords, tokens, token
+/// hashes and secrets are left out, as they'd let anyone who got hold of the archive log in as the user or act as
+/// their cameras, webhooks and assistants.
+const EXPORTED_TABLES: [(&str, &[&str]); 24] = [
+    (
+        "announcement_reads",
+        &["announcement_id", "user_id", "read_at"],
+    ),
+    (
+        "audit_log",
+        &[
+            "audit_id",
+            "occurred_at",
+            "user_id",
+            "client",
+            "method",
+            "path",
+            "route",
+            "status",
+            "request_id",
+        ],
+    ),
+    (
+        "bootstrap_devices",
+        &[
+            "hardware_id",
+            "imported_at",
+            "user_id",
+            "name",
+            "camera_id",
+            "exchanged_at",
+        ],
+    ),
+    (
+        "digest_settings",
+        &[
+            "user_id",
+            "enabled",
+            "recipient",
+            "send_hour",
+            "replaces_alerts",
+            "last_sent_at",
+        ],
+    ),
+    (
+        "email_alerts",
+        &[
+            "user_id",
+            "camera_id",
+            "recipients",
+            "event_types",
+            "attach_snapshot",
+            "throttle_minutes",
+            "last_alerted_at",
+            "min_severity",
+        ],
+    ),
+    (
+        "event_acknowledgements",
+        &["user_id", "event_id", "acknowledged_at"],
+    ),
+    (
+        "event_holds",
+        &["hold_id", "event_id", "user_id", "reason", "created_at"],
+    ),
+    (
+        "media_holds",
+        &[
+            "hold_id",
+            "camera_id",
+            "user_id",
+            "placed_by_admin",
+            "media_type",
+            "media_id",
+            "starts_at",
+            "ends_at",
+            "reason",
+            "created_at",
+        ],
+    ),
+    (
+        "mode_schedules",
+        &["schedule_id", "user_id", "mode", "at_time", "days_of_week"],
+    ),
+    (
+        "notes",
+        &[
+            "note_id",
+            "user_id",
+            "camera_id",
+            "event_id",
+            "noted_at",
+            "body",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "notification_preferences",
+        &[
+            "user_id",
+            "camera_id",
+            "push_enabled",
+            "event_types",
+            "offline_alerts",
+            "armed_modes",
+            "min_severity",
+            "tamper_overrides",
+        ],
+    ),
+    (
+        "notifications",
+        &[
+            "notification_id",
+            "user_id",
+            "camera_id",
+            "event_id",
+            "channel",
+            "title",
+            "body",
+            "sent",
+            "attempts",
+            "created_at",
+            "rule_id",
+            "event_count",
+            "last_event_at",
+        ],
+    ),
+    (
+        "oauth_links",
+        &[
+            "link_id",
+            "user_id",
+            "assistant",
+            "access_token_expires_at",
+            "created_at",
+            "last_used_at",
+        ],
+    ),
+    ("onvif_credentials", &["user_id", "username", "created_at"]),
+    ("push_tokens", &["push_token_id", "user_id", "platform"]),
+    (
+        "remote_cameras",
+        &[
+            "remote_camera_id",
+            "peer_id",
+            "share_id",
+            "user_id",
+            "camera_id",
+            "name",
+            "created_at",
+        ],
+    ),
+    (
+        "rules",
+        &[
+            "rule_id",
+            "user_id",
+            "name",
+            "camera_id",
+            "event_types",
+            "min_confidence",
+            "start_time",
+            "end_time",
+            "channels",
+            "enabled",
+            "modes",
+            "min_severity",
+            "cooldown_seconds",
+            "trigger_url",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "sms_settings",
+        &[
+            "user_id",
+            "phone_number",
+            "enabled",
+            "critical_alerts",
+            "offline_alerts",
+            "monthly_cap",
+        ],
+    ),
+    (
+        "token_usage_daily",
+        &["day", "scope", "user_id", "camera_id", "requests", "bytes"],
+    ),
+    (
+        "usage_daily",
+        &[
+            "user_id",
+            "day",
+            "api_calls",
+            "upload_bytes",
+            "stream_seconds",
+        ],
+    ),
+    ("user_modes", &["user_id", "mode", "changed_at"]),
+    ("user_presence", &["user_id", "home", "changed_at"]),
+    ("users_cameras", &SHARE_COLUMNS),
+    (
+        "webhooks",
+        &[
+            "webhook_id",
+            "user_id",
+            "url",
+            "event_types",
+            "min_severity",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+];
+
+/// What cameras.jsonl has for each camera.
+const CAMERA_COLUMNS: [&str; 9] = [
+    "camera_id",
+    "name",
+    "online",
+    "last_seen_at",
+    "deleted_at",
+    "created_at",
+    "updated_at",
+    "location",
+    "tags",
+];
+
+/// What shares.jsonl and tables/users_cameras.jsonl have for each share.
+const SHARE_COLUMNS: [&str; 5] = [
+    "users_cameras_id",
+    "camera_id",
+    "user_id",
+    "deleted_at",
+    "can_talk",
+];
+
+/// An archive of everything the server holds about a user, built in the background after POST /Account/Export.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct AccountExport {
+    pub export_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    /// Whether the cameras' images and audio clips are in the archive, not just listed.
+    pub include_media: bool,
+    /// pending, ready, failed or expired.
+    pub status: String,
+    pub size_bytes: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// When the archive is deleted. Set once it's ready.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Whether faces are blurred on the images in the archive.
+    pub blur_faces: bool,
+}
+
+#[derive(Insertable)]
+#[table_name = "account_exports"]
+pub struct InsertableAccountExport {
+    pub user_id: uuid::Uuid,
+    pub include_media: bool,
+    pub blur_faces: bool,
+}
+
+/// Sent with POST /Account/Export.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewAccountExport {
+    /// Put the images and audio clips in the archive too, which can make it very big. Defaults to false, which
+    /// only lists them.
+    #[serde(default)]
+    pub include_media: bool,
+    /// Blur every face on the images in the archive, for sharing them publicly. Needs include_media, and face
+    /// detection set up on the server. The stored images are left as they are.
+    #[serde(default)]
+    pub blur_faces: bool,
+}
+
+/// The images and audio clips of one camera, as listed in media.json.
+#[derive(Serialize)]
+struct CameraMedia {
+    images: Vec<u64>,
+    audio_clips: Vec<audio::AudioClip>,
+}
+
+#[derive(QueryableByName)]
+struct JsonRow {
+    #[sql_type = "Text"]
+    row: String,
+}
+
+/// Where account exports are written, set with export_directory in [storage]. Defaults to exports.
+pub fn export_directory() -> String {
+    settings().storage.export_directory.clone()
+}
+
+/// How long (in hours) exports can be downloaded for, set with export_expiry_hours in [limits]. Defaults to 72.
+pub fn export_expiry_hours() -> i64 {
+    settings().limits.export_expiry_hours
+}
+
+pub fn archive_path(export_id: i32) -> String {
+    format!("{}/{}.tar.gz", export_directory(), export_id)
+}
+
+pub fn get(export_id: i32, connection: &PgConnection) -> QueryResult<AccountExport> {
+    account_exports::table
+        .find(export_id)
+        .get_result(connection)
+}
+
+/// Selects the columns of the table's rows that match the condition, each as a JSON object.
+fn json_rows_query(table: &str, columns: &[&str], condition: &str) -> String {
+    let columns = columns
+        .iter()
+        .map(|column| format!("\"{}\"", column))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "SELECT row_to_json(t)::text AS row FROM (SELECT {} FROM \"{}\" WHERE {}) t",
+        columns, table, condition
+    )
+}
+
+/// The rows the query selects with the user's ID as $1, one JSON object per line.
+fn users_rows(query: &str, user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Vec<u8>> {
+    // Table and column names are from EXPORTED_TABLES, not from the request
+    let rows = diesel::sql_query(query)
+        .bind::<SqlUuid, _>(user_id)
+        .load::<JsonRow>(connection)?;
+
+    let mut lines = Vec::new();
+    for row in rows {
+        lines.extend_from_slice(row.row.as_bytes());
+        lines.push(b'\n');
+    }
+
+    Ok(lines)
+}
+
+fn append_unlisted<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+
+    archive.append_data(&mut header, path, data)
+}
+
+/// Adds the file to the archive and the manifest.
+fn append<'a, W: Write>(
+    archive: &mut tar::Builder<W>,
+    manifest: &'a mut Manifest,
+    path: &str,
+    data: &[u8],
+) -> io::Result<&'a mut custody::ManifestFile> {
+    append_unlisted(archive, path, data)?;
+    Ok(manifest.add(path, data))
+}
+
+fn to_io_error(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+/// Builds the archive for the export as a gzipped tarball:
+///
+/// - profile.json, the user without their password hash
+/// - tables/<table>.jsonl, their rows from each of EXPORTED_TABLES, e.g. users_cameras and
+///   notification_preferences
+/// - cameras.jsonl, every camera they have access to
+/// - shares.jsonl, who else has access to the cameras they have access to
+/// - events.jsonl, their cameras' events
+/// - media.json, every image and audio clip their cameras have, by camera
+/// - media/images/<camera_id>/<image_id>.jpg and media/audio/<camera_id>/<audio_id>, if include_media is set, with
+///   faces blurred on the images if blur_faces is
+/// - manifest.json, the SHA-256 of every other file, and when the media in it was taken in
+/// - manifest.sig, the server's signature of manifest.json, which can be checked with GET /Exports/SigningKey
+fn write_archive(export: &AccountExport, file: File, connection: &PgConnection) -> io::Result<()> {
+    let user_id = export.user_id;
+    let mut archive =
+        tar::Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default()));
+    let mut manifest = Manifest {
+        export_id: export.export_id,
+        user_id,
+        requested_at: export.created_at,
+        generated_at: Utc::now(),
+        files: Vec::new(),
+    };
+
+    let profile = users::table
+        .find(user_id)
+        .select((
+            users::user_id,
+            users::username,
+            users::deleted_at,
+            users::disabled_at,
+            users::plan_id,
+            users::suspended_at,
+        ))
+        .get_result::<(
+            uuid::Uuid,
+            String,
+            Option<DateTime<Utc>>,
+            Option<DateTime<Utc>>,
+            Option<i32>,
+            Option<DateTime<Utc>>,
+        )>(connection)
+        .map_err(to_io_error)?;
+    let profile = json!({
+        "user_id": profile.0,
+        "username": profile.1,
+        "deleted_at": profile.2,
+        "disabled_at": profile.3,
+        "plan_id": profile.4,
+        "suspended_at": profile.5,
+        "exported_at": Utc::now(),
+    });
+    append(
+        &mut archive,
+        &mut manifest,
+        "profile.json",
+        &serde_json::to_vec_pretty(&profile)?,
+    )?;
+
+    for (table, columns) in &EXPORTED_TABLES {
+        let query = json_rows_query(table, columns, "user_id = $1");
+        let rows = users_rows(&query, user_id, connection).map_err(to_io_error)?;
+        append(
+            &mut archive,
+            &mut manifest,
+            &format!("tables/{}.jsonl", table),
+            &rows,
+        )?;
+    }
+
+    let camera_ids = users_cameras::table
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .select(users_cameras::camera_id)
+        .load::<uuid::Uuid>(connection)
+        .map_err(to_io_error)?;
+
+    let users_camera_ids = format!(
+        "camera_id IN (SELECT camera_id FROM users_cameras WHERE user_id = $1 AND deleted_at IS NULL AND {})",
+        IN_OWNERS_TENANT
+    );
+    for (path, query) in &[
+        (
+            "cameras.jsonl",
+            json_rows_query("cameras", &CAMERA_COLUMNS, &users_camera_ids),
+        ),
+        (
+            "shares.jsonl",
+            json_rows_query(
+                "users_cameras",
+                &SHARE_COLUMNS,
+                &format!("user_id <> $1 AND {}", users_camera_ids),
+            ),
+        ),
+    ] {
+        let lines = users_rows(query, user_id, connection).map_err(to_io_error)?;
+        append(&mut archive, &mut manifest, path, &lines)?;
+    }
+
+    let mut events_lines = Vec::new();
+    let mut last_event_id = 0;
+    loop {
+        let batch = users_events_query(user_id, &EventFilter::default())
+            .filter(events::event_id.gt(last_event_id))
+            .order(events::event_id)
+            .limit(EXPORT_BATCH_SIZE)
+            .load::<Event>(connection)
+            .map_err(to_io_error)?;
+
+        for event in &batch {
+            serde_json::to_writer(&mut events_lines, event)?;
+            events_lines.push(b'\n');
+        }
+
+        match batch.last() {
+            Some(event) if batch.len() as i64 == EXPORT_BATCH_SIZE => {
+                last_event_id = event.event_id
+            }
+            _ => break,
+        }
+    }
+    append(&mut archive, &mut manifest, "events.jsonl", &events_lines)?;
+
+    let face_detector =
+        match export.blur_faces {
+            true => Some(face_blur::face_detector().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, "Face detection isn't set up")
+            })?),
+            false => None,
+        };
+
+    let store = media_store();
+    let mut media = BTreeMap::new();
+
+    for camera_id in &camera_ids {
+        let images = match store.list_images(camera_id) {
+            Ok(images) => images,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let audio_clips = audio_clips::table
+            .filter(audio_clips::camera_id.eq(camera_id))
+            .order(audio_clips::audio_id)
+            .load::<audio::AudioClip>(connection)
+            .map_err(to_io_error)?;
+
+        if export.include_media {
+            for image_id in &images {
+                let mut image = Vec::new();
+                store
+                    .open_image(camera_id, *image_id)?
+                    .read_to_end(&mut image)?;
+                let original_sha256 = custody::sha256_hex(&image);
+                let blurred = face_detector.is_some();
+                if let Some(face_detector) = &face_detector {
+                    image =
+                        face_blur::blur_faces(&image, face_detector.as_ref()).map_err(|error| {
+                            io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("Image {} of camera {}: {}", image_id, camera_id, error),
+                            )
+                        })?;
+                }
+                let file = append(
+                    &mut archive,
+                    &mut manifest,
+                    &format!("media/images/{}/{}.jpg", camera_id, image_id),
+                    &image,
+                )?;
+                // Image IDs are when they were uploaded
+                file.ingested_at = Some(Utc.timestamp(*image_id as i64, 0));
+                if blurred {
+                    file.original_sha256 = Some(original_sha256);
+                }
+            }
+
+            for audio_clip in &audio_clips {
+                let audio = fs::read(audio::audio_path(camera_id, audio_clip.audio_id))?;
+                append(
+                    &mut archive,
+                    &mut manifest,
+                    &format!("media/audio/{}/{}", camera_id, audio_clip.audio_id),
+                    &audio,
+                )?
+                .ingested_at = Some(audio_clip.recorded_at);
+            }
+        }
+
+        media.insert(
+            *camera_id,
+            CameraMedia {
+                images,
+                audio_clips,
+            },
+        );
+    }
+    append(
+        &mut archive,
+        &mut manifest,
+        "media.json",
+        &serde_json::to_vec_pretty(&media)?,
+    )?;
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let signature = custody::sign(&manifest_json)
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+    append_unlisted(&mut archive, "manifest.json", &manifest_json)?;
+    append_unlisted(
+        &mut archive,
+        "manifest.sig",
+        &serde_json::to_vec_pretty(&signature)?,
+    )?;
+
+    archive.into_inner()?.finish()?.flush()
+}
+
+/// Builds the export's archive in export_directory(), and marks it as ready to download until
+/// export_expiry_hours() from now. It's written under a temporary name first, so a half written archive is never
+/// downloaded.
+pub fn build(export_id: i32, connection: &PgConnection) -> Result<(), String> {
+    let export = get(export_id, connection)
+        .map_err(|error| format!("Failed to get export {}: {}", export_id, error))?;
+
+    let path = archive_path(export_id);
+    let partial_path = format!("{}.partial", path);
+
+    let written = fs::create_dir_all(export_directory())
+        .and_then(|_| File::create(&partial_path))
+        .and_then(|file| write_archive(&export, file, connection))
+        .and_then(|_| fs::rename(&partial_path, &path))
+        .and_then(|_| fs::metadata(&path));
+
+    let update = diesel::update(account_exports::table.find(export_id));
+
+    match written {
+        Ok(metadata) => {
+            let now = Utc::now();
+            update
+                .set((
+                    account_exports::status.eq(READY_STATUS),
+                    account_exports::size_bytes.eq(metadata.len() as i64),
+                    account_exports::completed_at.eq(now),
+                    account_exports::expires_at
+                        .eq(now + ChronoDuration::hours(export_expiry_hours())),
+                ))
+                .execute(connection)
+                .map_err(|error| {
+                    format!("Failed to mark export {} as ready: {}", export_id, error)
+                })?;
+            Ok(())
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&partial_path);
+            if let Err(error) = update
+                .set(account_exports::status.eq(FAILED_STATUS))
+                .execute(connection)
+            {
+                error!(
+                    "Failed to mark export {} as failed! The error was {}",
+                    export_id, error
+                );
+            }
+            Err(format!("Failed to build export {}: {}", export_id, error))
+        }
+    }
+}
+
+/// Deletes the archives of exports past expires_at. Returns how many were deleted.
+pub fn delete_expired(connection: &PgConnection) -> QueryResult<usize> {
+    let expired = account_exports::table
+        .filter(account_exports::status.eq(READY_STATUS))
+        .filter(account_exports::expires_at.lt(Utc::now()))
+        .select(account_exports::export_id)
+        .load::<i32>(connection)?;
+
+    let mut deleted = 0;
+
+    for export_id in expired {
+        match fs::remove_file(archive_path(export_id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                error!(
+                    "Failed to delete expired export {}! The error was {}",
+                    export_id, error
+                );
+                continue;
+            }
+            _ => {}
+        }
+
+        deleted += diesel::update(account_exports::table.find(export_id))
+            .set(account_exports::status.eq(EXPIRED_STATUS))
+            .execute(connection)?;
+    }
+
+    Ok(deleted)
+}
+
+/// Starts the thread that deletes exports once they've expired.
+pub fn spawn_expiry_worker(database_url: String) {
+    worker::spawn_worker(
+        "Account export expiry",
+        Duration::from_secs(60 * 60),
+        database_url,
+        |connection| match delete_expired(connection) {
+            Ok(0) => {}
+            Ok(deleted) => info!("Deleted {} expired account exports", deleted),
+            Err(error) => error!("Failed to delete expired exports! The error was {}", error),
+        },
+    );
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get account exports! The error was {}", error);
+    ApiError {
+        error: "Failed to get account exports",
+        code: "get_account_exports_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Starts building an archive of everything the server holds about the user. Poll GET /Account/Exports until it's
+/// ready, then download it from GET /Account/Exports/<export_id>/Download. Users can only have one export
+/// pending at a time.
+#[openapi]
+#[post("/Account/Export", format = "json", data = "<new_export>")]
+pub fn start_export(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_export: Json<NewAccountExport>,
+) -> Result<Json<AccountExport>, ApiError> {
+    if new_export.blur_faces && !new_export.include_media {
+        return Err(ApiError {
+            error: "Faces can only be blurred when media is included",
+            code: "face_blur_needs_media",
+            status: Status::UnprocessableEntity,
+            field: Some("blur_faces"),
+        });
+    }
+    if new_export.blur_faces && !face_blur::face_blurring_enabled() {
+        return Err(ApiError {
+            error: "Face blurring isn't set up on this server",
+            code: "face_blur_not_configured",
+            status: Status::UnprocessableEntity,
+            field: Some("blur_faces"),
+        });
+    }
+
+    let pending = account_exports::table
+        .filter(account_exports::user_id.eq(user_token.user_id))
+        .filter(account_exports::status.eq(PENDING_STATUS))
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    if pending > 0 {
+        return Err(ApiError {
+            error: "An export is already being built",
+            code: "export_in_progress",
+            status: Status::Conflict,
+            field: None,
+        });
+    }
+
+    conn.transaction(|| {
+        let export = diesel::insert_into(account_exports::table)
+            .values(InsertableAccountExport {
+                user_id: user_token.user_id,
+                include_media: new_export.include_media,
+                blur_faces: new_export.blur_faces,
+            })
+            .get_result::<AccountExport>(&*conn)?;
+
+        jobs::enqueue(
+            jobs::ACCOUNT_EXPORT_JOB,
+            json!({ "export_id": export.export_id }),
+            Utc::now(),
+            &conn,
+        )?;
+
+        Ok(export)
+    })
+    .map(Json)
+    .map_err(|error: diesel::result::Error| {
+        error!(
+            "Failed to start export for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to start export",
+            code: "start_export_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// The user's exports, newest first, including expired ones.
+#[openapi]
+#[get("/Account/Exports")]
+pub fn get_exports(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<AccountExport>>, ApiError> {
+    account_exports::table
+        .filter(account_exports::user_id.eq(user_token.user_id))
+        .order(account_exports::export_id.desc())
+        .load::<AccountExport>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Downloads a ready export as a .tar.gz, named after when it was asked for in the user's timezone.
+#[openapi(skip)]
+#[get("/Account/Exports/<export_id>/Download")]
+pub fn download_export(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    export_id: i32,
+) -> Result<Attachment<Content<Stream<File>>>, ApiError> {
+    let export = account_exports::table
+        .filter(account_exports::export_id.eq(export_id))
+        .filter(account_exports::user_id.eq(user_token.user_id))
+        .get_result::<AccountExport>(&*conn)
+        .optional()
+        .map_err(database_error)?
+        .ok_or(ApiError {
+            error: "Export not found",
+            code: "export_not_found",
+            status: Status::NotFound,
+            field: None,
+        })?;
+
+    let expired = export
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= Utc::now());
+
+    if export.status != READY_STATUS || expired {
+        return Err(ApiError {
+            error: "Export isn't ready to download",
+            code: "export_not_ready",
+            status: Status::Conflict,
+            field: None,
+        });
+    }
+
+    let file_name = format!(
+        "account-export-{}.tar.gz",
+        timezone::file_name_time(
+            export.created_at,
+            timezone::user_timezone(user_token.user_id, &conn)
+        )
+    );
+
+    File::open(archive_path(export_id))
+        .map(|file| Attachment {
+            file_name,
+            response: Content(ContentType::new("application", "gzip"), Stream::from(file)),
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to open export {}! The error was {}",
+                export_id, error
+            );
+            ApiError {
+                error: "Failed to download export",
+                code: "download_export_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -0,0 +1,140 @@
+use crate::{
+    api_error::ApiError, database::ReadDbConn, event::get_users_event, user_tokens::UserToken,
+    users_cameras::in_owners_tenant, CameraServerDbConn,
+};
+
+use super::schema::{event_acknowledgements, events, users, users_cameras};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Records that a user has seen and dealt with an event. Each user acknowledges events separately,
+/// so everyone who shares a camera can see who already handled an alert.
+#[derive(Queryable, Insertable, Deserialize, Serialize)]
+#[table_name = "event_acknowledgements"]
+pub struct EventAcknowledgement {
+    pub user_id: uuid::Uuid,
+    pub event_id: i32,
+    pub acknowledged_at: DateTime<Utc>,
+}
+
+/// Who acknowledged an event and when, as shown to other users of the camera.
+#[derive(Queryable, Deserialize, Serialize, JsonSchema)]
+pub struct Acknowledgement {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub acknowledged_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct UnreadCount {
+    pub unread: i64,
+}
+
+/// Acknowledges the event for the user. Acknowledging an event twice keeps the first acknowledgement.
+pub fn acknowledge(
+    user_id: uuid::Uuid,
+    event_id: i32,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::insert_into(event_acknowledgements::table)
+        .values(EventAcknowledgement {
+            user_id,
+            event_id,
+            acknowledged_at: Utc::now(),
+        })
+        .on_conflict_do_nothing()
+        .execute(connection)
+}
+
+/// Returns everyone who has acknowledged the event, earliest first.
+pub fn get_events_acknowledgements(
+    event_id: i32,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Acknowledgement>> {
+    event_acknowledgements::table
+        .inner_join(users::table.on(users::user_id.eq(event_acknowledgements::user_id)))
+        .filter(event_acknowledgements::event_id.eq(event_id))
+        .filter(users::deleted_at.is_null())
+        .select((
+            event_acknowledgements::user_id,
+            users::username,
+            event_acknowledgements::acknowledged_at,
+        ))
+        .order(event_acknowledgements::acknowledged_at)
+        .load::<Acknowledgement>(connection)
+}
+
+/// Counts the events from the user's cameras that the user hasn't acknowledged.
+pub fn count_unread(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<i64> {
+    events::table
+        .inner_join(users_cameras::table.on(users_cameras::camera_id.eq(events::camera_id)))
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .filter(
+            events::event_id.ne_all(
+                event_acknowledgements::table
+                    .filter(event_acknowledgements::user_id.eq(user_id))
+                    .select(event_acknowledgements::event_id),
+            ),
+        )
+        .count()
+        .get_result(connection)
+}
+
+#[openapi]
+#[post("/Events/<event_id>/Ack")]
+pub fn acknowledge_event(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    event_id: i32,
+) -> Result<Json<Vec<Acknowledgement>>, ApiError> {
+    get_users_event(user_token.user_id, event_id, &conn)?;
+
+    acknowledge(user_token.user_id, event_id, &conn)
+        .and_then(|_| get_events_acknowledgements(event_id, &conn))
+        .map(|acknowledgements| Json(acknowledgements))
+        .map_err(|error| {
+            error!(
+                "Failed to acknowledge event {} for user {}! The error was {}",
+                event_id, user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to acknowledge event",
+                code: "acknowledge_event_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Returns how many of the user's events they haven't acknowledged yet, for badges in the app.
+#[openapi]
+#[get("/Events/UnreadCount")]
+pub fn get_unread_count(
+    conn: ReadDbConn,
+    user_token: UserToken,
+) -> Result<Json<UnreadCount>, ApiError> {
+    count_unread(user_token.user_id, &conn)
+        .map(|unread| Json(UnreadCount { unread }))
+        .map_err(|error| {
+            error!(
+                "Failed to count unread events for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to count unread events",
+                code: "count_unread_events_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
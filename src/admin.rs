@@ -0,0 +1,39 @@
+use crate::{
+    enums::token_error::TokenError, impersonation, settings::settings, user_tokens::UserToken,
+};
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest},
+    Outcome, Request,
+};
+
+/// Users who can use /Admin routes, set with user_ids in [admin] as a comma separated list of user IDs. Defaults to
+/// nobody.
+pub fn admin_user_ids() -> Vec<uuid::Uuid> {
+    settings().admin.user_ids().filter_map(Result::ok).collect()
+}
+
+/// A UserToken for one of admin_user_ids(). Anyone else gets a 403.
+pub struct AdminToken {
+    pub user_id: uuid::Uuid,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let user_token = request.guard::<UserToken>()?;
+
+        // Impersonation is for seeing what the user sees, not for getting their privileges
+        if admin_user_ids().contains(&user_token.user_id)
+            && impersonation::impersonated_by(request).is_none()
+        {
+            Outcome::Success(AdminToken {
+                user_id: user_token.user_id,
+            })
+        } else {
+            Outcome::Failure((Status::Forbidden, TokenError::NotAdmin))
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use crate::{
+    detection::{self, validate_reported_detections, InsertableDetection, ReportedDetection},
+    event::{self, default_severity, dispatch_event, InsertableEvent, REPORTED_EVENT_TYPES},
+    media_store::{media_store, MediaStore},
+    settings::settings,
+    worker,
+    zone::{is_in_zones, load_zones},
+};
+
+use super::schema::{analysis_jobs, detections};
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+pub const MAX_ANALYSIS_ATTEMPTS: i32 = 3;
+pub const INITIAL_RETRY_DELAY_SECONDS: i64 = 10;
+
+/// Runs an image through some kind of object detection and returns what it found.
+pub trait Analyser: Send {
+    fn analyse(&self, image: &[u8]) -> Result<Vec<ReportedDetection>, String>;
+}
+
+/// What an inference service is expected to respond with, whether it's behind HTTP or a local process.
+#[derive(Deserialize, Serialize)]
+pub struct AnalysisResult {
+    pub detections: Vec<ReportedDetection>,
+}
+
+/// POSTs the image as image/jpeg to an inference service and reads an AnalysisResult back.
+pub struct HttpAnalyser {
+    pub client: reqwest::blocking::Client,
+    pub url: String,
+}
+
+impl Analyser for HttpAnalyser {
+    fn analyse(&self, image: &[u8]) -> Result<Vec<ReportedDetection>, String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "image/jpeg")
+            .body(image.to_vec())
+            .send()
+            .map_err(|error| error.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!(
+                "Analysis service responded with {}",
+                response.status()
+            ));
+        }
+
+        response
+            .json::<AnalysisResult>()
+            .map(|result| result.detections)
+            .map_err(|error| format!("Failed to parse analysis result: {}", error))
+    }
+}
+
+/// Analysis commands are killed if they take longer than this to print their result.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs a local program with the image on stdin. The program should print an AnalysisResult to stdout.
+pub struct CommandAnalyser {
+    pub command: String,
+}
+
+impl Analyser for CommandAnalyser {
+    fn analyse(&self, image: &[u8]) -> Result<Vec<ReportedDetection>, String> {
+        let mut child = Command::new(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|error| format!("Failed to run {}: {}", self.command, error))?;
+
+        // Written and read on threads of their own, as a program that prints before it has read all of stdin would
+        // otherwise fill the stdout pipe and wait for it to be read while this waits for it to take the rest
+        let mut stdin = child.stdin.take().expect("Child's stdin was piped");
+        let image = image.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&image));
+
+        let mut stdout = child.stdout.take().expect("Child's stdout was piped");
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = sender.send(stdout.read_to_end(&mut output).map(|_| output));
+        });
+
+        let output = match receiver.recv_timeout(COMMAND_TIMEOUT) {
+            Ok(output) => output,
+            Err(_) => {
+                // Closes its pipes too, so the threads finish
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "{} didn't finish within {} seconds",
+                    self.command,
+                    COMMAND_TIMEOUT.as_secs()
+                ));
+            }
+        }
+        .map_err(|error| format!("Failed to read output of {}: {}", self.command, error))?;
+
+        let status = child
+            .wait()
+            .map_err(|error| format!("Failed to wait for {}: {}", self.command, error))?;
+        if !status.success() {
+            return Err(format!("{} exited with {}", self.command, status));
+        }
+
+        // Programs that exit without reading all of the image are left to their output
+        if let Ok(Err(error)) = writer.join() {
+            debug!("{} didn't read all of the image: {}", self.command, error);
+        }
+
+        serde_json::from_slice::<AnalysisResult>(&output)
+            .map(|result| result.detections)
+            .map_err(|error| format!("Failed to parse analysis result: {}", error))
+    }
+}
+
+/// Returns the analyser configured with url or command in [analysis], or None if analysis is turned off.
+pub fn analyser() -> Option<Box<dyn Analyser>> {
+    let analysis = &settings().analysis;
+
+    if let Some(url) = analysis.url.clone() {
+        return Some(Box::new(HttpAnalyser {
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("Failed to build analysis HTTP client!"),
+            url,
+        }));
+    }
+
+    analysis
+        .command
+        .clone()
+        .map(|command| Box::new(CommandAnalyser { command }) as Box<dyn Analyser>)
+}
+
+pub fn analysis_enabled() -> bool {
+    let analysis = &settings().analysis;
+    analysis.url.is_some() || analysis.command.is_some()
+}
+
+/// Whether detections whose label is an event type (e.g. person) should raise an event, set with raise_events in
+/// [analysis].
+pub fn raise_events() -> bool {
+    settings().analysis.raise_events
+}
+
+/// How confident the analyser has to be before a detection raises an event, set with event_min_confidence in
+/// [analysis]. Defaults to 0.6.
+pub fn event_min_confidence() -> f32 {
+    settings().analysis.event_min_confidence
+}
+
+/// An image waiting to be analysed.
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "analysis_jobs"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct AnalysisJob {
+    pub job_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub image_id: i64,
+    pub attempts: i32,
+    pub done: bool,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "analysis_jobs"]
+pub struct InsertableAnalysisJob {
+    pub camera_id: uuid::Uuid,
+    pub image_id: i64,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// Queues a newly uploaded image for analysis. Does nothing if analysis is turned off.
+pub fn queue_analysis(
+    camera_id: uuid::Uuid,
+    image_id: u64,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    if !analysis_enabled() {
+        return Ok(());
+    }
+
+    diesel::insert_into(analysis_jobs::table)
+        .values(InsertableAnalysisJob {
+            camera_id,
+            image_id: image_id as i64,
+            next_attempt_at: Some(Utc::now()),
+        })
+        .execute(connection)
+        .map(|_| ())
+}
+
+fn read_image(camera_id: &uuid::Uuid, image_id: u64) -> Result<Vec<u8>, String> {
+    let mut image = media_store()
+        .open_image(camera_id, image_id)
+        .map_err(|error| format!("Failed to open image: {}", error))?;
+
+    let mut image_bytes = Vec::new();
+    image
+        .read_to_end(&mut image_bytes)
+        .map_err(|error| format!("Failed to read image: {}", error))?;
+
+    Ok(image_bytes)
+}
+
+/// Analyses the job's image and stores whatever was found against the image.
+/// If raise_events() is on, the most confident detection that is an event type, inside the camera's zones,
+/// and above event_min_confidence() raises an event, and the image's detections are attached to it.
+pub fn run_job(
+    analyser: &dyn Analyser,
+    job: &AnalysisJob,
+    connection: &PgConnection,
+) -> Result<(), String> {
+    let image = read_image(&job.camera_id, job.image_id as u64)?;
+    let reported_detections = analyser.analyse(&image)?;
+
+    validate_reported_detections(&reported_detections)
+        .map_err(|error| format!("Analyser returned a bad detection: {}", error.error))?;
+
+    if reported_detections.len() == 0 {
+        return Ok(());
+    }
+
+    let zones = load_zones(job.camera_id, connection)
+        .map_err(|error| format!("Failed to load zones: {}", error.error))?;
+
+    let event_detection = reported_detections
+        .iter()
+        .filter(|detection| {
+            REPORTED_EVENT_TYPES.contains(&detection.label.as_str())
+                && detection.confidence >= event_min_confidence()
+                && is_in_zones(&detection.bounding_box, &zones)
+        })
+        .max_by(|a, b| a.confidence.partial_cmp(&b.confidence).unwrap())
+        .map(|detection| (detection.label.clone(), detection.confidence));
+
+    // The detections and the event they raise are stored together, so a retried job never stores them twice
+    let event = in_transaction(
+        connection,
+        || {
+            let stored_detections = detection::insert(
+                reported_detections
+                    .into_iter()
+                    .map(|detection| {
+                        InsertableDetection::from_reported_detection(
+                            job.camera_id,
+                            None,
+                            Some(job.image_id),
+                            detection,
+                        )
+                    })
+                    .collect(),
+                connection,
+            )
+            .map_err(|error| format!("Failed to store detections: {}", error))?;
+
+            let (event_type, confidence) = match (raise_events(), event_detection) {
+                (true, Some(event_detection)) => event_detection,
+                _ => return Ok(None),
+            };
+
+            let event = event::insert(
+                InsertableEvent {
+                    camera_id: job.camera_id,
+                    // Image IDs are the seconds since epoch the image was uploaded at
+                    occurred_at: Utc.timestamp(job.image_id, 0),
+                    confidence,
+                    image_id: Some(job.image_id),
+                    severity: default_severity(&event_type).to_string(),
+                    audio_id: None,
+                    tamper_reason: None,
+                    event_type,
+                },
+                connection,
+            )
+            .map_err(|error| format!("Failed to store event: {}", error))?;
+
+            diesel::update(
+                detections::table.filter(
+                    detections::detection_id.eq_any(
+                        stored_detections
+                            .iter()
+                            .map(|detection| detection.detection_id)
+                            .collect::<Vec<i32>>(),
+                    ),
+                ),
+            )
+            .set(detections::event_id.eq(event.event_id))
+            .execute(connection)
+            .map_err(|error| format!("Failed to attach detections to event: {}", error))?;
+
+            Ok(Some(event))
+        },
+        |error| format!("Failed to commit detections: {}", error),
+    )?;
+
+    if let Some(event) = event {
+        dispatch_event(&event, connection);
+    }
+
+    Ok(())
+}
+
+/// Runs every analysis job that is due, and schedules a retry with exponential backoff for ones that fail.
+pub fn run_due(analyser: &dyn Analyser, connection: &PgConnection) -> QueryResult<()> {
+    let due_jobs = analysis_jobs::table
+        .filter(analysis_jobs::done.eq(false))
+        .filter(analysis_jobs::next_attempt_at.le(Utc::now()))
+        .order(analysis_jobs::next_attempt_at)
+        .limit(20)
+        .load::<AnalysisJob>(connection)?;
+
+    for mut job in due_jobs {
+        job.attempts += 1;
+
+        match run_job(analyser, &job, connection) {
+            Ok(()) => {
+                job.done = true;
+                job.next_attempt_at = None;
+                job.last_error = None;
+            }
+            Err(error) => {
+                job.last_error = Some(error);
+                job.next_attempt_at = worker::next_attempt_at(
+                    job.attempts,
+                    MAX_ANALYSIS_ATTEMPTS,
+                    INITIAL_RETRY_DELAY_SECONDS,
+                );
+            }
+        }
+
+        diesel::update(analysis_jobs::table.find(job.job_id))
+            .set(&job)
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that analyses uploaded images, if an analyser is configured.
+pub fn spawn_analysis_worker(database_url: String) {
+    let analyser = match analyser() {
+        Some(analyser) => analyser,
+        None => return,
+    };
+
+    worker::spawn_worker(
+        "Analysis",
+        Duration::from_secs(2),
+        database_url,
+        move |connection| {
+            if let Err(error) = run_due(analyser.as_ref(), connection) {
+                error!("Failed to run analysis jobs! The error was {}", error);
+            }
+        },
+    );
+}
@@ -0,0 +1,296 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audit,
+    page::{Page, PageQuery},
+    realtime,
+    soft_delete::not_found_or_database_error,
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::{announcement_reads, announcements};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Announcements longer than this are refused, they're meant to be shown in a banner.
+pub const MAX_ANNOUNCEMENT_BODY_LENGTH: usize = 2000;
+
+/// A message from the server's operators to everyone using it, e.g. about planned maintenance.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Announcement {
+    pub announcement_id: i32,
+    pub title: String,
+    pub body: String,
+    /// Users don't see it before this.
+    pub starts_at: DateTime<Utc>,
+    /// Users don't see it after this. None if it doesn't expire.
+    pub ends_at: Option<DateTime<Utc>>,
+    /// The admin who published it, or None if they've since been deleted.
+    #[schemars(with = "Option<String>")]
+    pub created_by: Option<uuid::Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "announcements"]
+pub struct InsertableAnnouncement {
+    pub title: String,
+    pub body: String,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub created_by: Option<uuid::Uuid>,
+}
+
+/// Sent with POST /Admin/Announcements.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewAnnouncement {
+    pub title: String,
+    pub body: String,
+    /// Defaults to now. Announcements that start later aren't pushed to WebSocket clients when they start, only
+    /// returned by GET /Announcements.
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get announcements! The error was {}", error);
+    ApiError {
+        error: "Failed to get announcements",
+        code: "get_announcements_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+pub fn insert(
+    announcement: InsertableAnnouncement,
+    connection: &PgConnection,
+) -> QueryResult<Announcement> {
+    diesel::insert_into(announcements::table)
+        .values(announcement)
+        .get_result(connection)
+}
+
+/// Announcements that have started and haven't ended, newest first, that the user hasn't marked as read.
+pub fn get_unread(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Announcement>> {
+    let now = Utc::now();
+
+    announcements::table
+        .filter(announcements::starts_at.le(now))
+        .filter(
+            announcements::ends_at
+                .is_null()
+                .or(announcements::ends_at.gt(now)),
+        )
+        .filter(
+            announcements::announcement_id.ne_all(
+                announcement_reads::table
+                    .filter(announcement_reads::user_id.eq(user_id))
+                    .select(announcement_reads::announcement_id),
+            ),
+        )
+        .order(announcements::starts_at.desc())
+        .load(connection)
+}
+
+/// Publishes an announcement to every user. It's pushed to WebSocket clients straight away if it has already started.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Announcements", format = "json", data = "<new_announcement>")]
+pub fn create_announcement(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    new_announcement: Json<NewAnnouncement>,
+) -> Result<Json<Announcement>, ApiError> {
+    let new_announcement = new_announcement.into_inner();
+
+    if new_announcement.title.trim().is_empty() {
+        return Err(ApiError {
+            error: "Announcements need a title",
+            code: "announcement_title_required",
+            status: Status::UnprocessableEntity,
+            field: Some("title"),
+        });
+    }
+    if new_announcement.body.chars().count() > MAX_ANNOUNCEMENT_BODY_LENGTH {
+        return Err(ApiError {
+            error: "Announcement body is too long",
+            code: "announcement_body_too_long",
+            status: Status::UnprocessableEntity,
+            field: Some("body"),
+        });
+    }
+
+    let starts_at = new_announcement.starts_at.unwrap_or_else(Utc::now);
+    if let Some(ends_at) = new_announcement.ends_at {
+        if ends_at <= starts_at {
+            return Err(ApiError {
+                error: "Announcements must end after they start",
+                code: "invalid_announcement_period",
+                status: Status::UnprocessableEntity,
+                field: Some("ends_at"),
+            });
+        }
+    }
+
+    let announcement = insert(
+        InsertableAnnouncement {
+            title: new_announcement.title,
+            body: new_announcement.body,
+            starts_at,
+            ends_at: new_announcement.ends_at,
+            created_by: Some(admin_token.user_id),
+        },
+        &conn,
+    )
+    .map_err(|error| {
+        error!("Failed to publish announcement! The error was {}", error);
+        ApiError {
+            error: "Failed to publish announcement",
+            code: "publish_announcement_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+    audit::record_after(&announcement);
+
+    if announcement.starts_at <= Utc::now() {
+        realtime::publish_announcement(&announcement, &conn);
+    }
+
+    Ok(Json(announcement))
+}
+
+/// Every announcement, including ones that haven't started or have ended, newest first. Only for users in
+/// ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Announcements?<query..>")]
+pub fn list_announcements(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<Announcement>>, ApiError> {
+    let (offset, limit) = query.offset_and_limit()?;
+
+    let items = announcements::table
+        .order(announcements::announcement_id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<Announcement>(&*conn)
+        .map_err(database_error)?;
+
+    let total = announcements::table
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
+
+/// Takes an announcement down, along with who's read it. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Announcements/<announcement_id>")]
+pub fn delete_announcement(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    announcement_id: i32,
+) -> Result<(), ApiError> {
+    let announcement = announcements::table
+        .find(announcement_id)
+        .get_result::<Announcement>(&*conn)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("announcement_not_found", "Announcement not found"),
+                (
+                    "delete_announcement_failed",
+                    "Failed to delete announcement",
+                ),
+            )
+        })?;
+    audit::record_before(&announcement);
+
+    diesel::delete(announcements::table.find(announcement_id))
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("announcement_not_found", "Announcement not found"),
+                (
+                    "delete_announcement_failed",
+                    "Failed to delete announcement",
+                ),
+            )
+        })
+}
+
+/// The current announcements the user hasn't read yet, newest first. WebSocket clients also get new ones as
+/// announcement messages.
+#[openapi]
+#[get("/Announcements")]
+pub fn get_announcements(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<Announcement>>, ApiError> {
+    get_unread(user_token.user_id, &conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Stops the announcement being returned by GET /Announcements for this user.
+#[openapi]
+#[post("/Announcements/<announcement_id>/Read")]
+pub fn read_announcement(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    announcement_id: i32,
+) -> Result<(), ApiError> {
+    announcements::table
+        .find(announcement_id)
+        .select(announcements::announcement_id)
+        .get_result::<i32>(&*conn)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("announcement_not_found", "Announcement not found"),
+                (
+                    "mark_announcement_read_failed",
+                    "Failed to mark announcement as read",
+                ),
+            )
+        })?;
+
+    diesel::insert_into(announcement_reads::table)
+        .values((
+            announcement_reads::announcement_id.eq(announcement_id),
+            announcement_reads::user_id.eq(user_token.user_id),
+        ))
+        .on_conflict_do_nothing()
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(|error| {
+            error!(
+                "Failed to mark announcement {} as read! The error was {}",
+                announcement_id, error
+            );
+            ApiError {
+                error: "Failed to mark announcement as read",
+                code: "mark_announcement_read_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -0,0 +1,277 @@
+use crate::{
+    api_error::ApiError,
+    camera::CameraId,
+    event::{self, default_severity, dispatch_event, InsertableEvent, ANOMALY_EVENT_TYPE},
+    settings::settings,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{activity_baselines, events};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Timelike, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::get;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Baselines need at least this many days of history before anything is flagged, so new cameras don't flag everything.
+pub const MIN_BASELINE_DAYS: i32 = 7;
+
+/// An hour isn't unusual unless it has at least this many events, so one event in a normally empty hour isn't flagged.
+pub const MIN_ANOMALY_EVENTS: i64 = 3;
+
+/// Hours where the camera is almost never busy have a standard deviation near 0, which would make anything look unusual.
+pub const MIN_STANDARD_DEVIATION: f64 = 0.5;
+
+/// How busy a camera usually is during one hour of the day (UTC), worked out from its recent events.
+#[derive(Queryable, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "activity_baselines"]
+pub struct ActivityBaseline {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// 0 to 23.
+    pub hour: i16,
+    /// The average number of events in this hour per day.
+    pub mean: f64,
+    pub standard_deviation: f64,
+    /// How many days of history the baseline is based on.
+    pub days: i32,
+    pub computed_at: DateTime<Utc>,
+}
+
+/// How many days of events baselines are worked out from, set with baseline_days in [anomaly]. Defaults to 28.
+pub fn baseline_days() -> i64 {
+    settings().anomaly.baseline_days
+}
+
+/// How many standard deviations above the mean an hour has to be to count as unusual, set with threshold in
+/// [anomaly]. Defaults to 3.
+pub fn anomaly_threshold() -> f64 {
+    settings().anomaly.threshold
+}
+
+/// Set with detection in [anomaly].
+pub fn anomaly_detection_enabled() -> bool {
+    settings().anomaly.detection
+}
+
+impl ActivityBaseline {
+    /// How many events in the hour it takes to count as unusual.
+    pub fn threshold(&self) -> f64 {
+        (self.mean + anomaly_threshold() * self.standard_deviation.max(MIN_STANDARD_DEVIATION))
+            .max(MIN_ANOMALY_EVENTS as f64)
+    }
+}
+
+/// Works out every camera's baselines from the events in the last baseline_days() days. Anomalies themselves
+/// aren't counted. Days before a camera's first event don't count towards its baseline, so new cameras aren't
+/// treated as having been quiet.
+pub fn compute_baselines(now: DateTime<Utc>, connection: &PgConnection) -> QueryResult<usize> {
+    let today = now.date().naive_utc();
+    let since = now - ChronoDuration::days(baseline_days());
+
+    let recent_events = events::table
+        .filter(events::occurred_at.ge(since))
+        .filter(events::occurred_at.lt(now.date().and_hms(0, 0, 0)))
+        .filter(events::event_type.ne(ANOMALY_EVENT_TYPE))
+        .select((events::camera_id, events::occurred_at))
+        .load::<(uuid::Uuid, DateTime<Utc>)>(connection)?;
+
+    let mut first_days: HashMap<uuid::Uuid, NaiveDate> = HashMap::new();
+    let mut counts: HashMap<(uuid::Uuid, i16, NaiveDate), i64> = HashMap::new();
+
+    for (camera_id, occurred_at) in recent_events {
+        let date = occurred_at.date().naive_utc();
+        let first_day = first_days.entry(camera_id).or_insert(date);
+        if date < *first_day {
+            *first_day = date;
+        }
+
+        *counts
+            .entry((camera_id, occurred_at.hour() as i16, date))
+            .or_insert(0) += 1;
+    }
+
+    let mut baselines = Vec::new();
+
+    for (camera_id, first_day) in first_days {
+        let days = (today - first_day).num_days() as i32;
+
+        if days <= 0 {
+            continue;
+        }
+
+        for hour in 0..24 {
+            let daily_counts: Vec<f64> = (0..days)
+                .map(|day| first_day + ChronoDuration::days(day as i64))
+                .map(|date| *counts.get(&(camera_id, hour, date)).unwrap_or(&0) as f64)
+                .collect();
+
+            let mean = daily_counts.iter().sum::<f64>() / days as f64;
+            let variance = daily_counts
+                .iter()
+                .map(|count| (count - mean).powi(2))
+                .sum::<f64>()
+                / days as f64;
+
+            baselines.push(ActivityBaseline {
+                camera_id,
+                hour,
+                mean,
+                standard_deviation: variance.sqrt(),
+                days,
+                computed_at: now,
+            });
+        }
+    }
+
+    connection.transaction(|| {
+        diesel::delete(activity_baselines::table).execute(connection)?;
+
+        // Postgres limits how many parameters one statement can have, so insert a camera's worth at a time
+        for chunk in baselines.chunks(24) {
+            diesel::insert_into(activity_baselines::table)
+                .values(chunk)
+                .execute(connection)?;
+        }
+
+        Ok(baselines.len())
+    })
+}
+
+pub fn get_cameras_baselines(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<ActivityBaseline>> {
+    activity_baselines::table
+        .filter(activity_baselines::camera_id.eq(camera_id))
+        .order(activity_baselines::hour)
+        .load::<ActivityBaseline>(connection)
+}
+
+/// Raises an anomaly event for every camera that is already busier this hour than its baseline allows.
+/// Each camera gets at most one anomaly per hour.
+pub fn flag_anomalies(now: DateTime<Utc>, connection: &PgConnection) -> QueryResult<usize> {
+    let hour_start = now.date().and_hms(now.hour(), 0, 0);
+
+    let baselines = activity_baselines::table
+        .filter(activity_baselines::hour.eq(now.hour() as i16))
+        .filter(activity_baselines::days.ge(MIN_BASELINE_DAYS))
+        .load::<ActivityBaseline>(connection)?;
+
+    let mut hour_counts: HashMap<uuid::Uuid, i64> = HashMap::new();
+    let mut already_flagged: HashSet<uuid::Uuid> = HashSet::new();
+
+    for (camera_id, event_type) in events::table
+        .filter(events::occurred_at.ge(hour_start))
+        .select((events::camera_id, events::event_type))
+        .load::<(uuid::Uuid, String)>(connection)?
+    {
+        if event_type == ANOMALY_EVENT_TYPE {
+            already_flagged.insert(camera_id);
+        } else {
+            *hour_counts.entry(camera_id).or_insert(0) += 1;
+        }
+    }
+
+    let mut flagged = 0;
+
+    for baseline in baselines {
+        let count = *hour_counts.get(&baseline.camera_id).unwrap_or(&0);
+
+        if already_flagged.contains(&baseline.camera_id) || (count as f64) < baseline.threshold() {
+            continue;
+        }
+
+        let standard_deviations = (count as f64 - baseline.mean)
+            / baseline.standard_deviation.max(MIN_STANDARD_DEVIATION);
+
+        let event = event::insert(
+            InsertableEvent {
+                camera_id: baseline.camera_id,
+                event_type: ANOMALY_EVENT_TYPE.to_string(),
+                occurred_at: now,
+                // Twice the threshold is as sure as it gets
+                confidence: (standard_deviations / (2.0 * anomaly_threshold())).min(1.0) as f32,
+                image_id: None,
+                severity: default_severity(ANOMALY_EVENT_TYPE).to_string(),
+                audio_id: None,
+                tamper_reason: None,
+            },
+            connection,
+        )?;
+
+        dispatch_event(&event, connection);
+        flagged += 1;
+    }
+
+    Ok(flagged)
+}
+
+/// Starts the thread that recomputes baselines once a day and checks for unusual activity every 5 minutes.
+/// Does nothing unless detection in [anomaly] is on.
+pub fn spawn_anomaly_worker(database_url: String) {
+    if !anomaly_detection_enabled() {
+        return;
+    }
+
+    let last_computed: Cell<Option<NaiveDate>> = Cell::new(None);
+
+    worker::spawn_worker(
+        "Anomaly detection",
+        Duration::from_secs(5 * 60),
+        database_url,
+        move |connection| {
+            let now = Utc::now();
+
+            if last_computed.get() != Some(now.date().naive_utc()) {
+                match compute_baselines(now, connection) {
+                    Ok(_) => last_computed.set(Some(now.date().naive_utc())),
+                    Err(error) => error!(
+                        "Failed to compute activity baselines! The error was {}",
+                        error
+                    ),
+                }
+            }
+
+            if let Err(error) = flag_anomalies(now, connection) {
+                error!("Failed to flag unusual activity! The error was {}", error);
+            }
+        },
+    );
+}
+
+/// Returns how busy the camera usually is in each hour of the day, as used to flag unusual activity.
+#[openapi]
+#[get("/Cameras/<camera_id>/ActivityBaseline")]
+pub fn get_activity_baseline(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Vec<ActivityBaseline>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    get_cameras_baselines(camera_id, &conn)
+        .map(|baselines| Json(baselines))
+        .map_err(|error| {
+            error!(
+                "Failed to get activity baseline for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get activity baseline",
+                code: "get_activity_baseline_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -1,20 +1,232 @@
+use crate::{i18n, request_id};
+
 use rocket::request::Request;
 use rocket::response;
 use rocket::response::{Responder, Response};
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
 
-use rocket::http::{ContentType, Status};
+use rocket::http::Status;
 
 #[derive(Debug)]
 pub struct ApiError {
     pub error: &'static str,
+    /// What the error is, like camera_not_found, see ErrorBody::code. Each error has its own, as statuses are shared
+    /// by many of them.
+    pub code: &'static str,
     pub status: Status,
+    /// The request field the error is about, for validation errors.
+    pub field: Option<&'static str>,
+}
+
+/// What every error response looks like. Messages are in the request's locale, see i18n::request_locale().
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    /// Stable, so clients can branch on it. Messages may be reworded. Errors from routes have one of their own, and
+    /// anything else uses its status's, see error_code().
+    pub code: &'static str,
+    pub message: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldDetail>,
+    /// Matches the X-Request-Id header, worth quoting when reporting a problem.
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct FieldDetail {
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+/// Set by a request guard that fails for a more specific reason than its status says, as (code, message).
+/// The catcher for the status returns it instead of its usual body.
+pub struct GuardFailure(pub Option<(&'static str, &'static str)>);
+
+/// Records why the request's guard is about to fail, see GuardFailure.
+pub fn record_guard_failure(request: &Request, code: &'static str, message: &'static str) {
+    request.local_cache(|| GuardFailure(Some((code, message))));
+}
+
+/// Returns the error code for a status, for errors that don't have a more specific one.
+pub fn error_code(status: Status) -> &'static str {
+    match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        402 => "plan_limit_exceeded",
+        403 => "forbidden",
+        404 => "not_found",
+        405 => "method_not_allowed",
+        409 => "conflict",
+        415 => "unsupported_media_type",
+        422 => "validation_failed",
+        429 => "too_many_requests",
+        503 => "service_unavailable",
+        504 => "upstream_timeout",
+        _ => "internal_error",
+    }
+}
+
+impl ApiError {
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code,
+            message: self.error,
+            details: self
+                .field
+                .map(|field| FieldDetail {
+                    field,
+                    message: self.error,
+                })
+                .into_iter()
+                .collect(),
+            request_id: request_id::current(),
+        }
+    }
+}
+
+impl ErrorBody {
+    /// Translates the message into the request's locale.
+    pub fn localized(self, request: &Request) -> ErrorBody {
+        let code = self.code;
+        self.localized_as(request, &[code])
+    }
+
+    /// Like localized(), trying the translations for each of `codes` in turn if the message doesn't have its own.
+    fn localized_as(mut self, request: &Request, codes: &[&str]) -> ErrorBody {
+        let locale = i18n::request_locale(request);
+
+        if locale != i18n::DEFAULT_LOCALE {
+            self.message = i18n::error_message(locale, codes, self.message);
+            for detail in &mut self.details {
+                detail.message = i18n::error_message(locale, codes, detail.message);
+            }
+        }
+
+        self
+    }
 }
 
 impl<'r> Responder<'r> for ApiError {
     fn respond_to(self, req: &Request) -> response::Result<'r> {
-        Response::build_from(self.error.respond_to(&req).unwrap())
+        // Few errors have a translation for their own code, so the status's is used for the rest
+        let body = self
+            .body()
+            .localized_as(req, &[self.code, error_code(self.status)]);
+
+        Response::build_from(Json(body).respond_to(&req)?)
             .status(self.status)
-            .header(ContentType::Plain)
             .ok()
     }
 }
+
+fn catcher_body(request: &Request, status: Status, message: &'static str) -> Json<ErrorBody> {
+    Json(
+        ErrorBody {
+            code: error_code(status),
+            message,
+            details: Vec::new(),
+            request_id: request_id::current(),
+        }
+        .localized(request),
+    )
+}
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> Json<ErrorBody> {
+    catcher_body(request, Status::BadRequest, "Malformed request")
+}
+
+/// Also used when the user_token or camera_token header is missing or wrong.
+#[catch(401)]
+pub fn unauthorized(request: &Request) -> Json<ErrorBody> {
+    catcher_body(request, Status::Unauthorized, "Missing or invalid token")
+}
+
+/// Used when a user who isn't an admin uses an /Admin route, and when a suspended user or their camera
+/// makes a request (with the code account_suspended).
+#[catch(403)]
+pub fn forbidden(request: &Request) -> Json<ErrorBody> {
+    match request.local_cache(|| GuardFailure(None)).0 {
+        Some((code, message)) => Json(
+            ErrorBody {
+                code,
+                message,
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request),
+        ),
+        None => catcher_body(request, Status::Forbidden, "Not allowed"),
+    }
+}
+
+/// Also used when the tenant header names a tenant that doesn't exist (with the code tenant_not_found).
+#[catch(404)]
+pub fn not_found(request: &Request) -> Json<ErrorBody> {
+    match request.local_cache(|| GuardFailure(None)).0 {
+        Some((code, message)) => Json(
+            ErrorBody {
+                code,
+                message,
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request),
+        ),
+        None => catcher_body(
+            request,
+            Status::NotFound,
+            "No such route, or an ID in the path is malformed",
+        ),
+    }
+}
+
+/// Rocket uses this when a JSON body doesn't match what the route expects.
+/// Used when a device endpoint gets a body that isn't JSON, CBOR or MessagePack.
+#[catch(415)]
+pub fn unsupported_media_type(request: &Request) -> Json<ErrorBody> {
+    catcher_body(
+        request,
+        Status::UnsupportedMediaType,
+        "Request body must be JSON, CBOR or MessagePack",
+    )
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> Json<ErrorBody> {
+    catcher_body(
+        request,
+        Status::UnprocessableEntity,
+        "Failed to parse request body",
+    )
+}
+
+/// Used when a camera has too many uploads going at once, see upload_limit.rs.
+#[catch(429)]
+pub fn too_many_requests(request: &Request) -> Json<ErrorBody> {
+    catcher_body(
+        request,
+        Status::TooManyRequests,
+        "Too many requests at once, try again after Retry-After",
+    )
+}
+
+/// Used when the database can't be reached, or when there are too many uploads to take another.
+#[catch(503)]
+pub fn service_unavailable(request: &Request) -> Json<ErrorBody> {
+    catcher_body(
+        request,
+        Status::ServiceUnavailable,
+        "The server can't take this request right now, try again later",
+    )
+}
+
+#[catch(500)]
+pub fn internal_error(request: &Request) -> Json<ErrorBody> {
+    catcher_body(
+        request,
+        Status::InternalServerError,
+        "Internal server error",
+    )
+}
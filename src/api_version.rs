@@ -0,0 +1,262 @@
+use crate::{metrics, settings::settings};
+
+use chrono::NaiveDate;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Header, Method};
+use rocket::{Data, Request, Response};
+
+/// Every route is mounted under this.
+pub const API_PREFIX: &str = "/api/v1";
+
+/// Paths for load balancers and monitoring rather than clients. They're mounted at the root and never rewritten.
+pub const UNVERSIONED_PATHS: [&str; 4] = ["/health", "/livez", "/readyz", "/metrics"];
+
+/// A v0 route that moved in v1. `*` matches any one path segment, and is carried over to the same place in the new path.
+pub struct LegacyRoute {
+    pub method: Method,
+    pub path: &'static str,
+    pub new_method: Method,
+    pub new_path: &'static str,
+}
+
+/// v0 routes that were renamed in v1. Any other v0 path is served by the v1 route with the same path.
+pub const LEGACY_ROUTES: [LegacyRoute; 11] = [
+    LegacyRoute {
+        method: Method::Post,
+        path: "/AddUser",
+        new_method: Method::Post,
+        new_path: "/Users",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/AddCamera",
+        new_method: Method::Post,
+        new_path: "/Cameras",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/ListCameras",
+        new_method: Method::Get,
+        new_path: "/Cameras",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/UploadImage",
+        new_method: Method::Post,
+        new_path: "/Device/Images",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/GetCommands",
+        new_method: Method::Get,
+        new_path: "/Device/Commands",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/GetConfigCamera",
+        new_method: Method::Get,
+        new_path: "/Device/Config",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/*/GetConfigUser",
+        new_method: Method::Get,
+        new_path: "/Cameras/*/Config",
+    },
+    LegacyRoute {
+        method: Method::Post,
+        path: "/Cameras/*/UpdateConfig",
+        new_method: Method::Put,
+        new_path: "/Cameras/*/Config",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/*/ImageList",
+        new_method: Method::Get,
+        new_path: "/Cameras/*/Images",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/*/Image/*",
+        new_method: Method::Get,
+        new_path: "/Cameras/*/Images/*",
+    },
+    LegacyRoute {
+        method: Method::Get,
+        path: "/Cameras/*/Image/*/Detections",
+        new_method: Method::Get,
+        new_path: "/Cameras/*/Images/*/Detections",
+    },
+];
+
+impl LegacyRoute {
+    /// Returns the v1 path for the given v0 path if this route matches it.
+    pub fn rewrite(&self, method: Method, path: &str) -> Option<String> {
+        if method != self.method {
+            return None;
+        }
+
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let pattern: Vec<&str> = self.path.trim_start_matches('/').split('/').collect();
+
+        if segments.len() != pattern.len() {
+            return None;
+        }
+
+        let mut wildcards = Vec::new();
+
+        for (segment, pattern_segment) in segments.iter().zip(pattern.iter()) {
+            if *pattern_segment == "*" {
+                wildcards.push(*segment);
+            } else if segment != pattern_segment {
+                return None;
+            }
+        }
+
+        let mut wildcards = wildcards.into_iter();
+
+        Some(
+            self.new_path
+                .split('/')
+                .map(|new_segment| match new_segment {
+                    "*" => wildcards.next().unwrap_or_default(),
+                    _ => new_segment,
+                })
+                .collect::<Vec<&str>>()
+                .join("/"),
+        )
+    }
+}
+
+/// Works out the v1 method and path for a request to a v0 path.
+pub fn upgrade_path(method: Method, path: &str) -> (Method, String) {
+    for legacy_route in LEGACY_ROUTES.iter() {
+        if let Some(new_path) = legacy_route.rewrite(method, path) {
+            return (legacy_route.new_method, new_path);
+        }
+    }
+
+    (method, path.to_string())
+}
+
+/// A v1 route that still works but is going to be removed.
+pub struct DeprecatedRoute {
+    pub method: Method,
+    /// The route as mounted, e.g. /api/v1/Cameras/<camera_id>/Config, rather than a request's path.
+    pub route: &'static str,
+    /// When the route will be removed.
+    pub sunset: NaiveDate,
+    /// The path clients should use instead.
+    pub successor: &'static str,
+}
+
+/// v1 routes that are going away. Responses from them get Deprecation and Sunset headers, and requests to them
+/// are counted in deprecated_requests_total. Nothing in v1 is deprecated yet.
+pub const DEPRECATED_ROUTES: &[DeprecatedRoute] = &[];
+
+/// Formats a date the way the Sunset header wants it, e.g. Sat, 01 Jan 2022 00:00:00 GMT.
+fn http_date(date: NaiveDate) -> String {
+    date.and_hms(0, 0, 0)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Stored in the request's local cache when a v0 path was upgraded, so the response can be marked as deprecated.
+pub struct LegacyRequest(pub Option<String>);
+
+/// Serves v0 paths (everything outside /api/), so that cameras running older firmware keep working.
+/// Requests are rewritten to the matching v1 route before routing, and responses get a Deprecation header
+/// with a Link to the v1 path. Responses from DEPRECATED_ROUTES are marked the same way.
+///
+/// Every deprecated request is counted in deprecated_requests_total, by the route it was served by, so it's
+/// possible to see which firmware still needs an old path before it's removed.
+pub struct LegacyPaths {
+    /// When v0 paths will stop working, if that's been decided.
+    legacy_sunset: Option<NaiveDate>,
+}
+
+impl LegacyPaths {
+    /// The date v0 paths will be removed is set with legacy_sunset in [server], as YYYY-MM-DD. Defaults to no Sunset
+    /// header.
+    pub fn from_env() -> LegacyPaths {
+        let legacy_sunset = settings()
+            .server
+            .legacy_sunset()
+            .map(|date| date.expect("The settings make sure legacy_sunset is a date"));
+
+        LegacyPaths { legacy_sunset }
+    }
+}
+
+impl Fairing for LegacyPaths {
+    fn info(&self) -> Info {
+        Info {
+            name: "Legacy v0 paths",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = request.uri().path().to_string();
+
+        if path == "/api" || path.starts_with("/api/") || UNVERSIONED_PATHS.contains(&path.as_str())
+        {
+            return;
+        }
+
+        let (method, new_path) = upgrade_path(request.method(), &path);
+        let new_path = format!("{}{}", API_PREFIX, new_path);
+
+        let new_uri = match request.uri().query() {
+            Some(query) => format!("{}?{}", new_path, query),
+            None => new_path.clone(),
+        };
+
+        match Origin::parse_owned(new_uri) {
+            Ok(origin) => {
+                request.set_uri(origin);
+                request.set_method(method);
+                request.local_cache(|| LegacyRequest(Some(new_path)));
+            }
+            Err(error) => error!(
+                "Failed to upgrade legacy path {}! The error was {}",
+                path, error
+            ),
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let route = request.route();
+
+        let (successor, sunset, kind) = match request.local_cache(|| LegacyRequest(None)) {
+            LegacyRequest(Some(new_path)) => (new_path.clone(), self.legacy_sunset, "legacy_path"),
+            LegacyRequest(None) => match route.and_then(|route| {
+                DEPRECATED_ROUTES.iter().find(|deprecated| {
+                    deprecated.method == route.method && deprecated.route == route.uri.path()
+                })
+            }) {
+                Some(deprecated) => (
+                    deprecated.successor.to_string(),
+                    Some(deprecated.sunset),
+                    "deprecated_route",
+                ),
+                None => return,
+            },
+        };
+
+        response.set_header(Header::new("Deprecation", "true"));
+        response.set_header(Header::new(
+            "Link",
+            format!("<{}>; rel=\"successor-version\"", successor),
+        ));
+
+        if let Some(sunset) = sunset {
+            response.set_header(Header::new("Sunset", http_date(sunset)));
+        }
+
+        // Requests that didn't match a route are labelled the same way RequestMetrics labels them
+        let route = route.map(|route| route.uri.path()).unwrap_or("unmatched");
+        metrics::record_deprecated_request(request.method().as_str(), route, kind);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    api_error::ApiError,
+    camera::{record_camera_contact, CameraId},
+    camera_tokens::CameraToken,
+    device_format::Device,
+    event::Event,
+    media_store::write_whole_file,
+    metrics,
+    multipart_upload::{report_metadata_event, MultipartUpload},
+    plan,
+    settings::settings,
+    storage,
+    upload_limit::UploadSlot,
+    usage,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::audio_clips;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::{ContentType, Status};
+use rocket::response::{Content, Stream};
+use rocket::{get, post, Data};
+use rocket_okapi::openapi;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, File};
+use std::io::{self, Read};
+
+/// Audio clips bigger than this are cut off. A few seconds of compressed audio is far smaller.
+pub const MAX_AUDIO_BYTES: u64 = 5 * 1024 * 1024;
+
+/// A short recording a camera uploaded, usually of whatever raised an audio event (glass breaking, a smoke alarm, etc).
+/// The audio itself is stored on disk as <audio_directory>/<camera_id>/<audio_id>.
+#[derive(Queryable, Deserialize, Serialize)]
+pub struct AudioClip {
+    pub audio_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "audio_clips"]
+pub struct InsertableAudioClip {
+    pub camera_id: uuid::Uuid,
+    pub content_type: String,
+    pub size_bytes: i64,
+}
+
+/// Where audio clips are stored, set with audio_directory in [storage]. Defaults to audio.
+pub fn audio_directory() -> String {
+    settings().storage.audio_directory.clone()
+}
+
+pub fn audio_path(camera_id: &uuid::Uuid, audio_id: i32) -> String {
+    format!("{}/{}/{}", audio_directory(), camera_id, audio_id)
+}
+
+pub fn insert(
+    audio_clip: InsertableAudioClip,
+    connection: &PgConnection,
+) -> QueryResult<AudioClip> {
+    diesel::insert_into(audio_clips::table)
+        .values(audio_clip)
+        .get_result(connection)
+}
+
+/// Returns the given audio clip, but only if it's from the given camera.
+pub fn get_cameras_audio_clip(
+    camera_id: uuid::Uuid,
+    audio_id: i32,
+    connection: &PgConnection,
+) -> Result<AudioClip, ApiError> {
+    audio_clips::table
+        .filter(audio_clips::audio_id.eq(audio_id))
+        .filter(audio_clips::camera_id.eq(camera_id))
+        .first::<AudioClip>(connection)
+        .map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Audio clip not found",
+                code: "audio_clip_not_found",
+                status: Status::NotFound,
+                field: None,
+            },
+            _ => {
+                error!(
+                    "Failed to get audio clip {}! The error was {}",
+                    audio_id, error
+                );
+                ApiError {
+                    error: "Failed to get audio clip",
+                    code: "get_audio_clip_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            }
+        })
+}
+
+/// Writes the clip to disk, returning how many bytes were written.
+pub fn store_audio(camera_id: &uuid::Uuid, audio_id: i32, audio: &mut dyn Read) -> io::Result<u64> {
+    create_dir_all(format!("{}/{}", audio_directory(), camera_id))?;
+    write_whole_file(
+        &audio_path(camera_id, audio_id),
+        &mut audio.take(MAX_AUDIO_BYTES),
+    )
+}
+
+/// Stores an audio clip, returning it once its size is known.
+fn store_audio_clip(
+    camera_id: uuid::Uuid,
+    content_type: String,
+    audio: &mut dyn Read,
+    conn: &PgConnection,
+) -> Result<AudioClip, ApiError> {
+    if !content_type.starts_with("audio/") {
+        return Err(ApiError {
+            error: "Audio clips must have an audio content type",
+            code: "invalid_audio_content_type",
+            status: Status::UnsupportedMediaType,
+            field: None,
+        });
+    }
+
+    plan::check_storage(camera_id, conn)?;
+
+    let audio_clip = insert(
+        InsertableAudioClip {
+            camera_id,
+            content_type,
+            size_bytes: 0,
+        },
+        conn,
+    )
+    .map_err(|error| {
+        error!(
+            "Failed to store audio clip for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to store audio clip",
+            code: "store_audio_clip_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    let size_bytes = store_audio(&camera_id, audio_clip.audio_id, audio).map_err(|error| {
+        error!("Failed to stream audio to file! The error was {}", error);
+        if let Err(error) =
+            diesel::delete(audio_clips::table.find(audio_clip.audio_id)).execute(conn)
+        {
+            error!(
+                "Failed to delete audio clip {} after failing to save it! The error was {}",
+                audio_clip.audio_id, error
+            );
+        }
+        ApiError {
+            error: "Failed to save audio clip to server",
+            code: "save_audio_clip_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    metrics::record_upload("audio", size_bytes);
+    usage::record_upload(camera_id, size_bytes);
+
+    storage::record_stored(
+        camera_id,
+        storage::AUDIO,
+        audio_clip.recorded_at.naive_utc().date(),
+        size_bytes,
+    );
+
+    diesel::update(audio_clips::table.find(audio_clip.audio_id))
+        .set(audio_clips::size_bytes.eq(size_bytes as i64))
+        .get_result::<AudioClip>(conn)
+        .map_err(|error| {
+            error!(
+                "Failed to update audio clip {}! The error was {}",
+                audio_clip.audio_id, error
+            );
+            ApiError {
+                error: "Failed to store audio clip",
+                code: "store_audio_clip_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Stores an audio clip from the camera. The clip's ID can then be attached to an event with audio_id.
+/// Any audio/* content type is accepted and served back as-is.
+// Ranked after upload_audio_multipart, which would otherwise collide with it
+#[openapi(skip)]
+#[post("/Device/Audio", data = "<audio>", rank = 2)]
+pub fn upload_audio(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    _upload_slot: UploadSlot,
+    content_type: &ContentType,
+    audio: Data,
+) -> Result<Device<AudioClip>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    store_audio_clip(
+        camera_token.camera_id,
+        content_type.to_string(),
+        &mut audio.open(),
+        &conn,
+    )
+    .map(Device)
+}
+
+/// Returned by multipart uploads to POST /Device/Audio.
+#[derive(Serialize)]
+pub struct AudioUpload {
+    pub audio_clip: AudioClip,
+    /// The event from the upload's metadata, None if it didn't have one.
+    pub event: Option<Event>,
+}
+
+/// The same as uploading the clip on its own, for firmware that can only send multipart/form-data.
+/// The file part needs an audio/* content type. If the metadata part has an event, it is reported with the clip attached.
+#[openapi(skip)]
+#[post("/Device/Audio", format = "multipart/form-data", data = "<upload>")]
+pub fn upload_audio_multipart(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    _upload_slot: UploadSlot,
+    upload: MultipartUpload,
+) -> Result<Device<AudioUpload>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    let audio_clip = store_audio_clip(
+        camera_token.camera_id,
+        upload.content_type.unwrap_or_default(),
+        &mut upload.file.as_slice(),
+        &conn,
+    )?;
+
+    let audio_id = audio_clip.audio_id;
+    let event = report_metadata_event(
+        camera_token.camera_id,
+        upload.metadata,
+        |event| event.audio_id = Some(audio_id),
+        &conn,
+    )?;
+
+    Ok(Device(AudioUpload { audio_clip, event }))
+}
+
+#[openapi(skip)]
+#[get("/Cameras/<camera_id>/Audio/<audio_id>")]
+pub fn get_audio(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    audio_id: i32,
+) -> Result<Content<Stream<File>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let audio_clip = get_cameras_audio_clip(camera_id, audio_id, &conn)?;
+
+    let content_type =
+        ContentType::parse_flexible(&audio_clip.content_type).unwrap_or(ContentType::Binary);
+
+    File::open(audio_path(&camera_id, audio_id))
+        .map(|file| Content(content_type, Stream::from(file)))
+        .map_err(|error| {
+            error!(
+                "Failed to open audio clip {}! The error was {}",
+                audio_id, error
+            );
+            ApiError {
+                error: "Failed to open audio clip",
+                code: "open_audio_clip_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
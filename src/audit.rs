@@ -0,0 +1,303 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    event::parse_timestamp,
+    impersonation,
+    page::{offset_and_limit, Page},
+    rate_limit, request_id,
+    settings::settings,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::audit_log;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Method, Status};
+use rocket::request::Form;
+use rocket::{get, Data, Outcome, Request, Response};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::time::Duration;
+
+thread_local! {
+    /// The user making the request on this thread, once their token has been checked.
+    static CURRENT_USER: RefCell<Option<uuid::Uuid>> = RefCell::new(None);
+
+    /// What the resource looked like before and after the request on this thread changed it, if its handler said.
+    static CURRENT_CHANGE: RefCell<(Option<serde_json::Value>, Option<serde_json::Value>)> =
+        RefCell::new((None, None));
+}
+
+/// One state-changing request. Entries are never changed, and are deleted once they're older than
+/// audit_retention_days().
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct AuditEntry {
+    pub audit_id: i64,
+    pub occurred_at: DateTime<Utc>,
+    /// Who made the request. None for requests without a valid user token, e.g. logging in.
+    #[schemars(with = "Option<String>")]
+    pub user_id: Option<uuid::Uuid>,
+    /// The IP address the request came from, or X-Real-IP if a proxy set it.
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// The route that handled the request, e.g. /Cameras/<camera_id>.
+    pub route: Option<String>,
+    pub status: i16,
+    pub request_id: Option<String>,
+    /// Summaries of the resource before and after, for the routes that record them.
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    /// The admin who made the request with an impersonation token for user_id.
+    #[schemars(with = "Option<String>")]
+    pub impersonated_by: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_log"]
+pub struct InsertableAuditEntry {
+    pub user_id: Option<uuid::Uuid>,
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub route: Option<String>,
+    pub status: i16,
+    pub request_id: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub impersonated_by: Option<uuid::Uuid>,
+}
+
+/// How many days audit log entries are kept for, set with audit_retention_days in [limits]. Defaults to 365.
+pub fn audit_retention_days() -> i64 {
+    settings().limits.audit_retention_days
+}
+
+/// Adds the user making the current request to its audit log entry.
+pub fn record_user(user_id: uuid::Uuid) {
+    CURRENT_USER.with(|current| *current.borrow_mut() = Some(user_id));
+}
+
+/// The user recorded for the current request, if its token has been checked yet.
+pub fn current_user() -> Option<uuid::Uuid> {
+    CURRENT_USER.with(|current| *current.borrow())
+}
+
+fn to_summary(summary: &impl Serialize) -> Option<serde_json::Value> {
+    serde_json::to_value(summary)
+        .map_err(|error| {
+            error!(
+                "Failed to summarise for the audit log! The error was {}",
+                error
+            )
+        })
+        .ok()
+}
+
+/// Records what the resource the current request changes looked like beforehand.
+pub fn record_before(before: &impl Serialize) {
+    CURRENT_CHANGE.with(|current| current.borrow_mut().0 = to_summary(before));
+}
+
+/// Records what the resource the current request changes looks like now.
+pub fn record_after(after: &impl Serialize) {
+    CURRENT_CHANGE.with(|current| current.borrow_mut().1 = to_summary(after));
+}
+
+/// Whether the request can change anything, or was made by an admin impersonating a user. Cameras' own uploads and
+/// reports are left out, as they're already recorded as images and events and would drown out what people did.
+fn is_audited(request: &Request) -> bool {
+    let changes_state = match request.method() {
+        Method::Post | Method::Put | Method::Patch | Method::Delete => true,
+        _ => false,
+    };
+    let from_camera = request.headers().get_one("user_token").is_none()
+        && request.headers().get_one("camera_token").is_some();
+
+    // Rerouted requests (e.g. idempotent replays) have no route, as nothing ran for them
+    (changes_state || impersonation::impersonated_by(request).is_some())
+        && !from_camera
+        && request.route().is_some()
+}
+
+/// Writes an entry to the audit log for every request that can change something, whether or not it succeeded.
+/// Failed requests are kept so that e.g. someone trying to delete cameras they don't have access to shows up.
+pub struct AuditLog;
+
+impl Fairing for AuditLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, _request: &mut Request, _: &Data) {
+        // Whatever the last request on this thread recorded mustn't end up in this one's entry
+        CURRENT_USER.with(|current| current.borrow_mut().take());
+        CURRENT_CHANGE.with(|current| *current.borrow_mut() = (None, None));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if !is_audited(request) {
+            return;
+        }
+
+        let (before, after) = CURRENT_CHANGE.with(|current| current.replace((None, None)));
+        let entry = InsertableAuditEntry {
+            user_id: CURRENT_USER.with(|current| current.borrow_mut().take()),
+            client: rate_limit::client_address(request).map(|ip| ip.to_string()),
+            method: request.method().as_str().to_string(),
+            path: request.uri().path().to_string(),
+            route: request.route().map(|route| route.uri.path().to_string()),
+            status: response.status().code as i16,
+            request_id: request_id::current(),
+            before,
+            after,
+            impersonated_by: impersonation::impersonated_by(request),
+        };
+
+        match request.guard::<CameraServerDbConn>() {
+            Outcome::Success(conn) => {
+                if let Err(error) = diesel::insert_into(audit_log::table)
+                    .values(&entry)
+                    .execute(&*conn)
+                {
+                    error!(
+                        "Failed to write {} {} to the audit log! The error was {}",
+                        entry.method, entry.path, error
+                    );
+                }
+            }
+            _ => error!(
+                "Failed to get a database connection to write {} {} to the audit log",
+                entry.method, entry.path
+            ),
+        }
+    }
+}
+
+pub fn purge_expired(retention_days: i64, connection: &PgConnection) -> QueryResult<usize> {
+    let expired_before = Utc::now() - ChronoDuration::days(retention_days);
+
+    diesel::delete(audit_log::table.filter(audit_log::occurred_at.lt(expired_before)))
+        .execute(connection)
+}
+
+/// Starts the thread that deletes entries once they're older than audit_retention_days().
+pub fn spawn_retention_worker(database_url: String) {
+    let retention_days = audit_retention_days();
+
+    worker::spawn_worker(
+        "Audit log retention",
+        Duration::from_secs(60 * 60),
+        database_url,
+        move |connection| match purge_expired(retention_days, connection) {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {} expired audit log entries", purged),
+            Err(error) => error!("Failed to purge the audit log! The error was {}", error),
+        },
+    );
+}
+
+/// Query string for GET /Admin/Audit.
+#[derive(FromForm, JsonSchema)]
+pub struct AuditQuery {
+    /// Only requests made by this user.
+    pub user_id: Option<String>,
+    /// Only requests with this method, e.g. DELETE.
+    pub method: Option<String>,
+    /// Only requests whose path starts with this, e.g. /Cameras/<camera_id> for everything done to one camera.
+    pub path: Option<String>,
+    /// Only requests at or after this RFC 3339 timestamp.
+    pub since: Option<String>,
+    /// Only requests before this RFC 3339 timestamp.
+    pub until: Option<String>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get the audit log! The error was {}", error);
+    ApiError {
+        error: "Failed to get the audit log",
+        code: "get_audit_log_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Escapes LIKE's wildcards, so a path prefix only matches literally.
+fn prefix_pattern(prefix: &str) -> String {
+    format!(
+        "{}%",
+        prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// Returns the audit log, newest first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Audit?<query..>")]
+pub fn list_audit_log(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<AuditQuery>,
+) -> Result<Json<Page<AuditEntry>>, ApiError> {
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+
+    let user_id = match &query.user_id {
+        Some(user_id) => Some(uuid::Uuid::parse_str(user_id).map_err(|_| ApiError {
+            error: "Failed to parse user_id",
+            code: "invalid_user_id",
+            status: Status::UnprocessableEntity,
+            field: Some("user_id"),
+        })?),
+        None => None,
+    };
+    let since = query.since.as_ref().map(parse_timestamp).transpose()?;
+    let until = query.until.as_ref().map(parse_timestamp).transpose()?;
+
+    let filtered = || {
+        let mut filtered = audit_log::table.into_boxed();
+
+        if let Some(user_id) = user_id {
+            filtered = filtered.filter(audit_log::user_id.eq(user_id));
+        }
+        if let Some(method) = &query.method {
+            filtered = filtered.filter(audit_log::method.eq(method.to_uppercase()));
+        }
+        if let Some(path) = &query.path {
+            filtered = filtered.filter(audit_log::path.like(prefix_pattern(path)));
+        }
+        if let Some(since) = since {
+            filtered = filtered.filter(audit_log::occurred_at.ge(since));
+        }
+        if let Some(until) = until {
+            filtered = filtered.filter(audit_log::occurred_at.lt(until));
+        }
+
+        filtered
+    };
+
+    let items = filtered()
+        .order(audit_log::audit_id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<AuditEntry>(&*conn)
+        .map_err(database_error)?;
+
+    let total = filtered()
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
@@ -0,0 +1,461 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audio, health,
+    jobs::{self, Job},
+    media_store::{media_store, MediaStore},
+    schema_check,
+    settings::settings,
+    CameraServerDbConn,
+};
+
+use super::schema::audio_clips;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use rocket::http::Status;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// How many rows are read from, or written to, the database at once.
+pub const BACKUP_BATCH_ROWS: usize = 1000;
+
+/// Tables that migrations fill in, or that only matter to the server that's running.
+/// The backed up job queue would otherwise include the backup job itself, still running.
+const EXCLUDED_TABLES: [&str; 4] = [
+    "__diesel_schema_migrations",
+    "schema_compatibility",
+    "jobs",
+    "idempotency_keys",
+];
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Backups are written under this name until they're complete, so a half-written one is never restored.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Written last, to <backup_directory>/<name>/manifest.json. Each table is next to it in tables/<table>.jsonl,
+/// with one row per line.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BackupManifest {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// The newest migration the database had run. Restoring needs a server with the same one.
+    pub schema_version: String,
+    /// How many rows were backed up from each table.
+    pub tables: BTreeMap<String, u64>,
+    /// Every image in the media store at the time of the backup, by camera. The images themselves aren't copied,
+    /// so the media store needs backing up too, e.g. with rsync or bucket versioning.
+    #[schemars(with = "BTreeMap<String, Vec<u64>>")]
+    pub images: BTreeMap<uuid::Uuid, Vec<u64>>,
+    /// Every audio clip in the backed up audio_clips table, by camera.
+    #[schemars(with = "BTreeMap<String, Vec<i32>>")]
+    pub audio: BTreeMap<uuid::Uuid, Vec<i32>>,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Database(diesel::result::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::Database(error) => write!(f, "database error: {}", error),
+            BackupError::Io(error) => write!(f, "IO error: {}", error),
+            BackupError::Json(error) => write!(f, "JSON error: {}", error),
+            BackupError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for BackupError {
+    fn from(error: diesel::result::Error) -> BackupError {
+        BackupError::Database(error)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> BackupError {
+        BackupError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(error: serde_json::Error) -> BackupError {
+        BackupError::Json(error)
+    }
+}
+
+/// Where backups are written, set with backup_directory in [storage]. This can be a mounted object store bucket.
+pub fn backup_directory() -> Option<&'static str> {
+    settings().storage.backup_directory.as_deref()
+}
+
+#[derive(QueryableByName)]
+struct TableName {
+    #[sql_type = "Text"]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct JsonRow {
+    #[sql_type = "Text"]
+    row: String,
+}
+
+fn table_names(connection: &PgConnection) -> QueryResult<Vec<String>> {
+    diesel::sql_query(
+        "SELECT table_name::text AS name FROM information_schema.tables
+        WHERE table_schema = current_schema() AND table_type = 'BASE TABLE' ORDER BY table_name",
+    )
+    .load::<TableName>(connection)
+    .map(|tables| {
+        tables
+            .into_iter()
+            .map(|table| table.name)
+            .filter(|name| !EXCLUDED_TABLES.contains(&name.as_str()))
+            .collect()
+    })
+}
+
+/// Table names go into SQL as identifiers, so a tampered manifest mustn't be able to smuggle anything else in.
+fn check_table_name(table: &str) -> Result<(), BackupError> {
+    if table.len() > 0
+        && table
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(BackupError::Invalid(format!(
+            "{} isn't a valid table name",
+            table
+        )))
+    }
+}
+
+/// Writes every row of the table as a line of JSON, reading through a cursor so big tables aren't loaded at once.
+fn export_table(
+    table: &str,
+    file: &mut impl Write,
+    connection: &PgConnection,
+) -> Result<u64, BackupError> {
+    check_table_name(table)?;
+
+    diesel::sql_query(format!(
+        "DECLARE backup_rows NO SCROLL CURSOR FOR SELECT row_to_json(t)::text AS row FROM \"{}\" t",
+        table
+    ))
+    .execute(connection)?;
+
+    let mut rows = 0;
+
+    loop {
+        let batch = diesel::sql_query(format!("FETCH {} FROM backup_rows", BACKUP_BATCH_ROWS))
+            .load::<JsonRow>(connection)?;
+
+        for row in &batch {
+            file.write_all(row.row.as_bytes())?;
+            file.write_all(b"\n")?;
+        }
+        rows += batch.len() as u64;
+
+        if batch.len() < BACKUP_BATCH_ROWS {
+            break;
+        }
+    }
+
+    diesel::sql_query("CLOSE backup_rows").execute(connection)?;
+
+    Ok(rows)
+}
+
+fn table_path(backup: &Path, table: &str) -> PathBuf {
+    backup.join("tables").join(format!("{}.jsonl", table))
+}
+
+/// Backs up every table to backup_directory() from one snapshot of the database, along with a manifest of the
+/// media the snapshot refers to.
+///
+/// The tables are read in a repeatable read transaction, so they're consistent with each other however long the
+/// backup takes. The media store is listed straight after the snapshot is taken, and only images uploaded before
+/// it (to the second) go in the manifest. Images deleted while the backup runs are still listed, as the backed up
+/// events can refer to them.
+pub fn back_up(connection: &PgConnection) -> Result<BackupManifest, BackupError> {
+    let directory = backup_directory().ok_or_else(|| {
+        BackupError::Invalid(String::from("backup_directory in [storage] isn't set"))
+    })?;
+
+    let created_at = Utc::now();
+    let name = created_at.format("%Y-%m-%dT%H-%M-%SZ").to_string();
+    let partial = Path::new(directory).join(format!("{}{}", name, PARTIAL_SUFFIX));
+    fs::create_dir_all(partial.join("tables"))?;
+
+    let manifest = connection
+        .build_transaction()
+        .repeatable_read()
+        .read_only()
+        .run::<_, BackupError, _>(|| {
+            // The snapshot is taken by the transaction's first query
+            let tables = table_names(connection)?;
+            let snapshot_at = Utc::now();
+            let schema_version = schema_check::applied_version(connection)?;
+
+            let store = media_store();
+            let mut images = BTreeMap::new();
+            for camera_id in store.list_cameras()? {
+                let camera_images = store
+                    .list_images(&camera_id)?
+                    .into_iter()
+                    .filter(|image_id| *image_id <= snapshot_at.timestamp() as u64)
+                    .collect::<Vec<u64>>();
+
+                if camera_images.len() > 0 {
+                    images.insert(camera_id, camera_images);
+                }
+            }
+
+            let mut counts = BTreeMap::new();
+            for table in tables {
+                let mut file = BufWriter::new(File::create(table_path(&partial, &table))?);
+                let rows = export_table(&table, &mut file, connection)?;
+                file.flush()?;
+                counts.insert(table, rows);
+            }
+
+            let mut audio = BTreeMap::new();
+            for (camera_id, audio_id) in audio_clips::table
+                .select((audio_clips::camera_id, audio_clips::audio_id))
+                .order(audio_clips::audio_id)
+                .load::<(uuid::Uuid, i32)>(connection)?
+            {
+                audio
+                    .entry(camera_id)
+                    .or_insert_with(Vec::new)
+                    .push(audio_id);
+            }
+
+            Ok(BackupManifest {
+                name: name.clone(),
+                created_at,
+                schema_version,
+                tables: counts,
+                images,
+                audio,
+            })
+        })?;
+
+    fs::write(
+        partial.join(MANIFEST_FILE),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    fs::rename(&partial, Path::new(directory).join(&name))?;
+
+    Ok(manifest)
+}
+
+pub fn read_manifest(backup: &Path) -> Result<BackupManifest, BackupError> {
+    let manifest = fs::read(backup.join(MANIFEST_FILE))?;
+    Ok(serde_json::from_slice(&manifest)?)
+}
+
+/// Every complete backup in backup_directory(), newest first.
+pub fn list_backups() -> Result<Vec<BackupManifest>, BackupError> {
+    let directory = match backup_directory() {
+        Some(directory) => directory,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            continue;
+        }
+
+        match read_manifest(&entry.path()) {
+            Ok(manifest) => backups.push(manifest),
+            Err(error) => warn!(
+                "Skipping {} in backup_directory, as its manifest couldn't be read: {}",
+                entry.path().display(),
+                error
+            ),
+        }
+    }
+
+    backups.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+    Ok(backups)
+}
+
+fn import_batch(table: &str, batch: &[String], connection: &PgConnection) -> QueryResult<usize> {
+    diesel::sql_query(format!(
+        "INSERT INTO \"{0}\" SELECT * FROM json_populate_recordset(NULL::\"{0}\", $1::json)",
+        table
+    ))
+    .bind::<Text, _>(format!("[{}]", batch.join(",")))
+    .execute(connection)
+}
+
+/// Serial columns would otherwise hand out IDs the restored rows already have.
+const RESET_SEQUENCES: &str = "DO $$
+DECLARE serial record;
+BEGIN
+    FOR serial IN SELECT table_name, column_name FROM information_schema.columns
+        WHERE table_schema = current_schema() AND column_default LIKE 'nextval(%'
+    LOOP
+        EXECUTE format(
+            'SELECT setval(pg_get_serial_sequence(%L, %L), COALESCE(MAX(%I), 0) + 1, false) FROM %I',
+            serial.table_name, serial.column_name, serial.column_name, serial.table_name
+        );
+    END LOOP;
+END $$";
+
+/// Replaces everything in the database with the backup, after migrating it to the backup's schema version.
+/// This is all done in one transaction, so a failed restore leaves the database as it was.
+///
+/// Foreign keys and triggers are turned off while the rows go in, as the tables are restored in name order
+/// rather than the order they refer to each other. That needs the database user to be a superuser.
+pub fn restore(backup: &Path, connection: &PgConnection) -> Result<BackupManifest, BackupError> {
+    let manifest = read_manifest(backup)?;
+
+    health::run_migrations(connection)
+        .map_err(|error| BackupError::Invalid(format!("Failed to migrate: {}", error)))?;
+
+    let schema_version = schema_check::applied_version(connection)?;
+    if schema_version != manifest.schema_version {
+        return Err(BackupError::Invalid(format!(
+            "The backup is from schema version {}, but the database is at {}. Restore it with the release it was made with",
+            manifest.schema_version, schema_version
+        )));
+    }
+
+    connection.transaction::<_, BackupError, _>(|| {
+        diesel::sql_query("SET LOCAL session_replication_role = replica").execute(connection)?;
+
+        for table in manifest.tables.keys() {
+            check_table_name(table)?;
+            diesel::sql_query(format!("DELETE FROM \"{}\"", table)).execute(connection)?;
+
+            let mut batch = Vec::new();
+            for line in BufReader::new(File::open(table_path(backup, table))?).lines() {
+                batch.push(line?);
+
+                if batch.len() == BACKUP_BATCH_ROWS {
+                    import_batch(table, &batch, connection)?;
+                    batch.clear();
+                }
+            }
+
+            if batch.len() > 0 {
+                import_batch(table, &batch, connection)?;
+            }
+        }
+
+        diesel::sql_query(RESET_SEQUENCES).execute(connection)?;
+
+        Ok(())
+    })?;
+
+    Ok(manifest)
+}
+
+/// The images and audio clips in the manifest that aren't where they should be, e.g. because the media store's
+/// own backup is older than the database's.
+pub fn missing_media(manifest: &BackupManifest) -> Result<Vec<String>, BackupError> {
+    let store = media_store();
+    let mut missing = Vec::new();
+
+    for (camera_id, images) in &manifest.images {
+        let stored = store.list_images(camera_id)?;
+
+        for image_id in images {
+            if stored.binary_search(image_id).is_err() {
+                missing.push(format!("image {} of camera {}", image_id, camera_id));
+            }
+        }
+    }
+
+    for (camera_id, audio_ids) in &manifest.audio {
+        for audio_id in audio_ids {
+            if !Path::new(&audio::audio_path(camera_id, *audio_id)).exists() {
+                missing.push(format!("audio clip {} of camera {}", audio_id, camera_id));
+            }
+        }
+    }
+
+    Ok(missing)
+}
+
+fn backups_error(error: BackupError) -> ApiError {
+    error!("Failed to list backups! The error was {}", error);
+    ApiError {
+        error: "Failed to list backups",
+        code: "list_backups_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Queues a backup, returning its job. It's done once the job has succeeded, see GET /Admin/Jobs/<job_id>.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Backups")]
+pub fn start_backup(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Job>, ApiError> {
+    if backup_directory().is_none() {
+        return Err(ApiError {
+            error: "Backups aren't configured",
+            code: "backups_not_configured",
+            status: Status::UnprocessableEntity,
+            field: None,
+        });
+    }
+
+    match jobs::enqueue_unless_pending(jobs::BACKUP_JOB, json!({}), Utc::now(), &conn) {
+        Ok(Some(job)) => Ok(Json(job)),
+        Ok(None) => Err(ApiError {
+            error: "A backup is already queued or running",
+            code: "backup_in_progress",
+            status: Status::Conflict,
+            field: None,
+        }),
+        Err(error) => {
+            error!("Failed to queue a backup! The error was {}", error);
+            Err(ApiError {
+                error: "Failed to queue a backup",
+                code: "queue_backup_failed",
+                status: Status::InternalServerError,
+                field: None,
+            })
+        }
+    }
+}
+
+/// Every complete backup, newest first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Backups")]
+pub fn get_backups(_admin_token: AdminToken) -> Result<Json<Vec<BackupManifest>>, ApiError> {
+    list_backups().map(Json).map_err(backups_error)
+}
@@ -0,0 +1,235 @@
+use crate::{
+    api_error::ApiError, camera::CameraId, user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera, worker, CameraServerDbConn,
+};
+
+use super::schema::bandwidth_daily;
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Date, Uuid as SqlUuid};
+use once_cell::sync::Lazy;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{get, Request, Response};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often each server adds the bandwidth it has counted to bandwidth_daily.
+pub const BANDWIDTH_FLUSH_SECONDS: u64 = 60;
+
+/// GET /Cameras/<camera_id>/Bandwidth covers this many days if it isn't given a range.
+pub const DEFAULT_BANDWIDTH_DAYS: i64 = 30;
+
+/// Bytes up and down by camera and day since the last flush.
+static PENDING: Lazy<Mutex<HashMap<(uuid::Uuid, NaiveDate), (i64, i64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn pending() -> std::sync::MutexGuard<'static, HashMap<(uuid::Uuid, NaiveDate), (i64, i64)>> {
+    PENDING.lock().expect("Bandwidth lock poisoned!")
+}
+
+fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+/// Counts bytes the camera sent to the server and got back from it.
+pub fn record(camera_id: uuid::Uuid, upload_bytes: u64, download_bytes: u64) {
+    let mut pending = pending();
+    let counts = pending.entry((camera_id, today())).or_insert((0, 0));
+
+    counts.0 += upload_bytes as i64;
+    counts.1 += download_bytes as i64;
+}
+
+/// The camera whose token the request was made with. Set by the CameraToken guard.
+struct MeteredCamera(Option<uuid::Uuid>);
+
+/// Marks the request as the camera's, so BandwidthMetering counts it.
+pub fn record_camera(request: &Request, camera_id: uuid::Uuid) {
+    request.local_cache(|| MeteredCamera(Some(camera_id)));
+}
+
+/// Counts the bodies of every request made with a camera token towards that camera: the request's Content-Length
+/// up, and the response's size down, after compression. Headers aren't counted, nor are streamed responses, as
+/// their size isn't known until they've been sent.
+pub struct BandwidthMetering;
+
+impl Fairing for BandwidthMetering {
+    fn info(&self) -> Info {
+        Info {
+            name: "Bandwidth metering",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let camera_id = match request.local_cache(|| MeteredCamera(None)).0 {
+            Some(camera_id) => camera_id,
+            None => return,
+        };
+
+        let upload_bytes = request
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        let download_bytes = response.body().and_then(|body| body.size()).unwrap_or(0);
+
+        record(camera_id, upload_bytes, download_bytes);
+    }
+}
+
+/// Adds what's been counted since the last flush to bandwidth_daily. If it can't be written, it's kept for next time.
+pub fn flush(connection: &PgConnection) -> QueryResult<()> {
+    let counted = std::mem::take(&mut *pending());
+    if counted.is_empty() {
+        return Ok(());
+    }
+
+    let result = connection.transaction(|| {
+        for ((camera_id, day), (upload_bytes, download_bytes)) in &counted {
+            // Cameras deleted since their requests were counted are skipped rather than failing the flush
+            diesel::sql_query(
+                "INSERT INTO bandwidth_daily (camera_id, day, upload_bytes, download_bytes)
+                SELECT $1, $2, $3, $4 WHERE EXISTS (SELECT 1 FROM cameras WHERE camera_id = $1)
+                ON CONFLICT (camera_id, day) DO UPDATE SET
+                    upload_bytes = bandwidth_daily.upload_bytes + EXCLUDED.upload_bytes,
+                    download_bytes = bandwidth_daily.download_bytes + EXCLUDED.download_bytes",
+            )
+            .bind::<SqlUuid, _>(camera_id)
+            .bind::<Date, _>(day)
+            .bind::<BigInt, _>(upload_bytes)
+            .bind::<BigInt, _>(download_bytes)
+            .execute(connection)?;
+        }
+
+        Ok(())
+    });
+
+    if result.is_err() {
+        let mut pending = pending();
+
+        for (key, (upload_bytes, download_bytes)) in counted {
+            let kept = pending.entry(key).or_insert((0, 0));
+            kept.0 += upload_bytes;
+            kept.1 += download_bytes;
+        }
+    }
+
+    result
+}
+
+/// Starts flushing bandwidth every BANDWIDTH_FLUSH_SECONDS. Every instance counts its own requests, so this runs on
+/// all of them.
+pub fn spawn_bandwidth_flusher(database_url: String) {
+    worker::spawn_concurrent_worker(
+        "Bandwidth metering",
+        Duration::from_secs(BANDWIDTH_FLUSH_SECONDS),
+        database_url,
+        |connection| {
+            if let Err(error) = flush(connection) {
+                error!("Failed to write bandwidth! The error was {}", error);
+            }
+        },
+    );
+}
+
+/// One camera's bandwidth on one day, in UTC.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct DailyBandwidth {
+    #[serde(skip)]
+    pub camera_id: uuid::Uuid,
+    pub day: NaiveDate,
+    /// Bytes the camera sent: images, audio, events and everything else it uploads.
+    pub upload_bytes: i64,
+    /// Bytes the camera was sent, like its config and commands.
+    pub download_bytes: i64,
+}
+
+/// What GET /Cameras/<camera_id>/Bandwidth returns.
+#[derive(Serialize, JsonSchema)]
+pub struct CameraBandwidth {
+    pub upload_bytes: i64,
+    pub download_bytes: i64,
+    /// Each day in the range the camera used any, oldest first.
+    pub days: Vec<DailyBandwidth>,
+}
+
+/// Query string for GET /Cameras/<camera_id>/Bandwidth.
+#[derive(FromForm, JsonSchema)]
+pub struct BandwidthQuery {
+    /// The first day to include, as YYYY-MM-DD. Defaults to DEFAULT_BANDWIDTH_DAYS ago.
+    pub from: Option<String>,
+    /// The last day to include, as YYYY-MM-DD. Defaults to today.
+    pub until: Option<String>,
+}
+
+impl BandwidthQuery {
+    fn days(&self) -> Result<(NaiveDate, NaiveDate), ApiError> {
+        let parse = |day: &String, field: &'static str| {
+            NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| ApiError {
+                error: "Failed to parse day, days must be YYYY-MM-DD",
+                code: "invalid_day",
+                status: Status::UnprocessableEntity,
+                field: Some(field),
+            })
+        };
+
+        let until = match &self.until {
+            Some(until) => parse(until, "until")?,
+            None => today(),
+        };
+        let from = match &self.from {
+            Some(from) => parse(from, "from")?,
+            None => until - ChronoDuration::days(DEFAULT_BANDWIDTH_DAYS - 1),
+        };
+
+        Ok((from, until))
+    }
+}
+
+/// How much the camera has sent and been sent each day, so users on metered connections can see what's using
+/// their data. Today's is up to BANDWIDTH_FLUSH_SECONDS behind.
+#[openapi]
+#[get("/Cameras/<camera_id>/Bandwidth?<query..>")]
+pub fn get_camera_bandwidth(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    query: Form<BandwidthQuery>,
+) -> Result<Json<CameraBandwidth>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+    let (from, until) = query.days()?;
+
+    let days = bandwidth_daily::table
+        .filter(bandwidth_daily::camera_id.eq(camera_id))
+        .filter(bandwidth_daily::day.between(from, until))
+        .order(bandwidth_daily::day)
+        .load::<DailyBandwidth>(&*conn)
+        .map_err(|error| {
+            error!(
+                "Failed to get bandwidth for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get bandwidth",
+                code: "get_bandwidth_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    Ok(Json(CameraBandwidth {
+        upload_bytes: days.iter().map(|day| day.upload_bytes).sum(),
+        download_bytes: days.iter().map(|day| day.download_bytes).sum(),
+        days,
+    }))
+}
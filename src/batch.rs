@@ -0,0 +1,163 @@
+use crate::{api_error::ApiError, api_version::API_PREFIX, user_tokens::UserToken};
+
+use rocket::http::Status;
+use rocket::{Config, State};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Batches are run one sub-request at a time, each taking up a worker, so they're kept small.
+pub const MAX_BATCH_REQUESTS: usize = 20;
+
+pub const SUB_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+
+/// Where sub-requests are sent. They go back through the server's own port so they get exactly the same
+/// routing, guards and fairings as any other request.
+pub struct Loopback {
+    pub base_url: String,
+    pub client: reqwest::blocking::Client,
+}
+
+impl Loopback {
+    pub fn from_config(config: &Config) -> Loopback {
+        Loopback {
+            base_url: format!("http://127.0.0.1:{}", config.port),
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(SUB_REQUEST_TIMEOUT_SECONDS))
+                .build()
+                .expect("Failed to build batch client!"),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SubRequest {
+    /// GET, POST, PUT, PATCH or DELETE.
+    pub method: String,
+    /// Relative to /api/v1, e.g. /Cameras. Query strings are allowed.
+    pub path: String,
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SubResponse {
+    pub status: u16,
+    /// None if the response wasn't JSON, e.g. an image. Those should be fetched directly.
+    pub body: Option<serde_json::Value>,
+}
+
+pub fn validate_sub_request(sub_request: &SubRequest) -> Result<reqwest::Method, ApiError> {
+    let method = match sub_request.method.to_uppercase().as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "PATCH" => reqwest::Method::PATCH,
+        "DELETE" => reqwest::Method::DELETE,
+        _ => {
+            return Err(ApiError {
+                error: "Method must be GET, POST, PUT, PATCH or DELETE",
+                code: "invalid_method",
+                status: Status::UnprocessableEntity,
+                field: Some("method"),
+            })
+        }
+    };
+
+    if !sub_request.path.starts_with('/') {
+        return Err(ApiError {
+            error: "Path must start with /",
+            code: "invalid_path",
+            status: Status::UnprocessableEntity,
+            field: Some("path"),
+        });
+    }
+
+    if sub_request.path.starts_with("/Batch") {
+        return Err(ApiError {
+            error: "Batches can't contain other batches",
+            code: "nested_batch",
+            status: Status::UnprocessableEntity,
+            field: Some("path"),
+        });
+    }
+
+    Ok(method)
+}
+
+fn run_sub_request(
+    loopback: &Loopback,
+    user_token: &UserToken,
+    method: reqwest::Method,
+    sub_request: SubRequest,
+) -> SubResponse {
+    let mut request = loopback
+        .client
+        .request(
+            method,
+            &format!("{}{}{}", loopback.base_url, API_PREFIX, sub_request.path),
+        )
+        .header("user_token", user_token.user_token.to_string());
+
+    if let Some(body) = &sub_request.body {
+        request = request.json(body);
+    }
+
+    match request.send() {
+        Ok(response) => SubResponse {
+            status: response.status().as_u16(),
+            body: response
+                .text()
+                .ok()
+                .and_then(|text| serde_json::from_str(&text).ok()),
+        },
+        Err(error) => {
+            error!(
+                "Failed to run batched {} {}! The error was {}",
+                sub_request.method, sub_request.path, error
+            );
+            SubResponse {
+                status: if error.is_timeout() { 504 } else { 500 },
+                body: None,
+            }
+        }
+    }
+}
+
+/// Runs each sub-request in order with the caller's user token, and returns their results in the same order.
+/// A sub-request failing doesn't stop the rest from running.
+#[openapi]
+#[post("/Batch", format = "json", data = "<sub_requests>")]
+pub fn batch(
+    user_token: UserToken,
+    loopback: State<Loopback>,
+    sub_requests: Json<Vec<SubRequest>>,
+) -> Result<Json<Vec<SubResponse>>, ApiError> {
+    let sub_requests = sub_requests.into_inner();
+
+    if sub_requests.len() > MAX_BATCH_REQUESTS {
+        return Err(ApiError {
+            error: "Batches can have at most 20 requests",
+            code: "batch_too_large",
+            status: Status::UnprocessableEntity,
+            field: None,
+        });
+    }
+
+    // Check everything first, so a bad sub-request doesn't leave the batch half done
+    let methods = sub_requests
+        .iter()
+        .map(validate_sub_request)
+        .collect::<Result<Vec<reqwest::Method>, ApiError>>()?;
+
+    Ok(Json(
+        sub_requests
+            .into_iter()
+            .zip(methods)
+            .map(|(sub_request, method)| {
+                run_sub_request(&loopback, &user_token, method, sub_request)
+            })
+            .collect(),
+    ))
+}
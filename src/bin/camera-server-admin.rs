@@ -0,0 +1,455 @@
+//! Operations that would otherwise mean writing SQL by hand. Connects to the database the server is configured with,
+//! so it reads the same Rocket.toml, camera-server.toml and environment variables.
+
+use camera_server::{
+    backup, bootstrap, bulk_registration, camera, database, event_retention,
+    footage_import::{self, ImportOptions, PathPattern},
+    media_store::{self, media_store},
+    seed, settings, soft_delete, tenant,
+    user::{self, InsertableUser},
+    user_tokens, worker,
+};
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: camera-server-admin <command>
+
+Commands:
+    create-user <username>      Creates a user, reading their password from stdin
+    reset-password <username>   Changes a user's password, reading it from stdin, and logs them out everywhere
+    list-cameras                Lists every camera that hasn't been deleted
+    prune-media [--days <days>] Prunes old events, purges deleted cameras and users, and moves old images to
+                                cold storage now rather than waiting for the workers. --days replaces
+                                event_retention_days in [limits]
+    import-devices <file>       Adds a manufacturer's list of cameras that can bootstrap, one
+                                <hardware_id>,<claim_token> per line. Devices already imported are left as they are
+    register-cameras <file> <username>
+                                Registers a CSV of cameras for a user, one name,hardware_id,location,tags per line,
+                                and prints each one's ID and token. Nothing is registered if any line can't be
+    seed                        Fills an empty database with demo users, cameras and a week of events
+    backup                      Backs up the database and the media manifest to backup_directory in [storage]
+    restore-backup <directory>  Replaces everything in the database with a backup, then checks its media is there
+    import-footage <directory> [--pattern <pattern>] [--camera <camera_id>] [--utc-offset <+HH:MM>] [--dry-run]
+                                Imports images and audio clips from another system, e.g. motionEye's media directory.
+                                The camera and time come from each file's path, matched against --pattern, which
+                                defaults to {camera}/{year}-{month}-{day}/{hour}-{minute}-{second}. {camera} is a
+                                camera's ID or name, and --camera puts everything in one camera instead. Times are
+                                UTC unless --utc-offset is given";
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+fn connect() -> PgConnection {
+    let config = database::with_timeouts(rocket::ignite().config().clone());
+    let database_url = worker::database_url(&config);
+
+    PgConnection::establish(&database_url).unwrap_or_else(|error| {
+        fail(format!(
+            "Failed to connect to the database! The error was {}",
+            error
+        ))
+    })
+}
+
+/// Reads a password from the first line of stdin, so it doesn't end up in the shell's history.
+fn read_password() -> String {
+    print!("Password: ");
+    io::stdout().flush().ok();
+
+    let mut password = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .unwrap_or_else(|error| {
+            fail(format!(
+                "Failed to read the password! The error was {}",
+                error
+            ))
+        });
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+
+    if password.chars().count() < user::MIN_PASSWORD_LENGTH {
+        fail(format!(
+            "Password must be at least {} characters long",
+            user::MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    password
+}
+
+fn hash_password(password: &str) -> String {
+    user::hash_password(password).unwrap_or_else(|error| {
+        fail(format!(
+            "Failed to hash the password! The error was {}",
+            error
+        ))
+    })
+}
+
+/// Admins are set with ADMIN_USER_IDS, so the new user's ID is printed to be added there.
+/// Users made here are in the default tenant, like admins.
+fn create_user(username: String) {
+    let connection = connect();
+
+    if user::get_by_username(username.clone(), tenant::DEFAULT_TENANT_ID, &connection).is_ok() {
+        fail(format!("Username {} already exists", username));
+    }
+
+    let password = hash_password(&read_password());
+
+    let new_user = user::insert(
+        InsertableUser { username, password },
+        tenant::DEFAULT_TENANT_ID,
+        &connection,
+    )
+    .unwrap_or_else(|error| {
+        fail(format!(
+            "Failed to create the user! The error was {}",
+            error
+        ))
+    });
+
+    println!("Created {} with ID {}", new_user.username, new_user.user_id);
+    println!("Add the ID to ADMIN_USER_IDS to make them an admin");
+}
+
+fn reset_password(username: String) {
+    let connection = connect();
+
+    let mut existing_user =
+        user::get_by_username(username.clone(), tenant::DEFAULT_TENANT_ID, &connection)
+            .unwrap_or_else(|_| fail(format!("No user called {}", username)));
+    existing_user.password = hash_password(&read_password());
+
+    let user_id = existing_user.user_id;
+    let result = connection.transaction::<_, diesel::result::Error, _>(|| {
+        user::update(user_id, existing_user, &connection)?;
+        user_tokens::delete_users_tokens(user_id, &connection)
+    });
+
+    match result {
+        Ok(logged_out) => println!(
+            "Changed the password for {} and logged out {} sessions",
+            username, logged_out
+        ),
+        Err(error) => fail(format!(
+            "Failed to change the password! The error was {}",
+            error
+        )),
+    }
+}
+
+fn list_cameras() {
+    let connection = connect();
+
+    let cameras = camera::all(&connection)
+        .unwrap_or_else(|error| fail(format!("Failed to get cameras! The error was {}", error)));
+
+    for camera in cameras {
+        println!(
+            "{}\t{}\t{}\t{}",
+            camera.camera_id,
+            camera.name,
+            if camera.online { "online" } else { "offline" },
+            camera
+                .last_seen_at
+                .map(|last_seen_at| last_seen_at.to_rfc3339())
+                .unwrap_or_else(|| String::from("never seen"))
+        );
+    }
+}
+
+fn prune_media(days: Option<i64>) {
+    let connection = connect();
+
+    match days.or_else(event_retention::event_retention_days) {
+        Some(days) => match event_retention::prune_events(days, &connection) {
+            Ok((deleted, anonymised)) => println!(
+                "Deleted {} events older than {} days and anonymised {} on hold",
+                deleted, days, anonymised
+            ),
+            Err(error) => fail(format!("Failed to prune events! The error was {}", error)),
+        },
+        None => {
+            println!("event_retention_days isn't set and --days wasn't given, keeping every event")
+        }
+    }
+
+    let retention_days = soft_delete::soft_delete_retention_days();
+    match soft_delete::purge_deleted(retention_days, &connection) {
+        Ok((cameras, users)) => println!(
+            "Purged {} cameras and {} users deleted more than {} days ago",
+            cameras, users, retention_days
+        ),
+        Err(error) => fail(format!(
+            "Failed to purge deleted cameras and users! The error was {}",
+            error
+        )),
+    }
+
+    if let Some(days) = media_store::cold_storage_after_days() {
+        match media_store().migrate_older_than(Duration::from_secs(days * 24 * 60 * 60)) {
+            Ok(moved) => println!("Moved {} images to cold storage", moved),
+            Err(error) => fail(format!(
+                "Failed to move images to cold storage! The error was {}",
+                error
+            )),
+        }
+    }
+}
+
+fn seed_database() {
+    let connection = connect();
+
+    let seeded = seed::seed(&connection).unwrap_or_else(|error| fail(error));
+
+    println!("Every user's password is {}", seed::SEED_PASSWORD);
+    println!(
+        "{}\t{}\tuser_token {}",
+        seed::OWNER_USERNAME,
+        seeded.owner_id,
+        seeded.owner_token
+    );
+    println!(
+        "{}\t{}\tuser_token {}",
+        seed::VIEWER_USERNAME,
+        seeded.viewer_id,
+        seeded.viewer_token
+    );
+
+    for (camera_id, name, camera_token) in seeded.cameras {
+        println!("{}\t{}\tcamera_token {}", name, camera_id, camera_token);
+    }
+
+    println!("Added {} events", seeded.events);
+}
+
+fn back_up() {
+    let connection = connect();
+
+    match backup::back_up(&connection) {
+        Ok(manifest) => println!(
+            "Backed up {} tables as {}",
+            manifest.tables.len(),
+            manifest.name
+        ),
+        Err(error) => fail(format!("Failed to back up! The error was {}", error)),
+    }
+}
+
+fn restore_backup(directory: String) {
+    let connection = connect();
+
+    print!("This replaces everything in the database. Type yes to carry on: ");
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).ok();
+    if answer.trim() != "yes" {
+        fail(String::from("Not restoring"));
+    }
+
+    let manifest = backup::restore(Path::new(&directory), &connection)
+        .unwrap_or_else(|error| fail(format!("Failed to restore! The error was {}", error)));
+
+    println!(
+        "Restored {} rows from {}",
+        manifest.tables.values().sum::<u64>(),
+        manifest.name
+    );
+
+    match backup::missing_media(&manifest) {
+        Ok(missing) if missing.len() == 0 => println!("Every image and audio clip is there"),
+        Ok(missing) => {
+            println!(
+                "{} images and audio clips in the backup are missing from the media store:",
+                missing.len()
+            );
+            for media in missing {
+                println!("\t{}", media);
+            }
+        }
+        Err(error) => fail(format!(
+            "Failed to check the media store! The error was {}",
+            error
+        )),
+    }
+}
+
+fn import_devices(file: String) {
+    let connection = connect();
+
+    match bootstrap::import(Path::new(&file), &connection) {
+        Ok(imported) => println!("Imported {} devices", imported),
+        Err(error) => fail(format!("Failed to import devices! The error was {}", error)),
+    }
+}
+
+fn register_cameras(file: String, username: Option<String>) {
+    let username = username.unwrap_or_else(|| fail(String::from(USAGE)));
+    let contents = fs::read_to_string(&file)
+        .unwrap_or_else(|error| fail(format!("Failed to read {}! The error was {}", file, error)));
+    let connection = connect();
+
+    let owner = user::get_by_username(username.clone(), tenant::DEFAULT_TENANT_ID, &connection)
+        .unwrap_or_else(|_| fail(format!("No user called {}", username)));
+
+    let report = bulk_registration::parse_csv(&contents)
+        .map(|rows| {
+            bulk_registration::register(rows, owner.user_id, &connection).unwrap_or_else(|error| {
+                fail(format!(
+                    "Failed to register cameras! The error was {}",
+                    error.error
+                ))
+            })
+        })
+        .unwrap_or_else(|errors| bulk_registration::RegistrationReport {
+            registered: false,
+            cameras: Vec::new(),
+            errors,
+        });
+
+    if !report.registered {
+        for error in report.errors {
+            eprintln!("Line {}: {}", error.line, error.error);
+        }
+        fail(String::from("No cameras were registered"));
+    }
+
+    for camera in &report.cameras {
+        println!(
+            "{}	{}	{}	camera_token {}",
+            camera.line, camera.name, camera.camera_id, camera.camera_token
+        );
+    }
+    println!(
+        "Registered {} cameras for {}",
+        report.cameras.len(),
+        username
+    );
+}
+
+fn import_footage(directory: String, mut flags: impl Iterator<Item = String>) {
+    let mut pattern = String::from(footage_import::MOTIONEYE_PATTERN);
+    let mut camera_id = None;
+    let mut utc_offset = None;
+    let mut dry_run = false;
+
+    while let Some(flag) = flags.next() {
+        let mut value = || {
+            flags
+                .next()
+                .unwrap_or_else(|| fail(format!("{} needs a value", flag)))
+        };
+
+        match flag.as_str() {
+            "--pattern" => pattern = value(),
+            "--camera" => {
+                camera_id = Some(
+                    uuid::Uuid::parse_str(&value())
+                        .unwrap_or_else(|_| fail(String::from("--camera must be a camera ID"))),
+                )
+            }
+            "--utc-offset" => {
+                utc_offset = Some(
+                    footage_import::parse_utc_offset(&value()).unwrap_or_else(|| {
+                        fail(String::from("--utc-offset must look like +01:00 or -05:30"))
+                    }),
+                )
+            }
+            "--dry-run" => dry_run = true,
+            _ => fail(String::from(USAGE)),
+        }
+    }
+
+    let pattern = PathPattern::parse(&pattern).unwrap_or_else(|error| fail(error));
+    if !pattern.has_camera() && camera_id.is_none() {
+        fail(String::from(
+            "The pattern has no {camera}, so --camera is needed to say which camera to import into",
+        ));
+    }
+
+    let connection = connect();
+    let options = ImportOptions {
+        pattern,
+        camera_id,
+        utc_offset: utc_offset.unwrap_or_else(|| chrono::FixedOffset::east(0)),
+        dry_run,
+    };
+
+    let report = footage_import::import(Path::new(&directory), &options, &connection)
+        .unwrap_or_else(|error| fail(format!("Failed to import! The error was {}", error)));
+
+    println!(
+        "{} {} images and {} audio clips",
+        if dry_run { "Would import" } else { "Imported" },
+        report.images,
+        report.audio_clips
+    );
+    if report.duplicates > 0 {
+        println!(
+            "Skipped {} images taken in the same second as another",
+            report.duplicates
+        );
+    }
+    if report.unsupported > 0 {
+        println!(
+            "Skipped {} files that aren't images or audio clips",
+            report.unsupported
+        );
+    }
+    if report.unmatched.len() > 0 {
+        println!(
+            "Skipped {} files that don't match the pattern:",
+            report.unmatched.len()
+        );
+        for path in report.unmatched.iter().take(10) {
+            println!("\t{}", path.display());
+        }
+    }
+    for camera in &report.unknown_cameras {
+        println!("Skipped files for {}, which isn't a camera", camera);
+    }
+    for (path, error) in &report.failed {
+        println!("Failed to import {}: {}", path.display(), error);
+    }
+}
+
+fn main() {
+    settings::settings();
+    camera_server::logging::init_from_env();
+
+    let mut args = env::args().skip(1);
+    let command = args.next();
+    let argument = args.next();
+
+    match (command.as_deref(), argument) {
+        (Some("create-user"), Some(username)) => create_user(username),
+        (Some("reset-password"), Some(username)) => reset_password(username),
+        (Some("list-cameras"), None) => list_cameras(),
+        (Some("seed"), None) => seed_database(),
+        (Some("backup"), None) => back_up(),
+        (Some("restore-backup"), Some(directory)) => restore_backup(directory),
+        (Some("import-footage"), Some(directory)) => import_footage(directory, args),
+        (Some("import-devices"), Some(file)) => import_devices(file),
+        (Some("register-cameras"), Some(file)) => register_cameras(file, args.next()),
+        (Some("prune-media"), None) => prune_media(None),
+        (Some("prune-media"), Some(flag)) if flag == "--days" => {
+            let days = args
+                .next()
+                .and_then(|days| days.parse::<i64>().ok())
+                .unwrap_or_else(|| fail(String::from("--days must be a whole number of days")));
+            prune_media(Some(days));
+        }
+        _ => fail(String::from(USAGE)),
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    api_error::ApiError,
+    camera::{register_camera, InsertableCamera},
+    camera_tokens::{self, CameraToken, InsertableCameraToken},
+    database,
+    device_format::{Device, DeviceBody},
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::bootstrap_devices;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::post;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A camera the manufacturer has burned a claim token into. Only the token's hash is kept, so the list can't be
+/// used to impersonate cameras that haven't been set up yet.
+#[derive(Queryable)]
+pub struct BootstrapDevice {
+    pub hardware_id: String,
+    pub claim_token_hash: String,
+    pub imported_at: DateTime<Utc>,
+    /// Who added the device with POST /Cameras/Bootstrap. Its camera is created for them.
+    pub user_id: Option<uuid::Uuid>,
+    pub name: Option<String>,
+    /// The camera the device's key is for, once it has exchanged its claim token, or the camera made for it if it was
+    /// registered in bulk.
+    pub camera_id: Option<uuid::Uuid>,
+    pub exchanged_at: Option<DateTime<Utc>>,
+}
+
+/// What POST /Cameras/Bootstrap returns.
+#[derive(Serialize, JsonSchema)]
+pub struct AddedDevice {
+    pub hardware_id: String,
+    pub name: String,
+    /// None until the camera has come online and exchanged its claim token.
+    #[schemars(with = "Option<String>")]
+    pub camera_id: Option<uuid::Uuid>,
+}
+
+/// What a user sends to add a camera by the hardware ID on its label.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewBootstrapDevice {
+    pub hardware_id: String,
+    pub name: String,
+}
+
+/// What a camera sends the first time it starts.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct BootstrapRequest {
+    pub hardware_id: String,
+    pub claim_token: String,
+}
+
+fn hash_claim_token(claim_token: &str) -> String {
+    hex::encode(Sha256::digest(claim_token.trim().as_bytes()))
+}
+
+/// Reads a manufacturer's list of devices, one `<hardware_id>,<claim_token>` per line, and adds any that aren't
+/// already known. Blank lines and lines starting with # are skipped. Returns how many were added.
+pub fn import(path: &Path, connection: &PgConnection) -> io::Result<usize> {
+    let contents = fs::read_to_string(path)?;
+    let mut devices = Vec::new();
+
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.splitn(2, ',');
+        match (fields.next(), fields.next()) {
+            (Some(hardware_id), Some(claim_token))
+                if !hardware_id.trim().is_empty() && !claim_token.trim().is_empty() =>
+            {
+                devices.push((
+                    bootstrap_devices::hardware_id.eq(hardware_id.trim().to_string()),
+                    bootstrap_devices::claim_token_hash.eq(hash_claim_token(claim_token)),
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {} isn't <hardware_id>,<claim_token>", line_number + 1),
+                ))
+            }
+        }
+    }
+
+    if devices.is_empty() {
+        return Ok(0);
+    }
+
+    // Devices already imported keep their claim token, so importing a list twice can't undo an exchange
+    diesel::insert_into(bootstrap_devices::table)
+        .values(devices)
+        .on_conflict_do_nothing()
+        .execute(connection)
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
+}
+
+pub fn get_device(
+    hardware_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<BootstrapDevice>> {
+    bootstrap_devices::table
+        .find(hardware_id)
+        .get_result::<BootstrapDevice>(connection)
+        .optional()
+}
+
+/// Adds a device for a camera that's already been created, e.g. by bulk_registration. When the device exchanges its
+/// claim token, it's given a token for that camera rather than having a new one made.
+pub fn add_device_for_camera(
+    hardware_id: &str,
+    user_id: uuid::Uuid,
+    name: &str,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(
+        bootstrap_devices::table
+            .find(hardware_id)
+            .filter(bootstrap_devices::camera_id.is_null()),
+    )
+    .set((
+        bootstrap_devices::user_id.eq(user_id),
+        bootstrap_devices::name.eq(name),
+        bootstrap_devices::camera_id.eq(camera_id),
+        bootstrap_devices::exchanged_at.eq(None::<DateTime<Utc>>),
+    ))
+    .execute(connection)
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to bootstrap device! The error was {}", error);
+    ApiError {
+        error: "Failed to bootstrap device",
+        code: "bootstrap_device_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Adds a camera by the hardware ID on its label. The camera itself is created, for whoever added it, when it
+/// first starts and exchanges its claim token with POST /Device/Bootstrap. Until then it can be added again,
+/// with a different name or by someone else.
+#[openapi]
+#[post("/Cameras/Bootstrap", format = "json", data = "<new_device>")]
+pub fn add_bootstrap_device(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_device: Json<NewBootstrapDevice>,
+) -> Result<Json<AddedDevice>, ApiError> {
+    let new_device = new_device.into_inner();
+    let hardware_id = new_device.hardware_id.trim();
+
+    let device = get_device(hardware_id, &conn)
+        .map_err(database_error)?
+        .ok_or(ApiError {
+            error: "Hardware ID not found",
+            code: "hardware_id_not_found",
+            status: Status::NotFound,
+            field: Some("hardware_id"),
+        })?;
+
+    if device.camera_id.is_some() {
+        return Err(ApiError {
+            error: "Device has already been added",
+            code: "device_already_added",
+            status: Status::Conflict,
+            field: Some("hardware_id"),
+        });
+    }
+
+    diesel::update(
+        bootstrap_devices::table
+            .find(hardware_id)
+            .filter(bootstrap_devices::camera_id.is_null()),
+    )
+    .set((
+        bootstrap_devices::user_id.eq(user_token.user_id),
+        bootstrap_devices::name.eq(&new_device.name),
+        bootstrap_devices::exchanged_at.eq(None::<DateTime<Utc>>),
+    ))
+    .execute(&*conn)
+    .map_err(database_error)?;
+
+    Ok(Json(AddedDevice {
+        hardware_id: device.hardware_id,
+        name: new_device.name,
+        camera_id: None,
+    }))
+}
+
+/// Exchanges a factory claim token for the camera's own token, which it uses for everything afterwards. Each
+/// claim token can only be exchanged once, and only after a user has added the device, so cameras should retry
+/// every so often until this succeeds.
+#[openapi]
+#[post("/Device/Bootstrap", data = "<bootstrap_request>")]
+pub fn exchange_claim_token(
+    conn: CameraServerDbConn,
+    bootstrap_request: DeviceBody<BootstrapRequest>,
+) -> Result<Device<CameraToken>, ApiError> {
+    let bootstrap_request = bootstrap_request.into_inner();
+    let hardware_id = bootstrap_request.hardware_id.trim();
+
+    database::transaction(&conn, || {
+        let device = bootstrap_devices::table
+            .find(hardware_id)
+            .for_update()
+            .get_result::<BootstrapDevice>(&*conn)
+            .optional()
+            .map_err(database_error)?
+            .filter(|device| {
+                device.claim_token_hash == hash_claim_token(&bootstrap_request.claim_token)
+            })
+            .ok_or(ApiError {
+                error: "Invalid hardware ID or claim token",
+                code: "invalid_claim_token",
+                status: Status::Unauthorized,
+                field: None,
+            })?;
+
+        if device.exchanged_at.is_some() {
+            return Err(ApiError {
+                error: "Claim token has already been exchanged",
+                code: "claim_token_used",
+                status: Status::Conflict,
+                field: None,
+            });
+        }
+
+        let camera_token = match (device.camera_id, device.user_id, device.name) {
+            // Added with a camera already made for it, so it only needs a token
+            (Some(camera_id), _, _) => {
+                camera_tokens::insert(InsertableCameraToken { camera_id }, &conn)
+                    .map_err(database_error)?
+            }
+            (None, Some(user_id), Some(name)) => {
+                register_camera(InsertableCamera { name }, user_id, &conn)?
+            }
+            _ => {
+                return Err(ApiError {
+                    error: "Device hasn't been added by a user yet",
+                    code: "device_not_added",
+                    status: Status::Conflict,
+                    field: None,
+                })
+            }
+        };
+
+        diesel::update(bootstrap_devices::table.find(hardware_id))
+            .set((
+                bootstrap_devices::camera_id.eq(camera_token.camera_id),
+                bootstrap_devices::exchanged_at.eq(Utc::now()),
+            ))
+            .execute(&*conn)
+            .map_err(database_error)?;
+
+        info!(
+            "Device {} exchanged its claim token for camera {}",
+            hardware_id, camera_token.camera_id
+        );
+
+        Ok(Device(camera_token))
+    })
+}
@@ -0,0 +1,493 @@
+use crate::{
+    api_error::ApiError,
+    bootstrap,
+    camera::{self, Camera, InsertableCamera},
+    database, home_assistant,
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::cameras;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::{post, Data};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Read;
+
+/// More than enough for a site's worth of cameras, while keeping the transaction they're made in short.
+pub const MAX_ROWS: usize = 500;
+
+const MAX_CSV_BYTES: u64 = 1024 * 1024;
+
+/// One camera from the CSV.
+pub struct RegistrationRow {
+    /// Counting from 1, including the header and skipped lines, so it matches what a spreadsheet shows.
+    pub line: usize,
+    pub name: String,
+    pub hardware_id: Option<String>,
+    pub location: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RegisteredCamera {
+    pub line: usize,
+    pub name: String,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// What the camera authenticates with, to be set on it by the installer. Cameras with a hardware ID can
+    /// instead get their own by exchanging their claim token when they first start.
+    #[schemars(with = "String")]
+    pub camera_token: uuid::Uuid,
+    pub hardware_id: Option<String>,
+    pub location: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct RowError {
+    pub line: usize,
+    pub error: String,
+}
+
+/// What registering a CSV of cameras did. Either every camera was registered, or none were and errors says why.
+#[derive(Serialize, JsonSchema)]
+pub struct RegistrationReport {
+    pub registered: bool,
+    pub cameras: Vec<RegisteredCamera>,
+    pub errors: Vec<RowError>,
+}
+
+impl RegistrationReport {
+    fn failed(errors: Vec<RowError>) -> RegistrationReport {
+        RegistrationReport {
+            registered: false,
+            cameras: Vec::new(),
+            errors,
+        }
+    }
+}
+
+/// Splits a CSV line into its fields. Fields can be quoted to have commas in them, with "" for a quote.
+fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(String::from("A quoted field isn't closed"));
+    }
+    fields.push(field);
+
+    Ok(fields)
+}
+
+fn non_empty(field: Option<&String>) -> Option<String> {
+    field
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+}
+
+/// Reads `name,hardware_id,location,tags` rows. Only the name is needed, and tags are separated by semicolons.
+/// A first line naming the columns is skipped, as are blank lines and lines starting with #. Every line's problem
+/// is returned, rather than only the first.
+pub fn parse_csv(contents: &str) -> Result<Vec<RegistrationRow>, Vec<RowError>> {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let fields = match split_csv_line(trimmed) {
+            Ok(fields) => fields,
+            Err(error) => {
+                errors.push(RowError {
+                    line: line_number,
+                    error,
+                });
+                continue;
+            }
+        };
+
+        if rows.is_empty() && errors.is_empty() && fields[0].trim().eq_ignore_ascii_case("name") {
+            continue;
+        }
+
+        if fields.len() > 4 {
+            errors.push(RowError {
+                line: line_number,
+                error: String::from(
+                    "Lines can have at most 4 fields: name,hardware_id,location,tags",
+                ),
+            });
+            continue;
+        }
+
+        let name = match non_empty(fields.get(0)) {
+            Some(name) => name,
+            None => {
+                errors.push(RowError {
+                    line: line_number,
+                    error: String::from("Camera name can't be empty"),
+                });
+                continue;
+            }
+        };
+
+        rows.push(RegistrationRow {
+            line: line_number,
+            name,
+            hardware_id: non_empty(fields.get(1)),
+            location: non_empty(fields.get(2)),
+            tags: fields
+                .get(3)
+                .map(|tags| {
+                    tags.split(';')
+                        .map(|tag| tag.trim().to_string())
+                        .filter(|tag| !tag.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        });
+    }
+
+    if rows.is_empty() && errors.is_empty() {
+        errors.push(RowError {
+            line: 1,
+            error: String::from("There are no cameras to register"),
+        });
+    }
+    if rows.len() > MAX_ROWS {
+        errors.push(RowError {
+            line: rows[MAX_ROWS].line,
+            error: format!("At most {} cameras can be registered at once", MAX_ROWS),
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks every hardware ID is a known device that hasn't been added for a camera yet, and is only in the CSV once.
+fn check_hardware_ids(
+    rows: &[RegistrationRow],
+    connection: &PgConnection,
+) -> Result<Vec<RowError>, ApiError> {
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+
+    for row in rows {
+        let hardware_id = match &row.hardware_id {
+            Some(hardware_id) => hardware_id,
+            None => continue,
+        };
+
+        let error = if !seen.insert(hardware_id.clone()) {
+            Some("Hardware ID is in the CSV more than once")
+        } else {
+            match bootstrap::get_device(hardware_id, connection).map_err(database_error)? {
+                None => Some("Hardware ID not found"),
+                Some(device) if device.camera_id.is_some() => Some("Device has already been added"),
+                Some(_) => None,
+            }
+        };
+
+        if let Some(error) = error {
+            errors.push(RowError {
+                line: row.line,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    Ok(errors)
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to register cameras! The error was {}", error);
+    ApiError {
+        error: "Failed to register cameras",
+        code: "register_cameras_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Registers every row's camera for the user in one transaction, see camera::register_camera(). Cameras with a
+/// hardware ID are added as that bootstrap device too. If any row can't be registered, none are, and the report
+/// says which rows failed. Only errors for problems that aren't down to the CSV, like the database failing.
+pub fn register(
+    rows: Vec<RegistrationRow>,
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<RegistrationReport, ApiError> {
+    let errors = check_hardware_ids(&rows, connection)?;
+    if !errors.is_empty() {
+        return Ok(RegistrationReport::failed(errors));
+    }
+
+    let mut failed_line = None;
+    let registered = database::transaction(connection, || {
+        let mut registered = Vec::new();
+
+        for row in &rows {
+            failed_line = Some(row.line);
+
+            let (camera, camera_token) = camera::create_camera(
+                InsertableCamera {
+                    name: row.name.clone(),
+                },
+                user_id,
+                connection,
+            )?;
+
+            let camera = diesel::update(cameras::table.find(camera.camera_id))
+                .set((
+                    cameras::location.eq(&row.location),
+                    cameras::tags.eq(&row.tags),
+                ))
+                .get_result::<Camera>(connection)
+                .map_err(database_error)?;
+
+            if let Some(hardware_id) = &row.hardware_id {
+                let added = bootstrap::add_device_for_camera(
+                    hardware_id,
+                    user_id,
+                    &row.name,
+                    camera.camera_id,
+                    connection,
+                )
+                .map_err(database_error)?;
+
+                // Someone else added it since it was checked
+                if added == 0 {
+                    return Err(ApiError {
+                        error: "Device has already been added",
+                        code: "device_already_added",
+                        status: Status::Conflict,
+                        field: Some("hardware_id"),
+                    });
+                }
+            }
+
+            registered.push((row.line, camera, camera_token.camera_token));
+        }
+
+        failed_line = None;
+        Ok(registered)
+    });
+
+    let registered = match registered {
+        Ok(registered) => registered,
+        // Failures down to the row, like going over the plan's camera limit, are reported against it
+        Err(error) if error.status != Status::InternalServerError => {
+            return Ok(RegistrationReport::failed(vec![RowError {
+                line: failed_line.unwrap_or(0),
+                error: error.error.to_string(),
+            }]))
+        }
+        Err(error) => return Err(error),
+    };
+
+    info!(
+        "User {} registered {} cameras in bulk",
+        user_id,
+        registered.len()
+    );
+
+    let cameras = registered
+        .into_iter()
+        .zip(rows)
+        .map(|((line, camera, camera_token), row)| {
+            home_assistant::announce_camera(&camera);
+
+            RegisteredCamera {
+                line,
+                name: camera.name,
+                camera_id: camera.camera_id,
+                camera_token,
+                hardware_id: row.hardware_id,
+                location: camera.location,
+                tags: camera.tags,
+            }
+        })
+        .collect();
+
+    Ok(RegistrationReport {
+        registered: true,
+        cameras,
+        errors: Vec::new(),
+    })
+}
+
+/// Registers a CSV of cameras at once, for installers setting up a site. Each line is
+/// `name,hardware_id,location,tags`, where only the name is needed and tags are separated by semicolons. Cameras
+/// with a hardware ID are added as that bootstrap device, so they can set themselves up with their claim token.
+/// Either every camera is registered or none are, and the report has each camera's token or each line's problem.
+#[openapi(skip)]
+#[post("/Cameras/Import", format = "text/csv", data = "<csv>")]
+pub fn import_cameras(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    csv: Data,
+) -> Result<Json<RegistrationReport>, ApiError> {
+    let mut contents = String::new();
+    csv.open()
+        .take(MAX_CSV_BYTES + 1)
+        .read_to_string(&mut contents)
+        .map_err(|_| ApiError {
+            error: "The CSV must be UTF-8",
+            code: "invalid_csv",
+            status: Status::BadRequest,
+            field: None,
+        })?;
+
+    if contents.len() as u64 > MAX_CSV_BYTES {
+        return Err(ApiError {
+            error: "The CSV can be at most 1MiB",
+            code: "csv_too_large",
+            status: Status::PayloadTooLarge,
+            field: None,
+        });
+    }
+
+    let rows = match parse_csv(&contents) {
+        Ok(rows) => rows,
+        Err(errors) => return Ok(Json(RegistrationReport::failed(errors))),
+    };
+
+    register(rows, user_token.user_id, &conn).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(contents: &str) -> Vec<RegistrationRow> {
+        match parse_csv(contents) {
+            Ok(rows) => rows,
+            Err(errors) => panic!(
+                "Failed to parse the CSV! The first error was {}",
+                errors[0].error
+            ),
+        }
+    }
+
+    fn error_lines(contents: &str) -> Vec<usize> {
+        match parse_csv(contents) {
+            Ok(_) => panic!("Expected the CSV to be rejected!"),
+            Err(errors) => errors.iter().map(|error| error.line).collect(),
+        }
+    }
+
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(
+            split_csv_line("a,b,,c"),
+            Ok(vec![
+                String::from("a"),
+                String::from("b"),
+                String::new(),
+                String::from("c"),
+            ])
+        );
+        assert_eq!(
+            split_csv_line(r#""Front, left",x"#),
+            Ok(vec![String::from("Front, left"), String::from("x")])
+        );
+        assert_eq!(
+            split_csv_line(r#""say ""hi""""#),
+            Ok(vec![String::from(r#"say "hi""#)])
+        );
+        assert!(split_csv_line(r#""not closed,x"#).is_err());
+    }
+
+    #[test]
+    fn reads_every_column() {
+        let rows = rows("name,hardware_id,location,tags\nGate,HW-1, Car park ,outside; north;;\n");
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[0].name, "Gate");
+        assert_eq!(rows[0].hardware_id.as_deref(), Some("HW-1"));
+        assert_eq!(rows[0].location.as_deref(), Some("Car park"));
+        assert_eq!(
+            rows[0].tags,
+            vec![String::from("outside"), String::from("north")]
+        );
+    }
+
+    #[test]
+    fn only_needs_a_name() {
+        let rows = rows("Gate\nDoor,,\n");
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].name, "Door");
+        assert_eq!(rows[1].hardware_id, None);
+        assert_eq!(rows[1].location, None);
+        assert!(rows[1].tags.is_empty());
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments_but_counts_them() {
+        let rows = rows("# Site A\n\nGate\r\n  \nDoor\n");
+
+        assert_eq!(
+            rows.iter().map(|row| row.line).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_line() {
+        assert_eq!(
+            error_lines("Gate\n,HW-1\na,b,c,d,e\n\"open\n"),
+            vec![2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn needs_at_least_one_camera() {
+        assert_eq!(error_lines("name,hardware_id\n# nothing yet\n"), vec![1]);
+        assert_eq!(error_lines(""), vec![1]);
+    }
+
+    #[test]
+    fn limits_how_many_cameras_are_registered_at_once() {
+        let contents = (0..=MAX_ROWS)
+            .map(|index| format!("Camera {}", index))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        assert_eq!(error_lines(&contents), vec![MAX_ROWS + 1]);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::settings::settings;
+
+use chrono::NaiveDate;
+use once_cell::sync::Lazy;
+use r2d2::{Pool, PooledConnection};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The in-process cache is emptied when it gets this big, so it can't grow without limit.
+pub const MAX_MEMORY_ENTRIES: usize = 10_000;
+
+/// Keys in Redis start with this, so the server can share a Redis with other things.
+pub const REDIS_KEY_PREFIX: &str = "camera-server:";
+
+/// Somewhere to keep lookups that are made on most requests but rarely change. Entries expire after their TTL.
+/// Caching is best effort: if the cache fails, it's logged and treated as a miss, and the database is used instead.
+pub trait Cache: Send + Sync {
+    fn get(&self, key: &str) -> Option<String>;
+    fn set(&self, key: &str, value: &str, ttl: Duration);
+    fn delete(&self, key: &str);
+
+    /// Adds one to the number at `key`, starting from 0, and returns the new number. The in-process cache expires it
+    /// `ttl` after it was created, but Redis pushes the expiry back on every increment, so keys should include
+    /// whatever window they're counting. Returns None if the cache failed.
+    fn increment(&self, key: &str, ttl: Duration) -> Option<u64> {
+        self.increment_by(key, 1, ttl)
+    }
+
+    /// Like increment(), but adds `amount` rather than one.
+    fn increment_by(&self, key: &str, amount: u64, ttl: Duration) -> Option<u64>;
+}
+
+/// A cache in the server's own memory, for single-node deployments. With more than one node, entries deleted
+/// on one node are kept by the others until they expire.
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+/// Drops expired entries once the cache is full, and everything if that isn't enough.
+fn make_room(entries: &mut HashMap<String, (String, Instant)>) {
+    if entries.len() >= MAX_MEMORY_ENTRIES {
+        let now = Instant::now();
+        entries.retain(|_, (_, expires_at)| *expires_at > now);
+
+        if entries.len() >= MAX_MEMORY_ENTRIES {
+            entries.clear();
+        }
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().expect("Cache lock poisoned!");
+
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Duration) {
+        let mut entries = self.entries.lock().expect("Cache lock poisoned!");
+
+        make_room(&mut entries);
+        entries.insert(key.to_string(), (value.to_string(), Instant::now() + ttl));
+    }
+
+    fn delete(&self, key: &str) {
+        self.entries
+            .lock()
+            .expect("Cache lock poisoned!")
+            .remove(key);
+    }
+
+    fn increment_by(&self, key: &str, amount: u64, ttl: Duration) -> Option<u64> {
+        let mut entries = self.entries.lock().expect("Cache lock poisoned!");
+        let now = Instant::now();
+
+        // Keeps the expiry it was first given, like Redis
+        let (count, expires_at) = match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > now => {
+                (value.parse::<u64>().unwrap_or(0) + amount, *expires_at)
+            }
+            _ => (amount, now + ttl),
+        };
+
+        make_room(&mut entries);
+        entries.insert(key.to_string(), (count.to_string(), expires_at));
+
+        Some(count)
+    }
+}
+
+/// A cache in Redis, shared by every node.
+pub struct RedisCache {
+    pool: Pool<redis::Client>,
+}
+
+impl RedisCache {
+    fn connection(&self) -> Option<PooledConnection<redis::Client>> {
+        self.pool
+            .get()
+            .map_err(|error| {
+                error!("Failed to connect to Redis! The error was {}", error);
+            })
+            .ok()
+    }
+
+    fn query<T: redis::FromRedisValue>(&self, command: &mut redis::Cmd) -> Option<T> {
+        let mut connection = self.connection()?;
+
+        command
+            .query(&mut *connection)
+            .map_err(|error| {
+                error!("Redis command failed! The error was {}", error);
+            })
+            .ok()
+    }
+}
+
+impl Cache for RedisCache {
+    fn get(&self, key: &str) -> Option<String> {
+        self.query::<Option<String>>(redis::cmd("GET").arg(format!("{}{}", REDIS_KEY_PREFIX, key)))
+            .flatten()
+    }
+
+    fn set(&self, key: &str, value: &str, ttl: Duration) {
+        self.query::<()>(
+            redis::cmd("SET")
+                .arg(format!("{}{}", REDIS_KEY_PREFIX, key))
+                .arg(value)
+                .arg("EX")
+                .arg(ttl.as_secs().max(1)),
+        );
+    }
+
+    fn delete(&self, key: &str) {
+        self.query::<()>(redis::cmd("DEL").arg(format!("{}{}", REDIS_KEY_PREFIX, key)));
+    }
+
+    fn increment_by(&self, key: &str, amount: u64, ttl: Duration) -> Option<u64> {
+        let key = format!("{}{}", REDIS_KEY_PREFIX, key);
+        let mut connection = self.connection()?;
+
+        redis::pipe()
+            .atomic()
+            .cmd("INCRBY")
+            .arg(&key)
+            .arg(amount)
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(ttl.as_secs().max(1))
+            .ignore()
+            .query::<(u64,)>(&mut *connection)
+            .map(|(count,)| count)
+            .map_err(|error| {
+                error!("Redis command failed! The error was {}", error);
+            })
+            .ok()
+    }
+}
+
+/// Where Redis is, set with redis_url in [cache], e.g. redis://localhost:6379. Without it, caching and everything
+/// else that would be shared between instances stays in the process, see cluster.rs.
+pub fn redis_url() -> Option<String> {
+    settings().cache.redis_url.clone()
+}
+
+/// Connections to Redis, if redis_url in [cache] is set. Shared by the cache and cluster.rs.
+static REDIS: Lazy<Option<Pool<redis::Client>>> = Lazy::new(|| {
+    redis_url().map(|redis_url| {
+        let client = redis::Client::open(redis_url)
+            .expect("The settings make sure redis_url is a Redis URL");
+
+        // Unchecked so that Redis being down doesn't stop the server starting
+        Pool::builder()
+            .max_size(cache_pool_size())
+            .connection_timeout(Duration::from_secs(1))
+            .build_unchecked(client)
+    })
+});
+
+pub fn redis_pool() -> Option<&'static Pool<redis::Client>> {
+    REDIS.as_ref()
+}
+
+/// Redis is used if redis_url in [cache] is set. Defaults to the in-process cache.
+static CACHE: Lazy<Box<dyn Cache>> = Lazy::new(|| match redis_pool() {
+    Some(pool) => Box::new(RedisCache { pool: pool.clone() }),
+    None => Box::new(MemoryCache {
+        entries: Mutex::new(HashMap::new()),
+    }),
+});
+
+pub fn cache() -> &'static dyn Cache {
+    CACHE.as_ref()
+}
+
+/// How many connections to keep to Redis, set with redis_pool_size in [cache]. Defaults to 16.
+pub fn cache_pool_size() -> u32 {
+    settings().cache.redis_pool_size
+}
+
+/// How long entries are kept, set with ttl_seconds in [cache]. Defaults to 60.
+/// Anything revoked on another node stays usable on this one for up to this long with the in-process cache.
+pub fn cache_ttl() -> Duration {
+    Duration::from_secs(settings().cache.ttl_seconds)
+}
+
+/// How long each user's camera list is kept for ListCameras, set with camera_list_ttl_seconds in [cache]. Defaults
+/// to 5, and 0 turns it off. Kept short, as last_seen_at isn't cleared from it every time a camera checks in.
+pub fn camera_list_ttl() -> Duration {
+    Duration::from_secs(settings().cache.camera_list_ttl_seconds)
+}
+
+pub fn user_token_key(user_token: uuid::Uuid) -> String {
+    format!("user_token:{}", user_token)
+}
+
+pub fn camera_token_key(camera_token: uuid::Uuid) -> String {
+    format!("camera_token:{}", camera_token)
+}
+
+pub fn camera_access_key(user_id: uuid::Uuid, camera_id: uuid::Uuid) -> String {
+    format!("camera_access:{}:{}", user_id, camera_id)
+}
+
+pub fn camera_list_key(user_id: uuid::Uuid) -> String {
+    format!("camera_list:{}", user_id)
+}
+
+pub fn feature_flag_key(name: &str) -> String {
+    format!("feature_flag:{}", name)
+}
+
+pub fn maintenance_key() -> String {
+    String::from("maintenance")
+}
+
+pub fn unverified_buffer_key(client: &str, window_start: i64) -> String {
+    format!("unverified_buffer:{}:{}", client, window_start)
+}
+
+pub fn latest_image_key(camera_id: uuid::Uuid) -> String {
+    format!("latest_image:{}", camera_id)
+}
+
+pub fn image_stats_key() -> String {
+    String::from("image_stats")
+}
+
+pub fn plan_storage_key(user_id: uuid::Uuid) -> String {
+    format!("plan_storage:{}", user_id)
+}
+
+pub fn user_suspended_key(user_id: uuid::Uuid) -> String {
+    format!("user_suspended:{}", user_id)
+}
+
+pub fn camera_suspended_key(camera_id: uuid::Uuid) -> String {
+    format!("camera_suspended:{}", camera_id)
+}
+
+pub fn user_tenant_key(user_id: uuid::Uuid) -> String {
+    format!("user_tenant:{}", user_id)
+}
+
+pub fn user_locale_key(user_id: uuid::Uuid) -> String {
+    format!("user_locale:{}", user_id)
+}
+
+pub fn camera_tenant_key(camera_id: uuid::Uuid) -> String {
+    format!("camera_tenant:{}", camera_id)
+}
+
+pub fn tenant_slug_key(slug: &str) -> String {
+    format!("tenant_slug:{}", slug)
+}
+
+pub fn tenant_hostname_key(hostname: &str) -> String {
+    format!("tenant_hostname:{}", hostname)
+}
+
+pub fn rate_limit_key(client: &str, window_start: i64) -> String {
+    format!("rate_limit:{}:{}", client, window_start)
+}
+
+pub fn quota_requests_key(scope: &str, token_hash: &str, day: NaiveDate) -> String {
+    format!("quota_requests:{}:{}:{}", scope, token_hash, day)
+}
+
+pub fn quota_bytes_key(scope: &str, token_hash: &str, day: NaiveDate) -> String {
+    format!("quota_bytes:{}:{}:{}", scope, token_hash, day)
+}
+
+pub fn camera_log_uploads_key(camera_id: uuid::Uuid, window_start: i64) -> String {
+    format!("camera_log_uploads:{}:{}", camera_id, window_start)
+}
+
+/// Looks `key` up as a bool, caching what `load` returns if it wasn't there.
+pub fn cached_bool<E>(key: &str, load: impl FnOnce() -> Result<bool, E>) -> Result<bool, E> {
+    if let Some(value) = cache().get(key).and_then(|value| value.parse().ok()) {
+        return Ok(value);
+    }
+
+    let value = load()?;
+    cache().set(key, &value.to_string(), cache_ttl());
+    Ok(value)
+}
+
+/// Looks `key` up as a UUID, caching what `load` returns if it wasn't there.
+pub fn cached_uuid<E>(
+    key: &str,
+    load: impl FnOnce() -> Result<uuid::Uuid, E>,
+) -> Result<uuid::Uuid, E> {
+    if let Some(value) = cache()
+        .get(key)
+        .and_then(|value| uuid::Uuid::parse_str(&value).ok())
+    {
+        return Ok(value);
+    }
+
+    let value = load()?;
+    cache().set(key, &value.to_string(), cache_ttl());
+    Ok(value)
+}
@@ -1,50 +1,133 @@
 use crate::{
+    analysis,
     api_error::ApiError,
+    audit,
+    cache::{self, cache},
+    camera_commands::{self, CameraCommand, InsertableCameraCommand, LongPolls, SNAPSHOT_COMMAND},
     camera_tokens,
     config::{self, Config},
-    user_tokens,
+    database::{self, ReadDbConn},
+    device_format::Device,
+    event::Event,
+    event_media, home_assistant, ingest_batch,
+    media_store::{media_store, MediaStore},
+    metrics, mqtt,
+    multipart_upload::{report_metadata_event, MultipartUpload},
+    notification,
+    page::{Page, PageQuery},
+    patch, plan, privacy_mask, realtime, request_id,
+    settings::settings,
+    storage,
+    upload_limit::UploadSlot,
+    usage, user_tokens,
     users_cameras::{self, check_if_user_has_access_to_camera, InsertableUsersCamera},
     CameraServerDbConn,
 };
 
-use super::schema::cameras;
+use super::schema::{camera_offline_periods, cameras, configs};
 use camera_tokens::{CameraToken, InsertableCameraToken};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use diesel::prelude::*;
 use diesel::{self};
+use rocket::http::RawStr;
 use rocket::post;
-use rocket::response::Stream;
-use rocket::{http::Status, Data};
+use rocket::request::{Form, FromParam};
+use rocket::response::{self, Responder, Stream};
+use rocket::{http::Status, Data, Request, Response, State};
 use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::path::Path;
-use std::time::SystemTime;
-use std::{env, fs::create_dir_all, fs::read_dir, fs::DirEntry};
+use std::io::Read;
+use std::time::{Duration, SystemTime};
 
-#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
 #[table_name = "cameras"]
 pub struct Camera {
+    #[schemars(with = "String")]
     pub camera_id: uuid::Uuid,
     pub name: String,
+    /// Set when the camera contacts the server, cleared by the offline monitor when it stops.
+    pub online: bool,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// Set when the camera is deleted. Deleted cameras are hidden, but kept with their footage until they're purged.
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// Kept up to date by a trigger. Pass it as ?updated_since= to only get what has changed since.
+    pub updated_at: DateTime<Utc>,
+    /// Where the camera is, e.g. "Warehouse 2, loading bay". Set when cameras are registered in bulk.
+    pub location: Option<String>,
+    pub tags: Vec<String>,
 }
 
-#[derive(Insertable, Serialize, Deserialize)]
+#[derive(Insertable, Serialize, Deserialize, JsonSchema)]
 #[table_name = "cameras"]
 pub struct InsertableCamera {
     pub name: String,
 }
 
+/// Returned by multipart uploads to POST /Device/Images.
+#[derive(Serialize, JsonSchema)]
+pub struct ImageUpload {
+    pub image_id: String,
+    /// The event from the upload's metadata, None if it didn't have one or it was outside the camera's zones.
+    pub event: Option<Event>,
+}
+
+/// A partial update to a camera, sent with PATCH /Cameras/<camera_id>. Missing fields are left as they are.
+#[derive(AsChangeset, Deserialize, JsonSchema)]
+#[table_name = "cameras"]
+pub struct UpdateCamera {
+    pub name: Option<String>,
+}
+
 impl InsertableCamera {
     pub fn from_camera(camera: Camera) -> InsertableCamera {
         InsertableCamera { name: camera.name }
     }
 }
 
+/// A stretch of time a camera was offline for. ended_at is None while the camera is still offline.
+#[derive(Queryable, Deserialize, Serialize)]
+pub struct OfflinePeriod {
+    pub period_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "camera_offline_periods"]
+pub struct InsertableOfflinePeriod {
+    pub camera_id: uuid::Uuid,
+    pub started_at: DateTime<Utc>,
+}
+
+pub type NotDeleted = diesel::dsl::Filter<cameras::table, diesel::dsl::IsNull<cameras::deleted_at>>;
+
+/// Cameras that haven't been deleted. Queries should start from this rather than cameras::table,
+/// unless they're meant to see deleted cameras too.
+pub fn not_deleted() -> NotDeleted {
+    cameras::table.filter(cameras::deleted_at.is_null())
+}
+
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<Camera>> {
-    cameras::table.load::<Camera>(&*connection)
+    not_deleted().load::<Camera>(&*connection)
 }
 
 pub fn get(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Camera> {
+    database::timed(
+        "camera_get",
+        not_deleted().filter(cameras::camera_id.eq(camera_id)),
+        |query| query.get_result::<Camera>(connection),
+    )
+}
+
+/// Like get(), but finds deleted cameras too.
+pub fn get_including_deleted(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Camera> {
     cameras::table
         .find(camera_id)
         .get_result::<Camera>(connection)
@@ -61,266 +144,968 @@ pub fn update(
     camera: Camera,
     connection: &PgConnection,
 ) -> QueryResult<Camera> {
-    diesel::update(cameras::table.find(camera_id))
+    let updated = diesel::update(cameras::table.find(camera_id))
         .set(&camera)
-        .get_result(connection)
+        .get_result(connection);
+    if updated.is_ok() {
+        users_cameras::forget_camera_lists(&[camera_id], connection);
+    }
+    updated
+}
+
+/// Updates only the fields that are set.
+pub fn update_partial(
+    camera_id: uuid::Uuid,
+    update: &UpdateCamera,
+    connection: &PgConnection,
+) -> QueryResult<Camera> {
+    let updated = patch::or_unchanged(
+        diesel::update(cameras::table.find(camera_id))
+            .set(update)
+            .get_result(connection),
+        || get(camera_id, connection),
+    );
+    if updated.is_ok() {
+        users_cameras::forget_camera_lists(&[camera_id], connection);
+    }
+    updated
 }
 
 pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
-    diesel::delete(cameras::table.find(camera_id)).execute(connection)
+    // Deleting the camera deletes who has access to it too, so their cached access checks have to go
+    let users = users_cameras::get_cameras_users(camera_id, connection)?;
+    let deleted = diesel::delete(cameras::table.find(camera_id)).execute(connection);
+
+    for user_id in users {
+        cache().delete(&cache::camera_access_key(user_id, camera_id));
+        cache().delete(&cache::camera_list_key(user_id));
+    }
+    cache().delete(&cache::latest_image_key(camera_id));
+
+    deleted
 }
 
-/// Returns a sorted image list of the given directory (usually a camera directory in this case)
+/// Hides the camera and who has access to it, until it's undeleted or purged. Its footage is kept until then.
+/// Returns how many cameras were deleted, 0 if it already was.
+pub fn soft_delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    let users = users_cameras::get_cameras_users(camera_id, connection)?;
+    let tokens = camera_tokens::get_cameras_tokens(camera_id, connection)?;
+    let deleted_at = Utc::now();
+
+    let deleted = connection.transaction(|| {
+        let deleted = diesel::update(not_deleted().filter(cameras::camera_id.eq(camera_id)))
+            .set(cameras::deleted_at.eq(deleted_at))
+            .execute(connection)?;
+        users_cameras::soft_delete_cameras_access(camera_id, deleted_at, connection)?;
+        Ok(deleted)
+    });
+
+    for user_id in users {
+        cache().delete(&cache::camera_access_key(user_id, camera_id));
+        cache().delete(&cache::camera_list_key(user_id));
+    }
+    for camera_token in tokens {
+        cache().delete(&cache::camera_token_key(camera_token));
+    }
+    cache().delete(&cache::latest_image_key(camera_id));
+
+    deleted
+}
+
+/// Brings back a deleted camera, along with the access that was deleted with it.
+pub fn undelete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Camera> {
+    let undeleted = connection.transaction(|| {
+        let camera = get_including_deleted(camera_id, connection)?;
+
+        if let Some(deleted_at) = camera.deleted_at {
+            users_cameras::undelete_cameras_access(camera_id, deleted_at, connection)?;
+        }
+
+        diesel::update(cameras::table.find(camera_id))
+            .set(cameras::deleted_at.eq(None::<DateTime<Utc>>))
+            .get_result(connection)
+    });
+    if undeleted.is_ok() {
+        users_cameras::forget_camera_lists(&[camera_id], connection);
+    }
+    undeleted
+}
+
+/// How long (in seconds) a camera can go without contacting the server before it counts as offline, set with
+/// offline_after_seconds in [limits]. Cameras with long intervals get 3 intervals instead if that is longer. Defaults to 300.
+pub fn offline_after_seconds() -> i64 {
+    settings().limits.offline_after_seconds
+}
+
+/// Records that the cameras have just contacted the server. Returns the cameras that were offline until now.
+pub fn mark_cameras_seen(
+    camera_ids: &[uuid::Uuid],
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    let now = Utc::now();
+
+    let came_online = connection.transaction(|| {
+        let came_online = diesel::update(
+            cameras::table
+                .filter(cameras::camera_id.eq_any(camera_ids))
+                .filter(cameras::online.eq(false)),
+        )
+        .set((cameras::online.eq(true), cameras::last_seen_at.eq(now)))
+        .returning(cameras::camera_id)
+        .get_results::<uuid::Uuid>(connection)?;
+
+        if came_online.len() > 0 {
+            diesel::update(
+                camera_offline_periods::table
+                    .filter(camera_offline_periods::camera_id.eq_any(&came_online))
+                    .filter(camera_offline_periods::ended_at.is_null()),
+            )
+            .set(camera_offline_periods::ended_at.eq(now))
+            .execute(connection)?;
+        }
+
+        diesel::update(
+            cameras::table
+                .filter(cameras::camera_id.eq_any(camera_ids))
+                .filter(cameras::camera_id.ne_all(&came_online)),
+        )
+        .set(cameras::last_seen_at.eq(now))
+        .execute(connection)?;
+
+        Ok(came_online)
+    })?;
+
+    // Only a camera coming online clears the lists it's in. last_seen_at alone is left to catch up
+    if !came_online.is_empty() {
+        users_cameras::forget_camera_lists(&came_online, connection);
+    }
+    Ok(came_online)
+}
+
+/// Calls mark_cameras_seen() from camera-authenticated routes. Failing to record presence shouldn't fail the camera's request,
+/// so errors are only logged. Contacts are written in batches, see ingest_batch::record_contact().
+pub fn record_camera_contact(camera_id: uuid::Uuid, connection: &PgConnection) {
+    ingest_batch::record_contact(camera_id, connection);
+}
+
+/// Marks every online camera that hasn't been seen for too long as offline. Returns the cameras that went offline.
+pub fn mark_offline_cameras(connection: &PgConnection) -> QueryResult<Vec<Camera>> {
+    let online_cameras = not_deleted()
+        .inner_join(configs::table.on(configs::camera_id.eq(cameras::camera_id)))
+        .filter(cameras::online.eq(true))
+        .select((cameras::all_columns, configs::interval))
+        .load::<(Camera, i16)>(connection)?;
+
+    let now = Utc::now();
+    let mut offline_cameras = Vec::new();
+
+    for (mut camera, interval) in online_cameras {
+        let offline_after = offline_after_seconds().max(interval as i64 * 3);
+
+        let is_offline = match camera.last_seen_at {
+            Some(last_seen_at) => (now - last_seen_at).num_seconds() > offline_after,
+            None => true,
+        };
+
+        if is_offline {
+            diesel::update(cameras::table.find(camera.camera_id))
+                .set(cameras::online.eq(false))
+                .execute(connection)?;
+            diesel::insert_into(camera_offline_periods::table)
+                .values(InsertableOfflinePeriod {
+                    camera_id: camera.camera_id,
+                    // The camera was really offline from when it was last seen
+                    started_at: camera.last_seen_at.unwrap_or(now),
+                })
+                .execute(connection)?;
+            camera.online = false;
+            offline_cameras.push(camera);
+        }
+    }
+
+    if !offline_cameras.is_empty() {
+        let camera_ids: Vec<uuid::Uuid> = offline_cameras
+            .iter()
+            .map(|camera| camera.camera_id)
+            .collect();
+        users_cameras::forget_camera_lists(&camera_ids, connection);
+    }
+    Ok(offline_cameras)
+}
+
+/// Returns the times the camera was offline that overlap with `from` to `to`, oldest first.
+pub fn get_cameras_offline_periods(
+    camera_id: uuid::Uuid,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<OfflinePeriod>> {
+    camera_offline_periods::table
+        .filter(camera_offline_periods::camera_id.eq(camera_id))
+        .filter(camera_offline_periods::started_at.lt(to))
+        .filter(
+            camera_offline_periods::ended_at
+                .is_null()
+                .or(camera_offline_periods::ended_at.gt(from)),
+        )
+        .order(camera_offline_periods::started_at)
+        .load::<OfflinePeriod>(connection)
+}
+
+/// Starts a thread that checks for cameras that have gone offline every 30 seconds and alerts their users.
+pub fn spawn_offline_monitor(database_url: String) {
+    crate::worker::spawn_worker(
+        "Offline monitor",
+        Duration::from_secs(30),
+        database_url,
+        |connection| match mark_offline_cameras(connection) {
+            Ok(offline_cameras) => {
+                for camera in offline_cameras {
+                    mqtt::publish_presence(&camera.camera_id, false);
+                    realtime::publish_presence(camera.camera_id, false, connection);
+
+                    if let Err(error) = notification::notify_offline(&camera, connection) {
+                        error!(
+                            "Failed to queue offline notifications for camera {}! The error was {}",
+                            camera.camera_id, error
+                        );
+                    }
+                }
+            }
+            Err(error) => error!(
+                "Failed to check for offline cameras! The error was {}",
+                error
+            ),
+        },
+    );
+}
+
+/// Returns a sorted list of a camera's image IDs, across every storage tier.
 /// Not to be confused the get_image_list() GET request (couldn't think of a better name).
-/// Returns a Vec<DirEntry> if successful, and an ApiError if something goes wrong.
-pub fn list_camera_directory(
-    camera_directory: &String,
-    sort: bool,
-) -> Result<Vec<DirEntry>, ApiError> {
-    let image_list = read_dir(camera_directory).map_err(|error| {
-        println!(
-            "Failed to directory {}! The error was {}",
-            camera_directory, error
+/// Returns an ApiError if the camera has no images or something goes wrong.
+pub fn list_camera_images(camera_id: &uuid::Uuid) -> Result<Vec<u64>, ApiError> {
+    let mut image_list = media_store().list_images(camera_id).map_err(|error| {
+        error!(
+            "Failed to list images for camera {}! The error was {}",
+            camera_id, error
         );
         ApiError {
             error: "Failed to get list of images",
+            code: "get_images_failed",
             status: Status::InternalServerError,
+            field: None,
         }
     })?;
 
-    let mut sorted_image_list: Vec<DirEntry> = image_list
-        .map(|x| x.expect("Failed to map to Vec<DirEntry>"))
-        .collect::<Vec<DirEntry>>();
-
-    if sorted_image_list.len() == 0 {
+    if image_list.len() == 0 {
         return Err(ApiError {
             error: "Camera has no images (or doesn't exist)",
+            code: "no_images",
             status: Status::NotFound,
+            field: None,
         });
     }
 
-    if sort {
-        sorted_image_list.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
-    }
+    image_list.sort();
 
-    Ok(sorted_image_list)
+    Ok(image_list)
 }
 
+/// Set with images_directory in [storage], which the settings won't load without.
 pub fn images_directory() -> String {
-    env::var("IMAGES_DIRECTORY").expect("IMAGES_DIRECTORY environment variable is not set!")
+    settings()
+        .storage
+        .images_directory
+        .clone()
+        .expect("images_directory in [storage] is not set!")
+}
+
+/// How long (in seconds) a camera has to upload a snapshot it was asked for, set with
+/// snapshot_timeout_seconds in [limits]. Defaults to 30.
+pub fn snapshot_timeout() -> u64 {
+    settings().limits.snapshot_timeout_seconds
+}
+
+/// Returns the ID (seconds since epoch) of the camera's newest image.
+/// Returns None if the camera has no images yet, unlike list_camera_images().
+pub fn latest_image_id(camera_id: &uuid::Uuid) -> Option<u64> {
+    media_store().list_images(camera_id).ok()?.into_iter().max()
+}
+
+/// Like latest_image_id(), but from the cache if it's there, for when listing the camera's images for every
+/// request would be too slow. The cached image can have been deleted since.
+pub fn cached_latest_image_id(camera_id: &uuid::Uuid) -> Option<u64> {
+    let latest_image_key = cache::latest_image_key(*camera_id);
+    if let Some(image_id) = cache()
+        .get(&latest_image_key)
+        .and_then(|image_id| image_id.parse::<u64>().ok())
+    {
+        return Some(image_id);
+    }
+
+    let image_id = latest_image_id(camera_id)?;
+    cache().set(&latest_image_key, &image_id.to_string(), cache::cache_ttl());
+    Some(image_id)
+}
+
+pub fn parse_camera_id(camera_id_string: &String) -> Result<uuid::Uuid, ApiError> {
+    uuid::Uuid::parse_str(camera_id_string).map_err(|error| {
+        warn!(
+            "Failed to parse camera id into UUID: Input was {}, error was {}",
+            camera_id_string, error
+        );
+        ApiError {
+            error: "Failed to parse camera ID string",
+            code: "invalid_camera_id",
+            status: Status::UnprocessableEntity,
+            field: None,
+        }
+    })
+}
+
+/// A camera ID taken from a path. Routes only match if it's a valid UUID, so malformed IDs get the same
+/// 404 everywhere and handlers never have to parse them.
+#[derive(Clone, Copy, JsonSchema)]
+pub struct CameraId(#[schemars(with = "String")] pub uuid::Uuid);
+
+impl CameraId {
+    pub fn into_inner(self) -> uuid::Uuid {
+        self.0
+    }
+}
+
+impl<'a> FromParam<'a> for CameraId {
+    type Error = &'a RawStr;
+
+    fn from_param(param: &'a RawStr) -> Result<Self, Self::Error> {
+        let camera_id = uuid::Uuid::parse_str(param.as_str()).map_err(|_| param)?;
+        request_id::record_camera(camera_id);
+        Ok(CameraId(camera_id))
+    }
+}
+
+/// Opens an image from whichever storage tier it is in.
+pub fn open_image(
+    camera_id: &uuid::Uuid,
+    image_id: u64,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    media_store()
+        .open_image(camera_id, image_id)
+        .map(Stream::from)
+        .map_err(|error| {
+            error!("Failed to read file! The error was {}", error);
+            ApiError {
+                error: "Failed to load image",
+                code: "load_image_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Creates a camera with a token and the default config, and gives the user access to it.
+/// It's all done in one transaction, so nothing is kept if a step fails. Returns the new camera's token.
+pub fn register_camera(
+    camera: InsertableCamera,
+    user_id: uuid::Uuid,
+    conn: &PgConnection,
+) -> Result<CameraToken, ApiError> {
+    let (new_camera, new_camera_token) = create_camera(camera, user_id, conn)?;
+
+    home_assistant::announce_camera(&new_camera);
+
+    Ok(new_camera_token)
 }
 
-pub fn camera_directory(images_directory_path: &String, camera_id_string: &String) -> String {
-    format!("{}/{}", images_directory_path, camera_id_string)
+/// Does the work of register_camera(), but doesn't announce the camera, for callers that create it inside a
+/// transaction of their own and should only announce it once that's committed.
+pub fn create_camera(
+    camera: InsertableCamera,
+    user_id: uuid::Uuid,
+    conn: &PgConnection,
+) -> Result<(Camera, CameraToken), ApiError> {
+    database::transaction(conn, || {
+        plan::check_camera_limit(user_id, conn)?;
+
+        // Insert a new camera into the DB. Returns the ID for the new camera.
+        let new_camera = insert(camera, conn).map_err(|error| {
+            error!("Failed to create new camera! The error was {}", error);
+            ApiError {
+                error: "Failed to create new camera",
+                code: "create_camera_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        // Generate a new token for the camera.
+        let new_camera_token = camera_tokens::insert(
+            InsertableCameraToken {
+                camera_id: new_camera.camera_id,
+            },
+            conn,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to add camera token for camera {}! The error was {}",
+                new_camera.camera_id, error
+            );
+            ApiError {
+                error: "Failed to add camera token",
+                code: "add_camera_token_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        // Create a pair between the current user and the new camera.
+        // This basically means the user who made the camera is automatically given access.
+        users_cameras::insert(
+            InsertableUsersCamera {
+                camera_id: new_camera.camera_id,
+                user_id: user_id,
+            },
+            conn,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to pair user {} to camera {}! The error was {}",
+                user_id, new_camera.camera_id, error
+            );
+            ApiError {
+                error: "Failed to pair user to camera",
+                code: "pair_user_to_camera_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        config::insert(
+            Config {
+                camera_id: new_camera.camera_id,
+                interval: 10,
+            },
+            conn,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to add config for {}! The error was {}",
+                new_camera.camera_id, error
+            );
+            ApiError {
+                error: "Failed to create camera config",
+                code: "create_camera_config_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        Ok((new_camera, new_camera_token))
+    })
 }
 
-#[post("/AddCamera", format = "json", data = "<camera_name>")]
+#[openapi]
+#[post("/Cameras", format = "json", data = "<camera_name>")]
 pub fn add_new_camera(
     conn: CameraServerDbConn,
     user_token: user_tokens::UserToken,
     camera_name: Json<InsertableCamera>,
 ) -> Result<Json<CameraToken>, ApiError> {
-    // Insert a new camera into the DB. Returns the ID for the new camera.
-    let new_camera = insert(camera_name.into_inner(), &conn).map_err(|error| {
-        println!("Failed to create new camera! The error was {}", error);
-        ApiError {
-            error: "Failed to create new camera",
-            status: Status::InternalServerError,
+    register_camera(camera_name.into_inner(), user_token.user_id, &conn).map(Json)
+}
+
+/// Stores an image from the camera and tells everything that wants to know about it, and answers the snapshot
+/// command it was taken for, see camera_commands::record_snapshot().
+/// Returns the seconds since epoch used as the image name.
+pub fn store_uploaded_image(
+    camera_id: uuid::Uuid,
+    command_id: Option<i32>,
+    image: &mut dyn Read,
+    conn: &PgConnection,
+) -> Result<u64, ApiError> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time somehow?")
+        .as_secs();
+
+    let image_id = store_image_taken_at(camera_id, current_time, image, conn)?;
+
+    // The image is saved either way, whoever asked for it will see the snapshot time out
+    if let Err(error) = camera_commands::record_snapshot(camera_id, command_id, image_id, conn) {
+        error!(
+            "Failed to record image {} as camera {}'s snapshot! The error was {}",
+            image_id, camera_id, error
+        );
+    }
+
+    Ok(image_id)
+}
+
+/// The same as store_uploaded_image, for an image that was uploaded earlier (at `current_time` seconds since epoch)
+/// but couldn't be stored then, e.g. during maintenance.
+pub fn store_image_taken_at(
+    camera_id: uuid::Uuid,
+    current_time: u64,
+    image: &mut dyn Read,
+    conn: &PgConnection,
+) -> Result<u64, ApiError> {
+    plan::check_storage(camera_id, conn)?;
+
+    let masked = privacy_mask::mask_image(camera_id, image, conn)?;
+    let mut masked_image = masked.as_deref().unwrap_or_default();
+    let image: &mut dyn Read = match masked {
+        Some(_) => &mut masked_image,
+        None => image,
+    };
+
+    let size_bytes = media_store()
+        .store_image(&camera_id, current_time, image)
+        .map_err(|error| {
+            error!("Failed to stream image to file! The error was {}", error);
+            ApiError {
+                error: "Failed to save image to server",
+                code: "save_image_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    metrics::record_upload("image", size_bytes);
+    usage::record_upload(camera_id, size_bytes);
+    storage::record_stored(
+        camera_id,
+        storage::IMAGE,
+        storage::image_day(current_time),
+        size_bytes,
+    );
+
+    // Buffered images can be stored after newer ones, which are still the latest
+    let latest_image_key = cache::latest_image_key(camera_id);
+    let is_latest = cache()
+        .get(&latest_image_key)
+        .and_then(|latest| latest.parse::<u64>().ok())
+        .map_or(true, |latest| latest <= current_time);
+    if is_latest {
+        cache().set(
+            &latest_image_key,
+            &current_time.to_string(),
+            cache::cache_ttl(),
+        );
+    }
+
+    if let Err(error) = event_media::link_image(camera_id, current_time, conn) {
+        error!(
+            "Failed to link image {} from camera {} to events! The error was {}",
+            current_time, camera_id, error
+        );
+    }
+
+    home_assistant::publish_image(&camera_id, current_time);
+    realtime::publish_image(camera_id, current_time, conn);
+
+    // The image is saved either way, analysis just won't happen for it
+    if let Err(error) = analysis::queue_analysis(camera_id, current_time, conn) {
+        error!(
+            "Failed to queue analysis for image {} from camera {}! The error was {}",
+            current_time, camera_id, error
+        );
+    }
+
+    Ok(current_time)
+}
+
+/// Stores a new image. Returns the seconds since epoch used as the image name. Snapshots should be sent with the
+/// command_id of the snapshot command they were taken for.
+#[openapi(skip)]
+#[post("/Device/Images?<command_id>", format = "image/jpeg", data = "<image>")]
+pub fn upload_image(
+    conn: CameraServerDbConn,
+    image: Data,
+    camera_token: CameraToken,
+    _upload_slot: UploadSlot,
+    command_id: Option<i32>,
+) -> Result<String, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    store_uploaded_image(camera_token.camera_id, command_id, &mut image.open(), &conn)
+        .map(|image_id| image_id.to_string())
+}
+
+/// The same as uploading a JPEG on its own, for firmware that can only send multipart/form-data.
+/// If the metadata part has an event, it is reported with the image attached.
+#[openapi(skip)]
+#[post(
+    "/Device/Images?<command_id>",
+    format = "multipart/form-data",
+    data = "<upload>"
+)]
+pub fn upload_image_multipart(
+    conn: CameraServerDbConn,
+    upload: MultipartUpload,
+    camera_token: CameraToken,
+    _upload_slot: UploadSlot,
+    command_id: Option<i32>,
+) -> Result<Device<ImageUpload>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    match upload.content_type.as_deref() {
+        None | Some("image/jpeg") => {}
+        Some(_) => {
+            return Err(ApiError {
+                error: "Images must be JPEGs",
+                code: "image_not_jpeg",
+                status: Status::UnsupportedMediaType,
+                field: Some("file"),
+            })
         }
-    })?;
+    }
 
-    // Generate a new token for the camera.
-    let new_camera_token = camera_tokens::insert(
-        InsertableCameraToken {
-            camera_id: new_camera.camera_id,
-        },
+    let image_id = store_uploaded_image(
+        camera_token.camera_id,
+        command_id,
+        &mut upload.file.as_slice(),
         &conn,
-    )
-    .map_err(|error| {
-        println!(
-            "Failed to add camera token for camera {}! The error was {}",
-            new_camera.camera_id, error
-        );
-        delete(new_camera.camera_id, &conn)
-            .expect("Failed to delete new camera while handling camera token error!");
-        return ApiError {
-            error: "Failed to add camera token",
-            status: Status::InternalServerError,
-        };
-    })?;
+    )?;
 
-    // Create a pair between the current user and the new camera.
-    // This basically means the user who made the camera is automatically given access.
-    let users_camera = users_cameras::insert(
-        InsertableUsersCamera {
-            camera_id: new_camera.camera_id,
-            user_id: user_token.user_id,
-        },
+    let event = report_metadata_event(
+        camera_token.camera_id,
+        upload.metadata,
+        |event| event.image_id = Some(image_id as i64),
         &conn,
-    )
-    .map_err(|error| {
-        println!(
-            "Failed to pair user {} to camera {}! The error was {}",
-            user_token.user_id, new_camera.camera_id, error
+    )?;
+
+    Ok(Device(ImageUpload {
+        image_id: image_id.to_string(),
+        event,
+    }))
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>")]
+pub fn get_camera(
+    conn: ReadDbConn,
+    user_token: user_tokens::UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Camera>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    get(camera_id, &conn)
+        .map(|camera| Json(camera))
+        .map_err(|error| {
+            error!(
+                "Failed to get camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get camera",
+                code: "get_camera_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[patch("/Cameras/<camera_id>", format = "json", data = "<update>")]
+pub fn patch_camera(
+    conn: CameraServerDbConn,
+    user_token: user_tokens::UserToken,
+    camera_id: CameraId,
+    update: Json<UpdateCamera>,
+) -> Result<Json<Camera>, ApiError> {
+    let update = update.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    if update
+        .name
+        .as_ref()
+        .map_or(false, |name| name.trim().len() == 0)
+    {
+        return Err(ApiError {
+            error: "Camera name can't be empty",
+            code: "camera_name_required",
+            status: Status::UnprocessableEntity,
+            field: Some("name"),
+        });
+    }
+
+    if let Ok(before) = get(camera_id, &conn) {
+        audit::record_before(&before);
+    }
+
+    update_partial(camera_id, &update, &conn)
+        .map(|camera| {
+            audit::record_after(&camera);
+            Json(camera)
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to update camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to update camera",
+                code: "update_camera_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Deletes the camera for everyone who has access to it. It can be brought back by an admin until it's purged,
+/// see soft_delete::soft_delete_retention_days().
+#[openapi]
+#[delete("/Cameras/<camera_id>")]
+pub fn delete_camera(
+    conn: CameraServerDbConn,
+    user_token: user_tokens::UserToken,
+    camera_id: CameraId,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    soft_delete(camera_id, &conn).map(|_| ()).map_err(|error| {
+        error!(
+            "Failed to delete camera {}! The error was {}",
+            camera_id, error
         );
-        camera_tokens::delete(new_camera.camera_id, &conn)
-            .expect("Failed to delete new camera token while handling pair user to camera error!");
-        delete(new_camera.camera_id, &conn)
-            .expect("Failed to delete new camera while handling pair user to camera error!");
-        return ApiError {
-            error: "Failed to pair user to camera",
+        ApiError {
+            error: "Failed to delete camera",
+            code: "delete_camera_failed",
             status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Where a snapshot asked for with POST /Cameras/<camera_id>/Snapshot is up to.
+#[derive(Serialize, JsonSchema)]
+pub struct Snapshot {
+    /// The snapshot command sent to the camera. Poll GET /Cameras/<camera_id>/Snapshot/<command_id> with it while
+    /// it's pending.
+    pub command_id: i32,
+    /// "pending" until the camera uploads it, then "taken", or "timed_out" after snapshot_timeout().
+    pub status: &'static str,
+    /// The image the camera uploaded, once it's been taken.
+    pub image_id: Option<String>,
+}
+
+impl Snapshot {
+    fn from_command(command: &CameraCommand) -> Snapshot {
+        let status = if command.image_id.is_some() {
+            "taken"
+        } else if command.has_expired() {
+            "timed_out"
+        } else {
+            "pending"
         };
-    })?;
 
-    config::insert(
-        Config {
-            camera_id: new_camera.camera_id,
-            interval: 10,
+        Snapshot {
+            command_id: command.command_id,
+            status,
+            image_id: command.image_id.map(|image_id| image_id.to_string()),
+        }
+    }
+}
+
+/// Sent as 200 OK once the snapshot has been taken, or 202 Accepted while the camera still hasn't taken it.
+pub struct SnapshotResponse(pub Snapshot);
+
+impl<'r> Responder<'r> for SnapshotResponse {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = if self.0.image_id.is_some() {
+            Status::Ok
+        } else {
+            Status::Accepted
+        };
+
+        Response::build_from(Json(self.0).respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Asks the camera to take a snapshot right now, and waits up to snapshot_timeout() for it to upload it. Returns the
+/// image, or a 504 if the camera didn't upload it in time. If too many requests are already waiting (see
+/// camera_commands::LongPolls), it returns a 202 straight away with the command sent to the camera, which
+/// GET /Cameras/<camera_id>/Snapshot/<command_id> says the image of once the camera has uploaded it.
+#[openapi]
+#[post("/Cameras/<camera_id>/Snapshot")]
+pub fn take_snapshot(
+    conn: CameraServerDbConn,
+    user_token: user_tokens::UserToken,
+    long_polls: State<LongPolls>,
+    camera_id: CameraId,
+) -> Result<SnapshotResponse, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let command = camera_commands::insert(
+        InsertableCameraCommand {
+            camera_id,
+            command: SNAPSHOT_COMMAND.to_string(),
+            expires_at: Some(Utc::now() + ChronoDuration::seconds(snapshot_timeout() as i64)),
         },
         &conn,
     )
     .map_err(|error| {
-        println!(
-            "Failed to add config for {}! The error was {}",
-            new_camera.camera_id, error
+        error!(
+            "Failed to queue snapshot command for camera {}! The error was {}",
+            camera_id, error
         );
-        users_cameras::delete(users_camera.users_cameras_id, &conn)
-            .expect("Failed to delete users camera while handling create config error!");
-        camera_tokens::delete(new_camera.camera_id, &conn)
-            .expect("Failed to delete new camera token while handling pair user to camera error!");
-        delete(new_camera.camera_id, &conn)
-            .expect("Failed to delete new camera while handling pair user to camera error!");
-        return ApiError {
-            error: "Failed to create camera config",
+        ApiError {
+            error: "Failed to send snapshot command",
+            code: "send_snapshot_command_failed",
             status: Status::InternalServerError,
-        };
+            field: None,
+        }
     })?;
 
-    Ok(Json(new_camera_token))
-}
+    let command =
+        camera_commands::wait_for_snapshot(command.command_id, camera_id, &long_polls, &conn)
+            .map_err(|error| {
+                error!(
+                    "Failed to check snapshot command {}! The error was {}",
+                    command.command_id, error
+                );
+                ApiError {
+                    error: "Failed to get snapshot",
+                    code: "get_snapshot_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            })?;
 
-/// Stores a new image. Returns the seconds since epoch used as the image name
-#[post("/UploadImage", format = "image/jpeg", data = "<image>")]
-pub fn upload_image(image: Data, camera_token: CameraToken) -> Result<String, ApiError> {
-    let images_directory = images_directory();
+    let snapshot = Snapshot::from_command(&command);
+    if snapshot.status == "timed_out" {
+        return Err(ApiError {
+            error: "The camera didn't send the snapshot in time",
+            code: "snapshot_timed_out",
+            status: Status::GatewayTimeout,
+            field: None,
+        });
+    }
 
-    create_dir_all(format!("{}/{}", &images_directory, camera_token.camera_id))
-        .expect("Failed to create images directory");
+    Ok(SnapshotResponse(snapshot))
+}
 
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Failed to get current time somehow?")
-        .as_secs();
+/// Where a snapshot from POST /Cameras/<camera_id>/Snapshot is up to.
+#[openapi]
+#[get("/Cameras/<camera_id>/Snapshot/<command_id>")]
+pub fn get_snapshot(
+    conn: ReadDbConn,
+    user_token: user_tokens::UserToken,
+    camera_id: CameraId,
+    command_id: i32,
+) -> Result<Json<Snapshot>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
 
-    image
-        .stream_to_file(format!(
-            "{}/{}/{}.jpg",
-            images_directory, camera_token.camera_id, current_time
-        ))
-        .map_err(|error| {
-            println!("Failed to stream image to file! The error was {}", error);
-            ApiError {
-                error: "Failed to save image to server",
+    match camera_commands::get(command_id, &conn) {
+        Ok(command) if command.camera_id == camera_id && command.command == SNAPSHOT_COMMAND => {
+            Ok(Json(Snapshot::from_command(&command)))
+        }
+        Ok(_) | Err(diesel::result::Error::NotFound) => Err(ApiError {
+            error: "Snapshot not found",
+            code: "snapshot_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        Err(error) => {
+            error!(
+                "Failed to get snapshot command {}! The error was {}",
+                command_id, error
+            );
+            Err(ApiError {
+                error: "Failed to get snapshot",
+                code: "get_snapshot_failed",
                 status: Status::InternalServerError,
-            }
-        })?;
-
-    Ok(current_time.to_string())
+                field: None,
+            })
+        }
+    }
 }
 
-#[get("/Cameras/<camera_id_string>/LatestImage", format = "image/jpeg")]
+#[openapi(skip)]
+#[get("/Cameras/<camera_id>/LatestImage", format = "image/jpeg")]
 pub fn get_latest(
     conn: CameraServerDbConn,
     user_token: user_tokens::UserToken,
-    camera_id_string: String,
-) -> Result<Stream<File>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
+    camera_id: CameraId,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
 
-    let images_directory_path = images_directory();
-
-    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
+    // The cached ID can be for an image that's since been deleted, so the list is used if it can't be opened
+    if let Some(image_id) = cache()
+        .get(&cache::latest_image_key(camera_id))
+        .and_then(|image_id| image_id.parse::<u64>().ok())
+    {
+        if let Ok(image) = open_image(&camera_id, image_id) {
+            return Ok(image);
+        }
+    }
 
-    let sorted_image_list = list_camera_directory(&camera_directory, true)?;
+    let sorted_image_list = list_camera_images(&camera_id)?;
 
-    // It should be OK to do an expect() here since sorted_directory_list() already returns an error if the dir list is empty
-    File::open(
-        sorted_image_list
+    // It should be OK to do an expect() here since list_camera_images() already returns an error if the image list is empty
+    open_image(
+        &camera_id,
+        *sorted_image_list
             .last()
-            .expect("Failed to get the last element of the sorted image list somehow?")
-            .path(),
+            .expect("Failed to get the last element of the sorted image list somehow?"),
     )
-    .map(Stream::from)
-    .map_err(|error| {
-        println!("Failed to read file! The error was {}", error);
-        ApiError {
-            error: "Failed to load image",
-            status: Status::InternalServerError,
-        }
-    })
 }
 
-#[get("/Cameras/<camera_id_string>/ImageList")]
+#[openapi]
+#[get("/Cameras/<camera_id>/Images?<query..>")]
 pub fn get_image_list(
-    conn: CameraServerDbConn,
+    conn: ReadDbConn,
     user_token: user_tokens::UserToken,
-    camera_id_string: String,
-) -> Result<Json<Vec<String>>, ApiError> {
-    let images_directory_path = images_directory();
-    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
+    camera_id: CameraId,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<String>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+    let (offset, limit) = query.offset_and_limit()?;
 
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let sorted_directory_list = list_camera_directory(&camera_directory, true)?
+    let sorted_image_list = list_camera_images(&camera_id)?
         .iter()
-        .map(|x| {
-            // This horrible thing removes the extension from the file name and effectively converts the DirEntrys to Strings (since returning &strs in Vecs is awkward to do)
-            Path::new(&x.file_name()).file_stem().expect("file_stem returned None! This should only happen if a file doesn't have a name somehow").to_str().expect("Failed to convert &OsStr to &str!").to_string()
-        })
+        .map(|image_id| image_id.to_string())
         .collect();
 
-    Ok(Json(sorted_directory_list))
+    Ok(Json(Page::from_vec(sorted_image_list, offset, limit)))
 }
 
-#[get(
-    "/Cameras/<camera_id_string>/Image/<image_id_string>",
-    format = "image/jpeg"
-)]
+#[openapi(skip)]
+#[get("/Cameras/<camera_id>/Images/<image_id_string>", format = "image/jpeg")]
 pub fn get_image(
-    conn: CameraServerDbConn,
+    conn: ReadDbConn,
     user_token: user_tokens::UserToken,
-    camera_id_string: String,
+    camera_id: CameraId,
     image_id_string: String,
-) -> Result<Stream<File>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let images_directory_path = images_directory();
-
-    let camera_directory = camera_directory(&images_directory_path, &camera_id_string);
-
-    let image_list = list_camera_directory(&camera_directory, false)?;
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
 
-    let image_list_basenames: Vec<String> = image_list.iter()
-    .map(|x| {
-        Path::new(&x.file_name()).file_stem().expect("file_stem returned None! This should only happen if a file doesn't have a name somehow").to_str().expect("Failed to convert &OsStr to &str!").to_string()
-    })
-    .collect();
+    // Image IDs are always numbers, so anything else can't be an image we have
+    let image_id = image_id_string.parse::<u64>().map_err(|_| ApiError {
+        error: "Image not found",
+        code: "image_not_found",
+        status: Status::NotFound,
+        field: None,
+    })?;
 
-    let image_index = image_list_basenames
-        .iter()
-        .position(|x| x == &image_id_string)
-        .ok_or(ApiError {
+    if !list_camera_images(&camera_id)?.contains(&image_id) {
+        return Err(ApiError {
             error: "Image not found",
+            code: "image_not_found",
             status: Status::NotFound,
-        })?;
+            field: None,
+        });
+    }
 
-    File::open(image_list[image_index].path())
-        .map(Stream::from)
-        .map_err(|error| {
-            println!("Failed to read file! The error was {}", error);
-            ApiError {
-                error: "Failed to load image",
-                status: Status::InternalServerError,
-            }
-        })
+    open_image(&camera_id, image_id)
 }
@@ -0,0 +1,140 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    camera::{self, Camera, CameraId},
+    soft_delete::not_found_or_database_error,
+    user,
+    users_cameras::{self, UsersCamera},
+    CameraServerDbConn,
+};
+
+use rocket::http::Status;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Sent with POST /Admin/Cameras/<camera_id>/Reassign.
+#[derive(Deserialize, JsonSchema)]
+pub struct Reassignment {
+    /// Who should have the camera. Everyone else loses access to it.
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+}
+
+/// Returned by DELETE /Admin/Cameras/Orphans.
+#[derive(Serialize, JsonSchema)]
+pub struct DeletedOrphans {
+    #[schemars(with = "Vec<String>")]
+    pub camera_ids: Vec<uuid::Uuid>,
+}
+
+fn orphans_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get orphaned cameras! The error was {}", error);
+    ApiError {
+        error: "Failed to get orphaned cameras",
+        code: "get_orphaned_cameras_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Gives the camera to another user, e.g. when the house it's in changes hands, or to rescue an orphaned camera.
+/// Everyone who had access to it loses it, including deleted users who could otherwise get it back.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post(
+    "/Admin/Cameras/<camera_id>/Reassign",
+    format = "json",
+    data = "<reassignment>"
+)]
+pub fn reassign_camera(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    camera_id: CameraId,
+    reassignment: Json<Reassignment>,
+) -> Result<Json<UsersCamera>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    let user_id = reassignment.into_inner().user_id;
+
+    camera::get(camera_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("camera_not_found", "Camera not found"),
+            ("reassign_camera_failed", "Failed to reassign camera"),
+        )
+    })?;
+    user::get(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("reassign_camera_failed", "Failed to reassign camera"),
+        )
+    })?;
+
+    users_cameras::reassign(camera_id, user_id, &conn)
+        .map(|users_camera| {
+            info!(
+                "Camera {} reassigned to user {} by {}",
+                camera_id, user_id, admin_token.user_id
+            );
+            Json(users_camera)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("camera_not_found", "Camera not found"),
+                ("reassign_camera_failed", "Failed to reassign camera"),
+            )
+        })
+}
+
+/// Cameras that nobody has access to. Reassign them with POST /Admin/Cameras/<camera_id>/Reassign,
+/// or delete them all with DELETE /Admin/Cameras/Orphans. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Cameras/Orphans")]
+pub fn get_orphaned_cameras(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<Camera>>, ApiError> {
+    users_cameras::get_orphaned_cameras(&conn)
+        .map(|cameras| Json(cameras))
+        .map_err(orphans_error)
+}
+
+/// Deletes every orphaned camera. They're soft deleted like any other camera, so they can be undeleted
+/// until they're purged. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Cameras/Orphans")]
+pub fn delete_orphaned_cameras(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+) -> Result<Json<DeletedOrphans>, ApiError> {
+    let orphans = users_cameras::get_orphaned_cameras(&conn).map_err(orphans_error)?;
+    let mut camera_ids = Vec::new();
+
+    for orphan in orphans {
+        camera::soft_delete(orphan.camera_id, &conn).map_err(|error| {
+            error!(
+                "Failed to delete orphaned camera {}! The error was {}",
+                orphan.camera_id, error
+            );
+            ApiError {
+                error: "Failed to delete orphaned cameras",
+                code: "delete_orphaned_cameras_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+        camera_ids.push(orphan.camera_id);
+    }
+
+    info!(
+        "{} orphaned cameras deleted by {}",
+        camera_ids.len(),
+        admin_token.user_id
+    );
+
+    Ok(Json(DeletedOrphans { camera_ids }))
+}
@@ -0,0 +1,405 @@
+use crate::{
+    api_error::ApiError, camera::record_camera_contact, camera_tokens::CameraToken,
+    device_format::Device, CameraServerDbConn,
+};
+
+use super::schema::camera_commands;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use once_cell::sync::Lazy;
+use rocket::http::Status;
+use rocket::{get, Config, State};
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Command sent to a camera when a user asks for a snapshot outside of the camera's normal interval. The camera
+/// should upload the snapshot to POST /Device/Images?command_id=, see record_snapshot(). Snapshots are sent with
+/// an expiry, as one taken much later isn't what was asked for.
+pub const SNAPSHOT_COMMAND: &str = "snapshot";
+/// Command sent to a camera when something in its config changes, so it knows to fetch GET /Device/Config again.
+pub const CONFIG_UPDATED_COMMAND: &str = "config_updated";
+/// Command sent to a camera when its stream credentials are being rotated, so it fetches GET /Device/StreamCredentials.
+pub const ROTATE_STREAM_CREDENTIALS_COMMAND: &str = "rotate_stream_credentials";
+/// Command sent to a camera when someone wants to talk through it, so it connects to the talk relay, see talk.
+pub const TALK_COMMAND: &str = "talk";
+/// Sent to cameras whose RTMP or SRT stream stalled, to stop it and push it again.
+pub const RESTART_STREAM_COMMAND: &str = "restart_stream";
+
+/// The longest a camera can ask GET /Device/Commands to wait for.
+pub const MAX_WAIT_SECONDS: u64 = 60;
+
+/// Waiting requests check the database this often too, in case the command was queued by another server.
+pub const WAIT_RECHECK_SECONDS: u64 = 5;
+
+/// How many times each camera has been woken, for requests that sleep on the condvar until something of the
+/// camera's changes. Cameras are only counted while a request is watching them, so they're forgotten as soon as the
+/// last one stops, rather than kept for every camera that's ever been woken.
+struct Wakeups {
+    counts: Mutex<HashMap<uuid::Uuid, Watched>>,
+    condvar: Condvar,
+}
+
+struct Watched {
+    wakeups: u64,
+    watchers: usize,
+}
+
+impl Wakeups {
+    fn new() -> Wakeups {
+        Wakeups {
+            counts: Mutex::new(HashMap::new()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<HashMap<uuid::Uuid, Watched>> {
+        self.counts.lock().expect("Wakeups lock poisoned!")
+    }
+
+    /// Starts watching the camera. Call it before checking the database, so a wake in between isn't missed.
+    fn watch(&'static self, camera_id: uuid::Uuid) -> Watch {
+        let mut counts = self.lock();
+        let watched = counts.entry(camera_id).or_insert(Watched {
+            wakeups: 0,
+            watchers: 0,
+        });
+        watched.watchers += 1;
+
+        Watch {
+            wakeups: self,
+            camera_id,
+            seen: watched.wakeups,
+        }
+    }
+
+    /// Wakes up any requests watching the camera. Does nothing if there aren't any.
+    fn wake(&self, camera_id: uuid::Uuid) {
+        if let Some(watched) = self.lock().get_mut(&camera_id) {
+            watched.wakeups += 1;
+            self.condvar.notify_all();
+        }
+    }
+}
+
+/// A request watching a camera, see Wakeups::watch(). The camera is forgotten once nothing watches it.
+struct Watch {
+    wakeups: &'static Wakeups,
+    camera_id: uuid::Uuid,
+    seen: u64,
+}
+
+impl Watch {
+    /// Waits until the camera is woken after the last wait (or since it was watched), or until the timeout passes.
+    fn wait(&mut self, timeout: Duration) {
+        let camera_id = self.camera_id;
+        let seen = self.seen;
+        let wakeups = |counts: &HashMap<uuid::Uuid, Watched>| {
+            counts
+                .get(&camera_id)
+                .map(|watched| watched.wakeups)
+                .unwrap_or(seen)
+        };
+
+        let counts = self.wakeups.lock();
+        let (counts, _) = self
+            .wakeups
+            .condvar
+            .wait_timeout_while(counts, timeout, |counts| wakeups(counts) == seen)
+            .expect("Wakeups lock poisoned!");
+        self.seen = wakeups(&counts);
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let mut counts = self.wakeups.lock();
+
+        if let Some(watched) = counts.get_mut(&self.camera_id) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                counts.remove(&self.camera_id);
+            }
+        }
+    }
+}
+
+/// Woken whenever a command is queued for the camera, for GET /Device/Commands?wait=.
+static QUEUED_COMMANDS: Lazy<Wakeups> = Lazy::new(Wakeups::new);
+
+/// Woken whenever the camera uploads a snapshot for one of its snapshot commands, see wait_for_snapshot().
+static ANSWERED_SNAPSHOTS: Lazy<Wakeups> = Lazy::new(Wakeups::new);
+
+/// Each waiting request ties up one of Rocket's workers, so only so many are allowed to wait at once.
+/// Requests past the limit get whatever is already queued straight away, as if they hadn't asked to wait.
+pub struct LongPolls {
+    pub limit: usize,
+    active: AtomicUsize,
+}
+
+impl LongPolls {
+    /// Lets a quarter of the workers wait, so there are always some left for everything else.
+    pub fn from_config(config: &Config) -> LongPolls {
+        LongPolls {
+            limit: (config.workers as usize / 4).max(1),
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a slot if there is one free. The slot is given back when it's dropped.
+    fn acquire(&self) -> Option<LongPollSlot> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst);
+
+        if active >= self.limit {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(LongPollSlot { long_polls: self })
+        }
+    }
+}
+
+struct LongPollSlot<'a> {
+    long_polls: &'a LongPolls,
+}
+
+impl Drop for LongPollSlot<'_> {
+    fn drop(&mut self) {
+        self.long_polls.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "camera_commands"]
+pub struct CameraCommand {
+    pub command_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub command: String,
+    pub delivered: bool,
+    pub created_at: DateTime<Utc>,
+    /// The command isn't handed to the camera after this. None for commands that never expire.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// For snapshot commands, the image the camera uploaded for it, once it has.
+    pub image_id: Option<i64>,
+}
+
+impl CameraCommand {
+    pub fn has_expired(&self) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= Utc::now())
+    }
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "camera_commands"]
+pub struct InsertableCameraCommand {
+    pub camera_id: uuid::Uuid,
+    pub command: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<CameraCommand>> {
+    camera_commands::table.load::<CameraCommand>(&*connection)
+}
+
+pub fn get(command_id: i32, connection: &PgConnection) -> QueryResult<CameraCommand> {
+    camera_commands::table
+        .find(command_id)
+        .get_result::<CameraCommand>(connection)
+}
+
+/// Queues a command, and wakes up the camera's request if it is waiting on GET /Device/Commands?wait=.
+pub fn insert(
+    camera_command: InsertableCameraCommand,
+    connection: &PgConnection,
+) -> QueryResult<CameraCommand> {
+    let command = diesel::insert_into(camera_commands::table)
+        .values(camera_command)
+        .get_result::<CameraCommand>(connection)?;
+
+    QUEUED_COMMANDS.wake(command.camera_id);
+
+    Ok(command)
+}
+
+pub fn update(
+    command_id: i32,
+    camera_command: CameraCommand,
+    connection: &PgConnection,
+) -> QueryResult<CameraCommand> {
+    diesel::update(camera_commands::table.find(command_id))
+        .set(&camera_command)
+        .get_result(connection)
+}
+
+pub fn delete(command_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(camera_commands::table.find(command_id)).execute(connection)
+}
+
+/// Queues CONFIG_UPDATED_COMMAND for the camera. Whatever changed is already saved by the time this is called,
+/// so a camera missing the nudge isn't worth failing over, and errors are only logged.
+pub fn notify_config_updated(camera_id: uuid::Uuid, connection: &PgConnection) {
+    if let Err(error) = insert(
+        InsertableCameraCommand {
+            camera_id,
+            command: CONFIG_UPDATED_COMMAND.to_string(),
+            expires_at: None,
+        },
+        connection,
+    ) {
+        error!(
+            "Failed to queue config updated command for camera {}! The error was {}",
+            camera_id, error
+        );
+    }
+}
+
+/// Records the image a camera uploaded as the result of its snapshot command. Firmware that doesn't send which
+/// command an image is for answers the oldest snapshot it has been sent and hasn't answered yet. Does nothing if
+/// there's no such command, as for the camera's normal uploads.
+pub fn record_snapshot(
+    camera_id: uuid::Uuid,
+    command_id: Option<i32>,
+    image_id: u64,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let waiting = camera_commands::table
+        .filter(camera_commands::camera_id.eq(camera_id))
+        .filter(camera_commands::command.eq(SNAPSHOT_COMMAND))
+        .filter(camera_commands::image_id.is_null())
+        .filter(camera_commands::expires_at.gt(Utc::now()));
+
+    let command_id = match command_id {
+        Some(command_id) => command_id,
+        None => match waiting
+            .filter(camera_commands::delivered.eq(true))
+            .order(camera_commands::command_id)
+            .select(camera_commands::command_id)
+            .first::<i32>(connection)
+            .optional()?
+        {
+            Some(command_id) => command_id,
+            None => return Ok(()),
+        },
+    };
+
+    let answered = diesel::update(waiting.filter(camera_commands::command_id.eq(command_id)))
+        .set(camera_commands::image_id.eq(image_id as i64))
+        .execute(connection)?;
+
+    if answered > 0 {
+        ANSWERED_SNAPSHOTS.wake(camera_id);
+    }
+
+    Ok(())
+}
+
+/// Waits until the camera uploads the snapshot asked for with the command, or the command expires, and returns the
+/// command as it was then. Returns straight away if `long_polls` has no free slot, as GET /Device/Commands would.
+pub fn wait_for_snapshot(
+    command_id: i32,
+    camera_id: uuid::Uuid,
+    long_polls: &LongPolls,
+    connection: &PgConnection,
+) -> QueryResult<CameraCommand> {
+    let slot = long_polls.acquire();
+    let mut watch = slot.as_ref().map(|_| ANSWERED_SNAPSHOTS.watch(camera_id));
+
+    loop {
+        let command = get(command_id, connection)?;
+        let remaining = command
+            .expires_at
+            .and_then(|expires_at| (expires_at - Utc::now()).to_std().ok())
+            .unwrap_or_default();
+
+        let watch = match &mut watch {
+            Some(watch) if command.image_id.is_none() && remaining > Duration::from_secs(0) => {
+                watch
+            }
+            _ => return Ok(command),
+        };
+
+        // Another server may have stored the snapshot, which only the database says
+        watch.wait(remaining.min(Duration::from_secs(WAIT_RECHECK_SECONDS)));
+    }
+}
+
+/// Returns every command for the given camera that hasn't been delivered yet, oldest first,
+/// and marks them as delivered so that they are only handed to the camera once. Commands that have expired
+/// are left behind.
+pub fn take_pending(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<CameraCommand>> {
+    diesel::update(
+        camera_commands::table
+            .filter(camera_commands::camera_id.eq(camera_id))
+            .filter(camera_commands::delivered.eq(false))
+            .filter(
+                camera_commands::expires_at
+                    .is_null()
+                    .or(camera_commands::expires_at.gt(Utc::now())),
+            ),
+    )
+    .set(camera_commands::delivered.eq(true))
+    .get_results::<CameraCommand>(connection)
+    .map(|mut commands| {
+        commands.sort_by_key(|command| command.command_id);
+        commands
+    })
+}
+
+/// Returns the commands queued for the camera since it last asked. Cameras are expected to poll this.
+/// Cameras that can't hold a WebSocket open can pass wait (in seconds, up to 60) to have the request
+/// block until a command is queued or the time runs out, instead of polling often.
+#[openapi]
+#[get("/Device/Commands?<wait>")]
+pub fn get_commands(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    long_polls: State<LongPolls>,
+    wait: Option<u64>,
+) -> Result<Device<Vec<CameraCommand>>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    let deadline = Instant::now() + Duration::from_secs(wait.unwrap_or(0).min(MAX_WAIT_SECONDS));
+    let slot = if wait.unwrap_or(0) > 0 {
+        long_polls.acquire()
+    } else {
+        None
+    };
+    // Watched before checking, so a command queued in between still wakes the wait below
+    let mut watch = slot
+        .as_ref()
+        .map(|_| QUEUED_COMMANDS.watch(camera_token.camera_id));
+
+    loop {
+        let commands = take_pending(camera_token.camera_id, &conn).map_err(|error| {
+            error!(
+                "Failed to get commands for camera {}! The error was {}",
+                camera_token.camera_id, error
+            );
+            ApiError {
+                error: "Failed to get commands",
+                code: "get_commands_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        let now = Instant::now();
+
+        let watch = match &mut watch {
+            Some(watch) if commands.len() == 0 && now < deadline => watch,
+            _ => return Ok(Device(commands)),
+        };
+
+        watch.wait((deadline - now).min(Duration::from_secs(WAIT_RECHECK_SECONDS)));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    api_error::ApiError,
+    cache::{self, cache},
+    camera::{record_camera_contact, CameraId},
+    camera_tokens::CameraToken,
+    device_format::{Device, DeviceBody},
+    event::parse_timestamp,
+    page::{offset_and_limit, Page},
+    settings::settings,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_owns_camera,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::camera_logs;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The most lines POST /Device/Logs takes at once.
+pub const MAX_LOG_LINES: usize = 500;
+/// Longer lines are cut short.
+pub const MAX_LOG_LINE_BYTES: usize = 1024;
+/// How many times a minute each camera can send logs. Cameras should batch lines up rather than send each one.
+pub const LOG_UPLOADS_PER_MINUTE: u64 = 6;
+/// Levels longer than this are cut short too, as they're meant to be a word like warning.
+const MAX_LEVEL_BYTES: usize = 16;
+
+/// How many hours log lines are kept for, set with camera_log_retention_hours in [limits]. Defaults to 72.
+pub fn camera_log_retention_hours() -> i64 {
+    settings().limits.camera_log_retention_hours
+}
+
+/// One line of a camera's log.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct CameraLogLine {
+    pub log_id: i64,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// When the camera says it logged the line, by its own clock.
+    pub logged_at: DateTime<Utc>,
+    pub level: String,
+    pub message: String,
+    pub received_at: DateTime<Utc>,
+}
+
+/// A line as the camera sends it.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ReportedLogLine {
+    pub logged_at: DateTime<Utc>,
+    /// Like debug, info, warning or error. Defaults to info.
+    pub level: Option<String>,
+    pub message: String,
+}
+
+/// What POST /Device/Logs returns.
+#[derive(Serialize, JsonSchema)]
+pub struct StoredLogs {
+    pub stored: usize,
+}
+
+/// Query string for GET /Cameras/<camera_id>/Logs.
+#[derive(FromForm, JsonSchema)]
+pub struct CameraLogQuery {
+    /// Only lines at this level, e.g. error.
+    pub level: Option<String>,
+    /// Only lines logged at or after this RFC 3339 timestamp.
+    pub since: Option<String>,
+    /// Only lines logged before this RFC 3339 timestamp.
+    pub until: Option<String>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get camera logs! The error was {}", error);
+    ApiError {
+        error: "Failed to get camera logs",
+        code: "get_camera_logs_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Cuts a string down to at most `bytes`, without splitting a character.
+fn truncate(text: &str, bytes: usize) -> &str {
+    if text.len() <= bytes {
+        return text;
+    }
+
+    let mut end = bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Counts an upload from the camera, and turns it away if it's sent too many this minute. If the cache fails,
+/// uploads are let through, like RateLimiter.
+fn check_upload_rate(camera_id: uuid::Uuid) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp();
+    let window_start = now - now % 60;
+
+    let uploads = cache()
+        .increment(
+            &cache::camera_log_uploads_key(camera_id, window_start),
+            Duration::from_secs(60),
+        )
+        .unwrap_or(0);
+
+    if uploads > LOG_UPLOADS_PER_MINUTE {
+        return Err(ApiError {
+            error: "Logs can only be sent 6 times a minute",
+            code: "log_rate_limited",
+            status: Status::TooManyRequests,
+            field: None,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn purge_expired(retention_hours: i64, connection: &PgConnection) -> QueryResult<usize> {
+    let expired_before = Utc::now() - ChronoDuration::hours(retention_hours);
+
+    diesel::delete(camera_logs::table.filter(camera_logs::received_at.lt(expired_before)))
+        .execute(connection)
+}
+
+/// Starts the thread that deletes log lines once they're older than camera_log_retention_hours().
+pub fn spawn_retention_worker(database_url: String) {
+    let retention_hours = camera_log_retention_hours();
+
+    worker::spawn_worker(
+        "Camera log retention",
+        Duration::from_secs(60 * 60),
+        database_url,
+        move |connection| match purge_expired(retention_hours, connection) {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {} expired camera log lines", purged),
+            Err(error) => error!("Failed to purge camera logs! The error was {}", error),
+        },
+    );
+}
+
+/// Stores recent lines from the camera's log, so its owner can see what it's been up to without getting their
+/// hands on it. Lines are kept for camera_log_retention_hours in [limits].
+#[openapi]
+#[post("/Device/Logs", data = "<lines>")]
+pub fn upload_logs(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    lines: DeviceBody<Vec<ReportedLogLine>>,
+) -> Result<Device<StoredLogs>, ApiError> {
+    let camera_id = camera_token.camera_id;
+    let lines = lines.into_inner();
+    record_camera_contact(camera_id, &conn);
+
+    if lines.len() > MAX_LOG_LINES {
+        return Err(ApiError {
+            error: "Logs can have at most 500 lines",
+            code: "too_many_log_lines",
+            status: Status::PayloadTooLarge,
+            field: None,
+        });
+    }
+    check_upload_rate(camera_id)?;
+
+    let rows: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            let level = line
+                .level
+                .as_deref()
+                .map(|level| truncate(level.trim(), MAX_LEVEL_BYTES).to_lowercase())
+                .filter(|level| !level.is_empty())
+                .unwrap_or_else(|| String::from("info"));
+
+            (
+                camera_logs::camera_id.eq(camera_id),
+                camera_logs::logged_at.eq(line.logged_at),
+                camera_logs::level.eq(level),
+                camera_logs::message.eq(truncate(&line.message, MAX_LOG_LINE_BYTES).to_string()),
+            )
+        })
+        .collect();
+
+    if rows.is_empty() {
+        return Ok(Device(StoredLogs { stored: 0 }));
+    }
+
+    diesel::insert_into(camera_logs::table)
+        .values(rows)
+        .execute(&*conn)
+        .map(|stored| Device(StoredLogs { stored }))
+        .map_err(|error| {
+            error!(
+                "Failed to store logs for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to store logs",
+                code: "store_logs_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// The camera's recent log lines, newest first. Only for its owner, as logs can say more about the network the
+/// camera is on than a shared user should see.
+#[openapi]
+#[get("/Cameras/<camera_id>/Logs?<query..>")]
+pub fn get_camera_logs(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    query: Form<CameraLogQuery>,
+) -> Result<Json<Page<CameraLogLine>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+    let since = query.since.as_ref().map(parse_timestamp).transpose()?;
+    let until = query.until.as_ref().map(parse_timestamp).transpose()?;
+
+    let filtered = || {
+        let mut filtered = camera_logs::table
+            .filter(camera_logs::camera_id.eq(camera_id))
+            .into_boxed();
+
+        if let Some(level) = &query.level {
+            filtered = filtered.filter(camera_logs::level.eq(level.to_lowercase()));
+        }
+        if let Some(since) = since {
+            filtered = filtered.filter(camera_logs::logged_at.ge(since));
+        }
+        if let Some(until) = until {
+            filtered = filtered.filter(camera_logs::logged_at.lt(until));
+        }
+
+        filtered
+    };
+
+    let items = filtered()
+        .order((camera_logs::logged_at.desc(), camera_logs::log_id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load::<CameraLogLine>(&*conn)
+        .map_err(database_error)?;
+
+    let total = filtered()
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
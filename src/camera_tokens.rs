@@ -1,16 +1,26 @@
-use crate::{enums::token_error::TokenError, CameraServerDbConn};
+use crate::{
+    bandwidth,
+    cache::{self, cache},
+    camera, database,
+    enums::token_error::{TokenError, TokenRejection},
+    request_id, tenant, user, CameraServerDbConn,
+};
 
-use super::schema::camera_tokens;
+use super::schema::{camera_tokens, cameras};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{self};
 use rocket::{http::Status, request, request::FromRequest, Outcome, Request};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::ops::Deref;
 
-#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
 #[table_name = "camera_tokens"]
 pub struct CameraToken {
+    #[schemars(with = "String")]
     pub camera_token: uuid::Uuid,
+    #[schemars(with = "String")]
     pub camera_id: uuid::Uuid,
 }
 
@@ -28,14 +38,20 @@ impl<'a, 'r> FromRequest<'a, 'r> for CameraToken {
                         return Outcome::Failure((Status::BadRequest, TokenError::ParseError))
                     }
                 };
-                let connection = CameraServerDbConn::from_request(&request)
-                    .expect("Failed to get DB connection on CameraToken request guard");
-                match get(parsed_token, &connection) {
-                    Ok(camera_token) => return Outcome::Success(camera_token),
 
-                    Err(_) => {
-                        return Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+                match authorise_camera_token(parsed_token, tenant::request_tenant(request), || {
+                    CameraServerDbConn::from_request(&request)
+                        .expect("Failed to get DB connection on CameraToken request guard")
+                }) {
+                    Ok(camera_id) => {
+                        request_id::record_camera(camera_id);
+                        bandwidth::record_camera(request, camera_id);
+                        Outcome::Success(CameraToken {
+                            camera_token: parsed_token,
+                            camera_id,
+                        })
                     }
+                    Err(rejection) => rejection.fail(request),
                 }
             }
             // Token does not exist
@@ -44,6 +60,72 @@ impl<'a, 'r> FromRequest<'a, 'r> for CameraToken {
     }
 }
 
+/// The checks the CameraToken guard makes, for every transport that takes camera tokens (HTTP, gRPC, CoAP and the
+/// realtime server): that the token exists and its camera hasn't been deleted, then authorise_camera(). Returns
+/// which camera the token is for.
+pub fn authorise_camera_token<C: Deref<Target = PgConnection>>(
+    camera_token: uuid::Uuid,
+    tenant_id: Result<i32, Status>,
+    connect: impl Fn() -> C,
+) -> Result<uuid::Uuid, TokenRejection> {
+    let camera_id = lookup_with(camera_token, &connect)
+        .map_err(|_| TokenRejection::new(Status::Unauthorized, TokenError::NotFound))?;
+
+    authorise_camera(camera_id, Some(tenant_id), &connect)?;
+    Ok(camera_id)
+}
+
+/// Checks that the camera is used in its owner's tenant (`tenant_id`, the tenant the client is for, see
+/// tenant::resolve_tenant()), and that its owner isn't suspended. For cameras that are known some other way than
+/// their token, like MQTT client IDs, which don't say which tenant they're for, so `tenant_id` is None. Only
+/// connects to the database if what it needs isn't cached.
+pub fn authorise_camera<C: Deref<Target = PgConnection>>(
+    camera_id: uuid::Uuid,
+    tenant_id: Option<Result<i32, Status>>,
+    connect: impl Fn() -> C,
+) -> Result<(), TokenRejection> {
+    if let Some(tenant_id) = tenant_id {
+        tenant::check_camera(camera_id, tenant_id, || Ok(connect()))?;
+    }
+
+    match user::is_cameras_owner_suspended(camera_id, &connect) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(TokenRejection::because(
+            Status::Forbidden,
+            TokenError::Suspended,
+            "account_suspended",
+            "The camera's owner is suspended",
+        )),
+        Err(error) => {
+            error!(
+                "Failed to check if camera {}'s owner is suspended! The error was {}",
+                camera_id, error
+            );
+            Err(TokenRejection::new(
+                Status::ServiceUnavailable,
+                TokenError::NotFound,
+            ))
+        }
+    }
+}
+
+/// Returns which camera the token is for. Only connects to the database if the token isn't cached.
+pub fn lookup(camera_token: uuid::Uuid, request: &Request) -> QueryResult<uuid::Uuid> {
+    lookup_with(camera_token, || {
+        CameraServerDbConn::from_request(&request)
+            .expect("Failed to get DB connection on CameraToken request guard")
+    })
+}
+
+fn lookup_with<C: Deref<Target = PgConnection>>(
+    camera_token: uuid::Uuid,
+    connect: impl Fn() -> C,
+) -> QueryResult<uuid::Uuid> {
+    cache::cached_uuid(&cache::camera_token_key(camera_token), || {
+        get(camera_token, &connect()).map(|camera_token| camera_token.camera_id)
+    })
+}
+
 #[derive(Insertable, Deserialize, Serialize)]
 #[table_name = "camera_tokens"]
 pub struct InsertableCameraToken {
@@ -62,10 +144,25 @@ pub fn all(connection: &PgConnection) -> QueryResult<Vec<CameraToken>> {
     camera_tokens::table.load::<CameraToken>(&*connection)
 }
 
+/// Deleted cameras' tokens aren't found, so they stop working until the camera is undeleted.
 pub fn get(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<CameraToken> {
+    database::timed(
+        "camera_token_get",
+        camera_tokens::table.find(camera_id).filter(
+            camera_tokens::camera_id.eq_any(camera::not_deleted().select(cameras::camera_id)),
+        ),
+        |query| query.get_result::<CameraToken>(connection),
+    )
+}
+
+pub fn get_cameras_tokens(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
     camera_tokens::table
-        .find(camera_id)
-        .get_result::<CameraToken>(connection)
+        .filter(camera_tokens::camera_id.eq(camera_id))
+        .select(camera_tokens::camera_token)
+        .load(connection)
 }
 
 pub fn insert(
@@ -82,11 +179,15 @@ pub fn update(
     camera_token: CameraToken,
     connection: &PgConnection,
 ) -> QueryResult<CameraToken> {
-    diesel::update(camera_tokens::table.find(camera_id))
+    let updated = diesel::update(camera_tokens::table.find(camera_id))
         .set(&camera_token)
-        .get_result(connection)
+        .get_result(connection);
+    cache().delete(&cache::camera_token_key(camera_id));
+    updated
 }
 
 pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
-    diesel::delete(camera_tokens::table.find(camera_id)).execute(connection)
+    let deleted = diesel::delete(camera_tokens::table.find(camera_id)).execute(connection);
+    cache().delete(&cache::camera_token_key(camera_id));
+    deleted
 }
@@ -0,0 +1,199 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    camera::{self, record_camera_contact, CameraId},
+    camera_tokens::CameraToken,
+    device_format::Device,
+    notification,
+    settings::settings,
+    soft_delete::not_found_or_database_error,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::camera_clocks;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::get;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// How far (in seconds) a camera's clock can be before its users are alerted, set with max_clock_drift_seconds
+/// in [limits]. Defaults to 30.
+pub fn max_clock_drift_seconds() -> i64 {
+    settings().limits.max_clock_drift_seconds
+}
+
+/// How far out a camera's clock was the last time it reported its time.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct CameraClock {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// The camera's time minus the server's, so positive if it's ahead. Includes however long the request took
+    /// to arrive, so a few hundred milliseconds is nothing to worry about.
+    pub drift_ms: i64,
+    /// Whether the drift is more than max_clock_drift_seconds.
+    pub drifted: bool,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// What GET /Device/Time returns.
+#[derive(Serialize, JsonSchema)]
+pub struct ServerTime {
+    pub server_time: DateTime<Utc>,
+    /// How far out the camera's clock is, if it sent device_time.
+    pub drift_ms: Option<i64>,
+    pub drifted: Option<bool>,
+}
+
+/// Compares the time a camera says it is with the server's, and alerts its users if it has just drifted past
+/// max_clock_drift_seconds.
+pub fn record_device_time(
+    camera_id: uuid::Uuid,
+    device_time: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<CameraClock> {
+    let now = Utc::now();
+    let drift_ms = (device_time - now).num_milliseconds();
+    let drifted = drift_ms.abs() > max_clock_drift_seconds() * 1000;
+
+    let was_drifted = camera_clocks::table
+        .find(camera_id)
+        .select(camera_clocks::drifted)
+        .get_result::<bool>(connection)
+        .optional()?
+        .unwrap_or(false);
+
+    let clock = diesel::insert_into(camera_clocks::table)
+        .values((
+            camera_clocks::camera_id.eq(camera_id),
+            camera_clocks::drift_ms.eq(drift_ms),
+            camera_clocks::drifted.eq(drifted),
+            camera_clocks::checked_at.eq(now),
+        ))
+        .on_conflict(camera_clocks::camera_id)
+        .do_update()
+        .set((
+            camera_clocks::drift_ms.eq(drift_ms),
+            camera_clocks::drifted.eq(drifted),
+            camera_clocks::checked_at.eq(now),
+        ))
+        .get_result::<CameraClock>(connection)?;
+
+    if drifted && !was_drifted {
+        warn!("Camera {}'s clock is {}ms out", camera_id, drift_ms);
+
+        if let Err(error) = camera::get(camera_id, connection).and_then(|camera| {
+            notification::notify_clock_drift(&camera, drift_ms / 1000, connection)
+        }) {
+            error!(
+                "Failed to alert users of camera {} about its clock! The error was {}",
+                camera_id, error
+            );
+        }
+    }
+
+    Ok(clock)
+}
+
+/// record_device_time() for heartbeats, which shouldn't fail over it.
+pub fn check_device_time(
+    camera_id: uuid::Uuid,
+    device_time: DateTime<Utc>,
+    connection: &PgConnection,
+) {
+    if let Err(error) = record_device_time(camera_id, device_time, connection) {
+        error!(
+            "Failed to check the clock of camera {}! The error was {}",
+            camera_id, error
+        );
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    not_found_or_database_error(
+        error,
+        ("time_not_reported", "Camera hasn't reported its time yet"),
+        ("check_clock_failed", "Failed to check clock"),
+    )
+}
+
+/// Returns the server's time, for cameras without NTP to set their clocks by. Pass device_time, the camera's own
+/// time as RFC 3339 like 2021-06-24T09:00:00Z, to have its drift checked too. Cameras should do so every so often,
+/// as events and images are stored with the times cameras give them.
+#[openapi]
+#[get("/Device/Time?<device_time>")]
+pub fn get_device_time(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    device_time: Option<String>,
+) -> Result<Device<ServerTime>, ApiError> {
+    let camera_id = camera_token.camera_id;
+    record_camera_contact(camera_id, &conn);
+
+    let clock = match device_time {
+        Some(device_time) => {
+            // A + that wasn't percent encoded arrives as a space
+            let device_time = DateTime::parse_from_rfc3339(&device_time.replace(' ', "+"))
+                .map_err(|_| ApiError {
+                    error: "device_time must be an RFC 3339 timestamp",
+                    code: "invalid_device_time",
+                    status: Status::UnprocessableEntity,
+                    field: Some("device_time"),
+                })?;
+
+            Some(
+                record_device_time(camera_id, device_time.with_timezone(&Utc), &conn)
+                    .map_err(database_error)?,
+            )
+        }
+        None => None,
+    };
+
+    Ok(Device(ServerTime {
+        server_time: Utc::now(),
+        drift_ms: clock.as_ref().map(|clock| clock.drift_ms),
+        drifted: clock.map(|clock| clock.drifted),
+    }))
+}
+
+/// How far out the camera's clock was the last time it reported its time.
+#[openapi]
+#[get("/Cameras/<camera_id>/Clock")]
+pub fn get_camera_clock(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<CameraClock>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    camera_clocks::table
+        .find(camera_id)
+        .get_result::<CameraClock>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Every camera whose clock was more than max_clock_drift_seconds out when it last reported its time, furthest out
+/// first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Cameras/ClockDrift")]
+pub fn get_drifted_cameras(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<CameraClock>>, ApiError> {
+    camera_clocks::table
+        .filter(camera_clocks::drifted.eq(true))
+        .load::<CameraClock>(&*conn)
+        .map(|mut clocks| {
+            clocks.sort_by_key(|clock| -clock.drift_ms.abs());
+            Json(clocks)
+        })
+        .map_err(database_error)
+}
@@ -0,0 +1,124 @@
+use crate::{cache, settings::settings};
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before subscribing again after losing the connection to Redis.
+pub const RESUBSCRIBE_SECONDS: u64 = 5;
+
+/// Whether more than one instance of the server shares the database, set with multiple_instances in [scaling].
+/// Defaults to off.
+///
+/// Everything an instance keeps to itself is either a copy of what's in the database or only matters to
+/// that instance (e.g. its health and its waiting long polls). The rest is shared through Postgres or Redis:
+/// - Caches and rate limit counts are in Redis if redis_url in [cache] is set, otherwise each instance has its own.
+/// - Realtime messages go through Redis if redis_url in [cache] is set, otherwise only clients of the instance
+///   that handled the upload hear about it.
+/// - Background workers take a Postgres advisory lock for each run, so only one instance runs each at a time.
+/// - The MQTT ingest bridge subscribes with a shared subscription, so each message is only ingested once.
+/// - Images and audio are stored in images_directory and audio_directory, which must be the same shared
+///   volume on every instance.
+///
+/// Turning this on makes redis_url in [cache] required, so instances can't silently drift apart.
+pub fn multiple_instances() -> bool {
+    settings().scaling.multiple_instances
+}
+
+#[derive(QueryableByName)]
+struct Locked {
+    #[sql_type = "Bool"]
+    locked: bool,
+}
+
+/// Takes the advisory lock called `name` for this connection, if no other connection has it.
+/// Returns whether it was taken. The lock is held until unlock() or the connection closes.
+pub fn try_lock(name: &str, connection: &PgConnection) -> QueryResult<bool> {
+    diesel::sql_query("SELECT pg_try_advisory_lock(hashtext($1)) AS locked")
+        .bind::<Text, _>(name)
+        .get_result::<Locked>(connection)
+        .map(|result| result.locked)
+}
+
+/// try_lock(), but waits for whoever has the lock to give it up.
+pub fn lock(name: &str, connection: &PgConnection) -> QueryResult<()> {
+    diesel::sql_query("SELECT pg_advisory_lock(hashtext($1))")
+        .bind::<Text, _>(name)
+        .execute(connection)
+        .map(|_| ())
+}
+
+pub fn unlock(name: &str, connection: &PgConnection) -> QueryResult<()> {
+    diesel::sql_query("SELECT pg_advisory_unlock(hashtext($1)) AS locked")
+        .bind::<Text, _>(name)
+        .get_result::<Locked>(connection)
+        .map(|_| ())
+}
+
+/// Sends `message` to every instance subscribed to `channel`, including this one.
+/// Returns false if there's no Redis or it couldn't be reached, so the caller can handle the message itself.
+pub fn publish(channel: &str, message: &str) -> bool {
+    let pool = match cache::redis_pool() {
+        Some(pool) => pool,
+        None => return false,
+    };
+
+    let result = pool
+        .get()
+        .map_err(|error| error.to_string())
+        .and_then(|mut connection| {
+            redis::cmd("PUBLISH")
+                .arg(format!("{}{}", cache::REDIS_KEY_PREFIX, channel))
+                .arg(message)
+                .query::<()>(&mut *connection)
+                .map_err(|error| error.to_string())
+        });
+
+    match result {
+        Ok(()) => true,
+        Err(error) => {
+            error!(
+                "Failed to publish to Redis channel {}! The error was {}",
+                channel, error
+            );
+            false
+        }
+    }
+}
+
+/// Starts a thread that calls `handle` with every message published to `channel`, subscribing again whenever
+/// the connection to Redis is lost. Does nothing if there's no Redis.
+pub fn subscribe<F>(channel: &'static str, mut handle: F)
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    let redis_url = match cache::redis_url() {
+        Some(redis_url) => redis_url,
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        let result = redis::Client::open(redis_url.as_str())
+            .and_then(|client| client.get_connection())
+            .and_then(|mut connection| -> redis::RedisResult<()> {
+                let mut pubsub = connection.as_pubsub();
+                pubsub.subscribe(format!("{}{}", cache::REDIS_KEY_PREFIX, channel))?;
+
+                loop {
+                    let message = pubsub.get_message()?;
+                    handle(&message.get_payload::<String>()?);
+                }
+            });
+
+        if let Err(error) = result {
+            error!(
+                "Lost Redis channel {}, subscribing again in {} seconds! The error was {}",
+                channel, RESUBSCRIBE_SECONDS, error
+            );
+        }
+
+        thread::sleep(Duration::from_secs(RESUBSCRIBE_SECONDS));
+    });
+}
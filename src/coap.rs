@@ -0,0 +1,524 @@
+use crate::{
+    api_error::ApiError,
+    bandwidth,
+    camera::{self, record_camera_contact},
+    camera_commands::{take_pending, CameraCommand},
+    camera_tokens, clock, config,
+    event::{store_reported_event, ReportedEvent},
+    settings::settings,
+    tenant, upload_limit,
+};
+
+use chrono::{DateTime, Utc};
+use coap_lite::{CoapOption, CoapRequest, Packet, RequestType, ResponseType};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bigger datagrams than this are cut off. Block-wise transfers use much smaller blocks than this anyway.
+pub const MAX_DATAGRAM_BYTES: usize = 2048;
+
+/// CoAP is for sensor-class cameras, so snapshots sent over it are expected to be small.
+pub const MAX_SNAPSHOT_BYTES: usize = 256 * 1024;
+
+/// Block-wise uploads that don't get a new block for this long are thrown away.
+pub const UPLOAD_TIMEOUT_SECONDS: u64 = 60;
+
+/// The Content-Format number for application/cbor.
+const CBOR_CONTENT_FORMAT: u8 = 60;
+
+/// The port the CoAP listener uses, set with coap_port in [server]. CoAP is only served if this is set.
+pub fn coap_port() -> Option<u16> {
+    settings().server.coap_port
+}
+
+/// What POST heartbeat hands back.
+#[derive(Serialize)]
+pub struct Heartbeat {
+    pub interval: i16,
+    pub commands: Vec<CameraCommand>,
+    pub server_time: DateTime<Utc>,
+}
+
+/// What cameras can send with POST heartbeat. The payload can also be left empty.
+#[derive(Deserialize)]
+pub struct HeartbeatReport {
+    /// The camera's own time, to check its clock against the server's.
+    pub device_time: Option<DateTime<Utc>>,
+}
+
+/// An image being uploaded a block at a time with Block1. Kept until it times out once it's stored, so the last
+/// block can be acknowledged again if the camera didn't get the response.
+struct PartialUpload {
+    camera_id: uuid::Uuid,
+    image: Vec<u8>,
+    next_block: u32,
+    updated_at: Instant,
+    /// Set once every block has arrived and the image is stored.
+    image_id: Option<u64>,
+}
+
+struct Reply {
+    status: ResponseType,
+    /// CBOR, or a diagnostic message for errors.
+    payload: Vec<u8>,
+    is_cbor: bool,
+    /// Echoed back for block-wise uploads.
+    block1: Option<Vec<u8>>,
+}
+
+impl Reply {
+    fn cbor<T: Serialize>(status: ResponseType, value: &T) -> Reply {
+        Reply {
+            status,
+            payload: serde_cbor::to_vec(value).expect("Failed to serialize CoAP reply somehow?"),
+            is_cbor: true,
+            block1: None,
+        }
+    }
+
+    fn error(error: ApiError) -> Reply {
+        let status = match error.status.code {
+            400 => ResponseType::BadRequest,
+            401 => ResponseType::Unauthorized,
+            403 => ResponseType::Forbidden,
+            404 => ResponseType::NotFound,
+            413 => ResponseType::RequestEntityTooLarge,
+            415 => ResponseType::UnsupportedContentFormat,
+            422 => ResponseType::UnprocessableEntity,
+            429 | 503 => ResponseType::ServiceUnavailable,
+            _ => ResponseType::InternalServerError,
+        };
+
+        Reply {
+            status,
+            payload: error.error.as_bytes().to_vec(),
+            is_cbor: false,
+            block1: None,
+        }
+    }
+}
+
+fn bad_request(code: &'static str, error: &'static str) -> ApiError {
+    ApiError {
+        error,
+        code,
+        status: Status::BadRequest,
+        field: None,
+    }
+}
+
+/// A parameter in the query, as cameras can't send headers over CoAP.
+fn query_parameter(packet: &Packet, name: &str) -> Option<String> {
+    packet.get_option(CoapOption::UriQuery).and_then(|queries| {
+        queries.iter().find_map(|query| {
+            let query = std::str::from_utf8(query).ok()?;
+            query
+                .strip_prefix(name)
+                .and_then(|query| query.strip_prefix('='))
+                .map(|value| value.to_string())
+        })
+    })
+}
+
+/// The camera's token goes in the query, as ?token=<camera_token>, along with its tenant's slug as
+/// ?tenant=<slug> if it can't be sent to the tenant's hostname.
+fn camera_id(packet: &Packet, connection: &PgConnection) -> Result<uuid::Uuid, ApiError> {
+    let camera_token = query_parameter(packet, "token").ok_or(ApiError {
+        error: "No camera token provided",
+        code: "camera_token_required",
+        status: Status::Unauthorized,
+        field: None,
+    })?;
+
+    let camera_token = uuid::Uuid::parse_str(&camera_token)
+        .map_err(|_| bad_request("invalid_camera_token", "Failed to parse camera token"))?;
+
+    let host = packet
+        .get_option(CoapOption::UriHost)
+        .and_then(|hosts| hosts.front())
+        .and_then(|host| std::str::from_utf8(host).ok());
+    let tenant_id = tenant::resolve_tenant(
+        query_parameter(packet, tenant::TENANT_HEADER).as_deref(),
+        host,
+        || Ok(connection),
+    );
+
+    camera_tokens::authorise_camera_token(camera_token, tenant_id, || connection).map_err(
+        |rejection| ApiError {
+            error: rejection.message(),
+            code: rejection.code(),
+            status: rejection.status,
+            field: None,
+        },
+    )
+}
+
+/// Splits a Block1 option into its block number, whether more blocks follow, and the block size.
+fn parse_block1(value: &[u8]) -> (u32, bool, usize) {
+    let value = value
+        .iter()
+        .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+
+    (value >> 4, value & 0x8 != 0, 16 << (value & 0x7))
+}
+
+fn heartbeat(
+    camera_id: uuid::Uuid,
+    payload: &[u8],
+    connection: &PgConnection,
+) -> Result<Reply, ApiError> {
+    if !payload.is_empty() {
+        let report = serde_cbor::from_slice::<HeartbeatReport>(payload).map_err(|error| {
+            warn!("Failed to parse CoAP heartbeat! The error was {}", error);
+            ApiError {
+                error: "Heartbeat must be empty or CBOR",
+                code: "invalid_heartbeat",
+                status: Status::UnprocessableEntity,
+                field: None,
+            }
+        })?;
+
+        if let Some(device_time) = report.device_time {
+            clock::check_device_time(camera_id, device_time, connection);
+        }
+    }
+
+    let config = config::get(camera_id, connection).map_err(|error| {
+        error!(
+            "Failed to get config for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to read config",
+            code: "read_config_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    let commands = take_pending(camera_id, connection).map_err(|error| {
+        error!(
+            "Failed to get commands for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to get commands",
+            code: "get_commands_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(Reply::cbor(
+        ResponseType::Content,
+        &Heartbeat {
+            interval: config.interval,
+            commands,
+            server_time: Utc::now(),
+        },
+    ))
+}
+
+fn report_event(
+    camera_id: uuid::Uuid,
+    payload: &[u8],
+    connection: &PgConnection,
+) -> Result<Reply, ApiError> {
+    let reported_event = serde_cbor::from_slice::<ReportedEvent>(payload).map_err(|error| {
+        warn!("Failed to parse CoAP event! The error was {}", error);
+        ApiError {
+            error: "Event must be CBOR",
+            code: "invalid_event",
+            status: Status::UnprocessableEntity,
+            field: None,
+        }
+    })?;
+
+    let event = store_reported_event(camera_id, reported_event, connection)?;
+
+    Ok(Reply::cbor(ResponseType::Created, &event))
+}
+
+/// Stores the image once its last block arrives. Images small enough for one datagram can be sent without Block1.
+fn upload_image(
+    camera_id: uuid::Uuid,
+    packet: &Packet,
+    source: SocketAddr,
+    uploads: &mut HashMap<SocketAddr, PartialUpload>,
+    connection: &PgConnection,
+) -> Result<Reply, ApiError> {
+    let block1 = packet
+        .get_option(CoapOption::Block1)
+        .and_then(|values| values.front())
+        .cloned();
+
+    let image = match &block1 {
+        None => packet.payload.clone(),
+        Some(value) => {
+            let (number, more, size) = parse_block1(value);
+
+            // Blocks are sent again when their response is lost, so ones that have already arrived are acknowledged
+            // again rather than added twice. A block 0 that doesn't match is a new upload
+            let repeated = uploads.get(&source).filter(|upload| {
+                upload.camera_id == camera_id
+                    && number < upload.next_block
+                    && (number > 0 || upload.image.starts_with(&packet.payload))
+            });
+            if let Some(upload) = repeated {
+                let mut reply = match upload.image_id {
+                    Some(image_id) if !more => {
+                        Reply::cbor(ResponseType::Created, &image_id.to_string())
+                    }
+                    _ => Reply {
+                        status: ResponseType::Continue,
+                        payload: Vec::new(),
+                        is_cbor: false,
+                        block1: None,
+                    },
+                };
+                reply.block1 = Some(value.clone());
+                return Ok(reply);
+            }
+
+            if number == 0 {
+                uploads.insert(
+                    source,
+                    PartialUpload {
+                        camera_id,
+                        image: Vec::new(),
+                        next_block: 0,
+                        updated_at: Instant::now(),
+                        image_id: None,
+                    },
+                );
+            }
+
+            let in_order = uploads.get(&source).map_or(false, |upload| {
+                upload.camera_id == camera_id
+                    && upload.image_id.is_none()
+                    && upload.next_block == number
+            });
+
+            if !in_order {
+                uploads.remove(&source);
+                return Ok(Reply {
+                    status: ResponseType::RequestEntityIncomplete,
+                    payload: b"Blocks must be sent in order, starting from 0, without gaps"
+                        .to_vec(),
+                    is_cbor: false,
+                    block1: None,
+                });
+            }
+
+            let upload = uploads
+                .get_mut(&source)
+                .expect("Upload disappeared after being checked somehow?");
+
+            if upload.image.len() + packet.payload.len() > MAX_SNAPSHOT_BYTES {
+                uploads.remove(&source);
+                return Err(ApiError {
+                    error: "Snapshots can be at most 256KiB",
+                    code: "snapshot_too_large",
+                    status: Status::PayloadTooLarge,
+                    field: None,
+                });
+            }
+
+            upload.image.extend_from_slice(&packet.payload);
+            upload.next_block += 1;
+            upload.updated_at = Instant::now();
+
+            if more {
+                if packet.payload.len() != size {
+                    uploads.remove(&source);
+                    return Err(bad_request(
+                        "short_block",
+                        "Every block but the last must be full",
+                    ));
+                }
+
+                return Ok(Reply {
+                    status: ResponseType::Continue,
+                    payload: Vec::new(),
+                    is_cbor: false,
+                    block1: Some(value.clone()),
+                });
+            }
+
+            upload.image.clone()
+        }
+    };
+
+    if image.len() > MAX_SNAPSHOT_BYTES {
+        return Err(ApiError {
+            error: "Snapshots can be at most 256KiB",
+            code: "snapshot_too_large",
+            status: Status::PayloadTooLarge,
+            field: None,
+        });
+    }
+
+    let stored =
+        upload_limit::acquire_within(camera_id, Duration::from_secs(0)).and_then(|_upload_slot| {
+            bandwidth::record(camera_id, image.len() as u64, 0);
+            camera::store_uploaded_image(camera_id, None, &mut Cursor::new(image), connection)
+        });
+    // A block-wise upload that couldn't be stored is started again from block 0
+    let image_id = stored.map_err(|error| {
+        if block1.is_some() {
+            uploads.remove(&source);
+        }
+        error
+    })?;
+    if let (Some(_), Some(upload)) = (&block1, uploads.get_mut(&source)) {
+        upload.image_id = Some(image_id);
+    }
+
+    let mut reply = Reply::cbor(ResponseType::Created, &image_id.to_string());
+    reply.block1 = block1;
+
+    Ok(reply)
+}
+
+fn handle_request(
+    packet: &Packet,
+    method: &RequestType,
+    path: &str,
+    source: SocketAddr,
+    uploads: &mut HashMap<SocketAddr, PartialUpload>,
+    connection: &PgConnection,
+) -> Result<Reply, ApiError> {
+    if !["heartbeat", "events", "images"].contains(&path) {
+        return Err(ApiError {
+            error: "No such resource",
+            code: "resource_not_found",
+            status: Status::NotFound,
+            field: None,
+        });
+    }
+
+    if *method != RequestType::Post {
+        return Ok(Reply {
+            status: ResponseType::MethodNotAllowed,
+            payload: b"Only POST is allowed".to_vec(),
+            is_cbor: false,
+            block1: None,
+        });
+    }
+
+    let camera_id = camera_id(packet, connection)?;
+
+    record_camera_contact(camera_id, connection);
+
+    match path {
+        "heartbeat" => heartbeat(camera_id, &packet.payload, connection),
+        "events" => report_event(camera_id, &packet.payload, connection),
+        _ => upload_image(camera_id, packet, source, uploads, connection),
+    }
+}
+
+/// Starts the CoAP listener if coap_port in [server] is set. It offers heartbeat, events and images as POSTs,
+/// which do the same as GET /Device/Commands (and GET /Device/Time), POST /Device/Events and POST /Device/Images.
+/// Requests are handled one at a time, since sensor-class cameras only wake up every so often.
+pub fn spawn_coap_server(database_url: String) {
+    let port = match coap_port() {
+        Some(port) => port,
+        None => return,
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", port)).expect("Failed to bind CoAP server!");
+
+    thread::spawn(move || {
+        let mut connection: Option<PgConnection> = None;
+        let mut uploads = HashMap::new();
+        let mut buffer = [0; MAX_DATAGRAM_BYTES];
+
+        loop {
+            let (length, source) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) => {
+                    error!("Failed to receive CoAP request! The error was {}", error);
+                    continue;
+                }
+            };
+
+            let packet = match Packet::from_bytes(&buffer[..length]) {
+                Ok(packet) => packet,
+                // Not CoAP, so there's nothing sensible to reply with
+                Err(_) => continue,
+            };
+
+            uploads.retain(|_, upload: &mut PartialUpload| {
+                upload.updated_at.elapsed() < Duration::from_secs(UPLOAD_TIMEOUT_SECONDS)
+            });
+
+            if connection.is_none() {
+                connection = PgConnection::establish(&database_url)
+                    .map_err(|error| {
+                        error!(
+                            "CoAP server failed to connect to the database! The error was {}",
+                            error
+                        )
+                    })
+                    .ok();
+            }
+
+            let mut request = CoapRequest::from_packet(packet, source);
+            let method = request.get_method().clone();
+            let path = request.get_path();
+
+            let reply = match &connection {
+                Some(conn) => {
+                    handle_request(&request.message, &method, &path, source, &mut uploads, conn)
+                        .unwrap_or_else(Reply::error)
+                }
+                None => Reply::error(ApiError {
+                    error: "Failed to connect to the database",
+                    code: "database_unavailable",
+                    status: Status::ServiceUnavailable,
+                    field: None,
+                }),
+            };
+
+            // The connection may have gone bad, so get a new one for the next request
+            if reply.status == ResponseType::InternalServerError {
+                connection = None;
+            }
+
+            let response = match request.response.as_mut() {
+                Some(response) => response,
+                // Resets and acknowledgements don't get a reply
+                None => continue,
+            };
+
+            response.set_status(reply.status);
+            response.message.payload = reply.payload;
+
+            if reply.is_cbor {
+                response
+                    .message
+                    .add_option(CoapOption::ContentFormat, vec![CBOR_CONTENT_FORMAT]);
+            }
+
+            if let Some(block1) = reply.block1 {
+                response.message.add_option(CoapOption::Block1, block1);
+            }
+
+            match response.message.to_bytes() {
+                Ok(bytes) => {
+                    if let Err(error) = socket.send_to(&bytes, source) {
+                        error!("Failed to send CoAP response! The error was {}", error);
+                    }
+                }
+                Err(error) => error!("Failed to encode CoAP response! The error was {:?}", error),
+            }
+        }
+    });
+}
@@ -0,0 +1,128 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header};
+use rocket::{Request, Response};
+use std::io::{Cursor, Write};
+
+/// Bodies smaller than this aren't worth compressing.
+pub const MIN_COMPRESS_BYTES: usize = 1024;
+
+/// 5 is much faster than the default of 11 and still compresses JSON almost as well.
+pub const BROTLI_QUALITY: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Picks the encoding to use from an Accept-Encoding header, preferring brotli.
+/// Encodings with a q of 0 are refused, other q values are ignored.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|encoding| {
+            let mut parts = encoding.split(';');
+            let name = parts.next()?.trim();
+            let refused = parts.any(|parameter| {
+                parameter
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map_or(false, |q| q == 0.0)
+            });
+
+            if refused {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect();
+
+    if accepted.contains(&"br") {
+        Some(Encoding::Brotli)
+    } else if accepted.contains(&"gzip") || accepted.contains(&"*") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+pub fn compress(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, 22);
+            writer.write_all(body)?;
+            Ok(writer.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Compresses JSON responses for clients that send Accept-Encoding.
+/// Images, audio and exports are already compressed or streamed, so only JSON is touched.
+pub struct Compression;
+
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if response.content_type() != Some(ContentType::JSON)
+            || response.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+
+        let encoding = match request
+            .headers()
+            .get_one("Accept-Encoding")
+            .and_then(negotiate)
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        // Whether the response is compressed depends on Accept-Encoding, so caches have to key on it
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+
+        if body.len() < MIN_COMPRESS_BYTES {
+            response.set_sized_body(Cursor::new(body));
+            return;
+        }
+
+        match compress(&body, encoding) {
+            Ok(compressed) => {
+                response.set_sized_body(Cursor::new(compressed));
+                response.set_header(Header::new("Content-Encoding", encoding.name()));
+            }
+            Err(error) => {
+                error!("Failed to compress response! The error was {}", error);
+                response.set_sized_body(Cursor::new(body));
+            }
+        }
+    }
+}
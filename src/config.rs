@@ -1,22 +1,48 @@
+use crate::camera::{record_camera_contact, CameraId};
 use crate::camera_tokens::CameraToken;
+use crate::device_format::Device;
+use crate::mode::is_camera_armed;
 use crate::user_tokens::UserToken;
-use crate::CameraServerDbConn;
+use crate::zone::{load_zones, Zone};
 use crate::{api_error::ApiError, users_cameras::check_if_user_has_access_to_camera};
+use crate::{patch, CameraServerDbConn};
 
 use super::schema::configs;
 use diesel::prelude::*;
 use diesel::{self};
 use rocket::http::Status;
 use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize)]
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
 #[table_name = "configs"]
 pub struct Config {
+    #[schemars(with = "String")]
     pub camera_id: uuid::Uuid,
     pub interval: i16,
 }
 
+/// A partial update to a camera's config, sent with PATCH /Cameras/<camera_id>/Config.
+/// Missing fields are left as they are.
+#[derive(AsChangeset, Deserialize, JsonSchema)]
+#[table_name = "configs"]
+pub struct UpdateConfig {
+    pub interval: Option<i16>,
+}
+
+/// Everything a camera needs to know about how it should behave. Sent to cameras by GET /Device/Config.
+#[derive(Serialize, JsonSchema)]
+pub struct CameraConfig {
+    #[serde(flatten)]
+    pub config: Config,
+    pub zones: Vec<Zone>,
+    /// Whether any of the camera's users are in a mode they've armed the camera in.
+    /// Cameras can skip motion detection while this is false.
+    pub armed: bool,
+}
+
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<Config>> {
     configs::table.load::<Config>(&*connection)
 }
@@ -43,90 +69,144 @@ pub fn update(
         .get_result(connection)
 }
 
+/// Updates only the fields that are set.
+pub fn update_partial(
+    camera_id: uuid::Uuid,
+    update: &UpdateConfig,
+    connection: &PgConnection,
+) -> QueryResult<Config> {
+    patch::or_unchanged(
+        diesel::update(configs::table.find(camera_id))
+            .set(update)
+            .get_result(connection),
+        || get(camera_id, connection),
+    )
+}
+
 pub fn delete(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
     diesel::delete(configs::table.find(camera_id)).execute(connection)
 }
 
-#[get("/Cameras/<camera_id_string>/GetConfigUser")]
+#[openapi]
+#[get("/Cameras/<camera_id>/Config")]
 /// Retrieves a camera's config, authenticates with a user token.
 pub fn get_config_user(
     conn: CameraServerDbConn,
-    camera_id_string: String,
+    camera_id: CameraId,
     user_token: UserToken,
 ) -> Result<Json<Config>, ApiError> {
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let camera_id = uuid::Uuid::parse_str(&camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
-    })?;
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
 
     let config = get(camera_id, &conn).map_err(|error| {
-        println!("Failed to read camera config! The error was {}", error);
+        error!("Failed to read camera config! The error was {}", error);
         return ApiError {
             error: "Failed to read config",
+            code: "read_config_failed",
             status: Status::InternalServerError,
+            field: None,
         };
     })?;
 
     Ok(Json(config))
 }
 
-#[get("/Cameras/GetConfigCamera")]
-/// Retrieves a camera's config, authenticates with a camera token.
+#[openapi]
+#[get("/Device/Config")]
+/// Retrieves a camera's config along with its motion zones, authenticates with a camera token.
 pub fn get_config_camera(
     conn: CameraServerDbConn,
     camera_token: CameraToken,
-) -> Result<Json<Config>, ApiError> {
+) -> Result<Device<CameraConfig>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
     let config = get(camera_token.camera_id, &conn).map_err(|error| {
-        println!("Failed to read camera config! The error was {}", error);
+        error!("Failed to read camera config! The error was {}", error);
         return ApiError {
             error: "Failed to read config",
+            code: "read_config_failed",
             status: Status::InternalServerError,
+            field: None,
         };
     })?;
 
-    Ok(Json(config))
+    let zones = load_zones(camera_token.camera_id, &conn)?;
+
+    let armed = is_camera_armed(camera_token.camera_id, &conn).map_err(|error| {
+        error!(
+            "Failed to check if camera {} is armed! The error was {}",
+            camera_token.camera_id, error
+        );
+        ApiError {
+            error: "Failed to read config",
+            code: "read_config_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(Device(CameraConfig {
+        config,
+        zones,
+        armed,
+    }))
 }
 
-#[post(
-    "/Cameras/<camera_id_string>/UpdateConfig",
-    data = "<new_config>",
-    format = "json"
-)]
+#[openapi]
+#[put("/Cameras/<camera_id>/Config", data = "<new_config>", format = "json")]
 pub fn update_config(
     conn: CameraServerDbConn,
     user_token: UserToken,
-    camera_id_string: String,
+    camera_id: CameraId,
     new_config: Json<Config>,
 ) -> Result<Json<Config>, ApiError> {
     let deserialized_new_config = new_config.into_inner();
-    check_if_user_has_access_to_camera(&conn, &user_token, &camera_id_string)?;
-
-    let camera_id = uuid::Uuid::parse_str(&camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
-    })?;
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
 
     update(camera_id, deserialized_new_config, &conn)
         .map(|result| Json(result))
         .map_err(|error| {
-            println!("Failed to update camera config! The error was {}", error);
+            error!("Failed to update camera config! The error was {}", error);
             return ApiError {
                 error: "Failed to update config",
+                code: "update_config_failed",
                 status: Status::InternalServerError,
+                field: None,
             };
         })
 }
+
+#[openapi]
+#[patch("/Cameras/<camera_id>/Config", data = "<update>", format = "json")]
+pub fn patch_config(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    update: Json<UpdateConfig>,
+) -> Result<Json<Config>, ApiError> {
+    let update = update.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    if update.interval.map_or(false, |interval| interval <= 0) {
+        return Err(ApiError {
+            error: "Interval must be positive",
+            code: "invalid_interval",
+            status: Status::UnprocessableEntity,
+            field: Some("interval"),
+        });
+    }
+
+    update_partial(camera_id, &update, &conn)
+        .map(|config| Json(config))
+        .map_err(|error| {
+            error!("Failed to update camera config! The error was {}", error);
+            ApiError {
+                error: "Failed to update config",
+                code: "update_config_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -0,0 +1,95 @@
+use crate::settings::settings;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+
+/// Request headers browsers are allowed to send cross-origin.
+pub const ALLOWED_HEADERS: &str =
+    "Content-Type, user_token, camera_token, Last-Event-ID, Idempotency-Key, tenant";
+
+/// Response headers browsers let cross-origin scripts read.
+pub const EXPOSED_HEADERS: &str =
+    "Deprecation, Sunset, Link, X-Request-Id, X-RateLimit-Limit, X-RateLimit-Remaining, X-RateLimit-Reset, Retry-After, Idempotent-Replayed";
+
+pub const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// How long browsers can cache a preflight response for.
+pub const PREFLIGHT_MAX_AGE_SECONDS: u32 = 86400;
+
+/// Lets browser frontends on other origins call the API.
+/// allowed_origins in [cors] is a comma separated list of origins (e.g. https://dashboard.example.com), or * for any.
+/// allow_credentials in [cors] lets the browser send cookies and auth headers, and can't be used with *.
+/// Nothing is allowed cross-origin if allowed_origins isn't set.
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allow_credentials: bool,
+}
+
+impl Cors {
+    pub fn from_env() -> Cors {
+        // The settings make sure allow_credentials isn't on with *
+        let cors = &settings().cors;
+
+        Cors {
+            allowed_origins: cors.allowed_origins().map(String::from).collect(),
+            allow_credentials: cors.allow_credentials,
+        }
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed_origin| allowed_origin == "*" || allowed_origin == origin)
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) if self.is_allowed(origin) => origin,
+            _ => return,
+        };
+
+        response.set_header(Header::new(
+            "Access-Control-Allow-Origin",
+            origin.to_string(),
+        ));
+        // The allowed origin depends on the request, so caches have to key on it
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        response.set_header(Header::new(
+            "Access-Control-Expose-Headers",
+            EXPOSED_HEADERS,
+        ));
+
+        if self.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        // No route handles OPTIONS, so preflights are answered here instead of getting a 404
+        let is_preflight = request.method() == Method::Options
+            && request
+                .headers()
+                .get_one("Access-Control-Request-Method")
+                .is_some();
+
+        if is_preflight {
+            response.set_status(Status::NoContent);
+            response.take_body();
+            response.remove_header("Content-Type");
+            response.set_header(Header::new("Access-Control-Allow-Methods", ALLOWED_METHODS));
+            response.set_header(Header::new("Access-Control-Allow-Headers", ALLOWED_HEADERS));
+            response.set_header(Header::new(
+                "Access-Control-Max-Age",
+                PREFLIGHT_MAX_AGE_SECONDS.to_string(),
+            ));
+        }
+    }
+}
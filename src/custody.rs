@@ -0,0 +1,156 @@
+use crate::{api_error::ApiError, settings::settings};
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use once_cell::sync::Lazy;
+use rocket::get;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+pub const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Loaded once, so a key that's made on first use is only made once.
+static SIGNING_KEY: Lazy<Result<Keypair, String>> = Lazy::new(load_signing_key);
+
+/// Lists every file in an account export with its hash, so anyone given the archive can check nothing in it has
+/// changed since the server built it. Written to manifest.json and signed in manifest.sig.
+#[derive(Serialize)]
+pub struct Manifest {
+    pub export_id: i32,
+    pub user_id: uuid::Uuid,
+    pub requested_at: DateTime<Utc>,
+    pub generated_at: DateTime<Utc>,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub sha256: String,
+    pub size_bytes: u64,
+    /// When the server took the image or audio clip in. Only set for media.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ingested_at: Option<DateTime<Utc>>,
+    /// The stored file's hash, if it was changed on its way into the archive, e.g. by blurring faces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub original_sha256: Option<String>,
+}
+
+/// manifest.sig, the server's signature over the exact bytes of manifest.json.
+#[derive(Serialize)]
+pub struct ManifestSignature {
+    pub algorithm: &'static str,
+    /// Hex, as returned by GET /Exports/SigningKey.
+    pub public_key: String,
+    /// Hex.
+    pub signature: String,
+}
+
+/// Returned by GET /Exports/SigningKey.
+#[derive(Serialize, JsonSchema)]
+pub struct SigningKey {
+    pub algorithm: String,
+    /// The Ed25519 public key, in hex.
+    pub public_key: String,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+impl Manifest {
+    /// Hashes a file that's been added to the archive. Returns its entry, so media can have the rest filled in.
+    pub fn add(&mut self, path: &str, data: &[u8]) -> &mut ManifestFile {
+        self.files.push(ManifestFile {
+            path: path.to_string(),
+            sha256: sha256_hex(data),
+            size_bytes: data.len() as u64,
+            ingested_at: None,
+            original_sha256: None,
+        });
+        self.files.last_mut().expect("A file was just added")
+    }
+}
+
+/// Reads the key at export_signing_key_path in [storage], a hex encoded Ed25519 secret key, or makes one there if
+/// there isn't one yet. Only the server can read a key it made.
+fn load_signing_key() -> Result<Keypair, String> {
+    let path = &settings().storage.export_signing_key_path;
+
+    let secret = match fs::read_to_string(path) {
+        Ok(contents) => hex::decode(contents.trim())
+            .map_err(|error| format!("{} isn't hex: {}", path, error))?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let mut secret = [0; 32];
+            getrandom::getrandom(&mut secret)
+                .map_err(|error| format!("Failed to make a signing key: {}", error))?;
+
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(hex::encode(secret).as_bytes()))
+                .map_err(|error| format!("Failed to write {}: {}", path, error))?;
+            info!("Made a new export signing key at {}", path);
+
+            secret.to_vec()
+        }
+        Err(error) => return Err(format!("Failed to read {}: {}", path, error)),
+    };
+
+    let secret = SecretKey::from_bytes(&secret)
+        .map_err(|error| format!("{} isn't an Ed25519 secret key: {}", path, error))?;
+    Ok(Keypair {
+        public: PublicKey::from(&secret),
+        secret,
+    })
+}
+
+fn signing_key() -> Result<&'static Keypair, String> {
+    SIGNING_KEY.as_ref().map_err(|error| error.clone())
+}
+
+/// Signs the bytes of manifest.json.
+pub fn sign(manifest: &[u8]) -> Result<ManifestSignature, String> {
+    let key = signing_key()?;
+
+    Ok(ManifestSignature {
+        algorithm: SIGNATURE_ALGORITHM,
+        public_key: hex::encode(key.public.as_bytes()),
+        signature: hex::encode(key.sign(manifest).to_bytes()),
+    })
+}
+
+/// The public key account export manifests are signed with, for checking an archive handed on to someone else.
+/// The signature in manifest.sig is over the bytes of manifest.json, which has the SHA-256 of every other file.
+#[openapi]
+#[get("/Exports/SigningKey")]
+pub fn get_signing_key() -> Result<Json<SigningKey>, ApiError> {
+    signing_key()
+        .map(|key| {
+            Json(SigningKey {
+                algorithm: SIGNATURE_ALGORITHM.to_string(),
+                public_key: hex::encode(key.public.as_bytes()),
+            })
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to load the export signing key! The error was {}",
+                error
+            );
+            ApiError {
+                error: "Failed to load the export signing key",
+                code: "load_export_signing_key_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
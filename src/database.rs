@@ -0,0 +1,323 @@
+use crate::{api_error::ApiError, metrics, settings::settings};
+
+use diesel::pg::{Pg, PgConnection, PgQueryBuilder};
+use diesel::query_builder::{QueryBuilder, QueryFragment};
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::{Connection, QueryResult};
+use rocket::config::{Table, Value};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{Config, Outcome, Request, State};
+use rocket_contrib::databases::database_config;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The name CameraServerDbConn is configured under in [global.databases].
+pub const DATABASE_NAME: &str = "camera-server-db";
+
+/// Seconds to wait for Postgres when opening a connection, set with connect_timeout. Defaults to 10.
+pub const DEFAULT_CONNECT_TIMEOUT_SECONDS: i64 = 10;
+
+/// Statements running for longer than this many milliseconds are cancelled, set with statement_timeout.
+/// 0 turns the timeout off. Defaults to 60000.
+pub const DEFAULT_STATEMENT_TIMEOUT_MS: i64 = 60_000;
+
+/// How long to wait for a replica connection before trying the next replica, and then the primary.
+pub const REPLICA_CHECKOUT_TIMEOUT_MS: u64 = 500;
+
+/// Adds the timeouts to a Postgres URL as libpq parameters, so they apply to every connection made with it.
+pub fn url_with_timeouts(url: &str, connect_timeout: i64, statement_timeout: i64) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+
+    format!(
+        "{}{}connect_timeout={}&options=-c%20statement_timeout%3D{}",
+        url, separator, connect_timeout, statement_timeout
+    )
+}
+
+fn timeout(database: &Table, key: &str, default: i64) -> i64 {
+    match database.get(key) {
+        Some(value) => match value.as_integer() {
+            Some(timeout) if timeout >= 0 => timeout,
+            _ => panic!(
+                "{} for {} must be a whole number of at least 0!",
+                key, DATABASE_NAME
+            ),
+        },
+        None => default,
+    }
+}
+
+/// Applies connect_timeout and statement_timeout from the camera-server-db entry of [global.databases]
+/// (or ROCKET_DATABASES) to its URL and its replica_urls. The URL is replaced first if url in [database] is set. The pool's size is pool_size in the same entry,
+/// which rocket_contrib reads itself, and defaults to four connections per worker.
+///
+/// The URL is changed rather than the pool, because rocket_contrib builds the pool, and so that background
+/// workers connecting with worker::database_url() get the same timeouts.
+pub fn with_timeouts(mut config: Config) -> Config {
+    let database = match config
+        .extras
+        .get_mut("databases")
+        .and_then(Value::as_table_mut)
+        .and_then(|databases| databases.get_mut(DATABASE_NAME))
+        .and_then(Value::as_table_mut)
+    {
+        Some(database) => database,
+        // rocket_contrib's fairing explains what's missing when it fails to start
+        None => return config,
+    };
+
+    let connect_timeout = timeout(database, "connect_timeout", DEFAULT_CONNECT_TIMEOUT_SECONDS);
+    let statement_timeout = timeout(database, "statement_timeout", DEFAULT_STATEMENT_TIMEOUT_MS);
+
+    if let Some(url) = &settings().database.url {
+        database.insert(String::from("url"), Value::String(url.clone()));
+    }
+
+    if let Some(url) = database.get("url").and_then(Value::as_str) {
+        let url = url_with_timeouts(url, connect_timeout, statement_timeout);
+        database.insert(String::from("url"), Value::String(url));
+    }
+
+    if let Some(replica_urls) = database
+        .get_mut("replica_urls")
+        .and_then(Value::as_array_mut)
+    {
+        for replica_url in replica_urls.iter_mut() {
+            if let Some(url) = replica_url.as_str() {
+                *replica_url =
+                    Value::String(url_with_timeouts(url, connect_timeout, statement_timeout));
+            }
+        }
+    }
+
+    config
+}
+
+/// The pool for the primary. It's built without connecting, so the server starts taking requests (and answering
+/// /readyz) while Postgres is still coming up, rather than failing to launch. See health::spawn_startup().
+pub struct CameraServerDbConnPool(pub Pool<ConnectionManager<PgConnection>>);
+
+impl CameraServerDbConnPool {
+    pub fn from_config(config: &Config) -> CameraServerDbConnPool {
+        let database =
+            database_config(DATABASE_NAME, config).expect("camera-server-db is not configured!");
+
+        CameraServerDbConnPool(
+            Pool::builder()
+                .max_size(database.pool_size)
+                .build_unchecked(ConnectionManager::new(database.url)),
+        )
+    }
+}
+
+/// A connection to the primary. Requests fail with 503 if the pool can't hand one out.
+pub struct CameraServerDbConn(PooledConnection<ConnectionManager<PgConnection>>);
+
+impl Deref for CameraServerDbConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.0
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CameraServerDbConn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let pool = request.guard::<State<CameraServerDbConnPool>>()?;
+
+        match pool.0.get() {
+            Ok(connection) => Outcome::Success(CameraServerDbConn(connection)),
+            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+        }
+    }
+}
+
+/// Pools for the read replicas in replica_urls, in the camera-server-db entry. Each gets pool_size connections,
+/// like the primary. Without any, ReadDbConn always uses the primary.
+pub struct ReadReplicas {
+    pools: Vec<Pool<ConnectionManager<PgConnection>>>,
+    /// Which replica the next request should try first, so they're used in turn.
+    next: AtomicUsize,
+}
+
+impl ReadReplicas {
+    pub fn from_config(config: &Config) -> ReadReplicas {
+        let database =
+            database_config(DATABASE_NAME, config).expect("camera-server-db is not configured!");
+
+        let pools = match database.extras.get("replica_urls") {
+            Some(replica_urls) => replica_urls
+                .as_array()
+                .and_then(|replica_urls| {
+                    replica_urls
+                        .iter()
+                        .map(|replica_url| replica_url.as_str())
+                        .collect::<Option<Vec<&str>>>()
+                })
+                .expect("replica_urls for camera-server-db must be a list of URLs!")
+                .into_iter()
+                .map(|replica_url| {
+                    // Unchecked so that a replica being down doesn't stop the server starting
+                    Pool::builder()
+                        .max_size(database.pool_size)
+                        .build_unchecked(ConnectionManager::new(replica_url))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        ReadReplicas {
+            pools,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a connection to the next replica that has one free, or None if none of them do.
+    fn get(&self) -> Option<PooledConnection<ConnectionManager<PgConnection>>> {
+        if self.pools.len() == 0 {
+            return None;
+        }
+
+        let first = self.next.fetch_add(1, Ordering::Relaxed);
+
+        (0..self.pools.len()).find_map(|offset| {
+            let pool = &self.pools[(first + offset) % self.pools.len()];
+
+            match pool.get_timeout(Duration::from_millis(REPLICA_CHECKOUT_TIMEOUT_MS)) {
+                Ok(connection) => Some(connection),
+                Err(error) => {
+                    warn!(
+                        "Failed to get a read replica connection! The error was {}",
+                        error
+                    );
+                    None
+                }
+            }
+        })
+    }
+}
+
+/// A connection for routes that only read, such as listings. It's to a read replica if there are any,
+/// otherwise (or if none can be reached) to the primary.
+///
+/// Replicas can be a little behind the primary, so this is only for dashboard reads where that doesn't matter,
+/// never for reading back something the same request wrote.
+pub enum ReadDbConn {
+    Replica(PooledConnection<ConnectionManager<PgConnection>>),
+    Primary(CameraServerDbConn),
+}
+
+impl Deref for ReadDbConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match self {
+            ReadDbConn::Replica(connection) => connection,
+            ReadDbConn::Primary(connection) => connection,
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ReadDbConn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let replicas = request.guard::<State<ReadReplicas>>()?;
+
+        match replicas.get() {
+            Some(connection) => Outcome::Success(ReadDbConn::Replica(connection)),
+            None => CameraServerDbConn::from_request(request).map(ReadDbConn::Primary),
+        }
+    }
+}
+
+/// Runs `work` in a transaction, so either every write it makes is kept or none of them are.
+/// For operations that write more than one row, so failing half way through can't leave orphaned rows behind.
+/// If `work` fails, its error is returned once everything has been rolled back.
+/// Failing to start or commit the transaction is turned into an error with `database_error`.
+pub fn in_transaction<T, E>(
+    connection: &PgConnection,
+    work: impl FnOnce() -> Result<T, E>,
+    database_error: impl FnOnce(diesel::result::Error) -> E,
+) -> Result<T, E> {
+    let mut work_error = None;
+
+    let result = connection.transaction::<T, diesel::result::Error, _>(|| {
+        work().map_err(|error| {
+            work_error = Some(error);
+            diesel::result::Error::RollbackTransaction
+        })
+    });
+
+    match (result, work_error) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(error)) => Err(error),
+        (Err(error), None) => Err(database_error(error)),
+    }
+}
+
+/// in_transaction() for route handlers. Failing to start or commit the transaction is logged and returned as a 500.
+pub fn transaction<T>(
+    connection: &PgConnection,
+    work: impl FnOnce() -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    in_transaction(connection, work, |error| {
+        error!("Failed to commit transaction! The error was {}", error);
+        ApiError {
+            error: "Failed to save changes",
+            code: "save_changes_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Queries taking longer than this many milliseconds are logged, set with slow_query_ms in [database].
+/// 0 turns the logging off. Defaults to 500.
+pub fn slow_query_ms() -> u128 {
+    settings().database.slow_query_ms as u128
+}
+
+/// The query's SQL with its bind parameters left as $1, $2 and so on, so logs never include what was queried for,
+/// such as tokens and password hashes.
+fn redacted_sql(query: &impl QueryFragment<Pg>) -> String {
+    let mut query_builder = PgQueryBuilder::default();
+
+    match query.to_sql(&mut query_builder) {
+        Ok(()) => query_builder.finish(),
+        Err(error) => format!("(couldn't build SQL: {})", error),
+    }
+}
+
+/// Runs `query` with `run`, e.g. `timed("camera_get", query, |query| query.get_result(connection))`.
+/// How long it took is recorded in the db_query_duration_seconds metric under `query_type`,
+/// and it's logged with its parameters redacted if it took longer than slow_query_ms().
+pub fn timed<Q: QueryFragment<Pg>, T>(
+    query_type: &'static str,
+    query: Q,
+    run: impl FnOnce(Q) -> QueryResult<T>,
+) -> QueryResult<T> {
+    // Built before running, since running takes the query. Diesel builds the same SQL to run it, so this is cheap
+    let sql = redacted_sql(&query);
+    let started_at = Instant::now();
+
+    let result = run(query);
+
+    let elapsed = started_at.elapsed();
+    metrics::record_query(query_type, elapsed.as_secs_f64());
+
+    if slow_query_ms() > 0 && elapsed.as_millis() > slow_query_ms() {
+        warn!(
+            "Slow {} query took {}ms: {}",
+            query_type,
+            elapsed.as_millis(),
+            sql
+        );
+    }
+
+    result
+}
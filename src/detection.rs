@@ -0,0 +1,285 @@
+use crate::{
+    api_error::ApiError,
+    camera::{list_camera_images, record_camera_contact, CameraId},
+    camera_tokens::CameraToken,
+    database::ReadDbConn,
+    device_format::{Device, DeviceBody},
+    event::get_users_event,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    zone::BoundingBox,
+    CameraServerDbConn,
+};
+
+use super::schema::detections;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Something a smart camera recognised in a frame, e.g. a person or a car.
+/// Detections belong to an event, an image, or both.
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "detections"]
+pub struct Detection {
+    pub detection_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub event_id: Option<i32>,
+    pub image_id: Option<i64>,
+    pub label: String,
+    pub confidence: f32,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "detections"]
+pub struct InsertableDetection {
+    pub camera_id: uuid::Uuid,
+    pub event_id: Option<i32>,
+    pub image_id: Option<i64>,
+    pub label: String,
+    pub confidence: f32,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// A detection as reported by a camera.
+#[derive(Clone, Deserialize, Serialize, JsonSchema)]
+pub struct ReportedDetection {
+    pub label: String,
+    pub confidence: f32,
+    pub bounding_box: BoundingBox,
+}
+
+impl InsertableDetection {
+    pub fn from_reported_detection(
+        camera_id: uuid::Uuid,
+        event_id: Option<i32>,
+        image_id: Option<i64>,
+        detection: ReportedDetection,
+    ) -> InsertableDetection {
+        InsertableDetection {
+            camera_id,
+            event_id,
+            image_id,
+            label: detection.label,
+            confidence: detection.confidence,
+            x: detection.bounding_box.x,
+            y: detection.bounding_box.y,
+            width: detection.bounding_box.width,
+            height: detection.bounding_box.height,
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Detection>> {
+    detections::table.load::<Detection>(&*connection)
+}
+
+pub fn get(detection_id: i32, connection: &PgConnection) -> QueryResult<Detection> {
+    detections::table
+        .find(detection_id)
+        .get_result::<Detection>(connection)
+}
+
+pub fn insert(
+    detections: Vec<InsertableDetection>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Detection>> {
+    diesel::insert_into(detections::table)
+        .values(&detections)
+        .get_results(connection)
+}
+
+pub fn update(
+    detection_id: i32,
+    detection: Detection,
+    connection: &PgConnection,
+) -> QueryResult<Detection> {
+    diesel::update(detections::table.find(detection_id))
+        .set(&detection)
+        .get_result(connection)
+}
+
+pub fn delete(detection_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(detections::table.find(detection_id)).execute(connection)
+}
+
+pub fn get_events_detections(
+    event_id: i32,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Detection>> {
+    detections::table
+        .filter(detections::event_id.eq(event_id))
+        .order(detections::confidence.desc())
+        .load::<Detection>(connection)
+}
+
+pub fn get_images_detections(
+    camera_id: uuid::Uuid,
+    image_id: i64,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Detection>> {
+    detections::table
+        .filter(detections::camera_id.eq(camera_id))
+        .filter(detections::image_id.eq(image_id))
+        .order(detections::confidence.desc())
+        .load::<Detection>(connection)
+}
+
+/// Checks that every detection has a label, a sensible confidence, and a bounding box inside the frame.
+pub fn validate_reported_detections(detections: &Vec<ReportedDetection>) -> Result<(), ApiError> {
+    for detection in detections {
+        if detection.label.trim().len() == 0 {
+            return Err(ApiError {
+                error: "Detection label can't be empty",
+                code: "detection_label_required",
+                status: Status::UnprocessableEntity,
+                field: Some("label"),
+            });
+        }
+
+        if !(0.0..=1.0).contains(&detection.confidence) {
+            return Err(ApiError {
+                error: "Confidence must be between 0 and 1",
+                code: "invalid_confidence",
+                status: Status::UnprocessableEntity,
+                field: Some("confidence"),
+            });
+        }
+
+        let bounding_box = &detection.bounding_box;
+
+        if bounding_box.x < 0.0
+            || bounding_box.y < 0.0
+            || bounding_box.width < 0.0
+            || bounding_box.height < 0.0
+            || bounding_box.x + bounding_box.width > 1.0
+            || bounding_box.y + bounding_box.height > 1.0
+        {
+            return Err(ApiError {
+                error: "Bounding box must be inside the frame",
+                code: "invalid_bounding_box",
+                status: Status::UnprocessableEntity,
+                field: Some("bounding_box"),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Attaches detections to an image that the camera has already uploaded.
+#[openapi]
+#[post("/Device/Images/<image_id>/Detections", data = "<reported_detections>")]
+pub fn report_image_detections(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    image_id: u64,
+    reported_detections: DeviceBody<Vec<ReportedDetection>>,
+) -> Result<Device<Vec<Detection>>, ApiError> {
+    let reported_detections = reported_detections.into_inner();
+
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    validate_reported_detections(&reported_detections)?;
+
+    if !list_camera_images(&camera_token.camera_id)?.contains(&image_id) {
+        return Err(ApiError {
+            error: "Image not found",
+            code: "image_not_found",
+            status: Status::NotFound,
+            field: None,
+        });
+    }
+
+    insert(
+        reported_detections
+            .into_iter()
+            .map(|detection| {
+                InsertableDetection::from_reported_detection(
+                    camera_token.camera_id,
+                    None,
+                    Some(image_id as i64),
+                    detection,
+                )
+            })
+            .collect(),
+        &conn,
+    )
+    .map(|detections| Device(detections))
+    .map_err(|error| {
+        error!(
+            "Failed to store detections for image {} from camera {}! The error was {}",
+            image_id, camera_token.camera_id, error
+        );
+        ApiError {
+            error: "Failed to store detections",
+            code: "store_detections_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>/Images/<image_id>/Detections")]
+pub fn get_image_detections(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    image_id: i64,
+) -> Result<Json<Vec<Detection>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    get_images_detections(camera_id, image_id, &conn)
+        .map(|detections| Json(detections))
+        .map_err(|error| {
+            error!(
+                "Failed to get detections for image {} from camera {}! The error was {}",
+                image_id, camera_id, error
+            );
+            ApiError {
+                error: "Failed to get detections",
+                code: "get_detections_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[get("/Events/<event_id>/Detections")]
+pub fn get_event_detections(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    event_id: i32,
+) -> Result<Json<Vec<Detection>>, ApiError> {
+    get_users_event(user_token.user_id, event_id, &conn)?;
+
+    get_events_detections(event_id, &conn)
+        .map(|detections| Json(detections))
+        .map_err(|error| {
+            error!(
+                "Failed to get detections for event {}! The error was {}",
+                event_id, error
+            );
+            ApiError {
+                error: "Failed to get detections",
+                code: "get_detections_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -0,0 +1,140 @@
+use rocket::data::{self, FromDataSimple};
+use rocket::http::{ContentType, Header, MediaType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::{Data, Outcome};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Cursor, Read};
+
+/// Device request bodies bigger than this are cut off, the same as Rocket's default limit for JSON.
+pub const MAX_DEVICE_BODY_BYTES: u64 = 1024 * 1024;
+
+/// The formats device endpoints speak. Cameras on microcontrollers often find CBOR or MessagePack
+/// easier to build and parse than JSON.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Format {
+    pub fn from_media_type(media_type: &MediaType) -> Option<Format> {
+        if media_type.top() != "application" {
+            return None;
+        }
+
+        match media_type.sub().as_str() {
+            "json" => Some(Format::Json),
+            "cbor" => Some(Format::Cbor),
+            "msgpack" | "x-msgpack" | "vnd.msgpack" => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Format::Json => ContentType::JSON,
+            Format::Cbor => ContentType::new("application", "cbor"),
+            Format::MessagePack => ContentType::MsgPack,
+        }
+    }
+}
+
+/// Picks the response format from the Accept header, falling back to whatever the request body was in,
+/// and then to JSON.
+pub fn response_format(request: &Request) -> Format {
+    request
+        .accept()
+        .and_then(|accept| Format::from_media_type(accept.preferred().media_type()))
+        .or_else(|| {
+            request
+                .content_type()
+                .and_then(|content_type| Format::from_media_type(content_type.media_type()))
+        })
+        .unwrap_or(Format::Json)
+}
+
+/// A request body from a camera, in JSON, CBOR or MessagePack depending on its Content-Type.
+/// Bodies without a Content-Type are read as JSON.
+pub struct DeviceBody<T>(pub T);
+
+impl<T> DeviceBody<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromDataSimple for DeviceBody<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let format = match request.content_type() {
+            Some(content_type) => match Format::from_media_type(content_type.media_type()) {
+                Some(format) => format,
+                None => {
+                    return Outcome::Failure((
+                        Status::UnsupportedMediaType,
+                        format!("Can't read a {} body", content_type),
+                    ))
+                }
+            },
+            None => Format::Json,
+        };
+
+        let mut body = Vec::new();
+
+        if let Err(error) = data
+            .open()
+            .take(MAX_DEVICE_BODY_BYTES)
+            .read_to_end(&mut body)
+        {
+            return Outcome::Failure((Status::BadRequest, error.to_string()));
+        }
+
+        let result = match format {
+            Format::Json => serde_json::from_slice(&body).map_err(|error| error.to_string()),
+            Format::Cbor => serde_cbor::from_slice(&body).map_err(|error| error.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(&body).map_err(|error| error.to_string()),
+        };
+
+        match result {
+            Ok(value) => Outcome::Success(DeviceBody(value)),
+            Err(error) => Outcome::Failure((Status::UnprocessableEntity, error)),
+        }
+    }
+}
+
+/// A response to a camera, in the format response_format() picks for the request.
+/// Errors are still sent as JSON.
+pub struct Device<T>(pub T);
+
+impl<'r, T: Serialize> Responder<'r> for Device<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let format = response_format(request);
+
+        let body = match format {
+            Format::Json => serde_json::to_vec(&self.0).map_err(|error| error.to_string()),
+            Format::Cbor => serde_cbor::to_vec(&self.0).map_err(|error| error.to_string()),
+            // Named, so objects are maps with the same keys as in JSON rather than arrays
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(&self.0).map_err(|error| error.to_string())
+            }
+        }
+        .map_err(|error| {
+            error!(
+                "Failed to serialize device response! The error was {}",
+                error
+            );
+            Status::InternalServerError
+        })?;
+
+        Response::build()
+            .header(format.content_type())
+            // The format depends on Accept, so caches have to key on it
+            .header(Header::new("Vary", "Accept"))
+            .sized_body(Cursor::new(body))
+            .ok()
+    }
+}
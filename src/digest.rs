@@ -0,0 +1,426 @@
+use crate::{
+    api_error::ApiError,
+    camera::{get_cameras_offline_periods, Camera},
+    email::EmailSender,
+    event::{severities_at_least, Event, WARNING_SEVERITY},
+    i18n::{self, text},
+    media_store::{media_store, MediaStore},
+    timezone::{self, display},
+    user_tokens::UserToken,
+    users_cameras::get_users_cameras,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{digest_settings, events};
+use chrono::{DateTime, Duration as ChronoDuration, Timelike, Utc};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::{self};
+use lettre::message::Mailbox;
+use rocket::http::Status;
+use rocket::{get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How many notable events are listed per camera in a digest.
+pub const MAX_NOTABLE_EVENTS: i64 = 5;
+
+/// Whether and where a user gets a daily summary email of their cameras.
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "digest_settings"]
+#[primary_key(user_id)]
+pub struct DigestSettings {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub enabled: bool,
+    pub recipient: String,
+    /// The hour of the day (UTC) the digest is sent at, 0 to 23.
+    pub send_hour: i16,
+    /// If true, the digest replaces real-time push and email notifications for anything less than critical.
+    /// Rules still notify as usual.
+    pub replaces_alerts: bool,
+    pub last_sent_at: Option<DateTime<Utc>>,
+}
+
+/// Digest settings as sent by the user.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct UpdatedDigestSettings {
+    pub enabled: bool,
+    pub recipient: String,
+    pub send_hour: i16,
+    pub replaces_alerts: bool,
+}
+
+pub fn get(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Option<DigestSettings>> {
+    digest_settings::table
+        .find(user_id)
+        .get_result::<DigestSettings>(connection)
+        .optional()
+}
+
+pub fn upsert(settings: DigestSettings, connection: &PgConnection) -> QueryResult<DigestSettings> {
+    diesel::insert_into(digest_settings::table)
+        .values(&settings)
+        .on_conflict(digest_settings::user_id)
+        .do_update()
+        .set((
+            digest_settings::enabled.eq(settings.enabled),
+            digest_settings::recipient.eq(&settings.recipient),
+            digest_settings::send_hour.eq(settings.send_hour),
+            digest_settings::replaces_alerts.eq(settings.replaces_alerts),
+        ))
+        .get_result(connection)
+}
+
+/// Whether the user gets a digest instead of real-time alerts for events that aren't critical.
+pub fn replaces_alerts(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<bool> {
+    Ok(get(user_id, connection)?
+        .map(|settings| settings.enabled && settings.replaces_alerts)
+        .unwrap_or(false))
+}
+
+/// Formats a number of bytes with the largest unit that keeps it above 1 (e.g. 1.5 GB).
+pub fn display_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Writes the section of the digest for one camera covering `from` to `to`, in `locale` with times in `timezone`.
+pub fn write_camera_summary(
+    digest: &mut String,
+    camera: &Camera,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    locale: &str,
+    timezone: Tz,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let event_types = events::table
+        .filter(events::camera_id.eq(camera.camera_id))
+        .filter(events::occurred_at.ge(from))
+        .filter(events::occurred_at.lt(to))
+        .select(events::event_type)
+        .load::<String>(connection)?;
+
+    let mut event_counts = BTreeMap::new();
+    for event_type in &event_types {
+        *event_counts.entry(event_type.as_str()).or_insert(0) += 1;
+    }
+
+    let notable_events = events::table
+        .filter(events::camera_id.eq(camera.camera_id))
+        .filter(events::occurred_at.ge(from))
+        .filter(events::occurred_at.lt(to))
+        .filter(events::severity.eq_any(severities_at_least(WARNING_SEVERITY)))
+        .order(events::occurred_at.desc())
+        .limit(MAX_NOTABLE_EVENTS)
+        .load::<Event>(connection)?;
+
+    let offline_periods = get_cameras_offline_periods(camera.camera_id, from, to, connection)?;
+
+    // Writing to a String can't fail
+    writeln!(digest, "{}", camera.name).unwrap();
+
+    if event_types.len() == 0 {
+        writeln!(
+            digest,
+            "  {}",
+            text(locale, "digest.no_events", "No events", &[])
+        )
+        .unwrap();
+    } else {
+        let count = event_types.len().to_string();
+        writeln!(
+            digest,
+            "  {}",
+            text(
+                locale,
+                "digest.events",
+                "{count} events",
+                &[("count", &count)]
+            )
+        )
+        .unwrap();
+        for (event_type, count) in event_counts {
+            writeln!(
+                digest,
+                "    {}: {}",
+                i18n::event_type(locale, event_type),
+                count
+            )
+            .unwrap();
+        }
+    }
+
+    if notable_events.len() > 0 {
+        writeln!(
+            digest,
+            "  {}",
+            text(locale, "digest.notable_events", "Notable events:", &[])
+        )
+        .unwrap();
+        for event in notable_events {
+            let (key, english) = if event.image_id.is_some() {
+                (
+                    "digest.notable_event_with_footage",
+                    "{event_type} at {time} ({severity}) with footage",
+                )
+            } else {
+                (
+                    "digest.notable_event",
+                    "{event_type} at {time} ({severity})",
+                )
+            };
+
+            writeln!(
+                digest,
+                "    {}",
+                text(
+                    locale,
+                    key,
+                    english,
+                    &[
+                        ("event_type", &i18n::event_type(locale, &event.event_type)),
+                        ("time", &display(event.occurred_at, timezone, "%H:%M %Z")),
+                        ("severity", &event.severity),
+                    ]
+                )
+            )
+            .unwrap();
+        }
+    }
+
+    for offline_period in offline_periods {
+        let from = display(offline_period.started_at, timezone, "%d %b %H:%M %Z");
+        let line = match offline_period.ended_at {
+            Some(ended_at) => text(
+                locale,
+                "digest.offline_period",
+                "Offline from {from} to {to}",
+                &[
+                    ("from", &from),
+                    ("to", &display(ended_at, timezone, "%d %b %H:%M %Z")),
+                ],
+            ),
+            None => text(
+                locale,
+                "digest.still_offline",
+                "Offline from {from} and still offline",
+                &[("from", &from)],
+            ),
+        };
+
+        writeln!(digest, "  {}", line).unwrap();
+    }
+
+    match media_store().storage_used(&camera.camera_id) {
+        Ok(bytes) => writeln!(
+            digest,
+            "  {}",
+            text(
+                locale,
+                "digest.storage_used",
+                "Storage used: {bytes}",
+                &[("bytes", &display_bytes(bytes))]
+            )
+        )
+        .unwrap(),
+        Err(error) => error!(
+            "Failed to get storage used by camera {}! The error was {}",
+            camera.camera_id, error
+        ),
+    }
+
+    writeln!(digest).unwrap();
+
+    Ok(())
+}
+
+/// Builds the digest covering the 24 hours before `to` for every camera the user has, in their language and
+/// timezone.
+pub fn build_digest(
+    user_id: uuid::Uuid,
+    to: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<String> {
+    let locale = i18n::user_locale(user_id, connection);
+    let timezone = timezone::user_timezone(user_id, connection);
+    let from = to - ChronoDuration::days(1);
+    let mut digest = text(
+        locale,
+        "digest.introduction",
+        "Here's what your cameras saw between {from} and {to}.",
+        &[
+            ("from", &display(from, timezone, "%d %b %H:%M %Z")),
+            ("to", &display(to, timezone, "%d %b %H:%M %Z")),
+        ],
+    );
+    digest.push_str("\n\n");
+
+    for camera in get_users_cameras(user_id, connection)? {
+        write_camera_summary(&mut digest, &camera, from, to, locale, timezone, connection)?;
+    }
+
+    Ok(digest)
+}
+
+/// Whether the digest is due: it's at or after the user's send hour, and it hasn't been sent since the last send hour.
+pub fn is_due(settings: &DigestSettings, now: DateTime<Utc>) -> bool {
+    let today_send_time = now.date().and_hms(settings.send_hour as u32, 0, 0);
+    let last_send_time = if now >= today_send_time {
+        today_send_time
+    } else {
+        today_send_time - ChronoDuration::days(1)
+    };
+
+    match settings.last_sent_at {
+        Some(last_sent_at) => last_sent_at < last_send_time,
+        None => now.hour() >= settings.send_hour as u32,
+    }
+}
+
+/// Sends every digest that is due. A digest that fails to send is tried again next time.
+pub fn send_due(email_sender: &EmailSender, connection: &PgConnection) -> QueryResult<()> {
+    let now = Utc::now();
+
+    let due_settings = digest_settings::table
+        .filter(digest_settings::enabled.eq(true))
+        .load::<DigestSettings>(connection)?
+        .into_iter()
+        .filter(|settings| is_due(settings, now));
+
+    for settings in due_settings {
+        let digest = build_digest(settings.user_id, now, connection)?;
+        let subject = text(
+            i18n::user_locale(settings.user_id, connection),
+            "digest.subject",
+            "Your daily camera digest",
+            &[],
+        );
+
+        match email_sender.send_email(&settings.recipient, &subject, digest) {
+            Ok(()) => {
+                diesel::update(digest_settings::table.find(settings.user_id))
+                    .set(digest_settings::last_sent_at.eq(now))
+                    .execute(connection)?;
+            }
+            Err(error) => error!(
+                "Failed to send digest to user {}! The error was {}",
+                settings.user_id, error
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that sends daily digests, if email is configured.
+pub fn spawn_digest_worker(database_url: String) {
+    let email_sender = match EmailSender::from_env() {
+        Some(email_sender) => email_sender,
+        None => return,
+    };
+
+    worker::spawn_worker(
+        "Digest",
+        Duration::from_secs(5 * 60),
+        database_url,
+        move |connection| {
+            if let Err(error) = send_due(&email_sender, connection) {
+                error!("Failed to send digests! The error was {}", error);
+            }
+        },
+    );
+}
+
+#[openapi]
+#[get("/DigestSettings")]
+pub fn get_digest_settings(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Option<DigestSettings>>, ApiError> {
+    get(user_token.user_id, &conn)
+        .map(|settings| Json(settings))
+        .map_err(|error| {
+            error!(
+                "Failed to get digest settings for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get digest settings",
+                code: "get_digest_settings_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[put("/DigestSettings", data = "<updated_settings>", format = "json")]
+pub fn update_digest_settings(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    updated_settings: Json<UpdatedDigestSettings>,
+) -> Result<Json<DigestSettings>, ApiError> {
+    let updated_settings = updated_settings.into_inner();
+
+    if updated_settings.recipient.parse::<Mailbox>().is_err() {
+        return Err(ApiError {
+            error: "Invalid recipient email address",
+            code: "invalid_email_address",
+            status: Status::UnprocessableEntity,
+            field: Some("recipient"),
+        });
+    }
+
+    if !(0..=23).contains(&updated_settings.send_hour) {
+        return Err(ApiError {
+            error: "Send hour must be between 0 and 23",
+            code: "invalid_send_hour",
+            status: Status::UnprocessableEntity,
+            field: Some("send_hour"),
+        });
+    }
+
+    upsert(
+        DigestSettings {
+            user_id: user_token.user_id,
+            enabled: updated_settings.enabled,
+            recipient: updated_settings.recipient,
+            send_hour: updated_settings.send_hour,
+            replaces_alerts: updated_settings.replaces_alerts,
+            last_sent_at: None,
+        },
+        &conn,
+    )
+    .map(|settings| Json(settings))
+    .map_err(|error| {
+        error!(
+            "Failed to update digest settings for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to update digest settings",
+            code: "update_digest_settings_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
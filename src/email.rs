@@ -0,0 +1,356 @@
+use crate::{
+    api_error::ApiError,
+    camera::CameraId,
+    event::{self, meets_severity, validate_severity, Event, EVENT_TYPES, INFO_SEVERITY},
+    media_store::{media_store, MediaStore},
+    notification::Notification,
+    settings::settings,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::email_alerts;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use lettre::message::{header::ContentType, Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rocket::http::Status;
+use rocket::{get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Who gets emailed about a camera's events, and how often.
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "email_alerts"]
+#[primary_key(user_id, camera_id)]
+pub struct EmailAlert {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub recipients: Vec<String>,
+    pub event_types: Vec<String>,
+    /// Whether to attach the event's image, if it has one.
+    pub attach_snapshot: bool,
+    /// At most one email is sent per this many minutes, so a windy night doesn't send hundreds of emails.
+    pub throttle_minutes: i32,
+    pub last_alerted_at: Option<DateTime<Utc>>,
+    /// Only events at least this severe are emailed.
+    pub min_severity: String,
+}
+
+/// Email alert settings as sent by the user.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct UpdatedEmailAlert {
+    pub recipients: Vec<String>,
+    pub event_types: Vec<String>,
+    pub attach_snapshot: bool,
+    pub throttle_minutes: i32,
+    /// Defaults to info, i.e. every event.
+    pub min_severity: Option<String>,
+}
+
+pub fn get(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<EmailAlert>> {
+    email_alerts::table
+        .find((user_id, camera_id))
+        .get_result::<EmailAlert>(connection)
+        .optional()
+}
+
+pub fn upsert(email_alert: EmailAlert, connection: &PgConnection) -> QueryResult<EmailAlert> {
+    diesel::insert_into(email_alerts::table)
+        .values(&email_alert)
+        .on_conflict((email_alerts::user_id, email_alerts::camera_id))
+        .do_update()
+        .set((
+            email_alerts::recipients.eq(&email_alert.recipients),
+            email_alerts::event_types.eq(&email_alert.event_types),
+            email_alerts::attach_snapshot.eq(email_alert.attach_snapshot),
+            email_alerts::throttle_minutes.eq(email_alert.throttle_minutes),
+            email_alerts::min_severity.eq(&email_alert.min_severity),
+        ))
+        .get_result(connection)
+}
+
+pub fn delete(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(email_alerts::table.find((user_id, camera_id))).execute(connection)
+}
+
+/// Checks whether an email should be sent for the event, and if so records that one is being sent
+/// so that the throttle applies to the next event. Unthrottled events are sent even during the throttle.
+pub fn should_alert(
+    email_alert: &EmailAlert,
+    event: &Event,
+    throttled: bool,
+    connection: &PgConnection,
+) -> QueryResult<bool> {
+    if !email_alert.event_types.contains(&event.event_type)
+        || !meets_severity(&event.severity, &email_alert.min_severity)
+    {
+        return Ok(false);
+    }
+
+    let now = Utc::now();
+
+    if let Some(last_alerted_at) = email_alert.last_alerted_at.filter(|_| throttled) {
+        if now - last_alerted_at < Duration::minutes(email_alert.throttle_minutes as i64) {
+            return Ok(false);
+        }
+    }
+
+    diesel::update(email_alerts::table.find((email_alert.user_id, email_alert.camera_id)))
+        .set(email_alerts::last_alerted_at.eq(now))
+        .execute(connection)?;
+
+    Ok(true)
+}
+
+/// Sends alert emails over SMTP. Configured with [smtp] in the settings.
+pub struct EmailSender {
+    pub transport: SmtpTransport,
+    pub from: Mailbox,
+}
+
+impl EmailSender {
+    /// Returns None if host in [smtp] isn't set, in which case email alerts are never sent.
+    /// from is checked when the settings are loaded.
+    pub fn from_env() -> Option<EmailSender> {
+        let smtp = &settings().smtp;
+        let host = smtp.host.as_ref()?;
+
+        let mut transport = SmtpTransport::relay(host)
+            .expect("Failed to set up SMTP transport for host in [smtp]!");
+
+        if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Some(EmailSender {
+            transport: transport.build(),
+            from: smtp
+                .from
+                .as_ref()
+                .expect("from in [smtp] must be set when host is set!")
+                .parse()
+                .expect("from in [smtp] is not a valid email address!"),
+        })
+    }
+
+    /// Reads the image attached to the notification's event, if it has one.
+    fn read_snapshot(notification: &Notification, connection: &PgConnection) -> Option<Vec<u8>> {
+        let event = event::get(notification.event_id?, connection).ok()?;
+        let mut image = media_store()
+            .open_image(&event.camera_id, event.image_id? as u64)
+            .ok()?;
+
+        let mut image_bytes = Vec::new();
+        image.read_to_end(&mut image_bytes).ok()?;
+
+        Some(image_bytes)
+    }
+
+    /// Sends a plain text email to a single recipient.
+    pub fn send_email(&self, recipient: &str, subject: &str, body: String) -> Result<(), String> {
+        let email = Message::builder()
+            .from(self.from.clone())
+            .to(recipient
+                .parse::<Mailbox>()
+                .map_err(|error| format!("Invalid recipient {}: {}", recipient, error))?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|error| format!("Failed to build email: {}", error))?;
+
+        self.transport
+            .send(&email)
+            .map(|_| ())
+            .map_err(|error| format!("Failed to send email to {}: {}", recipient, error))
+    }
+
+    /// Emails the notification to everyone in the user's email alert for the camera.
+    pub fn send_alert(
+        &self,
+        notification: &Notification,
+        connection: &PgConnection,
+    ) -> Result<(), String> {
+        let email_alert = match get(notification.user_id, notification.camera_id, connection)
+            .map_err(|error| format!("Failed to get email alert: {}", error))?
+        {
+            Some(email_alert) => email_alert,
+            // The user turned email alerts off after this was queued
+            None => return Ok(()),
+        };
+
+        let mut body = MultiPart::mixed().singlepart(
+            SinglePart::builder()
+                .header(ContentType::TEXT_PLAIN)
+                .body(notification.body.clone()),
+        );
+
+        if email_alert.attach_snapshot {
+            if let Some(snapshot) = EmailSender::read_snapshot(notification, connection) {
+                body = body.singlepart(Attachment::new(String::from("snapshot.jpg")).body(
+                    snapshot,
+                    ContentType::parse("image/jpeg").expect("image/jpeg is a valid content type"),
+                ));
+            }
+        }
+
+        for recipient in &email_alert.recipients {
+            let email = Message::builder()
+                .from(self.from.clone())
+                .to(recipient
+                    .parse::<Mailbox>()
+                    .map_err(|error| format!("Invalid recipient {}: {}", recipient, error))?)
+                .subject(notification.title.clone())
+                .multipart(body.clone())
+                .map_err(|error| format!("Failed to build email: {}", error))?;
+
+            self.transport
+                .send(&email)
+                .map_err(|error| format!("Failed to send email to {}: {}", recipient, error))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn validate_email_alert(email_alert: &UpdatedEmailAlert) -> Result<(), ApiError> {
+    if email_alert
+        .recipients
+        .iter()
+        .any(|recipient| recipient.parse::<Mailbox>().is_err())
+    {
+        return Err(ApiError {
+            error: "Invalid recipient email address",
+            code: "invalid_email_address",
+            status: Status::UnprocessableEntity,
+            field: Some("recipient"),
+        });
+    }
+
+    if email_alert
+        .event_types
+        .iter()
+        .any(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        return Err(ApiError {
+            error: "Unknown event type",
+            code: "unknown_event_type",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    if let Some(min_severity) = &email_alert.min_severity {
+        validate_severity(min_severity)?;
+    }
+
+    if email_alert.throttle_minutes < 0 {
+        return Err(ApiError {
+            error: "Throttle can't be negative",
+            code: "invalid_throttle",
+            status: Status::UnprocessableEntity,
+            field: Some("throttle_minutes"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the user's email alert settings for the camera, or null if they haven't set any up.
+#[openapi]
+#[get("/Cameras/<camera_id>/EmailAlerts")]
+pub fn get_email_alerts(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Option<EmailAlert>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    get(user_token.user_id, camera_id, &conn)
+        .map(|email_alert| Json(email_alert))
+        .map_err(|error| {
+            error!(
+                "Failed to get email alerts for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get email alerts",
+                code: "get_email_alerts_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Sets up email alerts for the camera. Sending an empty recipient list turns them off.
+#[openapi]
+#[put(
+    "/Cameras/<camera_id>/EmailAlerts",
+    data = "<updated_email_alert>",
+    format = "json"
+)]
+pub fn update_email_alerts(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    updated_email_alert: Json<UpdatedEmailAlert>,
+) -> Result<Json<Option<EmailAlert>>, ApiError> {
+    let updated_email_alert = updated_email_alert.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    validate_email_alert(&updated_email_alert)?;
+
+    let result = if updated_email_alert.recipients.len() == 0 {
+        delete(user_token.user_id, camera_id, &conn).map(|_| None)
+    } else {
+        upsert(
+            EmailAlert {
+                user_id: user_token.user_id,
+                camera_id,
+                recipients: updated_email_alert.recipients,
+                event_types: updated_email_alert.event_types,
+                attach_snapshot: updated_email_alert.attach_snapshot,
+                throttle_minutes: updated_email_alert.throttle_minutes,
+                last_alerted_at: None,
+                min_severity: updated_email_alert
+                    .min_severity
+                    .unwrap_or_else(|| INFO_SEVERITY.to_string()),
+            },
+            &conn,
+        )
+        .map(Some)
+    };
+
+    result
+        .map(|email_alert| Json(email_alert))
+        .map_err(|error| {
+            error!(
+                "Failed to update email alerts for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to update email alerts",
+                code: "update_email_alerts_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
@@ -1,6 +1,77 @@
+use crate::api_error;
+
+use rocket::http::Status;
+use rocket::{request, Outcome, Request};
+
 #[derive(Debug)]
 pub enum TokenError {
     ParseError,
     NotFound,
     NoTokenProvided,
+    NotAdmin,
+    /// An impersonation token was used for something other than reading.
+    ReadOnly,
+    /// The user, or the camera's owner, is suspended.
+    Suspended,
+}
+
+/// Why a token was turned away by the checks every transport makes, see user_tokens::authorise_user_token() and
+/// camera_tokens::authorise_camera_token().
+#[derive(Debug)]
+pub struct TokenRejection {
+    pub status: Status,
+    pub error: TokenError,
+    /// The error code and message, when they say more than the status does.
+    pub reason: Option<(&'static str, &'static str)>,
+}
+
+impl TokenRejection {
+    pub fn new(status: Status, error: TokenError) -> TokenRejection {
+        TokenRejection {
+            status,
+            error,
+            reason: None,
+        }
+    }
+
+    pub fn because(
+        status: Status,
+        error: TokenError,
+        code: &'static str,
+        message: &'static str,
+    ) -> TokenRejection {
+        TokenRejection {
+            status,
+            error,
+            reason: Some((code, message)),
+        }
+    }
+
+    /// What to tell clients that don't get an error body, like gRPC and CoAP ones.
+    pub fn message(&self) -> &'static str {
+        match (self.reason, &self.error) {
+            (Some((_, message)), _) => message,
+            (None, TokenError::ReadOnly) => "Impersonation tokens can only read",
+            (None, TokenError::Suspended) => "Account suspended",
+            (None, _) if self.status == Status::ServiceUnavailable => "Failed to check token",
+            (None, _) => "Invalid token",
+        }
+    }
+
+    /// The error code to answer with, see api_error::ErrorBody::code.
+    pub fn code(&self) -> &'static str {
+        match self.reason {
+            Some((code, _)) => code,
+            None => api_error::error_code(self.status),
+        }
+    }
+
+    /// Fails a request guard with the rejection, so the catcher answers with its reason, see
+    /// api_error::record_guard_failure().
+    pub fn fail<S>(self, request: &Request) -> request::Outcome<S, TokenError> {
+        if let Some((code, message)) = self.reason {
+            api_error::record_guard_failure(request, code, message);
+        }
+        Outcome::Failure((self.status, self.error))
+    }
 }
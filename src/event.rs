@@ -0,0 +1,842 @@
+use crate::{
+    acknowledgement::{get_events_acknowledgements, Acknowledgement},
+    api_error::ApiError,
+    audio::get_cameras_audio_clip,
+    camera::record_camera_contact,
+    camera_tokens::CameraToken,
+    database::{self, ReadDbConn},
+    detection::{
+        self, get_events_detections, validate_reported_detections, Detection, InsertableDetection,
+        ReportedDetection,
+    },
+    device_format::{Device, DeviceBody},
+    event_media,
+    fields::{parse_fields, Sparse},
+    ingest_batch,
+    media_store::{media_store, MediaStore},
+    mqtt, notification,
+    page::{offset_and_limit, parse_updated_since, Page},
+    realtime,
+    user_tokens::UserToken,
+    users_cameras::in_owners_tenant,
+    webhook,
+    zone::{is_in_zones, load_zones, BoundingBox},
+    CameraServerDbConn,
+};
+
+use super::schema::{detections, event_acknowledgements, events, users_cameras};
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use diesel::{self};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const GLASS_BREAK_EVENT_TYPE: &str = "glass_break";
+pub const SMOKE_ALARM_EVENT_TYPE: &str = "smoke_alarm";
+pub const LOUD_NOISE_EVENT_TYPE: &str = "loud_noise";
+
+/// Event types raised by a camera's microphone rather than its image. These usually come with an audio clip.
+pub const AUDIO_EVENT_TYPES: [&str; 3] = [
+    GLASS_BREAK_EVENT_TYPE,
+    SMOKE_ALARM_EVENT_TYPE,
+    LOUD_NOISE_EVENT_TYPE,
+];
+
+/// Reported when someone interferes with the camera itself. Tamper events come with one of TAMPER_REASONS.
+pub const TAMPER_EVENT_TYPE: &str = "tamper";
+
+/// The camera was moved or knocked, covered up, or restarted when it wasn't asked to.
+pub const TAMPER_REASONS: [&str; 3] = ["moved", "covered", "reboot"];
+
+/// Every event type a camera is allowed to report.
+pub const REPORTED_EVENT_TYPES: [&str; 7] = [
+    "motion",
+    "person",
+    "doorbell",
+    GLASS_BREAK_EVENT_TYPE,
+    SMOKE_ALARM_EVENT_TYPE,
+    LOUD_NOISE_EVENT_TYPE,
+    TAMPER_EVENT_TYPE,
+];
+
+/// Raised by the server when a camera is much busier than usual for the time of day, see anomaly.rs.
+pub const ANOMALY_EVENT_TYPE: &str = "anomaly";
+
+/// Raised by the server when a camera's RTMP or SRT stream keeps stalling, see ingest_watchdog.rs.
+pub const STREAM_FLAPPING_EVENT_TYPE: &str = "stream_flapping";
+
+/// Every event type, including the ones the server raises itself. Users can be notified about any of these.
+pub const EVENT_TYPES: [&str; 9] = [
+    "motion",
+    "person",
+    "doorbell",
+    GLASS_BREAK_EVENT_TYPE,
+    SMOKE_ALARM_EVENT_TYPE,
+    LOUD_NOISE_EVENT_TYPE,
+    TAMPER_EVENT_TYPE,
+    ANOMALY_EVENT_TYPE,
+    STREAM_FLAPPING_EVENT_TYPE,
+];
+
+pub const INFO_SEVERITY: &str = "info";
+pub const WARNING_SEVERITY: &str = "warning";
+pub const CRITICAL_SEVERITY: &str = "critical";
+
+/// Every severity an event can have, least severe first.
+pub const SEVERITIES: [&str; 3] = [INFO_SEVERITY, WARNING_SEVERITY, CRITICAL_SEVERITY];
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "events"]
+pub struct Event {
+    pub event_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    pub severity: String,
+    /// When the event was stripped of its detections, images and acknowledgements by the retention job.
+    /// Events are only anonymised instead of deleted if they are on hold.
+    pub anonymised_at: Option<DateTime<Utc>>,
+    /// The audio clip that triggered the event, see GET /Cameras/<camera_id>/Audio/<audio_id>.
+    pub audio_id: Option<i32>,
+    /// One of TAMPER_REASONS for tamper events, None for everything else.
+    pub tamper_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Kept up to date by a trigger. Pass it as ?updated_since= to only get what has changed since.
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Insertable, Deserialize, Serialize)]
+#[table_name = "events"]
+pub struct InsertableEvent {
+    pub camera_id: uuid::Uuid,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    pub severity: String,
+    pub audio_id: Option<i32>,
+    pub tamper_reason: Option<String>,
+}
+
+/// An event as reported by a camera. The camera ID comes from the camera token rather than the body.
+/// If the camera knows where in the frame the event happened, it can send a bounding box so that the camera's zones are applied.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ReportedEvent {
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    /// An audio clip uploaded to POST /Device/Audio, for audio events.
+    pub audio_id: Option<i32>,
+    /// Required for tamper events: moved, covered or reboot.
+    pub tamper_reason: Option<String>,
+    /// Defaults to default_severity() for the event type.
+    pub severity: Option<String>,
+    pub bounding_box: Option<BoundingBox>,
+    /// What a smart camera recognised while the event was happening. Stored attached to the event.
+    #[serde(default)]
+    pub detections: Vec<ReportedDetection>,
+}
+
+/// An event along with everything attached to it. Returned by GET /Events/<event_id>.
+#[derive(Serialize, JsonSchema)]
+pub struct EventDetails {
+    #[serde(flatten)]
+    pub event: Event,
+    /// Images the camera took around the time of the event, oldest first.
+    pub image_ids: Vec<String>,
+    pub detections: Vec<Detection>,
+    /// Everyone who has acknowledged the event, earliest first.
+    pub acknowledgements: Vec<Acknowledgement>,
+}
+
+/// Query string accepted by GET /Events. Everything is optional, and is parsed into an EventFilter.
+#[derive(FromForm, JsonSchema)]
+pub struct EventQuery {
+    pub camera_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[form(field = "type")]
+    #[schemars(rename = "type")]
+    pub event_type: Option<String>,
+    pub label: Option<String>,
+    pub min_confidence: Option<f32>,
+    pub min_severity: Option<String>,
+    pub unread: Option<bool>,
+    /// Free text, matched against event types, severities, camera names and detection labels.
+    pub q: Option<String>,
+    /// Only events that have changed since this RFC 3339 timestamp, for syncing incrementally.
+    pub updated_since: Option<String>,
+    /// How GET /Events/Search groups events by time: hour, day, week or month. Defaults to day.
+    pub bucket: Option<String>,
+    /// The next_cursor from the previous page.
+    pub cursor: Option<String>,
+    /// Deprecated, use cursor instead.
+    pub page: Option<i64>,
+    pub page_size: Option<i64>,
+    /// Comma separated fields to return for each event, e.g. event_id,event_type. Defaults to every field.
+    pub fields: Option<String>,
+}
+
+/// Narrows down which events get_users_events() returns. None means "don't filter on this".
+#[derive(Default)]
+pub struct EventFilter {
+    pub camera_id: Option<uuid::Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub event_type: Option<String>,
+    /// Only events with a detection with this label. min_confidence then applies to that detection.
+    pub label: Option<String>,
+    /// Without a label, only events the camera was at least this confident about.
+    pub min_confidence: Option<f32>,
+    pub min_severity: Option<String>,
+    /// Only events the user hasn't acknowledged.
+    pub unread: bool,
+    /// Full-text search query.
+    pub text: Option<String>,
+    pub updated_since: Option<DateTime<Utc>>,
+}
+
+impl InsertableEvent {
+    pub fn from_event(event: Event) -> InsertableEvent {
+        InsertableEvent {
+            camera_id: event.camera_id,
+            event_type: event.event_type,
+            occurred_at: event.occurred_at,
+            confidence: event.confidence,
+            image_id: event.image_id,
+            severity: event.severity,
+            audio_id: event.audio_id,
+            tamper_reason: event.tamper_reason,
+        }
+    }
+
+    pub fn from_reported_event(camera_id: uuid::Uuid, event: ReportedEvent) -> InsertableEvent {
+        InsertableEvent {
+            camera_id,
+            severity: event
+                .severity
+                .unwrap_or_else(|| default_severity(&event.event_type).to_string()),
+            event_type: event.event_type,
+            occurred_at: event.occurred_at,
+            confidence: event.confidence,
+            image_id: event.image_id,
+            audio_id: event.audio_id,
+            tamper_reason: event.tamper_reason,
+        }
+    }
+}
+
+/// The severity used for events that don't come with one.
+pub fn default_severity(event_type: &str) -> &'static str {
+    match event_type {
+        GLASS_BREAK_EVENT_TYPE | SMOKE_ALARM_EVENT_TYPE | TAMPER_EVENT_TYPE => CRITICAL_SEVERITY,
+        "person"
+        | "doorbell"
+        | LOUD_NOISE_EVENT_TYPE
+        | ANOMALY_EVENT_TYPE
+        | STREAM_FLAPPING_EVENT_TYPE => WARNING_SEVERITY,
+        _ => INFO_SEVERITY,
+    }
+}
+
+/// Returns the severities that are at least as bad as min_severity. Unknown severities are treated as info.
+pub fn severities_at_least(min_severity: &str) -> Vec<String> {
+    let min_rank = SEVERITIES
+        .iter()
+        .position(|severity| *severity == min_severity)
+        .unwrap_or(0);
+
+    SEVERITIES[min_rank..]
+        .iter()
+        .map(|severity| severity.to_string())
+        .collect()
+}
+
+/// Returns the severities that are no worse than max_severity, i.e. the minimum severities a channel can ask for
+/// and still hear about an event this severe.
+pub fn severities_at_most(max_severity: &str) -> Vec<String> {
+    let max_rank = SEVERITIES
+        .iter()
+        .position(|severity| *severity == max_severity)
+        .unwrap_or(0);
+
+    SEVERITIES[..=max_rank]
+        .iter()
+        .map(|severity| severity.to_string())
+        .collect()
+}
+
+/// Whether an event with the given severity should get through a channel that only wants min_severity and above.
+pub fn meets_severity(severity: &str, min_severity: &str) -> bool {
+    severities_at_least(min_severity)
+        .iter()
+        .any(|allowed| allowed == severity)
+}
+
+pub fn validate_severity(severity: &str) -> Result<(), ApiError> {
+    if !SEVERITIES.contains(&severity) {
+        return Err(ApiError {
+            error: "Severity must be info, warning or critical",
+            code: "invalid_severity",
+            status: Status::UnprocessableEntity,
+            field: Some("severity"),
+        });
+    }
+
+    Ok(())
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Event>> {
+    events::table.load::<Event>(&*connection)
+}
+
+pub fn get(event_id: i32, connection: &PgConnection) -> QueryResult<Event> {
+    events::table.find(event_id).get_result::<Event>(connection)
+}
+
+pub fn insert(event: InsertableEvent, connection: &PgConnection) -> QueryResult<Event> {
+    diesel::insert_into(events::table)
+        .values(event)
+        .get_result(connection)
+}
+
+/// Stores the events with one INSERT and all their detections with another, in one transaction,
+/// so an event is never left without its detections. Returns the stored events in the same order.
+pub fn insert_with_detections(
+    events: Vec<(InsertableEvent, Vec<ReportedDetection>)>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Event>> {
+    let (insertable_events, reported_detections): (Vec<_>, Vec<_>) = events.into_iter().unzip();
+
+    connection.transaction(|| {
+        // Postgres returns the rows of a multi-row INSERT in the order they were given
+        let stored_events = database::timed(
+            "events_insert",
+            diesel::insert_into(events::table).values(&insertable_events),
+            |query| query.get_results::<Event>(connection),
+        )?;
+
+        let detections = stored_events
+            .iter()
+            .zip(reported_detections)
+            .flat_map(|(event, detections)| {
+                let (camera_id, event_id, image_id) =
+                    (event.camera_id, event.event_id, event.image_id);
+
+                detections.into_iter().map(move |detection| {
+                    InsertableDetection::from_reported_detection(
+                        camera_id,
+                        Some(event_id),
+                        image_id,
+                        detection,
+                    )
+                })
+            })
+            .collect::<Vec<InsertableDetection>>();
+
+        if detections.len() > 0 {
+            detection::insert(detections, connection)?;
+        }
+
+        Ok(stored_events)
+    })
+}
+
+pub fn update(event_id: i32, event: Event, connection: &PgConnection) -> QueryResult<Event> {
+    diesel::update(events::table.find(event_id))
+        .set(&event)
+        .get_result(connection)
+}
+
+pub fn delete(event_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(events::table.find(event_id)).execute(connection)
+}
+
+/// Builds a query for the events from every camera the user has access to that get through the filter.
+/// Shared by the timeline and search so that they always filter the same way.
+pub fn users_events_query<'a>(
+    user_id: uuid::Uuid,
+    filter: &EventFilter,
+) -> events::BoxedQuery<'a, Pg> {
+    let mut query = filter_events(
+        events::table
+            .filter(
+                events::camera_id.eq_any(
+                    users_cameras::table
+                        .filter(users_cameras::user_id.eq(user_id))
+                        .filter(users_cameras::deleted_at.is_null())
+                        .filter(in_owners_tenant())
+                        .select(users_cameras::camera_id),
+                ),
+            )
+            .into_boxed(),
+        filter,
+    );
+
+    if filter.unread {
+        query = query.filter(
+            events::event_id.ne_all(
+                event_acknowledgements::table
+                    .filter(event_acknowledgements::user_id.eq(user_id))
+                    .select(event_acknowledgements::event_id),
+            ),
+        );
+    }
+
+    query
+}
+
+/// Narrows `query` down to the events that get through the filter, apart from unread, which depends on the user.
+pub fn filter_events<'a>(
+    mut query: events::BoxedQuery<'a, Pg>,
+    filter: &EventFilter,
+) -> events::BoxedQuery<'a, Pg> {
+    if let Some(camera_id) = filter.camera_id {
+        query = query.filter(events::camera_id.eq(camera_id));
+    }
+
+    if let Some(from) = filter.from {
+        query = query.filter(events::occurred_at.ge(from));
+    }
+
+    if let Some(to) = filter.to {
+        query = query.filter(events::occurred_at.le(to));
+    }
+
+    if let Some(event_type) = &filter.event_type {
+        query = query.filter(events::event_type.eq(event_type.clone()));
+    }
+
+    if let Some(updated_since) = filter.updated_since {
+        query = query.filter(events::updated_at.gt(updated_since));
+    }
+
+    match (&filter.label, filter.min_confidence) {
+        (Some(label), min_confidence) => {
+            query = query.filter(
+                events::event_id.nullable().eq_any(
+                    detections::table
+                        .filter(detections::label.eq(label.clone()))
+                        .filter(detections::confidence.ge(min_confidence.unwrap_or(0.0)))
+                        .select(detections::event_id),
+                ),
+            );
+        }
+        (None, Some(min_confidence)) => {
+            query = query.filter(events::confidence.ge(min_confidence));
+        }
+        (None, None) => {}
+    }
+
+    if let Some(min_severity) = &filter.min_severity {
+        query = query.filter(events::severity.eq_any(severities_at_least(min_severity)));
+    }
+
+    // search_vector is kept up to date by triggers, see the event_search migration
+    if let Some(text) = &filter.text {
+        query = query.filter(
+            sql::<Bool>("events.search_vector @@ plainto_tsquery('english', ")
+                .bind::<Text, _>(text.clone())
+                .sql(")"),
+        );
+    }
+
+    query
+}
+
+/// Returns a page of events from every camera the user has access to, newest first.
+pub fn get_users_events(
+    user_id: uuid::Uuid,
+    filter: &EventFilter,
+    offset: i64,
+    limit: i64,
+    connection: &PgConnection,
+) -> QueryResult<Page<Event>> {
+    let events = database::timed(
+        "users_events_list",
+        users_events_query(user_id, filter)
+            .order((events::occurred_at.desc(), events::event_id.desc()))
+            .limit(limit)
+            .offset(offset),
+        |query| query.load::<Event>(connection),
+    )?;
+
+    let total = database::timed(
+        "users_events_count",
+        users_events_query(user_id, filter).count(),
+        |query| query.get_result::<i64>(connection),
+    )?;
+
+    Ok(Page::new(events, offset, total))
+}
+
+/// Returns the given event, but only if it's from one of the user's cameras.
+pub fn get_users_event(
+    user_id: uuid::Uuid,
+    event_id: i32,
+    connection: &PgConnection,
+) -> Result<Event, ApiError> {
+    events::table
+        .inner_join(users_cameras::table.on(users_cameras::camera_id.eq(events::camera_id)))
+        .filter(users_cameras::user_id.eq(user_id))
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .filter(events::event_id.eq(event_id))
+        .select(events::all_columns)
+        .first::<Event>(connection)
+        .map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Event not found",
+                code: "event_not_found",
+                status: Status::NotFound,
+                field: None,
+            },
+            _ => {
+                error!("Failed to get event {}! The error was {}", event_id, error);
+                ApiError {
+                    error: "Failed to get event",
+                    code: "get_event_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            }
+        })
+}
+
+/// Parses an RFC 3339 timestamp from a query string.
+pub fn parse_timestamp(timestamp_string: &String) -> Result<DateTime<Utc>, ApiError> {
+    DateTime::parse_from_rfc3339(timestamp_string)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|error| {
+            warn!(
+                "Failed to parse timestamp: Input was {}, error was {}",
+                timestamp_string, error
+            );
+            ApiError {
+                error: "Failed to parse timestamp, timestamps must be RFC 3339",
+                code: "invalid_timestamp",
+                status: Status::UnprocessableEntity,
+                field: None,
+            }
+        })
+}
+
+impl EventQuery {
+    pub fn offset_and_limit(&self) -> Result<(i64, i64), ApiError> {
+        offset_and_limit(&self.cursor, self.page, self.page_size)
+    }
+
+    pub fn to_filter(&self) -> Result<EventFilter, ApiError> {
+        Ok(EventFilter {
+            camera_id: match &self.camera_id {
+                Some(camera_id_string) => Some(crate::camera::parse_camera_id(camera_id_string)?),
+                None => None,
+            },
+            from: match &self.from {
+                Some(from) => Some(parse_timestamp(from)?),
+                None => None,
+            },
+            to: match &self.to {
+                Some(to) => Some(parse_timestamp(to)?),
+                None => None,
+            },
+            event_type: self.event_type.clone(),
+            label: self.label.clone(),
+            min_confidence: self.min_confidence,
+            min_severity: match &self.min_severity {
+                Some(min_severity) => {
+                    validate_severity(min_severity)?;
+                    Some(min_severity.clone())
+                }
+                None => None,
+            },
+            unread: self.unread.unwrap_or(false),
+            text: self.q.clone().filter(|text| text.trim().len() > 0),
+            updated_since: parse_updated_since(&self.updated_since)?,
+        })
+    }
+}
+
+/// Checks that a reported event makes sense before it gets stored.
+/// Returns an empty Ok() if it does, returns ApiError describing the first problem found if it doesn't.
+pub fn validate_reported_event(
+    camera_id: &uuid::Uuid,
+    event: &ReportedEvent,
+) -> Result<(), ApiError> {
+    if !REPORTED_EVENT_TYPES.contains(&event.event_type.as_str()) {
+        return Err(ApiError {
+            error: "Unknown event type",
+            code: "unknown_event_type",
+            status: Status::UnprocessableEntity,
+            field: Some("event_type"),
+        });
+    }
+
+    if !(0.0..=1.0).contains(&event.confidence) {
+        return Err(ApiError {
+            error: "Confidence must be between 0 and 1",
+            code: "invalid_confidence",
+            status: Status::UnprocessableEntity,
+            field: Some("confidence"),
+        });
+    }
+
+    if let Some(severity) = &event.severity {
+        validate_severity(severity)?;
+    }
+
+    match (event.event_type.as_str(), &event.tamper_reason) {
+        (TAMPER_EVENT_TYPE, Some(tamper_reason))
+            if TAMPER_REASONS.contains(&tamper_reason.as_str()) => {}
+        (TAMPER_EVENT_TYPE, _) => {
+            return Err(ApiError {
+                error: "Tamper events must have a tamper reason of moved, covered or reboot",
+                code: "invalid_tamper_reason",
+                status: Status::UnprocessableEntity,
+                field: Some("tamper_reason"),
+            })
+        }
+        (_, Some(_)) => {
+            return Err(ApiError {
+                error: "Only tamper events can have a tamper reason",
+                code: "unexpected_tamper_reason",
+                status: Status::UnprocessableEntity,
+                field: Some("tamper_reason"),
+            })
+        }
+        (_, None) => {}
+    }
+
+    if let Some(image_id) = event.image_id {
+        let image_list = media_store().list_images(camera_id).map_err(|error| {
+            error!(
+                "Failed to list images for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get list of images",
+                code: "get_images_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        if image_id < 0 || !image_list.contains(&(image_id as u64)) {
+            return Err(ApiError {
+                error: "Attached image not found",
+                code: "attached_image_not_found",
+                status: Status::UnprocessableEntity,
+                field: Some("image_id"),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Tells everything that wants to know about a newly stored event: MQTT, WebSocket clients, webhooks and notifications.
+/// The event is stored either way, so failures are only logged rather than making whoever raised the event retry.
+pub fn dispatch_event(event: &Event, connection: &PgConnection) {
+    if let Err(error) = event_media::link_event(event, connection) {
+        error!(
+            "Failed to link images to event {}! The error was {}",
+            event.event_id, error
+        );
+    }
+
+    mqtt::publish_event(event);
+    realtime::publish_event(event, connection);
+
+    if let Err(error) = webhook::queue_deliveries(event, connection) {
+        error!(
+            "Failed to queue webhook deliveries for event {}! The error was {}",
+            event.event_id, error
+        );
+    }
+
+    if let Err(error) = notification::notify_event(event, connection) {
+        error!(
+            "Failed to queue notifications for event {}! The error was {}",
+            event.event_id, error
+        );
+    }
+}
+
+/// Checks and stores an event reported by a camera, along with its detections, and dispatches it.
+/// Returns None if the event happened outside of the camera's zones and was dropped.
+pub fn store_reported_event(
+    camera_id: uuid::Uuid,
+    mut reported_event: ReportedEvent,
+    conn: &PgConnection,
+) -> Result<Option<Event>, ApiError> {
+    validate_reported_event(&camera_id, &reported_event)?;
+    validate_reported_detections(&reported_event.detections)?;
+
+    if let Some(audio_id) = reported_event.audio_id {
+        get_cameras_audio_clip(camera_id, audio_id, conn).map_err(|error| {
+            if error.status == Status::NotFound {
+                ApiError {
+                    error: "Attached audio clip not found",
+                    code: "attached_audio_clip_not_found",
+                    status: Status::UnprocessableEntity,
+                    field: Some("audio_id"),
+                }
+            } else {
+                error
+            }
+        })?;
+    }
+
+    // Cameras should already apply their zones, this catches ones running older firmware.
+    // Zones are areas of the image, so they don't apply to anything the microphone heard
+    if let Some(bounding_box) = &reported_event.bounding_box {
+        if !AUDIO_EVENT_TYPES.contains(&reported_event.event_type.as_str())
+            && !is_in_zones(bounding_box, &load_zones(camera_id, conn)?)
+        {
+            return Ok(None);
+        }
+    }
+
+    let reported_detections = std::mem::take(&mut reported_event.detections);
+
+    let event = ingest_batch::store_event(
+        InsertableEvent::from_reported_event(camera_id, reported_event),
+        reported_detections,
+        conn,
+    )?;
+
+    dispatch_event(&event, conn);
+
+    Ok(Some(event))
+}
+
+/// Stores an event reported by a camera. Returns the stored event,
+/// or null if the event happened outside of the camera's zones and was dropped.
+#[openapi]
+#[post("/Device/Events", data = "<reported_event>")]
+pub fn report_event(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    reported_event: DeviceBody<ReportedEvent>,
+) -> Result<Device<Option<Event>>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    store_reported_event(camera_token.camera_id, reported_event.into_inner(), &conn).map(Device)
+}
+
+/// Returns the user's events across all of their cameras, newest first. Used for the activity feed.
+#[openapi]
+#[get("/Events?<query..>")]
+pub fn get_events(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    query: Form<EventQuery>,
+) -> Result<Json<Page<Sparse<Event>>>, ApiError> {
+    let filter = query.to_filter()?;
+    let (offset, limit) = query.offset_and_limit()?;
+    let fields = parse_fields(&query.fields)?;
+
+    get_users_events(user_token.user_id, &filter, offset, limit, &conn)
+        .map(|events| Json(events.sparse(&fields)))
+        .map_err(|error| {
+            error!(
+                "Failed to get events for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get events",
+                code: "get_events_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Returns a single event, with the images taken around it and anything detected in them.
+/// Images that have since been deleted are left out.
+#[openapi]
+#[get("/Events/<event_id>")]
+pub fn get_event(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    event_id: i32,
+) -> Result<Json<EventDetails>, ApiError> {
+    let event = get_users_event(user_token.user_id, event_id, &conn)?;
+
+    let linked_image_ids = event_media::get_events_image_ids(event_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get images for event {}! The error was {}",
+            event_id, error
+        );
+        ApiError {
+            error: "Failed to get event images",
+            code: "get_event_images_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    let stored_image_ids = media_store()
+        .list_images(&event.camera_id)
+        .map_err(|error| {
+            error!(
+                "Failed to list images for camera {}! The error was {}",
+                event.camera_id, error
+            );
+            ApiError {
+                error: "Failed to get list of images",
+                code: "get_images_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    let detections = get_events_detections(event_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get detections for event {}! The error was {}",
+            event_id, error
+        );
+        ApiError {
+            error: "Failed to get detections",
+            code: "get_detections_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    let acknowledgements = get_events_acknowledgements(event_id, &conn).map_err(|error| {
+        error!(
+            "Failed to get acknowledgements for event {}! The error was {}",
+            event_id, error
+        );
+        ApiError {
+            error: "Failed to get acknowledgements",
+            code: "get_acknowledgements_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(Json(EventDetails {
+        event,
+        image_ids: linked_image_ids
+            .into_iter()
+            .filter(|image_id| stored_image_ids.contains(&(*image_id as u64)))
+            .map(|image_id| image_id.to_string())
+            .collect(),
+        detections,
+        acknowledgements,
+    }))
+}
@@ -0,0 +1,185 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    event::{filter_events, users_events_query, Event, EventQuery},
+    row_stream::{NextBatch, RowStream},
+    timezone::{self, Attachment},
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::events;
+use chrono::Utc;
+use chrono_tz::Tz;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use rocket::get;
+use rocket::http::{ContentType, Status};
+use rocket::request::Form;
+use rocket::response::{Content, Stream};
+use rocket_okapi::openapi;
+
+pub const CSV_FORMAT: &str = "csv";
+pub const JSONL_FORMAT: &str = "jsonl";
+pub const JSON_FORMAT: &str = "json";
+
+/// How many events are loaded from the database at a time while exporting.
+pub const EXPORT_BATCH_SIZE: i64 = 500;
+
+pub const CSV_HEADER: &str =
+    "event_id,camera_id,event_type,occurred_at,confidence,severity,image_id,audio_id,tamper_reason\n";
+
+fn write_csv_row(event: &Event, buffer: &mut Vec<u8>) {
+    // None of the fields can contain commas, quotes or newlines, so nothing needs escaping
+    let row = format!(
+        "{},{},{},{},{},{},{},{},{}\n",
+        event.event_id,
+        event.camera_id,
+        event.event_type,
+        timezone::rfc3339(event.occurred_at),
+        event.confidence,
+        event.severity,
+        event
+            .image_id
+            .map(|image_id| image_id.to_string())
+            .unwrap_or_default(),
+        event
+            .audio_id
+            .map(|audio_id| audio_id.to_string())
+            .unwrap_or_default(),
+        event.tamper_reason.as_deref().unwrap_or_default()
+    );
+    buffer.extend_from_slice(row.as_bytes());
+}
+
+/// Streams the events `query` returns, oldest first, a batch at a time so that exporting years of events
+/// doesn't need them all in memory. The download is named after when it started, in `timezone`.
+fn export<F>(
+    format: Option<String>,
+    description: String,
+    timezone: Tz,
+    query: F,
+    conn: CameraServerDbConn,
+) -> Result<Attachment<Content<Stream<RowStream<Event>>>>, ApiError>
+where
+    F: Fn() -> events::BoxedQuery<'static, Pg> + 'static,
+{
+    let next_batch: NextBatch<Event> = Box::new(move |last_event: Option<&Event>| {
+        query()
+            .filter(events::event_id.gt(last_event.map(|event| event.event_id).unwrap_or(0)))
+            .order(events::event_id)
+            .limit(EXPORT_BATCH_SIZE)
+            .load::<Event>(&*conn)
+            .map_err(|error| {
+                error!(
+                    "Failed to export events for {}! The error was {}",
+                    description, error
+                );
+                error
+            })
+    });
+
+    let format = format.unwrap_or_else(|| JSONL_FORMAT.to_string());
+    let file_name = format!(
+        "events-{}.{}",
+        timezone::file_name_time(Utc::now(), timezone),
+        format
+    );
+
+    let response = match format.as_str() {
+        CSV_FORMAT => Ok(Content(
+            ContentType::CSV,
+            Stream::from(RowStream::csv(
+                CSV_HEADER,
+                EXPORT_BATCH_SIZE,
+                write_csv_row,
+                next_batch,
+            )),
+        )),
+        JSONL_FORMAT => Ok(Content(
+            ContentType::new("application", "x-ndjson"),
+            Stream::from(RowStream::json_lines(EXPORT_BATCH_SIZE, next_batch)),
+        )),
+        JSON_FORMAT => Ok(Content(
+            ContentType::JSON,
+            Stream::from(RowStream::json_array(EXPORT_BATCH_SIZE, next_batch)),
+        )),
+        _ => Err(ApiError {
+            error: "Format must be csv, jsonl or json",
+            code: "invalid_format",
+            status: Status::UnprocessableEntity,
+            field: Some("format"),
+        }),
+    }?;
+
+    Ok(Attachment {
+        file_name,
+        response,
+    })
+}
+
+/// Downloads the user's event history for offline analysis or insurance claims.
+/// Takes the same filters as GET /Events, and format=csv, format=jsonl (the default) or format=json for
+/// a JSON array.
+/// Events that happen after the export starts aren't included, unless `to` is in the future.
+#[openapi(skip)]
+#[get("/Events/Export?<format>&<query..>")]
+pub fn export_events(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    format: Option<String>,
+    query: Form<EventQuery>,
+) -> Result<Attachment<Content<Stream<RowStream<Event>>>>, ApiError> {
+    let mut filter = query.to_filter()?;
+
+    if filter.to.is_none() {
+        filter.to = Some(Utc::now());
+    }
+
+    let user_id = user_token.user_id;
+    let timezone = timezone::user_timezone(user_id, &conn);
+
+    export(
+        format,
+        format!("user {}", user_id),
+        timezone,
+        move || users_events_query(user_id, &filter),
+        conn,
+    )
+}
+
+/// Downloads every camera's events, for audits and migrations. Takes the same filters as GET /Events/Export,
+/// apart from unread, which only makes sense for one user. Only for users in ADMIN_USER_IDS.
+#[openapi(skip)]
+#[get("/Admin/Events/Export?<format>&<query..>")]
+pub fn export_all_events(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    format: Option<String>,
+    query: Form<EventQuery>,
+) -> Result<Attachment<Content<Stream<RowStream<Event>>>>, ApiError> {
+    let mut filter = query.to_filter()?;
+
+    if filter.unread {
+        return Err(ApiError {
+            error: "Unread can't be used when exporting every camera's events",
+            code: "unread_needs_camera",
+            status: Status::UnprocessableEntity,
+            field: Some("unread"),
+        });
+    }
+
+    if filter.to.is_none() {
+        filter.to = Some(Utc::now());
+    }
+
+    let timezone = timezone::user_timezone(admin_token.user_id, &conn);
+
+    export(
+        format,
+        String::from("every camera"),
+        timezone,
+        move || filter_events(events::table.into_boxed(), &filter),
+        conn,
+    )
+}
@@ -0,0 +1,112 @@
+use crate::{
+    event::Event,
+    media_store::{media_store, MediaStore},
+    settings::settings,
+};
+
+use super::schema::{event_media, events};
+use chrono::{Duration, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use serde::{Deserialize, Serialize};
+
+/// Links an event to an image from the same camera, so clients can show the footage for an event.
+#[derive(Queryable, Insertable, Deserialize, Serialize)]
+#[table_name = "event_media"]
+pub struct EventMedia {
+    pub event_id: i32,
+    pub image_id: i64,
+}
+
+/// How many seconds before an event an image can be taken and still count as footage of the event, set with
+/// window_before_seconds in [event_media]. Defaults to 10.
+pub fn window_before_seconds() -> i64 {
+    settings().event_media.window_before_seconds
+}
+
+/// How many seconds after an event an image can be taken and still count as footage of the event, set with
+/// window_after_seconds in [event_media]. Defaults to 30.
+pub fn window_after_seconds() -> i64 {
+    settings().event_media.window_after_seconds
+}
+
+pub fn insert(event_media: Vec<EventMedia>, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::insert_into(event_media::table)
+        .values(&event_media)
+        .on_conflict_do_nothing()
+        .execute(connection)
+}
+
+/// Returns the IDs of the images linked to the event, oldest first.
+pub fn get_events_image_ids(event_id: i32, connection: &PgConnection) -> QueryResult<Vec<i64>> {
+    event_media::table
+        .filter(event_media::event_id.eq(event_id))
+        .select(event_media::image_id)
+        .order(event_media::image_id)
+        .load(connection)
+}
+
+/// Links a new event to the images its camera has already taken inside the event's window, and to the event's own image.
+/// Images taken after this are linked as they are uploaded, by link_image().
+pub fn link_event(event: &Event, connection: &PgConnection) -> QueryResult<usize> {
+    let occurred_at = event.occurred_at.timestamp();
+    let from = occurred_at - window_before_seconds();
+    let to = occurred_at + window_after_seconds();
+
+    let mut image_ids: Vec<i64> = media_store()
+        .list_images(&event.camera_id)
+        .unwrap_or_else(|error| {
+            error!(
+                "Failed to list images for camera {}! The error was {}",
+                event.camera_id, error
+            );
+            Vec::new()
+        })
+        .into_iter()
+        .map(|image_id| image_id as i64)
+        .filter(|image_id| (from..=to).contains(image_id))
+        .collect();
+
+    if let Some(image_id) = event.image_id {
+        image_ids.push(image_id);
+    }
+
+    insert(
+        image_ids
+            .into_iter()
+            .map(|image_id| EventMedia {
+                event_id: event.event_id,
+                image_id,
+            })
+            .collect(),
+        connection,
+    )
+}
+
+/// Links a newly uploaded image to every event from the camera whose window it falls into.
+pub fn link_image(
+    camera_id: uuid::Uuid,
+    image_id: u64,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    // Image IDs are the seconds since epoch the image was uploaded at
+    let taken_at = Utc.timestamp(image_id as i64, 0);
+
+    let event_ids = events::table
+        .filter(events::camera_id.eq(camera_id))
+        .filter(events::occurred_at.ge(taken_at - Duration::seconds(window_after_seconds())))
+        .filter(events::occurred_at.le(taken_at + Duration::seconds(window_before_seconds())))
+        .select(events::event_id)
+        .load::<i32>(connection)?;
+
+    insert(
+        event_ids
+            .into_iter()
+            .map(|event_id| EventMedia {
+                event_id,
+                image_id: image_id as i64,
+            })
+            .collect(),
+        connection,
+    )
+}
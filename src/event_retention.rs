@@ -0,0 +1,257 @@
+use crate::{
+    api_error::ApiError, event::get_users_event, jobs, settings::settings, user_tokens::UserToken,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{detections, event_acknowledgements, event_holds, event_media, events};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+/// Stops the retention job from deleting an event, e.g. because it is part of an export or a legal hold.
+/// Held events that are past retention are anonymised instead, so the fact that they happened is kept.
+#[derive(Queryable, Deserialize, Serialize, JsonSchema)]
+pub struct EventHold {
+    pub hold_id: i32,
+    pub event_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "event_holds"]
+pub struct InsertableEventHold {
+    pub event_id: i32,
+    pub user_id: uuid::Uuid,
+    pub reason: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewEventHold {
+    pub reason: String,
+}
+
+/// How many days events are kept for, set with event_retention_days in [limits]. This is independent of how long images
+/// are kept, so event history can outlive its images or be pruned sooner. Returns None (keep events forever) if it isn't set.
+pub fn event_retention_days() -> Option<i64> {
+    settings().limits.event_retention_days
+}
+
+pub fn insert(hold: InsertableEventHold, connection: &PgConnection) -> QueryResult<EventHold> {
+    diesel::insert_into(event_holds::table)
+        .values(hold)
+        .get_result(connection)
+}
+
+pub fn delete(hold_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(event_holds::table.find(hold_id)).execute(connection)
+}
+
+pub fn get_events_holds(event_id: i32, connection: &PgConnection) -> QueryResult<Vec<EventHold>> {
+    event_holds::table
+        .filter(event_holds::event_id.eq(event_id))
+        .order(event_holds::created_at)
+        .load::<EventHold>(connection)
+}
+
+/// Strips an event down to what happened, where and when. Its detections, image links and acknowledgements are deleted,
+/// since they can identify people, but the event itself is kept.
+pub fn anonymise(event_ids: &Vec<i32>, connection: &PgConnection) -> QueryResult<usize> {
+    connection.transaction(|| {
+        diesel::delete(detections::table.filter(detections::event_id.eq_any(event_ids)))
+            .execute(connection)?;
+        diesel::delete(event_media::table.filter(event_media::event_id.eq_any(event_ids)))
+            .execute(connection)?;
+        diesel::delete(
+            event_acknowledgements::table
+                .filter(event_acknowledgements::event_id.eq_any(event_ids)),
+        )
+        .execute(connection)?;
+
+        diesel::update(events::table.filter(events::event_id.eq_any(event_ids)))
+            .set((
+                events::image_id.eq(None::<i64>),
+                events::audio_id.eq(None::<i32>),
+                events::anonymised_at.eq(Some(Utc::now())),
+            ))
+            .execute(connection)
+    })
+}
+
+/// Deletes every event older than `retention_days`, apart from events on hold, which are anonymised instead.
+/// Returns how many events were deleted and how many were anonymised.
+pub fn prune_events(retention_days: i64, connection: &PgConnection) -> QueryResult<(usize, usize)> {
+    let cutoff = Utc::now() - ChronoDuration::days(retention_days);
+
+    let deleted = diesel::delete(
+        events::table
+            .filter(events::occurred_at.lt(cutoff))
+            .filter(events::event_id.ne_all(event_holds::table.select(event_holds::event_id))),
+    )
+    .execute(connection)?;
+
+    let expired_held_event_ids = events::table
+        .filter(events::occurred_at.lt(cutoff))
+        .filter(events::anonymised_at.is_null())
+        .filter(events::event_id.eq_any(event_holds::table.select(event_holds::event_id)))
+        .select(events::event_id)
+        .load::<i32>(connection)?;
+
+    let anonymised = if expired_held_event_ids.len() > 0 {
+        anonymise(&expired_held_event_ids, connection)?
+    } else {
+        0
+    };
+
+    Ok((deleted, anonymised))
+}
+
+/// Starts the thread that queues a job to prune old events once an hour, see jobs::PRUNE_EVENTS_JOB.
+/// Does nothing if event_retention_days() is None.
+pub fn spawn_retention_worker(database_url: String) {
+    let retention_days = match event_retention_days() {
+        Some(retention_days) => retention_days,
+        None => return,
+    };
+
+    worker::spawn_worker(
+        "Event retention",
+        Duration::from_secs(60 * 60),
+        database_url,
+        move |connection| {
+            if let Err(error) = jobs::enqueue_unless_pending(
+                jobs::PRUNE_EVENTS_JOB,
+                json!({ "retention_days": retention_days }),
+                Utc::now(),
+                connection,
+            ) {
+                error!(
+                    "Failed to queue pruning old events! The error was {}",
+                    error
+                );
+            }
+        },
+    );
+}
+
+#[openapi]
+#[post("/Events/<event_id>/Holds", format = "json", data = "<new_hold>")]
+pub fn create_event_hold(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    event_id: i32,
+    new_hold: Json<NewEventHold>,
+) -> Result<Json<EventHold>, ApiError> {
+    let new_hold = new_hold.into_inner();
+
+    get_users_event(user_token.user_id, event_id, &conn)?;
+
+    if new_hold.reason.trim().len() == 0 {
+        return Err(ApiError {
+            error: "Hold reason can't be empty",
+            code: "hold_reason_required",
+            status: Status::UnprocessableEntity,
+            field: Some("reason"),
+        });
+    }
+
+    insert(
+        InsertableEventHold {
+            event_id,
+            user_id: user_token.user_id,
+            reason: new_hold.reason,
+        },
+        &conn,
+    )
+    .map(|hold| Json(hold))
+    .map_err(|error| {
+        error!(
+            "Failed to place hold on event {} for user {}! The error was {}",
+            event_id, user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to place hold",
+            code: "place_hold_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+#[openapi]
+#[get("/Events/<event_id>/Holds")]
+pub fn get_event_holds(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    event_id: i32,
+) -> Result<Json<Vec<EventHold>>, ApiError> {
+    get_users_event(user_token.user_id, event_id, &conn)?;
+
+    get_events_holds(event_id, &conn)
+        .map(|holds| Json(holds))
+        .map_err(|error| {
+            error!(
+                "Failed to get holds for event {}! The error was {}",
+                event_id, error
+            );
+            ApiError {
+                error: "Failed to get holds",
+                code: "get_holds_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Releases a hold. Only the user who placed a hold can release it.
+#[openapi]
+#[delete("/Events/<event_id>/Holds/<hold_id>")]
+pub fn delete_event_hold(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    event_id: i32,
+    hold_id: i32,
+) -> Result<(), ApiError> {
+    let hold = event_holds::table
+        .filter(event_holds::hold_id.eq(hold_id))
+        .filter(event_holds::event_id.eq(event_id))
+        .filter(event_holds::user_id.eq(user_token.user_id))
+        .get_result::<EventHold>(&*conn)
+        .map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Hold not found",
+                code: "hold_not_found",
+                status: Status::NotFound,
+                field: None,
+            },
+            _ => {
+                error!("Failed to get hold {}! The error was {}", hold_id, error);
+                ApiError {
+                    error: "Failed to get hold",
+                    code: "get_hold_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            }
+        })?;
+
+    delete(hold.hold_id, &conn).map(|_| ()).map_err(|error| {
+        error!("Failed to delete hold {}! The error was {}", hold_id, error);
+        ApiError {
+            error: "Failed to delete hold",
+            code: "delete_hold_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
@@ -0,0 +1,173 @@
+use crate::{
+    api_error::ApiError,
+    database::ReadDbConn,
+    event::{users_events_query, Event, EventFilter, EventQuery},
+    fields::{parse_fields, Sparse},
+    note::{self, Note},
+    page::Page,
+    timezone,
+    user_tokens::UserToken,
+};
+
+use super::schema::events;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use diesel::prelude::*;
+use rocket::get;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const HOUR_BUCKET: &str = "hour";
+pub const DAY_BUCKET: &str = "day";
+pub const WEEK_BUCKET: &str = "week";
+pub const MONTH_BUCKET: &str = "month";
+
+/// Facets are counted over at most this many matching events, so a vague search over years of events stays fast.
+pub const MAX_FACET_EVENTS: i64 = 50000;
+
+/// How many matching events have a particular value, e.g. how many were from a particular camera.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct EventFacets {
+    pub cameras: Vec<FacetCount>,
+    pub event_types: Vec<FacetCount>,
+    /// Keyed by the RFC 3339 start of each bucket (UTC), oldest first.
+    pub time_buckets: Vec<FacetCount>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct EventSearchResult<T> {
+    /// One page of matching events, newest first.
+    #[serde(flatten)]
+    pub page: Page<T>,
+    pub facets: EventFacets,
+    /// Notes matching the search, newest first, see note::search_users_notes(). Notes on events also make the
+    /// events they're on match.
+    pub notes: Vec<Note>,
+}
+
+/// Rounds a timestamp down to the start of its bucket.
+pub fn bucket_start(timestamp: DateTime<Utc>, bucket: &str) -> DateTime<Utc> {
+    let day_start = timestamp.date().and_hms(0, 0, 0);
+
+    match bucket {
+        HOUR_BUCKET => day_start + Duration::hours(timestamp.hour() as i64),
+        WEEK_BUCKET => {
+            day_start - Duration::days(timestamp.weekday().num_days_from_monday() as i64)
+        }
+        MONTH_BUCKET => Utc
+            .ymd(timestamp.year(), timestamp.month(), 1)
+            .and_hms(0, 0, 0),
+        _ => day_start,
+    }
+}
+
+fn to_facet_counts(counts: BTreeMap<String, i64>) -> Vec<FacetCount> {
+    counts
+        .into_iter()
+        .map(|(value, count)| FacetCount { value, count })
+        .collect()
+}
+
+pub fn search_users_events(
+    user_id: uuid::Uuid,
+    filter: &EventFilter,
+    bucket: &str,
+    offset: i64,
+    limit: i64,
+    connection: &PgConnection,
+) -> QueryResult<EventSearchResult<Event>> {
+    let events = users_events_query(user_id, filter)
+        .order((events::occurred_at.desc(), events::event_id.desc()))
+        .limit(limit)
+        .offset(offset)
+        .load::<Event>(connection)?;
+
+    let total = users_events_query(user_id, filter)
+        .count()
+        .get_result::<i64>(connection)?;
+
+    let facet_rows = users_events_query(user_id, filter)
+        .select((events::camera_id, events::event_type, events::occurred_at))
+        .order(events::occurred_at.desc())
+        .limit(MAX_FACET_EVENTS)
+        .load::<(uuid::Uuid, String, DateTime<Utc>)>(connection)?;
+
+    let mut cameras = BTreeMap::new();
+    let mut event_types = BTreeMap::new();
+    let mut time_buckets = BTreeMap::new();
+
+    for (camera_id, event_type, occurred_at) in facet_rows {
+        *cameras.entry(camera_id.to_string()).or_insert(0) += 1;
+        *event_types.entry(event_type).or_insert(0) += 1;
+        // RFC 3339 timestamps in UTC sort the same way as the times they represent
+        *time_buckets
+            .entry(timezone::rfc3339(bucket_start(occurred_at, bucket)))
+            .or_insert(0) += 1;
+    }
+
+    Ok(EventSearchResult {
+        page: Page::new(events, offset, total),
+        facets: EventFacets {
+            cameras: to_facet_counts(cameras),
+            event_types: to_facet_counts(event_types),
+            time_buckets: to_facet_counts(time_buckets),
+        },
+        notes: note::search_users_notes(user_id, filter, connection)?,
+    })
+}
+
+/// Searches the user's events. Takes the same filters as GET /Events, plus q for free text
+/// and bucket for how to group the time facet. Notes on the user's cameras that match are returned too.
+#[openapi]
+#[get("/Events/Search?<query..>")]
+pub fn search_events(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    query: Form<EventQuery>,
+) -> Result<Json<EventSearchResult<Sparse<Event>>>, ApiError> {
+    let filter = query.to_filter()?;
+    let (offset, limit) = query.offset_and_limit()?;
+    let fields = parse_fields(&query.fields)?;
+
+    let bucket = query.bucket.clone().unwrap_or(DAY_BUCKET.to_string());
+
+    if ![HOUR_BUCKET, DAY_BUCKET, WEEK_BUCKET, MONTH_BUCKET].contains(&bucket.as_str()) {
+        return Err(ApiError {
+            error: "Bucket must be hour, day, week or month",
+            code: "invalid_bucket",
+            status: Status::UnprocessableEntity,
+            field: Some("bucket"),
+        });
+    }
+
+    search_users_events(user_token.user_id, &filter, &bucket, offset, limit, &conn)
+        .map(|result| {
+            Json(EventSearchResult {
+                page: result.page.sparse(&fields),
+                facets: result.facets,
+                notes: result.notes,
+            })
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to search events for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to search events",
+                code: "search_events_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
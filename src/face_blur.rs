@@ -0,0 +1,98 @@
+use crate::{
+    analysis::{Analyser, CommandAnalyser, HttpAnalyser},
+    settings::settings,
+    zone::BoundingBox,
+};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops;
+use image::{ColorType, ImageFormat};
+use std::time::Duration;
+
+const JPEG_QUALITY: u8 = 90;
+
+/// How much of a face's width and height is added around each side of it, as detectors tend to box faces tightly
+/// and leave hair and ears out.
+const FACE_PADDING: f32 = 0.15;
+
+/// The face detector set in [privacy], or None if face blurring is off. Faces are only blurred in account exports
+/// and remote shares that ask for it, see NewAccountExport::blur_faces and NewRemoteShare::blur_faces. Everywhere
+/// else, including cameras shared with users on this server, images are served as they were taken.
+pub fn face_detector() -> Option<Box<dyn Analyser>> {
+    let privacy = &settings().privacy;
+
+    if let Some(url) = &privacy.face_detection_url {
+        return Some(Box::new(HttpAnalyser {
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .expect("Failed to build face detection HTTP client!"),
+            url: url.clone(),
+        }));
+    }
+
+    privacy
+        .face_detection_command
+        .clone()
+        .map(|command| Box::new(CommandAnalyser { command }) as Box<dyn Analyser>)
+}
+
+pub fn face_blurring_enabled() -> bool {
+    let privacy = &settings().privacy;
+    privacy.face_detection_url.is_some() || privacy.face_detection_command.is_some()
+}
+
+/// The pixels a face covers, padded and clipped to the image.
+fn face_pixels(face: &BoundingBox, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    let left = (face.x - face.width * FACE_PADDING).max(0.0);
+    let top = (face.y - face.height * FACE_PADDING).max(0.0);
+    let right = (face.x + face.width * (1.0 + FACE_PADDING)).min(1.0);
+    let bottom = (face.y + face.height * (1.0 + FACE_PADDING)).min(1.0);
+
+    let x = (left * width as f32) as u32;
+    let y = (top * height as f32) as u32;
+    let face_width = ((right * width as f32) as u32).saturating_sub(x);
+    let face_height = ((bottom * height as f32) as u32).saturating_sub(y);
+
+    if face_width == 0 || face_height == 0 {
+        return None;
+    }
+    Some((x, y, face_width, face_height))
+}
+
+/// A copy of the JPEG with every face the detector finds blurred, re-encoded as a JPEG. Images without faces are
+/// returned as they were. Errors if the image can't be decoded or the detector fails, rather than letting faces
+/// through.
+pub fn blur_faces(image: &[u8], detector: &dyn Analyser) -> Result<Vec<u8>, String> {
+    let faces = detector
+        .analyse(image)
+        .map_err(|error| format!("Failed to detect faces: {}", error))?;
+    if faces.is_empty() {
+        return Ok(image.to_vec());
+    }
+
+    let mut decoded = image::load_from_memory_with_format(image, ImageFormat::Jpeg)
+        .map_err(|error| format!("Failed to decode image: {}", error))?
+        .to_rgb8();
+    let (width, height) = decoded.dimensions();
+
+    for face in &faces {
+        let (x, y, face_width, face_height) = match face_pixels(&face.bounding_box, width, height) {
+            Some(pixels) => pixels,
+            None => continue,
+        };
+
+        // Strong enough that the face can't be made out, however big it is in the frame
+        let sigma = face_width.max(face_height) as f32 / 6.0;
+        let face_image = imageops::crop_imm(&decoded, x, y, face_width, face_height).to_image();
+        let blurred = imageops::blur(&face_image, sigma.max(2.0));
+        imageops::replace(&mut decoded, &blurred, x, y);
+    }
+
+    let mut blurred = Vec::new();
+    JpegEncoder::new_with_quality(&mut blurred, JPEG_QUALITY)
+        .encode(&decoded, width, height, ColorType::Rgb8)
+        .map_err(|error| format!("Failed to encode blurred image: {}", error))?;
+
+    Ok(blurred)
+}
@@ -0,0 +1,235 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audit,
+    cache::{self, cache},
+    settings::settings,
+    CameraServerDbConn,
+};
+
+use super::schema::feature_flags;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The realtime server's websockets and event streams.
+pub const STREAMING: &str = "streaming";
+
+/// Queueing and delivering webhooks.
+pub const WEBHOOKS: &str = "webhooks";
+
+pub const FEATURES: [&str; 2] = [STREAMING, WEBHOOKS];
+
+/// What [features] in the settings says the feature should be, before any admin has changed it.
+pub fn default_enabled(name: &str) -> bool {
+    let features = &settings().features;
+
+    match name {
+        STREAMING => features.streaming,
+        WEBHOOKS => features.webhooks,
+        _ => false,
+    }
+}
+
+/// An admin's choice for a feature, which overrides the settings until it's deleted.
+#[derive(Queryable, Insertable)]
+#[table_name = "feature_flags"]
+pub struct StoredFeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+    /// When an admin last turned the feature on or off, or None if it's as the settings say.
+    pub overridden_at: Option<DateTime<Utc>>,
+}
+
+/// Sent with PUT /Admin/Features/<name>.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewFeatureFlag {
+    pub enabled: bool,
+}
+
+fn get_stored(name: &str, connection: &PgConnection) -> QueryResult<Option<StoredFeatureFlag>> {
+    feature_flags::table
+        .find(name)
+        .get_result::<StoredFeatureFlag>(connection)
+        .optional()
+}
+
+fn get(name: &str, connection: &PgConnection) -> QueryResult<FeatureFlag> {
+    Ok(match get_stored(name, connection)? {
+        Some(stored) => FeatureFlag {
+            name: stored.name,
+            enabled: stored.enabled,
+            overridden_at: Some(stored.updated_at),
+        },
+        None => FeatureFlag {
+            name: name.to_string(),
+            enabled: default_enabled(name),
+            overridden_at: None,
+        },
+    })
+}
+
+/// Whether the feature is on. This is checked on hot paths, so it's cached for cache_ttl().
+/// If the database can't be read, the settings' default is used.
+pub fn is_enabled(name: &str, connection: &PgConnection) -> bool {
+    let key = cache::feature_flag_key(name);
+
+    if let Some(enabled) = cache().get(&key) {
+        return enabled == "1";
+    }
+
+    match get(name, connection) {
+        Ok(flag) => {
+            cache().set(
+                &key,
+                if flag.enabled { "1" } else { "0" },
+                cache::cache_ttl(),
+            );
+            flag.enabled
+        }
+        Err(error) => {
+            error!(
+                "Failed to get feature flag {}! The error was {}",
+                name, error
+            );
+            default_enabled(name)
+        }
+    }
+}
+
+fn check_feature_name(name: &str) -> Result<(), ApiError> {
+    if FEATURES.contains(&name) {
+        Ok(())
+    } else {
+        Err(ApiError {
+            error: "No such feature",
+            code: "feature_not_found",
+            status: Status::NotFound,
+            field: None,
+        })
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to save feature flag! The error was {}", error);
+    ApiError {
+        error: "Failed to save feature flag",
+        code: "save_feature_flag_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Every feature that can be turned on and off while the server runs. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Features")]
+pub fn get_features(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<FeatureFlag>>, ApiError> {
+    FEATURES
+        .iter()
+        .map(|name| get(name, &conn))
+        .collect::<QueryResult<Vec<FeatureFlag>>>()
+        .map(|flags| Json(flags))
+        .map_err(|error| {
+            error!("Failed to get feature flags! The error was {}", error);
+            ApiError {
+                error: "Failed to get feature flags",
+                code: "get_feature_flags_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Turns the feature on or off, whatever the settings say. Other nodes notice within ttl_seconds in [cache],
+/// or straight away if they share a Redis cache. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[put("/Admin/Features/<name>", format = "json", data = "<new_flag>")]
+pub fn update_feature(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    name: String,
+    new_flag: Json<NewFeatureFlag>,
+) -> Result<Json<FeatureFlag>, ApiError> {
+    check_feature_name(&name)?;
+
+    if let Ok(before) = get(&name, &conn) {
+        audit::record_before(&before);
+    }
+
+    let stored = StoredFeatureFlag {
+        name,
+        enabled: new_flag.into_inner().enabled,
+        updated_at: Utc::now(),
+    };
+
+    let stored = diesel::insert_into(feature_flags::table)
+        .values(&stored)
+        .on_conflict(feature_flags::name)
+        .do_update()
+        .set(feature_flags::enabled.eq(stored.enabled))
+        .get_result::<StoredFeatureFlag>(&*conn)
+        .map_err(database_error)?;
+
+    cache().delete(&cache::feature_flag_key(&stored.name));
+    info!(
+        "Feature {} turned {}",
+        stored.name,
+        if stored.enabled { "on" } else { "off" }
+    );
+
+    let flag = FeatureFlag {
+        name: stored.name,
+        enabled: stored.enabled,
+        overridden_at: Some(stored.updated_at),
+    };
+    audit::record_after(&flag);
+
+    Ok(Json(flag))
+}
+
+/// Puts the feature back to what the settings say. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Features/<name>")]
+pub fn reset_feature(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    name: String,
+) -> Result<Json<FeatureFlag>, ApiError> {
+    check_feature_name(&name)?;
+
+    if let Ok(before) = get(&name, &conn) {
+        audit::record_before(&before);
+    }
+
+    diesel::delete(feature_flags::table.find(&name))
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    cache().delete(&cache::feature_flag_key(&name));
+
+    let flag = FeatureFlag {
+        enabled: default_enabled(&name),
+        name,
+        overridden_at: None,
+    };
+    audit::record_after(&flag);
+
+    Ok(Json(flag))
+}
@@ -0,0 +1,1127 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::{self, list_camera_images, open_image, CameraId},
+    enums::token_error::TokenError,
+    face_blur,
+    media_store::{media_store, MediaStore},
+    page::{Page, PageQuery},
+    settings::settings,
+    tenant, user,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_owns_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::{federation_peers, remote_cameras, remote_shares};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use rocket::http::Status;
+use rocket::request::{self, Form, FromRequest};
+use rocket::response::Stream;
+use rocket::{delete, get, post, Outcome, Request, State};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+use std::time::Duration;
+
+/// Added by this server's admin, waiting for the other server to finish the handshake.
+pub const PENDING_STATUS: &str = "pending";
+pub const ACTIVE_STATUS: &str = "active";
+
+pub const REQUEST_TIMEOUT_SECONDS: u64 = 30;
+
+/// Where other servers can reach this one, set with public_url in [federation]. Defaults to none, which turns
+/// federation off.
+pub fn public_url() -> Option<String> {
+    settings()
+        .federation
+        .public_url
+        .as_ref()
+        .map(|public_url| normalize_url(public_url))
+}
+
+fn normalize_url(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_string()
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
+
+fn generate_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// What federation requests to other servers are sent with.
+pub struct FederationClient {
+    pub client: reqwest::blocking::Client,
+}
+
+impl FederationClient {
+    pub fn new() -> FederationClient {
+        FederationClient {
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+                .build()
+                .expect("Failed to build federation client!"),
+        }
+    }
+}
+
+/// Another server this one shares cameras with. Each server is the only one that knows about its own users and
+/// cameras: it's asked whether a username exists, and serves its cameras' images itself.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct FederationPeer {
+    pub peer_id: i32,
+    pub name: String,
+    pub url: String,
+    /// The token the peer presents to this server, hashed.
+    #[serde(skip)]
+    pub inbound_token_hash: String,
+    /// The token this server presents to the peer, once the handshake is done.
+    #[serde(skip)]
+    pub outbound_token: Option<String>,
+    /// pending or active.
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub activated_at: Option<DateTime<Utc>>,
+}
+
+/// What an admin sends to add a peer.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewFederationPeer {
+    pub name: String,
+    /// The peer's public_url.
+    pub url: String,
+    /// The code the peer's admin got when they added this server. Leave it out if this server is being added
+    /// first, to get a code for them instead.
+    pub handshake_code: Option<String>,
+}
+
+/// What POST /Admin/Federation/Peers returns.
+#[derive(Serialize, JsonSchema)]
+pub struct AddedPeer {
+    pub peer: FederationPeer,
+    /// Give this to the peer's admin, who adds this server with it. Only shown once.
+    pub handshake_code: Option<String>,
+}
+
+/// What a server sends, with the handshake code as its federation_token, to finish a handshake.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Handshake {
+    /// The sender's public_url.
+    pub url: String,
+    /// What the receiver should send as federation_token from now on.
+    pub token: String,
+}
+
+/// One of this server's cameras, shared with a user on a peer.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct RemoteShare {
+    pub share_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub peer_id: i32,
+    /// The user's username on the peer.
+    pub username: String,
+    #[schemars(with = "String")]
+    pub shared_by: uuid::Uuid,
+    #[serde(skip)]
+    pub access_token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Whether faces are blurred on the images the peer fetches.
+    pub blur_faces: bool,
+}
+
+/// What an owner sends to share a camera with a user on a peer.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewRemoteShare {
+    pub peer_id: i32,
+    pub username: String,
+    /// The slug of the user's tenant on the peer, as usernames are only unique within a tenant. Leave it out for
+    /// the peer's default tenant.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Blur every face on the images the peer fetches, for sharing with people who shouldn't see bystanders.
+    /// Needs face detection set up on the server. The stored images are left as they are. Faces aren't blurred
+    /// for users the camera is shared with on this server, or in anything else that isn't a remote share or an
+    /// account export.
+    #[serde(default)]
+    pub blur_faces: bool,
+}
+
+/// What a server sends a peer when one of its cameras is shared with a user there.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct SharedCamera {
+    pub share_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub camera_name: String,
+    pub username: String,
+    /// The slug of the user's tenant. Servers that don't send it mean the default tenant.
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// What the peer sends as federation_access_token to fetch the camera's images.
+    pub access_token: String,
+}
+
+/// A camera on a peer, shared with a user on this server.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct RemoteCamera {
+    pub remote_camera_id: i32,
+    pub peer_id: i32,
+    pub share_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    /// The camera's ID on the peer.
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub access_token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A request from a peer, with the federation_token header it was given in the handshake. Every federation route
+/// is a 404 while federation is off.
+pub struct PeerToken(pub FederationPeer);
+
+impl<'a, 'r> FromRequest<'a, 'r> for PeerToken {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if public_url().is_none() {
+            return Outcome::Failure((Status::NotFound, TokenError::NotFound));
+        }
+
+        let token = match request.headers().get_one("federation_token") {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        };
+
+        let connection = CameraServerDbConn::from_request(&request)
+            .expect("Failed to get DB connection on PeerToken request guard");
+
+        match federation_peers::table
+            .filter(federation_peers::inbound_token_hash.eq(hash_token(token)))
+            .get_result::<FederationPeer>(&*connection)
+        {
+            Ok(peer) => Outcome::Success(PeerToken(peer)),
+            Err(diesel::result::Error::NotFound) => {
+                Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+            }
+            Err(error) => {
+                error!("Failed to get federation peer! The error was {}", error);
+                Outcome::Failure((Status::ServiceUnavailable, TokenError::NotFound))
+            }
+        }
+    }
+}
+
+/// A peer fetching a shared camera's images, with the federation_access_token header it was given for the share.
+/// Revoking the share stops the token working straight away.
+pub struct ShareAccess(pub RemoteShare);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ShareAccess {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if public_url().is_none() {
+            return Outcome::Failure((Status::NotFound, TokenError::NotFound));
+        }
+
+        let token = match request.headers().get_one("federation_access_token") {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        };
+
+        let connection = CameraServerDbConn::from_request(&request)
+            .expect("Failed to get DB connection on ShareAccess request guard");
+
+        match remote_shares::table
+            .filter(remote_shares::access_token_hash.eq(hash_token(token)))
+            .filter(remote_shares::revoked_at.is_null())
+            .get_result::<RemoteShare>(&*connection)
+        {
+            Ok(share) => Outcome::Success(ShareAccess(share)),
+            Err(diesel::result::Error::NotFound) => {
+                Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+            }
+            Err(error) => {
+                error!("Failed to get remote share! The error was {}", error);
+                Outcome::Failure((Status::ServiceUnavailable, TokenError::NotFound))
+            }
+        }
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to update federation! The error was {}", error);
+    ApiError {
+        error: "Failed to update federation",
+        code: "update_federation_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn unreachable_peer(error: reqwest::Error) -> ApiError {
+    warn!("Failed to reach federation peer! The error was {}", error);
+    ApiError {
+        error: "Failed to reach the peer",
+        code: "peer_unreachable",
+        status: Status::BadGateway,
+        field: None,
+    }
+}
+
+fn federation_off() -> ApiError {
+    ApiError {
+        error: "Federation is off, set public_url in [federation] to turn it on",
+        code: "federation_off",
+        status: Status::Conflict,
+        field: None,
+    }
+}
+
+fn peer_not_found() -> ApiError {
+    ApiError {
+        error: "Peer not found",
+        code: "peer_not_found",
+        status: Status::NotFound,
+        field: Some("peer_id"),
+    }
+}
+
+fn get_active_peer(peer_id: i32, connection: &PgConnection) -> Result<FederationPeer, ApiError> {
+    federation_peers::table
+        .find(peer_id)
+        .filter(federation_peers::status.eq(ACTIVE_STATUS))
+        .get_result::<FederationPeer>(connection)
+        .optional()
+        .map_err(database_error)?
+        .ok_or_else(peer_not_found)
+}
+
+fn check_active(peer: &FederationPeer) -> Result<(), ApiError> {
+    if peer.status != ACTIVE_STATUS {
+        return Err(ApiError {
+            error: "The handshake hasn't been finished",
+            code: "handshake_not_finished",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Starts a request to one of the peer's federation routes, with this server's token for it.
+fn peer_request(
+    client: &FederationClient,
+    method: reqwest::Method,
+    peer: &FederationPeer,
+    path: &str,
+) -> reqwest::blocking::RequestBuilder {
+    client
+        .client
+        .request(method, &format!("{}{}{}", peer.url, API_PREFIX, path))
+        .header(
+            "federation_token",
+            peer.outbound_token.as_deref().unwrap_or_default(),
+        )
+}
+
+/// Sends a request to a peer that only matters to it, like telling it a share was revoked. Anything that goes
+/// wrong is only logged, as it already makes no difference to what the peer can see.
+fn notify_peer(request: reqwest::blocking::RequestBuilder, peer: &FederationPeer) {
+    match request.send() {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => warn!(
+            "Federation peer {} responded with {}",
+            peer.url,
+            response.status()
+        ),
+        Err(error) => warn!(
+            "Failed to reach federation peer {}! The error was {}",
+            peer.url, error
+        ),
+    }
+}
+
+/// Every server this one has a handshake with, or is waiting on one from. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Federation/Peers")]
+pub fn list_peers(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<FederationPeer>>, ApiError> {
+    federation_peers::table
+        .order(federation_peers::peer_id)
+        .load::<FederationPeer>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Adds another server as a peer. Whichever admin goes first leaves handshake_code out and passes the code they
+/// get back to the other, who adds the first server with it to finish the handshake. Only for users in
+/// ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Federation/Peers", format = "json", data = "<new_peer>")]
+pub fn add_peer(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    client: State<FederationClient>,
+    new_peer: Json<NewFederationPeer>,
+) -> Result<Json<AddedPeer>, ApiError> {
+    let public_url = public_url().ok_or_else(federation_off)?;
+    let new_peer = new_peer.into_inner();
+    let url = normalize_url(&new_peer.url);
+
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(ApiError {
+            error: "Peer URL must be http or https",
+            code: "invalid_peer_url",
+            status: Status::UnprocessableEntity,
+            field: Some("url"),
+        });
+    }
+
+    let inbound_token = generate_token();
+    let peer = diesel::insert_into(federation_peers::table)
+        .values((
+            federation_peers::name.eq(&new_peer.name),
+            federation_peers::url.eq(&url),
+            federation_peers::inbound_token_hash.eq(hash_token(&inbound_token)),
+            federation_peers::outbound_token.eq(&new_peer.handshake_code),
+        ))
+        .get_result::<FederationPeer>(&*conn)
+        .map_err(|error| match error {
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                ApiError {
+                    error: "Peer has already been added",
+                    code: "peer_already_added",
+                    status: Status::Conflict,
+                    field: Some("url"),
+                }
+            }
+            error => database_error(error),
+        })?;
+
+    if new_peer.handshake_code.is_none() {
+        return Ok(Json(AddedPeer {
+            peer,
+            handshake_code: Some(inbound_token),
+        }));
+    }
+
+    let handshake = peer_request(
+        &client,
+        reqwest::Method::POST,
+        &peer,
+        "/Federation/Handshake",
+    )
+    .json(&Handshake {
+        url: public_url,
+        token: inbound_token,
+    })
+    .send();
+
+    // The peer is only kept if it finished the handshake too, so either both servers have it or neither does
+    let refused = match handshake {
+        Ok(response) if response.status().is_success() => None,
+        Ok(response) if response.status() == reqwest::StatusCode::UNAUTHORIZED => Some(ApiError {
+            error: "The peer didn't accept the handshake code",
+            code: "handshake_refused",
+            status: Status::UnprocessableEntity,
+            field: Some("handshake_code"),
+        }),
+        Ok(response) => {
+            warn!(
+                "Federation peer {} refused the handshake with {}",
+                url,
+                response.status()
+            );
+            Some(ApiError {
+                error: "The peer refused the handshake",
+                code: "handshake_refused",
+                status: Status::BadGateway,
+                field: None,
+            })
+        }
+        Err(error) => Some(unreachable_peer(error)),
+    };
+
+    if let Some(refused) = refused {
+        diesel::delete(federation_peers::table.find(peer.peer_id))
+            .execute(&*conn)
+            .map_err(database_error)?;
+        return Err(refused);
+    }
+
+    diesel::update(federation_peers::table.find(peer.peer_id))
+        .set((
+            federation_peers::status.eq(ACTIVE_STATUS),
+            federation_peers::activated_at.eq(Utc::now()),
+        ))
+        .get_result::<FederationPeer>(&*conn)
+        .map(|peer| {
+            info!("Finished federation handshake with {}", peer.url);
+            Json(AddedPeer {
+                peer,
+                handshake_code: None,
+            })
+        })
+        .map_err(database_error)
+}
+
+/// Removes a peer, along with every share to or from it. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Federation/Peers/<peer_id>")]
+pub fn delete_peer(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    peer_id: i32,
+) -> Result<(), ApiError> {
+    diesel::delete(federation_peers::table.find(peer_id))
+        .execute(&*conn)
+        .map_err(database_error)
+        .and_then(|deleted| match deleted {
+            0 => Err(peer_not_found()),
+            _ => Ok(()),
+        })
+}
+
+/// Finishes a handshake this server's admin started. Sent by the peer, with the handshake code as its
+/// federation_token.
+#[openapi]
+#[post("/Federation/Handshake", format = "json", data = "<handshake>")]
+pub fn finish_handshake(
+    conn: CameraServerDbConn,
+    peer_token: PeerToken,
+    handshake: Json<Handshake>,
+) -> Result<(), ApiError> {
+    let peer = peer_token.0;
+
+    if peer.status != PENDING_STATUS {
+        return Err(ApiError {
+            error: "The handshake has already been finished",
+            code: "handshake_finished",
+            status: Status::Conflict,
+            field: None,
+        });
+    }
+    if normalize_url(&handshake.url) != peer.url {
+        return Err(ApiError {
+            error: "The handshake code is for a different server",
+            code: "wrong_handshake_server",
+            status: Status::UnprocessableEntity,
+            field: Some("url"),
+        });
+    }
+
+    diesel::update(federation_peers::table.find(peer.peer_id))
+        .set((
+            federation_peers::outbound_token.eq(&handshake.token),
+            federation_peers::status.eq(ACTIVE_STATUS),
+            federation_peers::activated_at.eq(Utc::now()),
+        ))
+        .execute(&*conn)
+        .map(|_| info!("Finished federation handshake with {}", peer.url))
+        .map_err(database_error)
+}
+
+/// Who on other servers the camera is shared with. Only for the camera's owner.
+#[openapi]
+#[get("/Cameras/<camera_id>/RemoteShares")]
+pub fn list_remote_shares(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Vec<RemoteShare>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    remote_shares::table
+        .filter(remote_shares::camera_id.eq(camera_id))
+        .filter(remote_shares::revoked_at.is_null())
+        .order(remote_shares::share_id)
+        .load::<RemoteShare>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Shares the camera with a user on a peer, by their username there. They can see its images, which the peer
+/// fetches from this server whenever they're viewed, until the share is revoked. Only for the camera's owner.
+#[openapi]
+#[post(
+    "/Cameras/<camera_id>/RemoteShares",
+    format = "json",
+    data = "<new_share>"
+)]
+pub fn share_camera(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    camera_id: CameraId,
+    new_share: Json<NewRemoteShare>,
+) -> Result<Json<RemoteShare>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+    public_url().ok_or_else(federation_off)?;
+
+    let new_share = new_share.into_inner();
+    if new_share.blur_faces && !face_blur::face_blurring_enabled() {
+        return Err(ApiError {
+            error: "Face blurring isn't set up on this server",
+            code: "face_blur_not_configured",
+            status: Status::UnprocessableEntity,
+            field: Some("blur_faces"),
+        });
+    }
+
+    let peer = get_active_peer(new_share.peer_id, &conn)?;
+    let camera = camera::get(camera_id, &conn).map_err(database_error)?;
+
+    let access_token = generate_token();
+    let share = diesel::insert_into(remote_shares::table)
+        .values((
+            remote_shares::camera_id.eq(camera_id),
+            remote_shares::peer_id.eq(peer.peer_id),
+            remote_shares::username.eq(&new_share.username),
+            remote_shares::shared_by.eq(user_token.user_id),
+            remote_shares::access_token_hash.eq(hash_token(&access_token)),
+            remote_shares::blur_faces.eq(new_share.blur_faces),
+        ))
+        .get_result::<RemoteShare>(&*conn)
+        .map_err(database_error)?;
+
+    let sent = peer_request(&client, reqwest::Method::POST, &peer, "/Federation/Shares")
+        .json(&SharedCamera {
+            share_id: share.share_id,
+            camera_id,
+            camera_name: camera.name,
+            username: new_share.username,
+            tenant: new_share.tenant,
+            access_token,
+        })
+        .send();
+
+    let refused = match sent {
+        Ok(response) if response.status().is_success() => None,
+        Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => Some(ApiError {
+            error: "User not found on the peer",
+            code: "remote_user_not_found",
+            status: Status::NotFound,
+            field: Some("username"),
+        }),
+        Ok(response) => {
+            warn!(
+                "Federation peer {} refused a share with {}",
+                peer.url,
+                response.status()
+            );
+            Some(ApiError {
+                error: "The peer refused the share",
+                code: "share_refused",
+                status: Status::BadGateway,
+                field: None,
+            })
+        }
+        Err(error) => Some(unreachable_peer(error)),
+    };
+
+    match refused {
+        Some(refused) => {
+            diesel::delete(remote_shares::table.find(share.share_id))
+                .execute(&*conn)
+                .map_err(database_error)?;
+            Err(refused)
+        }
+        None => Ok(Json(share)),
+    }
+}
+
+/// Stops sharing the camera with a user on a peer. Their access ends straight away, even if the peer can't be
+/// told. Only for the camera's owner.
+#[openapi]
+#[delete("/Cameras/<camera_id>/RemoteShares/<share_id>")]
+pub fn revoke_remote_share(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    camera_id: CameraId,
+    share_id: i32,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    let share = diesel::update(
+        remote_shares::table
+            .find(share_id)
+            .filter(remote_shares::camera_id.eq(camera_id))
+            .filter(remote_shares::revoked_at.is_null()),
+    )
+    .set(remote_shares::revoked_at.eq(Utc::now()))
+    .get_result::<RemoteShare>(&*conn)
+    .optional()
+    .map_err(database_error)?
+    .ok_or(ApiError {
+        error: "Share not found",
+        code: "share_not_found",
+        status: Status::NotFound,
+        field: None,
+    })?;
+
+    if let Ok(peer) = get_active_peer(share.peer_id, &conn) {
+        notify_peer(
+            peer_request(
+                &client,
+                reqwest::Method::DELETE,
+                &peer,
+                &format!("/Federation/Shares/{}", share.share_id),
+            ),
+            &peer,
+        );
+    }
+
+    Ok(())
+}
+
+/// Takes a camera a peer has shared with one of this server's users. Sent by the peer. A 404 means there's no
+/// such user, or no such tenant, here.
+#[openapi]
+#[post("/Federation/Shares", format = "json", data = "<shared_camera>")]
+pub fn receive_share(
+    conn: CameraServerDbConn,
+    peer_token: PeerToken,
+    shared_camera: Json<SharedCamera>,
+) -> Result<Json<RemoteCamera>, ApiError> {
+    let peer = peer_token.0;
+    check_active(&peer)?;
+    let shared_camera = shared_camera.into_inner();
+
+    // Usernames are only unique within a tenant, so peers name the tenant too
+    let tenant_id = tenant::resolve_tenant(shared_camera.tenant.as_deref(), None, || Ok(&*conn))
+        .map_err(|status| {
+            if status == Status::NotFound {
+                ApiError {
+                    error: "No such tenant",
+                    code: "tenant_not_found",
+                    status,
+                    field: Some("tenant"),
+                }
+            } else {
+                ApiError {
+                    error: "Failed to look up tenant",
+                    code: "look_up_tenant_failed",
+                    status,
+                    field: None,
+                }
+            }
+        })?;
+    let user = user::get_by_username(shared_camera.username.clone(), tenant_id, &conn)
+        .optional()
+        .map_err(database_error)?
+        .filter(|user| user.deleted_at.is_none())
+        .ok_or(ApiError {
+            error: "User not found",
+            code: "user_not_found",
+            status: Status::NotFound,
+            field: Some("username"),
+        })?;
+
+    diesel::insert_into(remote_cameras::table)
+        .values((
+            remote_cameras::peer_id.eq(peer.peer_id),
+            remote_cameras::share_id.eq(shared_camera.share_id),
+            remote_cameras::user_id.eq(user.user_id),
+            remote_cameras::camera_id.eq(shared_camera.camera_id),
+            remote_cameras::name.eq(&shared_camera.camera_name),
+            remote_cameras::access_token.eq(&shared_camera.access_token),
+        ))
+        .on_conflict((remote_cameras::peer_id, remote_cameras::share_id))
+        .do_nothing()
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    remote_cameras::table
+        .filter(remote_cameras::peer_id.eq(peer.peer_id))
+        .filter(remote_cameras::share_id.eq(shared_camera.share_id))
+        .get_result::<RemoteCamera>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Forgets a camera the peer has stopped sharing. Sent by the peer.
+#[openapi]
+#[delete("/Federation/Shares/<share_id>")]
+pub fn forget_share(
+    conn: CameraServerDbConn,
+    peer_token: PeerToken,
+    share_id: i32,
+) -> Result<(), ApiError> {
+    diesel::delete(
+        remote_cameras::table
+            .filter(remote_cameras::peer_id.eq(peer_token.0.peer_id))
+            .filter(remote_cameras::share_id.eq(share_id)),
+    )
+    .execute(&*conn)
+    .map(|_| ())
+    .map_err(database_error)
+}
+
+/// Revokes a share whose user has removed the camera on the peer. Sent by the peer.
+#[openapi]
+#[post("/Federation/Shares/<share_id>/Leave")]
+pub fn leave_share(
+    conn: CameraServerDbConn,
+    peer_token: PeerToken,
+    share_id: i32,
+) -> Result<(), ApiError> {
+    diesel::update(
+        remote_shares::table
+            .find(share_id)
+            .filter(remote_shares::peer_id.eq(peer_token.0.peer_id))
+            .filter(remote_shares::revoked_at.is_null()),
+    )
+    .set(remote_shares::revoked_at.eq(Utc::now()))
+    .execute(&*conn)
+    .map(|_| ())
+    .map_err(database_error)
+}
+
+/// Checks that the share is for the camera being asked for, and that the camera is still here.
+fn check_share(
+    share: &RemoteShare,
+    camera_id: uuid::Uuid,
+    conn: &PgConnection,
+) -> Result<(), ApiError> {
+    let not_found = ApiError {
+        error: "Camera not found",
+        code: "camera_not_found",
+        status: Status::NotFound,
+        field: None,
+    };
+
+    if share.camera_id != camera_id {
+        return Err(not_found);
+    }
+
+    camera::get(camera_id, conn)
+        .optional()
+        .map_err(database_error)?
+        .map(|_| ())
+        .ok_or(not_found)
+}
+
+/// Opens one of the shared camera's images, with its faces blurred if the share says to. If face detection has
+/// been turned off since the share was made, its images aren't served at all rather than letting faces through.
+fn open_shared_image(
+    share: &RemoteShare,
+    image_id: u64,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    if !share.blur_faces {
+        return open_image(&share.camera_id, image_id);
+    }
+
+    let face_detector = face_blur::face_detector().ok_or(ApiError {
+        error: "Face blurring isn't set up on this server",
+        code: "face_blur_not_configured",
+        status: Status::ServiceUnavailable,
+        field: None,
+    })?;
+
+    let mut image = Vec::new();
+    media_store()
+        .open_image(&share.camera_id, image_id)
+        .and_then(|mut file| file.read_to_end(&mut image))
+        .map_err(|error| {
+            error!("Failed to read file! The error was {}", error);
+            ApiError {
+                error: "Failed to load image",
+                code: "load_image_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    let blurred = face_blur::blur_faces(&image, face_detector.as_ref()).map_err(|error| {
+        error!(
+            "Failed to blur image {} of camera {} for share {}! The error was {}",
+            image_id, share.camera_id, share.share_id, error
+        );
+        ApiError {
+            error: "Failed to blur faces",
+            code: "blur_faces_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(Stream::from(
+        Box::new(Cursor::new(blurred)) as Box<dyn Read + Send>
+    ))
+}
+
+/// The shared camera's image IDs, oldest first. Sent by the peer, for its user.
+#[openapi]
+#[get("/Federation/Cameras/<camera_id>/Images?<query..>")]
+pub fn get_shared_image_list(
+    conn: CameraServerDbConn,
+    share_access: ShareAccess,
+    camera_id: CameraId,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<String>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_share(&share_access.0, camera_id, &conn)?;
+    let (offset, limit) = query.offset_and_limit()?;
+
+    let image_ids = list_camera_images(&camera_id)?
+        .iter()
+        .map(|image_id| image_id.to_string())
+        .collect();
+
+    Ok(Json(Page::from_vec(image_ids, offset, limit)))
+}
+
+/// The shared camera's newest image. Sent by the peer, for its user.
+#[openapi(skip)]
+#[get("/Federation/Cameras/<camera_id>/LatestImage", format = "image/jpeg")]
+pub fn get_shared_latest(
+    conn: CameraServerDbConn,
+    share_access: ShareAccess,
+    camera_id: CameraId,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_share(&share_access.0, camera_id, &conn)?;
+
+    let image_ids = list_camera_images(&camera_id)?;
+    open_shared_image(
+        &share_access.0,
+        *image_ids
+            .last()
+            .expect("list_camera_images() returns an error if there are no images"),
+    )
+}
+
+/// One of the shared camera's images. Sent by the peer, for its user.
+#[openapi(skip)]
+#[get(
+    "/Federation/Cameras/<camera_id>/Images/<image_id>",
+    format = "image/jpeg"
+)]
+pub fn get_shared_image(
+    conn: CameraServerDbConn,
+    share_access: ShareAccess,
+    camera_id: CameraId,
+    image_id: u64,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_share(&share_access.0, camera_id, &conn)?;
+
+    if !list_camera_images(&camera_id)?.contains(&image_id) {
+        return Err(ApiError {
+            error: "Image not found",
+            code: "image_not_found",
+            status: Status::NotFound,
+            field: None,
+        });
+    }
+
+    open_shared_image(&share_access.0, image_id)
+}
+
+fn get_users_remote_camera(
+    user_id: uuid::Uuid,
+    remote_camera_id: i32,
+    connection: &PgConnection,
+) -> Result<(RemoteCamera, FederationPeer), ApiError> {
+    let remote_camera = remote_cameras::table
+        .find(remote_camera_id)
+        .filter(remote_cameras::user_id.eq(user_id))
+        .get_result::<RemoteCamera>(connection)
+        .optional()
+        .map_err(database_error)?
+        .ok_or(ApiError {
+            error: "Remote camera not found",
+            code: "remote_camera_not_found",
+            status: Status::NotFound,
+            field: None,
+        })?;
+
+    let peer = get_active_peer(remote_camera.peer_id, connection)?;
+    Ok((remote_camera, peer))
+}
+
+/// Fetches something of a shared camera's from the server it's on.
+fn fetch_from_host(
+    client: &FederationClient,
+    remote_camera: &RemoteCamera,
+    peer: &FederationPeer,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<reqwest::blocking::Response, ApiError> {
+    let response = client
+        .client
+        .get(&format!(
+            "{}{}/Federation/Cameras/{}{}",
+            peer.url, API_PREFIX, remote_camera.camera_id, path
+        ))
+        .header(
+            "federation_access_token",
+            remote_camera.access_token.as_str(),
+        )
+        .query(query)
+        .send()
+        .map_err(unreachable_peer)?;
+
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        reqwest::StatusCode::UNAUTHORIZED => Err(ApiError {
+            error: "The camera is no longer shared with you",
+            code: "camera_unshared",
+            status: Status::Forbidden,
+            field: None,
+        }),
+        reqwest::StatusCode::NOT_FOUND => Err(ApiError {
+            error: "Image not found",
+            code: "image_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        status => {
+            warn!(
+                "Federation peer {} responded with {} for camera {}",
+                peer.url, status, remote_camera.camera_id
+            );
+            Err(ApiError {
+                error: "The camera's server couldn't get it",
+                code: "remote_fetch_failed",
+                status: Status::BadGateway,
+                field: None,
+            })
+        }
+    }
+}
+
+/// Cameras on other servers that have been shared with the user.
+#[openapi]
+#[get("/RemoteCameras")]
+pub fn list_remote_cameras(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<RemoteCamera>>, ApiError> {
+    remote_cameras::table
+        .filter(remote_cameras::user_id.eq(user_token.user_id))
+        .order(remote_cameras::remote_camera_id)
+        .load::<RemoteCamera>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Removes a camera shared from another server, which is told so it can revoke the share.
+#[openapi]
+#[delete("/RemoteCameras/<remote_camera_id>")]
+pub fn delete_remote_camera(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    remote_camera_id: i32,
+) -> Result<(), ApiError> {
+    let (remote_camera, peer) =
+        get_users_remote_camera(user_token.user_id, remote_camera_id, &conn)?;
+
+    diesel::delete(remote_cameras::table.find(remote_camera.remote_camera_id))
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    notify_peer(
+        peer_request(
+            &client,
+            reqwest::Method::POST,
+            &peer,
+            &format!("/Federation/Shares/{}/Leave", remote_camera.share_id),
+        ),
+        &peer,
+    );
+
+    Ok(())
+}
+
+/// The same as GET /Cameras/<camera_id>/Images, for a camera shared from another server.
+#[openapi]
+#[get("/RemoteCameras/<remote_camera_id>/Images?<query..>")]
+pub fn get_remote_image_list(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    remote_camera_id: i32,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<String>>, ApiError> {
+    let (remote_camera, peer) =
+        get_users_remote_camera(user_token.user_id, remote_camera_id, &conn)?;
+
+    let mut page_query = Vec::new();
+    if let Some(cursor) = &query.cursor {
+        page_query.push(("cursor", cursor.clone()));
+    }
+    if let Some(page_size) = query.page_size {
+        page_query.push(("page_size", page_size.to_string()));
+    }
+
+    fetch_from_host(&client, &remote_camera, &peer, "/Images", &page_query)?
+        .json::<Page<String>>()
+        .map(Json)
+        .map_err(unreachable_peer)
+}
+
+/// The same as GET /Cameras/<camera_id>/LatestImage, for a camera shared from another server.
+#[openapi(skip)]
+#[get("/RemoteCameras/<remote_camera_id>/LatestImage", format = "image/jpeg")]
+pub fn get_remote_latest(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    remote_camera_id: i32,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let (remote_camera, peer) =
+        get_users_remote_camera(user_token.user_id, remote_camera_id, &conn)?;
+
+    fetch_from_host(&client, &remote_camera, &peer, "/LatestImage", &[])
+        .map(|response| Stream::from(Box::new(response) as Box<dyn Read + Send>))
+}
+
+/// The same as GET /Cameras/<camera_id>/Images/<image_id>, for a camera shared from another server.
+#[openapi(skip)]
+#[get(
+    "/RemoteCameras/<remote_camera_id>/Images/<image_id>",
+    format = "image/jpeg"
+)]
+pub fn get_remote_image(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    client: State<FederationClient>,
+    remote_camera_id: i32,
+    image_id: u64,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let (remote_camera, peer) =
+        get_users_remote_camera(user_token.user_id, remote_camera_id, &conn)?;
+
+    fetch_from_host(
+        &client,
+        &remote_camera,
+        &peer,
+        &format!("/Images/{}", image_id),
+        &[],
+    )
+    .map(|response| Stream::from(Box::new(response) as Box<dyn Read + Send>))
+}
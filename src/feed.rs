@@ -0,0 +1,100 @@
+use crate::{
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::{self, Camera},
+    database::ReadDbConn,
+    event::Event,
+    user_tokens::UserToken,
+    users_cameras::get_users_cameras,
+};
+
+use super::schema::events;
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use rocket::get;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// One camera on the app's home screen.
+#[derive(Serialize, JsonSchema)]
+pub struct FeedCamera {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub name: String,
+    pub online: bool,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// Where to get the camera's newest image, like /api/v1/Cameras/<camera_id>/Images/<image_id>.
+    /// None if it hasn't uploaded any.
+    pub latest_image_url: Option<String>,
+    pub latest_image_at: Option<DateTime<Utc>>,
+    /// The camera's most recent event, None if it has never had one.
+    pub latest_event: Option<Event>,
+}
+
+impl FeedCamera {
+    fn new(camera: Camera, latest_event: Option<Event>) -> FeedCamera {
+        let latest_image_id = camera::cached_latest_image_id(&camera.camera_id);
+
+        FeedCamera {
+            camera_id: camera.camera_id,
+            name: camera.name,
+            online: camera.online,
+            last_seen_at: camera.last_seen_at,
+            latest_image_url: latest_image_id.map(|image_id| {
+                format!(
+                    "{}/Cameras/{}/Images/{}",
+                    API_PREFIX, camera.camera_id, image_id
+                )
+            }),
+            latest_image_at: latest_image_id.map(|image_id| Utc.timestamp(image_id as i64, 0)),
+            latest_event,
+        }
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get feed! The error was {}", error);
+    ApiError {
+        error: "Failed to get feed",
+        code: "get_feed_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Every camera the user has, by name, with what the home screen shows for it, so the app doesn't need another
+/// request per camera. The latest image can be a few seconds behind, as it's cached.
+#[openapi]
+#[get("/Feed")]
+pub fn get_feed(
+    conn: ReadDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<FeedCamera>>, ApiError> {
+    let mut cameras = get_users_cameras(user_token.user_id, &conn).map_err(database_error)?;
+    cameras.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let camera_ids: Vec<uuid::Uuid> = cameras.iter().map(|camera| camera.camera_id).collect();
+    let mut latest_events: HashMap<uuid::Uuid, Event> = events::table
+        .filter(events::camera_id.eq_any(camera_ids))
+        .order((events::camera_id, events::occurred_at.desc()))
+        .distinct_on(events::camera_id)
+        .load::<Event>(&*conn)
+        .map_err(database_error)?
+        .into_iter()
+        .map(|event| (event.camera_id, event))
+        .collect();
+
+    Ok(Json(
+        cameras
+            .into_iter()
+            .map(|camera| {
+                let latest_event = latest_events.remove(&camera.camera_id);
+                FeedCamera::new(camera, latest_event)
+            })
+            .collect(),
+    ))
+}
@@ -0,0 +1,97 @@
+use crate::{api_error::ApiError, page::Page};
+
+use rocket::http::Status;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::ser::Error;
+use serde::{Serialize, Serializer};
+use std::rc::Rc;
+
+/// The fields a client asked for with ?fields=, e.g. camera_id,name,online. None means every field.
+pub type Fields = Option<Rc<Vec<String>>>;
+
+/// Parses a comma separated ?fields= list. Unknown field names are ignored, so clients can ask for
+/// fields that newer servers have added.
+pub fn parse_fields(fields: &Option<String>) -> Result<Fields, ApiError> {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return Ok(None),
+    };
+
+    let names: Vec<String> = fields
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| name.len() > 0)
+        .map(|name| name.to_string())
+        .collect();
+
+    if names.len() == 0 {
+        return Err(ApiError {
+            error: "Fields must list at least one field",
+            code: "invalid_fields",
+            status: Status::UnprocessableEntity,
+            field: Some("fields"),
+        });
+    }
+
+    Ok(Some(Rc::new(names)))
+}
+
+/// An item that only serializes the top level fields in its Fields. Anything that doesn't serialize
+/// to an object is sent as it is.
+pub struct Sparse<T> {
+    pub item: T,
+    pub fields: Fields,
+}
+
+impl<T: Serialize> Serialize for Sparse<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match &self.fields {
+            Some(fields) => fields,
+            None => return self.item.serialize(serializer),
+        };
+
+        match serde_json::to_value(&self.item).map_err(S::Error::custom)? {
+            serde_json::Value::Object(object) => object
+                .into_iter()
+                .filter(|(name, _)| fields.contains(name))
+                .collect::<serde_json::Map<String, serde_json::Value>>()
+                .serialize(serializer),
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+/// Documented as the full item, since which fields are left out depends on the request.
+impl<T: JsonSchema> JsonSchema for Sparse<T> {
+    fn schema_name() -> String {
+        T::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        T::json_schema(gen)
+    }
+
+    fn is_referenceable() -> bool {
+        T::is_referenceable()
+    }
+}
+
+impl<T> Page<T> {
+    /// Trims every item on the page down to the fields.
+    pub fn sparse(self, fields: &Fields) -> Page<Sparse<T>> {
+        Page {
+            items: self
+                .items
+                .into_iter()
+                .map(|item| Sparse {
+                    item,
+                    fields: fields.clone(),
+                })
+                .collect(),
+            total: self.total,
+            next_cursor: self.next_cursor,
+        }
+    }
+}
@@ -0,0 +1,754 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    audit,
+    camera::record_camera_contact,
+    camera_tokens::CameraToken,
+    device_format::{Device, DeviceBody},
+    media_store::write_whole_file,
+    settings::settings,
+    soft_delete::not_found_or_database_error,
+    CameraServerDbConn,
+};
+
+use super::schema::{camera_firmware, firmware_releases};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket::{delete, get, post, put, Data, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The largest firmware image POST /Admin/Firmware takes.
+pub const MAX_FIRMWARE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What cameras can report with POST /Device/Firmware/<firmware_id>/Status, in the order they're expected in.
+pub const FIRMWARE_STATUSES: [&str; 5] = [
+    "downloading",
+    "downloaded",
+    "installing",
+    "installed",
+    "failed",
+];
+
+/// Where firmware images are kept, set with firmware_directory in [storage]. Defaults to firmware.
+pub fn firmware_directory() -> String {
+    settings().storage.firmware_directory.clone()
+}
+
+fn firmware_path(firmware_id: i32) -> String {
+    format!("{}/{}.bin", firmware_directory(), firmware_id)
+}
+
+/// A firmware image for one model of camera.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct FirmwareRelease {
+    pub firmware_id: i32,
+    pub model: String,
+    pub version: String,
+    pub size_bytes: i64,
+    /// Hex SHA-256 of the image, which cameras should check before installing it.
+    pub sha256: String,
+    /// How many of the model's cameras, out of 100, are offered it. 0 until it's rolled out.
+    pub rollout_percent: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A release along with how far its cameras have got with it. Returned by GET /Admin/Firmware.
+#[derive(Serialize, JsonSchema)]
+pub struct FirmwareReleaseStatus {
+    #[serde(flatten)]
+    pub release: FirmwareRelease,
+    /// How many cameras last reported each status for this release.
+    pub statuses: HashMap<String, i64>,
+}
+
+/// What a camera is running, and how it's getting on with the release it was last offered.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct CameraFirmware {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub model: String,
+    pub version: String,
+    pub firmware_id: Option<i32>,
+    /// One of FIRMWARE_STATUSES, None until the camera reports one.
+    pub status: Option<String>,
+    pub bytes_downloaded: Option<i64>,
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What GET /Device/Firmware offers a camera.
+#[derive(Serialize, JsonSchema)]
+pub struct FirmwareUpdate {
+    pub firmware_id: i32,
+    pub version: String,
+    pub size_bytes: i64,
+    pub sha256: String,
+    /// Supports Range and If-Range, so an interrupted download can carry on where it stopped.
+    pub download_url: String,
+}
+
+/// What a camera sends as it downloads and installs a release.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct FirmwareStatusReport {
+    /// One of FIRMWARE_STATUSES.
+    pub status: String,
+    pub bytes_downloaded: Option<i64>,
+    pub error: Option<String>,
+}
+
+/// Sent with PUT /Admin/Firmware/<firmware_id>/Rollout.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Rollout {
+    /// From 0 to 100. Cameras already offered the release stay offered it as this goes up.
+    pub rollout_percent: i32,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    not_found_or_database_error(
+        error,
+        ("firmware_not_found", "Firmware not found"),
+        ("get_firmware_failed", "Failed to get firmware"),
+    )
+}
+
+/// Which of 100 buckets the camera falls in for a release. Salted with the release, so it isn't always the same
+/// cameras that get new firmware first.
+fn rollout_bucket(camera_id: uuid::Uuid, firmware_id: i32) -> i32 {
+    let digest = Sha256::digest(format!("{}:{}", camera_id, firmware_id).as_bytes());
+    (u16::from_be_bytes([digest[0], digest[1]]) % 100) as i32
+}
+
+/// The newest release of the camera's model that's been rolled out to it, or None if it's already running that
+/// or something newer. Releases older than the one the camera is running are never offered, so turning a
+/// rollout down doesn't downgrade cameras that already have it.
+pub fn target_release(
+    camera_id: uuid::Uuid,
+    model: &str,
+    version: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<FirmwareRelease>> {
+    let releases = firmware_releases::table
+        .filter(firmware_releases::model.eq(model))
+        .order((
+            firmware_releases::created_at.desc(),
+            firmware_releases::firmware_id.desc(),
+        ))
+        .load::<FirmwareRelease>(connection)?;
+
+    for release in releases {
+        if release.version == version {
+            return Ok(None);
+        }
+        if rollout_bucket(camera_id, release.firmware_id) < release.rollout_percent {
+            return Ok(Some(release));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads everything, keeping track of its SHA-256 as it goes.
+struct Hashing<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Sha256,
+}
+
+impl Read for Hashing<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        Ok(read)
+    }
+}
+
+/// Stores a firmware image for a model, not rolled out to any cameras yet.
+pub fn store_release(
+    model: &str,
+    version: &str,
+    image: &mut dyn Read,
+    connection: &PgConnection,
+) -> Result<FirmwareRelease, ApiError> {
+    let release = diesel::insert_into(firmware_releases::table)
+        .values((
+            firmware_releases::model.eq(model),
+            firmware_releases::version.eq(version),
+        ))
+        .get_result::<FirmwareRelease>(connection)
+        .map_err(|error| match error {
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => ApiError {
+                error: "That model already has a release with that version",
+                code: "firmware_version_exists",
+                status: Status::Conflict,
+                field: Some("version"),
+            },
+            error => database_error(error),
+        })?;
+
+    // One byte over the limit is read, so images that are too big can be told apart from ones that are just big
+    let mut limited = image.take(MAX_FIRMWARE_BYTES + 1);
+    let mut hashing = Hashing {
+        inner: &mut limited,
+        hasher: Sha256::new(),
+    };
+    let stored = fs::create_dir_all(firmware_directory())
+        .and_then(|_| write_whole_file(&firmware_path(release.firmware_id), &mut hashing));
+
+    let size_bytes = match stored {
+        Ok(size) if size <= MAX_FIRMWARE_BYTES => Ok(size),
+        Ok(_) => Err(ApiError {
+            error: "Firmware images can be at most 64MiB",
+            code: "firmware_too_large",
+            status: Status::PayloadTooLarge,
+            field: None,
+        }),
+        Err(error) => {
+            error!("Failed to save firmware! The error was {}", error);
+            Err(ApiError {
+                error: "Failed to save firmware",
+                code: "save_firmware_failed",
+                status: Status::InternalServerError,
+                field: None,
+            })
+        }
+    };
+
+    let size_bytes = match size_bytes {
+        Ok(size_bytes) => size_bytes,
+        Err(failed) => {
+            let _ = fs::remove_file(firmware_path(release.firmware_id));
+            if let Err(error) = diesel::delete(firmware_releases::table.find(release.firmware_id))
+                .execute(connection)
+            {
+                error!(
+                    "Failed to delete firmware {} after failing to save it! The error was {}",
+                    release.firmware_id, error
+                );
+            }
+            return Err(failed);
+        }
+    };
+
+    diesel::update(firmware_releases::table.find(release.firmware_id))
+        .set((
+            firmware_releases::size_bytes.eq(size_bytes as i64),
+            firmware_releases::sha256.eq(hex::encode(hashing.hasher.finalize())),
+        ))
+        .get_result::<FirmwareRelease>(connection)
+        .map_err(database_error)
+}
+
+/// Uploads a firmware image for a model. It isn't offered to any cameras until PUT /Admin/Firmware/<firmware_id>/Rollout.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi(skip)]
+#[post(
+    "/Admin/Firmware?<model>&<version>",
+    format = "application/octet-stream",
+    data = "<image>"
+)]
+pub fn upload_firmware(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    model: String,
+    version: String,
+    image: Data,
+) -> Result<Json<FirmwareRelease>, ApiError> {
+    let (model, version) = (model.trim(), version.trim());
+    if model.is_empty() || version.is_empty() {
+        return Err(ApiError {
+            error: "Firmware needs a model and a version",
+            code: "firmware_model_and_version_required",
+            status: Status::UnprocessableEntity,
+            field: Some(if model.is_empty() { "model" } else { "version" }),
+        });
+    }
+
+    let release = store_release(model, version, &mut image.open(), &conn)?;
+    info!(
+        "Firmware {} for {} uploaded as release {}",
+        release.version, release.model, release.firmware_id
+    );
+
+    Ok(Json(release))
+}
+
+/// Every release, newest first, with how its cameras are getting on. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Firmware")]
+pub fn list_firmware(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<FirmwareReleaseStatus>>, ApiError> {
+    let releases = firmware_releases::table
+        .order(firmware_releases::firmware_id.desc())
+        .load::<FirmwareRelease>(&*conn)
+        .map_err(database_error)?;
+
+    let mut statuses: HashMap<i32, HashMap<String, i64>> = HashMap::new();
+    for (firmware_id, status, cameras) in camera_firmware::table
+        .filter(camera_firmware::status.is_not_null())
+        .group_by((camera_firmware::firmware_id, camera_firmware::status))
+        .select((
+            camera_firmware::firmware_id,
+            camera_firmware::status,
+            diesel::dsl::count_star(),
+        ))
+        .load::<(Option<i32>, Option<String>, i64)>(&*conn)
+        .map_err(database_error)?
+    {
+        if let (Some(firmware_id), Some(status)) = (firmware_id, status) {
+            statuses
+                .entry(firmware_id)
+                .or_default()
+                .insert(status, cameras);
+        }
+    }
+
+    Ok(Json(
+        releases
+            .into_iter()
+            .map(|release| FirmwareReleaseStatus {
+                statuses: statuses.remove(&release.firmware_id).unwrap_or_default(),
+                release,
+            })
+            .collect(),
+    ))
+}
+
+/// Offers the release to this many percent of its model's cameras. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[put(
+    "/Admin/Firmware/<firmware_id>/Rollout",
+    format = "json",
+    data = "<rollout>"
+)]
+pub fn update_rollout(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    firmware_id: i32,
+    rollout: Json<Rollout>,
+) -> Result<Json<FirmwareRelease>, ApiError> {
+    let rollout = rollout.into_inner();
+    if !(0..=100).contains(&rollout.rollout_percent) {
+        return Err(ApiError {
+            error: "rollout_percent must be from 0 to 100",
+            code: "invalid_rollout_percent",
+            status: Status::UnprocessableEntity,
+            field: Some("rollout_percent"),
+        });
+    }
+
+    let before = firmware_releases::table
+        .find(firmware_id)
+        .get_result::<FirmwareRelease>(&*conn)
+        .map_err(database_error)?;
+    audit::record_before(&before);
+
+    let release = diesel::update(firmware_releases::table.find(firmware_id))
+        .set(firmware_releases::rollout_percent.eq(rollout.rollout_percent))
+        .get_result::<FirmwareRelease>(&*conn)
+        .map_err(database_error)?;
+    audit::record_after(&release);
+
+    Ok(Json(release))
+}
+
+/// Deletes a release and its image. Cameras part way through downloading it will fail to finish.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Firmware/<firmware_id>")]
+pub fn delete_firmware(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    firmware_id: i32,
+) -> Result<(), ApiError> {
+    let deleted = diesel::delete(firmware_releases::table.find(firmware_id))
+        .execute(&*conn)
+        .map_err(database_error)?;
+    if deleted == 0 {
+        return Err(database_error(diesel::result::Error::NotFound));
+    }
+
+    if let Err(error) = fs::remove_file(firmware_path(firmware_id)) {
+        error!(
+            "Failed to delete the image for firmware {}! The error was {}",
+            firmware_id, error
+        );
+    }
+
+    Ok(())
+}
+
+/// Records what the camera is running and offers it the release it should be running, or null if it's up to
+/// date. Cameras are expected to check this on start up and every so often after.
+#[openapi]
+#[get("/Device/Firmware?<model>&<version>")]
+pub fn check_firmware(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    model: String,
+    version: String,
+) -> Result<Device<Option<FirmwareUpdate>>, ApiError> {
+    let camera_id = camera_token.camera_id;
+    record_camera_contact(camera_id, &conn);
+
+    diesel::insert_into(camera_firmware::table)
+        .values((
+            camera_firmware::camera_id.eq(camera_id),
+            camera_firmware::model.eq(&model),
+            camera_firmware::version.eq(&version),
+        ))
+        .on_conflict(camera_firmware::camera_id)
+        .do_update()
+        .set((
+            camera_firmware::model.eq(&model),
+            camera_firmware::version.eq(&version),
+            camera_firmware::updated_at.eq(Utc::now()),
+        ))
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    let release = target_release(camera_id, &model, &version, &conn).map_err(database_error)?;
+
+    Ok(Device(release.map(|release| FirmwareUpdate {
+        download_url: format!(
+            "{}/Device/Firmware/{}/Image",
+            API_PREFIX, release.firmware_id
+        ),
+        firmware_id: release.firmware_id,
+        version: release.version,
+        size_bytes: release.size_bytes,
+        sha256: release.sha256,
+    })))
+}
+
+/// Records how the camera is getting on with a release. Installed also becomes the version the camera is running.
+#[openapi]
+#[post("/Device/Firmware/<firmware_id>/Status", data = "<report>")]
+pub fn report_firmware_status(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    firmware_id: i32,
+    report: DeviceBody<FirmwareStatusReport>,
+) -> Result<Device<CameraFirmware>, ApiError> {
+    let camera_id = camera_token.camera_id;
+    let report = report.into_inner();
+    record_camera_contact(camera_id, &conn);
+
+    if !FIRMWARE_STATUSES.contains(&report.status.as_str()) {
+        return Err(ApiError {
+            error: "Unknown firmware status",
+            code: "unknown_firmware_status",
+            status: Status::UnprocessableEntity,
+            field: Some("status"),
+        });
+    }
+
+    let release = firmware_releases::table
+        .find(firmware_id)
+        .get_result::<FirmwareRelease>(&*conn)
+        .map_err(database_error)?;
+
+    let row = camera_firmware::table.find(camera_id);
+    let updated = if report.status == "installed" {
+        diesel::update(row)
+            .set((
+                camera_firmware::version.eq(&release.version),
+                camera_firmware::firmware_id.eq(firmware_id),
+                camera_firmware::status.eq(&report.status),
+                camera_firmware::bytes_downloaded.eq(report.bytes_downloaded),
+                camera_firmware::error.eq(&report.error),
+                camera_firmware::updated_at.eq(Utc::now()),
+            ))
+            .get_result::<CameraFirmware>(&*conn)
+    } else {
+        diesel::update(row)
+            .set((
+                camera_firmware::firmware_id.eq(firmware_id),
+                camera_firmware::status.eq(&report.status),
+                camera_firmware::bytes_downloaded.eq(report.bytes_downloaded),
+                camera_firmware::error.eq(&report.error),
+                camera_firmware::updated_at.eq(Utc::now()),
+            ))
+            .get_result::<CameraFirmware>(&*conn)
+    };
+
+    updated.map(Device).map_err(|error| match error {
+        diesel::result::Error::NotFound => ApiError {
+            error: "Check GET /Device/Firmware before reporting a status",
+            code: "firmware_not_offered",
+            status: Status::Conflict,
+            field: None,
+        },
+        error => database_error(error),
+    })
+}
+
+/// The Range and If-Range headers of a download.
+pub struct RangeRequest {
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RangeRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RangeRequest {
+            range: request.headers().get_one("Range").map(String::from),
+            if_range: request.headers().get_one("If-Range").map(String::from),
+        })
+    }
+}
+
+/// Which bytes of a file to send.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    /// From the first byte to the last, inclusive, like Content-Range.
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Anything else, like several ranges, is ignored and the whole file sent, which
+/// the spec allows.
+fn parse_range(range: &str, size: u64) -> ByteRange {
+    let range = match range.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range,
+        _ => return ByteRange::Whole,
+    };
+    let mut bounds = range.splitn(2, '-');
+    let (first, last) = match (bounds.next(), bounds.next()) {
+        (Some(first), Some(last)) => (first.trim(), last.trim()),
+        _ => return ByteRange::Whole,
+    };
+
+    if first.is_empty() {
+        // bytes=-500 is the last 500 bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(suffix) if size > 0 => ByteRange::Part(size.saturating_sub(suffix), size - 1),
+            Ok(_) => ByteRange::Unsatisfiable,
+            Err(_) => ByteRange::Whole,
+        };
+    }
+
+    let first = match first.parse::<u64>() {
+        Ok(first) => first,
+        Err(_) => return ByteRange::Whole,
+    };
+    let last = match last {
+        "" => size.saturating_sub(1),
+        last => match last.parse::<u64>() {
+            Ok(last) if last >= first => last.min(size.saturating_sub(1)),
+            _ => return ByteRange::Whole,
+        },
+    };
+
+    if first >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Part(first, last)
+    }
+}
+
+/// A window onto part of a file, so Rocket can send just that part with a Content-Length.
+struct FileWindow {
+    file: File,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl Read for FileWindow {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.position);
+        let wanted = (buffer.len() as u64).min(remaining) as usize;
+        if wanted == 0 {
+            return Ok(0);
+        }
+
+        let read = self.file.read(&mut buffer[..wanted])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileWindow {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.length as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't seek before the start",
+            ));
+        }
+
+        self.position = (position as u64).min(self.length);
+        self.file
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        Ok(self.position)
+    }
+}
+
+/// A firmware image, or the part of it that was asked for.
+pub struct FirmwareDownload {
+    file: File,
+    size: u64,
+    etag: String,
+    range: ByteRange,
+}
+
+impl<'r> Responder<'r> for FirmwareDownload {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response
+            .header(ContentType::Binary)
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("ETag", self.etag);
+
+        let (start, length) = match self.range {
+            ByteRange::Whole => (0, self.size),
+            ByteRange::Part(first, last) => {
+                response.status(Status::PartialContent).raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", first, last, self.size),
+                );
+                (first, last - first + 1)
+            }
+            ByteRange::Unsatisfiable => {
+                return response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", self.size))
+                    .ok();
+            }
+        };
+
+        response
+            .sized_body(FileWindow {
+                file: self.file,
+                start,
+                length,
+                position: 0,
+            })
+            .ok()
+    }
+}
+
+/// Downloads a release's image. Send Range to carry on an interrupted download, with If-Range set to the ETag
+/// from before so a release that's been replaced is sent whole instead.
+#[openapi(skip)]
+#[get("/Device/Firmware/<firmware_id>/Image")]
+pub fn download_firmware(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    firmware_id: i32,
+    range_request: RangeRequest,
+) -> Result<FirmwareDownload, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    let release = firmware_releases::table
+        .find(firmware_id)
+        .get_result::<FirmwareRelease>(&*conn)
+        .map_err(database_error)?;
+
+    let file = File::open(firmware_path(firmware_id)).map_err(|error| {
+        error!(
+            "Failed to open the image for firmware {}! The error was {}",
+            firmware_id, error
+        );
+        ApiError {
+            error: "Failed to get firmware",
+            code: "get_firmware_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    let size = release.size_bytes as u64;
+    let etag = format!("\"{}\"", release.sha256);
+    let range = match (&range_request.range, &range_request.if_range) {
+        (Some(_), Some(if_range)) if *if_range != etag => ByteRange::Whole,
+        (Some(range), _) => parse_range(range, size),
+        (None, _) => ByteRange::Whole,
+    };
+
+    Ok(FirmwareDownload {
+        file,
+        size,
+        etag,
+        range,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Part(0, 99));
+        assert_eq!(parse_range(" bytes=100- ", 1000), ByteRange::Part(100, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Part(900, 999));
+        assert_eq!(
+            parse_range("bytes=999-999", 1000),
+            ByteRange::Part(999, 999)
+        );
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            ByteRange::Part(500, 999)
+        );
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Part(0, 999));
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(
+            parse_range("bytes=1000-2000", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn sends_the_whole_file_for_anything_else() {
+        for range in &[
+            "",
+            "0-99",
+            "items=0-99",
+            "bytes=0-99,200-299",
+            "bytes=99-0",
+            "bytes=a-b",
+            "bytes=0",
+            "bytes=-",
+            "bytes=18446744073709551616-",
+        ] {
+            assert_eq!(parse_range(range, 1000), ByteRange::Whole, "{}", range);
+        }
+    }
+}
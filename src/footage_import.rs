@@ -0,0 +1,528 @@
+use crate::{
+    audio,
+    camera::{self, Camera},
+    media_store::{media_store, MediaStore},
+};
+
+use super::schema::audio_clips;
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// How motionEye lays out snapshots and movies, e.g. Camera1/2021-06-01/12-30-45.jpg.
+pub const MOTIONEYE_PATTERN: &str = "{camera}/{year}-{month}-{day}/{hour}-{minute}-{second}";
+
+/// How many audio clips' rows are inserted at once.
+pub const IMPORT_BATCH_FILES: usize = 500;
+
+const IMAGE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Audio clips' extensions and the content type they're stored with.
+const AUDIO_EXTENSIONS: [(&str, &str); 5] = [
+    ("wav", "audio/wav"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("m4a", "audio/mp4"),
+];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(char),
+    Camera,
+    /// A number with this many digits, e.g. {year} or {month}.
+    Number(&'static str, usize),
+    /// {*}, which matches anything within one directory or file name.
+    Anything,
+}
+
+/// Which parts of a path the camera and the time come from. Patterns are matched against each file's path inside the
+/// directory being imported, without its extension. They can use {camera} (a camera's ID or name), {year}, {month},
+/// {day}, {hour}, {minute}, {second} and {*}, which matches anything. {second} can be left out for 0.
+pub struct PathPattern {
+    tokens: Vec<Token>,
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<PathPattern, String> {
+        let mut tokens = Vec::new();
+        let mut rest = pattern;
+
+        while let Some(c) = rest.chars().next() {
+            if c != '{' {
+                tokens.push(Token::Literal(c));
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+
+            let end = rest
+                .find('}')
+                .ok_or_else(|| format!("{} has a {{ without a }}", pattern))?;
+            tokens.push(match &rest[1..end] {
+                "camera" => Token::Camera,
+                "year" => Token::Number("year", 4),
+                "month" => Token::Number("month", 2),
+                "day" => Token::Number("day", 2),
+                "hour" => Token::Number("hour", 2),
+                "minute" => Token::Number("minute", 2),
+                "second" => Token::Number("second", 2),
+                "*" => Token::Anything,
+                field => return Err(format!("{{{}}} isn't something a pattern can have", field)),
+            });
+            rest = &rest[end + 1..];
+        }
+
+        for required in &["year", "month", "day", "hour", "minute"] {
+            let found = tokens.iter().any(|token| match token {
+                Token::Number(field, _) => field == required,
+                _ => false,
+            });
+
+            if !found {
+                return Err(format!("{} needs a {{{}}}", pattern, required));
+            }
+        }
+
+        Ok(PathPattern { tokens })
+    }
+
+    pub fn has_camera(&self) -> bool {
+        self.tokens.contains(&Token::Camera)
+    }
+
+    /// The camera (if the pattern has one) and the fields matched in the path, or None if it doesn't match.
+    fn captures(&self, path: &str) -> Option<(Option<String>, BTreeMap<&'static str, u32>)> {
+        let mut camera = None;
+        let mut numbers = BTreeMap::new();
+
+        if match_tokens(&self.tokens, path, &mut camera, &mut numbers) {
+            Some((camera, numbers))
+        } else {
+            None
+        }
+    }
+
+    /// The camera and time the path is for, with the time read at the given offset from UTC.
+    pub fn match_path(
+        &self,
+        path: &str,
+        utc_offset: FixedOffset,
+    ) -> Option<(Option<String>, DateTime<Utc>)> {
+        let (camera, numbers) = self.captures(path)?;
+
+        let time =
+            NaiveDate::from_ymd_opt(numbers["year"] as i32, numbers["month"], numbers["day"])?
+                .and_hms_opt(
+                    numbers["hour"],
+                    numbers["minute"],
+                    numbers.get("second").copied().unwrap_or(0),
+                )?;
+
+        Some((
+            camera,
+            utc_offset
+                .from_local_datetime(&time)
+                .single()?
+                .with_timezone(&Utc),
+        ))
+    }
+}
+
+/// Backtracks over {camera} and {*}, since they can match any number of characters.
+fn match_tokens(
+    tokens: &[Token],
+    path: &str,
+    camera: &mut Option<String>,
+    numbers: &mut BTreeMap<&'static str, u32>,
+) -> bool {
+    let token = match tokens.first() {
+        Some(token) => token,
+        None => return path.is_empty(),
+    };
+
+    match token {
+        Token::Literal(c) => {
+            path.starts_with(*c)
+                && match_tokens(&tokens[1..], &path[c.len_utf8()..], camera, numbers)
+        }
+        Token::Number(field, digits) => match path.get(..*digits) {
+            Some(number) if number.chars().all(|c| c.is_ascii_digit()) => {
+                numbers.insert(*field, number.parse().expect("Only digits were matched"));
+                match_tokens(&tokens[1..], &path[*digits..], camera, numbers)
+            }
+            _ => false,
+        },
+        Token::Camera | Token::Anything => {
+            let segment_end = path.find('/').unwrap_or(path.len());
+
+            // Shortest first, so {*} after a field only soaks up what the rest of the pattern doesn't match
+            let ends = path[..segment_end]
+                .char_indices()
+                .map(|(end, _)| end)
+                .chain(std::iter::once(segment_end))
+                .filter(|end| *end > 0);
+
+            for end in ends {
+                if match_tokens(&tokens[1..], &path[end..], camera, numbers) {
+                    if *token == Token::Camera {
+                        *camera = Some(path[..end].to_string());
+                    }
+                    return true;
+                }
+            }
+
+            false
+        }
+    }
+}
+
+/// Parses a UTC offset like +01:00 or -05:30.
+pub fn parse_utc_offset(offset: &str) -> Option<FixedOffset> {
+    let sign = match offset.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    // Only digits, so a sign can't sneak in after the first one
+    let parse = |part: &str| match part.chars().all(|c| c.is_ascii_digit()) {
+        true => part.parse::<i32>().ok(),
+        false => None,
+    };
+    let mut parts = offset[1..].split(':');
+    let hours = parse(parts.next()?)?;
+    let minutes = parts.next().map_or(Some(0), parse)?;
+
+    if parts.next().is_some() || hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    FixedOffset::east_opt(sign * (hours * 60 * 60 + minutes * 60))
+}
+
+pub struct ImportOptions {
+    pub pattern: PathPattern,
+    /// Imports everything into this camera, instead of the one in the path.
+    pub camera_id: Option<uuid::Uuid>,
+    pub utc_offset: FixedOffset,
+    /// Only works out what would be imported.
+    pub dry_run: bool,
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub images: usize,
+    pub audio_clips: usize,
+    /// Images for a second that already has one. Image IDs are seconds, so only one image per camera fits in each.
+    pub duplicates: usize,
+    /// Files whose path doesn't match the pattern.
+    pub unmatched: Vec<PathBuf>,
+    /// {camera} in a path that isn't a camera's ID or name.
+    pub unknown_cameras: BTreeSet<String>,
+    /// Files that aren't images or audio, e.g. motionEye's movies, as the server has nowhere to keep video.
+    pub unsupported: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+enum Media {
+    Image,
+    Audio(&'static str),
+}
+
+/// Every file under the directory, depth first, in name order so imports are repeatable.
+fn files_under(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            files_under(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn find_camera(camera: &str, cameras: &[Camera]) -> Option<uuid::Uuid> {
+    match uuid::Uuid::parse_str(camera) {
+        Ok(camera_id) => cameras
+            .iter()
+            .find(|found| found.camera_id == camera_id)
+            .map(|found| found.camera_id),
+        Err(_) => cameras
+            .iter()
+            .find(|found| found.name.eq_ignore_ascii_case(camera))
+            .map(|found| found.camera_id),
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "audio_clips"]
+struct ImportedAudioClip {
+    camera_id: uuid::Uuid,
+    content_type: String,
+    size_bytes: i64,
+    recorded_at: DateTime<Utc>,
+}
+
+/// Inserts a batch of audio clips in one statement, then writes each one's file. A clip whose file can't be
+/// written has its row deleted again.
+fn import_audio_batch(
+    batch: &[(uuid::Uuid, &'static str, DateTime<Utc>, PathBuf)],
+    report: &mut ImportReport,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let mut rows = Vec::new();
+    for (camera_id, content_type, recorded_at, path) in batch {
+        let size_bytes = fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        rows.push(ImportedAudioClip {
+            camera_id: *camera_id,
+            content_type: content_type.to_string(),
+            size_bytes: size_bytes.min(audio::MAX_AUDIO_BYTES) as i64,
+            recorded_at: *recorded_at,
+        });
+    }
+
+    // Postgres returns the rows in the order they were given
+    let audio_ids = diesel::insert_into(audio_clips::table)
+        .values(&rows)
+        .returning(audio_clips::audio_id)
+        .get_results::<i32>(connection)?;
+
+    for ((camera_id, _, _, path), audio_id) in batch.iter().zip(audio_ids) {
+        let stored = File::open(path)
+            .and_then(|mut file| audio::store_audio(camera_id, audio_id, &mut file));
+
+        match stored {
+            Ok(_) => report.audio_clips += 1,
+            Err(error) => {
+                diesel::delete(audio_clips::table.find(audio_id)).execute(connection)?;
+                report.failed.push((path.clone(), error.to_string()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Imports every image and audio clip under the directory whose path matches the pattern. Events aren't made for
+/// them and they aren't analysed, as old footage would otherwise set off alerts. Audio clips' rows are inserted
+/// IMPORT_BATCH_FILES at a time.
+pub fn import(
+    directory: &Path,
+    options: &ImportOptions,
+    connection: &PgConnection,
+) -> Result<ImportReport, String> {
+    let mut files = Vec::new();
+    files_under(directory, &mut files)
+        .map_err(|error| format!("Failed to list {}: {}", directory.display(), error))?;
+
+    let cameras = camera::all(connection).map_err(|error| error.to_string())?;
+    let store = media_store();
+    let mut stored_images: BTreeMap<uuid::Uuid, HashSet<u64>> = BTreeMap::new();
+    let mut audio_batch = Vec::new();
+    let mut report = ImportReport::default();
+
+    for path in files {
+        let relative = path.strip_prefix(directory).unwrap_or(&path).to_path_buf();
+        let extension = relative
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let media = if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            Media::Image
+        } else if let Some((_, content_type)) = AUDIO_EXTENSIONS
+            .iter()
+            .find(|(audio, _)| *audio == extension)
+        {
+            Media::Audio(content_type)
+        } else {
+            report.unsupported += 1;
+            continue;
+        };
+
+        let (camera, recorded_at) = match options.pattern.match_path(
+            &relative.with_extension("").to_string_lossy(),
+            options.utc_offset,
+        ) {
+            Some(matched) => matched,
+            None => {
+                report.unmatched.push(path);
+                continue;
+            }
+        };
+
+        let camera_id = match (options.camera_id, camera) {
+            (Some(camera_id), _) => camera_id,
+            (None, Some(camera)) => match find_camera(&camera, &cameras) {
+                Some(camera_id) => camera_id,
+                None => {
+                    report.unknown_cameras.insert(camera);
+                    continue;
+                }
+            },
+            (None, None) => {
+                report.unmatched.push(path);
+                continue;
+            }
+        };
+
+        match media {
+            Media::Image => {
+                let image_id = recorded_at.timestamp() as u64;
+                let existing = stored_images.entry(camera_id).or_insert_with(|| {
+                    store
+                        .list_images(&camera_id)
+                        .map(|images| images.into_iter().collect())
+                        .unwrap_or_default()
+                });
+
+                if !existing.insert(image_id) {
+                    report.duplicates += 1;
+                    continue;
+                }
+
+                if options.dry_run {
+                    report.images += 1;
+                    continue;
+                }
+
+                match File::open(&path)
+                    .and_then(|mut file| store.store_image(&camera_id, image_id, &mut file))
+                {
+                    Ok(_) => report.images += 1,
+                    Err(error) => report.failed.push((path, error.to_string())),
+                }
+            }
+            Media::Audio(_) if options.dry_run => report.audio_clips += 1,
+            Media::Audio(content_type) => {
+                audio_batch.push((camera_id, content_type, recorded_at, path));
+
+                if audio_batch.len() == IMPORT_BATCH_FILES {
+                    import_audio_batch(&audio_batch, &mut report, connection)
+                        .map_err(|error| format!("Failed to import audio clips: {}", error))?;
+                    audio_batch.clear();
+                    info!(
+                        "Imported {} images and {} audio clips so far",
+                        report.images, report.audio_clips
+                    );
+                }
+            }
+        }
+    }
+
+    if audio_batch.len() > 0 {
+        import_audio_batch(&audio_batch, &mut report, connection)
+            .map_err(|error| format!("Failed to import audio clips: {}", error))?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, second)
+    }
+
+    fn pattern(pattern: &str) -> PathPattern {
+        PathPattern::parse(pattern).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn at_utc(pattern: &PathPattern, path: &str) -> Option<(Option<String>, DateTime<Utc>)> {
+        pattern.match_path(path, FixedOffset::east(0))
+    }
+
+    #[test]
+    fn matches_motioneye_paths() {
+        let motioneye = pattern(MOTIONEYE_PATTERN);
+
+        assert!(motioneye.has_camera());
+        assert_eq!(
+            at_utc(&motioneye, "Camera1/2021-06-01/12-30-45"),
+            Some((Some(String::from("Camera1")), utc(2021, 6, 1, 12, 30, 45)))
+        );
+        // Camera names can have the pattern's separators in them
+        assert_eq!(
+            at_utc(&motioneye, "Front-door 2/2021-06-01/12-30-45"),
+            Some((
+                Some(String::from("Front-door 2")),
+                utc(2021, 6, 1, 12, 30, 45)
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_times_at_the_offset() {
+        let offset = parse_utc_offset("+02:00").unwrap();
+
+        assert_eq!(
+            pattern(MOTIONEYE_PATTERN).match_path("Camera1/2021-06-01/01-30-00", offset),
+            Some((Some(String::from("Camera1")), utc(2021, 5, 31, 23, 30, 0)))
+        );
+    }
+
+    #[test]
+    fn matches_anything_with_wildcards() {
+        let wildcard = pattern("{*}/{year}{month}{day}_{hour}{minute}{*}");
+
+        assert!(!wildcard.has_camera());
+        assert_eq!(
+            at_utc(&wildcard, "exports/20210601_1230_motion_01"),
+            Some((None, utc(2021, 6, 1, 12, 30, 0)))
+        );
+        // Wildcards don't cross directories
+        assert_eq!(at_utc(&wildcard, "a/b/20210601_1230"), None);
+    }
+
+    #[test]
+    fn rejects_paths_that_dont_match() {
+        let motioneye = pattern(MOTIONEYE_PATTERN);
+
+        for path in &[
+            "Camera1/2021-06-01/12-30",
+            "Camera1/2021-06-01/12-30-45-extra",
+            "Camera1/21-06-01/12-30-45",
+            "Camera1/2021-0a-01/12-30-45",
+            "/2021-06-01/12-30-45",
+            "Camera1/2021-02-30/12-30-45",
+            "Camera1/2021-06-01/24-00-00",
+        ] {
+            assert_eq!(at_utc(&motioneye, path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(PathPattern::parse("{year}-{month}-{day}/{hour}").is_err());
+        assert!(PathPattern::parse("{year}-{month}-{day}/{hour}-{minute}-{millisecond}").is_err());
+        assert!(PathPattern::parse("{year}-{month}-{day}/{hour}-{minute").is_err());
+    }
+
+    #[test]
+    fn parses_utc_offsets() {
+        assert_eq!(parse_utc_offset("+01:00"), FixedOffset::east_opt(60 * 60));
+        assert_eq!(
+            parse_utc_offset("-05:30"),
+            FixedOffset::west_opt(5 * 60 * 60 + 30 * 60)
+        );
+        assert_eq!(parse_utc_offset("+3"), FixedOffset::east_opt(3 * 60 * 60));
+
+        for offset in &["", "01:00", "+24:00", "+01:60", "+01:00:00", "+-1:00", "+a"] {
+            assert_eq!(parse_utc_offset(offset), None, "{}", offset);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{
+    api_error::ApiError,
+    mode::{current_mode, set_mode, AWAY_MODE, HOME_MODE},
+    user_tokens::UserToken,
+    users_cameras::in_owners_tenant,
+    CameraServerDbConn,
+};
+
+use super::schema::{user_presence, users_cameras};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const ENTER_TRANSITION: &str = "enter";
+pub const LEAVE_TRANSITION: &str = "leave";
+
+/// Whether a user is inside their home geofence, as last reported by their phone.
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "user_presence"]
+#[primary_key(user_id)]
+pub struct UserPresence {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub home: bool,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// What the app sends when the phone enters or leaves the home geofence.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct GeofenceTransition {
+    /// enter or leave.
+    pub transition: String,
+}
+
+/// Where everyone in the user's household is, and the mode the user ended up in.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct HouseholdPresence {
+    pub mode: String,
+    pub members: Vec<UserPresence>,
+}
+
+/// Returns the user and everyone who shares a camera with them.
+pub fn get_household(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    let mut household = users_cameras::table
+        .filter(
+            users_cameras::camera_id.eq_any(
+                users_cameras::table
+                    .filter(users_cameras::user_id.eq(user_id))
+                    .filter(users_cameras::deleted_at.is_null())
+                    .filter(in_owners_tenant())
+                    .select(users_cameras::camera_id),
+            ),
+        )
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .select(users_cameras::user_id)
+        .distinct()
+        .load::<uuid::Uuid>(connection)?;
+
+    if !household.contains(&user_id) {
+        household.push(user_id);
+    }
+
+    Ok(household)
+}
+
+pub fn get_presences(
+    user_ids: &Vec<uuid::Uuid>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<UserPresence>> {
+    user_presence::table
+        .filter(user_presence::user_id.eq_any(user_ids))
+        .load::<UserPresence>(connection)
+}
+
+pub fn set_presence(
+    user_id: uuid::Uuid,
+    home: bool,
+    connection: &PgConnection,
+) -> QueryResult<UserPresence> {
+    let presence = UserPresence {
+        user_id,
+        home,
+        changed_at: Utc::now(),
+    };
+
+    diesel::insert_into(user_presence::table)
+        .values(&presence)
+        .on_conflict(user_presence::user_id)
+        .do_update()
+        .set(&presence)
+        .get_result(connection)
+}
+
+/// Records the user arriving or leaving, and switches the household's mode.
+/// The first person to arrive switches everyone who is in away mode to home mode (night mode is left alone),
+/// and the last person to leave switches everyone to away mode, arming the cameras.
+/// Household members who have never reported their location don't count as being home.
+pub fn apply_transition(
+    user_id: uuid::Uuid,
+    home: bool,
+    connection: &PgConnection,
+) -> QueryResult<HouseholdPresence> {
+    set_presence(user_id, home, connection)?;
+
+    let household = get_household(user_id, connection)?;
+    let members = get_presences(&household, connection)?;
+    let anyone_home = members.iter().any(|presence| presence.home);
+
+    for member in &household {
+        let mode = current_mode(*member, connection)?;
+
+        if home && mode == AWAY_MODE {
+            set_mode(*member, HOME_MODE, connection)?;
+        } else if !anyone_home && mode != AWAY_MODE {
+            set_mode(*member, AWAY_MODE, connection)?;
+        }
+    }
+
+    Ok(HouseholdPresence {
+        mode: current_mode(user_id, connection)?,
+        members,
+    })
+}
+
+/// Called by the app when the phone crosses the home geofence.
+#[openapi]
+#[post("/Geofence", format = "json", data = "<transition>")]
+pub fn report_geofence_transition(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    transition: Json<GeofenceTransition>,
+) -> Result<Json<HouseholdPresence>, ApiError> {
+    let home = match transition.transition.as_str() {
+        ENTER_TRANSITION => true,
+        LEAVE_TRANSITION => false,
+        _ => {
+            return Err(ApiError {
+                error: "Transition must be enter or leave",
+                code: "invalid_transition",
+                status: Status::UnprocessableEntity,
+                field: Some("transition"),
+            })
+        }
+    };
+
+    apply_transition(user_token.user_id, home, &conn)
+        .map(|presence| Json(presence))
+        .map_err(|error| {
+            error!(
+                "Failed to apply geofence transition for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to apply geofence transition",
+                code: "apply_geofence_transition_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[get("/Geofence")]
+pub fn get_household_presence(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<HouseholdPresence>, ApiError> {
+    get_household(user_token.user_id, &conn)
+        .and_then(|household| {
+            Ok(HouseholdPresence {
+                mode: current_mode(user_token.user_id, &conn)?,
+                members: get_presences(&household, &conn)?,
+            })
+        })
+        .map(|presence| Json(presence))
+        .map_err(|error| {
+            error!(
+                "Failed to get household presence for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get household presence",
+                code: "get_household_presence_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
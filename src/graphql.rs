@@ -0,0 +1,300 @@
+use crate::{
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::{latest_image_id, parse_camera_id, Camera},
+    event::{get_users_event, users_events_query, Event, EventFilter},
+    media_store::{media_store, MediaStore},
+    page::MAX_PAGE_SIZE,
+    settings::settings,
+    user,
+    user_tokens::UserToken,
+    users_cameras::{check_if_user_has_access_to_camera, get_cameras_users, get_users_cameras},
+    CameraServerDbConn,
+};
+
+use super::schema::events;
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::prelude::*;
+use juniper::{EmptyMutation, FieldError, FieldResult, RootNode, ID};
+use rocket::response::content::Html;
+use rocket::State;
+
+/// How many events a camera's events field returns if the query doesn't say.
+pub const DEFAULT_CAMERA_EVENTS: i32 = 10;
+
+/// Turn graphql in [server] on to serve /graphql (and GraphiQL at GET /graphql/explorer).
+pub fn graphql_enabled() -> bool {
+    settings().server.graphql
+}
+
+/// Everything is read through the user's token, the same way the REST routes do it,
+/// so the GraphQL schema can't see anything the user couldn't already fetch.
+pub struct Context {
+    pub conn: CameraServerDbConn,
+    pub user_token: UserToken,
+}
+
+impl juniper::Context for Context {}
+
+pub type Schema = RootNode<'static, Query, EmptyMutation<Context>>;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, EmptyMutation::new())
+}
+
+/// Keeps the error code from the REST API in the error's extensions.
+fn field_error(error: ApiError) -> FieldError {
+    let mut extensions = juniper::Object::with_capacity(1);
+    extensions.add_field("code", juniper::Value::scalar(error.code.to_string()));
+
+    FieldError::new(error.error, juniper::Value::Object(extensions))
+}
+
+fn database_error(
+    code: &'static str,
+    message: &'static str,
+    error: diesel::result::Error,
+) -> FieldError {
+    error!("{}! The error was {}", message, error);
+
+    field_error(ApiError {
+        error: message,
+        code,
+        status: rocket::http::Status::InternalServerError,
+        field: None,
+    })
+}
+
+/// Limits a first argument to something sensible.
+fn clamp_first(first: Option<i32>, default: i32) -> i64 {
+    (first.unwrap_or(default) as i64).max(1).min(MAX_PAGE_SIZE)
+}
+
+fn users_events(
+    context: &Context,
+    camera_id: Option<uuid::Uuid>,
+    first: Option<i32>,
+) -> FieldResult<Vec<EventNode>> {
+    let filter = EventFilter {
+        camera_id,
+        ..EventFilter::default()
+    };
+
+    users_events_query(context.user_token.user_id, &filter)
+        .order((events::occurred_at.desc(), events::event_id.desc()))
+        .limit(clamp_first(first, DEFAULT_CAMERA_EVENTS))
+        .load::<Event>(&*context.conn)
+        .map(|events| events.into_iter().map(EventNode::from_event).collect())
+        .map_err(|error| database_error("get_events_failed", "Failed to get events", error))
+}
+
+pub struct Query;
+
+#[juniper::graphql_object(Context = Context)]
+impl Query {
+    /// Every camera the user has access to.
+    fn cameras(context: &Context) -> FieldResult<Vec<CameraNode>> {
+        get_users_cameras(context.user_token.user_id, &context.conn)
+            .map(|cameras| cameras.into_iter().map(CameraNode).collect())
+            .map_err(|error| {
+                database_error("get_cameras_failed", "Failed to get list of cameras", error)
+            })
+    }
+
+    fn camera(context: &Context, id: ID) -> FieldResult<CameraNode> {
+        let camera_id = parse_camera_id(&id.to_string()).map_err(field_error)?;
+
+        check_if_user_has_access_to_camera(&context.conn, &context.user_token, camera_id)
+            .map_err(field_error)?;
+
+        crate::camera::get(camera_id, &context.conn)
+            .map(CameraNode)
+            .map_err(|error| database_error("get_camera_failed", "Failed to get camera", error))
+    }
+
+    /// The user's events across all of their cameras, newest first.
+    fn events(context: &Context, first: Option<i32>) -> FieldResult<Vec<EventNode>> {
+        users_events(context, None, first)
+    }
+
+    fn event(context: &Context, id: i32) -> FieldResult<EventNode> {
+        get_users_event(context.user_token.user_id, id, &context.conn)
+            .map(EventNode::from_event)
+            .map_err(field_error)
+    }
+}
+
+pub struct CameraNode(Camera);
+
+#[juniper::graphql_object(Context = Context, name = "Camera")]
+impl CameraNode {
+    fn id(&self) -> ID {
+        ID::new(self.0.camera_id.to_string())
+    }
+
+    fn name(&self) -> String {
+        self.0.name.clone()
+    }
+
+    fn online(&self) -> bool {
+        self.0.online
+    }
+
+    fn last_seen_at(&self) -> Option<DateTime<Utc>> {
+        self.0.last_seen_at
+    }
+
+    /// The camera's newest image, None if it hasn't uploaded any yet.
+    fn latest_snapshot(&self) -> Option<Snapshot> {
+        latest_image_id(&self.0.camera_id).map(|image_id| Snapshot::new(&self.0, image_id))
+    }
+
+    /// The camera's images, newest first.
+    fn snapshots(&self, first: Option<i32>) -> FieldResult<Vec<Snapshot>> {
+        let mut image_ids = media_store()
+            .list_images(&self.0.camera_id)
+            .map_err(|error| {
+                error!(
+                    "Failed to list images for camera {}! The error was {}",
+                    self.0.camera_id, error
+                );
+                field_error(ApiError {
+                    error: "Failed to get list of images",
+                    code: "get_images_failed",
+                    status: rocket::http::Status::InternalServerError,
+                    field: None,
+                })
+            })?;
+
+        image_ids.sort();
+
+        Ok(image_ids
+            .into_iter()
+            .rev()
+            .take(clamp_first(first, DEFAULT_CAMERA_EVENTS) as usize)
+            .map(|image_id| Snapshot::new(&self.0, image_id))
+            .collect())
+    }
+
+    /// The camera's events, newest first. Defaults to the last 10.
+    fn events(&self, context: &Context, first: Option<i32>) -> FieldResult<Vec<EventNode>> {
+        users_events(context, Some(self.0.camera_id), first)
+    }
+
+    /// Everyone the camera is shared with, including the user.
+    fn shared_with(&self, context: &Context) -> FieldResult<Vec<SharedUser>> {
+        let user_ids = get_cameras_users(self.0.camera_id, &context.conn).map_err(|error| {
+            database_error(
+                "get_camera_users_failed",
+                "Failed to get camera's users",
+                error,
+            )
+        })?;
+
+        user_ids
+            .into_iter()
+            .map(|user_id| {
+                user::get(user_id, &context.conn)
+                    .map(|user| SharedUser {
+                        user_id: ID::new(user.user_id.to_string()),
+                        username: user.username,
+                    })
+                    .map_err(|error| database_error("get_user_failed", "Failed to get user", error))
+            })
+            .collect()
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct Snapshot {
+    pub image_id: String,
+    pub taken_at: DateTime<Utc>,
+    /// Where to GET the JPEG from, with the same user_token header.
+    pub url: String,
+}
+
+impl Snapshot {
+    pub fn new(camera: &Camera, image_id: u64) -> Snapshot {
+        Snapshot {
+            image_id: image_id.to_string(),
+            // Image IDs are seconds since the epoch
+            taken_at: Utc.timestamp(image_id as i64, 0),
+            url: format!(
+                "{}/Cameras/{}/Images/{}",
+                API_PREFIX, camera.camera_id, image_id
+            ),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct SharedUser {
+    pub user_id: ID,
+    pub username: String,
+}
+
+/// GraphQL ints are 32 bit, so the image ID is a string like in the REST API.
+#[derive(juniper::GraphQLObject)]
+#[graphql(name = "Event")]
+pub struct EventNode {
+    pub event_id: i32,
+    pub camera_id: ID,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f64,
+    pub severity: String,
+    pub image_id: Option<String>,
+    pub audio_id: Option<i32>,
+    pub tamper_reason: Option<String>,
+}
+
+impl EventNode {
+    pub fn from_event(event: Event) -> EventNode {
+        EventNode {
+            event_id: event.event_id,
+            camera_id: ID::new(event.camera_id.to_string()),
+            event_type: event.event_type,
+            occurred_at: event.occurred_at,
+            confidence: event.confidence as f64,
+            severity: event.severity,
+            image_id: event.image_id.map(|image_id| image_id.to_string()),
+            audio_id: event.audio_id,
+            tamper_reason: event.tamper_reason,
+        }
+    }
+}
+
+#[get("/graphql?<request>")]
+pub fn get_graphql(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    request: juniper_rocket::GraphQLRequest,
+    schema: State<Schema>,
+) -> juniper_rocket::GraphQLResponse {
+    request.execute(&schema, &Context { conn, user_token })
+}
+
+#[post("/graphql", data = "<request>")]
+pub fn post_graphql(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    request: juniper_rocket::GraphQLRequest,
+    schema: State<Schema>,
+) -> juniper_rocket::GraphQLResponse {
+    request.execute(&schema, &Context { conn, user_token })
+}
+
+/// GraphiQL, for trying out queries. Requests from it still need a user_token header.
+#[get("/graphql/explorer")]
+pub fn graphiql() -> Html<String> {
+    juniper_rocket::graphiql_source(&format!("{}/graphql", API_PREFIX))
+}
+
+/// The routes to mount, none if GraphQL isn't enabled.
+pub fn routes() -> Vec<rocket::Route> {
+    if graphql_enabled() {
+        routes![get_graphql, post_graphql, graphiql]
+    } else {
+        Vec::new()
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{
+    api_error::ApiError,
+    bandwidth,
+    camera::{self, record_camera_contact, InsertableCamera},
+    camera_commands::take_pending,
+    camera_tokens, clock, config,
+    detection::ReportedDetection,
+    enums::token_error::TokenRejection,
+    event::{store_reported_event, ReportedEvent},
+    settings::settings,
+    tenant, tls, upload_limit, user_tokens,
+    zone::BoundingBox,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use std::io::Cursor;
+use std::thread;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status, Streaming};
+
+pub mod proto {
+    tonic::include_proto!("camera_server");
+}
+
+use proto::camera_device_server::{CameraDevice, CameraDeviceServer};
+
+/// Calls are handled on tokio's blocking threads, each with one of these connections.
+pub const MAX_CONNECTIONS: u32 = 10;
+
+/// Uploads that go over this are cancelled. They're held in memory until the last chunk arrives.
+pub const MAX_IMAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// The port the gRPC server listens on, set with grpc_port in [server]. Defaults to 50051.
+/// Rocket 0.4 only speaks HTTP/1, so gRPC is served separately to the API's port.
+pub fn grpc_port() -> u16 {
+    settings().server.grpc_port
+}
+
+/// Turns an ApiError into the gRPC status closest to its HTTP status.
+fn grpc_status(error: ApiError) -> Status {
+    let code = match error.status.code {
+        400 | 415 | 422 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        409 => Code::Aborted,
+        429 => Code::ResourceExhausted,
+        503 => Code::Unavailable,
+        _ => Code::Internal,
+    };
+
+    Status::new(code, error.error)
+}
+
+/// Reads a token from the call's metadata, where the REST API would have it as a header.
+fn token(metadata: &MetadataMap, key: &str) -> Result<uuid::Uuid, Status> {
+    let token = metadata
+        .get(key)
+        .ok_or_else(|| Status::unauthenticated(format!("No {} provided", key)))?;
+
+    token
+        .to_str()
+        .ok()
+        .and_then(|token| uuid::Uuid::parse_str(token).ok())
+        .ok_or_else(|| Status::invalid_argument(format!("Failed to parse {}", key)))
+}
+
+/// The tenant slug the call names in its tenant metadata, where the REST API would have the tenant header.
+/// Calls that don't name one are for the default tenant, as there's no Host to go by.
+fn tenant(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get(tenant::TENANT_HEADER)
+        .and_then(|slug| slug.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Turns a token turned away by the checks the REST API's guards make into the closest gRPC status.
+fn rejected(rejection: TokenRejection) -> Status {
+    let code = match rejection.status.code {
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        503 => Code::Unavailable,
+        _ => Code::Unauthenticated,
+    };
+
+    Status::new(code, rejection.message())
+}
+
+fn camera_id(
+    camera_token: uuid::Uuid,
+    tenant: Option<&str>,
+    connection: &PgConnection,
+) -> Result<uuid::Uuid, Status> {
+    let tenant_id = tenant::resolve_tenant(tenant, None, || Ok(connection));
+    camera_tokens::authorise_camera_token(camera_token, tenant_id, || connection).map_err(rejected)
+}
+
+fn bounding_box(bounding_box: proto::BoundingBox) -> BoundingBox {
+    BoundingBox {
+        x: bounding_box.x,
+        y: bounding_box.y,
+        width: bounding_box.width,
+        height: bounding_box.height,
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn reported_event(request: proto::ReportEventRequest) -> Result<ReportedEvent, Status> {
+    let occurred_at = request
+        .occurred_at
+        .ok_or_else(|| Status::invalid_argument("Events must have an occurred_at"))?;
+
+    let detections = request
+        .detections
+        .into_iter()
+        .map(|detection| {
+            Ok(ReportedDetection {
+                label: detection.label,
+                confidence: detection.confidence,
+                bounding_box: bounding_box(detection.bounding_box.ok_or_else(|| {
+                    Status::invalid_argument("Detections must have a bounding_box")
+                })?),
+            })
+        })
+        .collect::<Result<Vec<ReportedDetection>, Status>>()?;
+
+    Ok(ReportedEvent {
+        event_type: request.event_type,
+        occurred_at: Utc.timestamp(occurred_at.seconds, occurred_at.nanos.max(0) as u32),
+        confidence: request.confidence,
+        image_id: request.image_id,
+        audio_id: request.audio_id,
+        tamper_reason: request.tamper_reason,
+        severity: request.severity,
+        bounding_box: request.bounding_box.map(bounding_box),
+        detections,
+    })
+}
+
+pub struct CameraDeviceService {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl CameraDeviceService {
+    /// Diesel blocks, so calls run on a blocking thread rather than holding up the runtime.
+    async fn with_connection<T, F>(&self, work: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PgConnection) -> Result<T, Status> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let connection = pool.get().map_err(|error| {
+                error!(
+                    "Failed to get a database connection for gRPC! The error was {}",
+                    error
+                );
+                Status::unavailable("Failed to connect to the database")
+            })?;
+
+            work(&connection)
+        })
+        .await
+        .map_err(|error| {
+            error!("gRPC call panicked! The error was {}", error);
+            Status::internal("Failed to handle call")
+        })?
+    }
+}
+
+#[tonic::async_trait]
+impl CameraDevice for CameraDeviceService {
+    async fn register_camera(
+        &self,
+        request: Request<proto::RegisterCameraRequest>,
+    ) -> Result<Response<proto::RegisterCameraResponse>, Status> {
+        let user_token = token(request.metadata(), "user_token")?;
+        let tenant = tenant(request.metadata());
+        let name = request.into_inner().name;
+
+        self.with_connection(move |connection| {
+            let tenant_id = tenant::resolve_tenant(tenant.as_deref(), None, || Ok(connection));
+            let user_id =
+                user_tokens::authorise_user_token(user_token, tenant_id, false, || connection)
+                    .map(|user| user.user_id)
+                    .map_err(rejected)?;
+
+            camera::register_camera(InsertableCamera { name }, user_id, connection)
+                .map(|camera_token| {
+                    Response::new(proto::RegisterCameraResponse {
+                        camera_id: camera_token.camera_id.to_string(),
+                        camera_token: camera_token.camera_token.to_string(),
+                    })
+                })
+                .map_err(grpc_status)
+        })
+        .await
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<proto::HeartbeatRequest>,
+    ) -> Result<Response<proto::HeartbeatResponse>, Status> {
+        let camera_token = token(request.metadata(), "camera_token")?;
+        let tenant = tenant(request.metadata());
+        let device_time = request.into_inner().device_time;
+
+        self.with_connection(move |connection| {
+            let camera_id = camera_id(camera_token, tenant.as_deref(), connection)?;
+
+            record_camera_contact(camera_id, connection);
+
+            if let Some(device_time) = device_time {
+                clock::check_device_time(
+                    camera_id,
+                    Utc.timestamp(device_time.seconds, device_time.nanos.max(0) as u32),
+                    connection,
+                );
+            }
+
+            let config = config::get(camera_id, connection).map_err(|error| {
+                error!(
+                    "Failed to get config for camera {}! The error was {}",
+                    camera_id, error
+                );
+                Status::internal("Failed to get config")
+            })?;
+
+            let commands = take_pending(camera_id, connection).map_err(|error| {
+                error!(
+                    "Failed to get commands for camera {}! The error was {}",
+                    camera_id, error
+                );
+                Status::internal("Failed to get commands")
+            })?;
+
+            Ok(Response::new(proto::HeartbeatResponse {
+                interval: config.interval as i32,
+                commands: commands
+                    .into_iter()
+                    .map(|command| proto::Command {
+                        command_id: command.command_id,
+                        command: command.command,
+                    })
+                    .collect(),
+                server_time: Some(timestamp(Utc::now())),
+            }))
+        })
+        .await
+    }
+
+    async fn report_event(
+        &self,
+        request: Request<proto::ReportEventRequest>,
+    ) -> Result<Response<proto::ReportEventResponse>, Status> {
+        let camera_token = token(request.metadata(), "camera_token")?;
+        let tenant = tenant(request.metadata());
+        let reported_event = reported_event(request.into_inner())?;
+
+        self.with_connection(move |connection| {
+            let camera_id = camera_id(camera_token, tenant.as_deref(), connection)?;
+
+            record_camera_contact(camera_id, connection);
+
+            store_reported_event(camera_id, reported_event, connection)
+                .map(|event| {
+                    Response::new(match event {
+                        Some(event) => proto::ReportEventResponse {
+                            dropped: false,
+                            event_id: event.event_id,
+                        },
+                        None => proto::ReportEventResponse {
+                            dropped: true,
+                            event_id: 0,
+                        },
+                    })
+                })
+                .map_err(grpc_status)
+        })
+        .await
+    }
+
+    async fn upload_image(
+        &self,
+        request: Request<Streaming<proto::ImageChunk>>,
+    ) -> Result<Response<proto::UploadImageResponse>, Status> {
+        let camera_token = token(request.metadata(), "camera_token")?;
+        let tenant = tenant(request.metadata());
+        let mut chunks = request.into_inner();
+        let mut image = Vec::new();
+
+        while let Some(chunk) = chunks.message().await? {
+            if image.len() + chunk.data.len() > MAX_IMAGE_BYTES {
+                return Err(Status::invalid_argument("Images can be at most 16MiB"));
+            }
+
+            image.extend_from_slice(&chunk.data);
+        }
+
+        self.with_connection(move |connection| {
+            let camera_id = camera_id(camera_token, tenant.as_deref(), connection)?;
+
+            record_camera_contact(camera_id, connection);
+
+            let _upload_slot = upload_limit::acquire(camera_id).map_err(grpc_status)?;
+            bandwidth::record(camera_id, image.len() as u64, 0);
+            camera::store_uploaded_image(camera_id, None, &mut Cursor::new(image), connection)
+                .map(|image_id| Response::new(proto::UploadImageResponse { image_id }))
+                .map_err(grpc_status)
+        })
+        .await
+    }
+}
+
+/// Starts the gRPC server on its own thread, with its own runtime and connection pool.
+pub fn spawn_grpc_server(database_url: String) {
+    let address = ([0, 0, 0, 0], grpc_port()).into();
+
+    let pool = Pool::builder()
+        .max_size(MAX_CONNECTIONS)
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create gRPC connection pool!");
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to start gRPC runtime!");
+
+        let mut server = Server::builder();
+
+        if let Some(tls_config) = tls::grpc_tls_config() {
+            server = server
+                .tls_config(tls_config)
+                .expect("Failed to set up TLS for gRPC!");
+        }
+
+        let result = runtime.block_on(
+            server
+                .add_service(CameraDeviceServer::new(CameraDeviceService { pool }))
+                .serve(address),
+        );
+
+        if let Err(error) = result {
+            error!("gRPC server stopped! The error was {}", error);
+        }
+    });
+}
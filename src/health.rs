@@ -0,0 +1,341 @@
+use crate::{
+    cluster,
+    media_store::{media_store, MediaStore},
+    schema_check,
+    settings::settings,
+    shutdown,
+    worker::{worker_statuses, WorkerStatus},
+    CameraServerDbConn, CameraServerDbConnPool,
+};
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::Serialize;
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The migrations are compiled into the binary, so the diesel CLI and the migrations directory aren't needed to deploy
+embed_migrations!();
+
+/// Runs pending migrations while holding an advisory lock, so instances started together by a rolling deploy
+/// take turns rather than racing each other. The ones that wait find nothing left to run.
+fn migrate(connection: &PgConnection) -> Result<(), String> {
+    cluster::lock(MIGRATIONS_LOCK, connection).map_err(|error| {
+        format!(
+            "Failed to lock the database for migrating! The error was {}",
+            error
+        )
+    })?;
+
+    let result = embedded_migrations::run_with_output(connection, &mut io::stdout())
+        .map_err(|error| format!("Failed to run migrations! The error was {}", error));
+
+    if let Err(error) = cluster::unlock(MIGRATIONS_LOCK, connection) {
+        // It's given up anyway once the connection closes
+        warn!(
+            "Failed to unlock the database after migrating! The error was {}",
+            error
+        );
+    }
+
+    result
+}
+
+/// Brings the database up to date without printing anything, for when the server's startup isn't being used.
+pub fn run_migrations(
+    connection: &PgConnection,
+) -> Result<(), diesel_migrations::RunMigrationsError> {
+    embedded_migrations::run(connection)
+}
+
+/// The advisory lock held while migrating.
+pub const MIGRATIONS_LOCK: &str = "migrations";
+
+/// How long to wait before trying to connect again the first time the database can't be reached.
+/// The wait doubles after every failure, up to MAX_CONNECT_RETRY_SECONDS.
+pub const FIRST_CONNECT_RETRY_MS: u64 = 500;
+
+pub const MAX_CONNECT_RETRY_SECONDS: u64 = 30;
+
+/// /readyz fails if the pool can't hand out a connection within this long, rather than waiting out the pool's timeout.
+pub const READINESS_POOL_TIMEOUT_SECONDS: u64 = 1;
+
+/// How long (in seconds) to keep trying to reach the database at startup before exiting, set with
+/// startup_timeout_seconds in [database]. 0 keeps trying forever. Defaults to 300.
+pub fn startup_timeout_seconds() -> u64 {
+    settings().database.startup_timeout_seconds
+}
+
+static DATABASE_REACHED: AtomicBool = AtomicBool::new(false);
+static MIGRATIONS_APPLIED: AtomicBool = AtomicBool::new(false);
+static WORKERS_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Turn on skip_migrations in [database] to run migrations some other way, e.g. with the diesel CLI before deploying.
+/// The server then assumes the database is already up to date.
+pub fn skip_migrations() -> bool {
+    settings().database.skip_migrations
+}
+
+/// Waits for the database on a new thread and runs any pending migrations, then calls `start_workers`,
+/// as workers need the tables to exist. Postgres often isn't up yet when the server starts (e.g. under docker-compose),
+/// so connecting is retried with exponential backoff until startup_timeout_seconds() has passed, and then the server exits.
+/// The server is already taking requests while this runs, but /readyz fails until it's done.
+/// Migrations aren't run if skip_migrations() is on.
+pub fn spawn_startup<F>(database_url: String, start_workers: F)
+where
+    F: FnOnce(String) + Send + 'static,
+{
+    thread::spawn(move || {
+        if skip_migrations() {
+            info!("skip_migrations is on, so the database must already be migrated");
+        }
+
+        let timeout = startup_timeout_seconds();
+        let started_at = Instant::now();
+        let mut retry_in = Duration::from_millis(FIRST_CONNECT_RETRY_MS);
+
+        loop {
+            let result = PgConnection::establish(&database_url)
+                .map_err(|error| {
+                    format!("Failed to connect to the database! The error was {}", error)
+                })
+                .and_then(|connection| {
+                    DATABASE_REACHED.store(true, Ordering::SeqCst);
+
+                    if !skip_migrations() {
+                        migrate(&connection)?;
+                    }
+
+                    schema_check::check(&connection).map_err(|error| {
+                        format!(
+                            "Failed to check the schema version! The error was {}",
+                            error
+                        )
+                    })
+                });
+
+            match result {
+                Ok(()) => break,
+                Err(error) => {
+                    if timeout > 0 && started_at.elapsed() >= Duration::from_secs(timeout) {
+                        error!("{} Giving up after {} seconds", error, timeout);
+                        process::exit(1);
+                    }
+
+                    warn!("{} Trying again in {}ms", error, retry_in.as_millis());
+                    thread::sleep(retry_in);
+                    retry_in = (retry_in * 2).min(Duration::from_secs(MAX_CONNECT_RETRY_SECONDS));
+                }
+            }
+        }
+
+        MIGRATIONS_APPLIED.store(true, Ordering::SeqCst);
+
+        start_workers(database_url);
+
+        WORKERS_STARTED.store(true, Ordering::SeqCst);
+    });
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub ok: bool,
+    /// Why the check failed, None if it passed.
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn from_result<T, E: std::fmt::Display>(result: Result<T, E>) -> Check {
+        match result {
+            Ok(_) => Check {
+                ok: true,
+                error: None,
+            },
+            Err(error) => Check {
+                ok: false,
+                error: Some(error.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// "ok" if every check passed, otherwise "unhealthy".
+    pub status: &'static str,
+    pub database: Check,
+    pub storage: Check,
+    pub workers: Vec<WorkerStatus>,
+}
+
+impl HealthReport {
+    pub fn healthy(&self) -> bool {
+        self.database.ok && self.storage.ok && self.workers.iter().all(|worker| worker.alive)
+    }
+}
+
+/// 200 if the check passed, otherwise 503 so load balancers take the instance out of rotation.
+fn respond<'r, T: Serialize>(body: T, healthy: bool, req: &Request) -> response::Result<'r> {
+    let status = if healthy {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    Response::build_from(Json(body).respond_to(&req)?)
+        .status(status)
+        .ok()
+}
+
+impl<'r> Responder<'r> for HealthReport {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let healthy = self.healthy();
+        respond(self, healthy, req)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReadinessReport {
+    /// "ready", "waiting_for_database", "starting" or "shutting_down".
+    pub status: &'static str,
+    /// Whether startup has connected to the database yet.
+    pub database_reached: bool,
+    pub migrations_applied: bool,
+    /// Whether a pooled database connection works.
+    pub pool: Check,
+    pub workers_started: bool,
+    /// Whether the database's schema matches the server's. See schema_check::check().
+    pub schema: Check,
+    pub shutting_down: bool,
+}
+
+impl ReadinessReport {
+    pub fn ready(&self) -> bool {
+        self.database_reached
+            && self.migrations_applied
+            && self.pool.ok
+            && self.workers_started
+            // In read-only mode the instance can still serve reads
+            && (self.schema.ok || schema_check::schema_mismatch() == schema_check::READ_ONLY)
+            && !self.shutting_down
+    }
+}
+
+impl<'r> Responder<'r> for ReadinessReport {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let ready = self.ready();
+        respond(self, ready, req)
+    }
+}
+
+#[derive(Serialize)]
+pub struct LivenessReport {
+    pub status: &'static str,
+}
+
+/// The pool guard failing (e.g. every connection is broken) is a failed check rather than an error,
+/// so the report still gets sent.
+pub fn check_database(conn: Option<CameraServerDbConn>) -> Check {
+    match conn {
+        Some(conn) => Check::from_result(diesel::sql_query("SELECT 1").execute(&*conn)),
+        None => Check {
+            ok: false,
+            error: Some(String::from("Failed to get a connection from the pool")),
+        },
+    }
+}
+
+pub fn check_storage() -> Check {
+    Check::from_result(media_store().list_cameras())
+}
+
+/// Checks the database, the image store and that no background worker has died or got stuck.
+/// Doesn't need a token, and isn't versioned, so it's always at /health.
+#[get("/health")]
+pub fn health(conn: Option<CameraServerDbConn>) -> HealthReport {
+    let mut report = HealthReport {
+        status: "ok",
+        database: check_database(conn),
+        storage: check_storage(),
+        workers: worker_statuses(),
+    };
+
+    if !report.healthy() {
+        report.status = "unhealthy";
+    }
+
+    report
+}
+
+/// Whether the process is up and handling requests. This never checks the database, so an outage
+/// doesn't get every instance restarted.
+#[get("/livez")]
+pub fn livez() -> Json<LivenessReport> {
+    Json(LivenessReport { status: "ok" })
+}
+
+/// Whether the instance should be sent traffic: startup has reached the database and run the migrations,
+/// the pool hands out working connections, the background workers have been started, the schema matches
+/// (unless schema_mismatch is read_only) and it isn't shutting down.
+#[get("/readyz")]
+pub fn readyz(pool: State<CameraServerDbConnPool>) -> ReadinessReport {
+    let database_reached = DATABASE_REACHED.load(Ordering::SeqCst);
+
+    // Until startup has reached the database, asking the pool would only wait out its timeout
+    let pool = if database_reached {
+        Check::from_result(
+            pool.0
+                .get_timeout(Duration::from_secs(READINESS_POOL_TIMEOUT_SECONDS))
+                .map_err(|error| error.to_string())
+                .and_then(|conn| {
+                    diesel::sql_query("SELECT 1")
+                        .execute(&*conn)
+                        .map_err(|error| error.to_string())
+                }),
+        )
+    } else {
+        Check {
+            ok: false,
+            error: Some(String::from("Waiting for the database")),
+        }
+    };
+
+    let mut report = ReadinessReport {
+        status: "ready",
+        database_reached,
+        migrations_applied: MIGRATIONS_APPLIED.load(Ordering::SeqCst),
+        pool,
+        workers_started: WORKERS_STARTED.load(Ordering::SeqCst),
+        schema: match schema_check::mismatch() {
+            Some(mismatch) => Check {
+                ok: false,
+                error: Some(mismatch),
+            },
+            None => Check {
+                ok: true,
+                error: None,
+            },
+        },
+        shutting_down: shutdown::shutting_down(),
+    };
+
+    if report.shutting_down {
+        report.status = "shutting_down";
+    } else if !report.database_reached {
+        report.status = "waiting_for_database";
+    } else if !report.schema.ok {
+        report.status = "schema_mismatch";
+    } else if !report.ready() {
+        report.status = "starting";
+    }
+
+    report
+}
@@ -0,0 +1,165 @@
+use crate::{
+    camera::{self, Camera},
+    event::REPORTED_EVENT_TYPES,
+    media_store::{media_store, MediaStore},
+    mqtt::{self, MqttPublisher},
+    notification::display_event_type,
+    settings::settings,
+    worker,
+};
+
+use serde_json::json;
+use std::io::Read;
+use std::time::Duration;
+
+/// How long a binary sensor stays on after an event, since events don't say when they end.
+pub const SENSOR_OFF_DELAY_SECONDS: u64 = 30;
+
+/// Turn discovery in [home_assistant] on to have cameras show up in Home Assistant through MQTT discovery. Needs host
+/// in [mqtt].
+pub fn discovery_enabled() -> bool {
+    settings().home_assistant.discovery
+}
+
+/// The topic prefix Home Assistant listens for discovery on, set with discovery_prefix in [home_assistant].
+/// Defaults to homeassistant, which is also Home Assistant's default.
+pub fn discovery_prefix() -> String {
+    settings().home_assistant.discovery_prefix.clone()
+}
+
+/// The Home Assistant device class for a binary sensor of the event type, if there's one that fits.
+pub fn device_class(event_type: &str) -> Option<&'static str> {
+    match event_type {
+        "motion" => Some("motion"),
+        "person" => Some("occupancy"),
+        "glass_break" => Some("safety"),
+        "smoke_alarm" => Some("smoke"),
+        "loud_noise" => Some("sound"),
+        "tamper" => Some("tamper"),
+        _ => None,
+    }
+}
+
+fn discovery_topic(component: &str, object_id: &str) -> String {
+    format!("{}/{}/{}/config", discovery_prefix(), component, object_id)
+}
+
+/// The part of every discovery payload that groups the camera's entities into one Home Assistant device.
+fn device(camera: &Camera) -> serde_json::Value {
+    json!({
+        "identifiers": [camera.camera_id.to_string()],
+        "name": camera.name,
+        "manufacturer": "camera-server",
+    })
+}
+
+/// Publishes the discovery payloads for the camera: a camera entity showing the latest image, and a binary sensor
+/// for every event type a camera can report. They are retained, so Home Assistant picks them up whenever it connects.
+pub fn publish_camera_discovery(publisher: &MqttPublisher, camera: &Camera) {
+    let availability_topic = publisher.topic(&camera.camera_id, "status");
+
+    let camera_payload = json!({
+        "name": camera.name,
+        "unique_id": format!("{}_camera", camera.camera_id),
+        "topic": publisher.topic(&camera.camera_id, "image"),
+        "availability_topic": availability_topic,
+        "payload_available": "online",
+        "payload_not_available": "offline",
+        "device": device(camera),
+    });
+
+    publisher.publish(
+        discovery_topic("camera", &camera.camera_id.to_string()),
+        true,
+        camera_payload.to_string().into(),
+    );
+
+    for event_type in REPORTED_EVENT_TYPES.iter() {
+        let object_id = format!("{}_{}", camera.camera_id, event_type);
+
+        // Events are published as JSON, so any message on the topic means the sensor is on
+        let mut sensor_payload = json!({
+            "name": format!("{} {}", camera.name, display_event_type(event_type).to_lowercase()),
+            "unique_id": object_id,
+            "state_topic": publisher.topic(&camera.camera_id, event_type),
+            "value_template": "ON",
+            "payload_on": "ON",
+            "off_delay": SENSOR_OFF_DELAY_SECONDS,
+            "availability_topic": availability_topic,
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "json_attributes_topic": publisher.topic(&camera.camera_id, event_type),
+            "device": device(camera),
+        });
+
+        if let Some(device_class) = device_class(event_type) {
+            sensor_payload["device_class"] = json!(device_class);
+        }
+
+        publisher.publish(
+            discovery_topic("binary_sensor", &object_id),
+            true,
+            sensor_payload.to_string().into(),
+        );
+    }
+}
+
+/// Announces the camera to Home Assistant, if discovery is turned on.
+pub fn announce_camera(camera: &Camera) {
+    if !discovery_enabled() {
+        return;
+    }
+
+    if let Some(publisher) = mqtt::publisher() {
+        publish_camera_discovery(publisher, camera);
+    }
+}
+
+/// Publishes the image to <prefix>/<camera_id>/image for the Home Assistant camera entity, if discovery is turned on.
+/// Retained so Home Assistant shows the latest image straight away.
+pub fn publish_image(camera_id: &uuid::Uuid, image_id: u64) {
+    let publisher = match (discovery_enabled(), mqtt::publisher()) {
+        (true, Some(publisher)) => publisher,
+        _ => return,
+    };
+
+    let mut image = Vec::new();
+
+    if let Err(error) = media_store()
+        .open_image(camera_id, image_id)
+        .and_then(|mut file| file.read_to_end(&mut image))
+    {
+        error!(
+            "Failed to read image {} from camera {} for Home Assistant! The error was {}",
+            image_id, camera_id, error
+        );
+        return;
+    }
+
+    publisher.publish(publisher.topic(camera_id, "image"), true, image);
+}
+
+/// Starts the thread that announces every camera to Home Assistant once an hour, so renamed cameras are updated
+/// and a broker that lost its retained messages is repopulated. Does nothing unless discovery and MQTT are turned on.
+pub fn spawn_discovery_worker(database_url: String) {
+    if !discovery_enabled() || mqtt::publisher().is_none() {
+        return;
+    }
+
+    worker::spawn_worker(
+        "Home Assistant discovery",
+        Duration::from_secs(60 * 60),
+        database_url,
+        |connection| match camera::all(connection) {
+            Ok(cameras) => {
+                for camera in &cameras {
+                    announce_camera(camera);
+                }
+            }
+            Err(error) => error!(
+                "Failed to get cameras for Home Assistant discovery! The error was {}",
+                error
+            ),
+        },
+    );
+}
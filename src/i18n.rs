@@ -0,0 +1,345 @@
+use crate::{
+    api_error::ApiError,
+    audit,
+    cache::{self, cache},
+    notification::display_event_type,
+    settings::settings,
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::users;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use once_cell::sync::Lazy;
+use rocket::http::Status;
+use rocket::request::FromRequest;
+use rocket::{get, put, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::ErrorKind;
+
+/// What everything is written in, so it never needs translating. Anything a locale doesn't translate is sent in it.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// One language's translations, read from <locale>.toml in locale_directory.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Catalogue {
+    /// Keyed by the stable error code, like camera_not_found or account_suspended. Errors whose code isn't here use
+    /// the translation for their status's code, like not_found.
+    errors: HashMap<String, String>,
+    /// Keyed by an error's English message, for the ones that need more than their code says. An error whose
+    /// message has been reworded falls back to the translation for its code.
+    messages: HashMap<String, String>,
+    /// Notifications, digests and event types, keyed like notification.offline_title. {name} is replaced with
+    /// the value it stands for, so translations can put them wherever the language needs.
+    text: HashMap<String, String>,
+}
+
+static CATALOGUES: Lazy<HashMap<String, Catalogue>> = Lazy::new(load_catalogues);
+
+fn load_catalogues() -> HashMap<String, Catalogue> {
+    let directory = &settings().i18n.locale_directory;
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(error) => {
+            if error.kind() != ErrorKind::NotFound {
+                error!(
+                    "Failed to read locales from {}! The error was {}",
+                    directory, error
+                );
+            }
+            return HashMap::new();
+        }
+    };
+
+    let mut catalogues = HashMap::new();
+
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.extension() != Some(OsStr::new("toml")) {
+            continue;
+        }
+        let locale = match path.file_stem().and_then(OsStr::to_str) {
+            Some(locale) => locale.to_lowercase().replace('_', "-"),
+            None => continue,
+        };
+
+        match fs::read_to_string(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|contents| {
+                toml::from_str::<Catalogue>(&contents).map_err(|error| error.to_string())
+            }) {
+            Ok(catalogue) => {
+                info!("Loaded translations for {}", locale);
+                catalogues.insert(locale, catalogue);
+            }
+            Err(error) => error!(
+                "Failed to load translations from {}! The error was {}",
+                path.display(),
+                error
+            ),
+        }
+    }
+
+    catalogues
+}
+
+/// Every locale there are translations for, English first.
+pub fn supported_locales() -> Vec<String> {
+    let mut locales: Vec<String> = CATALOGUES.keys().cloned().collect();
+    locales.sort();
+    locales.insert(0, DEFAULT_LOCALE.to_string());
+    locales
+}
+
+/// The translated locale that best matches a language tag: the tag itself, or else its language, so en-GB is
+/// English and fr-CA is French unless there's a fr-ca.toml. None if neither is translated.
+pub fn supported(locale: &str) -> Option<&'static str> {
+    let locale = locale.trim().to_lowercase().replace('_', "-");
+    let language = locale.split('-').next().unwrap_or_default();
+
+    for candidate in &[locale.as_str(), language] {
+        if *candidate == DEFAULT_LOCALE {
+            return Some(DEFAULT_LOCALE);
+        }
+        if let Some((key, _)) = CATALOGUES.get_key_value(*candidate) {
+            return Some(key.as_str());
+        }
+    }
+
+    None
+}
+
+/// The most preferred translated locale in an Accept-Language header.
+fn accept_language(header: &str) -> Option<&'static str> {
+    let mut ranges: Vec<(f32, &str)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = match parts.find_map(|part| part.trim().strip_prefix("q=")) {
+                Some(quality) => quality.parse().ok()?,
+                None => 1.0,
+            };
+            Some((quality, tag))
+        })
+        .filter(|(quality, tag)| *quality > 0.0 && !tag.is_empty() && *tag != "*")
+        .collect();
+
+    // Stable, so ranges with the same quality keep the order they were sent in
+    ranges.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    ranges.into_iter().find_map(|(_, tag)| supported(tag))
+}
+
+/// The user's locale, if they've set one that's still translated. Cached, as every error sent to them needs it.
+fn cached_locale(
+    user_id: uuid::Uuid,
+    load: impl FnOnce() -> Option<Option<String>>,
+) -> Option<&'static str> {
+    let key = cache::user_locale_key(user_id);
+    let locale = match cache().get(&key) {
+        Some(locale) => locale,
+        None => {
+            let locale = load()?.unwrap_or_default();
+            cache().set(&key, &locale, cache::cache_ttl());
+            locale
+        }
+    };
+
+    supported(&locale)
+}
+
+fn load_locale(user_id: uuid::Uuid, connection: &PgConnection) -> Option<Option<String>> {
+    users::table
+        .find(user_id)
+        .select(users::locale)
+        .get_result::<Option<String>>(connection)
+        .map_err(|error| {
+            error!(
+                "Failed to get the locale of user {}! The error was {}",
+                user_id, error
+            )
+        })
+        .ok()
+}
+
+/// The locale notifications and emails are sent to the user in.
+pub fn user_locale(user_id: uuid::Uuid, connection: &PgConnection) -> &'static str {
+    if CATALOGUES.is_empty() {
+        return DEFAULT_LOCALE;
+    }
+
+    cached_locale(user_id, || load_locale(user_id, connection)).unwrap_or(DEFAULT_LOCALE)
+}
+
+/// Stored in a request's local cache, so its locale is only worked out once.
+struct RequestLocale(&'static str);
+
+fn resolve(request: &Request) -> &'static str {
+    let stored = audit::current_user().and_then(|user_id| {
+        cached_locale(user_id, || {
+            let connection = CameraServerDbConn::from_request(request).succeeded()?;
+            load_locale(user_id, &*connection)
+        })
+    });
+
+    stored
+        .or_else(|| {
+            request
+                .headers()
+                .get_one("Accept-Language")
+                .and_then(accept_language)
+        })
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+/// The locale to answer a request in: its user's if they've set one, or else the best match for Accept-Language.
+/// Only worth calling once the request's guards have run, so the user is known.
+pub fn request_locale(request: &Request) -> &'static str {
+    if CATALOGUES.is_empty() {
+        return DEFAULT_LOCALE;
+    }
+
+    request.local_cache(|| RequestLocale(resolve(request))).0
+}
+
+/// An error's message in `locale`: its own translation, or else the first of its codes' that there is, or else
+/// the English message.
+pub fn error_message(locale: &str, codes: &[&str], english: &'static str) -> &'static str {
+    match CATALOGUES.get(locale) {
+        Some(catalogue) => catalogue
+            .messages
+            .get(english)
+            .or_else(|| codes.iter().find_map(|code| catalogue.errors.get(*code)))
+            .map(String::as_str)
+            .unwrap_or(english),
+        None => english,
+    }
+}
+
+/// Fills in a template's {name}s. Each is only replaced once, so values with braces in them are left alone.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &rest[1..end])
+                .map(|(_, value)| (end, *value))
+        });
+
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+/// The text for `key` in `locale`, or `english` if it isn't translated, with its {name}s filled in from `values`.
+pub fn text(locale: &str, key: &str, english: &str, values: &[(&str, &str)]) -> String {
+    let template = CATALOGUES
+        .get(locale)
+        .and_then(|catalogue| catalogue.text.get(key))
+        .map(String::as_str)
+        .unwrap_or(english);
+
+    fill(template, values)
+}
+
+/// An event type for showing people, like "Glass break", from event_type.glass_break if it's translated.
+pub fn event_type(locale: &str, event_type: &str) -> String {
+    CATALOGUES
+        .get(locale)
+        .and_then(|catalogue| catalogue.text.get(&format!("event_type.{}", event_type)))
+        .cloned()
+        .unwrap_or_else(|| display_event_type(event_type))
+}
+
+/// The language a user gets error messages, notifications and digests in.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct AccountLocale {
+    /// One of GET /Locales. None is English, unless a request's Accept-Language asks for something else.
+    pub locale: Option<String>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get locale! The error was {}", error);
+    ApiError {
+        error: "Failed to get locale",
+        code: "get_locale_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Every locale the server has translations for.
+#[openapi]
+#[get("/Locales")]
+pub fn get_locales() -> Json<Vec<String>> {
+    Json(supported_locales())
+}
+
+#[openapi]
+#[get("/Account/Locale")]
+pub fn get_locale(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<AccountLocale>, ApiError> {
+    users::table
+        .find(user_token.user_id)
+        .select(users::locale)
+        .get_result::<Option<String>>(&*conn)
+        .map(|locale| Json(AccountLocale { locale }))
+        .map_err(database_error)
+}
+
+#[openapi]
+#[put("/Account/Locale", format = "json", data = "<account_locale>")]
+pub fn update_locale(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    account_locale: Json<AccountLocale>,
+) -> Result<Json<AccountLocale>, ApiError> {
+    let locale = match &account_locale.locale {
+        Some(locale) => Some(
+            supported(locale)
+                .ok_or(ApiError {
+                    error: "Locale must be one of GET /Locales",
+                    code: "invalid_locale",
+                    status: Status::UnprocessableEntity,
+                    field: Some("locale"),
+                })?
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    diesel::update(users::table.find(user_token.user_id))
+        .set(users::locale.eq(&locale))
+        .execute(&*conn)
+        .map_err(database_error)?;
+    cache().delete(&cache::user_locale_key(user_token.user_id));
+
+    Ok(Json(AccountLocale { locale }))
+}
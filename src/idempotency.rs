@@ -0,0 +1,371 @@
+use crate::{
+    api_error::ErrorBody,
+    api_version::API_PREFIX,
+    rate_limit::{client_key, RateLimitStatus},
+    request_id, worker, CameraServerDbConn,
+};
+
+use super::schema::idempotency_keys;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::{Data, Outcome, Request, Response};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::time::Duration;
+
+/// How long a key is remembered for. Retrying with it after this runs the request again.
+pub const KEY_LIFETIME_HOURS: i64 = 24;
+
+/// A request that still hasn't got a response after this long is assumed to have died with the server,
+/// so a retry runs it again instead of getting a 409.
+pub const IN_FLIGHT_TIMEOUT_SECONDS: i64 = 5 * 60;
+
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// Responses bigger than this aren't stored, and retries of their request run again.
+pub const MAX_STORED_BODY_BYTES: usize = 64 * 1024;
+
+/// Replayed and rejected requests are routed here instead. Nothing is mounted at it, so no handler runs for them.
+const REPLAY_PATH: &str = "/IdempotentReplay";
+
+/// A key that has been used. status is None while the first request with it is still being handled.
+#[derive(Queryable)]
+pub struct IdempotencyKey {
+    pub client: String,
+    pub idempotency_key: String,
+    pub method: String,
+    pub uri: String,
+    pub status: Option<i16>,
+    pub content_type: Option<String>,
+    pub body: Option<Vec<u8>>,
+    pub created_at: DateTime<Utc>,
+    /// See body_hash(). None for keys claimed before bodies were hashed.
+    pub body_hash: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "idempotency_keys"]
+pub struct InsertableIdempotencyKey {
+    pub client: String,
+    pub idempotency_key: String,
+    pub method: String,
+    pub uri: String,
+    pub body_hash: String,
+}
+
+/// What to do with a request, stored in its local cache for on_response.
+enum IdempotencyState {
+    /// No Idempotency-Key, or it couldn't be checked.
+    None,
+    /// The first request with the key. Its response is stored.
+    Recording {
+        client: String,
+        idempotency_key: String,
+    },
+    Replay(IdempotencyKey),
+    /// With the status, error code and message to answer with.
+    Rejected(Status, &'static str, &'static str),
+}
+
+/// What a retry's body is compared with. Fairings can only see the start of the body, so it's the hash of that
+/// and the Content-Length, which catches a retry sending something else in every case but a body that only differs
+/// after the first 512 bytes and is the same length.
+fn body_hash(request: &Request, data: &Data) -> String {
+    let content_length = request
+        .headers()
+        .get_one("Content-Length")
+        .unwrap_or_default();
+
+    hex::encode(
+        Sha256::new()
+            .chain(content_length)
+            .chain(b"\n")
+            .chain(data.peek())
+            .finalize(),
+    )
+}
+
+/// Claims the key for the request, or works out what to send back if it has been used before.
+fn claim_key(
+    client: String,
+    idempotency_key: String,
+    method: String,
+    uri: String,
+    body_hash: String,
+    connection: &PgConnection,
+) -> QueryResult<IdempotencyState> {
+    let inserted = diesel::insert_into(idempotency_keys::table)
+        .values(InsertableIdempotencyKey {
+            client: client.clone(),
+            idempotency_key: idempotency_key.clone(),
+            method: method.clone(),
+            uri: uri.clone(),
+            body_hash: body_hash.clone(),
+        })
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+
+    if inserted == 1 {
+        return Ok(IdempotencyState::Recording {
+            client,
+            idempotency_key,
+        });
+    }
+
+    let existing = idempotency_keys::table
+        .find((&client, &idempotency_key))
+        .get_result::<IdempotencyKey>(connection)?;
+
+    let now = Utc::now();
+    let expired = existing.created_at < now - ChronoDuration::hours(KEY_LIFETIME_HOURS);
+    let abandoned = existing.status.is_none()
+        && existing.created_at < now - ChronoDuration::seconds(IN_FLIGHT_TIMEOUT_SECONDS);
+
+    if expired || abandoned {
+        diesel::update(idempotency_keys::table.find((&client, &idempotency_key)))
+            .set((
+                idempotency_keys::method.eq(&method),
+                idempotency_keys::uri.eq(&uri),
+                idempotency_keys::body_hash.eq(Some(&body_hash)),
+                idempotency_keys::status.eq(None::<i16>),
+                idempotency_keys::content_type.eq(None::<String>),
+                idempotency_keys::body.eq(None::<Vec<u8>>),
+                idempotency_keys::created_at.eq(now),
+            ))
+            .execute(connection)?;
+
+        return Ok(IdempotencyState::Recording {
+            client,
+            idempotency_key,
+        });
+    }
+
+    let different_body = existing
+        .body_hash
+        .as_ref()
+        .map_or(false, |existing_hash| *existing_hash != body_hash);
+
+    if existing.method != method || existing.uri != uri || different_body {
+        Ok(IdempotencyState::Rejected(
+            Status::UnprocessableEntity,
+            "idempotency_key_reused",
+            "Idempotency-Key was already used for a different request",
+        ))
+    } else if existing.status.is_none() {
+        Ok(IdempotencyState::Rejected(
+            Status::Conflict,
+            "idempotency_key_in_use",
+            "A request with this Idempotency-Key is still being handled",
+        ))
+    } else {
+        Ok(IdempotencyState::Replay(existing))
+    }
+}
+
+fn release_key(client: &str, idempotency_key: &str, connection: &PgConnection) {
+    if let Err(error) =
+        diesel::delete(idempotency_keys::table.find((client, idempotency_key))).execute(connection)
+    {
+        error!(
+            "Failed to release Idempotency-Key {}! The error was {}",
+            idempotency_key, error
+        );
+    }
+}
+
+/// Stores the response to the first request with a key. Server errors and rate limiting aren't stored,
+/// so retrying after them runs the request again.
+fn record_response(
+    client: &str,
+    idempotency_key: &str,
+    response: &mut Response,
+    connection: &PgConnection,
+) {
+    let status = response.status();
+
+    if status.code >= 500 || status == Status::TooManyRequests {
+        release_key(client, idempotency_key, connection);
+        return;
+    }
+
+    let body = response.body_bytes().unwrap_or_default();
+
+    if body.len() > MAX_STORED_BODY_BYTES {
+        debug!(
+            "Not storing {} byte response for Idempotency-Key {}",
+            body.len(),
+            idempotency_key
+        );
+        release_key(client, idempotency_key, connection);
+    } else if let Err(error) =
+        diesel::update(idempotency_keys::table.find((client, idempotency_key)))
+            .set((
+                idempotency_keys::status.eq(Some(status.code as i16)),
+                idempotency_keys::content_type.eq(response
+                    .content_type()
+                    .map(|content_type| content_type.to_string())),
+                idempotency_keys::body.eq(Some(body.as_slice())),
+            ))
+            .execute(connection)
+    {
+        error!(
+            "Failed to store response for Idempotency-Key {}! The error was {}",
+            idempotency_key, error
+        );
+        release_key(client, idempotency_key, connection);
+    }
+
+    response.set_sized_body(Cursor::new(body));
+}
+
+/// Lets clients safely retry a POST by sending the same Idempotency-Key header: the first response is
+/// stored, and retries get it back instead of running the request again. Keys are per client, so two
+/// cameras picking the same key don't clash. Reusing a key for a different method, URI or body gets a 422.
+pub struct Idempotency;
+
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency keys",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, data: &Data) {
+        if request.method() != Method::Post {
+            return;
+        }
+
+        let idempotency_key = match request.headers().get_one("Idempotency-Key") {
+            Some(idempotency_key) => idempotency_key.to_string(),
+            None => return,
+        };
+
+        // Rate limited requests are never handled, so they shouldn't use up the key
+        if let Some(status) = request.local_cache(|| None::<RateLimitStatus>) {
+            if status.limited {
+                return;
+            }
+        }
+
+        let state = if idempotency_key.len() == 0 || idempotency_key.len() > MAX_KEY_LENGTH {
+            IdempotencyState::Rejected(
+                Status::UnprocessableEntity,
+                "invalid_idempotency_key",
+                "Idempotency-Key must be between 1 and 255 characters",
+            )
+        } else {
+            match request.guard::<CameraServerDbConn>() {
+                Outcome::Success(conn) => {
+                    let client = hex::encode(Sha256::digest(client_key(request).as_bytes()));
+
+                    claim_key(
+                        client,
+                        idempotency_key,
+                        request.method().as_str().to_string(),
+                        request.uri().to_string(),
+                        body_hash(request, data),
+                        &conn,
+                    )
+                    .unwrap_or_else(|error| {
+                        error!("Failed to check Idempotency-Key! The error was {}", error);
+                        IdempotencyState::None
+                    })
+                }
+                _ => {
+                    error!("Failed to get a database connection to check Idempotency-Key");
+                    IdempotencyState::None
+                }
+            }
+        };
+
+        if let IdempotencyState::Replay(_) | IdempotencyState::Rejected(_, _, _) = state {
+            match Origin::parse_owned(format!("{}{}", API_PREFIX, REPLAY_PATH)) {
+                Ok(origin) => request.set_uri(origin),
+                Err(error) => error!(
+                    "Failed to reroute idempotent request! The error was {}",
+                    error
+                ),
+            }
+        }
+
+        request.local_cache(|| state);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        match request.local_cache(|| IdempotencyState::None) {
+            IdempotencyState::None => {}
+            IdempotencyState::Recording {
+                client,
+                idempotency_key,
+            } => match request.guard::<CameraServerDbConn>() {
+                Outcome::Success(conn) => record_response(client, idempotency_key, response, &conn),
+                _ => error!(
+                    "Failed to get a database connection to store the response for Idempotency-Key {}",
+                    idempotency_key
+                ),
+            },
+            IdempotencyState::Replay(stored) => {
+                response.set_status(
+                    stored
+                        .status
+                        .and_then(|status| Status::from_code(status as u16))
+                        .unwrap_or(Status::Ok),
+                );
+                response.remove_header("Content-Type");
+
+                if let Some(content_type) = stored
+                    .content_type
+                    .as_ref()
+                    .and_then(|content_type| ContentType::parse_flexible(content_type))
+                {
+                    response.set_header(content_type);
+                }
+
+                response.set_header(Header::new("Idempotent-Replayed", "true"));
+                response.set_sized_body(Cursor::new(stored.body.clone().unwrap_or_default()));
+            }
+            IdempotencyState::Rejected(status, code, message) => {
+                let body = ErrorBody {
+                    code: *code,
+                    message: *message,
+                    details: Vec::new(),
+                    request_id: request_id::current(),
+                }.localized(request);
+
+                response.set_status(*status);
+                response.set_header(ContentType::JSON);
+                response.set_sized_body(Cursor::new(
+                    serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+                ));
+            }
+        }
+    }
+}
+
+/// Starts the thread that forgets keys once they're older than KEY_LIFETIME_HOURS.
+pub fn spawn_expiry_worker(database_url: String) {
+    worker::spawn_worker(
+        "Idempotency key expiry",
+        Duration::from_secs(60 * 60),
+        database_url,
+        |connection| {
+            let expired_before = Utc::now() - ChronoDuration::hours(KEY_LIFETIME_HOURS);
+
+            if let Err(error) = diesel::delete(
+                idempotency_keys::table.filter(idempotency_keys::created_at.lt(expired_before)),
+            )
+            .execute(connection)
+            {
+                error!(
+                    "Failed to delete expired idempotency keys! The error was {}",
+                    error
+                );
+            }
+        },
+    );
+}
@@ -0,0 +1,287 @@
+use crate::{
+    admin::{admin_user_ids, AdminToken},
+    api_error::ApiError,
+    audit,
+    page::{Page, PageQuery},
+    soft_delete::{not_found_or_database_error, parse_user_id},
+    user,
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::impersonation_tokens;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{delete, get, post, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Impersonation tokens last this long unless the admin asks for less.
+pub const DEFAULT_IMPERSONATION_MINUTES: i64 = 15;
+
+/// Impersonation tokens can't last any longer than this.
+pub const MAX_IMPERSONATION_MINUTES: i64 = 60;
+
+/// Lets an admin see the API as a user does, e.g. to find out why a camera doesn't show up for them. It works
+/// anywhere a user token does, but only for reading: anything else gets a 403. Every use is in the audit log with
+/// the admin as impersonated_by, and the user can see that it happened with GET /Account/Impersonations.
+#[derive(Queryable, Serialize, Clone, JsonSchema)]
+pub struct Impersonation {
+    /// Sent as the user_token header.
+    #[schemars(with = "String")]
+    pub impersonation_token: uuid::Uuid,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    /// None if the admin has since been deleted, which also ends the impersonation.
+    #[schemars(with = "Option<String>")]
+    pub admin_id: Option<uuid::Uuid>,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Set if it was ended early with DELETE /Admin/Impersonations/<impersonation_token>.
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "impersonation_tokens"]
+pub struct InsertableImpersonation {
+    pub user_id: uuid::Uuid,
+    pub admin_id: Option<uuid::Uuid>,
+    pub reason: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Sent with POST /Admin/Users/<user_id>/Impersonate.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewImpersonation {
+    /// Why, e.g. a support ticket. The user sees this.
+    pub reason: String,
+    /// How long the token lasts, up to MAX_IMPERSONATION_MINUTES. Defaults to DEFAULT_IMPERSONATION_MINUTES.
+    pub minutes: Option<i64>,
+}
+
+/// An impersonation as the user sees it, without the token.
+#[derive(Serialize, JsonSchema)]
+pub struct AccountImpersonation {
+    #[schemars(with = "Option<String>")]
+    pub admin_id: Option<uuid::Uuid>,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl AccountImpersonation {
+    pub fn from_impersonation(impersonation: Impersonation) -> AccountImpersonation {
+        AccountImpersonation {
+            admin_id: impersonation.admin_id,
+            reason: impersonation.reason,
+            created_at: impersonation.created_at,
+            expires_at: impersonation.expires_at,
+            revoked_at: impersonation.revoked_at,
+        }
+    }
+}
+
+/// The admin behind the current request's user token, if it's an impersonation token. Set by the UserToken guard.
+pub struct ImpersonatedBy(pub Option<uuid::Uuid>);
+
+/// Who is impersonating the request's user, or None if it's the user themselves.
+pub fn impersonated_by(request: &Request) -> Option<uuid::Uuid> {
+    request.local_cache(|| ImpersonatedBy(None)).0
+}
+
+/// Returns the impersonation if it hasn't expired or been revoked, and its admin still exists.
+pub fn get_active(
+    impersonation_token: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Impersonation> {
+    impersonation_tokens::table
+        .find(impersonation_token)
+        .filter(impersonation_tokens::expires_at.gt(Utc::now()))
+        .filter(impersonation_tokens::revoked_at.is_null())
+        .filter(impersonation_tokens::admin_id.is_not_null())
+        .get_result(connection)
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get impersonations! The error was {}", error);
+    ApiError {
+        error: "Failed to get impersonations",
+        code: "get_impersonations_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Makes a token that acts as the user, read only, for a limited time. Admins can't be impersonated.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post(
+    "/Admin/Users/<user_id>/Impersonate",
+    format = "json",
+    data = "<new_impersonation>"
+)]
+pub fn impersonate_user(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    user_id: String,
+    new_impersonation: Json<NewImpersonation>,
+) -> Result<Json<Impersonation>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+    let new_impersonation = new_impersonation.into_inner();
+
+    if admin_user_ids().contains(&user_id) {
+        return Err(ApiError {
+            error: "Admins can't be impersonated",
+            code: "admin_impersonation_refused",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+    if new_impersonation.reason.trim().is_empty() {
+        return Err(ApiError {
+            error: "Impersonating a user needs a reason",
+            code: "impersonation_reason_required",
+            status: Status::UnprocessableEntity,
+            field: Some("reason"),
+        });
+    }
+
+    let minutes = new_impersonation
+        .minutes
+        .unwrap_or(DEFAULT_IMPERSONATION_MINUTES);
+    if minutes < 1 || minutes > MAX_IMPERSONATION_MINUTES {
+        return Err(ApiError {
+            error: "minutes must be between 1 and MAX_IMPERSONATION_MINUTES",
+            code: "invalid_minutes",
+            status: Status::UnprocessableEntity,
+            field: Some("minutes"),
+        });
+    }
+
+    user::get(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("impersonate_user_failed", "Failed to impersonate user"),
+        )
+    })?;
+
+    let impersonation = diesel::insert_into(impersonation_tokens::table)
+        .values(InsertableImpersonation {
+            user_id,
+            admin_id: Some(admin_token.user_id),
+            reason: new_impersonation.reason,
+            expires_at: Utc::now() + ChronoDuration::minutes(minutes),
+        })
+        .get_result::<Impersonation>(&*conn)
+        .map_err(|error| {
+            error!("Failed to impersonate user! The error was {}", error);
+            ApiError {
+                error: "Failed to impersonate user",
+                code: "impersonate_user_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    info!(
+        "User {} impersonated by {} until {}",
+        user_id, admin_token.user_id, impersonation.expires_at
+    );
+    audit::record_after(&AccountImpersonation::from_impersonation(
+        impersonation.clone(),
+    ));
+
+    Ok(Json(impersonation))
+}
+
+/// Ends an impersonation before it expires. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Impersonations/<impersonation_token>")]
+pub fn revoke_impersonation(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    impersonation_token: String,
+) -> Result<Json<Impersonation>, ApiError> {
+    let impersonation_token =
+        uuid::Uuid::parse_str(&impersonation_token).map_err(|_| ApiError {
+            error: "Failed to parse impersonation token",
+            code: "invalid_impersonation_token",
+            status: Status::UnprocessableEntity,
+            field: Some("impersonation_token"),
+        })?;
+
+    diesel::update(impersonation_tokens::table.find(impersonation_token))
+        .set(impersonation_tokens::revoked_at.eq(Utc::now()))
+        .get_result::<Impersonation>(&*conn)
+        .map(|impersonation| {
+            audit::record_after(&AccountImpersonation::from_impersonation(
+                impersonation.clone(),
+            ));
+            Json(impersonation)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("impersonation_not_found", "Impersonation not found"),
+                (
+                    "revoke_impersonation_failed",
+                    "Failed to revoke impersonation",
+                ),
+            )
+        })
+}
+
+/// Every impersonation, newest first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Impersonations?<query..>")]
+pub fn list_impersonations(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<Impersonation>>, ApiError> {
+    let (offset, limit) = query.offset_and_limit()?;
+
+    let items = impersonation_tokens::table
+        .order(impersonation_tokens::created_at.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<Impersonation>(&*conn)
+        .map_err(database_error)?;
+
+    let total = impersonation_tokens::table
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
+
+/// Every time an admin has impersonated the user, newest first.
+#[openapi]
+#[get("/Account/Impersonations")]
+pub fn get_account_impersonations(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<AccountImpersonation>>, ApiError> {
+    impersonation_tokens::table
+        .filter(impersonation_tokens::user_id.eq(user_token.user_id))
+        .order(impersonation_tokens::created_at.desc())
+        .load::<Impersonation>(&*conn)
+        .map(|impersonations| {
+            Json(
+                impersonations
+                    .into_iter()
+                    .map(AccountImpersonation::from_impersonation)
+                    .collect(),
+            )
+        })
+        .map_err(database_error)
+}
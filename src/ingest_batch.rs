@@ -0,0 +1,292 @@
+use crate::{
+    api_error::ApiError,
+    camera,
+    detection::ReportedDetection,
+    event::{self, Event, InsertableEvent},
+    mqtt, realtime,
+    settings::settings,
+    worker,
+};
+
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use once_cell::sync::Lazy;
+use rocket::http::Status;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Flusher threads wait at most this long for something to write, so their heartbeats keep going while it's quiet.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How long a request waits for its event to be written before giving up.
+const EVENT_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest (in milliseconds) a queued row waits before being written, set with ingest_batch_latency_ms in [limits].
+/// 0 turns batching off, so every event and camera contact is written as it arrives. Defaults to 20.
+pub fn batch_latency() -> Duration {
+    Duration::from_millis(settings().limits.ingest_batch_latency_ms)
+}
+
+/// A batch is written straight away once it has this many rows, set with ingest_batch_size in [limits]. Defaults to 200.
+pub fn batch_size() -> usize {
+    settings().limits.ingest_batch_size
+}
+
+/// Rows waiting to be written together.
+struct Batch<T> {
+    items: Mutex<Vec<T>>,
+    ready: Condvar,
+}
+
+impl<T> Batch<T> {
+    fn new() -> Batch<T> {
+        Batch {
+            items: Mutex::new(Vec::new()),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, item: T) {
+        let mut items = self.items.lock().expect("Batch lock poisoned!");
+        items.push(item);
+
+        // The flusher is waiting for either the first item or a full batch
+        if items.len() == 1 || items.len() >= batch_size() {
+            self.ready.notify_one();
+        }
+    }
+
+    /// Waits for something to be queued, then for the batch to fill up or `latency` to pass, and takes everything queued.
+    /// Returns nothing if IDLE_WAIT passes without anything being queued.
+    fn take(&self, latency: Duration) -> Vec<T> {
+        let mut items = self.items.lock().expect("Batch lock poisoned!");
+
+        if items.is_empty() {
+            items = self
+                .ready
+                .wait_timeout(items, IDLE_WAIT)
+                .expect("Batch lock poisoned!")
+                .0;
+        }
+
+        if !items.is_empty() {
+            let deadline = Instant::now() + latency;
+
+            while items.len() < batch_size() {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+
+                items = self
+                    .ready
+                    .wait_timeout(items, deadline - now)
+                    .expect("Batch lock poisoned!")
+                    .0;
+            }
+        }
+
+        std::mem::take(&mut *items)
+    }
+}
+
+struct PendingEvent {
+    event: InsertableEvent,
+    detections: Vec<ReportedDetection>,
+    reply: mpsc::Sender<Result<Event, String>>,
+}
+
+static EVENTS: Lazy<Batch<PendingEvent>> = Lazy::new(Batch::new);
+static CONTACTS: Lazy<Batch<uuid::Uuid>> = Lazy::new(Batch::new);
+
+/// Set once the flushers are running. Until then (e.g. while migrations run) everything is written straight away.
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+fn batching() -> bool {
+    FLUSHING.load(Ordering::SeqCst)
+}
+
+/// Stores an event and its detections. With batching on, this waits for the event to be written with the rest of
+/// its batch, so callers still get the stored event back.
+pub fn store_event(
+    insertable_event: InsertableEvent,
+    detections: Vec<ReportedDetection>,
+    connection: &PgConnection,
+) -> Result<Event, ApiError> {
+    let camera_id = insertable_event.camera_id;
+
+    let stored = if batching() {
+        let (reply, stored) = mpsc::channel();
+        EVENTS.push(PendingEvent {
+            event: insertable_event,
+            detections,
+            reply,
+        });
+
+        stored
+            .recv_timeout(EVENT_REPLY_TIMEOUT)
+            .unwrap_or_else(|_| {
+                Err(String::from(
+                    "Timed out waiting for the batch to be written",
+                ))
+            })
+    } else {
+        event::insert_with_detections(vec![(insertable_event, detections)], connection)
+            .map(|mut events| events.remove(0))
+            .map_err(|error| error.to_string())
+    };
+
+    stored.map_err(|error| {
+        error!(
+            "Failed to store event for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to store event",
+            code: "store_event_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Records that the camera has just contacted the server, see camera::mark_cameras_seen().
+/// With batching on, this returns straight away and the contact is written with the rest of its batch.
+pub fn record_contact(camera_id: uuid::Uuid, connection: &PgConnection) {
+    if batching() {
+        CONTACTS.push(camera_id);
+    } else {
+        flush_contacts(vec![camera_id], connection);
+    }
+}
+
+/// Writes the events together. If the batch can't be written, e.g. because one event's camera has just been
+/// deleted, each event is written again on its own, so only the ones that can't be stored fail.
+fn flush_events(pending: Vec<PendingEvent>, connection: &PgConnection) {
+    let events = pending
+        .iter()
+        .map(|pending_event| {
+            (
+                pending_event.event.clone(),
+                pending_event.detections.clone(),
+            )
+        })
+        .collect();
+
+    // A request that has given up waiting has dropped its receiver, so failing to reply is fine
+    match event::insert_with_detections(events, connection) {
+        Ok(stored_events) => {
+            for (pending_event, stored_event) in pending.into_iter().zip(stored_events) {
+                let _ = pending_event.reply.send(Ok(stored_event));
+            }
+        }
+        Err(error) if pending.len() == 1 => {
+            let _ = pending[0].reply.send(Err(error.to_string()));
+        }
+        Err(error) => {
+            warn!(
+                "Failed to write a batch of {} events, writing them one at a time! The error was {}",
+                pending.len(),
+                error
+            );
+
+            for pending_event in pending {
+                let stored = event::insert_with_detections(
+                    vec![(pending_event.event, pending_event.detections)],
+                    connection,
+                )
+                .map(|mut events| events.remove(0))
+                .map_err(|error| error.to_string());
+                let _ = pending_event.reply.send(stored);
+            }
+        }
+    }
+}
+
+fn flush_contacts(mut camera_ids: Vec<uuid::Uuid>, connection: &PgConnection) {
+    camera_ids.sort();
+    camera_ids.dedup();
+
+    match camera::mark_cameras_seen(&camera_ids, connection) {
+        Ok(came_online) => {
+            for camera_id in came_online {
+                mqtt::publish_presence(&camera_id, true);
+                realtime::publish_presence(camera_id, true, connection);
+            }
+        }
+        Err(error) => error!(
+            "Failed to record contact from {} cameras! The error was {}",
+            camera_ids.len(),
+            error
+        ),
+    }
+}
+
+/// Starts a thread that writes `batch` with `flush` whenever it fills up or batch_latency() has passed.
+/// The connection is kept between batches, and made again if a batch can't be written with it.
+fn spawn_flusher<T: Send + 'static>(
+    name: &'static str,
+    batch: &'static Lazy<Batch<T>>,
+    database_url: String,
+    flush: fn(Vec<T>, &PgConnection),
+) {
+    worker::register_worker(name, IDLE_WAIT);
+
+    thread::spawn(move || {
+        let mut connection: Option<PgConnection> = None;
+
+        loop {
+            let items = batch.take(batch_latency());
+            worker::record_heartbeat(name);
+
+            if items.is_empty() {
+                continue;
+            }
+
+            if connection.is_none() {
+                connection = PgConnection::establish(&database_url)
+                    .map_err(|error| {
+                        error!(
+                            "{} failed to connect to the database! The error was {}",
+                            name, error
+                        )
+                    })
+                    .ok();
+            }
+
+            match &connection {
+                Some(conn) => flush(items, conn),
+                // Dropping the items drops any reply senders, so waiting requests fail straight away
+                None => warn!("{} dropped {} rows", name, items.len()),
+            }
+
+            // The connection may have gone bad, so check it before the next batch
+            if let Some(conn) = &connection {
+                if conn.execute("SELECT 1").is_err() {
+                    connection = None;
+                }
+            }
+        }
+    });
+}
+
+/// Starts writing events and camera contacts in batches, unless ingest_batch_latency_ms in [limits] is 0.
+/// Contacts queued in the last few milliseconds before the server exits are lost, which only makes
+/// last_seen_at a little older than it should be.
+pub fn spawn_flushers(database_url: String) {
+    if batch_latency() == Duration::from_millis(0) {
+        return;
+    }
+
+    spawn_flusher(
+        "Event batching",
+        &EVENTS,
+        database_url.clone(),
+        flush_events,
+    );
+    spawn_flusher("Contact batching", &CONTACTS, database_url, flush_contacts);
+
+    FLUSHING.store(true, Ordering::SeqCst);
+}
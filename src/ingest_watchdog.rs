@@ -0,0 +1,365 @@
+use crate::{
+    camera_commands::{self, InsertableCameraCommand, RESTART_STREAM_COMMAND},
+    event::{self, default_severity, dispatch_event, InsertableEvent, STREAM_FLAPPING_EVENT_TYPE},
+    metrics,
+    settings::settings,
+};
+
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often streams are checked for stalls.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a camera is given to reconnect by itself after its stream stalls, before it's sent
+/// RESTART_STREAM_COMMAND. Doubles with every stall in FLAP_WINDOW_SECONDS, up to MAX_BACKOFF_SECONDS.
+const INITIAL_BACKOFF_SECONDS: u64 = 5;
+const MAX_BACKOFF_SECONDS: u64 = 300;
+
+/// Stalls are counted over this long. A camera that stalls FLAP_STALLS times in it raises a stream_flapping event,
+/// once per window.
+const FLAP_WINDOW_SECONDS: u64 = 10 * 60;
+const FLAP_STALLS: u32 = 3;
+
+/// Ingest servers that stopped are restarted after this long, doubling every time they stop again soon after, up to
+/// MAX_BACKOFF_SECONDS. Servers that ran for longer than FLAP_WINDOW_SECONDS start from here again.
+const INITIAL_SERVER_BACKOFF_SECONDS: u64 = 1;
+
+/// The RTMP and SRT streams being pushed to this instance, by the ID they were watched with.
+static STREAMS: Lazy<Mutex<HashMap<u64, Watched>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How cameras' streams have stalled lately.
+static FLAPS: Lazy<Mutex<HashMap<uuid::Uuid, Flaps>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// When watching started, which last_frame_at is counted from, so it fits in an AtomicU64.
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Watched {
+    camera_id: uuid::Uuid,
+    protocol: &'static str,
+    last_frame_at: Arc<AtomicU64>,
+    stalled: Arc<AtomicBool>,
+    /// Stops whatever is blocking the stream's thread, like closing its socket.
+    on_stall: Option<Box<dyn Fn() + Send>>,
+}
+
+struct Flaps {
+    window_started_at: Instant,
+    stalls: u32,
+    event_raised: bool,
+    /// When to send RESTART_STREAM_COMMAND, unless the camera reconnects first.
+    restart_at: Option<Instant>,
+}
+
+/// How long a stream can go without audio or video before it's dropped, set with stall_seconds in [ingest].
+/// Defaults to 15.
+pub fn stall_seconds() -> u64 {
+    settings().ingest.stall_seconds
+}
+
+fn milliseconds_since_start() -> u64 {
+    STARTED_AT.elapsed().as_millis() as u64
+}
+
+/// Somewhere between half and one and a half times `seconds`, so cameras that stalled together don't all come back
+/// at once.
+fn jittered(seconds: u64) -> Duration {
+    let mut random = [0; 2];
+    let _ = getrandom::getrandom(&mut random);
+    let factor = 0.5 + u16::from_be_bytes(random) as f64 / u16::MAX as f64;
+    Duration::from_secs_f64(seconds as f64 * factor)
+}
+
+/// A stream being watched. Dropping it stops watching, for when the stream ends normally.
+pub struct WatchedStream {
+    id: u64,
+    last_frame_at: Arc<AtomicU64>,
+    stalled: Arc<AtomicBool>,
+}
+
+impl WatchedStream {
+    /// Records that audio or video arrived.
+    pub fn frame(&self) {
+        self.last_frame_at
+            .store(milliseconds_since_start(), Ordering::Relaxed);
+    }
+
+    /// Whether the watchdog dropped the stream for stalling. Whatever is serving it should stop.
+    pub fn stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for WatchedStream {
+    fn drop(&mut self) {
+        STREAMS
+            .lock()
+            .expect("Ingest streams were poisoned")
+            .remove(&self.id);
+    }
+}
+
+/// Starts watching a camera's stream, which counts as stalled if WatchedStream::frame() isn't called for
+/// stall_seconds(). `on_stall` is called once it stalls, from the watchdog's thread. A camera that's been asked to
+/// restart its stream doesn't need to be any more.
+pub fn watch<F>(camera_id: uuid::Uuid, protocol: &'static str, on_stall: Option<F>) -> WatchedStream
+where
+    F: Fn() + Send + 'static,
+{
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::SeqCst);
+    let last_frame_at = Arc::new(AtomicU64::new(milliseconds_since_start()));
+    let stalled = Arc::new(AtomicBool::new(false));
+
+    STREAMS
+        .lock()
+        .expect("Ingest streams were poisoned")
+        .insert(
+            id,
+            Watched {
+                camera_id,
+                protocol,
+                last_frame_at: last_frame_at.clone(),
+                stalled: stalled.clone(),
+                on_stall: on_stall.map(|on_stall| Box::new(on_stall) as Box<dyn Fn() + Send>),
+            },
+        );
+
+    if let Some(flaps) = FLAPS
+        .lock()
+        .expect("Ingest flaps were poisoned")
+        .get_mut(&camera_id)
+    {
+        flaps.restart_at = None;
+    }
+
+    WatchedStream {
+        id,
+        last_frame_at,
+        stalled,
+    }
+}
+
+/// Drops every stream that's stalled, and works out when its camera should be asked to restart it. Returns the
+/// cameras that have stalled often enough to raise an event.
+fn check_streams() -> Vec<(uuid::Uuid, &'static str)> {
+    let stall_milliseconds = stall_seconds() * 1000;
+    let now = milliseconds_since_start();
+
+    let stalled = {
+        let mut streams = STREAMS.lock().expect("Ingest streams were poisoned");
+        let stalled_ids = streams
+            .iter()
+            .filter(|(_, watched)| {
+                now.saturating_sub(watched.last_frame_at.load(Ordering::Relaxed))
+                    >= stall_milliseconds
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u64>>();
+
+        stalled_ids
+            .into_iter()
+            .filter_map(|id| streams.remove(&id))
+            .collect::<Vec<Watched>>()
+    };
+
+    let mut flaps = FLAPS.lock().expect("Ingest flaps were poisoned");
+    let mut flapping = Vec::new();
+
+    for watched in stalled {
+        warn!(
+            "Camera {}'s {} stream stalled, so it was dropped",
+            watched.camera_id, watched.protocol
+        );
+        metrics::record_ingest_stall(watched.protocol);
+        watched.stalled.store(true, Ordering::Relaxed);
+        if let Some(on_stall) = &watched.on_stall {
+            on_stall();
+        }
+
+        let camera_flaps = flaps.entry(watched.camera_id).or_insert_with(|| Flaps {
+            window_started_at: Instant::now(),
+            stalls: 0,
+            event_raised: false,
+            restart_at: None,
+        });
+        if camera_flaps.window_started_at.elapsed() >= Duration::from_secs(FLAP_WINDOW_SECONDS) {
+            camera_flaps.window_started_at = Instant::now();
+            camera_flaps.stalls = 0;
+            camera_flaps.event_raised = false;
+        }
+        camera_flaps.stalls += 1;
+
+        let backoff =
+            (INITIAL_BACKOFF_SECONDS << (camera_flaps.stalls - 1).min(16)).min(MAX_BACKOFF_SECONDS);
+        camera_flaps.restart_at = Some(Instant::now() + jittered(backoff));
+
+        if camera_flaps.stalls >= FLAP_STALLS && !camera_flaps.event_raised {
+            camera_flaps.event_raised = true;
+            flapping.push((watched.camera_id, watched.protocol));
+        }
+    }
+
+    // Cameras that haven't stalled for a whole window are forgotten
+    flaps.retain(|_, camera_flaps| {
+        camera_flaps.restart_at.is_some()
+            || camera_flaps.window_started_at.elapsed() < Duration::from_secs(FLAP_WINDOW_SECONDS)
+    });
+
+    flapping
+}
+
+/// The cameras whose restarts are due, which are then forgotten until they stall again.
+fn due_restarts() -> Vec<uuid::Uuid> {
+    let mut flaps = FLAPS.lock().expect("Ingest flaps were poisoned");
+    flaps
+        .iter_mut()
+        .filter(|(_, camera_flaps)| {
+            camera_flaps
+                .restart_at
+                .map_or(false, |restart_at| restart_at <= Instant::now())
+        })
+        .map(|(camera_id, camera_flaps)| {
+            camera_flaps.restart_at = None;
+            *camera_id
+        })
+        .collect()
+}
+
+fn raise_flapping_event(
+    camera_id: uuid::Uuid,
+    protocol: &str,
+    connection: &PgConnection,
+) -> diesel::QueryResult<()> {
+    metrics::record_ingest_flap(protocol);
+    warn!(
+        "Camera {}'s {} stream stalled {} times in {} minutes",
+        camera_id,
+        protocol,
+        FLAP_STALLS,
+        FLAP_WINDOW_SECONDS / 60
+    );
+
+    let event = event::insert(
+        InsertableEvent {
+            camera_id,
+            event_type: STREAM_FLAPPING_EVENT_TYPE.to_string(),
+            occurred_at: Utc::now(),
+            confidence: 1.0,
+            image_id: None,
+            severity: default_severity(STREAM_FLAPPING_EVENT_TYPE).to_string(),
+            audio_id: None,
+            tamper_reason: None,
+        },
+        connection,
+    )?;
+    dispatch_event(&event, connection);
+    Ok(())
+}
+
+fn connect<'a>(
+    connection: &'a mut Option<PgConnection>,
+    database_url: &str,
+) -> Option<&'a PgConnection> {
+    if connection.is_none() {
+        *connection = PgConnection::establish(database_url)
+            .map_err(|error| {
+                error!(
+                    "Ingest watchdog failed to connect to the database! The error was {}",
+                    error
+                )
+            })
+            .ok();
+    }
+    connection.as_ref()
+}
+
+/// Starts the thread that drops stalled RTMP and SRT streams, asks their cameras to restart them after a jittered
+/// backoff, and raises stream_flapping events for cameras that keep stalling. Only needed if either is on. The
+/// database connection is only made once it's needed, like the SRT server's.
+pub fn spawn_ingest_watchdog(database_url: String) {
+    let ingest = &settings().ingest;
+    if ingest.rtmp_port.is_none() && ingest.srt_port.is_none() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut connection = None;
+
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+
+            let flapping = check_streams();
+            let restarts = due_restarts();
+            if flapping.is_empty() && restarts.is_empty() {
+                continue;
+            }
+
+            let connection = match connect(&mut connection, &database_url) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            for (camera_id, protocol) in flapping {
+                if let Err(error) = raise_flapping_event(camera_id, protocol, connection) {
+                    error!(
+                        "Failed to raise stream flapping event for camera {}! The error was {}",
+                        camera_id, error
+                    );
+                }
+            }
+
+            for camera_id in restarts {
+                info!("Asking camera {} to restart its stream", camera_id);
+                if let Err(error) = camera_commands::insert(
+                    InsertableCameraCommand {
+                        camera_id,
+                        command: RESTART_STREAM_COMMAND.to_string(),
+                        expires_at: None,
+                    },
+                    connection,
+                ) {
+                    error!(
+                        "Failed to ask camera {} to restart its stream! The error was {}",
+                        camera_id, error
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// Runs an ingest server on its own thread, and runs it again if it returns or panics, after a jittered backoff
+/// that grows while it keeps stopping.
+pub fn supervise<F>(server: &'static str, run: F)
+where
+    F: Fn() + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut backoff = INITIAL_SERVER_BACKOFF_SECONDS;
+
+        loop {
+            let started_at = Instant::now();
+            match panic::catch_unwind(AssertUnwindSafe(&run)) {
+                Ok(()) => error!("The {} server stopped! Restarting it", server),
+                Err(_) => error!("The {} server panicked! Restarting it", server),
+            }
+            metrics::record_ingest_restart(server);
+
+            if started_at.elapsed() >= Duration::from_secs(FLAP_WINDOW_SECONDS) {
+                backoff = INITIAL_SERVER_BACKOFF_SECONDS;
+            }
+            thread::sleep(jittered(backoff));
+            backoff = (backoff * 2).min(MAX_BACKOFF_SECONDS);
+        }
+    });
+}
@@ -0,0 +1,447 @@
+use crate::{
+    account_export,
+    admin::AdminToken,
+    api_error::ApiError,
+    backup, database, event_retention,
+    page::{offset_and_limit, Page},
+    settings::settings,
+    shutdown, snapshot_schedule, soft_delete, worker, CameraServerDbConn,
+};
+
+use super::schema::jobs;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::get;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const QUEUED_STATUS: &str = "queued";
+pub const RUNNING_STATUS: &str = "running";
+pub const SUCCEEDED_STATUS: &str = "succeeded";
+/// Failed on every attempt. Jobs that failed but have attempts left go back to queued.
+pub const FAILED_STATUS: &str = "failed";
+pub const JOB_STATUSES: [&str; 4] = [
+    QUEUED_STATUS,
+    RUNNING_STATUS,
+    SUCCEEDED_STATUS,
+    FAILED_STATUS,
+];
+
+/// Deletes old events, see event_retention::prune_events(). The payload is {"retention_days": 30}.
+pub const PRUNE_EVENTS_JOB: &str = "prune_events";
+
+/// Deletes cameras and users that were soft deleted a while ago, see soft_delete::purge_deleted().
+/// The payload is {"retention_days": 30}.
+pub const PURGE_DELETED_JOB: &str = "purge_deleted";
+
+/// Backs up the database and the media manifest, see backup::back_up(). The payload is {}.
+pub const BACKUP_JOB: &str = "backup";
+
+/// Builds a user's archive, see account_export::build(). The payload is {"export_id": 1}.
+pub const ACCOUNT_EXPORT_JOB: &str = "account_export";
+
+/// Asks cameras for the snapshots their schedules are due, and checks whether earlier ones arrived, see
+/// snapshot_schedule::run_due(). The payload is {}.
+pub const SNAPSHOT_SCHEDULES_JOB: &str = "snapshot_schedules";
+
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+pub const INITIAL_RETRY_DELAY_SECONDS: i64 = 30;
+
+/// A job still running after this long is assumed to have been lost with the server that ran it, and is run again.
+pub const STALE_JOB_SECONDS: i64 = 60 * 60;
+
+/// Finished jobs are deleted after this many days.
+pub const FINISHED_JOB_DAYS: i64 = 7;
+
+/// Something to be done in the background. Jobs are run by every server's job workers, see spawn_job_workers(),
+/// and each is only run by one of them at a time.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Job {
+    pub job_id: i32,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    /// queued, running, succeeded or failed.
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    /// When the job should next run, if it's queued.
+    pub run_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "jobs"]
+pub struct InsertableJob {
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+}
+
+/// How many jobs each server runs at once, set with workers in [jobs]. Defaults to 2.
+pub fn job_workers() -> usize {
+    settings().jobs.workers
+}
+
+/// Queues a job to be run at `run_at`, or as soon as possible if that's in the past.
+pub fn enqueue(
+    kind: &str,
+    payload: serde_json::Value,
+    run_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<Job> {
+    diesel::insert_into(jobs::table)
+        .values(InsertableJob {
+            kind: kind.to_string(),
+            payload,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            run_at,
+        })
+        .get_result(connection)
+}
+
+/// Like enqueue(), but does nothing if a job of the same kind is already queued or running.
+/// For jobs that are scheduled regularly, so a slow run doesn't pile up more of the same job behind it.
+pub fn enqueue_unless_pending(
+    kind: &str,
+    payload: serde_json::Value,
+    run_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<Option<Job>> {
+    let pending = jobs::table
+        .filter(jobs::kind.eq(kind))
+        .filter(jobs::status.eq_any(vec![QUEUED_STATUS, RUNNING_STATUS]))
+        .count()
+        .get_result::<i64>(connection)?;
+
+    if pending > 0 {
+        Ok(None)
+    } else {
+        enqueue(kind, payload, run_at, connection).map(Some)
+    }
+}
+
+/// Runs the job's work. Returns an error to have it retried.
+fn run_job(job: &Job, connection: &PgConnection) -> Result<(), String> {
+    match job.kind.as_str() {
+        PRUNE_EVENTS_JOB => {
+            let retention_days = job
+                .payload
+                .get("retention_days")
+                .and_then(|retention_days| retention_days.as_i64())
+                .ok_or("Payload needs retention_days")?;
+
+            let (deleted, anonymised) =
+                event_retention::prune_events(retention_days, connection)
+                    .map_err(|error| format!("Failed to prune old events: {}", error))?;
+
+            if deleted > 0 || anonymised > 0 {
+                info!(
+                    "Deleted {} old events and anonymised {} held events",
+                    deleted, anonymised
+                );
+            }
+
+            Ok(())
+        }
+        PURGE_DELETED_JOB => {
+            let retention_days = job
+                .payload
+                .get("retention_days")
+                .and_then(|retention_days| retention_days.as_i64())
+                .ok_or("Payload needs retention_days")?;
+
+            let (cameras, users) = soft_delete::purge_deleted(retention_days, connection)
+                .map_err(|error| format!("Failed to purge deleted cameras and users: {}", error))?;
+
+            if cameras > 0 || users > 0 {
+                info!(
+                    "Purged {} deleted cameras and {} deleted users",
+                    cameras, users
+                );
+            }
+
+            Ok(())
+        }
+        BACKUP_JOB => {
+            let manifest = backup::back_up(connection)
+                .map_err(|error| format!("Failed to back up: {}", error))?;
+
+            info!(
+                "Backed up {} tables and {} cameras' media as {}",
+                manifest.tables.len(),
+                manifest.images.len(),
+                manifest.name
+            );
+
+            Ok(())
+        }
+        ACCOUNT_EXPORT_JOB => {
+            let export_id = job
+                .payload
+                .get("export_id")
+                .and_then(|export_id| export_id.as_i64())
+                .ok_or("Payload needs export_id")?;
+
+            account_export::build(export_id as i32, connection)
+        }
+        SNAPSHOT_SCHEDULES_JOB => {
+            let (requested, missed) = snapshot_schedule::run_due(connection)
+                .map_err(|error| format!("Failed to run snapshot schedules: {}", error))?;
+
+            if requested > 0 || missed > 0 {
+                info!(
+                    "Requested {} scheduled snapshots, {} earlier ones were missed",
+                    requested, missed
+                );
+            }
+
+            Ok(())
+        }
+        kind => Err(format!("No handler for {} jobs", kind)),
+    }
+}
+
+/// Takes the job that's been due the longest, marking it as running. Jobs other workers have taken are skipped.
+pub fn claim_next(connection: &PgConnection) -> QueryResult<Option<Job>> {
+    connection.transaction(|| {
+        let job_id = database::timed(
+            "job_claim",
+            jobs::table
+                .filter(jobs::status.eq(QUEUED_STATUS))
+                .filter(jobs::run_at.le(Utc::now()))
+                .order(jobs::run_at)
+                .select(jobs::job_id)
+                .for_update()
+                .skip_locked(),
+            |query| query.first::<i32>(connection).optional(),
+        )?;
+
+        match job_id {
+            Some(job_id) => diesel::update(jobs::table.find(job_id))
+                .set((
+                    jobs::status.eq(RUNNING_STATUS),
+                    jobs::attempts.eq(jobs::attempts + 1),
+                    jobs::started_at.eq(Utc::now()),
+                ))
+                .get_result(connection)
+                .map(Some),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Records how the job went, queueing it again with exponential backoff if it failed and has attempts left.
+fn finish(job: &Job, result: Result<(), String>, connection: &PgConnection) -> QueryResult<()> {
+    let update = diesel::update(jobs::table.find(job.job_id));
+
+    match result {
+        Ok(()) => update
+            .set((
+                jobs::status.eq(SUCCEEDED_STATUS),
+                jobs::finished_at.eq(Utc::now()),
+                jobs::last_error.eq(None::<String>),
+            ))
+            .execute(connection),
+        Err(error) => {
+            error!(
+                "{} job {} failed! The error was {}",
+                job.kind, job.job_id, error
+            );
+
+            match worker::next_attempt_at(
+                job.attempts,
+                job.max_attempts,
+                INITIAL_RETRY_DELAY_SECONDS,
+            ) {
+                Some(run_at) => update
+                    .set((
+                        jobs::status.eq(QUEUED_STATUS),
+                        jobs::run_at.eq(run_at),
+                        jobs::last_error.eq(error),
+                    ))
+                    .execute(connection),
+                None => update
+                    .set((
+                        jobs::status.eq(FAILED_STATUS),
+                        jobs::finished_at.eq(Utc::now()),
+                        jobs::last_error.eq(error),
+                    ))
+                    .execute(connection),
+            }
+        }
+    }
+    .map(|_| ())
+}
+
+/// Runs due jobs one at a time until there aren't any left, or the server starts shutting down.
+pub fn run_due(connection: &PgConnection) -> QueryResult<()> {
+    while !shutdown::shutting_down() {
+        match claim_next(connection)? {
+            Some(job) => {
+                let result = run_job(&job, connection);
+                finish(&job, result, connection)?;
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Queues jobs that have been running for too long again, and deletes jobs that finished a while ago.
+pub fn tidy_up(connection: &PgConnection) -> QueryResult<()> {
+    diesel::update(
+        jobs::table
+            .filter(jobs::status.eq(RUNNING_STATUS))
+            .filter(jobs::started_at.lt(Utc::now() - ChronoDuration::seconds(STALE_JOB_SECONDS))),
+    )
+    .set((
+        jobs::status.eq(QUEUED_STATUS),
+        jobs::run_at.eq(Utc::now()),
+        jobs::last_error.eq("Ran for too long, assumed lost"),
+    ))
+    .execute(connection)?;
+
+    diesel::delete(
+        jobs::table
+            .filter(jobs::status.eq_any(vec![SUCCEEDED_STATUS, FAILED_STATUS]))
+            .filter(jobs::finished_at.lt(Utc::now() - ChronoDuration::days(FINISHED_JOB_DAYS))),
+    )
+    .execute(connection)
+    .map(|_| ())
+}
+
+/// Starts job_workers() threads that run jobs as they come due, and one that tidies the queue up every few minutes.
+pub fn spawn_job_workers(database_url: String) {
+    for worker_number in 1..=job_workers() {
+        // Worker names are kept for as long as the server runs, so leaking them is fine
+        let name: &'static str = Box::leak(format!("Jobs {}", worker_number).into_boxed_str());
+
+        // Jobs are claimed with SKIP LOCKED, so every instance can run them
+        worker::spawn_concurrent_worker(
+            name,
+            Duration::from_secs(1),
+            database_url.clone(),
+            |connection| {
+                if let Err(error) = run_due(connection) {
+                    error!("Failed to run jobs! The error was {}", error);
+                }
+            },
+        );
+    }
+
+    worker::spawn_worker(
+        "Job queue tidying",
+        Duration::from_secs(5 * 60),
+        database_url,
+        |connection| {
+            if let Err(error) = tidy_up(connection) {
+                error!("Failed to tidy up the job queue! The error was {}", error);
+            }
+        },
+    );
+}
+
+/// Query string for GET /Admin/Jobs.
+#[derive(FromForm, JsonSchema)]
+pub struct JobQuery {
+    /// Only jobs with this status.
+    pub status: Option<String>,
+    /// Only jobs of this kind.
+    pub kind: Option<String>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get jobs! The error was {}", error);
+    ApiError {
+        error: "Failed to get jobs",
+        code: "get_jobs_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Returns jobs, newest first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Jobs?<query..>")]
+pub fn list_jobs(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<JobQuery>,
+) -> Result<Json<Page<Job>>, ApiError> {
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+
+    if let Some(status) = &query.status {
+        if !JOB_STATUSES.contains(&status.as_str()) {
+            return Err(ApiError {
+                error: "Status must be queued, running, succeeded or failed",
+                code: "invalid_status",
+                status: Status::UnprocessableEntity,
+                field: Some("status"),
+            });
+        }
+    }
+
+    let filtered = || {
+        let mut filtered = jobs::table.into_boxed();
+
+        if let Some(status) = &query.status {
+            filtered = filtered.filter(jobs::status.eq(status));
+        }
+        if let Some(kind) = &query.kind {
+            filtered = filtered.filter(jobs::kind.eq(kind));
+        }
+
+        filtered
+    };
+
+    let items = filtered()
+        .order(jobs::job_id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<Job>(&*conn)
+        .map_err(database_error)?;
+
+    let total = filtered()
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
+
+#[openapi]
+#[get("/Admin/Jobs/<job_id>")]
+pub fn get_job(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    job_id: i32,
+) -> Result<Json<Job>, ApiError> {
+    match jobs::table
+        .find(job_id)
+        .get_result::<Job>(&*conn)
+        .optional()
+    {
+        Ok(Some(job)) => Ok(Json(job)),
+        Ok(None) => Err(ApiError {
+            error: "Job not found",
+            code: "job_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        Err(error) => Err(database_error(error)),
+    }
+}
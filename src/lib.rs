@@ -0,0 +1,466 @@
+#![feature(proc_macro_hygiene, decl_macro)]
+
+#[macro_use]
+extern crate rocket;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate rocket_okapi;
+#[macro_use]
+extern crate tracing;
+
+extern crate bcrypt;
+extern crate chrono;
+
+pub mod camera;
+mod camera_admin;
+mod camera_commands;
+mod camera_logs;
+mod camera_tokens;
+mod enums {
+    pub mod token_error;
+}
+mod account_export;
+mod acknowledgement;
+pub mod admin;
+mod analysis;
+mod announcement;
+mod anomaly;
+mod api_error;
+mod api_version;
+mod audio;
+mod audit;
+pub mod backup;
+mod bandwidth;
+mod batch;
+pub mod bootstrap;
+pub mod bulk_registration;
+mod cache;
+mod clock;
+mod cluster;
+mod coap;
+mod compression;
+mod config;
+mod cors;
+mod custody;
+pub mod database;
+mod detection;
+mod device_format;
+mod digest;
+mod email;
+mod event;
+mod event_export;
+mod event_media;
+pub mod event_retention;
+mod event_search;
+mod face_blur;
+mod feature_flags;
+mod federation;
+mod feed;
+mod fields;
+mod firmware;
+pub mod footage_import;
+mod geofence;
+mod graphql;
+mod grpc;
+mod health;
+mod home_assistant;
+mod i18n;
+mod idempotency;
+mod impersonation;
+mod ingest_batch;
+mod ingest_watchdog;
+mod jobs;
+pub mod logging;
+mod maintenance;
+mod media_hold;
+pub mod media_store;
+mod method_routing;
+mod metrics;
+mod mode;
+mod mqtt;
+mod mqtt_ingest;
+mod multipart_upload;
+mod note;
+mod notification;
+mod oauth;
+mod onvif;
+mod openapi;
+mod page;
+mod patch;
+mod plan;
+mod privacy_mask;
+mod push;
+mod quota;
+mod rate_limit;
+mod realtime;
+mod recording;
+mod replication;
+mod request_id;
+mod row_stream;
+mod rtmp;
+mod rtsp;
+mod rule;
+mod schema;
+mod schema_check;
+pub mod seed;
+pub mod settings;
+mod shutdown;
+mod sms;
+mod snapshot_schedule;
+pub mod soft_delete;
+mod srt;
+mod stats;
+mod storage;
+mod stream_credentials;
+mod stream_keys;
+mod talk;
+pub mod tenant;
+#[cfg(feature = "test-support")]
+pub mod test_support;
+mod timezone;
+mod tls;
+mod trigger;
+mod unix_socket;
+mod upload_limit;
+mod usage;
+pub mod user;
+mod user_admin;
+pub mod user_tokens;
+mod users_cameras;
+mod voice_assistant;
+mod webhook;
+pub mod worker;
+mod zone;
+
+pub use database::{CameraServerDbConn, CameraServerDbConnPool};
+
+use rocket::{Config, Rocket};
+
+/// Starts the server, and only returns if Rocket fails to launch.
+pub fn run() {
+    // Loaded before anything else, so invalid settings stop the server before it starts
+    settings::settings();
+    logging::init_from_env();
+
+    let config = database::with_timeouts(rocket::ignite().config().clone());
+    let database_url = worker::database_url(&config);
+    tls::init();
+
+    mqtt::init_from_env();
+    shutdown::spawn_signal_handler();
+    tls::spawn_renewal_watcher();
+
+    health::spawn_startup(database_url, |database_url| {
+        media_store::spawn_tiering_worker(database_url.clone());
+        webhook::spawn_delivery_worker(database_url.clone());
+        notification::spawn_delivery_worker(database_url.clone());
+        mode::spawn_schedule_worker(database_url.clone());
+        snapshot_schedule::spawn_schedule_worker(database_url.clone());
+        analysis::spawn_analysis_worker(database_url.clone());
+        event_retention::spawn_retention_worker(database_url.clone());
+        soft_delete::spawn_purge_worker(database_url.clone());
+        audit::spawn_retention_worker(database_url.clone());
+        camera_logs::spawn_retention_worker(database_url.clone());
+        usage::spawn_usage_flusher(database_url.clone());
+        bandwidth::spawn_bandwidth_flusher(database_url.clone());
+        storage::spawn_storage_flusher(database_url.clone());
+        plan::spawn_retention_worker(database_url.clone());
+        account_export::spawn_expiry_worker(database_url.clone());
+        maintenance::spawn_buffer_replay(database_url.clone());
+        replication::spawn_replication_worker(database_url.clone());
+        schema_check::spawn_schema_check(database_url.clone());
+        digest::spawn_digest_worker(database_url.clone());
+        anomaly::spawn_anomaly_worker(database_url.clone());
+        home_assistant::spawn_discovery_worker(database_url.clone());
+        idempotency::spawn_expiry_worker(database_url.clone());
+        ingest_batch::spawn_flushers(database_url.clone());
+        jobs::spawn_job_workers(database_url.clone());
+        realtime::spawn_realtime_server(database_url.clone());
+        grpc::spawn_grpc_server(database_url.clone());
+        coap::spawn_coap_server(database_url.clone());
+        mqtt_ingest::spawn_ingest_bridge(database_url.clone());
+        rtsp::spawn_rtsp_server(database_url.clone());
+        rtmp::spawn_rtmp_server(database_url.clone());
+        srt::spawn_srt_server(database_url.clone());
+        ingest_watchdog::spawn_ingest_watchdog(database_url.clone());
+        camera::spawn_offline_monitor(database_url);
+    });
+
+    tls::launch(rocket(config));
+}
+
+/// The server with every fairing, catcher and route mounted, but without the workers or the other servers
+/// (realtime, gRPC, CoAP and MQTT), which run() starts. Also used by test_support.
+pub fn rocket(config: Config) -> Rocket {
+    let rocket = rocket::custom(config);
+    let loopback = batch::Loopback::from_config(rocket.config());
+    let long_polls = camera_commands::LongPolls::from_config(rocket.config());
+    let pool = CameraServerDbConnPool::from_config(rocket.config());
+    let read_replicas = database::ReadReplicas::from_config(rocket.config());
+
+    rocket
+        .attach(request_id::RequestIds)
+        .attach(shutdown::ShutdownDraining)
+        .attach(schema_check::SchemaGuard)
+        .attach(metrics::RequestMetrics)
+        .attach(api_version::LegacyPaths::from_env())
+        .attach(maintenance::MaintenanceMode)
+        .attach(rate_limit::RateLimiter::from_env())
+        .attach(idempotency::Idempotency)
+        .attach(audit::AuditLog)
+        .attach(usage::UsageMetering)
+        .attach(upload_limit::UploadLimits)
+        .attach(cors::Cors::from_env())
+        .attach(method_routing::MethodRouting::new())
+        .attach(compression::Compression)
+        .attach(bandwidth::BandwidthMetering)
+        .register(catchers![
+            api_error::bad_request,
+            api_error::unauthorized,
+            api_error::forbidden,
+            api_error::not_found,
+            api_error::unsupported_media_type,
+            api_error::unprocessable_entity,
+            api_error::too_many_requests,
+            api_error::service_unavailable,
+            api_error::internal_error,
+        ])
+        .mount(
+            api_version::API_PREFIX,
+            // Also serves the OpenAPI document at /api/v1/openapi.json. Routes that upload or stream raw files are left out of it
+            routes_with_openapi![
+                user::add_user,
+                user::login,
+                camera::add_new_camera,
+                bootstrap::add_bootstrap_device,
+                bulk_registration::import_cameras,
+                bootstrap::exchange_claim_token,
+                camera::get_camera,
+                camera::patch_camera,
+                camera::delete_camera,
+                camera::upload_image,
+                camera::upload_image_multipart,
+                camera::take_snapshot,
+                camera::get_snapshot,
+                snapshot_schedule::list_snapshot_schedules,
+                snapshot_schedule::add_snapshot_schedule,
+                snapshot_schedule::update_snapshot_schedule,
+                snapshot_schedule::delete_snapshot_schedule,
+                snapshot_schedule::list_scheduled_snapshots,
+                camera::get_latest,
+                camera::get_image_list,
+                camera::get_image,
+                audio::upload_audio,
+                audio::upload_audio_multipart,
+                audio::get_audio,
+                camera_commands::get_commands,
+                camera_logs::upload_logs,
+                clock::get_device_time,
+                users_cameras::list_cameras,
+                users_cameras::set_capabilities,
+                feed::get_feed,
+                config::get_config_user,
+                config::get_config_camera,
+                config::update_config,
+                config::patch_config,
+                event::report_event,
+                event::get_events,
+                event::get_event,
+                event_search::search_events,
+                event_export::export_events,
+                event_export::export_all_events,
+                event_retention::create_event_hold,
+                event_retention::get_event_holds,
+                event_retention::delete_event_hold,
+                media_hold::create_media_hold,
+                media_hold::delete_media_hold,
+                media_hold::get_media_holds,
+                media_hold::admin_create_media_hold,
+                media_hold::admin_delete_media_hold,
+                media_hold::admin_get_media_holds,
+                note::get_notes,
+                note::create_note,
+                note::get_note,
+                note::update_note,
+                note::delete_note,
+                acknowledgement::acknowledge_event,
+                acknowledgement::get_unread_count,
+                detection::report_image_detections,
+                detection::get_image_detections,
+                anomaly::get_activity_baseline,
+                detection::get_event_detections,
+                zone::get_zones,
+                zone::update_zones,
+                mqtt_ingest::get_mqtt_client,
+                mqtt_ingest::update_mqtt_client,
+                mqtt_ingest::delete_mqtt_client,
+                webhook::add_webhook,
+                webhook::list_webhooks,
+                webhook::delete_webhook,
+                webhook::list_deliveries,
+                push::add_push_token,
+                push::delete_push_token,
+                notification::get_notification_preferences,
+                notification::update_notification_preferences,
+                email::get_email_alerts,
+                email::update_email_alerts,
+                sms::get_sms_settings,
+                sms::update_sms_settings,
+                digest::get_digest_settings,
+                digest::update_digest_settings,
+                rule::add_rule,
+                rule::list_rules,
+                rule::update_rule,
+                rule::patch_rule,
+                rule::delete_rule,
+                rule::test_rule,
+                mode::get_mode,
+                mode::update_mode,
+                mode::list_mode_schedules,
+                mode::add_mode_schedule,
+                mode::delete_mode_schedule,
+                geofence::report_geofence_transition,
+                geofence::get_household_presence,
+                batch::batch,
+                rate_limit::get_rate_limit,
+                quota::get_quota,
+                jobs::list_jobs,
+                jobs::get_job,
+                soft_delete::undelete_camera,
+                camera_admin::reassign_camera,
+                camera_admin::get_orphaned_cameras,
+                camera_admin::delete_orphaned_cameras,
+                clock::get_drifted_cameras,
+                soft_delete::delete_user,
+                soft_delete::undelete_user,
+                user_admin::list_users,
+                user_admin::get_user,
+                user_admin::disable_user,
+                user_admin::enable_user,
+                user_admin::suspend_user,
+                user_admin::reinstate_user,
+                user_admin::reset_user_tokens,
+                stats::get_stats,
+                audit::list_audit_log,
+                backup::start_backup,
+                backup::get_backups,
+                replication::get_replication_status,
+                replication::replicate_cameras,
+                replication::replicate_events,
+                replication::replicate_image,
+                federation::list_peers,
+                federation::add_peer,
+                federation::delete_peer,
+                federation::finish_handshake,
+                federation::list_remote_shares,
+                federation::share_camera,
+                federation::revoke_remote_share,
+                federation::receive_share,
+                federation::forget_share,
+                federation::leave_share,
+                federation::get_shared_image_list,
+                federation::get_shared_latest,
+                federation::get_shared_image,
+                federation::list_remote_cameras,
+                federation::delete_remote_camera,
+                federation::get_remote_image_list,
+                federation::get_remote_latest,
+                federation::get_remote_image,
+                oauth::authorize_page,
+                oauth::authorize,
+                oauth::token,
+                oauth::list_links,
+                oauth::delete_link,
+                voice_assistant::get_stream,
+                voice_assistant::set_stream,
+                voice_assistant::delete_stream,
+                voice_assistant::get_snapshot,
+                voice_assistant::google_fulfillment,
+                voice_assistant::alexa_directive,
+                onvif::get_credential,
+                onvif::create_credential,
+                onvif::delete_credential,
+                stream_keys::create_stream_key,
+                stream_keys::delete_stream_key,
+                stream_keys::create_srt_passphrase,
+                stream_keys::delete_srt_passphrase,
+                recording::list_recordings,
+                recording::get_recording,
+                usage::get_usage,
+                usage::get_all_usage,
+                plan::get_plans,
+                plan::create_plan,
+                plan::update_plan,
+                plan::delete_plan,
+                plan::assign_plan,
+                plan::get_account_plan,
+                announcement::create_announcement,
+                announcement::list_announcements,
+                announcement::delete_announcement,
+                announcement::get_announcements,
+                announcement::read_announcement,
+                impersonation::impersonate_user,
+                impersonation::revoke_impersonation,
+                impersonation::list_impersonations,
+                impersonation::get_account_impersonations,
+                account_export::start_export,
+                account_export::get_exports,
+                account_export::download_export,
+                custody::get_signing_key,
+                storage::get_camera_storage,
+                bandwidth::get_camera_bandwidth,
+                clock::get_camera_clock,
+                camera_logs::get_camera_logs,
+                storage::get_account_storage,
+                stream_credentials::rotate_stream_credentials,
+                stream_credentials::list_stream_credentials,
+                stream_credentials::get_device_stream_credentials,
+                stream_credentials::confirm_stream_credentials,
+                maintenance::get_maintenance,
+                maintenance::start_maintenance,
+                maintenance::end_maintenance,
+                maintenance::buffer_image,
+                maintenance::buffer_image_multipart,
+                i18n::get_locales,
+                i18n::get_locale,
+                i18n::update_locale,
+                timezone::get_timezone,
+                timezone::update_timezone,
+                tenant::get_tenants,
+                tenant::create_tenant,
+                tenant::delete_tenant,
+                feature_flags::get_features,
+                feature_flags::update_feature,
+                feature_flags::reset_feature,
+                firmware::upload_firmware,
+                firmware::list_firmware,
+                firmware::update_rollout,
+                firmware::delete_firmware,
+                firmware::check_firmware,
+                firmware::report_firmware_status,
+                firmware::download_firmware,
+            ],
+        )
+        .manage(pool)
+        .manage(loopback)
+        .manage(federation::FederationClient::new())
+        .manage(long_polls)
+        .manage(read_replicas)
+        .manage(graphql::schema())
+        .mount(api_version::API_PREFIX, graphql::routes())
+        .mount(
+            "/",
+            routes![
+                health::health,
+                health::livez,
+                health::readyz,
+                metrics::metrics,
+                onvif::device_service,
+                onvif::media_service,
+                onvif::snapshot
+            ],
+        )
+}
@@ -0,0 +1,32 @@
+use crate::settings::settings;
+
+use tracing_subscriber::EnvFilter;
+
+/// What gets logged, set with RUST_LOG, e.g. camera_server=debug. Defaults to info.
+pub fn log_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Set format in [logging] to json for one JSON object per line, so logs can be shipped to Loki or Elasticsearch.
+/// Defaults to human-readable lines.
+pub fn json_logs() -> bool {
+    settings().logging.format == "json"
+}
+
+/// Sends everything logged with tracing to stdout. Requests are logged inside a span with their ID, user
+/// and camera, see request_id::RequestIds, and background workers inside a span with the worker's name.
+pub fn init_from_env() {
+    let subscriber = tracing_subscriber::fmt().with_env_filter(log_filter());
+
+    if json_logs() {
+        // Only the innermost span's fields are included, as "span", next to the event's own fields
+        subscriber
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init();
+    } else {
+        subscriber.init();
+    }
+}
@@ -0,0 +1,765 @@
+use crate::{
+    admin::AdminToken,
+    api_error::{error_code, ApiError, ErrorBody},
+    api_version::{API_PREFIX, UNVERSIONED_PATHS},
+    audit,
+    cache::{self, cache},
+    camera::{self, ImageUpload},
+    camera_tokens,
+    device_format::Device,
+    enums::token_error::TokenError,
+    multipart_upload::{MultipartUpload, MAX_FILE_BYTES},
+    rate_limit, request_id, schema_check,
+    settings::settings,
+    shutdown, worker,
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use once_cell::sync::Lazy;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::request::{self, FromRequest};
+use rocket::{delete, get, post, put, Data, Outcome, Request, Response};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Requests refused during maintenance are routed here instead. Nothing is mounted at it.
+const MAINTENANCE_PATH: &str = "/Maintenance";
+
+/// Images uploaded during maintenance are routed here, to be buffered instead of stored.
+const BUFFER_PATH: &str = "/Maintenance/Device/Images";
+
+/// Maintenance turned on by an admin ends after this long unless they ask for something else.
+pub const DEFAULT_MAINTENANCE_MINUTES: i64 = 30;
+
+/// Maintenance turned on by an admin can't last any longer than this, so it can't be forgotten about.
+pub const MAX_MAINTENANCE_MINUTES: i64 = 24 * 60;
+
+/// Buffered images are named <random ID>.jpg, so camera tokens aren't in file names and two images can't share one.
+const BUFFER_EXTENSION: &str = "jpg";
+
+/// Each buffered image has a <random ID>.json next to it, saying when it was taken and which camera it's from.
+const SIDECAR_EXTENSION: &str = "json";
+
+/// Added to a buffered image's name while it's being stored, so only one instance stores it.
+const REPLAYING_EXTENSION: &str = "replaying";
+
+/// Buffered images that have been replaying for this long were left behind by an instance that stopped, and are
+/// tried again.
+const STALE_REPLAY_SECONDS: u64 = 10 * 60;
+
+/// Maintenance turned on with PUT /Admin/Maintenance.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct MaintenanceWindow {
+    pub started_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    /// The admin who turned it on.
+    #[schemars(with = "String")]
+    pub started_by: uuid::Uuid,
+    pub reason: Option<String>,
+}
+
+/// Sent with PUT /Admin/Maintenance.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewMaintenance {
+    /// How long until maintenance ends by itself, up to MAX_MAINTENANCE_MINUTES.
+    /// Defaults to DEFAULT_MAINTENANCE_MINUTES.
+    pub minutes: Option<i64>,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct MaintenanceStatus {
+    pub active: bool,
+    /// Whether enabled in [maintenance] is on, which only a restart can turn off.
+    pub from_settings: bool,
+    /// None if no admin has turned it on, or it has ended.
+    pub window: Option<MaintenanceWindow>,
+    /// Images waiting to be stored on this instance.
+    pub buffered_images: u64,
+    pub buffered_bytes: u64,
+}
+
+/// How many bytes each camera token has in this instance's buffer, so one camera (or someone making up tokens)
+/// can't fill it for everyone else. Forgotten on restart, which only loosens the limit until the buffer is replayed.
+static BUFFERED_BYTES: Lazy<Mutex<HashMap<uuid::Uuid, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Only used without Redis. The in-process cache can drop entries when it's full, which would end maintenance early.
+static LOCAL_WINDOW: Lazy<Mutex<Option<MaintenanceWindow>>> = Lazy::new(|| Mutex::new(None));
+
+/// The window an admin turned on, if it hasn't ended. Every instance shares it when there's a Redis cache,
+/// otherwise it only applies to the instance it was turned on with.
+fn window() -> Option<MaintenanceWindow> {
+    let window = if cache::redis_pool().is_some() {
+        cache()
+            .get(&cache::maintenance_key())
+            .and_then(|window| serde_json::from_str::<MaintenanceWindow>(&window).ok())
+    } else {
+        LOCAL_WINDOW
+            .lock()
+            .expect("Maintenance lock poisoned!")
+            .clone()
+    };
+
+    window.filter(|window| window.ends_at > Utc::now())
+}
+
+fn set_window(window: Option<&MaintenanceWindow>) {
+    if cache::redis_pool().is_some() {
+        match window {
+            Some(window) => cache().set(
+                &cache::maintenance_key(),
+                &serde_json::to_string(window).expect("Failed to serialize maintenance somehow?"),
+                (window.ends_at - Utc::now())
+                    .to_std()
+                    .unwrap_or_else(|_| Duration::from_secs(0)),
+            ),
+            None => cache().delete(&cache::maintenance_key()),
+        }
+    } else {
+        *LOCAL_WINDOW.lock().expect("Maintenance lock poisoned!") = window.cloned();
+    }
+}
+
+/// Whether the server is in maintenance, from the settings or an admin.
+pub fn active() -> bool {
+    settings().maintenance.enabled || window().is_some()
+}
+
+/// Where images uploaded during maintenance wait, set with buffer_directory in [maintenance].
+/// Defaults to maintenance-buffer. Each instance should have its own unless they can all reach the database.
+pub fn buffer_directory() -> PathBuf {
+    PathBuf::from(&settings().maintenance.buffer_directory)
+}
+
+/// How many images are buffered and how big they are altogether.
+fn buffer_size() -> io::Result<(u64, u64)> {
+    let directory = buffer_directory();
+    if !directory.exists() {
+        return Ok((0, 0));
+    }
+
+    let mut images = 0;
+    let mut bytes = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let name = name
+                .strip_suffix(&format!(".{}", REPLAYING_EXTENSION))
+                .unwrap_or(&name);
+            if buffered_image_id(name).is_some() {
+                images += 1;
+            }
+            bytes += metadata.len();
+        }
+    }
+
+    Ok((images, bytes))
+}
+
+fn buffer_error(error: io::Error) -> ApiError {
+    error!("Failed to buffer image! The error was {}", error);
+    ApiError {
+        error: "Failed to buffer image",
+        code: "buffer_image_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// A buffered image's sidecar. The camera token is kept rather than the camera, as the database may be down
+/// during maintenance, and is looked up when the image is replayed.
+#[derive(Serialize, Deserialize)]
+struct BufferedImage {
+    /// Seconds since epoch, which the image is stored as.
+    captured_at: u64,
+    camera_token: uuid::Uuid,
+}
+
+/// Counts `bytes` towards the camera token's share of the buffer, or refuses them if they'd take it over
+/// max_buffer_megabytes_per_camera in [maintenance].
+fn reserve_buffer(camera_token: uuid::Uuid, bytes: u64) -> Result<(), ApiError> {
+    let mut buffered = BUFFERED_BYTES
+        .lock()
+        .expect("Maintenance buffer lock poisoned!");
+    let camera_bytes = buffered.entry(camera_token).or_insert(0);
+
+    if *camera_bytes + bytes > settings().maintenance.max_buffer_megabytes_per_camera * 1024 * 1024
+    {
+        warn!("Refused an image during maintenance, its camera has filled its share of the buffer");
+        return Err(ApiError {
+            error: "The server is in maintenance and can't hold any more images from this camera",
+            code: "maintenance_camera_buffer_full",
+            status: Status::ServiceUnavailable,
+            field: None,
+        });
+    }
+
+    *camera_bytes += bytes;
+    Ok(())
+}
+
+/// Takes `bytes` back off the camera token's share of the buffer, once they've been replayed or couldn't be written.
+fn release_buffer(camera_token: uuid::Uuid, bytes: u64) {
+    let mut buffered = BUFFERED_BYTES
+        .lock()
+        .expect("Maintenance buffer lock poisoned!");
+
+    if let Some(camera_bytes) = buffered.get_mut(&camera_token) {
+        *camera_bytes = camera_bytes.saturating_sub(bytes);
+        if *camera_bytes == 0 {
+            buffered.remove(&camera_token);
+        }
+    }
+}
+
+/// Writes the image to the buffer, returning the seconds since epoch it'll be stored as.
+fn buffer_image_from(
+    camera_token: &BufferCameraToken,
+    image: &mut dyn Read,
+) -> Result<u64, ApiError> {
+    if !active() {
+        return Err(ApiError {
+            error: "The server isn't in maintenance",
+            code: "not_in_maintenance",
+            status: Status::NotFound,
+            field: None,
+        });
+    }
+
+    if !camera_token.verified {
+        let now = Utc::now().timestamp();
+        let window_start = now - now % 60;
+        let images = cache()
+            .increment(
+                &cache::unverified_buffer_key(&camera_token.client, window_start),
+                Duration::from_secs(60),
+            )
+            .unwrap_or(0);
+
+        if images > settings().maintenance.unverified_images_per_minute {
+            warn!(
+                "Refused an image during maintenance from {}, it has sent too many unverified ones",
+                camera_token.client
+            );
+            return Err(ApiError {
+                error: "The server is in maintenance and can't check this camera token, try again later",
+                code: "maintenance_token_unchecked",
+                status: Status::TooManyRequests,
+                field: None,
+            });
+        }
+    }
+
+    let (_, buffered_bytes) = buffer_size().map_err(buffer_error)?;
+    if buffered_bytes >= settings().maintenance.max_buffer_megabytes * 1024 * 1024 {
+        warn!("Refused an image during maintenance, the buffer is full");
+        return Err(ApiError {
+            error: "The server is in maintenance and can't hold any more images",
+            code: "maintenance_buffer_full",
+            status: Status::ServiceUnavailable,
+            field: None,
+        });
+    }
+
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get current time somehow?")
+        .as_secs();
+
+    let directory = buffer_directory();
+    fs::create_dir_all(&directory).map_err(buffer_error)?;
+
+    // Written under another name first, so it isn't replayed before it's all there
+    let id = uuid::Uuid::new_v4();
+    let name = format!("{}.{}", id, BUFFER_EXTENSION);
+    let partial_path = directory.join(format!(".{}.part", name));
+
+    let mut body = Vec::new();
+    image
+        .take(MAX_FILE_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(buffer_error)?;
+    if body.len() as u64 > MAX_FILE_BYTES {
+        return Err(ApiError {
+            error: "The image is too big",
+            code: "image_too_large",
+            status: Status::PayloadTooLarge,
+            field: None,
+        });
+    }
+
+    let camera_token = camera_token.camera_token;
+    reserve_buffer(camera_token, body.len() as u64)?;
+
+    // The sidecar goes first, so every image that can be replayed has one
+    let sidecar = BufferedImage {
+        captured_at: current_time,
+        camera_token,
+    };
+    fs::write(
+        directory.join(format!("{}.{}", id, SIDECAR_EXTENSION)),
+        serde_json::to_vec(&sidecar).expect("Failed to serialize buffered image somehow?"),
+    )
+    .and_then(|_| File::create(&partial_path))
+    .and_then(|mut file| file.write_all(&body))
+    .and_then(|_| fs::rename(&partial_path, directory.join(name)))
+    .map_err(|error| {
+        release_buffer(camera_token, body.len() as u64);
+        buffer_error(error)
+    })?;
+
+    Ok(current_time)
+}
+
+/// The camera token header, checked against the cache rather than the database, which may be down.
+/// Tokens that aren't cached are still taken, but only unverified_images_per_minute in [maintenance] from each
+/// address, and buffered images with tokens that don't exist are dropped when they're replayed.
+pub struct BufferCameraToken {
+    pub camera_token: uuid::Uuid,
+    /// Whether the token was cached, which it only is if it was found in the database.
+    pub verified: bool,
+    /// Who sent the image, which unverified tokens are rate limited by.
+    pub client: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for BufferCameraToken {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let camera_token = match request.headers().get_one("camera_token") {
+            Some(token) => match uuid::Uuid::parse_str(token) {
+                Ok(token) => token,
+                Err(_) => return Outcome::Failure((Status::BadRequest, TokenError::ParseError)),
+            },
+            None => return Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        };
+
+        Outcome::Success(BufferCameraToken {
+            camera_token,
+            verified: cache()
+                .get(&cache::camera_token_key(camera_token))
+                .is_some(),
+            client: match rate_limit::client_address(request) {
+                Some(address) => address.to_string(),
+                None => String::from("unknown"),
+            },
+        })
+    }
+}
+
+/// POST /Device/Images during maintenance. The image is stored when maintenance ends, with the time it was
+/// uploaded as its ID, so the camera gets the same response as it would have.
+#[openapi(skip)]
+#[post("/Maintenance/Device/Images", format = "image/jpeg", data = "<image>")]
+pub fn buffer_image(camera_token: BufferCameraToken, image: Data) -> Result<String, ApiError> {
+    buffer_image_from(&camera_token, &mut image.open()).map(|image_id| image_id.to_string())
+}
+
+/// The multipart POST /Device/Images during maintenance. Only the image is buffered, an event in the metadata
+/// can't be reported until maintenance is over.
+#[openapi(skip)]
+#[post(
+    "/Maintenance/Device/Images",
+    format = "multipart/form-data",
+    data = "<upload>"
+)]
+pub fn buffer_image_multipart(
+    camera_token: BufferCameraToken,
+    upload: MultipartUpload,
+) -> Result<Device<ImageUpload>, ApiError> {
+    match upload.content_type.as_deref() {
+        None | Some("image/jpeg") => {}
+        Some(_) => {
+            return Err(ApiError {
+                error: "Images must be JPEGs",
+                code: "image_not_jpeg",
+                status: Status::UnsupportedMediaType,
+                field: Some("file"),
+            })
+        }
+    }
+
+    if upload.metadata.event.is_some() {
+        warn!("Dropped an event uploaded with an image during maintenance");
+    }
+
+    buffer_image_from(&camera_token, &mut upload.file.as_slice()).map(|image_id| {
+        Device(ImageUpload {
+            image_id: image_id.to_string(),
+            event: None,
+        })
+    })
+}
+
+/// The random ID in a buffered image's file name, or None if it isn't one.
+fn buffered_image_id(name: &str) -> Option<uuid::Uuid> {
+    let stem = name.strip_suffix(&format!(".{}", BUFFER_EXTENSION))?;
+    uuid::Uuid::parse_str(stem).ok()
+}
+
+/// Reads the sidecar for the buffered image with the given ID.
+fn read_sidecar(directory: &Path, id: uuid::Uuid) -> Option<BufferedImage> {
+    fs::read(directory.join(format!("{}.{}", id, SIDECAR_EXTENSION)))
+        .ok()
+        .and_then(|sidecar| serde_json::from_slice(&sidecar).ok())
+}
+
+fn remove_buffered_image(directory: &Path, id: uuid::Uuid, path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    fs::remove_file(directory.join(format!("{}.{}", id, SIDECAR_EXTENSION)))
+}
+
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(false, |elapsed| elapsed.as_secs() >= STALE_REPLAY_SECONDS)
+}
+
+enum Replayed {
+    Stored,
+    Dropped,
+    /// The database is probably still unavailable, so the image is kept for next time.
+    Retry,
+}
+
+fn replay_image(
+    current_time: u64,
+    camera_token: uuid::Uuid,
+    path: &Path,
+    connection: &PgConnection,
+) -> Replayed {
+    let camera_id = match camera_tokens::get(camera_token, connection) {
+        Ok(camera_token) => camera_token.camera_id,
+        Err(diesel::result::Error::NotFound) => {
+            warn!("Dropped an image buffered during maintenance, its camera token doesn't exist");
+            return Replayed::Dropped;
+        }
+        Err(error) => {
+            error!(
+                "Failed to get the camera for a buffered image! The error was {}",
+                error
+            );
+            return Replayed::Retry;
+        }
+    };
+
+    // The tenant the image was sent to can't be worked out while the database is down, so only the owner is checked
+    match camera_tokens::authorise_camera(camera_id, None, || connection) {
+        Ok(()) => {}
+        Err(rejection) if rejection.status == Status::ServiceUnavailable => return Replayed::Retry,
+        Err(rejection) => {
+            info!(
+                "Dropped an image buffered during maintenance for camera {}: {}",
+                camera_id,
+                rejection.message()
+            );
+            return Replayed::Dropped;
+        }
+    }
+
+    let stored = File::open(path)
+        .map_err(buffer_error)
+        .and_then(|mut image| {
+            camera::store_image_taken_at(camera_id, current_time, &mut image, connection)
+        });
+
+    match stored {
+        Ok(_) => Replayed::Stored,
+        Err(error) if error.status.code >= 500 => Replayed::Retry,
+        Err(error) => {
+            warn!(
+                "Dropped image {} buffered during maintenance for camera {}: {}",
+                current_time, camera_id, error.error
+            );
+            Replayed::Dropped
+        }
+    }
+}
+
+/// Stores every buffered image, oldest first. Returns how many were stored.
+fn replay_buffer(connection: &PgConnection) -> io::Result<usize> {
+    let directory = buffer_directory();
+    if !directory.exists() {
+        return Ok(0);
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let path = directory.join(&name);
+
+        if let Some(claimed) = name.strip_suffix(&format!(".{}", REPLAYING_EXTENSION)) {
+            if is_stale(&path) {
+                names.push(claimed.to_string());
+                fs::rename(&path, directory.join(claimed))?;
+            }
+        } else if buffered_image_id(&name).is_some() {
+            names.push(name);
+        }
+    }
+
+    let mut images = Vec::new();
+    for name in names {
+        let id = match buffered_image_id(&name) {
+            Some(id) => id,
+            None => continue,
+        };
+
+        match read_sidecar(&directory, id) {
+            Some(sidecar) => images.push((sidecar, id, name)),
+            // There's no telling which camera it's from. Unless another instance has just stored it
+            None => {
+                if fs::remove_file(directory.join(&name)).is_ok() {
+                    warn!("Dropped an image buffered during maintenance, its sidecar couldn't be read");
+                }
+            }
+        }
+    }
+    images.sort_by_key(|(sidecar, _, _)| sidecar.captured_at);
+
+    let mut stored = 0;
+    for (sidecar, id, name) in images {
+        // Another instance sharing the directory got to it first
+        let claimed_path = directory.join(format!("{}.{}", name, REPLAYING_EXTENSION));
+        if fs::rename(directory.join(&name), &claimed_path).is_err() {
+            continue;
+        }
+
+        let bytes = fs::metadata(&claimed_path).map_or(0, |metadata| metadata.len());
+
+        match replay_image(
+            sidecar.captured_at,
+            sidecar.camera_token,
+            &claimed_path,
+            connection,
+        ) {
+            Replayed::Stored => {
+                stored += 1;
+                remove_buffered_image(&directory, id, &claimed_path)?;
+                release_buffer(sidecar.camera_token, bytes);
+            }
+            Replayed::Dropped => {
+                remove_buffered_image(&directory, id, &claimed_path)?;
+                release_buffer(sidecar.camera_token, bytes);
+            }
+            Replayed::Retry => {
+                fs::rename(&claimed_path, directory.join(&name))?;
+                break;
+            }
+        }
+    }
+
+    Ok(stored)
+}
+
+/// Stores the images buffered during maintenance once it's over. Every instance replays its own buffer.
+pub fn spawn_buffer_replay(database_url: String) {
+    worker::spawn_concurrent_worker(
+        "Maintenance buffer replay",
+        Duration::from_secs(30),
+        database_url,
+        |connection| {
+            if active() {
+                return;
+            }
+
+            match replay_buffer(connection) {
+                Ok(0) => {}
+                Ok(stored) => info!("Stored {} images buffered during maintenance", stored),
+                Err(error) => error!(
+                    "Failed to store images buffered during maintenance! The error was {}",
+                    error
+                ),
+            }
+        },
+    );
+}
+
+/// Stored in a request's local cache when it's refused.
+struct Refused(bool);
+
+/// During maintenance, refuses anything that could write to the database with a 503 and Retry-After, and sends
+/// cameras' images to the buffer instead. Reads, health checks and PUT/DELETE /Admin/Maintenance still go through.
+pub struct MaintenanceMode;
+
+impl Fairing for MaintenanceMode {
+    fn info(&self) -> Info {
+        Info {
+            name: "Maintenance mode",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // ShutdownDraining or SchemaGuard has already refused it
+        if shutdown::shutting_down() || schema_check::mismatch().is_some() || !active() {
+            return;
+        }
+
+        let method = request.method();
+        if let Method::Get | Method::Head | Method::Options = method {
+            return;
+        }
+
+        let path = request.uri().path().to_string();
+        if UNVERSIONED_PATHS.contains(&path.as_str())
+            || path == format!("{}/Admin/Maintenance", API_PREFIX)
+        {
+            return;
+        }
+
+        let new_path = if method == Method::Post && path == format!("{}/Device/Images", API_PREFIX)
+        {
+            BUFFER_PATH
+        } else {
+            request.local_cache(|| Refused(true));
+            MAINTENANCE_PATH
+        };
+
+        match Origin::parse_owned(format!("{}{}", API_PREFIX, new_path)) {
+            Ok(origin) => request.set_uri(origin),
+            Err(error) => error!(
+                "Failed to reroute request during maintenance! The error was {}",
+                error
+            ),
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Refused(true) = request.local_cache(|| Refused(false)) {
+            let body = ErrorBody {
+                code: error_code(Status::ServiceUnavailable),
+                message: "The server is in maintenance, try again shortly",
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request);
+
+            response.set_status(Status::ServiceUnavailable);
+            response.set_header(ContentType::JSON);
+            response.set_header(Header::new(
+                "Retry-After",
+                settings().maintenance.retry_after_seconds.to_string(),
+            ));
+            response.set_sized_body(Cursor::new(
+                serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+            ));
+        }
+    }
+}
+
+fn status() -> Result<MaintenanceStatus, ApiError> {
+    let (buffered_images, buffered_bytes) = buffer_size().map_err(|error| {
+        error!(
+            "Failed to read the maintenance buffer! The error was {}",
+            error
+        );
+        ApiError {
+            error: "Failed to read the maintenance buffer",
+            code: "read_maintenance_buffer_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(MaintenanceStatus {
+        active: active(),
+        from_settings: settings().maintenance.enabled,
+        window: window(),
+        buffered_images,
+        buffered_bytes,
+    })
+}
+
+/// Whether the server is in maintenance, and how many images are waiting to be stored.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Maintenance")]
+pub fn get_maintenance(_admin_token: AdminToken) -> Result<Json<MaintenanceStatus>, ApiError> {
+    status().map(Json)
+}
+
+/// Puts the server in maintenance until DELETE /Admin/Maintenance, or `minutes` pass. Without Redis, only this
+/// instance goes into maintenance. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[put("/Admin/Maintenance", format = "json", data = "<new_maintenance>")]
+pub fn start_maintenance(
+    admin_token: AdminToken,
+    new_maintenance: Json<NewMaintenance>,
+) -> Result<Json<MaintenanceStatus>, ApiError> {
+    let new_maintenance = new_maintenance.into_inner();
+
+    let minutes = new_maintenance
+        .minutes
+        .unwrap_or(DEFAULT_MAINTENANCE_MINUTES);
+    if minutes < 1 || minutes > MAX_MAINTENANCE_MINUTES {
+        return Err(ApiError {
+            error: "minutes must be between 1 and MAX_MAINTENANCE_MINUTES",
+            code: "invalid_minutes",
+            status: Status::UnprocessableEntity,
+            field: Some("minutes"),
+        });
+    }
+
+    let started_at = Utc::now();
+    let window = MaintenanceWindow {
+        started_at,
+        ends_at: started_at + ChronoDuration::minutes(minutes),
+        started_by: admin_token.user_id,
+        reason: new_maintenance.reason,
+    };
+
+    set_window(Some(&window));
+    warn!(
+        "Maintenance started by {} until {}",
+        window.started_by, window.ends_at
+    );
+    audit::record_after(&window);
+
+    status().map(Json)
+}
+
+/// Ends maintenance an admin started. Buffered images are stored within 30 seconds. It can't end maintenance from
+/// enabled in [maintenance]. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Maintenance")]
+pub fn end_maintenance(admin_token: AdminToken) -> Result<Json<MaintenanceStatus>, ApiError> {
+    if let Some(window) = window() {
+        audit::record_before(&window);
+    }
+
+    set_window(None);
+    warn!("Maintenance ended by {}", admin_token.user_id);
+
+    status().map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffered_image_ids_are_read_from_names() {
+        let id = uuid::Uuid::new_v4();
+        assert_eq!(buffered_image_id(&format!("{}.jpg", id)), Some(id));
+        assert_eq!(buffered_image_id(&format!("{}.json", id)), None);
+        assert_eq!(buffered_image_id(&format!(".{}.jpg.part", id)), None);
+        assert_eq!(buffered_image_id("1625900000_camera.jpg"), None);
+    }
+}
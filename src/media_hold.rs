@@ -0,0 +1,454 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audit,
+    camera::{self, CameraId},
+    media_store::{media_store, MediaStore},
+    soft_delete::not_found_or_database_error,
+    storage::{AUDIO, IMAGE, VIDEO},
+    user_tokens::UserToken,
+    users_cameras::{self, check_if_user_owns_camera},
+    CameraServerDbConn,
+};
+
+use super::schema::{audio_clips, media_holds, recordings};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Stops a camera's footage from being deleted by plan retention, or by purging the camera once it's been deleted,
+/// e.g. for a legal hold. It's either on one item or on everything the camera recorded between two times.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct MediaHold {
+    pub hold_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// Who placed it. None if they've since been purged.
+    #[schemars(with = "Option<String>")]
+    pub user_id: Option<uuid::Uuid>,
+    /// Holds placed by admins can only be released by admins.
+    pub placed_by_admin: bool,
+    /// image, audio or video, for a hold on one item.
+    pub media_type: Option<String>,
+    /// The item's ID. Image IDs are when they were taken, in seconds since the epoch.
+    pub media_id: Option<i64>,
+    /// For a hold on a range, which keeps anything recorded during it.
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "media_holds"]
+pub struct InsertableMediaHold {
+    pub camera_id: uuid::Uuid,
+    pub user_id: Option<uuid::Uuid>,
+    pub placed_by_admin: bool,
+    pub media_type: Option<String>,
+    pub media_id: Option<i64>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub reason: String,
+}
+
+/// Sent with POST /Cameras/<camera_id>/Holds and POST /Admin/Cameras/<camera_id>/Holds. Set either media_type and
+/// media_id, or starts_at and ends_at.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewMediaHold {
+    pub media_type: Option<String>,
+    pub media_id: Option<i64>,
+    pub starts_at: Option<DateTime<Utc>>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub reason: String,
+}
+
+impl MediaHold {
+    /// Whether the hold covers the item, which was recorded between `started_at` and `ended_at`.
+    pub fn covers(
+        &self,
+        media_type: &str,
+        media_id: i64,
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+    ) -> bool {
+        match (
+            &self.media_type,
+            self.media_id,
+            self.starts_at,
+            self.ends_at,
+        ) {
+            (Some(hold_type), Some(hold_id), _, _) => {
+                hold_type == media_type && hold_id == media_id
+            }
+            (_, _, Some(starts_at), Some(ends_at)) => {
+                started_at <= ends_at && ended_at >= starts_at
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether any of the holds cover the item, see MediaHold::covers().
+pub fn is_held(
+    holds: &[MediaHold],
+    media_type: &str,
+    media_id: i64,
+    started_at: DateTime<Utc>,
+    ended_at: DateTime<Utc>,
+) -> bool {
+    holds
+        .iter()
+        .any(|hold| hold.covers(media_type, media_id, started_at, ended_at))
+}
+
+pub fn get_cameras_holds(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<MediaHold>> {
+    media_holds::table
+        .filter(media_holds::camera_id.eq(camera_id))
+        .order(media_holds::hold_id)
+        .load(connection)
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to update media holds! The error was {}", error);
+    ApiError {
+        error: "Failed to update media holds",
+        code: "update_media_holds_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Checks the new hold is either on an item the camera has or on a range, and has a reason.
+fn validate(
+    camera_id: uuid::Uuid,
+    new_hold: &NewMediaHold,
+    connection: &PgConnection,
+) -> Result<(), ApiError> {
+    if new_hold.reason.trim().is_empty() {
+        return Err(ApiError {
+            error: "Hold reason can't be empty",
+            code: "hold_reason_required",
+            status: Status::UnprocessableEntity,
+            field: Some("reason"),
+        });
+    }
+
+    match (
+        &new_hold.media_type,
+        new_hold.media_id,
+        new_hold.starts_at,
+        new_hold.ends_at,
+    ) {
+        (Some(media_type), Some(media_id), None, None) => {
+            let exists = match media_type.as_str() {
+                IMAGE => media_store()
+                    .open_image(&camera_id, media_id as u64)
+                    .is_ok(),
+                AUDIO => {
+                    audio_clips::table
+                        .filter(audio_clips::camera_id.eq(camera_id))
+                        .filter(audio_clips::audio_id.eq(media_id as i32))
+                        .count()
+                        .get_result::<i64>(connection)
+                        .map_err(database_error)?
+                        > 0
+                }
+                VIDEO => {
+                    recordings::table
+                        .filter(recordings::camera_id.eq(camera_id))
+                        .filter(recordings::recording_id.eq(media_id as i32))
+                        .count()
+                        .get_result::<i64>(connection)
+                        .map_err(database_error)?
+                        > 0
+                }
+                _ => {
+                    return Err(ApiError {
+                        error: "Media type must be image, audio or video",
+                        code: "invalid_media_type",
+                        status: Status::UnprocessableEntity,
+                        field: Some("media_type"),
+                    })
+                }
+            };
+
+            if !exists {
+                return Err(ApiError {
+                    error: "The camera doesn't have that media",
+                    code: "media_not_found",
+                    status: Status::NotFound,
+                    field: Some("media_id"),
+                });
+            }
+            Ok(())
+        }
+        (None, None, Some(starts_at), Some(ends_at)) if starts_at <= ends_at => Ok(()),
+        (None, None, Some(_), Some(_)) => Err(ApiError {
+            error: "Holds can't end before they start",
+            code: "invalid_hold_range",
+            status: Status::UnprocessableEntity,
+            field: Some("ends_at"),
+        }),
+        _ => Err(ApiError {
+            error: "Holds need either media_type and media_id, or starts_at and ends_at",
+            code: "invalid_hold",
+            status: Status::UnprocessableEntity,
+            field: None,
+        }),
+    }
+}
+
+fn place_hold(
+    camera_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    placed_by_admin: bool,
+    new_hold: NewMediaHold,
+    connection: &PgConnection,
+) -> Result<Json<MediaHold>, ApiError> {
+    validate(camera_id, &new_hold, connection)?;
+
+    let hold = diesel::insert_into(media_holds::table)
+        .values(InsertableMediaHold {
+            camera_id,
+            user_id: Some(user_id),
+            placed_by_admin,
+            media_type: new_hold.media_type,
+            media_id: new_hold.media_id,
+            starts_at: new_hold.starts_at,
+            ends_at: new_hold.ends_at,
+            reason: new_hold.reason,
+        })
+        .get_result::<MediaHold>(connection)
+        .map_err(database_error)?;
+    audit::record_after(&hold);
+
+    info!(
+        "User {} placed hold {} on camera {}",
+        user_id, hold.hold_id, camera_id
+    );
+
+    Ok(Json(hold))
+}
+
+/// Keeps an item or a range of the camera's footage from being deleted until the hold is released. Only for the
+/// camera's owner.
+#[openapi]
+#[post("/Cameras/<camera_id>/Holds", format = "json", data = "<new_hold>")]
+pub fn create_media_hold(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_hold: Json<NewMediaHold>,
+) -> Result<Json<MediaHold>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    place_hold(
+        camera_id,
+        user_token.user_id,
+        false,
+        new_hold.into_inner(),
+        &conn,
+    )
+}
+
+/// Releases a hold on the camera's footage, which can then be deleted as usual. Only for the camera's owner, and
+/// not for holds placed by admins.
+#[openapi]
+#[delete("/Cameras/<camera_id>/Holds/<hold_id>")]
+pub fn delete_media_hold(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    hold_id: i32,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    let hold = media_holds::table
+        .filter(media_holds::hold_id.eq(hold_id))
+        .filter(media_holds::camera_id.eq(camera_id))
+        .get_result::<MediaHold>(&*conn)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("hold_not_found", "Hold not found"),
+                ("get_hold_failed", "Failed to get hold"),
+            )
+        })?;
+
+    if hold.placed_by_admin {
+        return Err(ApiError {
+            error: "Only admins can release holds placed by admins",
+            code: "admin_hold",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    audit::record_before(&hold);
+    diesel::delete(media_holds::table.find(hold_id))
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(database_error)
+}
+
+/// Every hold on footage from cameras the user owns.
+#[openapi]
+#[get("/Holds")]
+pub fn get_media_holds(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<MediaHold>>, ApiError> {
+    let camera_ids =
+        users_cameras::get_owned_camera_ids(user_token.user_id, &conn).map_err(database_error)?;
+
+    media_holds::table
+        .filter(media_holds::camera_id.eq_any(camera_ids))
+        .order(media_holds::hold_id)
+        .load::<MediaHold>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Places a hold on any camera's footage, which its owner can't release. Deleted cameras that haven't been purged
+/// yet can be held too, which stops them being purged. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post(
+    "/Admin/Cameras/<camera_id>/Holds",
+    format = "json",
+    data = "<new_hold>"
+)]
+pub fn admin_create_media_hold(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    camera_id: CameraId,
+    new_hold: Json<NewMediaHold>,
+) -> Result<Json<MediaHold>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    camera::get_including_deleted(camera_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("camera_not_found", "Camera not found"),
+            ("place_hold_failed", "Failed to place hold"),
+        )
+    })?;
+
+    place_hold(
+        camera_id,
+        admin_token.user_id,
+        true,
+        new_hold.into_inner(),
+        &conn,
+    )
+}
+
+/// Releases any hold. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Holds/<hold_id>")]
+pub fn admin_delete_media_hold(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    hold_id: i32,
+) -> Result<(), ApiError> {
+    let hold = media_holds::table
+        .find(hold_id)
+        .get_result::<MediaHold>(&*conn)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("hold_not_found", "Hold not found"),
+                ("get_hold_failed", "Failed to get hold"),
+            )
+        })?;
+
+    audit::record_before(&hold);
+    diesel::delete(media_holds::table.find(hold_id))
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(database_error)
+}
+
+/// Every hold on every camera's footage. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Holds")]
+pub fn admin_get_media_holds(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<MediaHold>>, ApiError> {
+    media_holds::table
+        .order(media_holds::hold_id)
+        .load::<MediaHold>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn hold(
+        media: Option<(&str, i64)>,
+        range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    ) -> MediaHold {
+        MediaHold {
+            hold_id: 1,
+            camera_id: uuid::Uuid::nil(),
+            user_id: None,
+            placed_by_admin: false,
+            media_type: media.map(|(media_type, _)| media_type.to_string()),
+            media_id: media.map(|(_, media_id)| media_id),
+            starts_at: range.map(|(starts_at, _)| starts_at),
+            ends_at: range.map(|(_, ends_at)| ends_at),
+            reason: String::from("Court order"),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn holds_on_an_item_only_cover_it() {
+        let at = Utc.ymd(2021, 1, 1).and_hms(12, 0, 0);
+        let holds = vec![hold(Some((IMAGE, 100)), None)];
+
+        assert!(is_held(&holds, IMAGE, 100, at, at));
+        assert!(!is_held(&holds, IMAGE, 101, at, at));
+        assert!(!is_held(&holds, VIDEO, 100, at, at));
+    }
+
+    #[test]
+    fn holds_on_a_range_cover_anything_recorded_during_it() {
+        let starts_at = Utc.ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let ends_at = Utc.ymd(2021, 2, 1).and_hms(0, 0, 0);
+        let holds = vec![hold(None, Some((starts_at, ends_at)))];
+
+        let during = Utc.ymd(2021, 1, 15).and_hms(0, 0, 0);
+        assert!(is_held(&holds, IMAGE, 1, during, during));
+        assert!(is_held(&holds, AUDIO, 1, starts_at, starts_at));
+
+        // Recordings that only overlap the range are kept whole
+        let before = Utc.ymd(2020, 12, 31).and_hms(23, 0, 0);
+        assert!(is_held(&holds, VIDEO, 1, before, during));
+
+        let after = Utc.ymd(2021, 2, 2).and_hms(0, 0, 0);
+        assert!(!is_held(&holds, IMAGE, 1, after, after));
+        assert!(!is_held(&holds, VIDEO, 1, before, before));
+    }
+
+    #[test]
+    fn nothing_is_held_without_holds() {
+        let at = Utc::now();
+
+        assert!(!is_held(&[], IMAGE, 1, at, at));
+    }
+}
@@ -0,0 +1,368 @@
+use crate::settings::settings;
+
+use std::collections::HashSet;
+use std::fs::{self, create_dir_all, read_dir, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Somewhere camera images can be kept. Images are addressed by their camera's ID and their image ID,
+/// which is the number of seconds since epoch at which they were uploaded.
+pub trait MediaStore: Send + Sync {
+    /// Returns the IDs of every image stored for the camera, in no particular order.
+    /// Returns an empty Vec if the store has never seen the camera.
+    fn list_images(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<u64>>;
+
+    /// Returns the IDs of every camera that has at least one image in the store.
+    fn list_cameras(&self) -> io::Result<Vec<uuid::Uuid>>;
+
+    fn open_image(&self, camera_id: &uuid::Uuid, image_id: u64)
+        -> io::Result<Box<dyn Read + Send>>;
+
+    /// Writes an image to the store, replacing any image with the same ID. Returns the number of bytes written.
+    fn store_image(
+        &self,
+        camera_id: &uuid::Uuid,
+        image_id: u64,
+        image: &mut dyn Read,
+    ) -> io::Result<u64>;
+
+    fn delete_image(&self, camera_id: &uuid::Uuid, image_id: u64) -> io::Result<()>;
+
+    /// Returns how many bytes the camera's images take up in the store.
+    fn storage_used(&self, camera_id: &uuid::Uuid) -> io::Result<u64>;
+
+    /// Returns the ID and size in bytes of every image stored for the camera, in no particular order.
+    fn image_sizes(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<(u64, u64)>>;
+}
+
+/// Writes to <path>.part and renames it to `path` once everything has been written, so a server that's stopped
+/// part way through an upload can't leave a truncated file behind. Returns how many bytes were written.
+pub fn write_whole_file(path: &str, contents: &mut dyn Read) -> io::Result<u64> {
+    let partial_path = format!("{}.part", path);
+    let mut file = File::create(&partial_path)?;
+
+    match io::copy(contents, &mut file).and_then(|size| file.sync_all().map(|_| size)) {
+        Ok(size) => {
+            fs::rename(&partial_path, path)?;
+            Ok(size)
+        }
+        Err(error) => {
+            let _ = fs::remove_file(&partial_path);
+            Err(error)
+        }
+    }
+}
+
+/// Stores images on the local filesystem as <root>/<camera_id>/<image_id>.jpg
+pub struct LocalMediaStore {
+    pub root: String,
+}
+
+impl LocalMediaStore {
+    pub fn image_path(&self, camera_id: &uuid::Uuid, image_id: u64) -> String {
+        format!("{}/{}/{}.jpg", self.root, camera_id, image_id)
+    }
+}
+
+impl MediaStore for LocalMediaStore {
+    fn list_images(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<u64>> {
+        let camera_directory = format!("{}/{}", self.root, camera_id);
+
+        if !Path::new(&camera_directory).exists() {
+            return Ok(Vec::new());
+        }
+
+        Ok(read_dir(camera_directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                Path::new(&entry.file_name())
+                    .file_stem()
+                    .and_then(|file_stem| file_stem.to_str())
+                    .and_then(|file_stem| file_stem.parse::<u64>().ok())
+            })
+            .collect())
+    }
+
+    fn list_cameras(&self) -> io::Result<Vec<uuid::Uuid>> {
+        if !Path::new(&self.root).exists() {
+            return Ok(Vec::new());
+        }
+
+        Ok(read_dir(&self.root)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|file_name| uuid::Uuid::parse_str(file_name).ok())
+            })
+            .collect())
+    }
+
+    fn open_image(
+        &self,
+        camera_id: &uuid::Uuid,
+        image_id: u64,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.image_path(camera_id, image_id))?))
+    }
+
+    fn store_image(
+        &self,
+        camera_id: &uuid::Uuid,
+        image_id: u64,
+        image: &mut dyn Read,
+    ) -> io::Result<u64> {
+        create_dir_all(format!("{}/{}", self.root, camera_id))?;
+        write_whole_file(&self.image_path(camera_id, image_id), image)
+    }
+
+    fn delete_image(&self, camera_id: &uuid::Uuid, image_id: u64) -> io::Result<()> {
+        fs::remove_file(self.image_path(camera_id, image_id))
+    }
+
+    fn storage_used(&self, camera_id: &uuid::Uuid) -> io::Result<u64> {
+        let camera_directory = format!("{}/{}", self.root, camera_id);
+
+        if !Path::new(&camera_directory).exists() {
+            return Ok(0);
+        }
+
+        Ok(read_dir(camera_directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum())
+    }
+
+    fn image_sizes(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<(u64, u64)>> {
+        let camera_directory = format!("{}/{}", self.root, camera_id);
+
+        if !Path::new(&camera_directory).exists() {
+            return Ok(Vec::new());
+        }
+
+        Ok(read_dir(camera_directory)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let image_id = Path::new(&entry.file_name())
+                    .file_stem()
+                    .and_then(|file_stem| file_stem.to_str())
+                    .and_then(|file_stem| file_stem.parse::<u64>().ok())?;
+
+                entry
+                    .metadata()
+                    .ok()
+                    .map(|metadata| (image_id, metadata.len()))
+            })
+            .collect())
+    }
+}
+
+/// Combines a primary ("hot") store with an optional cheaper ("cold") store.
+/// New images always go to the hot store, reads check the hot store first and fall back to the cold store,
+/// so callers don't need to know which tier an image currently lives in. Which tier an image is in isn't recorded
+/// anywhere else, the stores themselves are the record.
+pub struct TieredMediaStore {
+    pub hot: Box<dyn MediaStore>,
+    pub cold: Option<Box<dyn MediaStore>>,
+}
+
+impl TieredMediaStore {
+    /// Moves every image older than `max_age` from the hot store to the cold store.
+    /// Returns how many images were moved. Does nothing if there is no cold store. An image that fails to move is
+    /// left in the hot store to be tried again next time, and doesn't stop the rest moving.
+    pub fn migrate_older_than(&self, max_age: Duration) -> io::Result<usize> {
+        let cold = match &self.cold {
+            Some(cold) => cold,
+            None => return Ok(0),
+        };
+
+        let cutoff = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Failed to get current time somehow?")
+            .as_secs()
+            .saturating_sub(max_age.as_secs());
+
+        let mut moved = 0;
+
+        for camera_id in self.hot.list_cameras()? {
+            for image_id in self.hot.list_images(&camera_id)? {
+                if image_id >= cutoff {
+                    continue;
+                }
+
+                // Only remove the hot copy once the cold copy has been fully written
+                let result = self
+                    .hot
+                    .open_image(&camera_id, image_id)
+                    .and_then(|mut image| {
+                        cold.store_image(&camera_id, image_id, &mut image)?;
+                        self.hot.delete_image(&camera_id, image_id)
+                    });
+
+                match result {
+                    Ok(()) => moved += 1,
+                    Err(error) => error!(
+                        "Failed to move image {} from camera {} to cold storage! The error was {}",
+                        image_id, camera_id, error
+                    ),
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+}
+
+impl MediaStore for TieredMediaStore {
+    fn list_images(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<u64>> {
+        let mut images = self.hot.list_images(camera_id)?;
+
+        if let Some(cold) = &self.cold {
+            images.extend(cold.list_images(camera_id)?);
+            images.sort();
+            images.dedup();
+        }
+
+        Ok(images)
+    }
+
+    fn list_cameras(&self) -> io::Result<Vec<uuid::Uuid>> {
+        let mut cameras = self.hot.list_cameras()?;
+
+        if let Some(cold) = &self.cold {
+            cameras.extend(cold.list_cameras()?);
+            cameras.sort();
+            cameras.dedup();
+        }
+
+        Ok(cameras)
+    }
+
+    fn open_image(
+        &self,
+        camera_id: &uuid::Uuid,
+        image_id: u64,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        match self.hot.open_image(camera_id, image_id) {
+            Ok(image) => Ok(image),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => match &self.cold {
+                Some(cold) => cold.open_image(camera_id, image_id),
+                None => Err(error),
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    fn store_image(
+        &self,
+        camera_id: &uuid::Uuid,
+        image_id: u64,
+        image: &mut dyn Read,
+    ) -> io::Result<u64> {
+        self.hot.store_image(camera_id, image_id, image)
+    }
+
+    fn delete_image(&self, camera_id: &uuid::Uuid, image_id: u64) -> io::Result<()> {
+        let hot_result = self.hot.delete_image(camera_id, image_id);
+
+        match &self.cold {
+            Some(cold) => match cold.delete_image(camera_id, image_id) {
+                Ok(()) => Ok(()),
+                // The image only has to be deleted from one of the tiers
+                Err(error) if error.kind() == io::ErrorKind::NotFound => hot_result,
+                Err(error) => Err(error),
+            },
+            None => hot_result,
+        }
+    }
+
+    fn storage_used(&self, camera_id: &uuid::Uuid) -> io::Result<u64> {
+        let hot_used = self.hot.storage_used(camera_id)?;
+
+        match &self.cold {
+            Some(cold) => Ok(hot_used + cold.storage_used(camera_id)?),
+            None => Ok(hot_used),
+        }
+    }
+
+    fn image_sizes(&self, camera_id: &uuid::Uuid) -> io::Result<Vec<(u64, u64)>> {
+        let mut sizes = self.hot.image_sizes(camera_id)?;
+
+        // An image being moved to the cold store can be in both for a moment
+        if let Some(cold) = &self.cold {
+            let hot_ids = sizes
+                .iter()
+                .map(|(image_id, _)| *image_id)
+                .collect::<HashSet<u64>>();
+
+            sizes.extend(
+                cold.image_sizes(camera_id)?
+                    .into_iter()
+                    .filter(|(image_id, _)| !hot_ids.contains(image_id)),
+            );
+        }
+
+        Ok(sizes)
+    }
+}
+
+/// Builds the media store from [storage] in the settings. images_directory is the hot store,
+/// cold_images_directory (optional) is the cold store. Both are directories, the cold one is meant to be on
+/// cheaper, slower storage mounted there (e.g. a network volume or a bucket mounted with s3fs).
+pub fn media_store() -> TieredMediaStore {
+    TieredMediaStore {
+        hot: Box::new(LocalMediaStore {
+            root: crate::camera::images_directory(),
+        }),
+        cold: settings()
+            .storage
+            .cold_images_directory
+            .clone()
+            .map(|root| Box::new(LocalMediaStore { root }) as Box<dyn MediaStore>),
+    }
+}
+
+/// How many days images stay in the hot store before being moved to the cold store, set with
+/// cold_storage_after_days in [storage]. Returns None (tiering disabled) if it isn't set.
+pub fn cold_storage_after_days() -> Option<u64> {
+    settings().storage.cold_storage_after_days
+}
+
+/// Starts a thread that moves old images to the cold store once an hour.
+/// Does nothing if tiering isn't configured.
+pub fn spawn_tiering_worker(database_url: String) {
+    let days = match cold_storage_after_days() {
+        Some(days) => days,
+        None => return,
+    };
+
+    if settings().storage.cold_images_directory.is_none() {
+        warn!("cold_storage_after_days is set but cold_images_directory isn't, not moving images to cold storage");
+        return;
+    }
+
+    // Only needs the database for its lock, so two instances don't move the same images at once
+    crate::worker::spawn_worker(
+        "Cold storage",
+        Duration::from_secs(60 * 60),
+        database_url,
+        move |_| {
+            let max_age = Duration::from_secs(days * 24 * 60 * 60);
+
+            match media_store().migrate_older_than(max_age) {
+                Ok(moved) => {
+                    if moved > 0 {
+                        info!("Moved {} images to cold storage", moved);
+                    }
+                }
+                Err(error) => error!(
+                    "Failed to move images to cold storage! The error was {}",
+                    error
+                ),
+            }
+        },
+    );
+}
@@ -0,0 +1,152 @@
+use crate::{
+    api_error::{error_code, ErrorBody},
+    request_id,
+};
+
+use once_cell::sync::OnceCell;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::{Request, Response, Rocket};
+use std::io::Cursor;
+
+/// Answers OPTIONS requests with the methods a path supports, and turns the 404s Rocket gives for
+/// a known path with the wrong method into 405s with an Allow header.
+pub struct MethodRouting;
+
+/// Every mounted route's method and path, filled in at launch.
+static ROUTES: OnceCell<Vec<(Method, String)>> = OnceCell::new();
+
+/// Records the server's routes, which OPTIONS answers and 405s are worked out from. Done at launch, or by
+/// tls::launch(), which serves without Rocket's launch().
+pub fn record_routes(rocket: &Rocket) {
+    let routes = rocket
+        .routes()
+        .map(|route| (route.method, route.uri.path().to_string()))
+        .collect();
+
+    if ROUTES.set(routes).is_err() {
+        warn!("Routes were recorded for OPTIONS and 405 handling twice!");
+    }
+}
+
+impl MethodRouting {
+    pub fn new() -> MethodRouting {
+        MethodRouting
+    }
+
+    /// Returns the methods that have a route for the path, in the order they were mounted.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut methods = Vec::new();
+
+        for (method, route_path) in ROUTES.get().into_iter().flatten() {
+            if path_matches(route_path, path) && !methods.contains(method) {
+                methods.push(*method);
+            }
+        }
+
+        // Rocket answers HEAD with the GET route
+        if methods.contains(&Method::Get) {
+            methods.push(Method::Head);
+        }
+
+        if methods.len() > 0 {
+            methods.push(Method::Options);
+        }
+
+        methods
+    }
+}
+
+/// Checks a request path against a route path, where <param> matches a segment and <param..> matches the rest.
+pub fn path_matches(route_path: &str, path: &str) -> bool {
+    let mut route_segments = route_path.trim_matches('/').split('/');
+    let mut segments = path.trim_matches('/').split('/');
+
+    loop {
+        match (route_segments.next(), segments.next()) {
+            (None, None) => return true,
+            (Some(route_segment), _)
+                if route_segment.starts_with('<') && route_segment.ends_with("..>") =>
+            {
+                return true
+            }
+            (Some(route_segment), Some(segment)) => {
+                let is_dynamic = route_segment.starts_with('<') && route_segment.ends_with('>');
+
+                if !is_dynamic && route_segment != segment {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+fn allow_header(methods: &[Method]) -> Header<'static> {
+    Header::new(
+        "Allow",
+        methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<&str>>()
+            .join(", "),
+    )
+}
+
+impl Fairing for MethodRouting {
+    fn info(&self) -> Info {
+        Info {
+            name: "OPTIONS and 405 handling",
+            kind: Kind::Launch | Kind::Response,
+        }
+    }
+
+    fn on_launch(&self, rocket: &Rocket) {
+        record_routes(rocket);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // A route handled the request, so any 404 is its own, e.g. "Event not found"
+        if request.route().is_some() {
+            return;
+        }
+
+        if response.status() != Status::NotFound {
+            return;
+        }
+
+        let methods = self.allowed_methods(request.uri().path());
+
+        if methods.len() == 0 {
+            return;
+        }
+
+        // There's a route for the method, but a path parameter (e.g. a malformed camera ID) or the format didn't match it
+        if methods.contains(&request.method()) && request.method() != Method::Options {
+            return;
+        }
+
+        response.set_header(allow_header(&methods));
+
+        if request.method() == Method::Options {
+            response.set_status(Status::NoContent);
+            response.take_body();
+            response.remove_header("Content-Type");
+            return;
+        }
+
+        let body = ErrorBody {
+            code: error_code(Status::MethodNotAllowed),
+            message: "Method not allowed",
+            details: Vec::new(),
+            request_id: request_id::current(),
+        }
+        .localized(request);
+
+        response.set_status(Status::MethodNotAllowed);
+        response.set_header(ContentType::JSON);
+        response.set_sized_body(Cursor::new(
+            serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+        ));
+    }
+}
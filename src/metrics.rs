@@ -0,0 +1,316 @@
+use crate::{jobs::QUEUED_STATUS, realtime, CameraServerDbConn, CameraServerDbConnPool};
+
+use super::schema::{analysis_jobs, jobs, notifications, webhook_deliveries};
+use diesel::prelude::*;
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::{Data, Request, Response, State};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Instant;
+
+/// Everything exported at /metrics. Gauges are only updated when /metrics is scraped.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    query_duration: HistogramVec,
+    upload_bytes: IntCounterVec,
+    deprecated_requests: IntCounterVec,
+    ingest_stalls: IntCounterVec,
+    ingest_flaps: IntCounterVec,
+    ingest_restarts: IntCounterVec,
+    pool_connections: IntGaugeVec,
+    active_streams: IntGaugeVec,
+    queue_depth: IntGaugeVec,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new_custom(Some(String::from("camera_server")), None)
+        .expect("Failed to create metrics registry!");
+
+    let metrics = Metrics {
+        requests: IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled, by route"),
+            &["method", "route", "status"],
+        )
+        .expect("Failed to create requests metric!"),
+        request_duration: HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "How long requests took to handle, by route",
+            ),
+            &["method", "route"],
+        )
+        .expect("Failed to create request duration metric!"),
+        query_duration: HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "How long database queries took, by query type",
+            ),
+            &["query"],
+        )
+        .expect("Failed to create query duration metric!"),
+        upload_bytes: IntCounterVec::new(
+            Opts::new("upload_bytes_total", "Bytes uploaded by cameras"),
+            &["kind"],
+        )
+        .expect("Failed to create upload bytes metric!"),
+        deprecated_requests: IntCounterVec::new(
+            Opts::new(
+                "deprecated_requests_total",
+                "Requests to v0 paths and deprecated routes, by the route that served them",
+            ),
+            &["method", "route", "kind"],
+        )
+        .expect("Failed to create deprecated requests metric!"),
+        ingest_stalls: IntCounterVec::new(
+            Opts::new(
+                "ingest_stalls_total",
+                "RTMP and SRT streams dropped for not sending anything, by protocol",
+            ),
+            &["protocol"],
+        )
+        .expect("Failed to create ingest stalls metric!"),
+        ingest_flaps: IntCounterVec::new(
+            Opts::new(
+                "ingest_flaps_total",
+                "Cameras whose streams kept stalling, by protocol",
+            ),
+            &["protocol"],
+        )
+        .expect("Failed to create ingest flaps metric!"),
+        ingest_restarts: IntCounterVec::new(
+            Opts::new(
+                "ingest_restarts_total",
+                "Ingest servers restarted after stopping, by server",
+            ),
+            &["server"],
+        )
+        .expect("Failed to create ingest restarts metric!"),
+        pool_connections: IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections, by state"),
+            &["state"],
+        )
+        .expect("Failed to create pool metric!"),
+        active_streams: IntGaugeVec::new(
+            Opts::new("active_streams", "Connected realtime clients"),
+            &["kind"],
+        )
+        .expect("Failed to create streams metric!"),
+        queue_depth: IntGaugeVec::new(
+            Opts::new("job_queue_depth", "Background jobs waiting to be attempted"),
+            &["queue"],
+        )
+        .expect("Failed to create queue depth metric!"),
+        registry,
+    };
+
+    let collectors: Vec<Box<dyn Collector>> = vec![
+        Box::new(metrics.requests.clone()),
+        Box::new(metrics.request_duration.clone()),
+        Box::new(metrics.query_duration.clone()),
+        Box::new(metrics.upload_bytes.clone()),
+        Box::new(metrics.deprecated_requests.clone()),
+        Box::new(metrics.ingest_stalls.clone()),
+        Box::new(metrics.ingest_flaps.clone()),
+        Box::new(metrics.ingest_restarts.clone()),
+        Box::new(metrics.pool_connections.clone()),
+        Box::new(metrics.active_streams.clone()),
+        Box::new(metrics.queue_depth.clone()),
+    ];
+
+    for collector in collectors {
+        metrics
+            .registry
+            .register(collector)
+            .expect("Failed to register metric!");
+    }
+
+    metrics
+});
+
+/// Counts bytes uploaded by cameras. `kind` is "image" or "audio".
+pub fn record_upload(kind: &str, bytes: u64) {
+    METRICS
+        .upload_bytes
+        .with_label_values(&[kind])
+        .inc_by(bytes);
+}
+
+/// Times a query, see database::timed().
+pub fn record_query(query_type: &str, seconds: f64) {
+    METRICS
+        .query_duration
+        .with_label_values(&[query_type])
+        .observe(seconds);
+}
+
+/// Counts an RTMP or SRT stream being dropped for stalling. `protocol` is "rtmp" or "srt".
+pub fn record_ingest_stall(protocol: &str) {
+    METRICS.ingest_stalls.with_label_values(&[protocol]).inc();
+}
+
+/// Counts a camera's stream stalling often enough to raise a stream_flapping event.
+pub fn record_ingest_flap(protocol: &str) {
+    METRICS.ingest_flaps.with_label_values(&[protocol]).inc();
+}
+
+/// Counts an ingest server being restarted by its supervisor.
+pub fn record_ingest_restart(server: &str) {
+    METRICS.ingest_restarts.with_label_values(&[server]).inc();
+}
+
+/// Counts a request to something that's going away. `kind` is "legacy_path" or "deprecated_route".
+pub fn record_deprecated_request(method: &str, route: &str, kind: &str) {
+    METRICS
+        .deprecated_requests
+        .with_label_values(&[method, route, kind])
+        .inc();
+}
+
+/// When the request started, stored in its local cache for on_response.
+struct RequestStart(Option<Instant>);
+
+/// Counts and times every request. Requests are labelled with the route they matched rather than their path,
+/// so IDs in paths don't make a new series each.
+pub struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let method = request.method().as_str();
+        let route = request
+            .route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or_else(|| String::from("unmatched"));
+
+        METRICS
+            .requests
+            .with_label_values(&[method, &route, &response.status().code.to_string()])
+            .inc();
+
+        if let RequestStart(Some(started_at)) = request.local_cache(|| RequestStart(None)) {
+            METRICS
+                .request_duration
+                .with_label_values(&[method, &route])
+                .observe(started_at.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// How much work is waiting for the background workers.
+#[derive(Serialize, JsonSchema)]
+pub struct QueueDepths {
+    pub analysis: i64,
+    pub webhook_delivery: i64,
+    pub notification_delivery: i64,
+    /// Jobs that haven't started yet, see jobs::enqueue().
+    pub jobs: i64,
+}
+
+/// Counts analysis jobs and deliveries with a next attempt set, which is every one the workers haven't finished
+/// or given up on, and jobs that are waiting to run.
+pub fn queue_depths(conn: &PgConnection) -> QueryResult<QueueDepths> {
+    Ok(QueueDepths {
+        analysis: analysis_jobs::table
+            .filter(analysis_jobs::next_attempt_at.is_not_null())
+            .count()
+            .get_result::<i64>(conn)?,
+        webhook_delivery: webhook_deliveries::table
+            .filter(webhook_deliveries::next_attempt_at.is_not_null())
+            .count()
+            .get_result::<i64>(conn)?,
+        notification_delivery: notifications::table
+            .filter(notifications::next_attempt_at.is_not_null())
+            .count()
+            .get_result::<i64>(conn)?,
+        jobs: jobs::table
+            .filter(jobs::status.eq(QUEUED_STATUS))
+            .count()
+            .get_result::<i64>(conn)?,
+    })
+}
+
+fn record_queue_depths(conn: &PgConnection) -> QueryResult<()> {
+    let depths = queue_depths(conn)?;
+
+    for (queue, depth) in &[
+        ("analysis", depths.analysis),
+        ("webhook_delivery", depths.webhook_delivery),
+        ("notification_delivery", depths.notification_delivery),
+        ("jobs", depths.jobs),
+    ] {
+        METRICS.queue_depth.with_label_values(&[queue]).set(*depth);
+    }
+
+    Ok(())
+}
+
+/// Prometheus metrics, in the text format. Isn't versioned, so it's always at /metrics.
+#[get("/metrics")]
+pub fn metrics(
+    pool: State<CameraServerDbConnPool>,
+    conn: Option<CameraServerDbConn>,
+) -> Result<Content<String>, Status> {
+    // Taken before the queue depths are counted, so the connection used for that isn't included
+    let pool_state = pool.0.state();
+    let pool_connections = &METRICS.pool_connections;
+    pool_connections
+        .with_label_values(&["max"])
+        .set(pool.0.max_size() as i64);
+    pool_connections
+        .with_label_values(&["open"])
+        .set(pool_state.connections as i64);
+    pool_connections
+        .with_label_values(&["idle"])
+        .set(pool_state.idle_connections as i64);
+
+    let (websockets, event_streams) = realtime::active_streams();
+    METRICS
+        .active_streams
+        .with_label_values(&["websocket"])
+        .set(websockets as i64);
+    METRICS
+        .active_streams
+        .with_label_values(&["event_stream"])
+        .set(event_streams as i64);
+
+    // Stale queue depths are better than no metrics at all
+    if let Some(conn) = conn {
+        if let Err(error) = record_queue_depths(&conn) {
+            error!("Failed to count queued jobs! The error was {}", error);
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+
+    encoder
+        .encode(&METRICS.registry.gather(), &mut body)
+        .map_err(|error| {
+            error!("Failed to encode metrics! The error was {}", error);
+            Status::InternalServerError
+        })?;
+
+    Ok(Content(
+        ContentType::parse_flexible(encoder.format_type()).unwrap_or(ContentType::Plain),
+        String::from_utf8(body).map_err(|_| Status::InternalServerError)?,
+    ))
+}
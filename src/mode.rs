@@ -0,0 +1,392 @@
+use crate::{
+    api_error::ApiError, camera_commands, notification, user_tokens::UserToken,
+    users_cameras::get_cameras_users, users_cameras::get_users_cameras, worker, CameraServerDbConn,
+};
+
+use super::schema::{mode_schedules, user_modes};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, get, post, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const HOME_MODE: &str = "home";
+pub const AWAY_MODE: &str = "away";
+pub const NIGHT_MODE: &str = "night";
+
+/// Every mode a user can be in.
+pub const MODES: [&str; 3] = [HOME_MODE, AWAY_MODE, NIGHT_MODE];
+
+/// The mode users are in before they've ever picked one. Everything is armed in away mode by default,
+/// so users who don't use modes get every alert.
+pub const DEFAULT_MODE: &str = AWAY_MODE;
+
+/// The mode a user is currently in.
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "user_modes"]
+#[primary_key(user_id)]
+pub struct UserMode {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub mode: String,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// Switches the user into a mode at the same time (UTC) every week on the given days.
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "mode_schedules"]
+pub struct ModeSchedule {
+    pub schedule_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub mode: String,
+    pub at_time: NaiveTime,
+    /// 0 is Monday, 6 is Sunday. Empty means every day.
+    pub days_of_week: Vec<i32>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "mode_schedules"]
+pub struct InsertableModeSchedule {
+    pub user_id: uuid::Uuid,
+    pub mode: String,
+    pub at_time: NaiveTime,
+    pub days_of_week: Vec<i32>,
+}
+
+/// What the user sends to change mode.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewMode {
+    pub mode: String,
+}
+
+/// Returned by GET /Mode. changed_at is null if the user has never changed mode.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ModeStatus {
+    pub mode: String,
+    pub changed_at: Option<DateTime<Utc>>,
+}
+
+/// A schedule as sent by the user. at_time is HH:MM:SS.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewModeSchedule {
+    pub mode: String,
+    pub at_time: NaiveTime,
+    pub days_of_week: Vec<i32>,
+}
+
+impl ModeSchedule {
+    /// Returns the last time this schedule switched (or should have switched) mode at or before `now`.
+    pub fn last_occurrence(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        (0..8)
+            .map(|days_ago| now.date() - ChronoDuration::days(days_ago))
+            .filter(|date| {
+                self.days_of_week.len() == 0
+                    || self
+                        .days_of_week
+                        .contains(&(date.weekday().num_days_from_monday() as i32))
+            })
+            .filter_map(|date| date.and_time(self.at_time))
+            .find(|occurrence| *occurrence <= now)
+    }
+}
+
+pub fn get_user_mode(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<UserMode>> {
+    user_modes::table
+        .find(user_id)
+        .get_result::<UserMode>(connection)
+        .optional()
+}
+
+/// Returns the mode the user is in, or DEFAULT_MODE if they've never picked one.
+pub fn current_mode(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<String> {
+    get_user_mode(user_id, connection).map(|user_mode| {
+        user_mode
+            .map(|user_mode| user_mode.mode)
+            .unwrap_or_else(|| DEFAULT_MODE.to_string())
+    })
+}
+
+/// Puts the user into a mode, and tells their cameras to fetch their config again since whether they are armed may have changed.
+pub fn set_mode(
+    user_id: uuid::Uuid,
+    mode: &str,
+    connection: &PgConnection,
+) -> QueryResult<UserMode> {
+    let user_mode = UserMode {
+        user_id,
+        mode: mode.to_string(),
+        changed_at: Utc::now(),
+    };
+
+    let user_mode = diesel::insert_into(user_modes::table)
+        .values(&user_mode)
+        .on_conflict(user_modes::user_id)
+        .do_update()
+        .set(&user_mode)
+        .get_result::<UserMode>(connection)?;
+
+    for camera in get_users_cameras(user_id, connection)? {
+        camera_commands::notify_config_updated(camera.camera_id, connection);
+    }
+
+    Ok(user_mode)
+}
+
+/// Whether any of the camera's users are in a mode they've armed the camera in.
+/// Cameras are told this so that they can stop detecting motion when nobody wants to hear about it.
+pub fn is_camera_armed(camera_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<bool> {
+    for user_id in get_cameras_users(camera_id, connection)? {
+        let preference = notification::get_preference(user_id, camera_id, connection)?;
+
+        if preference
+            .armed_modes
+            .contains(&current_mode(user_id, connection)?)
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+pub fn get_users_schedules(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<ModeSchedule>> {
+    mode_schedules::table
+        .filter(mode_schedules::user_id.eq(user_id))
+        .order(mode_schedules::at_time)
+        .load::<ModeSchedule>(connection)
+}
+
+/// Changes the mode of every user whose schedule has switched since they last changed mode.
+/// Changing mode by hand after a scheduled switch takes priority until the next scheduled switch.
+pub fn apply_due_schedules(connection: &PgConnection) -> QueryResult<()> {
+    let now = Utc::now();
+    let mut due_modes: HashMap<uuid::Uuid, (DateTime<Utc>, String)> = HashMap::new();
+
+    for schedule in mode_schedules::table.load::<ModeSchedule>(connection)? {
+        if let Some(occurrence) = schedule.last_occurrence(now) {
+            let is_latest = due_modes
+                .get(&schedule.user_id)
+                .map_or(true, |(latest, _)| occurrence > *latest);
+
+            if is_latest {
+                due_modes.insert(schedule.user_id, (occurrence, schedule.mode));
+            }
+        }
+    }
+
+    for (user_id, (occurrence, mode)) in due_modes {
+        let is_due = get_user_mode(user_id, connection)?
+            .map_or(true, |user_mode| user_mode.changed_at < occurrence);
+
+        if is_due {
+            set_mode(user_id, &mode, connection)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that applies mode schedules.
+pub fn spawn_schedule_worker(database_url: String) {
+    worker::spawn_worker(
+        "Mode schedule",
+        Duration::from_secs(60),
+        database_url,
+        |connection| {
+            if let Err(error) = apply_due_schedules(connection) {
+                error!("Failed to apply mode schedules! The error was {}", error);
+            }
+        },
+    );
+}
+
+pub fn validate_mode(mode: &str) -> Result<(), ApiError> {
+    if !MODES.contains(&mode) {
+        return Err(ApiError {
+            error: "Mode must be home, away or night",
+            code: "invalid_mode",
+            status: Status::UnprocessableEntity,
+            field: Some("mode"),
+        });
+    }
+
+    Ok(())
+}
+
+#[openapi]
+#[get("/Mode")]
+pub fn get_mode(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<ModeStatus>, ApiError> {
+    get_user_mode(user_token.user_id, &conn)
+        .map(|user_mode| {
+            Json(match user_mode {
+                Some(user_mode) => ModeStatus {
+                    mode: user_mode.mode,
+                    changed_at: Some(user_mode.changed_at),
+                },
+                None => ModeStatus {
+                    mode: DEFAULT_MODE.to_string(),
+                    changed_at: None,
+                },
+            })
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to get mode for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get mode",
+                code: "get_mode_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[put("/Mode", format = "json", data = "<new_mode>")]
+pub fn update_mode(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_mode: Json<NewMode>,
+) -> Result<Json<UserMode>, ApiError> {
+    let new_mode = new_mode.into_inner();
+
+    validate_mode(&new_mode.mode)?;
+
+    set_mode(user_token.user_id, &new_mode.mode, &conn)
+        .map(|user_mode| Json(user_mode))
+        .map_err(|error| {
+            error!(
+                "Failed to set mode for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to set mode",
+                code: "set_mode_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[get("/Mode/Schedules")]
+pub fn list_mode_schedules(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<ModeSchedule>>, ApiError> {
+    get_users_schedules(user_token.user_id, &conn)
+        .map(|schedules| Json(schedules))
+        .map_err(|error| {
+            error!(
+                "Failed to get mode schedules for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get mode schedules",
+                code: "get_mode_schedules_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[post("/Mode/Schedules", format = "json", data = "<new_schedule>")]
+pub fn add_mode_schedule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_schedule: Json<NewModeSchedule>,
+) -> Result<Json<ModeSchedule>, ApiError> {
+    let new_schedule = new_schedule.into_inner();
+
+    validate_mode(&new_schedule.mode)?;
+
+    if new_schedule
+        .days_of_week
+        .iter()
+        .any(|day| !(0..=6).contains(day))
+    {
+        return Err(ApiError {
+            error: "Days of the week must be between 0 (Monday) and 6 (Sunday)",
+            code: "invalid_days_of_week",
+            status: Status::UnprocessableEntity,
+            field: Some("days_of_week"),
+        });
+    }
+
+    diesel::insert_into(mode_schedules::table)
+        .values(InsertableModeSchedule {
+            user_id: user_token.user_id,
+            mode: new_schedule.mode,
+            at_time: new_schedule.at_time,
+            days_of_week: new_schedule.days_of_week,
+        })
+        .get_result::<ModeSchedule>(&*conn)
+        .map(|schedule| Json(schedule))
+        .map_err(|error| {
+            error!(
+                "Failed to add mode schedule for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to add mode schedule",
+                code: "add_mode_schedule_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[delete("/Mode/Schedules/<schedule_id>")]
+pub fn delete_mode_schedule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    schedule_id: i32,
+) -> Result<(), ApiError> {
+    diesel::delete(
+        mode_schedules::table
+            .filter(mode_schedules::schedule_id.eq(schedule_id))
+            .filter(mode_schedules::user_id.eq(user_token.user_id)),
+    )
+    .execute(&*conn)
+    .map_err(|error| {
+        error!(
+            "Failed to delete mode schedule {}! The error was {}",
+            schedule_id, error
+        );
+        ApiError {
+            error: "Failed to delete mode schedule",
+            code: "delete_mode_schedule_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+    .and_then(|deleted| match deleted {
+        0 => Err(ApiError {
+            error: "Mode schedule not found",
+            code: "mode_schedule_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        _ => Ok(()),
+    })
+}
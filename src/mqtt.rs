@@ -0,0 +1,125 @@
+use crate::{cluster, event::Event, settings::settings};
+
+use once_cell::sync::{Lazy, OnceCell};
+use rumqttc::{Client, MqttOptions, QoS};
+use std::thread;
+use std::time::Duration;
+
+/// Set by init_from_env() if host in [mqtt] is set. Everything in here does nothing if it isn't.
+static PUBLISHER: OnceCell<MqttPublisher> = OnceCell::new();
+
+pub struct MqttPublisher {
+    pub client: Client,
+    /// Every topic starts with this, defaults to cameraserver.
+    pub topic_prefix: String,
+}
+
+impl MqttPublisher {
+    pub fn topic(&self, camera_id: &uuid::Uuid, subtopic: &str) -> String {
+        format!("{}/{}/{}", self.topic_prefix, camera_id, subtopic)
+    }
+
+    pub fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) {
+        if let Err(error) =
+            self.client
+                .clone()
+                .publish(topic.clone(), QoS::AtLeastOnce, retain, payload)
+        {
+            error!(
+                "Failed to publish to MQTT topic {}! The error was {}",
+                topic, error
+            );
+        }
+    }
+}
+
+/// Returns the publisher, or None if MQTT isn't configured.
+pub fn publisher() -> Option<&'static MqttPublisher> {
+    PUBLISHER.get()
+}
+
+/// Tells this instance's connections apart from other instances', as the broker drops a connection when another
+/// one connects with the same client ID. Empty unless multiple_instances in [scaling] is on.
+static INSTANCE_SUFFIX: Lazy<String> = Lazy::new(|| {
+    if cluster::multiple_instances() {
+        format!("-{}", uuid::Uuid::new_v4().simple())
+    } else {
+        String::new()
+    }
+});
+
+/// Builds connection options from [mqtt], or None if host in it isn't set.
+/// Every connection needs its own client ID, so the suffix is added to client_id.
+pub fn options_from_env(client_id_suffix: &str) -> Option<MqttOptions> {
+    let mqtt = &settings().mqtt;
+    let host = mqtt.host.clone()?;
+
+    let mut options = MqttOptions::new(
+        format!("{}{}{}", mqtt.client_id, client_id_suffix, *INSTANCE_SUFFIX),
+        host,
+        mqtt.port,
+    );
+    options.set_keep_alive(30);
+
+    if let (Some(username), Some(password)) = (&mqtt.username, &mqtt.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+
+    Some(options)
+}
+
+/// Every topic starts with this, set with topic_prefix in [mqtt]. Defaults to cameraserver.
+pub fn topic_prefix() -> String {
+    settings().mqtt.topic_prefix.clone()
+}
+
+/// Connects to the MQTT broker from options_from_env(), using topic_prefix() for topics.
+/// The connection is kept alive (and reconnected) by a background thread.
+pub fn init_from_env() {
+    let options = match options_from_env("") {
+        Some(options) => options,
+        None => return,
+    };
+
+    let (client, mut connection) = Client::new(options, 100);
+
+    // rumqttc only sends anything while the connection is being polled, and reconnects on the next poll after an error
+    thread::spawn(move || {
+        for notification in connection.iter() {
+            if let Err(error) = notification {
+                error!("MQTT connection error! The error was {}", error);
+                thread::sleep(Duration::from_secs(5));
+            }
+        }
+    });
+
+    PUBLISHER
+        .set(MqttPublisher {
+            client,
+            topic_prefix: topic_prefix(),
+        })
+        .ok()
+        .expect("MQTT was initialised twice!");
+}
+
+/// Publishes the event as JSON to <prefix>/<camera_id>/<event_type>.
+pub fn publish_event(event: &Event) {
+    if let Some(publisher) = PUBLISHER.get() {
+        publisher.publish(
+            publisher.topic(&event.camera_id, &event.event_type),
+            false,
+            serde_json::to_vec(event).expect("Failed to serialize event somehow?"),
+        );
+    }
+}
+
+/// Publishes "online" or "offline" to <prefix>/<camera_id>/status. Retained so new subscribers see the current state.
+pub fn publish_presence(camera_id: &uuid::Uuid, online: bool) {
+    if let Some(publisher) = PUBLISHER.get() {
+        publisher.publish(
+            publisher.topic(camera_id, "status"),
+            true,
+            if online { "online" } else { "offline" }.into(),
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{
+    api_error::ApiError,
+    bandwidth,
+    camera::{self, record_camera_contact, CameraId},
+    camera_tokens, cluster,
+    event::{store_reported_event, ReportedEvent},
+    mqtt::{options_from_env, topic_prefix},
+    settings::settings,
+    upload_limit,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::mqtt_clients;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use rumqttc::{Client, Event, Packet, QoS};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::thread;
+use std::time::Duration;
+
+/// Snapshots bigger than this are dropped by the MQTT client before they reach the server.
+pub const MAX_SNAPSHOT_BYTES: usize = 1024 * 1024;
+
+pub const MAX_CLIENT_ID_LENGTH: usize = 128;
+
+/// The shared subscription group every instance's ingest bridge joins.
+pub const INGEST_SHARE_GROUP: &str = "camera-server-ingest";
+
+/// Turn on ingest in [mqtt] (as well as setting host) to take events and snapshots from cameras over MQTT.
+pub fn ingest_enabled() -> bool {
+    settings().mqtt.ingest
+}
+
+/// The MQTT client ID a camera's firmware connects with. Cameras publish under their client ID, see spawn_ingest_bridge().
+#[derive(Queryable, Insertable, Serialize, JsonSchema)]
+#[table_name = "mqtt_clients"]
+pub struct MqttClient {
+    pub client_id: String,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+}
+
+/// Sent with PUT /Cameras/<camera_id>/MqttClient.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewMqttClient {
+    pub client_id: String,
+}
+
+pub fn get_cameras_client(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<MqttClient>> {
+    mqtt_clients::table
+        .filter(mqtt_clients::camera_id.eq(camera_id))
+        .get_result::<MqttClient>(connection)
+        .optional()
+}
+
+pub fn get_clients_camera_id(
+    client_id: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<uuid::Uuid>> {
+    mqtt_clients::table
+        .find(client_id)
+        .select(mqtt_clients::camera_id)
+        .get_result::<uuid::Uuid>(connection)
+        .optional()
+}
+
+/// Replaces the camera's client ID, if it had one.
+pub fn replace_cameras_client(
+    client: MqttClient,
+    connection: &PgConnection,
+) -> QueryResult<MqttClient> {
+    connection.transaction(|| {
+        diesel::delete(mqtt_clients::table.filter(mqtt_clients::camera_id.eq(client.camera_id)))
+            .execute(connection)?;
+
+        diesel::insert_into(mqtt_clients::table)
+            .values(&client)
+            .get_result(connection)
+    })
+}
+
+/// Topics look like <prefix>/ingest/<client_id>/event or <prefix>/ingest/<client_id>/snapshot.
+/// Returns the client ID and the last part.
+fn parse_topic<'a>(prefix: &str, topic: &'a str) -> Option<(&'a str, &'a str)> {
+    let mut parts = topic
+        .strip_prefix(prefix)?
+        .strip_prefix("/ingest/")?
+        .splitn(2, '/');
+
+    Some((parts.next()?, parts.next()?))
+}
+
+/// Handles something a camera published. Nobody is waiting for a reply, so problems are only logged.
+fn handle_publish(topic: &str, payload: &[u8], connection: &PgConnection) {
+    let (client_id, kind) = match parse_topic(&topic_prefix(), topic) {
+        Some(parsed) => parsed,
+        None => {
+            warn!("Ignoring MQTT message on unexpected topic {}", topic);
+            return;
+        }
+    };
+
+    let camera_id = match get_clients_camera_id(client_id, connection) {
+        Ok(Some(camera_id)) => camera_id,
+        Ok(None) => {
+            warn!("Ignoring MQTT message from unknown client {}", client_id);
+            return;
+        }
+        Err(error) => {
+            error!(
+                "Failed to look up MQTT client {}! The error was {}",
+                client_id, error
+            );
+            return;
+        }
+    };
+
+    // Client IDs are registered by the camera's owner, so they're already in the owner's tenant
+    if let Err(rejection) = camera_tokens::authorise_camera(camera_id, None, || connection) {
+        warn!(
+            "Ignoring MQTT message from camera {}: {}",
+            camera_id,
+            rejection.message()
+        );
+        return;
+    }
+
+    record_camera_contact(camera_id, connection);
+    bandwidth::record(camera_id, payload.len() as u64, 0);
+
+    let result = match kind {
+        "event" => serde_json::from_slice::<ReportedEvent>(payload)
+            .map_err(|error| {
+                warn!(
+                    "Failed to parse MQTT event from camera {}! The error was {}",
+                    camera_id, error
+                );
+                ApiError {
+                    error: "Event must be JSON",
+                    code: "invalid_event",
+                    status: Status::UnprocessableEntity,
+                    field: None,
+                }
+            })
+            .and_then(|reported_event| {
+                store_reported_event(camera_id, reported_event, connection).map(|_| ())
+            }),
+        "snapshot" => upload_limit::acquire_within(camera_id, Duration::from_secs(0)).and_then(
+            |_upload_slot| {
+                camera::store_uploaded_image(camera_id, None, &mut Cursor::new(payload), connection)
+                    .map(|_| ())
+            },
+        ),
+        _ => {
+            warn!("Ignoring MQTT message on unexpected topic {}", topic);
+            Ok(())
+        }
+    };
+
+    if let Err(error) = result {
+        error!(
+            "Failed to ingest MQTT {} from camera {}: {}",
+            kind, camera_id, error.error
+        );
+    }
+}
+
+/// What the bridge subscribes to for `kind` messages. With more than one instance it's a shared subscription,
+/// so the broker gives each message to just one of them.
+pub fn ingest_subscription(prefix: &str, kind: &str) -> String {
+    let topic = format!("{}/ingest/+/{}", prefix, kind);
+
+    if cluster::multiple_instances() {
+        format!("$share/{}/{}", INGEST_SHARE_GROUP, topic)
+    } else {
+        topic
+    }
+}
+
+/// Subscribes to <prefix>/ingest/+/event (ReportedEvent as JSON) and <prefix>/ingest/+/snapshot (a JPEG),
+/// for cameras whose firmware only speaks MQTT. The server can't see who published a message, so the broker
+/// has to only let clients publish under their own client ID, e.g. with Mosquitto's `pattern write <prefix>/ingest/%c/#`.
+/// With multiple_instances in [scaling] on, the broker has to support shared subscriptions (Mosquitto 1.6 and later do).
+pub fn spawn_ingest_bridge(database_url: String) {
+    if !ingest_enabled() {
+        return;
+    }
+
+    let mut options = match options_from_env("-ingest") {
+        Some(options) => options,
+        None => {
+            warn!("ingest in [mqtt] is on but host isn't set, so nothing will be ingested");
+            return;
+        }
+    };
+    options.set_max_packet_size(MAX_SNAPSHOT_BYTES, 10 * 1024);
+
+    let (mut client, mut connection) = Client::new(options, 100);
+    let prefix = topic_prefix();
+
+    thread::spawn(move || {
+        let mut database: Option<PgConnection> = None;
+
+        for notification in connection.iter() {
+            match notification {
+                // Subscriptions don't survive reconnecting, so they're made again every time
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for kind in &["event", "snapshot"] {
+                        let topic = ingest_subscription(&prefix, kind);
+
+                        if let Err(error) = client.subscribe(topic.clone(), QoS::AtLeastOnce) {
+                            error!(
+                                "Failed to subscribe to MQTT topic {}! The error was {}",
+                                topic, error
+                            );
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if database.is_none() {
+                        database = PgConnection::establish(&database_url)
+                            .map_err(|error| {
+                                error!(
+                                    "MQTT ingest failed to connect to the database! The error was {}",
+                                    error
+                                )
+                            })
+                            .ok();
+                    }
+
+                    match &database {
+                        Some(database) => {
+                            handle_publish(&publish.topic, &publish.payload, database)
+                        }
+                        None => warn!("Dropping MQTT message on {}", publish.topic),
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    error!("MQTT ingest connection error! The error was {}", error);
+                    thread::sleep(Duration::from_secs(5));
+                }
+            }
+        }
+    });
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>/MqttClient")]
+pub fn get_mqtt_client(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<MqttClient>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    match get_cameras_client(camera_id, &conn) {
+        Ok(Some(client)) => Ok(Json(client)),
+        Ok(None) => Err(ApiError {
+            error: "Camera doesn't have an MQTT client ID",
+            code: "mqtt_client_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        Err(error) => {
+            error!(
+                "Failed to get MQTT client for camera {}! The error was {}",
+                camera_id, error
+            );
+            Err(ApiError {
+                error: "Failed to get MQTT client",
+                code: "get_mqtt_client_failed",
+                status: Status::InternalServerError,
+                field: None,
+            })
+        }
+    }
+}
+
+/// Sets the client ID the camera's firmware connects to the broker with, so the server knows which camera
+/// published to <prefix>/ingest/<client_id>/event and <prefix>/ingest/<client_id>/snapshot.
+#[openapi]
+#[put(
+    "/Cameras/<camera_id>/MqttClient",
+    data = "<new_client>",
+    format = "json"
+)]
+pub fn update_mqtt_client(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_client: Json<NewMqttClient>,
+) -> Result<Json<MqttClient>, ApiError> {
+    let client_id = new_client.into_inner().client_id;
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    // Wildcards and slashes would let the client ID match other clients' topics
+    if client_id.len() == 0
+        || client_id.len() > MAX_CLIENT_ID_LENGTH
+        || client_id.contains(|c| c == '/' || c == '+' || c == '#')
+    {
+        return Err(ApiError {
+            error: "Client ID must be 1 to 128 characters, without /, + or #",
+            code: "invalid_mqtt_client_id",
+            status: Status::UnprocessableEntity,
+            field: Some("client_id"),
+        });
+    }
+
+    let database_error = |error: diesel::result::Error| {
+        error!(
+            "Failed to set MQTT client for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to set MQTT client",
+            code: "set_mqtt_client_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    };
+
+    match get_clients_camera_id(&client_id, &conn).map_err(database_error)? {
+        Some(clients_camera_id) if clients_camera_id != camera_id => {
+            return Err(ApiError {
+                error: "Client ID is already used by another camera",
+                code: "mqtt_client_id_taken",
+                status: Status::Conflict,
+                field: Some("client_id"),
+            })
+        }
+        _ => {}
+    }
+
+    replace_cameras_client(
+        MqttClient {
+            client_id,
+            camera_id,
+        },
+        &conn,
+    )
+    .map(|client| Json(client))
+    .map_err(database_error)
+}
+
+#[openapi]
+#[delete("/Cameras/<camera_id>/MqttClient")]
+pub fn delete_mqtt_client(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    diesel::delete(mqtt_clients::table.filter(mqtt_clients::camera_id.eq(camera_id)))
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(|error| {
+            error!(
+                "Failed to delete MQTT client for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to delete MQTT client",
+                code: "delete_mqtt_client_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
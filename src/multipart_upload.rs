@@ -0,0 +1,127 @@
+use crate::{
+    api_error::ApiError,
+    event::{store_reported_event, Event, ReportedEvent},
+};
+
+use diesel::pg::PgConnection;
+use multipart::server::Multipart;
+use rocket::data::{self, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::{Data, Outcome};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::io::Read;
+
+/// Files bigger than this are refused. Multipart uploads are read into memory before being stored.
+pub const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// The metadata part is JSON, so it's expected to be small.
+pub const MAX_METADATA_BYTES: u64 = 64 * 1024;
+
+/// The metadata part of a multipart upload.
+#[derive(Default, Deserialize, JsonSchema)]
+pub struct UploadMetadata {
+    /// Reported with the upload attached, so a camera can send an event and what it captured in one request.
+    pub event: Option<ReportedEvent>,
+}
+
+/// An upload sent as multipart/form-data, for firmware that can't send the file as the whole body.
+/// It needs a "file" part, and can have a "metadata" part with UploadMetadata as JSON. Other parts are ignored.
+pub struct MultipartUpload {
+    pub metadata: UploadMetadata,
+    pub file: Vec<u8>,
+    /// The file part's content type, if it had one.
+    pub content_type: Option<String>,
+}
+
+fn failure(status: Status, error: &str) -> data::Outcome<MultipartUpload, String> {
+    Outcome::Failure((status, error.to_string()))
+}
+
+impl FromDataSimple for MultipartUpload {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let boundary = match request.content_type().and_then(|content_type| {
+            content_type
+                .params()
+                .find(|(key, _)| *key == "boundary")
+                .map(|(_, boundary)| boundary.to_string())
+        }) {
+            Some(boundary) => boundary,
+            None => return failure(Status::BadRequest, "multipart/form-data needs a boundary"),
+        };
+
+        let mut multipart = Multipart::with_body(data.open(), boundary);
+        let mut metadata = UploadMetadata::default();
+        let mut file = None;
+
+        loop {
+            let mut field = match multipart.read_entry() {
+                Ok(Some(field)) => field,
+                Ok(None) => break,
+                Err(error) => return failure(Status::BadRequest, &error.to_string()),
+            };
+
+            let limit = match &*field.headers.name {
+                "metadata" => MAX_METADATA_BYTES,
+                "file" => MAX_FILE_BYTES,
+                _ => continue,
+            };
+
+            let mut body = Vec::new();
+
+            // Reading one more byte than the limit shows whether the part went over it
+            if let Err(error) = (&mut field.data).take(limit + 1).read_to_end(&mut body) {
+                return failure(Status::BadRequest, &error.to_string());
+            }
+
+            if body.len() as u64 > limit {
+                return failure(
+                    Status::PayloadTooLarge,
+                    &format!("The {} part is too big", field.headers.name),
+                );
+            }
+
+            if &*field.headers.name == "metadata" {
+                metadata = match serde_json::from_slice(&body) {
+                    Ok(metadata) => metadata,
+                    Err(error) => return failure(Status::UnprocessableEntity, &error.to_string()),
+                };
+            } else {
+                let content_type = field
+                    .headers
+                    .content_type
+                    .as_ref()
+                    .map(|content_type| content_type.to_string());
+                file = Some((body, content_type));
+            }
+        }
+
+        match file {
+            Some((file, content_type)) => Outcome::Success(MultipartUpload {
+                metadata,
+                file,
+                content_type,
+            }),
+            None => failure(Status::UnprocessableEntity, "Uploads need a file part"),
+        }
+    }
+}
+
+/// Reports the metadata's event, if it has one, once `attach` has pointed it at whatever was uploaded.
+pub fn report_metadata_event(
+    camera_id: uuid::Uuid,
+    metadata: UploadMetadata,
+    attach: impl FnOnce(&mut ReportedEvent),
+    connection: &PgConnection,
+) -> Result<Option<Event>, ApiError> {
+    match metadata.event {
+        Some(mut reported_event) => {
+            attach(&mut reported_event);
+            store_reported_event(camera_id, reported_event, connection)
+        }
+        None => Ok(None),
+    }
+}
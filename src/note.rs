@@ -0,0 +1,362 @@
+use crate::{
+    api_error::ApiError,
+    audit,
+    camera::CameraId,
+    database::ReadDbConn,
+    event::{self, parse_timestamp, EventFilter},
+    soft_delete::not_found_or_database_error,
+    user_tokens::UserToken,
+    users_cameras::{
+        check_if_user_has_access_to_camera, check_if_user_owns_camera, in_owners_tenant,
+    },
+    CameraServerDbConn,
+};
+
+use super::schema::{notes, users_cameras};
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
+use diesel::pg::{Pg, PgConnection};
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Text};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{delete, get, patch, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Long enough for a paragraph about what happened, short enough that notes stay notes.
+pub const MAX_NOTE_LENGTH: usize = 2000;
+
+/// Event search returns at most this many matching notes, newest first.
+pub const MAX_SEARCH_NOTES: i64 = 100;
+
+/// Text a user has left on a camera, on one of its events, or at a moment in its footage, e.g. "package stolen
+/// here". Everyone with access to the camera can read it.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Note {
+    pub note_id: i32,
+    /// Who wrote it. Only they can change it.
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// The event it's on. None for other notes, or once the event has been deleted.
+    pub event_id: Option<i32>,
+    /// When in the footage it's about. The event's occurred_at for notes on events, None for notes on the camera.
+    pub noted_at: Option<DateTime<Utc>>,
+    pub body: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "notes"]
+pub struct InsertableNote {
+    pub user_id: uuid::Uuid,
+    pub camera_id: uuid::Uuid,
+    pub event_id: Option<i32>,
+    pub noted_at: Option<DateTime<Utc>>,
+    pub body: String,
+}
+
+/// Sent with POST /Cameras/<camera_id>/Notes. Set event_id for a note on one of the camera's events, noted_at for
+/// a note at a moment in its footage, or neither for a note on the camera.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewNote {
+    pub body: String,
+    pub event_id: Option<i32>,
+    pub noted_at: Option<DateTime<Utc>>,
+}
+
+/// Sent with PATCH /Notes/<note_id>.
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateNote {
+    pub body: String,
+}
+
+#[derive(FromForm, JsonSchema)]
+pub struct NoteQuery {
+    /// Only notes on this event.
+    pub event_id: Option<i32>,
+    /// Only notes about footage from this RFC 3339 timestamp onwards.
+    pub from: Option<String>,
+    /// Only notes about footage up to this RFC 3339 timestamp.
+    pub to: Option<String>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to update notes! The error was {}", error);
+    ApiError {
+        error: "Failed to update notes",
+        code: "update_notes_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn validate_body(body: &str) -> Result<String, ApiError> {
+    let body = body.trim();
+
+    if body.is_empty() {
+        return Err(ApiError {
+            error: "Note body can't be empty",
+            code: "note_body_required",
+            status: Status::UnprocessableEntity,
+            field: Some("body"),
+        });
+    }
+
+    if body.chars().count() > MAX_NOTE_LENGTH {
+        return Err(ApiError {
+            error: "Notes can be at most 2000 characters",
+            code: "note_too_long",
+            status: Status::UnprocessableEntity,
+            field: Some("body"),
+        });
+    }
+
+    Ok(body.to_string())
+}
+
+/// Builds a query for the notes on every camera the user has access to, whoever wrote them.
+pub fn users_notes_query<'a>(user_id: uuid::Uuid) -> notes::BoxedQuery<'a, Pg> {
+    notes::table
+        .filter(
+            notes::camera_id.eq_any(
+                users_cameras::table
+                    .filter(users_cameras::user_id.eq(user_id))
+                    .filter(users_cameras::deleted_at.is_null())
+                    .filter(in_owners_tenant())
+                    .select(users_cameras::camera_id),
+            ),
+        )
+        .into_boxed()
+}
+
+/// The notes matching an event search's camera, time range and text, newest first. Notes on the camera as a whole
+/// aren't about a time, so they're left out when searching a time range.
+pub fn search_users_notes(
+    user_id: uuid::Uuid,
+    filter: &EventFilter,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Note>> {
+    let mut query = users_notes_query(user_id);
+
+    if let Some(camera_id) = filter.camera_id {
+        query = query.filter(notes::camera_id.eq(camera_id));
+    }
+
+    if let Some(from) = filter.from {
+        query = query.filter(notes::noted_at.ge(from));
+    }
+
+    if let Some(to) = filter.to {
+        query = query.filter(notes::noted_at.le(to));
+    }
+
+    // Matches the notes_search index, see the notes migration
+    if let Some(text) = &filter.text {
+        query = query.filter(
+            sql::<Bool>("to_tsvector('english', notes.body) @@ plainto_tsquery('english', ")
+                .bind::<Text, _>(text.clone())
+                .sql(")"),
+        );
+    }
+
+    query
+        .order((notes::created_at.desc(), notes::note_id.desc()))
+        .limit(MAX_SEARCH_NOTES)
+        .load::<Note>(connection)
+}
+
+/// Returns the given note, but only if it's on one of the user's cameras.
+pub fn get_users_note(
+    user_id: uuid::Uuid,
+    note_id: i32,
+    connection: &PgConnection,
+) -> Result<Note, ApiError> {
+    users_notes_query(user_id)
+        .filter(notes::note_id.eq(note_id))
+        .first::<Note>(connection)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("note_not_found", "Note not found"),
+                ("get_note_failed", "Failed to get note"),
+            )
+        })
+}
+
+/// Notes on the camera, its events and its footage, in the order they're about, with notes on the camera first.
+#[openapi]
+#[get("/Cameras/<camera_id>/Notes?<query..>")]
+pub fn get_notes(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    query: Form<NoteQuery>,
+) -> Result<Json<Vec<Note>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let mut notes_query = notes::table
+        .filter(notes::camera_id.eq(camera_id))
+        .into_boxed();
+
+    if let Some(event_id) = query.event_id {
+        notes_query = notes_query.filter(notes::event_id.eq(event_id));
+    }
+
+    if let Some(from) = &query.from {
+        notes_query = notes_query.filter(notes::noted_at.ge(parse_timestamp(from)?));
+    }
+
+    if let Some(to) = &query.to {
+        notes_query = notes_query.filter(notes::noted_at.le(parse_timestamp(to)?));
+    }
+
+    notes_query
+        // false sorts first, so notes on the camera come before the rest
+        .order((
+            notes::noted_at.is_not_null(),
+            notes::noted_at,
+            notes::note_id,
+        ))
+        .load::<Note>(&*conn)
+        .map(Json)
+        .map_err(|error| {
+            error!(
+                "Failed to get notes for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get notes",
+                code: "get_notes_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Leaves a note on the camera, one of its events, or a moment in its footage. Notes on events are found by event
+/// search along with them, and are kept when the event is deleted.
+#[openapi]
+#[post("/Cameras/<camera_id>/Notes", format = "json", data = "<new_note>")]
+pub fn create_note(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_note: Json<NewNote>,
+) -> Result<Json<Note>, ApiError> {
+    let new_note = new_note.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let body = validate_body(&new_note.body)?;
+
+    let noted_at = match new_note.event_id {
+        Some(event_id) => {
+            let event = event::get_users_event(user_token.user_id, event_id, &conn)?;
+            if event.camera_id != camera_id {
+                return Err(ApiError {
+                    error: "Event not found",
+                    code: "event_not_found",
+                    status: Status::NotFound,
+                    field: Some("event_id"),
+                });
+            }
+            Some(event.occurred_at)
+        }
+        None => new_note.noted_at,
+    };
+
+    let note = diesel::insert_into(notes::table)
+        .values(InsertableNote {
+            user_id: user_token.user_id,
+            camera_id,
+            event_id: new_note.event_id,
+            noted_at,
+            body,
+        })
+        .get_result::<Note>(&*conn)
+        .map_err(database_error)?;
+    audit::record_after(&note);
+
+    Ok(Json(note))
+}
+
+#[openapi]
+#[get("/Notes/<note_id>")]
+pub fn get_note(
+    conn: ReadDbConn,
+    user_token: UserToken,
+    note_id: i32,
+) -> Result<Json<Note>, ApiError> {
+    get_users_note(user_token.user_id, note_id, &conn).map(Json)
+}
+
+/// Changes what a note says. Only for whoever wrote it.
+#[openapi]
+#[patch("/Notes/<note_id>", format = "json", data = "<update>")]
+pub fn update_note(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    note_id: i32,
+    update: Json<UpdateNote>,
+) -> Result<Json<Note>, ApiError> {
+    let note = get_users_note(user_token.user_id, note_id, &conn)?;
+
+    if note.user_id != user_token.user_id {
+        return Err(ApiError {
+            error: "Only whoever wrote a note can change it",
+            code: "not_note_author",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    let body = validate_body(&update.body)?;
+
+    audit::record_before(&note);
+    diesel::update(notes::table.find(note_id))
+        .set(notes::body.eq(body))
+        .get_result::<Note>(&*conn)
+        .map(|note| {
+            audit::record_after(&note);
+            Json(note)
+        })
+        .map_err(database_error)
+}
+
+/// Deletes a note. Only for whoever wrote it, or the camera's owner.
+#[openapi]
+#[delete("/Notes/<note_id>")]
+pub fn delete_note(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    note_id: i32,
+) -> Result<(), ApiError> {
+    let note = get_users_note(user_token.user_id, note_id, &conn)?;
+
+    if note.user_id != user_token.user_id {
+        check_if_user_owns_camera(&conn, &user_token, note.camera_id).map_err(
+            |error| match error.status {
+                Status::Forbidden => ApiError {
+                    error: "Only whoever wrote a note or the camera's owner can delete it",
+                    code: "not_note_author",
+                    status: Status::Forbidden,
+                    field: None,
+                },
+                _ => error,
+            },
+        )?;
+    }
+
+    audit::record_before(&note);
+    diesel::delete(notes::table.find(note_id))
+        .execute(&*conn)
+        .map(|_| ())
+        .map_err(database_error)
+}
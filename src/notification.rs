@@ -0,0 +1,803 @@
+use crate::{
+    api_error::ApiError,
+    camera::{self, Camera, CameraId},
+    camera_commands, digest,
+    email::{self, EmailSender},
+    event::{
+        meets_severity, validate_severity, Event, CRITICAL_SEVERITY, EVENT_TYPES, INFO_SEVERITY,
+        TAMPER_EVENT_TYPE,
+    },
+    i18n,
+    mode::{self, MODES},
+    push::PushSender,
+    rule::{self, Rule},
+    sms::{self, SmsProvider},
+    timezone, trigger,
+    user_tokens::UserToken,
+    users_cameras::{check_if_user_has_access_to_camera, get_cameras_users},
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{notification_preferences, notifications};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Notifications sent to the user's phones through FCM/APNs.
+pub const PUSH_CHANNEL: &str = "push";
+/// Notifications emailed to the recipients in the user's email alert for the camera.
+pub const EMAIL_CHANNEL: &str = "email";
+/// Text messages to the user's phone number, see SmsSettings.
+pub const SMS_CHANNEL: &str = "sms";
+/// POSTs to the rule's trigger URL. Only used by rules with a trigger URL, rather than being a channel users choose.
+pub const TRIGGER_CHANNEL: &str = "trigger";
+
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 5;
+pub const INITIAL_RETRY_DELAY_SECONDS: i64 = 15;
+
+/// Which notifications a user wants for one of their cameras.
+/// Users who have never changed their preferences for a camera get NotificationPreference::default_for().
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "notification_preferences"]
+#[primary_key(user_id, camera_id)]
+pub struct NotificationPreference {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub push_enabled: bool,
+    /// The event types that trigger a notification.
+    pub event_types: Vec<String>,
+    /// Whether to notify the user when the camera goes offline.
+    pub offline_alerts: bool,
+    /// The modes the camera is armed in. Events from the camera only notify the user while they are in one of these modes.
+    pub armed_modes: Vec<String>,
+    /// Only events at least this severe are pushed.
+    pub min_severity: String,
+    /// Whether tamper events notify the user even while the camera is disarmed, while they get digests instead of
+    /// alerts, and during email throttles and rule cooldowns. Someone interfering with the camera is worth hearing about.
+    pub tamper_overrides: bool,
+}
+
+impl NotificationPreference {
+    pub fn default_for(user_id: uuid::Uuid, camera_id: uuid::Uuid) -> NotificationPreference {
+        NotificationPreference {
+            user_id,
+            camera_id,
+            push_enabled: true,
+            event_types: EVENT_TYPES
+                .iter()
+                .map(|event_type| event_type.to_string())
+                .collect(),
+            offline_alerts: true,
+            armed_modes: MODES.iter().map(|mode| mode.to_string()).collect(),
+            min_severity: INFO_SEVERITY.to_string(),
+            tamper_overrides: true,
+        }
+    }
+
+    /// Whether the event gets past disarmed modes, digests, throttles and cooldowns for this user and camera.
+    pub fn overrides_quiet(&self, event: &Event) -> bool {
+        event.event_type == TAMPER_EVENT_TYPE && self.tamper_overrides
+    }
+}
+
+/// Preferences as sent by the user. The user and camera come from the token and route.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct UpdatedNotificationPreference {
+    pub push_enabled: bool,
+    pub event_types: Vec<String>,
+    pub offline_alerts: bool,
+    pub armed_modes: Vec<String>,
+    /// Defaults to info, i.e. every event.
+    pub min_severity: Option<String>,
+    /// Defaults to true.
+    pub tamper_overrides: Option<bool>,
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "notifications"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct Notification {
+    pub notification_id: i32,
+    pub user_id: uuid::Uuid,
+    pub camera_id: uuid::Uuid,
+    /// The event that caused the notification, if it was caused by one.
+    pub event_id: Option<i32>,
+    pub channel: String,
+    pub title: String,
+    pub body: String,
+    pub sent: bool,
+    pub attempts: i32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// The rule that caused the notification, if it was caused by one.
+    pub rule_id: Option<i32>,
+    /// How many events the notification is about. Events that match a rule during its cooldown are grouped
+    /// into the rule's last notification instead of causing a new one.
+    pub event_count: i32,
+    pub last_event_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "notifications"]
+pub struct InsertableNotification {
+    pub user_id: uuid::Uuid,
+    pub camera_id: uuid::Uuid,
+    pub event_id: Option<i32>,
+    pub channel: String,
+    pub title: String,
+    pub body: String,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub rule_id: Option<i32>,
+}
+
+impl InsertableNotification {
+    pub fn new(
+        user_id: uuid::Uuid,
+        camera_id: uuid::Uuid,
+        event_id: Option<i32>,
+        channel: &str,
+        title: String,
+        body: String,
+    ) -> InsertableNotification {
+        InsertableNotification {
+            user_id,
+            camera_id,
+            event_id,
+            channel: channel.to_string(),
+            title,
+            body,
+            next_attempt_at: Some(Utc::now()),
+            rule_id: None,
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Notification>> {
+    notifications::table.load::<Notification>(&*connection)
+}
+
+pub fn get(notification_id: i32, connection: &PgConnection) -> QueryResult<Notification> {
+    notifications::table
+        .find(notification_id)
+        .get_result::<Notification>(connection)
+}
+
+pub fn insert(
+    notification: InsertableNotification,
+    connection: &PgConnection,
+) -> QueryResult<Notification> {
+    diesel::insert_into(notifications::table)
+        .values(notification)
+        .get_result(connection)
+}
+
+pub fn update(
+    notification_id: i32,
+    notification: Notification,
+    connection: &PgConnection,
+) -> QueryResult<Notification> {
+    diesel::update(notifications::table.find(notification_id))
+        .set(&notification)
+        .get_result(connection)
+}
+
+pub fn delete(notification_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(notifications::table.find(notification_id)).execute(connection)
+}
+
+/// Returns the user's preferences for the camera, or the defaults if they haven't set any.
+pub fn get_preference(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<NotificationPreference> {
+    notification_preferences::table
+        .find((user_id, camera_id))
+        .get_result::<NotificationPreference>(connection)
+        .optional()
+        .map(|preference| {
+            preference.unwrap_or_else(|| NotificationPreference::default_for(user_id, camera_id))
+        })
+}
+
+pub fn upsert_preference(
+    preference: NotificationPreference,
+    connection: &PgConnection,
+) -> QueryResult<NotificationPreference> {
+    diesel::insert_into(notification_preferences::table)
+        .values(&preference)
+        .on_conflict((
+            notification_preferences::user_id,
+            notification_preferences::camera_id,
+        ))
+        .do_update()
+        .set(&preference)
+        .get_result(connection)
+}
+
+/// Capitalises the first letter of an event type so it can start a sentence (motion -> Motion, glass_break -> Glass break).
+pub fn display_event_type(event_type: &str) -> String {
+    let event_type = event_type.replace('_', " ");
+    let mut characters = event_type.chars();
+    match characters.next() {
+        Some(first) => first.to_uppercase().chain(characters).collect(),
+        None => String::new(),
+    }
+}
+
+/// Queues a notification for an event that matched a rule. If the rule already notified about the camera on this channel
+/// within its cooldown, the event is grouped into that notification instead, so one ongoing episode is one notification.
+/// Grouped push notifications are sent again with the updated count and replace the old one on the phone,
+/// but emails and text messages that have already gone out aren't sent again.
+/// Events that aren't in cooldown always get their own notification.
+pub fn queue_rule_notification(
+    rule: &Rule,
+    channel: &str,
+    event: &Event,
+    in_cooldown: bool,
+    title: String,
+    body: String,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let now = Utc::now();
+
+    let episode = if in_cooldown && rule.cooldown_seconds > 0 {
+        notifications::table
+            .filter(notifications::rule_id.eq(rule.rule_id))
+            .filter(notifications::user_id.eq(rule.user_id))
+            .filter(notifications::camera_id.eq(event.camera_id))
+            .filter(notifications::channel.eq(channel))
+            .filter(
+                notifications::last_event_at
+                    .ge(now - ChronoDuration::seconds(rule.cooldown_seconds as i64)),
+            )
+            .order(notifications::last_event_at.desc())
+            .first::<Notification>(connection)
+            .optional()?
+    } else {
+        None
+    };
+
+    match episode {
+        Some(mut notification) => {
+            notification.event_count += 1;
+            notification.event_id = Some(event.event_id);
+            notification.last_event_at = now;
+            notification.body = i18n::text(
+                i18n::user_locale(rule.user_id, connection),
+                "notification.grouped_body",
+                "{body} ({count} events)",
+                &[
+                    ("body", &body),
+                    ("count", &notification.event_count.to_string()),
+                ],
+            );
+
+            if notification.sent && channel == PUSH_CHANNEL {
+                notification.sent = false;
+                notification.attempts = 0;
+                notification.next_attempt_at = Some(now);
+                notification.last_error = None;
+            }
+
+            update(notification.notification_id, notification, connection)?;
+        }
+        None => {
+            insert(
+                InsertableNotification {
+                    rule_id: Some(rule.rule_id),
+                    ..InsertableNotification::new(
+                        rule.user_id,
+                        event.camera_id,
+                        Some(event.event_id),
+                        channel,
+                        title,
+                        body,
+                    )
+                },
+                connection,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The title and body of a notification about `event`, in the user's language and timezone.
+fn event_text(
+    event: &Event,
+    camera: &Camera,
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> (String, String) {
+    let locale = i18n::user_locale(user_id, connection);
+    let event_type = i18n::event_type(locale, &event.event_type);
+    let tamper_reason = event
+        .tamper_reason
+        .as_ref()
+        .map(|tamper_reason| format!(" ({})", tamper_reason))
+        .unwrap_or_default();
+    let time = timezone::display(
+        event.occurred_at,
+        timezone::user_timezone(user_id, connection),
+        "%H:%M %Z",
+    );
+
+    let title = i18n::text(
+        locale,
+        "notification.event_title",
+        "{event_type} on {camera}",
+        &[("event_type", &event_type), ("camera", &camera.name)],
+    );
+    let body = i18n::text(
+        locale,
+        "notification.event_body",
+        "{event_type}{tamper_reason} detected at {time}",
+        &[
+            ("event_type", &event_type),
+            ("tamper_reason", &tamper_reason),
+            ("time", &time),
+        ],
+    );
+
+    (title, body)
+}
+
+/// Queues a notification for every user of the event's camera who wants to hear about this type of event,
+/// on every channel they want to hear about it on. Matching rules are checked first, and a user is only
+/// notified once per channel even if several rules match. Cameras that are disarmed in the user's current mode only
+/// notify them through rules, apart from tamper events unless the user turned tamper_overrides off.
+/// Returns how many notifications were queued.
+pub fn notify_event(event: &Event, connection: &PgConnection) -> QueryResult<usize> {
+    let camera = camera::get(event.camera_id, connection)?;
+    let mut queued_channels: HashSet<(uuid::Uuid, String)> = HashSet::new();
+    // Every rule has its own trigger URL, so triggers aren't deduplicated like channels
+    let mut queued_triggers = 0;
+
+    for matching_rule in rule::get_matching_rules(event, connection)? {
+        let in_cooldown = !get_preference(matching_rule.user_id, event.camera_id, connection)?
+            .overrides_quiet(event);
+        let (title, body) = event_text(event, &camera, matching_rule.user_id, connection);
+
+        for channel in &matching_rule.channels {
+            if channel == SMS_CHANNEL
+                && !sms::can_send(
+                    &sms::get_settings(matching_rule.user_id, connection)?,
+                    connection,
+                )?
+            {
+                continue;
+            }
+
+            if queued_channels.insert((matching_rule.user_id, channel.clone())) {
+                queue_rule_notification(
+                    &matching_rule,
+                    channel,
+                    event,
+                    in_cooldown,
+                    format!("{}: {}", matching_rule.name, title),
+                    body.clone(),
+                    connection,
+                )?;
+            }
+        }
+
+        if matching_rule.trigger_url.is_some() {
+            queue_rule_notification(
+                &matching_rule,
+                TRIGGER_CHANNEL,
+                event,
+                in_cooldown,
+                format!("{}: {}", matching_rule.name, title),
+                body.clone(),
+                connection,
+            )?;
+            queued_triggers += 1;
+        }
+    }
+
+    for user_id in get_cameras_users(event.camera_id, connection)? {
+        let preference = get_preference(user_id, event.camera_id, connection)?;
+        let overrides_quiet = preference.overrides_quiet(event);
+
+        // Disarmed cameras (e.g. indoor cameras while the user is home) only notify through rules that ask for it
+        if !overrides_quiet
+            && !preference
+                .armed_modes
+                .contains(&mode::current_mode(user_id, connection)?)
+        {
+            continue;
+        }
+
+        let (title, body) = event_text(event, &camera, user_id, connection);
+
+        // Users who get a digest instead of alerts only hear about critical events straight away
+        let digest_only = !overrides_quiet
+            && event.severity != CRITICAL_SEVERITY
+            && digest::replaces_alerts(user_id, connection)?;
+
+        if !digest_only
+            && preference.push_enabled
+            && preference.event_types.contains(&event.event_type)
+            && meets_severity(&event.severity, &preference.min_severity)
+            && queued_channels.insert((user_id, PUSH_CHANNEL.to_string()))
+        {
+            insert(
+                InsertableNotification::new(
+                    user_id,
+                    event.camera_id,
+                    Some(event.event_id),
+                    PUSH_CHANNEL,
+                    title.clone(),
+                    body.clone(),
+                ),
+                connection,
+            )?;
+        }
+
+        if digest_only || queued_channels.contains(&(user_id, EMAIL_CHANNEL.to_string())) {
+            continue;
+        }
+
+        if let Some(email_alert) = email::get(user_id, event.camera_id, connection)? {
+            if email::should_alert(&email_alert, event, !overrides_quiet, connection)? {
+                insert(
+                    InsertableNotification::new(
+                        user_id,
+                        event.camera_id,
+                        Some(event.event_id),
+                        EMAIL_CHANNEL,
+                        title.clone(),
+                        body.clone(),
+                    ),
+                    connection,
+                )?;
+                queued_channels.insert((user_id, EMAIL_CHANNEL.to_string()));
+            }
+        }
+
+        if event.severity == CRITICAL_SEVERITY
+            && !queued_channels.contains(&(user_id, SMS_CHANNEL.to_string()))
+        {
+            let sms_settings = sms::get_settings(user_id, connection)?;
+
+            if sms_settings.critical_alerts && sms::can_send(&sms_settings, connection)? {
+                insert(
+                    InsertableNotification::new(
+                        user_id,
+                        event.camera_id,
+                        Some(event.event_id),
+                        SMS_CHANNEL,
+                        title.clone(),
+                        body.clone(),
+                    ),
+                    connection,
+                )?;
+                queued_channels.insert((user_id, SMS_CHANNEL.to_string()));
+            }
+        }
+    }
+
+    Ok(queued_channels.len() + queued_triggers)
+}
+
+/// Queues a notification for every user of the camera who wants offline alerts, by push and/or SMS.
+pub fn notify_offline(camera: &Camera, connection: &PgConnection) -> QueryResult<usize> {
+    let mut queued = 0;
+
+    for user_id in get_cameras_users(camera.camera_id, connection)? {
+        let preference = get_preference(user_id, camera.camera_id, connection)?;
+        let locale = i18n::user_locale(user_id, connection);
+        let title = i18n::text(
+            locale,
+            "notification.offline_title",
+            "{camera} is offline",
+            &[("camera", &camera.name)],
+        );
+        let body = match camera.last_seen_at {
+            Some(last_seen_at) => i18n::text(
+                locale,
+                "notification.offline_body",
+                "{camera} hasn't been seen since {time}",
+                &[
+                    ("camera", &camera.name),
+                    (
+                        "time",
+                        &timezone::display(
+                            last_seen_at,
+                            timezone::user_timezone(user_id, connection),
+                            "%H:%M %Z",
+                        ),
+                    ),
+                ],
+            ),
+            None => i18n::text(
+                locale,
+                "notification.never_seen_body",
+                "{camera} hasn't contacted the server",
+                &[("camera", &camera.name)],
+            ),
+        };
+
+        if preference.push_enabled && preference.offline_alerts {
+            insert(
+                InsertableNotification::new(
+                    user_id,
+                    camera.camera_id,
+                    None,
+                    PUSH_CHANNEL,
+                    title.clone(),
+                    body.clone(),
+                ),
+                connection,
+            )?;
+            queued += 1;
+        }
+
+        let sms_settings = sms::get_settings(user_id, connection)?;
+
+        if preference.offline_alerts
+            && sms_settings.offline_alerts
+            && sms::can_send(&sms_settings, connection)?
+        {
+            insert(
+                InsertableNotification::new(
+                    user_id,
+                    camera.camera_id,
+                    None,
+                    SMS_CHANNEL,
+                    title.clone(),
+                    body.clone(),
+                ),
+                connection,
+            )?;
+            queued += 1;
+        }
+    }
+
+    Ok(queued)
+}
+
+/// Queues a push notification for every user of the camera who wants offline alerts, as a clock that's wrong
+/// is about the camera's health too. Only sent when the camera first drifts, not on every check after.
+pub fn notify_clock_drift(
+    camera: &Camera,
+    drift_seconds: i64,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    let mut queued = 0;
+    let drift = drift_seconds.abs().to_string();
+
+    for user_id in get_cameras_users(camera.camera_id, connection)? {
+        let preference = get_preference(user_id, camera.camera_id, connection)?;
+        if !preference.push_enabled || !preference.offline_alerts {
+            continue;
+        }
+
+        let locale = i18n::user_locale(user_id, connection);
+        let title = i18n::text(
+            locale,
+            "notification.clock_drift_title",
+            "{camera}'s clock is wrong",
+            &[("camera", &camera.name)],
+        );
+        let body = if drift_seconds > 0 {
+            i18n::text(
+                locale,
+                "notification.clock_ahead_body",
+                "{camera}'s clock is {seconds} seconds ahead, so its events and images have the wrong times",
+                &[("camera", &camera.name), ("seconds", &drift)],
+            )
+        } else {
+            i18n::text(
+                locale,
+                "notification.clock_behind_body",
+                "{camera}'s clock is {seconds} seconds behind, so its events and images have the wrong times",
+                &[("camera", &camera.name), ("seconds", &drift)],
+            )
+        };
+
+        insert(
+            InsertableNotification::new(user_id, camera.camera_id, None, PUSH_CHANNEL, title, body),
+            connection,
+        )?;
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+/// Sends every notification that is due, and schedules a retry with exponential backoff for ones that fail.
+pub fn deliver_due(
+    push_sender: &PushSender,
+    email_sender: &Option<EmailSender>,
+    sms_provider: &Option<Box<dyn SmsProvider>>,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let due_notifications = notifications::table
+        .filter(notifications::sent.eq(false))
+        .filter(notifications::next_attempt_at.le(Utc::now()))
+        .order(notifications::next_attempt_at)
+        .limit(100)
+        .load::<Notification>(connection)?;
+
+    for mut notification in due_notifications {
+        notification.attempts += 1;
+
+        let result = match notification.channel.as_str() {
+            PUSH_CHANNEL => push_sender.send_to_user(
+                notification.user_id,
+                &notification.title,
+                &notification.body,
+                // Grouped notifications are sent again as they're updated, and should replace what the phone already shows
+                &notification.notification_id.to_string(),
+                connection,
+            ),
+            EMAIL_CHANNEL => match email_sender {
+                Some(email_sender) => email_sender.send_alert(&notification, connection),
+                None => Err(String::from("Email alerts aren't configured")),
+            },
+            SMS_CHANNEL => match sms_provider {
+                Some(sms_provider) => sms::send_notification(
+                    sms_provider.as_ref(),
+                    notification.user_id,
+                    &notification.title,
+                    &notification.body,
+                    connection,
+                ),
+                None => Err(String::from("SMS alerts aren't configured")),
+            },
+            TRIGGER_CHANNEL => trigger::send_trigger(&notification, connection),
+            channel => Err(format!("Unknown notification channel {}", channel)),
+        };
+
+        match result {
+            Ok(()) => {
+                notification.sent = true;
+                notification.next_attempt_at = None;
+                notification.last_error = None;
+            }
+            Err(error) => {
+                notification.last_error = Some(error);
+                notification.next_attempt_at = worker::next_attempt_at(
+                    notification.attempts,
+                    MAX_DELIVERY_ATTEMPTS,
+                    INITIAL_RETRY_DELAY_SECONDS,
+                );
+            }
+        }
+
+        diesel::update(notifications::table.find(notification.notification_id))
+            .set(&notification)
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that sends queued notifications.
+pub fn spawn_delivery_worker(database_url: String) {
+    let push_sender = PushSender::from_env();
+    let email_sender = EmailSender::from_env();
+    let sms_provider = sms::sms_provider();
+
+    worker::spawn_worker(
+        "Notification delivery",
+        Duration::from_secs(2),
+        database_url,
+        move |connection| {
+            if let Err(error) = deliver_due(&push_sender, &email_sender, &sms_provider, connection)
+            {
+                error!("Failed to deliver notifications! The error was {}", error);
+            }
+        },
+    );
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>/NotificationPreferences")]
+pub fn get_notification_preferences(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<NotificationPreference>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    get_preference(user_token.user_id, camera_id, &conn)
+        .map(|preference| Json(preference))
+        .map_err(|error| {
+            error!(
+                "Failed to get notification preferences for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get notification preferences",
+                code: "get_notification_preferences_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[put(
+    "/Cameras/<camera_id>/NotificationPreferences",
+    data = "<updated_preference>",
+    format = "json"
+)]
+pub fn update_notification_preferences(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    updated_preference: Json<UpdatedNotificationPreference>,
+) -> Result<Json<NotificationPreference>, ApiError> {
+    let updated_preference = updated_preference.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    if updated_preference
+        .event_types
+        .iter()
+        .any(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        return Err(ApiError {
+            error: "Unknown event type",
+            code: "unknown_event_type",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    for armed_mode in &updated_preference.armed_modes {
+        mode::validate_mode(armed_mode)?;
+    }
+
+    if let Some(min_severity) = &updated_preference.min_severity {
+        validate_severity(min_severity)?;
+    }
+
+    upsert_preference(
+        NotificationPreference {
+            user_id: user_token.user_id,
+            camera_id,
+            push_enabled: updated_preference.push_enabled,
+            event_types: updated_preference.event_types,
+            offline_alerts: updated_preference.offline_alerts,
+            armed_modes: updated_preference.armed_modes,
+            min_severity: updated_preference
+                .min_severity
+                .unwrap_or_else(|| INFO_SEVERITY.to_string()),
+            tamper_overrides: updated_preference.tamper_overrides.unwrap_or(true),
+        },
+        &conn,
+    )
+    .map(|preference| {
+        // Whether the camera is armed may have changed
+        camera_commands::notify_config_updated(camera_id, &conn);
+        Json(preference)
+    })
+    .map_err(|error| {
+        error!(
+            "Failed to update notification preferences for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to update notification preferences",
+            code: "update_notification_preferences_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
@@ -0,0 +1,619 @@
+use crate::{
+    api_error::ApiError, api_version::API_PREFIX, enums::token_error::TokenError,
+    settings::settings, tenant::RequestTenantId, user, user_tokens::UserToken, CameraServerDbConn,
+};
+
+use super::schema::{oauth_codes, oauth_links};
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::uri::Uri;
+use rocket::http::Status;
+use rocket::request::{self, Form, FromRequest, LenientForm};
+use rocket::response::{content::Html, status, Redirect};
+use rocket::{delete, get, post, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+pub const GOOGLE_ASSISTANT: &str = "google";
+pub const ALEXA: &str = "alexa";
+
+/// How long an assistant has to swap an authorization code for tokens.
+pub const AUTHORIZATION_CODE_MINUTES: i64 = 10;
+/// How long an access token lasts before the assistant has to refresh it.
+pub const ACCESS_TOKEN_SECONDS: i64 = 60 * 60;
+
+/// Where each assistant sends users back to once they've linked their account. redirect_uri has to start with one
+/// for the client's assistant, so codes can't be sent anywhere else.
+const REDIRECT_URI_PREFIXES: &[(&str, &str)] = &[
+    (
+        GOOGLE_ASSISTANT,
+        "https://oauth-redirect.googleusercontent.com/r/",
+    ),
+    (
+        GOOGLE_ASSISTANT,
+        "https://oauth-redirect-sandbox.googleusercontent.com/r/",
+    ),
+    (ALEXA, "https://pitangui.amazon.com/api/skill/link/"),
+    (ALEXA, "https://layla.amazon.com/api/skill/link/"),
+    (ALEXA, "https://alexa.amazon.co.jp/api/skill/link/"),
+];
+
+/// A voice assistant set up in [voice_assistants].
+pub struct OAuthClient {
+    pub assistant: &'static str,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Every assistant with a client ID and secret in [voice_assistants].
+pub fn clients() -> Vec<OAuthClient> {
+    let voice_assistants = &settings().voice_assistants;
+
+    vec![
+        (
+            GOOGLE_ASSISTANT,
+            &voice_assistants.google_client_id,
+            &voice_assistants.google_client_secret,
+        ),
+        (
+            ALEXA,
+            &voice_assistants.alexa_client_id,
+            &voice_assistants.alexa_client_secret,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(assistant, client_id, client_secret)| {
+        Some(OAuthClient {
+            assistant,
+            client_id: client_id.clone()?,
+            client_secret: client_secret.clone()?,
+        })
+    })
+    .collect()
+}
+
+pub fn client_for(assistant: &str) -> Option<OAuthClient> {
+    clients()
+        .into_iter()
+        .find(|client| client.assistant == assistant)
+}
+
+fn client_by_id(client_id: &str) -> Option<OAuthClient> {
+    clients()
+        .into_iter()
+        .find(|client| client.client_id == client_id)
+}
+
+fn display_name(assistant: &str) -> &'static str {
+    match assistant {
+        GOOGLE_ASSISTANT => "Google Assistant",
+        _ => "Alexa",
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.trim().as_bytes()))
+}
+
+fn generate_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+/// A user's account linked to a voice assistant.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct OAuthLink {
+    pub link_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    /// google or alexa.
+    pub assistant: String,
+    #[serde(skip)]
+    pub refresh_token_hash: String,
+    #[serde(skip)]
+    pub access_token_hash: String,
+    pub access_token_expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    /// When the assistant last asked for something with the link.
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Queryable)]
+struct OAuthCode {
+    _code_hash: String,
+    user_id: uuid::Uuid,
+    assistant: String,
+    redirect_uri: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Query string for GET /OAuth/Authorize, as the assistant sends it.
+#[derive(FromForm)]
+pub struct AuthorizeQuery {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub state: Option<String>,
+}
+
+/// What the login page sends.
+#[derive(FromForm)]
+pub struct AuthorizeForm {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub state: Option<String>,
+    pub username: String,
+    pub password: String,
+}
+
+/// What the assistant sends to POST /OAuth/Token.
+#[derive(FromForm)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub refresh_token: Option<String>,
+    /// Can be sent with HTTP basic authentication instead, as Alexa does by default.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub token_type: &'static str,
+    pub access_token: String,
+    /// Only when an authorization code is swapped, refreshing keeps the same refresh token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    pub expires_in: i64,
+}
+
+/// The client ID and secret from an Authorization: Basic header, if there is one.
+pub struct BasicAuth(pub Option<(String, String)>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BasicAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let credentials = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let mut parts = decoded.splitn(2, ':');
+                Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            });
+
+        Outcome::Success(BasicAuth(credentials))
+    }
+}
+
+/// Checks an access token, for assistants that send it somewhere other than the Authorization header. Using it
+/// counts as using the link.
+pub fn authenticate(
+    access_token: &str,
+    connection: &PgConnection,
+) -> Result<OAuthLink, TokenError> {
+    let link = diesel::update(
+        oauth_links::table
+            .filter(oauth_links::access_token_hash.eq(hash_token(access_token)))
+            .filter(oauth_links::access_token_expires_at.gt(Utc::now())),
+    )
+    .set(oauth_links::last_used_at.eq(Utc::now()))
+    .get_result::<OAuthLink>(connection)
+    .map_err(|error| match error {
+        diesel::result::Error::NotFound => TokenError::NotFound,
+        error => {
+            error!(
+                "Failed to check OAuth access token! The error was {}",
+                error
+            );
+            TokenError::NotFound
+        }
+    })?;
+
+    match user::is_suspended(link.user_id, || connection) {
+        Ok(false) => Ok(link),
+        Ok(true) => Err(TokenError::Suspended),
+        Err(error) => {
+            error!(
+                "Failed to check if user {} is suspended! The error was {}",
+                link.user_id, error
+            );
+            Err(TokenError::NotFound)
+        }
+    }
+}
+
+/// An assistant's request, with Authorization: Bearer and an access token from POST /OAuth/Token.
+pub struct OAuthToken(pub OAuthLink);
+
+impl<'a, 'r> FromRequest<'a, 'r> for OAuthToken {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let access_token = match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        {
+            Some(access_token) => access_token,
+            None => return Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        };
+
+        let connection = CameraServerDbConn::from_request(&request)
+            .expect("Failed to get DB connection on OAuthToken request guard");
+
+        match authenticate(access_token, &connection) {
+            Ok(link) => Outcome::Success(OAuthToken(link)),
+            Err(TokenError::Suspended) => {
+                Outcome::Failure((Status::Forbidden, TokenError::Suspended))
+            }
+            Err(error) => Outcome::Failure((Status::Unauthorized, error)),
+        }
+    }
+}
+
+/// Removes a link, so its tokens stop working.
+pub fn unlink(link_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(oauth_links::table.find(link_id)).execute(connection)
+}
+
+/// Removes every link the user has, and any codes that haven't been exchanged yet. Returns how many links were
+/// removed.
+pub fn unlink_users_assistants(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(oauth_codes::table.filter(oauth_codes::user_id.eq(user_id)))
+        .execute(connection)?;
+    diesel::delete(oauth_links::table.filter(oauth_links::user_id.eq(user_id))).execute(connection)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n{}\n</body>\n</html>\n",
+        escape_html(title),
+        escape_html(title),
+        body
+    ))
+}
+
+fn error_page(status: Status, message: &str) -> status::Custom<Html<String>> {
+    status::Custom(
+        status,
+        page(
+            "Couldn't link your account",
+            &format!("<p>{}</p>", escape_html(message)),
+        ),
+    )
+}
+
+fn login_page(
+    client: &OAuthClient,
+    redirect_uri: &str,
+    state: &Option<String>,
+    error: Option<&str>,
+) -> Html<String> {
+    let error = error
+        .map(|error| format!("<p><strong>{}</strong></p>\n", escape_html(error)))
+        .unwrap_or_default();
+
+    page(
+        &format!("Link your cameras to {}", display_name(client.assistant)),
+        &format!(
+            "{}<form method=\"post\" action=\"{}/OAuth/Authorize\">\n\
+             <input type=\"hidden\" name=\"client_id\" value=\"{}\">\n\
+             <input type=\"hidden\" name=\"redirect_uri\" value=\"{}\">\n\
+             <input type=\"hidden\" name=\"state\" value=\"{}\">\n\
+             <p><label>Username <input name=\"username\" autocomplete=\"username\" required></label></p>\n\
+             <p><label>Password <input name=\"password\" type=\"password\" autocomplete=\"current-password\" \
+             required></label></p>\n\
+             <p><button type=\"submit\">Link</button></p>\n</form>",
+            error,
+            API_PREFIX,
+            escape_html(&client.client_id),
+            escape_html(redirect_uri),
+            escape_html(state.as_deref().unwrap_or_default()),
+        ),
+    )
+}
+
+/// Finds the client and checks redirect_uri is one of its assistant's.
+fn check_client(client_id: &str, redirect_uri: &str) -> Result<OAuthClient, &'static str> {
+    let client = client_by_id(client_id).ok_or("This assistant isn't set up on this server")?;
+
+    if !REDIRECT_URI_PREFIXES.iter().any(|(assistant, prefix)| {
+        *assistant == client.assistant && redirect_uri.starts_with(prefix)
+    }) {
+        return Err("The assistant asked to be sent somewhere it doesn't use");
+    }
+
+    Ok(client)
+}
+
+/// The login page assistants open when a user links their account. Logging in sends them back to the assistant
+/// with an authorization code.
+#[openapi(skip)]
+#[get("/OAuth/Authorize?<query..>")]
+pub fn authorize_page(
+    query: LenientForm<AuthorizeQuery>,
+) -> Result<Html<String>, status::Custom<Html<String>>> {
+    if query.response_type != "code" {
+        return Err(error_page(
+            Status::BadRequest,
+            "Only the authorization code flow is supported",
+        ));
+    }
+    let client = check_client(&query.client_id, &query.redirect_uri)
+        .map_err(|message| error_page(Status::BadRequest, message))?;
+
+    Ok(login_page(&client, &query.redirect_uri, &query.state, None))
+}
+
+/// Logs in from the login page, and sends the user back to the assistant with an authorization code.
+#[openapi(skip)]
+#[post("/OAuth/Authorize", data = "<form>")]
+pub fn authorize(
+    conn: CameraServerDbConn,
+    tenant: RequestTenantId,
+    form: Form<AuthorizeForm>,
+) -> Result<Redirect, status::Custom<Html<String>>> {
+    let form = form.into_inner();
+    let client = check_client(&form.client_id, &form.redirect_uri)
+        .map_err(|message| error_page(Status::BadRequest, message))?;
+
+    if !user::is_login_valid(
+        form.username.clone(),
+        form.password.clone(),
+        tenant.0,
+        &conn,
+    ) {
+        return Err(status::Custom(
+            Status::Unauthorized,
+            login_page(
+                &client,
+                &form.redirect_uri,
+                &form.state,
+                Some("Invalid username or password"),
+            ),
+        ));
+    }
+
+    let server_error = |error: diesel::result::Error| {
+        error!("Failed to create OAuth code! The error was {}", error);
+        error_page(
+            Status::InternalServerError,
+            "Something went wrong, please try again",
+        )
+    };
+
+    let user =
+        user::get_by_username(form.username.clone(), tenant.0, &conn).map_err(server_error)?;
+    if user::is_suspended(user.user_id, || &*conn).map_err(server_error)? {
+        return Err(error_page(Status::Forbidden, "Your account is suspended"));
+    }
+
+    let code = generate_token();
+    let now = Utc::now();
+
+    diesel::delete(oauth_codes::table.filter(oauth_codes::expires_at.lt(now)))
+        .execute(&*conn)
+        .map_err(server_error)?;
+    diesel::insert_into(oauth_codes::table)
+        .values((
+            oauth_codes::code_hash.eq(hash_token(&code)),
+            oauth_codes::user_id.eq(user.user_id),
+            oauth_codes::assistant.eq(client.assistant),
+            oauth_codes::redirect_uri.eq(&form.redirect_uri),
+            oauth_codes::expires_at.eq(now + Duration::minutes(AUTHORIZATION_CODE_MINUTES)),
+        ))
+        .execute(&*conn)
+        .map_err(server_error)?;
+
+    let separator = if form.redirect_uri.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let state = form
+        .state
+        .as_deref()
+        .filter(|state| !state.is_empty())
+        .map(|state| format!("&state={}", Uri::percent_encode(state)))
+        .unwrap_or_default();
+
+    Ok(Redirect::to(format!(
+        "{}{}code={}{}",
+        form.redirect_uri, separator, code, state
+    )))
+}
+
+/// An error the way OAuth clients expect them, like {"error": "invalid_grant"}.
+fn oauth_error(status: Status, error: &'static str) -> status::Custom<Json<serde_json::Value>> {
+    status::Custom(status, Json(json!({ "error": error })))
+}
+
+fn oauth_server_error(error: diesel::result::Error) -> status::Custom<Json<serde_json::Value>> {
+    error!("Failed to issue OAuth tokens! The error was {}", error);
+    oauth_error(Status::InternalServerError, "server_error")
+}
+
+/// Swaps an authorization code for tokens, or a refresh token for a new access token. Called by the assistant,
+/// with its client ID and secret.
+#[openapi(skip)]
+#[post("/OAuth/Token", data = "<request>")]
+pub fn token(
+    conn: CameraServerDbConn,
+    basic_auth: BasicAuth,
+    request: LenientForm<TokenRequest>,
+) -> Result<Json<TokenResponse>, status::Custom<Json<serde_json::Value>>> {
+    let request = request.into_inner();
+    let invalid_request = || oauth_error(Status::BadRequest, "invalid_request");
+    let invalid_grant = || oauth_error(Status::BadRequest, "invalid_grant");
+
+    let (client_id, client_secret) = basic_auth
+        .0
+        .or_else(|| request.client_id.clone().zip(request.client_secret.clone()))
+        .ok_or_else(|| oauth_error(Status::Unauthorized, "invalid_client"))?;
+    let client = client_by_id(&client_id)
+        .filter(|client| hash_token(&client.client_secret) == hash_token(&client_secret))
+        .ok_or_else(|| oauth_error(Status::Unauthorized, "invalid_client"))?;
+
+    let now = Utc::now();
+    let access_token = generate_token();
+    let access_token_expires_at = now + Duration::seconds(ACCESS_TOKEN_SECONDS);
+
+    match request.grant_type.as_str() {
+        "authorization_code" => {
+            let code = request.code.as_deref().ok_or_else(invalid_request)?;
+
+            // Deleted as it's read, so a code can't be used twice
+            let code = diesel::delete(oauth_codes::table.find(hash_token(code)))
+                .get_result::<OAuthCode>(&*conn)
+                .optional()
+                .map_err(oauth_server_error)?
+                .filter(|code| {
+                    code.expires_at > now
+                        && code.assistant == client.assistant
+                        && Some(&code.redirect_uri) == request.redirect_uri.as_ref()
+                })
+                .ok_or_else(invalid_grant)?;
+
+            let refresh_token = generate_token();
+            diesel::insert_into(oauth_links::table)
+                .values((
+                    oauth_links::user_id.eq(code.user_id),
+                    oauth_links::assistant.eq(client.assistant),
+                    oauth_links::refresh_token_hash.eq(hash_token(&refresh_token)),
+                    oauth_links::access_token_hash.eq(hash_token(&access_token)),
+                    oauth_links::access_token_expires_at.eq(access_token_expires_at),
+                ))
+                .on_conflict((oauth_links::user_id, oauth_links::assistant))
+                .do_update()
+                .set((
+                    oauth_links::refresh_token_hash.eq(hash_token(&refresh_token)),
+                    oauth_links::access_token_hash.eq(hash_token(&access_token)),
+                    oauth_links::access_token_expires_at.eq(access_token_expires_at),
+                    oauth_links::created_at.eq(now),
+                    oauth_links::last_used_at.eq(None::<DateTime<Utc>>),
+                ))
+                .execute(&*conn)
+                .map_err(oauth_server_error)?;
+
+            info!(
+                "User {} linked their account to {}",
+                code.user_id,
+                display_name(client.assistant)
+            );
+
+            Ok(Json(TokenResponse {
+                token_type: "Bearer",
+                access_token,
+                refresh_token: Some(refresh_token),
+                expires_in: ACCESS_TOKEN_SECONDS,
+            }))
+        }
+        "refresh_token" => {
+            let refresh_token = request
+                .refresh_token
+                .as_deref()
+                .ok_or_else(invalid_request)?;
+
+            let refreshed = diesel::update(
+                oauth_links::table
+                    .filter(oauth_links::refresh_token_hash.eq(hash_token(refresh_token)))
+                    .filter(oauth_links::assistant.eq(client.assistant)),
+            )
+            .set((
+                oauth_links::access_token_hash.eq(hash_token(&access_token)),
+                oauth_links::access_token_expires_at.eq(access_token_expires_at),
+            ))
+            .execute(&*conn)
+            .map_err(oauth_server_error)?;
+
+            if refreshed == 0 {
+                return Err(invalid_grant());
+            }
+
+            Ok(Json(TokenResponse {
+                token_type: "Bearer",
+                access_token,
+                refresh_token: None,
+                expires_in: ACCESS_TOKEN_SECONDS,
+            }))
+        }
+        _ => Err(oauth_error(Status::BadRequest, "unsupported_grant_type")),
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to get voice assistant links! The error was {}",
+        error
+    );
+    ApiError {
+        error: "Failed to get voice assistant links",
+        code: "get_voice_assistant_links_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// The voice assistants the user has linked their account to.
+#[openapi]
+#[get("/VoiceAssistants/Links")]
+pub fn list_links(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<Vec<OAuthLink>>, ApiError> {
+    oauth_links::table
+        .filter(oauth_links::user_id.eq(user_token.user_id))
+        .order(oauth_links::link_id)
+        .load::<OAuthLink>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Unlinks a voice assistant from the user's account. It can't see their cameras any more, even with tokens it
+/// already has.
+#[openapi]
+#[delete("/VoiceAssistants/Links/<link_id>")]
+pub fn delete_link(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    link_id: i32,
+) -> Result<(), ApiError> {
+    diesel::delete(
+        oauth_links::table
+            .find(link_id)
+            .filter(oauth_links::user_id.eq(user_token.user_id)),
+    )
+    .execute(&*conn)
+    .map_err(database_error)
+    .and_then(|deleted| match deleted {
+        0 => Err(ApiError {
+            error: "Link not found",
+            code: "link_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        _ => Ok(()),
+    })
+}
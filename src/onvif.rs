@@ -0,0 +1,645 @@
+use crate::{
+    api_error::ApiError,
+    camera::{latest_image_id, open_image, Camera, CameraId},
+    media_store::{media_store, MediaStore},
+    oauth::BasicAuth,
+    rtsp::{self, jpeg_dimensions, rtsp_port},
+    settings::settings,
+    tls, user,
+    user_tokens::UserToken,
+    users_cameras::get_users_cameras,
+    CameraServerDbConn,
+};
+
+use super::schema::onvif_credentials;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest};
+use rocket::response::{content::Content, status, Response, Stream};
+use rocket::{delete, get, post, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::{Cursor, Read};
+
+/// The size profiles claim for cameras with no images yet.
+pub const DEFAULT_RESOLUTION: (u16, u16) = (640, 480);
+
+const DEVICE_NAMESPACE: &str = "http://www.onvif.org/ver10/device/wsdl";
+const MEDIA_NAMESPACE: &str = "http://www.onvif.org/ver10/media/wsdl";
+
+/// What NVRs log in to the ONVIF facade and its RTSP streams with. Each user has at most one, and sees every camera
+/// they have access to through it.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct OnvifCredential {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+    /// The password's HA1, see rtsp::ha1(). The password itself isn't kept.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub ha1: String,
+}
+
+impl OnvifCredential {
+    pub fn password_matches(&self, password: &str) -> bool {
+        rtsp::hashes_match(&rtsp::ha1(&self.username, password), &self.ha1)
+    }
+}
+
+/// Sent back by POST /Account/Onvif. The password is only shown this once.
+#[derive(Serialize, JsonSchema)]
+pub struct NewOnvifCredential {
+    #[serde(flatten)]
+    pub credential: OnvifCredential,
+    pub password: String,
+}
+
+pub fn find_credential(
+    username: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<OnvifCredential>> {
+    onvif_credentials::table
+        .filter(onvif_credentials::username.eq(username))
+        .get_result(connection)
+        .optional()
+}
+
+/// Removes the user's ONVIF username and password. Returns how many were removed, 0 if they didn't have one.
+pub fn delete_users_credential(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(onvif_credentials::table.find(user_id)).execute(connection)
+}
+
+/// The camera, if the credential's user can see it and isn't suspended. Disabled users' credentials are deleted,
+/// see user::disable().
+pub fn credential_camera(
+    credential: &OnvifCredential,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<Camera>> {
+    if user::is_suspended(credential.user_id, || connection)? {
+        return Ok(None);
+    }
+
+    Ok(get_users_cameras(credential.user_id, connection)?
+        .into_iter()
+        .find(|camera| camera.camera_id == camera_id))
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get ONVIF credentials! The error was {}", error);
+    ApiError {
+        error: "Failed to get ONVIF credentials",
+        code: "get_onvif_credentials_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn onvif_off() -> ApiError {
+    ApiError {
+        error: "ONVIF isn't turned on on this server",
+        code: "onvif_off",
+        status: Status::NotFound,
+        field: None,
+    }
+}
+
+/// The user's ONVIF username. The password was only shown when it was made.
+#[openapi]
+#[get("/Account/Onvif")]
+pub fn get_credential(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<OnvifCredential>, ApiError> {
+    rtsp_port().ok_or_else(onvif_off)?;
+
+    onvif_credentials::table
+        .find(user_token.user_id)
+        .get_result::<OnvifCredential>(&*conn)
+        .optional()
+        .map_err(database_error)?
+        .map(Json)
+        .ok_or(ApiError {
+            error: "ONVIF credentials not found",
+            code: "onvif_credentials_not_found",
+            status: Status::NotFound,
+            field: None,
+        })
+}
+
+/// Makes the user an ONVIF username and password, replacing any they had. NVRs using the old ones stop working.
+#[openapi]
+#[post("/Account/Onvif")]
+pub fn create_credential(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<NewOnvifCredential>, ApiError> {
+    rtsp_port().ok_or_else(onvif_off)?;
+
+    let username = format!("onvif-{}", &uuid::Uuid::new_v4().simple().to_string()[..12]);
+    let password = uuid::Uuid::new_v4().simple().to_string();
+    let ha1 = rtsp::ha1(&username, &password);
+
+    diesel::insert_into(onvif_credentials::table)
+        .values((
+            onvif_credentials::user_id.eq(user_token.user_id),
+            onvif_credentials::username.eq(&username),
+            onvif_credentials::ha1.eq(&ha1),
+        ))
+        .on_conflict(onvif_credentials::user_id)
+        .do_update()
+        .set((
+            onvif_credentials::username.eq(&username),
+            onvif_credentials::ha1.eq(&ha1),
+            onvif_credentials::created_at.eq(Utc::now()),
+        ))
+        .get_result::<OnvifCredential>(&*conn)
+        .map(|credential| {
+            Json(NewOnvifCredential {
+                credential,
+                password,
+            })
+        })
+        .map_err(database_error)
+}
+
+/// Removes the user's ONVIF username and password, so NVRs can't see their cameras any more.
+#[openapi]
+#[delete("/Account/Onvif")]
+pub fn delete_credential(conn: CameraServerDbConn, user_token: UserToken) -> Result<(), ApiError> {
+    delete_users_credential(user_token.user_id, &conn)
+        .map(|_| ())
+        .map_err(database_error)
+}
+
+/// The Host header, which addresses in responses are built from so they work however the NVR reached the server.
+pub struct RequestHost(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestHost {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Host") {
+            Some(host) => Outcome::Success(RequestHost(host.to_string())),
+            None => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+impl RequestHost {
+    fn http_url(&self, path: &str) -> String {
+        let scheme = match tls::paths() {
+            Some(_) => "https",
+            None => "http",
+        };
+        format!("{}://{}{}", scheme, self.0, path)
+    }
+
+    /// The host without the API's port, for the RTSP server's.
+    fn hostname(&self) -> &str {
+        match (self.0.starts_with('['), self.0.find(']')) {
+            (true, Some(end)) => &self.0[..=end],
+            _ => self.0.split(':').next().unwrap_or(&self.0),
+        }
+    }
+
+    fn rtsp_url(&self, camera_id: uuid::Uuid) -> String {
+        format!(
+            "rtsp://{}:{}/{}",
+            self.hostname(),
+            rtsp_port().expect("ONVIF routes refuse requests unless rtsp_port is set"),
+            camera_id
+        )
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The first element with the local name, ignoring its namespace prefix, as (start tag, text). Only for elements
+/// holding text, which is all that's read from requests.
+fn element<'a>(xml: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let mut offset = 0;
+
+    while let Some(start) = xml[offset..].find('<') {
+        let start = offset + start;
+        let tag_end = start + xml[start..].find('>')?;
+        let tag = &xml[start + 1..tag_end];
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next()?;
+        let local_name = tag_name.rsplit(':').next()?;
+
+        if local_name == name && !tag.starts_with('/') {
+            if tag.ends_with('/') {
+                return Some((tag, ""));
+            }
+            let text_end = tag_end + 1 + xml[tag_end + 1..].find('<')?;
+            return Some((tag, xml[tag_end + 1..text_end].trim()));
+        }
+
+        offset = tag_end + 1;
+    }
+
+    None
+}
+
+/// The local name of the first element in the SOAP body, which is the operation being called.
+fn operation(xml: &str) -> Option<&str> {
+    let (body_tag, _) = element(xml, "Body")?;
+    let body_start = xml.find(body_tag)? + body_tag.len() + 1;
+
+    xml[body_start..]
+        .split('<')
+        .skip(1)
+        .map(|tag| {
+            tag.split(|c: char| c.is_whitespace() || c == '/' || c == '>')
+                .next()
+                .unwrap_or_default()
+        })
+        .find(|tag_name| {
+            !tag_name.is_empty() && !tag_name.starts_with('?') && !tag_name.starts_with('!')
+        })
+        .map(|tag_name| tag_name.rsplit(':').next().unwrap_or(tag_name))
+}
+
+/// Checks the WS-Security UsernameToken in the SOAP header. Only plain text passwords are taken, as a
+/// PasswordDigest needs the password itself to check and only its HA1 is kept, so NVRs should reach the facade
+/// over TLS.
+fn authenticate(xml: &str, connection: &PgConnection) -> Option<OnvifCredential> {
+    let (_, username) = element(xml, "Username")?;
+    let (password_tag, password) = element(xml, "Password")?;
+
+    let credential = find_credential(username, connection)
+        .map_err(|error| {
+            error!(
+                "Failed to get ONVIF credentials for {}! The error was {}",
+                username, error
+            )
+        })
+        .ok()??;
+
+    match !password_tag.contains("PasswordDigest") && credential.password_matches(password) {
+        true => Some(credential),
+        false => None,
+    }
+}
+
+type SoapResponse = status::Custom<Content<String>>;
+
+fn envelope(status: Status, body: &str) -> SoapResponse {
+    status::Custom(
+        status,
+        Content(
+            ContentType::new("application", "soap+xml"),
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <s:Envelope xmlns:s=\"http://www.w3.org/2003/05/soap-envelope\" xmlns:tds=\"{}\" \
+                 xmlns:trt=\"{}\" xmlns:tt=\"http://www.onvif.org/ver10/schema\" \
+                 xmlns:ter=\"http://www.onvif.org/ver10/error\"><s:Body>{}</s:Body></s:Envelope>",
+                DEVICE_NAMESPACE, MEDIA_NAMESPACE, body
+            ),
+        ),
+    )
+}
+
+fn fault(code: &str, subcode: &str, reason: &str) -> SoapResponse {
+    let status = match code {
+        "s:Sender" => Status::BadRequest,
+        _ => Status::InternalServerError,
+    };
+
+    envelope(
+        status,
+        &format!(
+            "<s:Fault><s:Code><s:Value>{}</s:Value><s:Subcode><s:Value>{}</s:Value></s:Subcode></s:Code>\
+             <s:Reason><s:Text xml:lang=\"en\">{}</s:Text></s:Reason></s:Fault>",
+            code,
+            subcode,
+            escape_xml(reason)
+        ),
+    )
+}
+
+fn resolution(camera_id: &uuid::Uuid) -> (u16, u16) {
+    let mut image = Vec::new();
+
+    latest_image_id(camera_id)
+        .and_then(|image_id| {
+            media_store()
+                .open_image(camera_id, image_id)
+                .and_then(|mut file| file.read_to_end(&mut image))
+                .ok()
+        })
+        .and_then(|_| jpeg_dimensions(&image))
+        .unwrap_or(DEFAULT_RESOLUTION)
+}
+
+/// A media profile for the camera. Every camera has exactly one, with the camera ID as its token.
+fn profile(element: &str, camera: &Camera) -> String {
+    let (width, height) = resolution(&camera.camera_id);
+    let frame_rate = (1000 / settings().onvif.frame_interval_milliseconds).max(1);
+    let name = escape_xml(&camera.name);
+
+    format!(
+        "<trt:{element} token=\"{token}\" fixed=\"true\"><tt:Name>{name}</tt:Name>\
+         <tt:VideoSourceConfiguration token=\"{token}\"><tt:Name>{name}</tt:Name><tt:UseCount>1</tt:UseCount>\
+         <tt:SourceToken>{token}</tt:SourceToken><tt:Bounds x=\"0\" y=\"0\" width=\"{width}\" height=\"{height}\"/>\
+         </tt:VideoSourceConfiguration>\
+         <tt:VideoEncoderConfiguration token=\"{token}\"><tt:Name>{name}</tt:Name><tt:UseCount>1</tt:UseCount>\
+         <tt:Encoding>JPEG</tt:Encoding><tt:Resolution><tt:Width>{width}</tt:Width><tt:Height>{height}</tt:Height>\
+         </tt:Resolution><tt:Quality>5</tt:Quality><tt:RateControl><tt:FrameRateLimit>{frame_rate}</tt:FrameRateLimit>\
+         <tt:EncodingInterval>1</tt:EncodingInterval><tt:BitrateLimit>0</tt:BitrateLimit></tt:RateControl>\
+         <tt:Multicast><tt:Address><tt:Type>IPv4</tt:Type><tt:IPv4Address>0.0.0.0</tt:IPv4Address></tt:Address>\
+         <tt:Port>0</tt:Port><tt:TTL>0</tt:TTL><tt:AutoStart>false</tt:AutoStart></tt:Multicast>\
+         <tt:SessionTimeout>PT{timeout}S</tt:SessionTimeout></tt:VideoEncoderConfiguration></trt:{element}>",
+        element = element,
+        token = camera.camera_id,
+        name = name,
+        width = width,
+        height = height,
+        frame_rate = frame_rate,
+        timeout = rtsp::SESSION_TIMEOUT_SECONDS,
+    )
+}
+
+fn media_uri(element: &str, uri: &str) -> String {
+    format!(
+        "<trt:{0}><trt:MediaUri><tt:Uri>{1}</tt:Uri><tt:InvalidAfterConnect>false</tt:InvalidAfterConnect>\
+         <tt:InvalidAfterReboot>false</tt:InvalidAfterReboot><tt:Timeout>PT0S</tt:Timeout></trt:MediaUri></trt:{0}>",
+        element,
+        escape_xml(uri)
+    )
+}
+
+fn service(namespace: &str, address: &str) -> String {
+    format!(
+        "<tds:Service><tds:Namespace>{}</tds:Namespace><tds:XAddr>{}</tds:XAddr><tds:Version><tt:Major>2</tt:Major>\
+         <tt:Minor>0</tt:Minor></tds:Version></tds:Service>",
+        namespace,
+        escape_xml(address)
+    )
+}
+
+fn date_and_time(now: DateTime<Utc>) -> String {
+    format!(
+        "<tds:GetSystemDateAndTimeResponse><tds:SystemDateAndTime><tt:DateTimeType>NTP</tt:DateTimeType>\
+         <tt:DaylightSavings>false</tt:DaylightSavings><tt:TimeZone><tt:TZ>UTC0</tt:TZ></tt:TimeZone>\
+         <tt:UTCDateTime><tt:Time><tt:Hour>{}</tt:Hour><tt:Minute>{}</tt:Minute><tt:Second>{}</tt:Second></tt:Time>\
+         <tt:Date><tt:Year>{}</tt:Year><tt:Month>{}</tt:Month><tt:Day>{}</tt:Day></tt:Date></tt:UTCDateTime>\
+         </tds:SystemDateAndTime></tds:GetSystemDateAndTimeResponse>",
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.year(),
+        now.month(),
+        now.day()
+    )
+}
+
+/// Answers a SOAP request to either service. They share one handler, as some NVRs send everything to the device
+/// service whatever GetCapabilities says.
+fn handle(host: &RequestHost, xml: &str, connection: &PgConnection) -> SoapResponse {
+    if rtsp_port().is_none() {
+        return fault(
+            "s:Receiver",
+            "ter:ActionNotSupported",
+            "ONVIF is turned off",
+        );
+    }
+
+    let operation = match operation(xml) {
+        Some(operation) => operation,
+        None => {
+            return fault(
+                "s:Sender",
+                "ter:InvalidArgVal",
+                "The request has no operation",
+            )
+        }
+    };
+
+    let device_url = host.http_url("/onvif/device_service");
+    let media_url = host.http_url("/onvif/media_service");
+
+    // Answered without logging in, as NVRs call them to find out what the device is and to set their clock
+    match operation {
+        "GetSystemDateAndTime" => return envelope(Status::Ok, &date_and_time(Utc::now())),
+        "GetCapabilities" => {
+            return envelope(
+                Status::Ok,
+                &format!(
+                    "<tds:GetCapabilitiesResponse><tds:Capabilities><tt:Device><tt:XAddr>{}</tt:XAddr></tt:Device>\
+                     <tt:Media><tt:XAddr>{}</tt:XAddr><tt:StreamingCapabilities>\
+                     <tt:RTPMulticast>false</tt:RTPMulticast><tt:RTP_TCP>true</tt:RTP_TCP>\
+                     <tt:RTP_RTSP_TCP>true</tt:RTP_RTSP_TCP></tt:StreamingCapabilities></tt:Media>\
+                     </tds:Capabilities></tds:GetCapabilitiesResponse>",
+                    escape_xml(&device_url),
+                    escape_xml(&media_url)
+                ),
+            )
+        }
+        "GetServices" => {
+            return envelope(
+                Status::Ok,
+                &format!(
+                    "<tds:GetServicesResponse>{}{}</tds:GetServicesResponse>",
+                    service(DEVICE_NAMESPACE, &device_url),
+                    service(MEDIA_NAMESPACE, &media_url)
+                ),
+            )
+        }
+        _ => {}
+    }
+
+    let credential = match authenticate(xml, connection) {
+        Some(credential) => credential,
+        None => return fault("s:Sender", "ter:NotAuthorized", "Sender not authorized"),
+    };
+
+    let cameras =
+        match user::is_suspended(credential.user_id, || connection).and_then(|suspended| {
+            match suspended {
+                true => Ok(Vec::new()),
+                false => get_users_cameras(credential.user_id, connection),
+            }
+        }) {
+            Ok(cameras) => cameras,
+            Err(error) => {
+                error!("Failed to get cameras for ONVIF! The error was {}", error);
+                return fault("s:Receiver", "ter:Action", "Failed to get cameras");
+            }
+        };
+
+    // Profiles, video sources and their configurations all use the camera ID as their token
+    let requested = || {
+        let token = element(xml, "ProfileToken")
+            .or_else(|| element(xml, "VideoSourceToken"))
+            .map(|(_, token)| token)?;
+        cameras
+            .iter()
+            .find(|camera| camera.camera_id.to_string() == token)
+    };
+    let no_profile = || fault("s:Sender", "ter:NoProfile", "The profile doesn't exist");
+
+    let body = match operation {
+        "GetDeviceInformation" => format!(
+            "<tds:GetDeviceInformationResponse><tds:Manufacturer>camera-server</tds:Manufacturer>\
+             <tds:Model>camera-server</tds:Model><tds:FirmwareVersion>{}</tds:FirmwareVersion>\
+             <tds:SerialNumber>{}</tds:SerialNumber><tds:HardwareId>camera-server</tds:HardwareId>\
+             </tds:GetDeviceInformationResponse>",
+            env!("CARGO_PKG_VERSION"),
+            escape_xml(&credential.username)
+        ),
+        "GetScopes" => {
+            let scopes = [
+                "onvif://www.onvif.org/Profile/Streaming",
+                "onvif://www.onvif.org/type/video_encoder",
+                "onvif://www.onvif.org/name/camera-server",
+            ];
+            format!(
+                "<tds:GetScopesResponse>{}</tds:GetScopesResponse>",
+                scopes
+                    .iter()
+                    .map(|scope| format!(
+                        "<tds:Scopes><tt:ScopeDef>Fixed</tt:ScopeDef><tt:ScopeItem>{}</tt:ScopeItem></tds:Scopes>",
+                        scope
+                    ))
+                    .collect::<String>()
+            )
+        }
+        "GetProfiles" => format!(
+            "<trt:GetProfilesResponse>{}</trt:GetProfilesResponse>",
+            cameras
+                .iter()
+                .map(|camera| profile("Profiles", camera))
+                .collect::<String>()
+        ),
+        "GetProfile" => match requested() {
+            Some(camera) => format!(
+                "<trt:GetProfileResponse>{}</trt:GetProfileResponse>",
+                profile("Profile", camera)
+            ),
+            None => return no_profile(),
+        },
+        "GetVideoSources" => format!(
+            "<trt:GetVideoSourcesResponse>{}</trt:GetVideoSourcesResponse>",
+            cameras
+                .iter()
+                .map(|camera| {
+                    let (width, height) = resolution(&camera.camera_id);
+                    format!(
+                        "<trt:VideoSources token=\"{}\"><tt:Framerate>{}</tt:Framerate><tt:Resolution>\
+                         <tt:Width>{}</tt:Width><tt:Height>{}</tt:Height></tt:Resolution></trt:VideoSources>",
+                        camera.camera_id,
+                        (1000 / settings().onvif.frame_interval_milliseconds).max(1),
+                        width,
+                        height
+                    )
+                })
+                .collect::<String>()
+        ),
+        "GetStreamUri" => match requested() {
+            Some(camera) => media_uri("GetStreamUriResponse", &host.rtsp_url(camera.camera_id)),
+            None => return no_profile(),
+        },
+        "GetSnapshotUri" => match requested() {
+            Some(camera) => media_uri(
+                "GetSnapshotUriResponse",
+                &host.http_url(&format!("/onvif/snapshot/{}", camera.camera_id)),
+            ),
+            None => return no_profile(),
+        },
+        _ => {
+            return fault(
+                "s:Receiver",
+                "ter:ActionNotSupported",
+                "The operation isn't supported",
+            )
+        }
+    };
+
+    envelope(Status::Ok, &body)
+}
+
+/// The ONVIF device service, which NVRs are pointed at.
+#[post("/onvif/device_service", data = "<body>")]
+pub fn device_service(conn: CameraServerDbConn, host: RequestHost, body: String) -> SoapResponse {
+    handle(&host, &body, &conn)
+}
+
+/// The ONVIF media service, for profiles and stream addresses.
+#[post("/onvif/media_service", data = "<body>")]
+pub fn media_service(conn: CameraServerDbConn, host: RequestHost, body: String) -> SoapResponse {
+    handle(&host, &body, &conn)
+}
+
+fn unauthorized() -> Response<'static> {
+    Response::build()
+        .status(Status::Unauthorized)
+        .raw_header(
+            "WWW-Authenticate",
+            format!("Basic realm=\"{}\"", rtsp::REALM),
+        )
+        .sized_body(Cursor::new("Unauthorized"))
+        .finalize()
+}
+
+/// The camera's latest image, from GetSnapshotUri. NVRs log in with HTTP basic authentication and the ONVIF
+/// username and password.
+#[get("/onvif/snapshot/<camera_id>")]
+pub fn snapshot(
+    conn: CameraServerDbConn,
+    basic_auth: BasicAuth,
+    camera_id: CameraId,
+) -> Result<Stream<Box<dyn Read + Send>>, Response<'static>> {
+    let camera_id = camera_id.into_inner();
+    if rtsp_port().is_none() {
+        return Err(Response::build().status(Status::NotFound).finalize());
+    }
+
+    let credential = match basic_auth.0 {
+        Some((username, password)) => match find_credential(&username, &conn) {
+            Ok(Some(credential)) if credential.password_matches(&password) => credential,
+            Ok(_) => return Err(unauthorized()),
+            Err(error) => {
+                error!("Failed to get ONVIF credentials! The error was {}", error);
+                return Err(Response::build()
+                    .status(Status::InternalServerError)
+                    .finalize());
+            }
+        },
+        None => return Err(unauthorized()),
+    };
+
+    let image_id = match credential_camera(&credential, camera_id, &conn) {
+        Ok(Some(_)) => latest_image_id(&camera_id),
+        Ok(None) => None,
+        Err(error) => {
+            error!("Failed to get camera for ONVIF! The error was {}", error);
+            return Err(Response::build()
+                .status(Status::InternalServerError)
+                .finalize());
+        }
+    };
+
+    match image_id {
+        Some(image_id) => open_image(&camera_id, image_id).map_err(|_| {
+            Response::build()
+                .status(Status::InternalServerError)
+                .finalize()
+        }),
+        None => Err(Response::build().status(Status::NotFound).finalize()),
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    admin::AdminToken,
+    api_error::{ApiError, ErrorBody},
+    camera::{Snapshot, SnapshotResponse},
+    camera_tokens::CameraToken,
+    database::ReadDbConn,
+    device_format::{Device, DeviceBody},
+    federation::{PeerToken, ShareAccess},
+    rate_limit::RateLimitStatus,
+    replication::ReplicationToken,
+    user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use okapi::openapi3::{MediaType, Parameter, ParameterValue, RequestBody, Responses};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromData, OpenApiFromRequest, RequestHeaderInput};
+use rocket_okapi::response::OpenApiResponder;
+use rocket_okapi::util::add_schema_response;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Device endpoints take and return the same schema in each of these.
+const DEVICE_CONTENT_TYPES: [&str; 3] = [
+    "application/json",
+    "application/cbor",
+    "application/msgpack",
+];
+
+/// Describes one of the token headers that UserToken and CameraToken read.
+fn token_header(
+    gen: &mut OpenApiGenerator,
+    header: &str,
+    description: &str,
+) -> rocket_okapi::Result<RequestHeaderInput> {
+    Ok(RequestHeaderInput::Parameter(Parameter {
+        name: header.to_string(),
+        location: String::from("header"),
+        description: Some(description.to_string()),
+        required: true,
+        deprecated: false,
+        allow_empty_value: false,
+        value: ParameterValue::Schema {
+            style: None,
+            explode: None,
+            allow_reserved: false,
+            schema: gen.json_schema::<String>(),
+            example: None,
+            examples: None,
+        },
+        extensions: Default::default(),
+    }))
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for UserToken {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "user_token",
+            "The user token returned by POST /Login or POST /Users",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for AdminToken {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "user_token",
+            "The user token of one of the users in ADMIN_USER_IDS",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for ReplicationToken {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "replication_token",
+            "token in the secondary's [replication], sent by the primary",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for PeerToken {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "federation_token",
+            "token from the federation handshake, sent by a peer",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for ShareAccess {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "federation_access_token",
+            "access token for a remote share, sent by a peer",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for CameraToken {
+    fn request_input(
+        gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        token_header(
+            gen,
+            "camera_token",
+            "The camera token returned by POST /Cameras",
+        )
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for RateLimitStatus {
+    fn request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for CameraServerDbConn {
+    fn request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+impl<'a, 'r> OpenApiFromRequest<'a, 'r> for ReadDbConn {
+    fn request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+/// Errors are an ErrorBody, with a status code that depends on what went wrong.
+impl<'r> OpenApiResponder<'r> for ApiError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = gen.json_schema::<ErrorBody>();
+        add_schema_response(&mut responses, 400, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 401, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 404, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 422, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 500, "application/json", schema)?;
+        Ok(responses)
+    }
+}
+
+impl<'a, T: JsonSchema + DeserializeOwned> OpenApiFromData<'a> for DeviceBody<T> {
+    fn request_body(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody> {
+        let schema = gen.json_schema::<T>();
+
+        Ok(RequestBody {
+            content: DEVICE_CONTENT_TYPES
+                .iter()
+                .map(|content_type| {
+                    (
+                        content_type.to_string(),
+                        MediaType {
+                            schema: Some(schema.clone()),
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            required: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// The format is picked with the Accept header.
+impl<'r, T: JsonSchema + Serialize> OpenApiResponder<'r> for Device<T> {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = gen.json_schema::<T>();
+
+        for content_type in DEVICE_CONTENT_TYPES.iter() {
+            add_schema_response(&mut responses, 200, content_type, schema.clone())?;
+        }
+
+        Ok(responses)
+    }
+}
+
+impl<'r> OpenApiResponder<'r> for SnapshotResponse {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = gen.json_schema::<Snapshot>();
+        add_schema_response(&mut responses, 200, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 202, "application/json", schema)?;
+        Ok(responses)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::api_error::ApiError;
+
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How many items are returned per page if the client doesn't ask for a specific page size.
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+/// What every list endpoint returns.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// How many items there are across every page.
+    pub total: i64,
+    /// Pass this back as ?cursor= to get the next page. None on the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// Query string accepted by list endpoints that don't take any filters.
+#[derive(FromForm, JsonSchema)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    /// Comma separated fields to return for each item, e.g. camera_id,name. Defaults to every field.
+    pub fields: Option<String>,
+}
+
+impl PageQuery {
+    pub fn offset_and_limit(&self) -> Result<(i64, i64), ApiError> {
+        offset_and_limit(&self.cursor, None, self.page_size)
+    }
+}
+
+/// Works out where a page starts and how long it is.
+/// Cursors are opaque to clients, but are currently just the offset of the first item.
+/// page is the older page number parameter, and is only used if there's no cursor.
+pub fn offset_and_limit(
+    cursor: &Option<String>,
+    page: Option<i64>,
+    page_size: Option<i64>,
+) -> Result<(i64, i64), ApiError> {
+    let limit = page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+
+    let offset = match cursor {
+        Some(cursor) => cursor.parse::<i64>().map_err(|_| ApiError {
+            error: "Invalid cursor",
+            code: "invalid_cursor",
+            status: Status::UnprocessableEntity,
+            field: Some("cursor"),
+        })?,
+        None => page
+            .unwrap_or(0)
+            .checked_mul(limit)
+            .ok_or_else(|| page_too_far("page"))?,
+    };
+
+    // Page::new() adds the page's length to the offset for the next cursor
+    if offset.checked_add(limit).is_none() {
+        return Err(page_too_far(if cursor.is_some() {
+            "cursor"
+        } else {
+            "page"
+        }));
+    }
+
+    Ok((offset.max(0), limit))
+}
+
+fn page_too_far(field: &'static str) -> ApiError {
+    ApiError {
+        error: "Page is past the end of every list",
+        code: "page_out_of_range",
+        status: Status::BadRequest,
+        field: Some(field),
+    }
+}
+
+/// Parses ?updated_since=, which list endpoints take so clients can sync only what has changed since they last looked.
+pub fn parse_updated_since(
+    updated_since: &Option<String>,
+) -> Result<Option<DateTime<Utc>>, ApiError> {
+    match updated_since {
+        Some(updated_since) => DateTime::parse_from_rfc3339(updated_since)
+            .map(|updated_since| Some(updated_since.with_timezone(&Utc)))
+            .map_err(|_| ApiError {
+                error: "Failed to parse updated_since, timestamps must be RFC 3339",
+                code: "invalid_updated_since",
+                status: Status::UnprocessableEntity,
+                field: Some("updated_since"),
+            }),
+        None => Ok(None),
+    }
+}
+
+impl<T> Page<T> {
+    /// Wraps one page of items that started at offset.
+    pub fn new(items: Vec<T>, offset: i64, total: i64) -> Page<T> {
+        // Offsets from offset_and_limit() can't overflow, but from_vec() callers can pass their own
+        let next_offset = offset.checked_add(items.len() as i64);
+
+        Page {
+            next_cursor: match next_offset {
+                Some(next_offset) if items.len() > 0 && next_offset < total => {
+                    Some(next_offset.to_string())
+                }
+                _ => None,
+            },
+            items,
+            total,
+        }
+    }
+
+    /// Pages a list that has already been loaded in full.
+    pub fn from_vec(all_items: Vec<T>, offset: i64, limit: i64) -> Page<T> {
+        let total = all_items.len() as i64;
+
+        let items = all_items
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect();
+
+        Page::new(items, offset, total)
+    }
+}
@@ -0,0 +1,24 @@
+use diesel::QueryResult;
+use serde::{Deserialize, Deserializer};
+
+/// For nullable fields in a PATCH body, where a missing field has to mean something different to null.
+/// Use with `#[serde(default, deserialize_with = "double_option")]`: missing is None, null is Some(None).
+pub fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Diesel refuses to run an UPDATE with nothing to set, so a PATCH that doesn't change anything
+/// gets the row as it already is.
+pub fn or_unchanged<T, F>(result: QueryResult<T>, unchanged: F) -> QueryResult<T>
+where
+    F: FnOnce() -> QueryResult<T>,
+{
+    match result {
+        Err(diesel::result::Error::QueryBuilderError(_)) => unchanged(),
+        result => result,
+    }
+}
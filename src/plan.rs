@@ -0,0 +1,542 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audio, audit,
+    cache::{self, cache},
+    media_hold,
+    media_store::{media_store, MediaStore},
+    recording,
+    soft_delete::{not_found_or_database_error, parse_user_id},
+    storage, usage,
+    user::{self, User},
+    user_tokens::UserToken,
+    users_cameras, worker, CameraServerDbConn,
+};
+
+use super::schema::{audio_clips, plans, recordings, users};
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use diesel::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use rocket::http::Status;
+use rocket::{delete, get, post, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+/// How long a user's storage total is cached for, as counting it means listing every camera they own.
+/// Uploads can go over max_storage_gb by up to this long's worth of footage.
+pub const STORAGE_CACHE_SECONDS: u64 = 60;
+
+const BYTES_PER_GB: u64 = 1024 * 1024 * 1024;
+
+/// What a user is allowed, given to them with PUT /Admin/Users/<user_id>/Plan. Limits that are None are unlimited,
+/// as is everything for users without a plan. Cameras and storage count what the user owns, not what's shared with
+/// them, see users_cameras::get_owners().
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Plan {
+    pub plan_id: i32,
+    pub name: String,
+    pub max_cameras: Option<i32>,
+    /// Images and audio clips across all their cameras, in GiB.
+    pub max_storage_gb: Option<i32>,
+    /// Images and audio clips older than this are deleted.
+    pub retention_days: Option<i32>,
+    /// Websockets, event streams, talk viewers and RTSP streams open at once, counted across every server.
+    pub max_streams: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Sent with POST /Admin/Plans and PUT /Admin/Plans/<plan_id>.
+#[derive(Insertable, AsChangeset, Deserialize, JsonSchema)]
+#[table_name = "plans"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewPlan {
+    pub name: String,
+    pub max_cameras: Option<i32>,
+    pub max_storage_gb: Option<i32>,
+    pub retention_days: Option<i32>,
+    pub max_streams: Option<i32>,
+}
+
+/// Sent with PUT /Admin/Users/<user_id>/Plan. None takes the user's plan away, leaving them unlimited.
+#[derive(Deserialize, JsonSchema)]
+pub struct PlanAssignment {
+    pub plan_id: Option<i32>,
+}
+
+/// Returned by GET /Account/Plan.
+#[derive(Serialize, JsonSchema)]
+pub struct PlanUsage {
+    /// None if the user doesn't have a plan, so has no limits.
+    pub plan: Option<Plan>,
+    pub cameras: usize,
+    /// Can be up to STORAGE_CACHE_SECONDS old.
+    pub storage_bytes: u64,
+    /// Across every server.
+    pub streams: i64,
+}
+
+pub fn get(plan_id: i32, connection: &PgConnection) -> QueryResult<Plan> {
+    plans::table.find(plan_id).get_result(connection)
+}
+
+/// The user's plan, or None if they don't have one.
+pub fn plan_for_user(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Option<Plan>> {
+    users::table
+        .inner_join(plans::table.on(users::plan_id.eq(plans::plan_id.nullable())))
+        .filter(users::user_id.eq(user_id))
+        .select(plans::all_columns)
+        .get_result(connection)
+        .optional()
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to check plan limits! The error was {}", error);
+    ApiError {
+        error: "Failed to check plan limits",
+        code: "check_plan_limits_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// How many bytes of images, audio clips and recordings the user's cameras have stored.
+fn count_storage(user_id: uuid::Uuid, connection: &PgConnection) -> Result<u64, ApiError> {
+    let camera_ids =
+        users_cameras::get_owned_camera_ids(user_id, connection).map_err(database_error)?;
+
+    let mut bytes = audio_clips::table
+        .filter(audio_clips::camera_id.eq_any(&camera_ids))
+        .select(sql::<BigInt>("COALESCE(SUM(size_bytes), 0)::BIGINT"))
+        .get_result::<i64>(connection)
+        .map_err(database_error)? as u64;
+
+    bytes += recordings::table
+        .filter(recordings::camera_id.eq_any(&camera_ids))
+        .select(sql::<BigInt>("COALESCE(SUM(size_bytes), 0)::BIGINT"))
+        .get_result::<i64>(connection)
+        .map_err(database_error)? as u64;
+
+    for camera_id in camera_ids {
+        bytes += media_store().storage_used(&camera_id).map_err(|error| {
+            error!(
+                "Failed to count storage used by camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to check plan limits",
+                code: "check_plan_limits_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+    }
+
+    Ok(bytes)
+}
+
+/// Like count_storage(), but cached for STORAGE_CACHE_SECONDS.
+pub fn storage_used(user_id: uuid::Uuid, connection: &PgConnection) -> Result<u64, ApiError> {
+    let key = cache::plan_storage_key(user_id);
+
+    if let Some(bytes) = cache().get(&key).and_then(|bytes| bytes.parse().ok()) {
+        return Ok(bytes);
+    }
+
+    let bytes = count_storage(user_id, connection)?;
+    cache().set(
+        &key,
+        &bytes.to_string(),
+        Duration::from_secs(STORAGE_CACHE_SECONDS),
+    );
+
+    Ok(bytes)
+}
+
+/// Stops the user registering another camera if they already own as many as their plan allows. Call it in the
+/// transaction that adds the camera: the user's row is locked until it ends, so two cameras registered at once
+/// can't both take the last place.
+pub fn check_camera_limit(user_id: uuid::Uuid, connection: &PgConnection) -> Result<(), ApiError> {
+    let max_cameras = match plan_for_user(user_id, connection).map_err(database_error)? {
+        Some(Plan {
+            max_cameras: Some(max_cameras),
+            ..
+        }) => max_cameras,
+        _ => return Ok(()),
+    };
+
+    users::table
+        .find(user_id)
+        .select(users::user_id)
+        .for_update()
+        .get_result::<uuid::Uuid>(connection)
+        .map_err(database_error)?;
+
+    let cameras = users_cameras::get_owned_camera_ids(user_id, connection)
+        .map_err(database_error)?
+        .len();
+
+    if cameras >= max_cameras as usize {
+        return Err(ApiError {
+            error: "Your plan's max_cameras limit has been reached",
+            code: "camera_limit_reached",
+            status: Status::PaymentRequired,
+            field: Some("max_cameras"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Stops a camera uploading once its owner has used all the storage their plan allows.
+pub fn check_storage(camera_id: uuid::Uuid, connection: &PgConnection) -> Result<(), ApiError> {
+    let owner_id = match users_cameras::get_owners(vec![camera_id], connection)
+        .map_err(database_error)?
+        .remove(&camera_id)
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(()),
+    };
+
+    let max_storage_gb = match plan_for_user(owner_id, connection).map_err(database_error)? {
+        Some(Plan {
+            max_storage_gb: Some(max_storage_gb),
+            ..
+        }) => max_storage_gb,
+        _ => return Ok(()),
+    };
+
+    if storage_used(owner_id, connection)? >= max_storage_gb as u64 * BYTES_PER_GB {
+        return Err(ApiError {
+            error: "The camera owner's plan's max_storage_gb limit has been reached",
+            code: "storage_limit_reached",
+            status: Status::PaymentRequired,
+            field: Some("max_storage_gb"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Starts counting a stream towards the user's max_streams, unless they already have as many open as their plan
+/// allows. Streams are counted in active_streams, so every server sees the others'. The user's streams are locked
+/// while they're counted, so two opened at once on different servers can't both take the last one.
+pub fn start_stream(
+    user_id: uuid::Uuid,
+    kind: &'static str,
+    connection: &PgConnection,
+) -> Result<usage::StreamUsage, ApiError> {
+    let usage = connection
+        .transaction::<_, diesel::result::Error, _>(|| {
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind::<Text, _>(format!("streams:{}", user_id))
+                .execute(connection)?;
+
+            if let Some(Plan {
+                max_streams: Some(max_streams),
+                ..
+            }) = plan_for_user(user_id, connection)?
+            {
+                if usage::active_streams(user_id, connection)? >= max_streams as i64 {
+                    return Ok(None);
+                }
+            }
+
+            usage::stream_started(user_id, kind, connection).map(Some)
+        })
+        .map_err(database_error)?;
+
+    usage.ok_or(ApiError {
+        error: "Your plan's max_streams limit has been reached",
+        code: "stream_limit_reached",
+        status: Status::PaymentRequired,
+        field: Some("max_streams"),
+    })
+}
+
+/// Deletes the camera's images, audio clips and recordings from before `cutoff`, apart from any under a hold, see
+/// media_hold. Returns how many were deleted.
+fn delete_footage_before(
+    camera_id: uuid::Uuid,
+    cutoff: DateTime<Utc>,
+    connection: &PgConnection,
+) -> io::Result<usize> {
+    let to_io_error = |error| io::Error::new(io::ErrorKind::Other, error);
+    let holds = media_hold::get_cameras_holds(camera_id, connection).map_err(to_io_error)?;
+    let store = media_store();
+    let mut deleted = 0;
+
+    // Image IDs are when they were taken, in seconds since the epoch
+    for image_id in store.list_images(&camera_id)? {
+        let taken_at = Utc.timestamp(image_id as i64, 0);
+        if taken_at < cutoff
+            && !media_hold::is_held(&holds, storage::IMAGE, image_id as i64, taken_at, taken_at)
+        {
+            store.delete_image(&camera_id, image_id)?;
+            deleted += 1;
+        }
+    }
+
+    let audio_ids = audio_clips::table
+        .filter(audio_clips::camera_id.eq(camera_id))
+        .filter(audio_clips::recorded_at.lt(cutoff))
+        .select((audio_clips::audio_id, audio_clips::recorded_at))
+        .load::<(i32, DateTime<Utc>)>(connection)
+        .map_err(to_io_error)?
+        .into_iter()
+        .filter(|(audio_id, recorded_at)| {
+            !media_hold::is_held(
+                &holds,
+                storage::AUDIO,
+                *audio_id as i64,
+                *recorded_at,
+                *recorded_at,
+            )
+        })
+        .map(|(audio_id, _)| audio_id)
+        .collect::<Vec<i32>>();
+    diesel::delete(audio_clips::table.filter(audio_clips::audio_id.eq_any(&audio_ids)))
+        .execute(connection)
+        .map_err(to_io_error)?;
+
+    for audio_id in &audio_ids {
+        match fs::remove_file(audio::audio_path(&camera_id, *audio_id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    deleted += audio_ids.len() + recording::delete_before(camera_id, cutoff, &holds, connection)?;
+
+    if deleted > 0 {
+        storage::recount_later(camera_id);
+    }
+
+    Ok(deleted)
+}
+
+/// Deletes footage older than retention_days from the cameras of every user whose plan sets it.
+pub fn apply_retention(connection: &PgConnection) -> QueryResult<usize> {
+    let users_plans = users::table
+        .inner_join(plans::table.on(users::plan_id.eq(plans::plan_id.nullable())))
+        .filter(users::deleted_at.is_null())
+        .filter(plans::retention_days.is_not_null())
+        .select((users::user_id, plans::retention_days))
+        .load::<(uuid::Uuid, Option<i32>)>(connection)?;
+
+    let mut deleted = 0;
+
+    for (user_id, retention_days) in users_plans {
+        let cutoff = Utc::now() - ChronoDuration::days(retention_days.unwrap_or_default() as i64);
+
+        for camera_id in users_cameras::get_owned_camera_ids(user_id, connection)? {
+            match delete_footage_before(camera_id, cutoff, connection) {
+                Ok(camera_deleted) => deleted += camera_deleted,
+                Err(error) => error!(
+                    "Failed to delete expired footage from camera {}! The error was {}",
+                    camera_id, error
+                ),
+            }
+        }
+
+        cache().delete(&cache::plan_storage_key(user_id));
+    }
+
+    Ok(deleted)
+}
+
+/// Starts the thread that deletes footage once it's older than its owner's plan allows.
+pub fn spawn_retention_worker(database_url: String) {
+    worker::spawn_worker(
+        "Plan retention",
+        Duration::from_secs(60 * 60),
+        database_url,
+        |connection| match apply_retention(connection) {
+            Ok(0) => {}
+            Ok(deleted) => info!(
+                "Deleted {} images, audio clips and recordings past retention",
+                deleted
+            ),
+            Err(error) => error!("Failed to apply plan retention! The error was {}", error),
+        },
+    );
+}
+
+fn plan_error(error: diesel::result::Error) -> ApiError {
+    match error {
+        // The only unique column is the name
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        ) => ApiError {
+            error: "A plan with that name already exists",
+            code: "plan_name_taken",
+            status: Status::Conflict,
+            field: Some("name"),
+        },
+        _ => not_found_or_database_error(
+            error,
+            ("plan_not_found", "Plan not found"),
+            ("save_plan_failed", "Failed to save plan"),
+        ),
+    }
+}
+
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Plans")]
+pub fn get_plans(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<Plan>>, ApiError> {
+    plans::table
+        .order(plans::plan_id)
+        .load::<Plan>(&*conn)
+        .map(Json)
+        .map_err(|error| {
+            error!("Failed to get plans! The error was {}", error);
+            ApiError {
+                error: "Failed to get plans",
+                code: "get_plans_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Plans", format = "json", data = "<new_plan>")]
+pub fn create_plan(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    new_plan: Json<NewPlan>,
+) -> Result<Json<Plan>, ApiError> {
+    let plan = diesel::insert_into(plans::table)
+        .values(new_plan.into_inner())
+        .get_result::<Plan>(&*conn)
+        .map_err(plan_error)?;
+    audit::record_after(&plan);
+
+    Ok(Json(plan))
+}
+
+/// Replaces the plan's name and limits. Users on it get the new limits straight away. Only for users in
+/// ADMIN_USER_IDS.
+#[openapi]
+#[put("/Admin/Plans/<plan_id>", format = "json", data = "<new_plan>")]
+pub fn update_plan(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    plan_id: i32,
+    new_plan: Json<NewPlan>,
+) -> Result<Json<Plan>, ApiError> {
+    if let Ok(before) = get(plan_id, &conn) {
+        audit::record_before(&before);
+    }
+
+    let plan = diesel::update(plans::table.find(plan_id))
+        .set(new_plan.into_inner())
+        .get_result::<Plan>(&*conn)
+        .map_err(plan_error)?;
+    audit::record_after(&plan);
+
+    Ok(Json(plan))
+}
+
+/// Deletes the plan, leaving anyone who was on it without one. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Plans/<plan_id>")]
+pub fn delete_plan(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    plan_id: i32,
+) -> Result<(), ApiError> {
+    if let Ok(before) = get(plan_id, &conn) {
+        audit::record_before(&before);
+    }
+
+    match diesel::delete(plans::table.find(plan_id)).execute(&*conn) {
+        Ok(0) => Err(ApiError {
+            error: "Plan not found",
+            code: "plan_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        Ok(_) => Ok(()),
+        Err(error) => Err(plan_error(error)),
+    }
+}
+
+/// Puts the user on a plan, or takes them off theirs. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[put("/Admin/Users/<user_id>/Plan", format = "json", data = "<assignment>")]
+pub fn assign_plan(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+    assignment: Json<PlanAssignment>,
+) -> Result<Json<Option<Plan>>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+    let plan_id = assignment.into_inner().plan_id;
+
+    let plan = match plan_id {
+        Some(plan_id) => Some(get(plan_id, &conn).map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Plan not found",
+                code: "plan_not_found",
+                status: Status::UnprocessableEntity,
+                field: Some("plan_id"),
+            },
+            _ => plan_error(error),
+        })?),
+        None => None,
+    };
+
+    let before = user::get(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("assign_plan_failed", "Failed to assign plan"),
+        )
+    })?;
+    audit::record_before(&json!({ "plan_id": before.plan_id }));
+
+    diesel::update(users::table.find(user_id))
+        .set(users::plan_id.eq(plan_id))
+        .get_result::<User>(&*conn)
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("assign_plan_failed", "Failed to assign plan"),
+            )
+        })?;
+    audit::record_after(&json!({ "plan_id": plan_id }));
+
+    Ok(Json(plan))
+}
+
+/// The user's plan and how much of it they're using.
+#[openapi]
+#[get("/Account/Plan")]
+pub fn get_account_plan(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<PlanUsage>, ApiError> {
+    Ok(Json(PlanUsage {
+        plan: plan_for_user(user_token.user_id, &conn).map_err(database_error)?,
+        cameras: users_cameras::get_owned_camera_ids(user_token.user_id, &conn)
+            .map_err(database_error)?
+            .len(),
+        storage_bytes: storage_used(user_token.user_id, &conn)?,
+        streams: usage::active_streams(user_token.user_id, &conn).map_err(database_error)?,
+    }))
+}
@@ -0,0 +1,92 @@
+use crate::{
+    api_error::ApiError,
+    zone::{self, Point, Zone, PRIVACY_ZONE},
+};
+
+use diesel::pg::PgConnection;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, ImageFormat, Rgb};
+use rocket::http::Status;
+use std::io::Read;
+
+const JPEG_QUALITY: u8 = 90;
+
+/// The image with the camera's privacy zones blacked out, re-encoded as a JPEG. None if the camera has no privacy
+/// zones, in which case the image hasn't been read and should be stored as it is.
+///
+/// Everything served from stored images, like the RTSP and ONVIF streams, is masked by this. RTMP and SRT
+/// recordings are stored as they arrive, since nothing here decodes video, and images replicated or imported straight
+/// into the media store are assumed to have been masked where they came from.
+pub fn mask_image(
+    camera_id: uuid::Uuid,
+    image: &mut dyn Read,
+    connection: &PgConnection,
+) -> Result<Option<Vec<u8>>, ApiError> {
+    let privacy_zones: Vec<Zone> = zone::load_zones(camera_id, connection)?
+        .into_iter()
+        .filter(|zone| zone.kind == PRIVACY_ZONE)
+        .collect();
+    if privacy_zones.is_empty() {
+        return Ok(None);
+    }
+
+    let mut bytes = Vec::new();
+    image.read_to_end(&mut bytes).map_err(|error| {
+        error!(
+            "Failed to read image from camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to read image",
+            code: "read_image_failed",
+            status: Status::BadRequest,
+            field: None,
+        }
+    })?;
+
+    // Images that can't be decoded are refused, rather than letting them through unmasked
+    let mut decoded = image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)
+        .map_err(|error| {
+            warn!(
+                "Failed to decode image from camera {} to mask it! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Images from cameras with privacy zones must be JPEGs",
+                code: "privacy_zones_need_jpeg",
+                status: Status::UnprocessableEntity,
+                field: None,
+            }
+        })?
+        .to_rgb8();
+
+    let (width, height) = decoded.dimensions();
+    for (x, y, pixel) in decoded.enumerate_pixels_mut() {
+        // Pixels are masked if their centre is inside a zone
+        let centre = Point {
+            x: (x as f32 + 0.5) / width as f32,
+            y: (y as f32 + 0.5) / height as f32,
+        };
+        if privacy_zones.iter().any(|zone| zone.contains(&centre)) {
+            *pixel = Rgb([0, 0, 0]);
+        }
+    }
+
+    let mut masked = Vec::new();
+    JpegEncoder::new_with_quality(&mut masked, JPEG_QUALITY)
+        .encode(&decoded, width, height, ColorType::Rgb8)
+        .map_err(|error| {
+            error!(
+                "Failed to encode masked image from camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to mask image",
+                code: "mask_image_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+    Ok(Some(masked))
+}
@@ -0,0 +1,415 @@
+use crate::{api_error::ApiError, settings::settings, user_tokens::UserToken, CameraServerDbConn};
+
+use super::schema::push_tokens;
+use diesel::prelude::*;
+use diesel::{self};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use rocket::http::Status;
+use rocket::{delete, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const ANDROID_PLATFORM: &str = "android";
+pub const IOS_PLATFORM: &str = "ios";
+
+/// Apple rejects provider tokens older than an hour, and doesn't like them being refreshed more than every 20 minutes.
+const APNS_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "push_tokens"]
+pub struct PushToken {
+    pub push_token_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub platform: String,
+    pub token: String,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "push_tokens"]
+pub struct InsertablePushToken {
+    pub user_id: uuid::Uuid,
+    pub platform: String,
+    pub token: String,
+}
+
+/// What the mobile app sends when registering for notifications.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewPushToken {
+    pub platform: String,
+    pub token: String,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<PushToken>> {
+    push_tokens::table.load::<PushToken>(&*connection)
+}
+
+pub fn get(push_token_id: i32, connection: &PgConnection) -> QueryResult<PushToken> {
+    push_tokens::table
+        .find(push_token_id)
+        .get_result::<PushToken>(connection)
+}
+
+/// Inserts a push token. Device tokens are unique, so if another user already registered this token
+/// (e.g. someone else logged into the same phone), the token is moved to the new user.
+pub fn insert(
+    push_token: InsertablePushToken,
+    connection: &PgConnection,
+) -> QueryResult<PushToken> {
+    diesel::insert_into(push_tokens::table)
+        .values(&push_token)
+        .on_conflict(push_tokens::token)
+        .do_update()
+        .set((
+            push_tokens::user_id.eq(push_token.user_id),
+            push_tokens::platform.eq(&push_token.platform),
+        ))
+        .get_result(connection)
+}
+
+pub fn update(
+    push_token_id: i32,
+    push_token: PushToken,
+    connection: &PgConnection,
+) -> QueryResult<PushToken> {
+    diesel::update(push_tokens::table.find(push_token_id))
+        .set(&push_token)
+        .get_result(connection)
+}
+
+pub fn delete(push_token_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(push_tokens::table.find(push_token_id)).execute(connection)
+}
+
+pub fn get_users_push_tokens(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<PushToken>> {
+    push_tokens::table
+        .filter(push_tokens::user_id.eq(user_id))
+        .load::<PushToken>(connection)
+}
+
+pub struct ApnsConfig {
+    pub key: EncodingKey,
+    pub key_id: String,
+    pub team_id: String,
+    /// The app's bundle ID.
+    pub topic: String,
+    pub sandbox: bool,
+}
+
+#[derive(Serialize)]
+struct ApnsClaims {
+    iss: String,
+    iat: u64,
+}
+
+/// Why a push to a single device failed.
+pub enum PushError {
+    /// The device token isn't valid anymore (app uninstalled, etc). The token should be removed.
+    Unregistered,
+    Other(String),
+}
+
+/// Sends push notifications through FCM (Android) and APNs (iOS).
+/// Platforms that haven't been configured are skipped.
+pub struct PushSender {
+    pub client: reqwest::blocking::Client,
+    pub fcm_server_key: Option<String>,
+    pub apns: Option<ApnsConfig>,
+    apns_token: Mutex<Option<(Instant, String)>>,
+}
+
+impl PushSender {
+    /// Reads [push], where fcm_server_key is for Android and the apns_ settings are for iOS. The settings make sure
+    /// the apns_ ones are set together.
+    pub fn from_env() -> PushSender {
+        let push = &settings().push;
+        let apns = push.apns_key_path.as_ref().map(|key_path| ApnsConfig {
+            key: EncodingKey::from_ec_pem(
+                &fs::read(key_path).expect("Failed to read apns_key_path in [push]!"),
+            )
+            .expect("apns_key_path in [push] is not a valid .p8 key!"),
+            key_id: push.apns_key_id.clone().unwrap_or_default(),
+            team_id: push.apns_team_id.clone().unwrap_or_default(),
+            topic: push.apns_topic.clone().unwrap_or_default(),
+            sandbox: push.apns_sandbox,
+        });
+
+        PushSender {
+            client: reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .expect("Failed to build push HTTP client!"),
+            fcm_server_key: push.fcm_server_key.clone(),
+            apns,
+            apns_token: Mutex::new(None),
+        }
+    }
+
+    fn send_fcm(
+        &self,
+        server_key: &str,
+        token: &str,
+        title: &str,
+        body: &str,
+        collapse_id: &str,
+    ) -> Result<(), PushError> {
+        let response = self
+            .client
+            .post("https://fcm.googleapis.com/fcm/send")
+            .header("Authorization", format!("key={}", server_key))
+            .json(&serde_json::json!({
+                "to": token,
+                "collapse_key": collapse_id,
+                "notification": { "title": title, "body": body, "tag": collapse_id },
+            }))
+            .send()
+            .map_err(|error| PushError::Other(error.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(PushError::Other(format!(
+                "FCM responded with {}",
+                response.status()
+            )));
+        }
+
+        // FCM responds with a 200 even if the token is bad, the actual result is in the body
+        let result: serde_json::Value = response
+            .json()
+            .map_err(|error| PushError::Other(error.to_string()))?;
+
+        match result["results"][0]["error"].as_str() {
+            None => Ok(()),
+            Some("NotRegistered") | Some("InvalidRegistration") => Err(PushError::Unregistered),
+            Some(error) => Err(PushError::Other(format!("FCM returned {}", error))),
+        }
+    }
+
+    /// Returns a provider token for APNs, reusing the last one until it gets old.
+    fn apns_token(&self, apns: &ApnsConfig) -> Result<String, PushError> {
+        let mut cached_token = self
+            .apns_token
+            .lock()
+            .expect("APNs token lock was poisoned!");
+
+        if let Some((created, token)) = &*cached_token {
+            if created.elapsed() < APNS_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(apns.key_id.clone());
+
+        let token = encode(
+            &header,
+            &ApnsClaims {
+                iss: apns.team_id.clone(),
+                iat: SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("Failed to get current time somehow?")
+                    .as_secs(),
+            },
+            &apns.key,
+        )
+        .map_err(|error| PushError::Other(error.to_string()))?;
+
+        *cached_token = Some((Instant::now(), token.clone()));
+
+        Ok(token)
+    }
+
+    fn send_apns(
+        &self,
+        apns: &ApnsConfig,
+        token: &str,
+        title: &str,
+        body: &str,
+        collapse_id: &str,
+    ) -> Result<(), PushError> {
+        let host = if apns.sandbox {
+            "api.sandbox.push.apple.com"
+        } else {
+            "api.push.apple.com"
+        };
+
+        let response = self
+            .client
+            .post(&format!("https://{}/3/device/{}", host, token))
+            .header(
+                "authorization",
+                format!("bearer {}", self.apns_token(apns)?),
+            )
+            .header("apns-topic", apns.topic.as_str())
+            .header("apns-push-type", "alert")
+            .header("apns-collapse-id", collapse_id)
+            .json(&serde_json::json!({
+                "aps": { "alert": { "title": title, "body": body } },
+            }))
+            .send()
+            .map_err(|error| PushError::Other(error.to_string()))?;
+
+        match response.status().as_u16() {
+            200 => Ok(()),
+            410 => Err(PushError::Unregistered),
+            _ => Err(PushError::Other(format!(
+                "APNs responded with {}",
+                response.status()
+            ))),
+        }
+    }
+
+    /// Pushes a notification to one device. A later notification with the same collapse ID replaces this one on the device
+    /// instead of showing up separately.
+    pub fn send(
+        &self,
+        push_token: &PushToken,
+        title: &str,
+        body: &str,
+        collapse_id: &str,
+    ) -> Result<(), PushError> {
+        match (
+            push_token.platform.as_str(),
+            &self.fcm_server_key,
+            &self.apns,
+        ) {
+            (ANDROID_PLATFORM, Some(server_key), _) => {
+                self.send_fcm(server_key, &push_token.token, title, body, collapse_id)
+            }
+            (IOS_PLATFORM, _, Some(apns)) => {
+                self.send_apns(apns, &push_token.token, title, body, collapse_id)
+            }
+            (platform, _, _) => Err(PushError::Other(format!(
+                "Push notifications for {} aren't configured",
+                platform
+            ))),
+        }
+    }
+
+    /// Sends a notification to every device the user has registered. Tokens the push service says are dead are removed.
+    /// Succeeds if the notification reached at least one device (or the user has no devices, since retrying won't help).
+    pub fn send_to_user(
+        &self,
+        user_id: uuid::Uuid,
+        title: &str,
+        body: &str,
+        collapse_id: &str,
+        connection: &PgConnection,
+    ) -> Result<(), String> {
+        let push_tokens = get_users_push_tokens(user_id, connection)
+            .map_err(|error| format!("Failed to get push tokens: {}", error))?;
+
+        if push_tokens.len() == 0 {
+            return Ok(());
+        }
+
+        let mut errors = Vec::new();
+
+        for push_token in &push_tokens {
+            match self.send(push_token, title, body, collapse_id) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Unregistered) => {
+                    if let Err(error) = delete(push_token.push_token_id, connection) {
+                        error!(
+                            "Failed to delete unregistered push token {}! The error was {}",
+                            push_token.push_token_id, error
+                        );
+                    }
+                }
+                Err(PushError::Other(error)) => errors.push(error),
+            }
+        }
+
+        if errors.len() == 0 {
+            Ok(())
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+/// Registers the phone the app is running on for push notifications.
+#[openapi]
+#[post("/PushTokens", format = "json", data = "<new_push_token>")]
+pub fn add_push_token(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_push_token: Json<NewPushToken>,
+) -> Result<Json<PushToken>, ApiError> {
+    let new_push_token = new_push_token.into_inner();
+
+    if new_push_token.platform != ANDROID_PLATFORM && new_push_token.platform != IOS_PLATFORM {
+        return Err(ApiError {
+            error: "Platform must be android or ios",
+            code: "invalid_platform",
+            status: Status::UnprocessableEntity,
+            field: Some("platform"),
+        });
+    }
+
+    insert(
+        InsertablePushToken {
+            user_id: user_token.user_id,
+            platform: new_push_token.platform,
+            token: new_push_token.token,
+        },
+        &conn,
+    )
+    .map(|push_token| Json(push_token))
+    .map_err(|error| {
+        error!(
+            "Failed to add push token for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to add push token",
+            code: "add_push_token_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Unregisters a phone, e.g. when the user logs out of the app.
+#[openapi]
+#[delete("/PushTokens/<token>")]
+pub fn delete_push_token(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    token: String,
+) -> Result<(), ApiError> {
+    diesel::delete(
+        push_tokens::table
+            .filter(push_tokens::token.eq(&token))
+            .filter(push_tokens::user_id.eq(user_token.user_id)),
+    )
+    .execute(&*conn)
+    .map_err(|error| {
+        error!(
+            "Failed to delete push token for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to delete push token",
+            code: "delete_push_token_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+    .and_then(|deleted| match deleted {
+        0 => Err(ApiError {
+            error: "Push token not found",
+            code: "push_token_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        _ => Ok(()),
+    })
+}
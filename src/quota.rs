@@ -0,0 +1,190 @@
+use crate::{
+    cache::{self, cache},
+    custody::sha256_hex,
+    rate_limit::{self, Client},
+    settings::settings,
+    usage,
+};
+
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{get, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::Duration;
+
+/// User tokens, which are always for the user themselves.
+pub const OWNER_SCOPE: &str = "owner";
+pub const CAMERA_SCOPE: &str = "camera";
+
+pub const REQUESTS_QUOTA: &str = "requests";
+pub const BANDWIDTH_QUOTA: &str = "bandwidth";
+
+/// Counts are kept a little past their day, as each key has the day in it anyway.
+const COUNT_TTL_SECONDS: u64 = 2 * 24 * 60 * 60;
+
+/// How much a token can use per day, from [limits]. None means there's no quota.
+pub struct QuotaLimits {
+    pub requests: Option<u64>,
+    pub bytes: Option<u64>,
+}
+
+pub fn limits(scope: &str) -> QuotaLimits {
+    let limits = &settings().limits;
+    let (requests, megabytes) = if scope == OWNER_SCOPE {
+        (limits.owner_daily_requests, limits.owner_daily_megabytes)
+    } else {
+        (limits.camera_daily_requests, limits.camera_daily_megabytes)
+    };
+
+    QuotaLimits {
+        requests: Some(requests).filter(|requests| *requests > 0),
+        bytes: Some(megabytes)
+            .filter(|megabytes| *megabytes > 0)
+            .map(|megabytes| megabytes * 1024 * 1024),
+    }
+}
+
+/// Where a token stands today (UTC). Also sent as X-Quota-* headers on every response made with a token.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct QuotaStatus {
+    /// owner or camera.
+    pub scope: String,
+    /// None if there's no daily request quota.
+    pub requests_limit: Option<u64>,
+    pub requests_used: u64,
+    /// None if there's no daily bandwidth quota.
+    pub bytes_limit: Option<u64>,
+    /// Bytes of request and response bodies, before compression.
+    pub bytes_used: u64,
+    /// When the quotas reset, at midnight UTC, in seconds since the epoch.
+    pub reset: i64,
+    /// Which quota the request went over, requests or bandwidth.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exceeded: Option<String>,
+}
+
+/// The user or camera token a request was made with, once it's been found, see rate_limit::client(). Requests
+/// without one, or with a token that isn't real, only have the rate limit.
+pub struct QuotaToken {
+    pub scope: &'static str,
+    /// The token's SHA-256, so tokens aren't kept in the cache or token_usage_daily.
+    pub token_hash: String,
+    /// Who the token is for, the user for owner tokens and the camera for camera tokens.
+    pub user_id: Option<uuid::Uuid>,
+    pub camera_id: Option<uuid::Uuid>,
+    day: NaiveDate,
+}
+
+pub fn quota_token(request: &Request) -> Option<QuotaToken> {
+    let (scope, token, user_id, camera_id) = match rate_limit::client(request) {
+        Client::User {
+            user_token,
+            user_id,
+        } => (OWNER_SCOPE, user_token, Some(*user_id), None),
+        Client::Camera {
+            camera_token,
+            camera_id,
+        } => (CAMERA_SCOPE, camera_token, None, Some(*camera_id)),
+        Client::Address(_) => return None,
+    };
+
+    Some(QuotaToken {
+        scope,
+        token_hash: sha256_hex(token.to_string().as_bytes()),
+        user_id,
+        camera_id,
+        day: Utc::now().naive_utc().date(),
+    })
+}
+
+impl QuotaToken {
+    /// Counts a request made with the token, and returns where it now stands. Requests over the request quota and
+    /// requests made once the bandwidth quota is used up are marked as exceeded. Bytes are counted once the response
+    /// is known, see record_response(), so the request that goes over the bandwidth quota is still let through.
+    /// If the cache fails, requests are let through.
+    pub fn record_request(&self) -> QuotaStatus {
+        let limits = limits(self.scope);
+        let ttl = Duration::from_secs(COUNT_TTL_SECONDS);
+
+        let requests_used = cache()
+            .increment(
+                &cache::quota_requests_key(self.scope, &self.token_hash, self.day),
+                ttl,
+            )
+            .unwrap_or(0);
+        let bytes_used = match limits.bytes {
+            Some(_) => cache()
+                .get(&cache::quota_bytes_key(
+                    self.scope,
+                    &self.token_hash,
+                    self.day,
+                ))
+                .and_then(|bytes| bytes.parse().ok())
+                .unwrap_or(0),
+            None => 0,
+        };
+
+        let exceeded = if limits.requests.map_or(false, |limit| requests_used > limit) {
+            Some(REQUESTS_QUOTA)
+        } else if limits.bytes.map_or(false, |limit| bytes_used >= limit) {
+            Some(BANDWIDTH_QUOTA)
+        } else {
+            None
+        };
+
+        QuotaStatus {
+            scope: self.scope.to_string(),
+            requests_limit: limits.requests,
+            requests_used,
+            bytes_limit: limits.bytes,
+            bytes_used,
+            reset: (self.day + ChronoDuration::days(1))
+                .and_hms(0, 0, 0)
+                .timestamp(),
+            exceeded: exceeded.map(String::from),
+        }
+    }
+
+    /// Counts the bytes a request sent and was sent towards the token's bandwidth quota, and the request towards its
+    /// row in token_usage_daily.
+    pub fn record_response(&self, bytes: u64) {
+        usage::record_token(self, bytes);
+
+        if limits(self.scope).bytes.is_some() && bytes > 0 {
+            cache().increment_by(
+                &cache::quota_bytes_key(self.scope, &self.token_hash, self.day),
+                bytes,
+                Duration::from_secs(COUNT_TTL_SECONDS),
+            );
+        }
+    }
+}
+
+/// A request's token and where it stood when it was counted. Kept in the request's local cache by the rate limiter.
+pub struct QuotaUsage {
+    pub token: QuotaToken,
+    pub status: QuotaStatus,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for QuotaStatus {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.local_cache(|| None::<QuotaUsage>) {
+            Some(usage) => Outcome::Success(usage.status.clone()),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Returns how much of today's quotas the caller's token has used, so clients can slow down before they run out.
+/// Checking counts as a request. Quotas are set per kind of token in [limits].
+#[openapi]
+#[get("/Quota")]
+pub fn get_quota(status: QuotaStatus) -> Json<QuotaStatus> {
+    Json(status)
+}
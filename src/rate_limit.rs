@@ -0,0 +1,320 @@
+use crate::{
+    api_error::{error_code, ErrorBody},
+    api_version::API_PREFIX,
+    cache::{self, cache},
+    camera_tokens,
+    quota::{self, QuotaStatus, QuotaUsage, BANDWIDTH_QUOTA},
+    replication, request_id,
+    settings::settings,
+    user_tokens,
+};
+
+use chrono::Utc;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Outcome, Request, Response};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// How long each rate limit window is. Counts start again from 0 at the start of every window.
+pub const WINDOW_SECONDS: i64 = 60;
+
+/// Rate limited requests are routed here instead. Nothing is mounted at it, so no handler runs for them.
+const RATE_LIMITED_PATH: &str = "/RateLimited";
+
+/// How many requests each client can make per minute, set with rate_limit_per_minute in [limits]. Defaults to 300.
+pub fn requests_per_window() -> u32 {
+    settings().limits.rate_limit_per_minute
+}
+
+/// Where the client stands in the current window. Also sent as X-RateLimit-* headers on every response.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// When the window resets, in seconds since the epoch.
+    pub reset: i64,
+    #[serde(skip)]
+    pub limited: bool,
+}
+
+/// Limits each client to requests_per_window() requests per minute. Clients are told apart by the user or camera
+/// their token is for, or by IP address if they don't send a real one, see client(). Requests made with a token also
+/// count towards its daily quotas, see quota.rs.
+/// Counts are kept in the cache, so with Redis every instance shares them. If the cache fails, requests are let through.
+pub struct RateLimiter {
+    pub limit: u32,
+}
+
+impl RateLimiter {
+    pub fn from_env() -> RateLimiter {
+        RateLimiter {
+            limit: requests_per_window(),
+        }
+    }
+
+    /// Counts a request from the client, and returns where it now stands.
+    pub fn record(&self, client: String) -> RateLimitStatus {
+        let now = Utc::now().timestamp();
+        let window_start = now - now % WINDOW_SECONDS;
+
+        let count = cache()
+            .increment(
+                &cache::rate_limit_key(&client, window_start),
+                Duration::from_secs(WINDOW_SECONDS as u64),
+            )
+            .unwrap_or(0)
+            .min(u32::MAX as u64) as u32;
+
+        RateLimitStatus {
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(count),
+            reset: window_start + WINDOW_SECONDS,
+            limited: count > self.limit,
+        }
+    }
+}
+
+/// What a request over one of its token's daily quotas gets back, with where the token stands.
+#[derive(Serialize)]
+struct QuotaErrorBody {
+    #[serde(flatten)]
+    error: ErrorBody,
+    quota: QuotaStatus,
+}
+
+/// Who a request is from. Tokens only count once they've been found, so a client can't get a new bucket by making
+/// one up, and requests with a token that isn't real are counted by address.
+#[derive(Clone)]
+pub enum Client {
+    User {
+        user_token: uuid::Uuid,
+        user_id: uuid::Uuid,
+    },
+    Camera {
+        camera_token: uuid::Uuid,
+        camera_id: uuid::Uuid,
+    },
+    Address(Option<IpAddr>),
+}
+
+/// The address the request came from. X-Real-IP is only believed from trusted_proxies in [server], as anyone can
+/// send it.
+pub fn client_address(request: &Request) -> Option<IpAddr> {
+    let peer = request.remote().map(|remote| remote.ip())?;
+
+    if settings()
+        .server
+        .trusted_proxies()
+        .any(|proxy| proxy == Ok(peer))
+    {
+        request.real_ip().or(Some(peer))
+    } else {
+        Some(peer)
+    }
+}
+
+fn find_client(request: &Request) -> Client {
+    let headers = request.headers();
+    let parse = |name| {
+        headers
+            .get_one(name)
+            .and_then(|token| uuid::Uuid::parse_str(token).ok())
+    };
+
+    if let Some(user_token) = parse("user_token") {
+        if let Ok((user_id, _)) = user_tokens::lookup(user_token, request) {
+            return Client::User {
+                user_token,
+                user_id,
+            };
+        }
+    } else if let Some(camera_token) = parse("camera_token") {
+        if let Ok(camera_id) = camera_tokens::lookup(camera_token, request) {
+            return Client::Camera {
+                camera_token,
+                camera_id,
+            };
+        }
+    }
+
+    Client::Address(client_address(request))
+}
+
+/// Who the request is from, looked up once per request.
+pub fn client(request: &Request) -> &Client {
+    request.local_cache(|| find_client(request))
+}
+
+/// Tells clients apart by their user or camera, falling back to their IP address.
+pub fn client_key(request: &Request) -> String {
+    match client(request) {
+        Client::User { user_id, .. } => format!("user:{}", user_id),
+        Client::Camera { camera_id, .. } => format!("camera:{}", camera_id),
+        Client::Address(Some(ip)) => format!("ip:{}", ip),
+        Client::Address(None) => String::from("ip:unknown"),
+    }
+}
+
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // A primary catching up makes far more requests than any client would
+        if replication::has_replication_token(request) {
+            return;
+        }
+
+        let status = self.record(client_key(request));
+        let quota = quota::quota_token(request).map(|token| QuotaUsage {
+            status: token.record_request(),
+            token,
+        });
+
+        if status.limited
+            || quota
+                .as_ref()
+                .map_or(false, |quota| quota.status.exceeded.is_some())
+        {
+            match Origin::parse_owned(format!("{}{}", API_PREFIX, RATE_LIMITED_PATH)) {
+                Ok(origin) => request.set_uri(origin),
+                Err(error) => error!(
+                    "Failed to reroute rate limited request! The error was {}",
+                    error
+                ),
+            }
+        }
+
+        request.local_cache(|| Some(status));
+        request.local_cache(|| quota);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let status = match request.local_cache(|| None::<RateLimitStatus>) {
+            Some(status) => status,
+            None => return,
+        };
+        let quota = request.local_cache(|| None::<QuotaUsage>);
+
+        response.set_header(Header::new("X-RateLimit-Limit", status.limit.to_string()));
+        response.set_header(Header::new(
+            "X-RateLimit-Remaining",
+            status.remaining.to_string(),
+        ));
+        response.set_header(Header::new("X-RateLimit-Reset", status.reset.to_string()));
+
+        if status.limited {
+            let body = ErrorBody {
+                code: error_code(Status::TooManyRequests),
+                message: "Too many requests, try again after X-RateLimit-Reset",
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request);
+
+            response.set_status(Status::TooManyRequests);
+            response.set_header(ContentType::JSON);
+            response.set_header(Header::new(
+                "Retry-After",
+                (status.reset - Utc::now().timestamp()).max(0).to_string(),
+            ));
+            response.set_sized_body(Cursor::new(
+                serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+            ));
+            return;
+        }
+
+        let quota = match quota {
+            Some(quota) => quota,
+            None => return,
+        };
+
+        if let Some(requests_limit) = quota.status.requests_limit {
+            response.set_header(Header::new(
+                "X-Quota-Requests-Remaining",
+                requests_limit
+                    .saturating_sub(quota.status.requests_used)
+                    .to_string(),
+            ));
+        }
+        if let Some(bytes_limit) = quota.status.bytes_limit {
+            response.set_header(Header::new(
+                "X-Quota-Bytes-Remaining",
+                bytes_limit
+                    .saturating_sub(quota.status.bytes_used)
+                    .to_string(),
+            ));
+        }
+        response.set_header(Header::new("X-Quota-Reset", quota.status.reset.to_string()));
+
+        if let Some(exceeded) = &quota.status.exceeded {
+            let body = QuotaErrorBody {
+                error: ErrorBody {
+                    code: "quota_exceeded",
+                    message: if exceeded == BANDWIDTH_QUOTA {
+                        "Daily bandwidth quota used up, try again after X-Quota-Reset"
+                    } else {
+                        "Daily request quota used up, try again after X-Quota-Reset"
+                    },
+                    details: Vec::new(),
+                    request_id: request_id::current(),
+                }
+                .localized(request),
+                quota: quota.status.clone(),
+            };
+
+            response.set_status(Status::TooManyRequests);
+            response.set_header(ContentType::JSON);
+            response.set_header(Header::new(
+                "Retry-After",
+                (quota.status.reset - Utc::now().timestamp())
+                    .max(0)
+                    .to_string(),
+            ));
+            response.set_sized_body(Cursor::new(
+                serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+            ));
+            return;
+        }
+
+        // Like bandwidth::BandwidthMetering, streamed responses aren't counted as their size isn't known yet
+        let upload_bytes = request
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok())
+            .unwrap_or(0);
+        let download_bytes = response.body().and_then(|body| body.size()).unwrap_or(0);
+        quota.token.record_response(upload_bytes + download_bytes);
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RateLimitStatus {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.local_cache(|| None::<RateLimitStatus>) {
+            Some(status) => Outcome::Success(status.clone()),
+            None => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// Returns the caller's rate limit, so clients can slow down before they hit it.
+/// Checking counts as a request.
+#[openapi]
+#[get("/RateLimit")]
+pub fn get_rate_limit(status: RateLimitStatus) -> Json<RateLimitStatus> {
+    Json(status)
+}
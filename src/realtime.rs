@@ -0,0 +1,940 @@
+use crate::{
+    announcement::Announcement,
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::record_camera_contact,
+    camera_tokens, cluster,
+    enums::token_error::TokenRejection,
+    event::{users_events_query, Event, EventFilter},
+    feature_flags,
+    page::MAX_PAGE_SIZE,
+    plan,
+    settings::settings,
+    talk, tenant, tls, usage, user_tokens,
+    users_cameras::get_cameras_users,
+};
+
+use super::schema::events;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error as WebSocketError, Message, WebSocket};
+
+/// How often connections are pinged, so proxies don't close them for being idle.
+pub const PING_INTERVAL_SECONDS: u64 = 30;
+
+/// Requests with a bigger head than this are dropped.
+pub const MAX_REQUEST_HEAD_BYTES: usize = 8192;
+
+/// Connections are only held while a client is authorised, and while an event stream loads the events it missed,
+/// so a few go a long way.
+pub const MAX_DATABASE_CONNECTIONS: u32 = 10;
+
+/// Clients that can't get a database connection within this long are turned away with a 503.
+pub const DATABASE_TIMEOUT_SECONDS: u64 = 5;
+
+/// The Redis channel realtime messages are published to, so every instance can pass them on to its own clients.
+pub const REALTIME_CHANNEL: &str = "realtime";
+
+/// What gets pushed to WebSocket clients, as JSON with a type field.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RealtimeMessage<'a> {
+    Presence { camera_id: String, online: bool },
+    Event { event: &'a Event },
+    Image { camera_id: String, image_id: String },
+    Announcement { announcement: &'a Announcement },
+}
+
+enum SubscriberKind {
+    WebSocket,
+    /// Only gets events, not presence or images.
+    EventStream,
+}
+
+/// Something to send to a subscriber. event_id is set for events so event streams can skip ones they've already sent.
+struct Outgoing {
+    event_id: Option<i32>,
+    payload: String,
+}
+
+struct Subscriber {
+    user_id: uuid::Uuid,
+    kind: SubscriberKind,
+    sender: Sender<Outgoing>,
+}
+
+/// Everyone connected to /ws or the event stream. Senders are dropped once their connection's thread has gone.
+static SUBSCRIBERS: Lazy<Mutex<Vec<Subscriber>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+type DbPool = Pool<ConnectionManager<PgConnection>>;
+type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+/// The port the realtime server listens on, set with websocket_port in [server]. Defaults to 8001.
+/// Rocket can neither upgrade connections nor flush a response part way through,
+/// so /ws and the event stream are served separately to the API's port.
+pub fn websocket_port() -> u16 {
+    settings().server.websocket_port
+}
+
+/// How many WebSocket and event stream clients are connected. Clients that have gone are still counted
+/// until a message fails to reach them.
+pub fn active_streams() -> (usize, usize) {
+    let subscribers = SUBSCRIBERS
+        .lock()
+        .expect("Realtime subscribers lock poisoned!");
+
+    let websockets = subscribers
+        .iter()
+        .filter(|subscriber| match subscriber.kind {
+            SubscriberKind::WebSocket => true,
+            SubscriberKind::EventStream => false,
+        })
+        .count();
+
+    (websockets, subscribers.len() - websockets)
+}
+
+fn subscribe(user_id: uuid::Uuid, kind: SubscriberKind) -> Receiver<Outgoing> {
+    let (sender, receiver) = mpsc::channel();
+
+    SUBSCRIBERS
+        .lock()
+        .expect("Realtime subscribers lock poisoned!")
+        .push(Subscriber {
+            user_id,
+            kind,
+            sender,
+        });
+
+    receiver
+}
+
+/// Formats an event as a server-sent event, with its ID so clients can resume with Last-Event-ID.
+fn event_stream_frame(event: &Event) -> String {
+    format!(
+        "id: {}\ndata: {}\n\n",
+        event.event_id,
+        serde_json::to_string(event).expect("Failed to serialize event somehow?")
+    )
+}
+
+/// A realtime message on its way to the instances' subscribers, see publish().
+#[derive(Serialize, Deserialize)]
+struct Broadcast {
+    /// None for messages to everyone, like announcements.
+    camera_id: Option<uuid::Uuid>,
+    payload: String,
+    /// Set for events, which are also sent to event streams.
+    event_id: Option<i32>,
+    event_frame: Option<String>,
+}
+
+/// Sends the message to every connected user who has access to the camera. With Redis, it goes through
+/// REALTIME_CHANNEL so users connected to other instances get it too, otherwise it's only sent to this
+/// instance's clients.
+pub fn publish(camera_id: uuid::Uuid, message: RealtimeMessage, connection: &PgConnection) {
+    broadcast(Some(camera_id), message, connection);
+}
+
+fn broadcast(camera_id: Option<uuid::Uuid>, message: RealtimeMessage, connection: &PgConnection) {
+    let event = match &message {
+        RealtimeMessage::Event { event } => Some(*event),
+        _ => None,
+    };
+
+    let broadcast = Broadcast {
+        camera_id,
+        payload: serde_json::to_string(&message).expect("Failed to serialize realtime message?"),
+        event_id: event.map(|event| event.event_id),
+        event_frame: event.map(event_stream_frame),
+    };
+
+    let published = cluster::publish(
+        REALTIME_CHANNEL,
+        &serde_json::to_string(&broadcast).expect("Failed to serialize realtime message?"),
+    );
+
+    if !published {
+        deliver(broadcast, connection);
+    }
+}
+
+/// Sends the message to this instance's subscribers. Returns false if the camera's users couldn't be looked up.
+fn deliver(broadcast: Broadcast, connection: &PgConnection) -> bool {
+    let mut subscribers = SUBSCRIBERS
+        .lock()
+        .expect("Realtime subscribers lock poisoned!");
+
+    // Nobody is listening, so there's no point looking up who would be
+    if subscribers.len() == 0 {
+        return true;
+    }
+
+    let user_ids = match broadcast.camera_id {
+        Some(camera_id) => match get_cameras_users(camera_id, connection) {
+            Ok(user_ids) => Some(user_ids),
+            Err(error) => {
+                error!(
+                    "Failed to get users of camera {} for realtime clients! The error was {}",
+                    camera_id, error
+                );
+                return false;
+            }
+        },
+        None => None,
+    };
+
+    subscribers.retain(|subscriber| {
+        if let Some(user_ids) = &user_ids {
+            if !user_ids.contains(&subscriber.user_id) {
+                return true;
+            }
+        }
+
+        match (&subscriber.kind, &broadcast.event_frame) {
+            (SubscriberKind::WebSocket, _) => subscriber
+                .sender
+                .send(Outgoing {
+                    event_id: None,
+                    payload: broadcast.payload.clone(),
+                })
+                .is_ok(),
+            (SubscriberKind::EventStream, Some(event_frame)) => subscriber
+                .sender
+                .send(Outgoing {
+                    event_id: broadcast.event_id,
+                    payload: event_frame.clone(),
+                })
+                .is_ok(),
+            (SubscriberKind::EventStream, None) => true,
+        }
+    });
+
+    true
+}
+
+/// Hands messages other instances (and this one) publish to this instance's subscribers.
+/// Keeps one database connection for looking up cameras' users, made again if it stops working.
+fn spawn_broadcast_listener(database_url: String) {
+    let mut connection: Option<PgConnection> = None;
+
+    cluster::subscribe(REALTIME_CHANNEL, move |message| {
+        let broadcast = match serde_json::from_str::<Broadcast>(message) {
+            Ok(broadcast) => broadcast,
+            Err(error) => {
+                warn!("Ignoring realtime message that failed to parse: {}", error);
+                return;
+            }
+        };
+
+        if connection.is_none() {
+            connection = PgConnection::establish(&database_url)
+                .map_err(|error| {
+                    error!(
+                        "Realtime listener failed to connect to the database! The error was {}",
+                        error
+                    )
+                })
+                .ok();
+        }
+
+        if let Some(database) = &connection {
+            if !deliver(broadcast, database) {
+                connection = None;
+            }
+        }
+    });
+}
+
+pub fn publish_presence(camera_id: uuid::Uuid, online: bool, connection: &PgConnection) {
+    publish(
+        camera_id,
+        RealtimeMessage::Presence {
+            camera_id: camera_id.to_string(),
+            online,
+        },
+        connection,
+    );
+}
+
+pub fn publish_event(event: &Event, connection: &PgConnection) {
+    publish(
+        event.camera_id,
+        RealtimeMessage::Event { event },
+        connection,
+    );
+}
+
+pub fn publish_image(camera_id: uuid::Uuid, image_id: u64, connection: &PgConnection) {
+    publish(
+        camera_id,
+        RealtimeMessage::Image {
+            camera_id: camera_id.to_string(),
+            image_id: image_id.to_string(),
+        },
+        connection,
+    );
+}
+
+/// Sends the announcement to every WebSocket client, whoever they are.
+pub fn publish_announcement(announcement: &Announcement, connection: &PgConnection) {
+    broadcast(
+        None,
+        RealtimeMessage::Announcement { announcement },
+        connection,
+    );
+}
+
+/// The parts of a request's head that the realtime server cares about.
+struct RequestHead {
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    /// Everything read from the stream so far, so the WebSocket handshake can read it again.
+    raw: Vec<u8>,
+}
+
+impl RequestHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query_parameter(&self, name: &str) -> Option<&str> {
+        self.query.as_ref().and_then(|query| {
+            query.split('&').find_map(|pair| {
+                pair.strip_prefix(name)
+                    .and_then(|rest| rest.strip_prefix('='))
+            })
+        })
+    }
+
+    /// The tenant the client is for, like the API's. Browsers can't set headers on WebSocket or EventSource requests
+    /// either, so the tenant's slug can also be given as ?tenant=.
+    fn tenant(&self, connection: &PgConnection) -> Result<i32, rocket::http::Status> {
+        tenant::resolve_tenant(
+            self.header(tenant::TENANT_HEADER)
+                .or_else(|| self.query_parameter(tenant::TENANT_HEADER)),
+            self.header("Host"),
+            || Ok(connection),
+        )
+    }
+
+    /// Cameras can't always set headers on WebSocket requests either, so the token can also be given as
+    /// ?camera_token=.
+    fn camera_token(&self) -> Option<uuid::Uuid> {
+        self.header("camera_token")
+            .or_else(|| self.query_parameter("camera_token"))
+            .and_then(|token| uuid::Uuid::parse_str(token).ok())
+    }
+
+    /// Browsers can't set headers on WebSocket or EventSource requests, so the token can also be given as ?user_token=.
+    fn user_token(&self) -> Option<uuid::Uuid> {
+        self.header("user_token")
+            .or_else(|| self.query_parameter("user_token"))
+            .and_then(|token| uuid::Uuid::parse_str(token).ok())
+    }
+}
+
+fn read_request_head(stream: &mut ClientStream) -> io::Result<RequestHead> {
+    let mut raw = Vec::new();
+    let mut buffer = [0; 1024];
+
+    loop {
+        let read = stream.read(&mut buffer)?;
+
+        if read == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed during request",
+            ));
+        }
+
+        raw.extend_from_slice(&buffer[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+
+        match request.parse(&raw) {
+            Ok(httparse::Status::Complete(_)) => {
+                let target = request.path.unwrap_or("/").to_string();
+                let mut parts = target.splitn(2, '?');
+
+                return Ok(RequestHead {
+                    path: parts.next().unwrap_or("/").to_string(),
+                    query: parts.next().map(|query| query.to_string()),
+                    headers: request
+                        .headers
+                        .iter()
+                        .map(|header| {
+                            (
+                                header.name.to_string(),
+                                String::from_utf8_lossy(header.value).to_string(),
+                            )
+                        })
+                        .collect(),
+                    raw,
+                });
+            }
+            Ok(httparse::Status::Partial) if raw.len() < MAX_REQUEST_HEAD_BYTES => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Request head too big",
+                ))
+            }
+            Err(error) => return Err(io::Error::new(ErrorKind::InvalidData, error.to_string())),
+        }
+    }
+}
+
+/// Writes an error in the same shape as the API's ErrorBody.
+fn write_error(stream: &mut ClientStream, status: &str, code: &str, message: &str) {
+    let body = format!("{{\"code\":\"{}\",\"message\":\"{}\"}}", code, message);
+
+    if let Err(error) = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    ) {
+        error!(
+            "Failed to write realtime error response! The error was {}",
+            error
+        );
+    }
+}
+
+/// A client's connection to the realtime server, which is over TLS if TLS is on, see tls::realtime_tls_config().
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ServerSession, TcpStream>>),
+}
+
+impl ClientStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.read(buffer),
+            ClientStream::Tls(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => stream.write(buffer),
+            ClientStream::Tls(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => stream.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Lets the WebSocket handshake read the request head that has already been read off the stream.
+struct ReplayStream {
+    head: Cursor<Vec<u8>>,
+    stream: ClientStream,
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.head.read(buffer)? {
+            0 => self.stream.read(buffer),
+            read => Ok(read),
+        }
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.stream.write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Read timeouts show up as WouldBlock on some platforms and TimedOut on others.
+pub fn is_timeout(error: &io::Error) -> bool {
+    error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+/// Finishes the WebSocket handshake. Reads time out after `read_timeout`, so that queued messages get sent while
+/// waiting for the client.
+fn accept_websocket(
+    stream: ClientStream,
+    head: RequestHead,
+    read_timeout: Duration,
+    config: Option<WebSocketConfig>,
+) -> Option<WebSocket<ReplayStream>> {
+    let websocket = match tungstenite::accept_with_config(
+        ReplayStream {
+            head: Cursor::new(head.raw),
+            stream,
+        },
+        config,
+    ) {
+        Ok(websocket) => websocket,
+        Err(error) => {
+            error!(
+                "Failed to accept WebSocket connection! The error was {}",
+                error
+            );
+            return None;
+        }
+    };
+
+    if let Err(error) = websocket
+        .get_ref()
+        .stream
+        .set_read_timeout(Some(read_timeout))
+    {
+        error!(
+            "Failed to set WebSocket read timeout! The error was {}",
+            error
+        );
+        return None;
+    }
+
+    Some(websocket)
+}
+
+fn serve_websocket(stream: ClientStream, head: RequestHead, user_id: uuid::Uuid) {
+    let mut websocket = match accept_websocket(stream, head, Duration::from_secs(1), None) {
+        Some(websocket) => websocket,
+        None => return,
+    };
+
+    let receiver = subscribe(user_id, SubscriberKind::WebSocket);
+    let mut last_ping = Instant::now();
+
+    loop {
+        for outgoing in receiver.try_iter() {
+            if websocket
+                .write_message(Message::Text(outgoing.payload))
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        if last_ping.elapsed() >= Duration::from_secs(PING_INTERVAL_SECONDS) {
+            if websocket.write_message(Message::Ping(Vec::new())).is_err() {
+                return;
+            }
+            last_ping = Instant::now();
+        }
+
+        // Clients aren't expected to send anything, but reading handles pongs and closes
+        match websocket.read_message() {
+            Ok(Message::Close(_)) => return,
+            Ok(_) => {}
+            Err(WebSocketError::Io(error)) if is_timeout(&error) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Serves the user's events as server-sent events. Clients that reconnect with a Last-Event-ID header
+/// (or ?last_event_id=, for the first connection) get the events they missed first, up to MAX_PAGE_SIZE of them.
+fn serve_event_stream(
+    mut stream: ClientStream,
+    head: RequestHead,
+    user_id: uuid::Uuid,
+    connection: DbConnection,
+) {
+    // Subscribing before loading missed events means nothing gets lost in between, duplicates are skipped below
+    let receiver = subscribe(user_id, SubscriberKind::EventStream);
+
+    let last_event_id = head
+        .header("Last-Event-ID")
+        .or_else(|| head.query_parameter("last_event_id"))
+        .and_then(|last_event_id| last_event_id.trim().parse::<i32>().ok());
+
+    let missed_events = match last_event_id {
+        Some(last_event_id) => users_events_query(user_id, &EventFilter::default())
+            .filter(events::event_id.gt(last_event_id))
+            .order(events::event_id.asc())
+            .limit(MAX_PAGE_SIZE)
+            .load::<Event>(&*connection)
+            .unwrap_or_else(|error| {
+                error!(
+                    "Failed to get missed events for user {}! The error was {}",
+                    user_id, error
+                );
+                Vec::new()
+            }),
+        None => Vec::new(),
+    };
+
+    // The stream can stay open for hours, so don't hold on to a database connection
+    drop(connection);
+
+    if write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"
+    )
+    .is_err()
+    {
+        return;
+    }
+
+    let mut last_sent_id = last_event_id.unwrap_or(0);
+
+    for event in missed_events {
+        if stream
+            .write_all(event_stream_frame(&event).as_bytes())
+            .is_err()
+        {
+            return;
+        }
+        last_sent_id = event.event_id;
+    }
+
+    loop {
+        let frame = match receiver.recv_timeout(Duration::from_secs(PING_INTERVAL_SECONDS)) {
+            Ok(outgoing) => {
+                if outgoing
+                    .event_id
+                    .map_or(false, |event_id| event_id <= last_sent_id)
+                {
+                    continue;
+                }
+                last_sent_id = outgoing.event_id.unwrap_or(last_sent_id);
+                outgoing.payload
+            }
+            // A comment, which EventSource ignores
+            Err(RecvTimeoutError::Timeout) => String::from(": keep-alive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if stream.write_all(frame.as_bytes()).is_err() || stream.flush().is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_connection(mut stream: ClientStream, pool: &DbPool) {
+    // Don't let a client that never finishes its request hold on to a thread
+    if let Err(error) = stream.set_read_timeout(Some(Duration::from_secs(10))) {
+        error!(
+            "Failed to set realtime read timeout! The error was {}",
+            error
+        );
+        return;
+    }
+
+    let head = match read_request_head(&mut stream) {
+        Ok(head) => head,
+        Err(error) => {
+            error!("Failed to read realtime request! The error was {}", error);
+            return;
+        }
+    };
+
+    // The event stream is also served without the prefix, like the API's v0 paths
+    let is_event_stream =
+        head.path == format!("{}/Events/Stream", API_PREFIX) || head.path == "/Events/Stream";
+
+    let talk_route = talk::route(&head.path);
+
+    if head.path != "/ws" && !is_event_stream && talk_route.is_none() {
+        write_error(&mut stream, "404 Not Found", "not_found", "No such route");
+        return;
+    }
+
+    let is_camera_talk = matches!(talk_route, Some(talk::Route::Camera));
+
+    // Checked before taking a database connection, so clients without a token can't use them all up
+    let token = if is_camera_talk {
+        head.camera_token()
+    } else {
+        head.user_token()
+    };
+    let token = match token {
+        Some(token) => token,
+        None => {
+            write_error(
+                &mut stream,
+                "401 Unauthorized",
+                "unauthorized",
+                if is_camera_talk {
+                    "Missing or invalid camera token"
+                } else {
+                    "Missing or invalid token"
+                },
+            );
+            return;
+        }
+    };
+
+    let connection = match pool.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!(
+                "Realtime server failed to get a database connection! The error was {}",
+                error
+            );
+            write_error(
+                &mut stream,
+                "503 Service Unavailable",
+                "service_unavailable",
+                "Failed to connect to the database",
+            );
+            return;
+        }
+    };
+
+    if !feature_flags::is_enabled(feature_flags::STREAMING, &connection) {
+        write_error(
+            &mut stream,
+            "503 Service Unavailable",
+            "service_unavailable",
+            "Streaming is turned off",
+        );
+        return;
+    }
+
+    if is_camera_talk {
+        return serve_camera_talk(stream, head, token, connection);
+    }
+
+    // Talking isn't just reading, so it isn't allowed while impersonating, but the rest is
+    let user_id = match user_tokens::authorise_user_token(
+        token,
+        head.tenant(&connection),
+        talk_route.is_none(),
+        || &*connection,
+    ) {
+        Ok(user) => {
+            if let Some(admin_id) = user.impersonated_by {
+                info!(
+                    "Realtime connection for user {} impersonated by {}",
+                    user.user_id, admin_id
+                );
+            }
+            user.user_id
+        }
+        Err(rejection) => return write_rejection(&mut stream, rejection),
+    };
+
+    let kind = if talk_route.is_some() {
+        usage::TALK_STREAM
+    } else if is_event_stream {
+        usage::EVENT_STREAM
+    } else {
+        usage::WEBSOCKET_STREAM
+    };
+    let _usage = match plan::start_stream(user_id, kind, &connection) {
+        Ok(usage) => usage,
+        Err(error) => return write_api_error(&mut stream, error),
+    };
+
+    match talk_route {
+        Some(talk::Route::Viewer(camera_id)) => {
+            let membership = match talk::join_as_viewer(user_id, camera_id, &connection) {
+                Ok(membership) => membership,
+                Err(error) => return write_api_error(&mut stream, error),
+            };
+            drop(connection);
+
+            if let Some(websocket) = accept_websocket(
+                stream,
+                head,
+                talk::POLL_INTERVAL,
+                Some(talk::websocket_config()),
+            ) {
+                talk::relay(websocket, membership);
+            }
+        }
+        _ if is_event_stream => serve_event_stream(stream, head, user_id, connection),
+        _ => {
+            drop(connection);
+            serve_websocket(stream, head, user_id);
+        }
+    }
+}
+
+fn write_api_error(stream: &mut ClientStream, error: ApiError) {
+    let status = format!("{} {}", error.status.code, error.status.reason);
+    write_error(stream, &status, error.code, error.error);
+}
+
+/// Answers a token turned away by the checks the API's guards make the same way they would.
+fn write_rejection(stream: &mut ClientStream, rejection: TokenRejection) {
+    let status = format!("{} {}", rejection.status.code, rejection.status.reason);
+    write_error(stream, &status, rejection.code(), rejection.message());
+}
+
+/// Connects a camera to its talk relay, see talk.
+fn serve_camera_talk(
+    mut stream: ClientStream,
+    head: RequestHead,
+    camera_token: uuid::Uuid,
+    connection: DbConnection,
+) {
+    let camera_id =
+        match camera_tokens::authorise_camera_token(camera_token, head.tenant(&connection), || {
+            &*connection
+        }) {
+            Ok(camera_id) => camera_id,
+            Err(rejection) => return write_rejection(&mut stream, rejection),
+        };
+
+    record_camera_contact(camera_id, &connection);
+    drop(connection);
+
+    if let Some(websocket) = accept_websocket(
+        stream,
+        head,
+        talk::POLL_INTERVAL,
+        Some(talk::websocket_config()),
+    ) {
+        talk::relay(websocket, talk::join_as_camera(camera_id));
+    }
+}
+
+/// How many connections the realtime server has open, so it can turn clients away once it has
+/// max_realtime_connections().
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many connections the realtime server takes at once, set with max_realtime_connections in [limits].
+/// Defaults to 1000.
+pub fn max_realtime_connections() -> usize {
+    settings().limits.max_realtime_connections
+}
+
+/// One of the realtime server's connections. It stops counting towards OPEN_CONNECTIONS when its thread drops it.
+struct OpenConnection;
+
+impl OpenConnection {
+    fn open() -> Option<OpenConnection> {
+        let opened = OPEN_CONNECTIONS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                if open < max_realtime_connections() {
+                    Some(open + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok();
+
+        if opened {
+            Some(OpenConnection)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Turns a client away without giving it a thread, as the server already has as many connections as it takes.
+/// Over TLS the connection is only closed, as a handshake isn't worth holding up accepting other connections for.
+fn refuse_connection(stream: TcpStream, tls_config: &Option<Arc<rustls::ServerConfig>>) {
+    if tls_config.is_some() {
+        return;
+    }
+
+    if stream
+        .set_write_timeout(Some(Duration::from_secs(1)))
+        .is_ok()
+    {
+        write_error(
+            &mut ClientStream::Plain(stream),
+            "503 Service Unavailable",
+            "too_many_connections",
+            "The realtime server has too many connections, try again later",
+        );
+    }
+}
+
+/// Starts the realtime server on websocket_port(), serving /ws, GET /api/v1/Events/Stream and the talk relay.
+/// Every connection gets its own thread, up to max_realtime_connections(). They share a pool of
+/// MAX_DATABASE_CONNECTIONS database connections. Connections are over TLS if TLS is on.
+pub fn spawn_realtime_server(database_url: String) {
+    let listener =
+        TcpListener::bind(("0.0.0.0", websocket_port())).expect("Failed to bind realtime server!");
+
+    spawn_broadcast_listener(database_url.clone());
+
+    let pool = Pool::builder()
+        .max_size(MAX_DATABASE_CONNECTIONS)
+        .connection_timeout(Duration::from_secs(DATABASE_TIMEOUT_SECONDS))
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create realtime connection pool!");
+
+    let tls_config = tls::realtime_tls_config();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => match OpenConnection::open() {
+                    Some(open_connection) => {
+                        let pool = pool.clone();
+                        // The TLS handshake happens on the connection's own thread, when the request is read
+                        let stream = match &tls_config {
+                            Some(tls_config) => {
+                                ClientStream::Tls(Box::new(rustls::StreamOwned::new(
+                                    rustls::ServerSession::new(tls_config),
+                                    stream,
+                                )))
+                            }
+                            None => ClientStream::Plain(stream),
+                        };
+                        thread::spawn(move || {
+                            handle_connection(stream, &pool);
+                            drop(open_connection);
+                        });
+                    }
+                    None => {
+                        warn!("Turned a realtime connection away, there are too many open");
+                        refuse_connection(stream, &tls_config);
+                    }
+                },
+                Err(error) => error!(
+                    "Failed to accept realtime connection! The error was {}",
+                    error
+                ),
+            }
+        }
+    });
+}
@@ -0,0 +1,276 @@
+use crate::{
+    api_error::ApiError,
+    camera::CameraId,
+    media_hold::{self, MediaHold},
+    metrics,
+    page::{Page, PageQuery},
+    plan,
+    settings::settings,
+    storage, usage,
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::recordings;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::get;
+use rocket::http::{ContentType, Status};
+use rocket::request::Form;
+use rocket::response::{Content, Stream};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::fs::{self, create_dir_all, File};
+use std::io::{self, Write};
+use std::time::Instant;
+
+pub const RTMP_SOURCE: &str = "rtmp";
+pub const SRT_SOURCE: &str = "srt";
+
+/// A segment of video a camera pushed to the server. Segments follow on from each other while the camera keeps
+/// streaming, and each one plays on its own.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Recording {
+    pub recording_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// How the video arrived, rtmp or srt.
+    pub source: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub started_at: DateTime<Utc>,
+    /// None while the segment is still being written.
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+/// Where recordings are stored, set with recordings_directory in [storage]. Defaults to recordings.
+pub fn recordings_directory() -> String {
+    settings().storage.recordings_directory.clone()
+}
+
+pub fn recording_path(camera_id: &uuid::Uuid, recording_id: i32) -> String {
+    format!("{}/{}/{}", recordings_directory(), camera_id, recording_id)
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get recordings! The error was {}", error);
+    ApiError {
+        error: "Failed to get recordings",
+        code: "get_recordings_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Writes one segment of a camera's video to disk, and counts it towards the camera's storage once it's finished.
+pub struct RecordingWriter {
+    pub recording: Recording,
+    file: File,
+    size_bytes: u64,
+    started_at: Instant,
+}
+
+impl RecordingWriter {
+    /// Starts a new segment, unless the camera owner's plan is out of storage.
+    pub fn start(
+        camera_id: uuid::Uuid,
+        source: &str,
+        content_type: &str,
+        connection: &PgConnection,
+    ) -> Result<RecordingWriter, ApiError> {
+        plan::check_storage(camera_id, connection)?;
+
+        let recording = diesel::insert_into(recordings::table)
+            .values((
+                recordings::camera_id.eq(camera_id),
+                recordings::source.eq(source),
+                recordings::content_type.eq(content_type),
+            ))
+            .get_result::<Recording>(connection)
+            .map_err(database_error)?;
+
+        let file = create_dir_all(format!("{}/{}", recordings_directory(), camera_id))
+            .and_then(|_| File::create(recording_path(&camera_id, recording.recording_id)))
+            .map_err(|error| {
+                error!(
+                    "Failed to create recording {} for camera {}! The error was {}",
+                    recording.recording_id, camera_id, error
+                );
+                ApiError {
+                    error: "Failed to save recording to server",
+                    code: "save_recording_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            })?;
+
+        Ok(RecordingWriter {
+            recording,
+            file,
+            size_bytes: 0,
+            started_at: Instant::now(),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.size_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    /// How long the segment has been going for.
+    pub fn elapsed_seconds(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    /// Finishes the segment, recording its size and when it ended.
+    pub fn finish(mut self, connection: &PgConnection) -> QueryResult<Recording> {
+        if let Err(error) = self.file.flush() {
+            error!(
+                "Failed to flush recording {}! The error was {}",
+                self.recording.recording_id, error
+            );
+        }
+
+        let camera_id = self.recording.camera_id;
+        metrics::record_upload("video", self.size_bytes);
+        usage::record_upload(camera_id, self.size_bytes);
+        storage::record_stored(
+            camera_id,
+            storage::VIDEO,
+            self.recording.started_at.naive_utc().date(),
+            self.size_bytes,
+        );
+
+        diesel::update(recordings::table.find(self.recording.recording_id))
+            .set((
+                recordings::size_bytes.eq(self.size_bytes as i64),
+                recordings::ended_at.eq(Utc::now()),
+            ))
+            .get_result(connection)
+    }
+}
+
+/// Deletes the camera's recordings that started before `cutoff`, apart from those under one of `holds`. Returns how
+/// many were deleted.
+pub fn delete_before(
+    camera_id: uuid::Uuid,
+    cutoff: DateTime<Utc>,
+    holds: &[MediaHold],
+    connection: &PgConnection,
+) -> io::Result<usize> {
+    let recording_ids = recordings::table
+        .filter(recordings::camera_id.eq(camera_id))
+        .filter(recordings::started_at.lt(cutoff))
+        .select((
+            recordings::recording_id,
+            recordings::started_at,
+            recordings::ended_at,
+        ))
+        .load::<(i32, DateTime<Utc>, Option<DateTime<Utc>>)>(connection)
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+        .into_iter()
+        .filter(|(recording_id, started_at, ended_at)| {
+            // Recordings still being written are held if a hold covers any of them so far
+            !media_hold::is_held(
+                holds,
+                storage::VIDEO,
+                *recording_id as i64,
+                *started_at,
+                ended_at.unwrap_or_else(Utc::now),
+            )
+        })
+        .map(|(recording_id, _, _)| recording_id)
+        .collect::<Vec<i32>>();
+
+    diesel::delete(recordings::table.filter(recordings::recording_id.eq_any(&recording_ids)))
+        .execute(connection)
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+
+    for recording_id in &recording_ids {
+        match fs::remove_file(recording_path(&camera_id, *recording_id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    Ok(recording_ids.len())
+}
+
+/// The camera's recordings, newest first.
+#[openapi]
+#[get("/Cameras/<camera_id>/Recordings?<query..>")]
+pub fn list_recordings(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    query: Form<PageQuery>,
+) -> Result<Json<Page<Recording>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+    let (offset, limit) = query.offset_and_limit()?;
+
+    let total = recordings::table
+        .filter(recordings::camera_id.eq(camera_id))
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    recordings::table
+        .filter(recordings::camera_id.eq(camera_id))
+        .order(recordings::started_at.desc())
+        .offset(offset)
+        .limit(limit)
+        .load::<Recording>(&*conn)
+        .map(|items| Json(Page::new(items, offset, total)))
+        .map_err(database_error)
+}
+
+/// Downloads one of the camera's recordings. Segments still being written can be downloaded too, with what has
+/// been written so far.
+#[openapi(skip)]
+#[get("/Cameras/<camera_id>/Recordings/<recording_id>")]
+pub fn get_recording(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    recording_id: i32,
+) -> Result<Content<Stream<File>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let recording = recordings::table
+        .find(recording_id)
+        .filter(recordings::camera_id.eq(camera_id))
+        .get_result::<Recording>(&*conn)
+        .optional()
+        .map_err(database_error)?
+        .ok_or(ApiError {
+            error: "Recording not found",
+            code: "recording_not_found",
+            status: Status::NotFound,
+            field: None,
+        })?;
+
+    let content_type =
+        ContentType::parse_flexible(&recording.content_type).unwrap_or(ContentType::Binary);
+
+    File::open(recording_path(&camera_id, recording_id))
+        .map(|file| Content(content_type, Stream::from(file)))
+        .map_err(|error| {
+            error!(
+                "Failed to open recording {}! The error was {}",
+                recording_id, error
+            );
+            ApiError {
+                error: "Failed to open recording",
+                code: "open_recording_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
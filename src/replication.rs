@@ -0,0 +1,764 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::{Camera, CameraId},
+    enums::token_error::TokenError,
+    event::Event,
+    media_store::{media_store, MediaStore},
+    settings::settings,
+    shutdown, storage, users_cameras, worker, CameraServerDbConn,
+};
+
+use super::schema::{cameras, events, replicated_events, replication_cursors};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{get, post, put, Data, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::time::Duration;
+
+pub const CAMERAS_STREAM: &str = "cameras";
+pub const EVENTS_STREAM: &str = "events";
+
+/// How many cameras or events are sent to the secondary at once.
+pub const BATCH_SIZE: i64 = 100;
+/// How many of each camera's images are sent each run, so one camera with a long backlog doesn't hold up the rest.
+pub const IMAGES_PER_RUN: usize = 50;
+pub const MAX_REPLICATED_IMAGE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Where the secondary's API is, from target_url in [replication]. Defaults to none, which turns replication off.
+pub fn target_url() -> Option<String> {
+    settings()
+        .replication
+        .target_url
+        .as_ref()
+        .map(|target_url| format!("{}{}", target_url.trim_end_matches('/'), API_PREFIX))
+}
+
+/// Each camera's images are a stream of their own, as they're not in the database.
+fn images_stream(camera_id: uuid::Uuid) -> String {
+    format!("images:{}", camera_id)
+}
+
+/// How far the primary has got through one of the streams it sends. Everything up to last_updated_at and last_id
+/// has been accepted by the secondary, so replication picks up from there after an outage of either server.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct ReplicationCursor {
+    pub stream: String,
+    pub last_updated_at: Option<DateTime<Utc>>,
+    pub last_id: Option<String>,
+    /// When the secondary last accepted something from the stream.
+    pub synced_at: Option<DateTime<Utc>>,
+    /// Why the last attempt failed, if it did. Cleared once the stream syncs again.
+    pub last_error: Option<String>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+}
+
+/// What GET /Admin/Replication returns.
+#[derive(Serialize, JsonSchema)]
+pub struct ReplicationStatus {
+    pub enabled: bool,
+    pub target_url: Option<String>,
+    pub media: bool,
+    pub cursors: Vec<ReplicationCursor>,
+}
+
+/// A camera as it's sent to the secondary. Whether it's online is the primary's business, and deletions aren't
+/// replicated, so whoever takes the primary can't delete the evidence from it.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ReplicatedCamera {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An event as it's sent to the secondary. Its audio isn't replicated, and nor are anonymised events.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ReplicatedEvent {
+    /// The event's ID on the primary. The secondary gives it one of its own.
+    pub event_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    pub image_id: Option<i64>,
+    pub severity: String,
+    pub tamper_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What POST /Replication/Events takes.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ReplicatedEvents {
+    /// origin in the primary's [replication].
+    pub origin: String,
+    pub events: Vec<ReplicatedEvent>,
+}
+
+/// What the /Replication routes return.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct Applied {
+    pub applied: usize,
+    /// Left alone because the secondary's copy was as new or newer.
+    pub skipped: usize,
+}
+
+/// Whether the request has a replication_token header matching token in [replication].
+pub fn has_replication_token(request: &Request) -> bool {
+    match (
+        &settings().replication.token,
+        request.headers().get_one("replication_token"),
+    ) {
+        // Comparing hashes means how long the comparison takes says nothing about the token
+        (Some(expected), Some(token)) => {
+            Sha256::digest(token.as_bytes()) == Sha256::digest(expected.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// A request from a primary server, see has_replication_token(). Without a token set, every /Replication route
+/// is a 404, so servers that aren't secondaries don't take writes this way.
+pub struct ReplicationToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ReplicationToken {
+    type Error = TokenError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if settings().replication.token.is_none() {
+            return Outcome::Failure((Status::NotFound, TokenError::NotFound));
+        }
+
+        match request.headers().get_one("replication_token") {
+            Some(_) if has_replication_token(request) => Outcome::Success(ReplicationToken),
+            Some(_) => Outcome::Failure((Status::Unauthorized, TokenError::NotFound)),
+            None => Outcome::Failure((Status::Unauthorized, TokenError::NoTokenProvided)),
+        }
+    }
+}
+
+fn get_cursor(stream: &str, connection: &PgConnection) -> QueryResult<Option<ReplicationCursor>> {
+    replication_cursors::table
+        .find(stream)
+        .get_result::<ReplicationCursor>(connection)
+        .optional()
+}
+
+/// Moves the stream on to what the secondary just accepted.
+fn advance_cursor(
+    stream: &str,
+    last_updated_at: Option<DateTime<Utc>>,
+    last_id: String,
+    connection: &PgConnection,
+) -> QueryResult<()> {
+    let now = Utc::now();
+
+    diesel::insert_into(replication_cursors::table)
+        .values((
+            replication_cursors::stream.eq(stream),
+            replication_cursors::last_updated_at.eq(last_updated_at),
+            replication_cursors::last_id.eq(&last_id),
+            replication_cursors::synced_at.eq(now),
+            replication_cursors::last_attempt_at.eq(now),
+        ))
+        .on_conflict(replication_cursors::stream)
+        .do_update()
+        .set((
+            replication_cursors::last_updated_at.eq(last_updated_at),
+            replication_cursors::last_id.eq(&last_id),
+            replication_cursors::synced_at.eq(now),
+            replication_cursors::last_error.eq(None::<String>),
+            replication_cursors::last_attempt_at.eq(now),
+        ))
+        .execute(connection)
+        .map(|_| ())
+}
+
+fn record_error(stream: &str, error: &str, connection: &PgConnection) {
+    let now = Utc::now();
+
+    if let Err(database_error) = diesel::insert_into(replication_cursors::table)
+        .values((
+            replication_cursors::stream.eq(stream),
+            replication_cursors::last_error.eq(error),
+            replication_cursors::last_attempt_at.eq(now),
+        ))
+        .on_conflict(replication_cursors::stream)
+        .do_update()
+        .set((
+            replication_cursors::last_error.eq(error),
+            replication_cursors::last_attempt_at.eq(now),
+        ))
+        .execute(connection)
+    {
+        error!(
+            "Failed to record replication error for {}! The error was {}",
+            stream, database_error
+        );
+    }
+}
+
+/// Sends a request to the secondary and reads what it applied.
+fn send(request: reqwest::blocking::RequestBuilder) -> Result<Applied, String> {
+    let token = settings().replication.token.clone().unwrap_or_default();
+
+    let response = request
+        .header("replication_token", token)
+        .send()
+        .map_err(|error| error.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("Secondary responded with {}", response.status()));
+    }
+
+    response
+        .json::<Applied>()
+        .map_err(|error| format!("Secondary sent back something unexpected: {}", error))
+}
+
+/// Sends cameras changed since the stream's cursor, a batch at a time, until the secondary has all of them.
+fn sync_cameras(
+    client: &reqwest::blocking::Client,
+    target_url: &str,
+    connection: &PgConnection,
+) -> Result<usize, String> {
+    let mut sent = 0;
+
+    while !shutdown::shutting_down() {
+        let cursor = get_cursor(CAMERAS_STREAM, connection).map_err(|error| error.to_string())?;
+        let mut changed = cameras::table.into_boxed();
+
+        if let Some(ReplicationCursor {
+            last_updated_at: Some(last_updated_at),
+            last_id: Some(last_id),
+            ..
+        }) = cursor
+        {
+            let last_camera_id =
+                uuid::Uuid::parse_str(&last_id).map_err(|error| error.to_string())?;
+            changed = changed.filter(
+                cameras::updated_at
+                    .gt(last_updated_at)
+                    .or(cameras::updated_at
+                        .eq(last_updated_at)
+                        .and(cameras::camera_id.gt(last_camera_id))),
+            );
+        }
+
+        let batch = changed
+            .order((cameras::updated_at, cameras::camera_id))
+            .limit(BATCH_SIZE)
+            .load::<Camera>(connection)
+            .map_err(|error| error.to_string())?;
+
+        let last = match batch.last() {
+            Some(last) => (last.updated_at, last.camera_id),
+            None => break,
+        };
+
+        let replicated: Vec<ReplicatedCamera> = batch
+            .into_iter()
+            .map(|camera| ReplicatedCamera {
+                camera_id: camera.camera_id,
+                name: camera.name,
+                created_at: camera.created_at,
+                updated_at: camera.updated_at,
+            })
+            .collect();
+
+        send(
+            client
+                .post(&format!("{}/Replication/Cameras", target_url))
+                .json(&replicated),
+        )?;
+        advance_cursor(CAMERAS_STREAM, Some(last.0), last.1.to_string(), connection)
+            .map_err(|error| error.to_string())?;
+
+        sent += replicated.len();
+        if (replicated.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// sync_cameras(), for events.
+fn sync_events(
+    client: &reqwest::blocking::Client,
+    target_url: &str,
+    connection: &PgConnection,
+) -> Result<usize, String> {
+    let mut sent = 0;
+
+    while !shutdown::shutting_down() {
+        let cursor = get_cursor(EVENTS_STREAM, connection).map_err(|error| error.to_string())?;
+        let mut changed = events::table
+            .filter(events::anonymised_at.is_null())
+            .into_boxed();
+
+        if let Some(ReplicationCursor {
+            last_updated_at: Some(last_updated_at),
+            last_id: Some(last_id),
+            ..
+        }) = cursor
+        {
+            let last_event_id = last_id.parse::<i32>().map_err(|error| error.to_string())?;
+            changed = changed.filter(
+                events::updated_at.gt(last_updated_at).or(events::updated_at
+                    .eq(last_updated_at)
+                    .and(events::event_id.gt(last_event_id))),
+            );
+        }
+
+        let batch = changed
+            .order((events::updated_at, events::event_id))
+            .limit(BATCH_SIZE)
+            .load::<Event>(connection)
+            .map_err(|error| error.to_string())?;
+
+        let last = match batch.last() {
+            Some(last) => (last.updated_at, last.event_id),
+            None => break,
+        };
+
+        let replicated = ReplicatedEvents {
+            origin: settings().replication.origin.clone(),
+            events: batch
+                .into_iter()
+                .map(|event| ReplicatedEvent {
+                    event_id: event.event_id,
+                    camera_id: event.camera_id,
+                    event_type: event.event_type,
+                    occurred_at: event.occurred_at,
+                    confidence: event.confidence,
+                    image_id: event.image_id,
+                    severity: event.severity,
+                    tamper_reason: event.tamper_reason,
+                    created_at: event.created_at,
+                    updated_at: event.updated_at,
+                })
+                .collect(),
+        };
+
+        send(
+            client
+                .post(&format!("{}/Replication/Events", target_url))
+                .json(&replicated),
+        )?;
+        advance_cursor(EVENTS_STREAM, Some(last.0), last.1.to_string(), connection)
+            .map_err(|error| error.to_string())?;
+
+        sent += replicated.events.len();
+        if (replicated.events.len() as i64) < BATCH_SIZE {
+            break;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Sends each camera's images newer than its stream's cursor, up to IMAGES_PER_RUN each. Images are sent oldest
+/// first, so the cursor is just the newest one the secondary has.
+fn sync_images(
+    client: &reqwest::blocking::Client,
+    target_url: &str,
+    connection: &PgConnection,
+) -> Result<usize, String> {
+    let store = media_store();
+    let mut sent = 0;
+
+    for camera_id in store.list_cameras().map_err(|error| error.to_string())? {
+        let stream = images_stream(camera_id);
+        let last_image_id = get_cursor(&stream, connection)
+            .map_err(|error| error.to_string())?
+            .and_then(|cursor| cursor.last_id)
+            .and_then(|last_id| last_id.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let mut image_ids: Vec<u64> = store
+            .list_images(&camera_id)
+            .map_err(|error| error.to_string())?
+            .into_iter()
+            .filter(|image_id| *image_id > last_image_id)
+            .collect();
+        image_ids.sort();
+
+        for image_id in image_ids.into_iter().take(IMAGES_PER_RUN) {
+            if shutdown::shutting_down() {
+                return Ok(sent);
+            }
+
+            let mut image = Vec::new();
+            store
+                .open_image(&camera_id, image_id)
+                .and_then(|mut file| file.read_to_end(&mut image))
+                .map_err(|error| error.to_string())
+                .and_then(|_| {
+                    send(
+                        client
+                            .put(&format!(
+                                "{}/Replication/Cameras/{}/Images/{}",
+                                target_url, camera_id, image_id
+                            ))
+                            .header("Content-Type", "image/jpeg")
+                            .body(image),
+                    )
+                })
+                .map_err(|error| {
+                    record_error(&stream, &error, connection);
+                    error
+                })?;
+
+            advance_cursor(&stream, None, image_id.to_string(), connection)
+                .map_err(|error| error.to_string())?;
+            sent += 1;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Runs a sync, recording why it failed against its stream if it did.
+fn sync_stream<F>(stream: &str, connection: &PgConnection, sync: F) -> Result<usize, String>
+where
+    F: FnOnce() -> Result<usize, String>,
+{
+    sync().map_err(|error| {
+        record_error(stream, &error, connection);
+        error
+    })
+}
+
+/// Sends the secondary everything that has changed since it last caught up: cameras first, since events need
+/// them, then events, then images if media in [replication] is on. Returns how many of each were sent.
+pub fn replicate(
+    client: &reqwest::blocking::Client,
+    connection: &PgConnection,
+) -> Result<(usize, usize, usize), String> {
+    let target_url = match target_url() {
+        Some(target_url) => target_url,
+        None => return Ok((0, 0, 0)),
+    };
+
+    let cameras = sync_stream(CAMERAS_STREAM, connection, || {
+        sync_cameras(client, &target_url, connection)
+    })?;
+    let events = sync_stream(EVENTS_STREAM, connection, || {
+        sync_events(client, &target_url, connection)
+    })?;
+    let images = if settings().replication.media {
+        sync_images(client, &target_url, connection)?
+    } else {
+        0
+    };
+
+    Ok((cameras, events, images))
+}
+
+/// Starts the thread that replicates to the secondary every interval_seconds in [replication]. Does nothing if
+/// target_url() is None.
+pub fn spawn_replication_worker(database_url: String) {
+    if target_url().is_none() {
+        return;
+    }
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()
+        .expect("Failed to build replication HTTP client!");
+
+    worker::spawn_worker(
+        "Replication",
+        Duration::from_secs(settings().replication.interval_seconds),
+        database_url,
+        move |connection| match replicate(&client, connection) {
+            Ok((0, 0, 0)) => {}
+            Ok((cameras, events, images)) => info!(
+                "Replicated {} cameras, {} events and {} images",
+                cameras, events, images
+            ),
+            Err(error) => error!("Failed to replicate! The error was {}", error),
+        },
+    );
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    match error {
+        // Events for a camera the secondary doesn't have yet, which the primary sends first
+        diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+            ApiError {
+                error: "Camera hasn't been replicated yet",
+                code: "camera_not_replicated",
+                status: Status::Conflict,
+                field: None,
+            }
+        }
+        error => {
+            error!("Failed to apply replicated data! The error was {}", error);
+            ApiError {
+                error: "Failed to apply replicated data",
+                code: "apply_replicated_data_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        }
+    }
+}
+
+/// Stores cameras from the primary. Whichever copy was changed last wins, so a camera renamed on the secondary
+/// keeps its new name until it's renamed on the primary again. Replicated cameras have no users, so admins see
+/// them rather than their owners, and DELETE /Admin/Cameras/Orphans shouldn't be used on a secondary.
+pub fn apply_cameras(
+    replicated: &[ReplicatedCamera],
+    connection: &PgConnection,
+) -> QueryResult<Applied> {
+    connection.transaction(|| {
+        let mut applied = Applied {
+            applied: 0,
+            skipped: 0,
+        };
+
+        for camera in replicated {
+            let existing = cameras::table
+                .find(camera.camera_id)
+                .select(cameras::updated_at)
+                .get_result::<DateTime<Utc>>(connection)
+                .optional()?;
+
+            match existing {
+                Some(updated_at) if updated_at >= camera.updated_at => applied.skipped += 1,
+                Some(_) => {
+                    diesel::update(cameras::table.find(camera.camera_id))
+                        .set((
+                            cameras::name.eq(&camera.name),
+                            cameras::updated_at.eq(camera.updated_at),
+                        ))
+                        .execute(connection)?;
+                    users_cameras::forget_camera_lists(&[camera.camera_id], connection);
+                    applied.applied += 1;
+                }
+                None => {
+                    diesel::insert_into(cameras::table)
+                        .values((
+                            cameras::camera_id.eq(camera.camera_id),
+                            cameras::name.eq(&camera.name),
+                            cameras::created_at.eq(camera.created_at),
+                            cameras::updated_at.eq(camera.updated_at),
+                        ))
+                        .execute(connection)?;
+                    applied.applied += 1;
+                }
+            }
+        }
+
+        Ok(applied)
+    })
+}
+
+/// Stores events from the primary, with the same rule as apply_cameras(). They're stored without notifying
+/// anyone, as the primary already has.
+pub fn apply_events(
+    replicated: &ReplicatedEvents,
+    connection: &PgConnection,
+) -> QueryResult<Applied> {
+    connection.transaction(|| {
+        let mut applied = Applied {
+            applied: 0,
+            skipped: 0,
+        };
+
+        for event in &replicated.events {
+            let existing = replicated_events::table
+                .inner_join(events::table.on(events::event_id.eq(replicated_events::event_id)))
+                .filter(replicated_events::origin.eq(&replicated.origin))
+                .filter(replicated_events::origin_event_id.eq(event.event_id))
+                .select((events::event_id, events::updated_at))
+                .get_result::<(i32, DateTime<Utc>)>(connection)
+                .optional()?;
+
+            match existing {
+                Some((_, updated_at)) if updated_at >= event.updated_at => applied.skipped += 1,
+                Some((event_id, _)) => {
+                    diesel::update(events::table.find(event_id))
+                        .set((
+                            events::event_type.eq(&event.event_type),
+                            events::occurred_at.eq(event.occurred_at),
+                            events::confidence.eq(event.confidence),
+                            events::image_id.eq(event.image_id),
+                            events::severity.eq(&event.severity),
+                            events::tamper_reason.eq(&event.tamper_reason),
+                            events::updated_at.eq(event.updated_at),
+                        ))
+                        .execute(connection)?;
+                    applied.applied += 1;
+                }
+                None => {
+                    let event_id = diesel::insert_into(events::table)
+                        .values((
+                            events::camera_id.eq(event.camera_id),
+                            events::event_type.eq(&event.event_type),
+                            events::occurred_at.eq(event.occurred_at),
+                            events::confidence.eq(event.confidence),
+                            events::image_id.eq(event.image_id),
+                            events::severity.eq(&event.severity),
+                            events::tamper_reason.eq(&event.tamper_reason),
+                            events::created_at.eq(event.created_at),
+                            events::updated_at.eq(event.updated_at),
+                        ))
+                        .returning(events::event_id)
+                        .get_result::<i32>(connection)?;
+
+                    diesel::insert_into(replicated_events::table)
+                        .values((
+                            replicated_events::origin.eq(&replicated.origin),
+                            replicated_events::origin_event_id.eq(event.event_id),
+                            replicated_events::event_id.eq(event_id),
+                        ))
+                        .execute(connection)?;
+                    applied.applied += 1;
+                }
+            }
+        }
+
+        Ok(applied)
+    })
+}
+
+/// Where replication has got to. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Replication")]
+pub fn get_replication_status(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<ReplicationStatus>, ApiError> {
+    let target_url = target_url();
+
+    replication_cursors::table
+        .order(replication_cursors::stream)
+        .load::<ReplicationCursor>(&*conn)
+        .map(|cursors| {
+            Json(ReplicationStatus {
+                enabled: target_url.is_some(),
+                target_url,
+                media: settings().replication.media,
+                cursors,
+            })
+        })
+        .map_err(|error| {
+            error!("Failed to get replication cursors! The error was {}", error);
+            ApiError {
+                error: "Failed to get replication status",
+                code: "get_replication_status_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Takes cameras from a primary. Only on secondaries, see ReplicationToken.
+#[openapi]
+#[post("/Replication/Cameras", format = "json", data = "<cameras>")]
+pub fn replicate_cameras(
+    conn: CameraServerDbConn,
+    _replication_token: ReplicationToken,
+    cameras: Json<Vec<ReplicatedCamera>>,
+) -> Result<Json<Applied>, ApiError> {
+    apply_cameras(&cameras, &conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Takes events from a primary. Only on secondaries, see ReplicationToken.
+#[openapi]
+#[post("/Replication/Events", format = "json", data = "<events>")]
+pub fn replicate_events(
+    conn: CameraServerDbConn,
+    _replication_token: ReplicationToken,
+    events: Json<ReplicatedEvents>,
+) -> Result<Json<Applied>, ApiError> {
+    apply_events(&events, &conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Takes an image from a primary. Images the secondary already has are kept as they are, so nothing sent later
+/// can replace the evidence. Only on secondaries, see ReplicationToken.
+#[openapi(skip)]
+#[put("/Replication/Cameras/<camera_id>/Images/<image_id>", data = "<image>")]
+pub fn replicate_image(
+    _replication_token: ReplicationToken,
+    camera_id: CameraId,
+    image_id: u64,
+    image: Data,
+) -> Result<Json<Applied>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    let store = media_store();
+
+    let failed = |error: std::io::Error| {
+        error!(
+            "Failed to store replicated image {} from camera {}! The error was {}",
+            image_id, camera_id, error
+        );
+        ApiError {
+            error: "Failed to store image",
+            code: "store_image_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    };
+
+    if store
+        .list_images(&camera_id)
+        .map_err(failed)?
+        .contains(&image_id)
+    {
+        return Ok(Json(Applied {
+            applied: 0,
+            skipped: 1,
+        }));
+    }
+
+    let mut bytes = Vec::new();
+    image
+        .open()
+        .take(MAX_REPLICATED_IMAGE_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .map_err(failed)?;
+    if bytes.len() as u64 > MAX_REPLICATED_IMAGE_BYTES {
+        return Err(ApiError {
+            error: "Images can be at most 16MiB",
+            code: "image_too_large",
+            status: Status::PayloadTooLarge,
+            field: None,
+        });
+    }
+
+    let size_bytes = store
+        .store_image(&camera_id, image_id, &mut bytes.as_slice())
+        .map_err(failed)?;
+    storage::record_stored(
+        camera_id,
+        storage::IMAGE,
+        storage::image_day(image_id),
+        size_bytes,
+    );
+
+    Ok(Json(Applied {
+        applied: 1,
+        skipped: 0,
+    }))
+}
@@ -0,0 +1,114 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use std::cell::RefCell;
+use std::time::Instant;
+use tracing::field::{display, Empty};
+use tracing::span::EnteredSpan;
+use tracing::{info_span, Span};
+
+/// Incoming X-Request-Id headers longer than this are replaced, so they can't flood the logs.
+pub const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+thread_local! {
+    /// Rocket handles each request on one thread from start to finish, so this is the ID of the request
+    /// the current thread is working on. Background workers never set it.
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+
+    /// The span for the request the current thread is working on, entered in on_request and left in on_response.
+    static CURRENT_SPAN: RefCell<Option<EnteredSpan>> = RefCell::new(None);
+}
+
+/// Returns the ID of the request being handled on this thread, if there is one.
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|request_id| request_id.borrow().clone())
+}
+
+/// Adds the user making the current request to its span, once their token has been checked.
+pub fn record_user(user_id: uuid::Uuid) {
+    Span::current().record("user_id", &display(user_id));
+}
+
+/// Adds the camera the current request is for to its span. Cameras' own requests and user requests for
+/// /Cameras/<camera_id> are both recorded.
+pub fn record_camera(camera_id: uuid::Uuid) {
+    Span::current().record("camera_id", &display(camera_id));
+}
+
+/// Takes the client's X-Request-Id if it's sensible, otherwise makes a new one.
+pub fn request_id_for(incoming: Option<&str>) -> String {
+    match incoming {
+        Some(request_id)
+            if request_id.len() > 0
+                && request_id.len() <= MAX_REQUEST_ID_LENGTH
+                && request_id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') =>
+        {
+            request_id.to_string()
+        }
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+/// The ID of a request and when it started, stored in its local cache for on_response.
+struct RequestId(String, Option<Instant>);
+
+/// Gives every request an ID, which is sent back in X-Request-Id and included in error bodies.
+/// Everything logged while the request is handled is inside a span with the ID, the user and the camera,
+/// and a line with the route, status and latency is logged once it has been handled.
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let request_id = request_id_for(request.headers().get_one("X-Request-Id"));
+
+        CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = Some(request_id.clone()));
+
+        let span = info_span!(
+            "request",
+            request_id = %request_id,
+            method = %request.method(),
+            path = %request.uri().path(),
+            user_id = Empty,
+            camera_id = Empty,
+        );
+
+        CURRENT_SPAN.with(|current| {
+            // A request that never got a response mustn't become the parent of this one
+            current.borrow_mut().take();
+            *current.borrow_mut() = Some(span.entered());
+        });
+        request.local_cache(|| RequestId(request_id, Some(Instant::now())));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let RequestId(request_id, started_at) =
+            request.local_cache(|| RequestId(String::new(), None));
+
+        if request_id.len() > 0 {
+            response.set_header(Header::new("X-Request-Id", request_id.clone()));
+        }
+
+        if let Some(started_at) = started_at {
+            info!(
+                route = request
+                    .route()
+                    .map(|route| route.uri.path())
+                    .unwrap_or("unmatched"),
+                status = response.status().code,
+                latency_ms = started_at.elapsed().as_millis() as u64,
+                "Handled request"
+            );
+        }
+
+        CURRENT_SPAN.with(|current| current.borrow_mut().take());
+    }
+}
@@ -0,0 +1,132 @@
+use diesel::QueryResult;
+use serde::Serialize;
+use std::io::{self, Read};
+
+/// Loads the batch of rows after the given one, or the first batch if it's None.
+pub type NextBatch<T> = Box<dyn FnMut(Option<&T>) -> QueryResult<Vec<T>>>;
+
+/// A response body that serialises rows a batch at a time as they're sent, rather than loading them all into a Vec
+/// first. Only one batch is held in memory at once, however many rows there are, so admins can export
+/// millions of them. Diesel can't iterate over rows as Postgres returns them, so each batch is its own query,
+/// continuing after the last row of the one before (keyset pagination).
+///
+/// If a batch fails to load partway through, the response is cut short, as the status has already been sent.
+pub struct RowStream<T> {
+    next_batch: NextBatch<T>,
+    write_row: fn(&T, &mut Vec<u8>),
+    batch_size: i64,
+    separator: &'static str,
+    end: &'static str,
+    /// The last row sent, which the next batch continues after.
+    last_row: Option<T>,
+    /// Whether anything has been sent, so only rows after the first get a separator.
+    started: bool,
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+fn write_json<T: Serialize>(row: &T, buffer: &mut Vec<u8>) {
+    serde_json::to_writer(buffer, row).expect("Rows can always be serialised");
+}
+
+fn write_json_line<T: Serialize>(row: &T, buffer: &mut Vec<u8>) {
+    write_json(row, buffer);
+    buffer.push(b'\n');
+}
+
+impl<T> RowStream<T> {
+    /// Sends `start`, then each row written by `write_row` with `separator` between them, then `end`.
+    /// `next_batch` must return fewer than `batch_size` rows once there are no more.
+    pub fn new(
+        start: &'static str,
+        separator: &'static str,
+        end: &'static str,
+        batch_size: i64,
+        write_row: fn(&T, &mut Vec<u8>),
+        next_batch: NextBatch<T>,
+    ) -> RowStream<T> {
+        RowStream {
+            next_batch,
+            write_row,
+            batch_size,
+            separator,
+            end,
+            last_row: None,
+            started: false,
+            buffer: start.as_bytes().to_vec(),
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// CSV with a header line. `write_row` must end each row with a newline.
+    pub fn csv(
+        header: &'static str,
+        batch_size: i64,
+        write_row: fn(&T, &mut Vec<u8>),
+        next_batch: NextBatch<T>,
+    ) -> RowStream<T> {
+        RowStream::new(header, "", "", batch_size, write_row, next_batch)
+    }
+
+    /// Loads the next batch into the buffer, and the end once there are no more.
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        let mut rows = (self.next_batch)(self.last_row.as_ref())
+            .map_err(|error| io::Error::new(io::ErrorKind::Other, error.to_string()))?;
+
+        self.buffer.clear();
+        self.position = 0;
+
+        for row in &rows {
+            if self.started {
+                self.buffer.extend_from_slice(self.separator.as_bytes());
+            }
+
+            (self.write_row)(row, &mut self.buffer);
+            self.started = true;
+        }
+
+        if (rows.len() as i64) < self.batch_size {
+            self.buffer.extend_from_slice(self.end.as_bytes());
+            self.done = true;
+        }
+
+        if let Some(row) = rows.pop() {
+            self.last_row = Some(row);
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Serialize> RowStream<T> {
+    /// A JSON array, for clients that expect the same body as a listing that isn't streamed.
+    pub fn json_array(batch_size: i64, next_batch: NextBatch<T>) -> RowStream<T> {
+        RowStream::new("[", ",", "]", batch_size, write_json, next_batch)
+    }
+
+    /// JSON Lines, one row per line, which can be processed before the whole response has arrived.
+    pub fn json_lines(batch_size: i64, next_batch: NextBatch<T>) -> RowStream<T> {
+        RowStream::new("", "", "", batch_size, write_json_line, next_batch)
+    }
+}
+
+impl<T> Read for RowStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if self.done {
+                return Ok(0);
+            }
+
+            self.fill_buffer()?;
+        }
+
+        let available = &self.buffer[self.position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position += length;
+
+        Ok(length)
+    }
+}
@@ -0,0 +1,836 @@
+use crate::{
+    camera::{self, record_camera_contact},
+    ingest_watchdog::{self, WatchedStream},
+    recording::{RecordingWriter, RTMP_SOURCE},
+    settings::settings,
+    stream_keys,
+};
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Recordings are stored as FLV, which holds what RTMP carries without having to remux it.
+pub const FLV_CONTENT_TYPE: &str = "video/x-flv";
+
+/// Encoders that send nothing for this long are disconnected, and their recording is finished.
+pub const READ_TIMEOUT_SECONDS: u64 = 30;
+
+/// Messages bigger than this close the connection. Keyframes from 4K encoders fit comfortably.
+pub const MAX_MESSAGE_BYTES: usize = 8 * 1024 * 1024;
+
+/// What the server tells encoders to send chunks in once they're connected.
+const SERVER_CHUNK_SIZE: u32 = 4096;
+
+/// Asked of encoders for acknowledgements, and the bandwidth they're told they can use.
+const WINDOW_ACK_SIZE: u32 = 5_000_000;
+
+const HANDSHAKE_BYTES: usize = 1536;
+
+// Message type IDs, see the RTMP specification
+const SET_CHUNK_SIZE: u8 = 1;
+const ABORT: u8 = 2;
+const ACKNOWLEDGEMENT: u8 = 3;
+const WINDOW_ACKNOWLEDGEMENT_SIZE: u8 = 5;
+const SET_PEER_BANDWIDTH: u8 = 6;
+const AUDIO: u8 = 8;
+const VIDEO: u8 = 9;
+const AMF3_DATA: u8 = 15;
+const AMF3_COMMAND: u8 = 17;
+const AMF0_DATA: u8 = 18;
+const AMF0_COMMAND: u8 = 20;
+
+/// The FLV tag type for script data, like onMetaData.
+const FLV_SCRIPT_DATA: u8 = 18;
+
+/// The port to take RTMP on, set with rtmp_port in [ingest]. Defaults to none, which turns RTMP ingest off.
+pub fn rtmp_port() -> Option<u16> {
+    settings().ingest.rtmp_port
+}
+
+/// How long recordings are before they're split, set with segment_seconds in [ingest]. Defaults to 60.
+pub fn segment_seconds() -> u64 {
+    settings().ingest.segment_seconds
+}
+
+/// An AMF0 value, which commands and metadata are sent as.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Amf0 {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf0)>),
+    Null,
+    Undefined,
+    EcmaArray(Vec<(String, Amf0)>),
+    StrictArray(Vec<Amf0>),
+}
+
+impl Amf0 {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf0::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Amf0::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if data.len() < length {
+        return None;
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Some(taken)
+}
+
+fn decode_amf0_string(data: &mut &[u8]) -> Option<String> {
+    let length = u16::from_be_bytes([*data.get(0)?, *data.get(1)?]) as usize;
+    take(data, 2)?;
+    Some(String::from_utf8_lossy(take(data, length)?).to_string())
+}
+
+fn decode_amf0_properties(data: &mut &[u8]) -> Option<Vec<(String, Amf0)>> {
+    let mut properties = Vec::new();
+
+    loop {
+        let key = decode_amf0_string(data)?;
+        // An empty key followed by the object end marker
+        if key.is_empty() && data.first() == Some(&9) {
+            take(data, 1)?;
+            return Some(properties);
+        }
+        properties.push((key, decode_amf0(data)?));
+    }
+}
+
+/// Reads the next AMF0 value off the front of data. Only the types encoders actually send are understood.
+pub fn decode_amf0(data: &mut &[u8]) -> Option<Amf0> {
+    let marker = take(data, 1)?[0];
+
+    match marker {
+        0 => {
+            let bytes = take(data, 8)?;
+            let mut number = [0; 8];
+            number.copy_from_slice(bytes);
+            Some(Amf0::Number(f64::from_be_bytes(number)))
+        }
+        1 => Some(Amf0::Boolean(take(data, 1)?[0] != 0)),
+        2 => Some(Amf0::String(decode_amf0_string(data)?)),
+        3 => Some(Amf0::Object(decode_amf0_properties(data)?)),
+        5 => Some(Amf0::Null),
+        6 => Some(Amf0::Undefined),
+        8 => {
+            // The count is only a hint, the properties still end with the object end marker
+            take(data, 4)?;
+            Some(Amf0::EcmaArray(decode_amf0_properties(data)?))
+        }
+        10 => {
+            let bytes = take(data, 4)?;
+            let count = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let mut values = Vec::new();
+            for _ in 0..count {
+                values.push(decode_amf0(data)?);
+            }
+            Some(Amf0::StrictArray(values))
+        }
+        _ => None,
+    }
+}
+
+/// Every value in a command or data message, stopping at the first that can't be read.
+pub fn decode_amf0_values(mut data: &[u8]) -> Vec<Amf0> {
+    let mut values = Vec::new();
+    while let Some(value) = decode_amf0(&mut data) {
+        values.push(value);
+    }
+    values
+}
+
+fn encode_amf0_string(string: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn encode_amf0_properties(properties: &[(String, Amf0)], out: &mut Vec<u8>) {
+    for (key, value) in properties {
+        encode_amf0_string(key, out);
+        encode_amf0(value, out);
+    }
+    out.extend_from_slice(&[0, 0, 9]);
+}
+
+pub fn encode_amf0(value: &Amf0, out: &mut Vec<u8>) {
+    match value {
+        Amf0::Number(number) => {
+            out.push(0);
+            out.extend_from_slice(&number.to_be_bytes());
+        }
+        Amf0::Boolean(boolean) => out.extend_from_slice(&[1, *boolean as u8]),
+        Amf0::String(string) => {
+            out.push(2);
+            encode_amf0_string(string, out);
+        }
+        Amf0::Object(properties) => {
+            out.push(3);
+            encode_amf0_properties(properties, out);
+        }
+        Amf0::Null => out.push(5),
+        Amf0::Undefined => out.push(6),
+        Amf0::EcmaArray(properties) => {
+            out.push(8);
+            out.extend_from_slice(&(properties.len() as u32).to_be_bytes());
+            encode_amf0_properties(properties, out);
+        }
+        Amf0::StrictArray(values) => {
+            out.push(10);
+            out.extend_from_slice(&(values.len() as u32).to_be_bytes());
+            for value in values {
+                encode_amf0(value, out);
+            }
+        }
+    }
+}
+
+fn object(properties: &[(&str, Amf0)]) -> Amf0 {
+    Amf0::Object(
+        properties
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+    )
+}
+
+/// A whole message, put back together from its chunks.
+pub struct Message {
+    pub type_id: u8,
+    pub stream_id: u32,
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
+}
+
+/// What's known about a chunk stream from its last chunk, which later chunks leave out.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    timestamp_delta: u32,
+    length: usize,
+    type_id: u8,
+    stream_id: u32,
+    extended_timestamp: bool,
+    payload: Vec<u8>,
+}
+
+/// Reads messages from the encoder, a chunk at a time. Counts what it has read, for acknowledgements.
+pub struct ChunkReader<R> {
+    reader: R,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+    pub bytes_read: u64,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            chunk_size: 128,
+            streams: HashMap::new(),
+            bytes_read: 0,
+        }
+    }
+
+    fn read_bytes(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; length];
+        self.reader.read_exact(&mut bytes)?;
+        self.bytes_read += length as u64;
+        Ok(bytes)
+    }
+
+    fn read_u24(&mut self) -> io::Result<u32> {
+        let bytes = self.read_bytes(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads chunks until a message is complete.
+    pub fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            let first = self.read_bytes(1)?[0];
+            let format = first >> 6;
+            let chunk_stream_id = match first & 0x3F {
+                0 => 64 + self.read_bytes(1)?[0] as u32,
+                1 => {
+                    let bytes = self.read_bytes(2)?;
+                    64 + bytes[0] as u32 + bytes[1] as u32 * 256
+                }
+                chunk_stream_id => chunk_stream_id as u32,
+            };
+
+            let mut stream = self.streams.remove(&chunk_stream_id).unwrap_or_default();
+            let starts_message = stream.payload.is_empty();
+
+            if format <= 2 {
+                let timestamp = self.read_u24()?;
+                if format <= 1 {
+                    stream.length = self.read_u24()? as usize;
+                    stream.type_id = self.read_bytes(1)?[0];
+                }
+                if format == 0 {
+                    let bytes = self.read_bytes(4)?;
+                    stream.stream_id = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                }
+
+                stream.extended_timestamp = timestamp == 0xFFFFFF;
+                let timestamp = match stream.extended_timestamp {
+                    true => self.read_u32()?,
+                    false => timestamp,
+                };
+
+                if format == 0 {
+                    stream.timestamp = timestamp;
+                    stream.timestamp_delta = 0;
+                } else {
+                    stream.timestamp_delta = timestamp;
+                    stream.timestamp = stream.timestamp.wrapping_add(timestamp);
+                }
+            } else {
+                // Type 3 chunks repeat the extended timestamp if the chunk they follow had one
+                if stream.extended_timestamp {
+                    self.read_u32()?;
+                }
+                if starts_message {
+                    stream.timestamp = stream.timestamp.wrapping_add(stream.timestamp_delta);
+                }
+            }
+
+            if stream.length > MAX_MESSAGE_BYTES {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "RTMP message too big",
+                ));
+            }
+
+            let remaining = stream.length - stream.payload.len();
+            let chunk = self.read_bytes(remaining.min(self.chunk_size))?;
+            stream.payload.extend_from_slice(&chunk);
+
+            if stream.payload.len() == stream.length {
+                let message = Message {
+                    type_id: stream.type_id,
+                    stream_id: stream.stream_id,
+                    timestamp: stream.timestamp,
+                    payload: std::mem::take(&mut stream.payload),
+                };
+                self.streams.insert(chunk_stream_id, stream);
+
+                match message.type_id {
+                    SET_CHUNK_SIZE if message.payload.len() >= 4 => {
+                        let size = u32::from_be_bytes([
+                            message.payload[0],
+                            message.payload[1],
+                            message.payload[2],
+                            message.payload[3],
+                        ]) & 0x7FFF_FFFF;
+                        self.chunk_size = (size as usize).max(1).min(MAX_MESSAGE_BYTES);
+                    }
+                    ABORT if message.payload.len() >= 4 => {
+                        let aborted = u32::from_be_bytes([
+                            message.payload[0],
+                            message.payload[1],
+                            message.payload[2],
+                            message.payload[3],
+                        ]);
+                        if let Some(aborted) = self.streams.get_mut(&aborted) {
+                            aborted.payload.clear();
+                        }
+                    }
+                    _ => {}
+                }
+
+                return Ok(message);
+            }
+
+            self.streams.insert(chunk_stream_id, stream);
+        }
+    }
+}
+
+/// Writes a message as chunks of SERVER_CHUNK_SIZE, once the encoder has been told about it.
+fn write_message(
+    writer: &mut impl Write,
+    chunk_stream_id: u8,
+    type_id: u8,
+    stream_id: u32,
+    payload: &[u8],
+    chunk_size: usize,
+) -> io::Result<()> {
+    let mut out = Vec::with_capacity(payload.len() + 16);
+    out.push(chunk_stream_id & 0x3F);
+    out.extend_from_slice(&[0, 0, 0]);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(type_id);
+    out.extend_from_slice(&stream_id.to_le_bytes());
+
+    for (index, chunk) in payload.chunks(chunk_size).enumerate() {
+        if index > 0 {
+            out.push(0xC0 | (chunk_stream_id & 0x3F));
+        }
+        out.extend_from_slice(chunk);
+    }
+
+    writer.write_all(&out)
+}
+
+/// The simple handshake, with no digest. Every encoder that matters accepts it.
+fn handshake(stream: &mut TcpStream) -> io::Result<()> {
+    let mut c0_c1 = [0; 1 + HANDSHAKE_BYTES];
+    stream.read_exact(&mut c0_c1)?;
+    if c0_c1[0] != 3 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Unsupported RTMP version",
+        ));
+    }
+
+    let mut s0_s1_s2 = Vec::with_capacity(1 + HANDSHAKE_BYTES * 2);
+    s0_s1_s2.push(3);
+    s0_s1_s2.extend_from_slice(&[0; 8]);
+    while s0_s1_s2.len() < 1 + HANDSHAKE_BYTES {
+        s0_s1_s2.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    }
+    s0_s1_s2.truncate(1 + HANDSHAKE_BYTES);
+    s0_s1_s2.extend_from_slice(&c0_c1[1..]);
+    stream.write_all(&s0_s1_s2)?;
+
+    let mut c2 = [0; HANDSHAKE_BYTES];
+    stream.read_exact(&mut c2)
+}
+
+/// Turns the encoder's audio and video into FLV segments, each starting with the stream's metadata and codec
+/// headers so it plays on its own.
+struct FlvRecorder {
+    camera_id: uuid::Uuid,
+    writer: Option<RecordingWriter>,
+    metadata: Option<Vec<u8>>,
+    video_header: Option<Vec<u8>>,
+    audio_header: Option<Vec<u8>>,
+    segment_started_at: u32,
+}
+
+fn flv_tag(tag_type: u8, timestamp: u32, data: &[u8]) -> Vec<u8> {
+    let mut tag = Vec::with_capacity(data.len() + 15);
+    tag.push(tag_type);
+    tag.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    tag.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+    tag.push((timestamp >> 24) as u8);
+    tag.extend_from_slice(&[0, 0, 0]);
+    tag.extend_from_slice(data);
+    tag.extend_from_slice(&(data.len() as u32 + 11).to_be_bytes());
+    tag
+}
+
+impl FlvRecorder {
+    fn finish(&mut self, connection: &PgConnection) {
+        if let Some(writer) = self.writer.take() {
+            let recording_id = writer.recording.recording_id;
+            if let Err(error) = writer.finish(connection) {
+                error!(
+                    "Failed to finish recording {}! The error was {}",
+                    recording_id, error
+                );
+            }
+        }
+    }
+
+    /// Finishes the current segment and starts another, beginning with what a player needs to decode it.
+    fn start_segment(&mut self, timestamp: u32, connection: &PgConnection) -> io::Result<()> {
+        self.finish(connection);
+
+        let mut writer =
+            RecordingWriter::start(self.camera_id, RTMP_SOURCE, FLV_CONTENT_TYPE, connection)
+                .map_err(|error| io::Error::new(ErrorKind::Other, error.error))?;
+        record_camera_contact(self.camera_id, connection);
+
+        // Audio and video flags are both set, players ignore whichever the stream doesn't have
+        writer.write(&[b'F', b'L', b'V', 1, 5, 0, 0, 0, 9, 0, 0, 0, 0])?;
+        if let Some(metadata) = &self.metadata {
+            writer.write(&flv_tag(FLV_SCRIPT_DATA, 0, metadata))?;
+        }
+        if let Some(video_header) = &self.video_header {
+            writer.write(&flv_tag(VIDEO, 0, video_header))?;
+        }
+        if let Some(audio_header) = &self.audio_header {
+            writer.write(&flv_tag(AUDIO, 0, audio_header))?;
+        }
+
+        self.writer = Some(writer);
+        self.segment_started_at = timestamp;
+        Ok(())
+    }
+
+    fn media(
+        &mut self,
+        type_id: u8,
+        timestamp: u32,
+        payload: &[u8],
+        connection: &PgConnection,
+    ) -> io::Result<()> {
+        if payload.len() < 2 {
+            return Ok(());
+        }
+
+        let is_video = type_id == VIDEO;
+        // AVC sequence headers and AAC audio specific configs, which every segment has to start with
+        if is_video && payload[0] & 0x0F == 7 && payload[1] == 0 {
+            self.video_header = Some(payload.to_vec());
+        } else if !is_video && payload[0] >> 4 == 10 && payload[1] == 0 {
+            self.audio_header = Some(payload.to_vec());
+        }
+
+        let is_keyframe = is_video && payload[0] >> 4 == 1;
+        let starts_segment = match &self.writer {
+            Some(writer) => is_keyframe && writer.elapsed_seconds() >= segment_seconds(),
+            // Segments start on a keyframe, unless the stream is only audio
+            None => is_keyframe || (!is_video && self.video_header.is_none()),
+        };
+
+        if starts_segment {
+            self.start_segment(timestamp, connection)?;
+        }
+
+        match &mut self.writer {
+            Some(writer) => writer.write(&flv_tag(
+                type_id,
+                timestamp.wrapping_sub(self.segment_started_at),
+                payload,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn metadata(&mut self, payload: &[u8]) -> io::Result<()> {
+        // Encoders send @setDataFrame then onMetaData, FLV files only have the onMetaData part
+        let mut data = payload;
+        let metadata = match decode_amf0(&mut data) {
+            Some(Amf0::String(name)) if name == "@setDataFrame" => data.to_vec(),
+            _ => payload.to_vec(),
+        };
+
+        if let Some(writer) = &mut self.writer {
+            writer.write(&flv_tag(FLV_SCRIPT_DATA, 0, &metadata))?;
+        }
+        self.metadata = Some(metadata);
+        Ok(())
+    }
+}
+
+fn command(values: &[Amf0]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        encode_amf0(value, &mut out);
+    }
+    out
+}
+
+fn status(level: &str, code: &str, description: &str) -> Amf0 {
+    object(&[
+        ("level", Amf0::String(level.to_string())),
+        ("code", Amf0::String(code.to_string())),
+        ("description", Amf0::String(description.to_string())),
+    ])
+}
+
+fn serve(stream: TcpStream, connection: &PgConnection) -> io::Result<()> {
+    let mut stream = stream;
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECONDS)))?;
+    handshake(&mut stream)?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = ChunkReader::new(stream);
+    let chunk_size = SERVER_CHUNK_SIZE as usize;
+    let mut window_ack_size = None;
+    let mut acknowledged = 0;
+    let mut recorder: Option<FlvRecorder> = None;
+    let mut watched: Option<WatchedStream> = None;
+
+    let result = (|| -> io::Result<()> {
+        loop {
+            let message = reader.read_message()?;
+
+            if let Some(window_ack_size) = window_ack_size {
+                if reader.bytes_read - acknowledged >= window_ack_size {
+                    acknowledged = reader.bytes_read;
+                    write_message(
+                        &mut writer,
+                        2,
+                        ACKNOWLEDGEMENT,
+                        0,
+                        &(reader.bytes_read as u32).to_be_bytes(),
+                        chunk_size,
+                    )?;
+                }
+            }
+
+            match message.type_id {
+                WINDOW_ACKNOWLEDGEMENT_SIZE if message.payload.len() >= 4 => {
+                    window_ack_size = Some(u32::from_be_bytes([
+                        message.payload[0],
+                        message.payload[1],
+                        message.payload[2],
+                        message.payload[3],
+                    ]) as u64);
+                }
+                AUDIO | VIDEO => {
+                    if let Some(watched) = &watched {
+                        watched.frame();
+                    }
+                    if let Some(recorder) = &mut recorder {
+                        recorder.media(
+                            message.type_id,
+                            message.timestamp,
+                            &message.payload,
+                            connection,
+                        )?;
+                    }
+                }
+                AMF0_DATA | AMF3_DATA => {
+                    let payload = match message.type_id {
+                        AMF3_DATA => message.payload.get(1..).unwrap_or_default(),
+                        _ => &message.payload,
+                    };
+                    if let Some(recorder) = &mut recorder {
+                        recorder.metadata(payload)?;
+                    }
+                }
+                AMF0_COMMAND | AMF3_COMMAND => {
+                    // AMF3 commands start with a byte that's always 0, then carry on in AMF0
+                    let payload = match message.type_id {
+                        AMF3_COMMAND => message.payload.get(1..).unwrap_or_default(),
+                        _ => &message.payload,
+                    };
+                    let values = decode_amf0_values(payload);
+                    let name = values.get(0).and_then(Amf0::as_str).unwrap_or_default();
+                    let transaction_id = values.get(1).and_then(Amf0::as_number).unwrap_or(0.0);
+                    let reply = |writer: &mut TcpStream, values: &[Amf0]| {
+                        write_message(
+                            writer,
+                            3,
+                            AMF0_COMMAND,
+                            message.stream_id,
+                            &command(values),
+                            chunk_size,
+                        )
+                    };
+
+                    match name {
+                        "connect" => {
+                            write_message(
+                                &mut writer,
+                                2,
+                                WINDOW_ACKNOWLEDGEMENT_SIZE,
+                                0,
+                                &WINDOW_ACK_SIZE.to_be_bytes(),
+                                128,
+                            )?;
+                            let mut peer_bandwidth = WINDOW_ACK_SIZE.to_be_bytes().to_vec();
+                            peer_bandwidth.push(2);
+                            write_message(
+                                &mut writer,
+                                2,
+                                SET_PEER_BANDWIDTH,
+                                0,
+                                &peer_bandwidth,
+                                128,
+                            )?;
+                            write_message(
+                                &mut writer,
+                                2,
+                                SET_CHUNK_SIZE,
+                                0,
+                                &SERVER_CHUNK_SIZE.to_be_bytes(),
+                                128,
+                            )?;
+
+                            let mut connected = status(
+                                "status",
+                                "NetConnection.Connect.Success",
+                                "Connection succeeded.",
+                            );
+                            if let Amf0::Object(properties) = &mut connected {
+                                properties
+                                    .push((String::from("objectEncoding"), Amf0::Number(0.0)));
+                            }
+                            reply(
+                                &mut writer,
+                                &[
+                                    Amf0::String(String::from("_result")),
+                                    Amf0::Number(transaction_id),
+                                    object(&[
+                                        ("fmsVer", Amf0::String(String::from("FMS/3,0,1,123"))),
+                                        ("capabilities", Amf0::Number(31.0)),
+                                    ]),
+                                    connected,
+                                ],
+                            )?;
+                        }
+                        "releaseStream" | "FCPublish" | "getStreamLength" => reply(
+                            &mut writer,
+                            &[
+                                Amf0::String(String::from("_result")),
+                                Amf0::Number(transaction_id),
+                                Amf0::Null,
+                                Amf0::Undefined,
+                            ],
+                        )?,
+                        "createStream" => reply(
+                            &mut writer,
+                            &[
+                                Amf0::String(String::from("_result")),
+                                Amf0::Number(transaction_id),
+                                Amf0::Null,
+                                Amf0::Number(1.0),
+                            ],
+                        )?,
+                        "publish" => {
+                            // Some encoders put options after the stream key, like key?bitrate=2500
+                            let stream_key = values
+                                .get(3)
+                                .and_then(Amf0::as_str)
+                                .unwrap_or_default()
+                                .split('?')
+                                .next()
+                                .unwrap_or_default();
+
+                            let camera_id =
+                                match stream_keys::camera_for_key(stream_key, connection) {
+                                    Ok(Some(camera_id)) => camera::get(camera_id, connection)
+                                        .optional()
+                                        .map_err(|error| io::Error::new(ErrorKind::Other, error))?
+                                        .map(|camera| camera.camera_id),
+                                    Ok(None) => None,
+                                    Err(error) => {
+                                        return Err(io::Error::new(ErrorKind::Other, error))
+                                    }
+                                };
+
+                            let camera_id = match camera_id {
+                                Some(camera_id) => camera_id,
+                                None => {
+                                    reply(
+                                        &mut writer,
+                                        &[
+                                            Amf0::String(String::from("onStatus")),
+                                            Amf0::Number(0.0),
+                                            Amf0::Null,
+                                            status(
+                                                "error",
+                                                "NetStream.Publish.BadName",
+                                                "Invalid stream key",
+                                            ),
+                                        ],
+                                    )?;
+                                    return Ok(());
+                                }
+                            };
+
+                            info!("Camera {} started pushing RTMP", camera_id);
+                            record_camera_contact(camera_id, connection);
+                            // Closing the socket ends the read the stream is stuck in
+                            let socket = writer.try_clone()?;
+                            watched = Some(ingest_watchdog::watch(
+                                camera_id,
+                                RTMP_SOURCE,
+                                Some(move || {
+                                    let _ = socket.shutdown(Shutdown::Both);
+                                }),
+                            ));
+                            recorder = Some(FlvRecorder {
+                                camera_id,
+                                writer: None,
+                                metadata: None,
+                                video_header: None,
+                                audio_header: None,
+                                segment_started_at: 0,
+                            });
+
+                            reply(
+                                &mut writer,
+                                &[
+                                    Amf0::String(String::from("onStatus")),
+                                    Amf0::Number(0.0),
+                                    Amf0::Null,
+                                    status("status", "NetStream.Publish.Start", "Publishing."),
+                                ],
+                            )?;
+                        }
+                        "FCUnpublish" | "deleteStream" | "closeStream" => return Ok(()),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    })();
+
+    if let Some(recorder) = &mut recorder {
+        info!("Camera {} stopped pushing RTMP", recorder.camera_id);
+        recorder.finish(connection);
+    }
+
+    result
+}
+
+/// Starts taking RTMP on rtmp_port in [ingest], if it's set. Each encoder gets its own thread and database
+/// connection, like RTSP clients. The server is restarted if it stops, see ingest_watchdog::supervise().
+pub fn spawn_rtmp_server(database_url: String) {
+    let port = match rtmp_port() {
+        Some(port) => port,
+        None => return,
+    };
+    let listener = TcpListener::bind(("0.0.0.0", port)).expect("Failed to bind RTMP server!");
+
+    ingest_watchdog::supervise(RTMP_SOURCE, move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    error!("Failed to accept RTMP connection! The error was {}", error);
+                    continue;
+                }
+            };
+            let database_url = database_url.clone();
+
+            thread::spawn(move || {
+                let connection = match PgConnection::establish(&database_url) {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!(
+                            "Failed to connect to the database for RTMP! The error was {}",
+                            error
+                        );
+                        return;
+                    }
+                };
+
+                if let Err(error) = serve(stream, &connection) {
+                    debug!("RTMP connection closed with {}", error);
+                }
+            });
+        }
+    });
+}
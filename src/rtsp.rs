@@ -0,0 +1,945 @@
+use crate::{
+    camera::{cached_latest_image_id, Camera},
+    media_store::{media_store, MediaStore},
+    onvif::{self, OnvifCredential},
+    plan,
+    settings::settings,
+    usage::{self, StreamUsage},
+};
+
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use md5::{Digest, Md5};
+use rocket::http::Status;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+pub const REALM: &str = "camera-server";
+
+/// Requests with a bigger head than this close the connection.
+pub const MAX_REQUEST_HEAD_BYTES: usize = 8192;
+
+/// How much of a frame goes in each RTP packet, so packets fit in one TCP segment on most links.
+pub const MAX_PAYLOAD_BYTES: usize = 1400;
+
+/// Sent with SETUP. Clients keep the session alive with GET_PARAMETER, and a connection that sends nothing for this
+/// long is closed.
+pub const SESSION_TIMEOUT_SECONDS: u64 = 60;
+
+/// The RTP payload type for JPEG, see RFC 2435.
+const JPEG_PAYLOAD_TYPE: u8 = 26;
+
+/// RTP timestamps for video count at 90kHz.
+const RTP_CLOCK_RATE: u64 = 90_000;
+
+/// The port to serve RTSP on, set with rtsp_port in [onvif]. Defaults to none, which turns the ONVIF facade off.
+pub fn rtsp_port() -> Option<u16> {
+    settings().onvif.rtsp_port
+}
+
+pub fn frame_interval() -> Duration {
+    Duration::from_millis(settings().onvif.frame_interval_milliseconds)
+}
+
+/// A baseline JPEG taken apart the way RFC 2435 sends it. Only 4:2:0 and 4:2:2 colour images with 8-bit
+/// quantization tables and the standard Huffman tables can be sent, which is what cameras almost always make.
+pub struct JpegFrame<'a> {
+    pub width: u16,
+    pub height: u16,
+    /// 0 for 4:2:2, 1 for 4:2:0.
+    pub kind: u8,
+    pub restart_interval: u16,
+    /// The luma table then the chroma table, 64 bytes each in zigzag order.
+    pub quantization_tables: Vec<u8>,
+    /// The entropy coded data, from after the start of scan header up to the end of image marker.
+    pub scan: &'a [u8],
+}
+
+/// Takes a JPEG apart for sending, see JpegFrame. Returns None for anything that can't be sent.
+pub fn parse_jpeg(image: &[u8]) -> Option<JpegFrame> {
+    if !image.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut tables: HashMap<u8, &[u8]> = HashMap::new();
+    let mut dimensions = None;
+    let mut kind = None;
+    let mut restart_interval = 0;
+    let mut offset = 2;
+
+    loop {
+        if *image.get(offset)? != 0xFF {
+            return None;
+        }
+        let marker = *image.get(offset + 1)?;
+        // Fill bytes can come before any marker
+        if marker == 0xFF {
+            offset += 1;
+            continue;
+        }
+        let length =
+            u16::from_be_bytes([*image.get(offset + 2)?, *image.get(offset + 3)?]) as usize;
+        let segment = image.get(offset + 4..offset + 2 + length)?;
+
+        match marker {
+            // Define quantization tables, which can hold more than one
+            0xDB => {
+                let mut rest = segment;
+                while !rest.is_empty() {
+                    // Only 8-bit precision tables can be sent with Q=255 without converting them
+                    if rest[0] >> 4 != 0 {
+                        return None;
+                    }
+                    tables.insert(rest[0] & 0x0F, rest.get(1..65)?);
+                    rest = rest.get(65..)?;
+                }
+            }
+            // Start of frame, baseline or extended sequential
+            0xC0 | 0xC1 => {
+                let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]);
+                let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]);
+                if *segment.get(5)? != 3 {
+                    return None;
+                }
+                let components = segment.get(6..15)?;
+                if components[4] != 0x11 || components[7] != 0x11 {
+                    return None;
+                }
+                kind = match components[1] {
+                    0x21 => Some(0),
+                    0x22 => Some(1),
+                    _ => return None,
+                };
+                dimensions = Some((width, height));
+            }
+            // Progressive, lossless and arithmetic coded frames
+            0xC2..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => return None,
+            0xDD => restart_interval = u16::from_be_bytes([*segment.get(0)?, *segment.get(1)?]),
+            // Start of scan, everything after the header up to the end of image marker is the scan
+            0xDA => {
+                let scan_start = offset + 2 + length;
+                let scan_end = image
+                    .windows(2)
+                    .rposition(|bytes| bytes == [0xFF, 0xD9])
+                    .filter(|end| *end >= scan_start)?;
+                let (width, height) = dimensions?;
+
+                // Sizes are sent in multiples of 8 pixels, in a byte each
+                if width == 0 || height == 0 || width > 2040 || height > 2040 {
+                    return None;
+                }
+
+                let mut quantization_tables = Vec::with_capacity(128);
+                quantization_tables.extend_from_slice(tables.get(&0)?);
+                quantization_tables.extend_from_slice(tables.get(&1)?);
+
+                return Some(JpegFrame {
+                    width,
+                    height,
+                    kind: kind?,
+                    restart_interval,
+                    quantization_tables,
+                    scan: &image[scan_start..scan_end],
+                });
+            }
+            _ => {}
+        }
+
+        offset += 2 + length;
+    }
+}
+
+/// The image's width and height, if it's a JPEG that can be streamed.
+pub fn jpeg_dimensions(image: &[u8]) -> Option<(u16, u16)> {
+    parse_jpeg(image).map(|frame| (frame.width, frame.height))
+}
+
+/// Splits the frame into RTP packets, see RFC 2435. The first has the quantization tables, and the last has the
+/// marker bit set.
+pub fn packetize(
+    frame: &JpegFrame,
+    sequence_number: &mut u16,
+    timestamp: u32,
+    ssrc: u32,
+) -> Vec<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut fragment_offset = 0;
+
+    while fragment_offset < frame.scan.len() {
+        let mut packet = Vec::with_capacity(MAX_PAYLOAD_BYTES + 160);
+
+        let fragment_end = (fragment_offset + MAX_PAYLOAD_BYTES).min(frame.scan.len());
+        let is_last = fragment_end == frame.scan.len();
+
+        packet.push(0x80);
+        packet.push(((is_last as u8) << 7) | JPEG_PAYLOAD_TYPE);
+        packet.extend_from_slice(&sequence_number.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&ssrc.to_be_bytes());
+        *sequence_number = sequence_number.wrapping_add(1);
+
+        // The JPEG header, Q=255 says the tables are sent in band
+        packet.push(0);
+        packet.extend_from_slice(&(fragment_offset as u32).to_be_bytes()[1..]);
+        packet.push(match frame.restart_interval {
+            0 => frame.kind,
+            _ => frame.kind + 64,
+        });
+        packet.push(255);
+        packet.push(((frame.width + 7) / 8) as u8);
+        packet.push(((frame.height + 7) / 8) as u8);
+
+        if frame.restart_interval != 0 {
+            packet.extend_from_slice(&frame.restart_interval.to_be_bytes());
+            // First and last set with a count of 0x3FFF, as the frame isn't split on restart markers
+            packet.extend_from_slice(&[0xFF, 0xFF]);
+        }
+
+        if fragment_offset == 0 {
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&(frame.quantization_tables.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&frame.quantization_tables);
+        }
+
+        packet.extend_from_slice(&frame.scan[fragment_offset..fragment_end]);
+        packets.push(packet);
+        fragment_offset = fragment_end;
+    }
+
+    packets
+}
+
+struct RtspRequest {
+    method: String,
+    uri: String,
+    headers: HashMap<String, String>,
+}
+
+impl RtspRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(String::as_str)
+    }
+}
+
+enum Message {
+    Request(RtspRequest),
+    /// RTCP from the client, interleaved on the connection. Nothing is done with it.
+    Interleaved,
+}
+
+/// Takes the next request or interleaved packet off the front of the buffer, once all of it has arrived.
+fn next_message(buffer: &mut Vec<u8>) -> io::Result<Option<Message>> {
+    if buffer.first() == Some(&b'$') {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+        let length = 4 + u16::from_be_bytes([buffer[2], buffer[3]]) as usize;
+        if buffer.len() < length {
+            return Ok(None);
+        }
+        buffer.drain(..length);
+        return Ok(Some(Message::Interleaved));
+    }
+
+    let head_end = match buffer.windows(4).position(|bytes| bytes == b"\r\n\r\n") {
+        Some(head_end) => head_end,
+        None if buffer.len() > MAX_REQUEST_HEAD_BYTES => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Request head too big",
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let uri = request_line.next().unwrap_or_default().to_string();
+
+    let headers = lines
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            Some((
+                parts.next()?.trim().to_lowercase(),
+                parts.next()?.trim().to_string(),
+            ))
+        })
+        .collect::<HashMap<_, _>>();
+
+    // GET_PARAMETER and SET_PARAMETER can have a body, which is skipped
+    let body_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    if buffer.len() < head_end + 4 + body_length {
+        return Ok(None);
+    }
+    buffer.drain(..head_end + 4 + body_length);
+
+    Ok(Some(Message::Request(RtspRequest {
+        method,
+        uri,
+        headers,
+    })))
+}
+
+fn md5_hex(text: &str) -> String {
+    hex::encode(Md5::digest(text.as_bytes()))
+}
+
+/// MD5 of username:realm:password, which is all that's kept of ONVIF passwords. RTSP digests are checked
+/// against it.
+pub fn ha1(username: &str, password: &str) -> String {
+    md5_hex(&format!("{}:{}:{}", username, REALM, password))
+}
+
+/// Compares hashes in constant time, so how long it takes doesn't give away how much of one was right.
+pub fn hashes_match(hash: &str, expected: &str) -> bool {
+    hash.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Splits a Digest Authorization header into its parameters.
+fn digest_parameters(header: &str) -> HashMap<String, String> {
+    header
+        .split(',')
+        .filter_map(|parameter| {
+            let mut parts = parameter.splitn(2, '=');
+            Some((
+                parts.next()?.trim().to_lowercase(),
+                parts.next()?.trim().trim_matches('"').to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Checks the request's Basic or Digest credentials, see onvif::find_credential(). Digests have to be for this
+/// connection's nonce and for the URI in the request line, so they can't be replayed against another camera.
+fn authenticate(
+    request: &RtspRequest,
+    nonce: &str,
+    connection: &PgConnection,
+) -> Option<OnvifCredential> {
+    let authorization = request.header("Authorization")?;
+
+    if let Some(encoded) = authorization.strip_prefix("Basic ") {
+        let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        let credential = find_credential(parts.next()?, connection)?;
+
+        return match credential.password_matches(parts.next()?) {
+            true => Some(credential),
+            false => None,
+        };
+    }
+
+    let parameters = digest_parameters(authorization.strip_prefix("Digest ")?);
+    if parameters.get("nonce").map(String::as_str) != Some(nonce)
+        || parameters.get("uri") != Some(&request.uri)
+    {
+        return None;
+    }
+    let credential = find_credential(parameters.get("username")?, connection)?;
+
+    let ha2 = md5_hex(&format!("{}:{}", request.method, request.uri));
+    let expected = md5_hex(&format!("{}:{}:{}", credential.ha1, nonce, ha2));
+
+    match hashes_match(parameters.get("response")?, &expected) {
+        true => Some(credential),
+        false => None,
+    }
+}
+
+fn find_credential(username: &str, connection: &PgConnection) -> Option<OnvifCredential> {
+    onvif::find_credential(username, connection)
+        .map_err(|error| {
+            error!(
+                "Failed to get ONVIF credentials for RTSP! The error was {}",
+                error
+            )
+        })
+        .ok()
+        .flatten()
+}
+
+/// The camera ID from rtsp://host:port/<camera_id>, or from a track under it like /<camera_id>/track1.
+fn camera_id_from_uri(uri: &str) -> Option<uuid::Uuid> {
+    let path = match uri.find("://") {
+        Some(scheme_end) => &uri[scheme_end + 3..],
+        None => uri,
+    };
+    let path = &path[path.find('/')? + 1..];
+
+    uuid::Uuid::parse_str(path.split(|c| c == '/' || c == '?').next()?).ok()
+}
+
+/// One RTSP client and what it has set up so far.
+struct Session {
+    stream: TcpStream,
+    nonce: String,
+    session_id: String,
+    camera: Option<Camera>,
+    /// The interleaved channel RTP goes out on, once the client has sent SETUP.
+    channel: Option<u8>,
+    playing: bool,
+    /// Counts the session towards the credential's user's max_streams once it's playing, until it ends.
+    usage: Option<StreamUsage>,
+    sequence_number: u16,
+    ssrc: u32,
+    started_at: Instant,
+    last_request_at: Instant,
+}
+
+impl Session {
+    fn respond(
+        &mut self,
+        request: &RtspRequest,
+        status: &str,
+        headers: &[(&str, String)],
+        body: Option<(&str, String)>,
+    ) -> io::Result<()> {
+        let mut response = format!("RTSP/1.0 {}\r\n", status);
+        if let Some(cseq) = request.header("CSeq") {
+            response.push_str(&format!("CSeq: {}\r\n", cseq));
+        }
+        response.push_str("Server: camera-server\r\n");
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        match body {
+            Some((content_type, body)) => {
+                response.push_str(&format!(
+                    "Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                ));
+            }
+            None => response.push_str("\r\n"),
+        }
+
+        self.stream.write_all(response.as_bytes())
+    }
+
+    fn unauthorized(&mut self, request: &RtspRequest) -> io::Result<()> {
+        let challenges = [
+            (
+                "WWW-Authenticate",
+                format!("Digest realm=\"{}\", nonce=\"{}\"", REALM, self.nonce),
+            ),
+            ("WWW-Authenticate", format!("Basic realm=\"{}\"", REALM)),
+        ];
+        self.respond(request, "401 Unauthorized", &challenges, None)
+    }
+
+    /// Answers a request. Returns false once the client has torn the session down.
+    fn handle(&mut self, request: RtspRequest, connection: &PgConnection) -> io::Result<bool> {
+        self.last_request_at = Instant::now();
+
+        if request.method == "OPTIONS" {
+            let public = (
+                "Public",
+                String::from(
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER, SET_PARAMETER",
+                ),
+            );
+            self.respond(&request, "200 OK", &[public], None)?;
+            return Ok(true);
+        }
+
+        let credential = match authenticate(&request, &self.nonce, connection) {
+            Some(credential) => credential,
+            None => {
+                self.unauthorized(&request)?;
+                return Ok(true);
+            }
+        };
+        let camera = match camera_id_from_uri(&request.uri)
+            .map(|camera_id| onvif::credential_camera(&credential, camera_id, connection))
+        {
+            Some(Ok(Some(camera))) => camera,
+            Some(Err(error)) => {
+                error!("Failed to get camera for RTSP! The error was {}", error);
+                self.respond(&request, "500 Internal Server Error", &[], None)?;
+                return Ok(true);
+            }
+            _ => {
+                self.respond(&request, "404 Not Found", &[], None)?;
+                return Ok(true);
+            }
+        };
+        let session = (
+            "Session",
+            format!("{};timeout={}", self.session_id, SESSION_TIMEOUT_SECONDS),
+        );
+
+        match request.method.as_str() {
+            "DESCRIBE" => {
+                let base = request.uri.trim_end_matches('/').to_string();
+                let sdp = format!(
+                    "v=0\r\no=- {} 1 IN IP4 0.0.0.0\r\ns={}\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\na=control:*\r\n\
+                     m=video 0 RTP/AVP {}\r\na=rtpmap:{} JPEG/{}\r\na=control:track1\r\na=framerate:{:.2}\r\n",
+                    self.ssrc,
+                    camera.name.replace(|c: char| c.is_control(), " "),
+                    JPEG_PAYLOAD_TYPE,
+                    JPEG_PAYLOAD_TYPE,
+                    RTP_CLOCK_RATE,
+                    1000.0 / settings().onvif.frame_interval_milliseconds as f64,
+                );
+                self.respond(
+                    &request,
+                    "200 OK",
+                    &[("Content-Base", format!("{}/", base))],
+                    Some(("application/sdp", sdp)),
+                )?;
+            }
+            "SETUP" => {
+                // RTP over UDP would need a port for every client, and doesn't get through NAT, so it's TCP only
+                let transport = request.header("Transport").unwrap_or_default();
+                let channel = transport
+                    .split(',')
+                    .find(|transport| transport.starts_with("RTP/AVP/TCP"))
+                    .and_then(|transport| {
+                        transport
+                            .split(';')
+                            .find_map(|parameter| parameter.strip_prefix("interleaved="))
+                            .map(|channels| channels.split('-').next().unwrap_or("0").parse::<u8>())
+                            .unwrap_or(Ok(0))
+                            .ok()
+                    });
+
+                match channel {
+                    Some(channel) => {
+                        self.channel = Some(channel);
+                        self.camera = Some(camera);
+                        let transport = (
+                            "Transport",
+                            format!(
+                                "RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                                channel,
+                                channel.wrapping_add(1),
+                                self.ssrc
+                            ),
+                        );
+                        self.respond(&request, "200 OK", &[transport, session], None)?;
+                    }
+                    None => self.respond(&request, "461 Unsupported Transport", &[], None)?,
+                }
+            }
+            "PLAY" => match self.camera.as_ref().map(|set_up| set_up.camera_id) {
+                Some(camera_id) if camera_id == camera.camera_id => {
+                    if self.usage.is_none() {
+                        match plan::start_stream(credential.user_id, usage::RTSP_STREAM, connection)
+                        {
+                            Ok(usage) => self.usage = Some(usage),
+                            Err(error) if error.status == Status::PaymentRequired => {
+                                self.respond(&request, "453 Not Enough Bandwidth", &[], None)?;
+                                return Ok(true);
+                            }
+                            Err(_) => {
+                                self.respond(&request, "500 Internal Server Error", &[], None)?;
+                                return Ok(true);
+                            }
+                        }
+                    }
+
+                    self.playing = true;
+                    let rtp_info = (
+                        "RTP-Info",
+                        format!(
+                            "url={}/track1;seq={};rtptime={}",
+                            request
+                                .uri
+                                .trim_end_matches('/')
+                                .trim_end_matches("/track1"),
+                            self.sequence_number,
+                            self.timestamp()
+                        ),
+                    );
+                    let range = ("Range", String::from("npt=now-"));
+                    self.respond(&request, "200 OK", &[session, range, rtp_info], None)?;
+                }
+                _ => self.respond(&request, "455 Method Not Valid in This State", &[], None)?,
+            },
+            "TEARDOWN" => {
+                self.respond(&request, "200 OK", &[session], None)?;
+                return Ok(false);
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => {
+                self.respond(&request, "200 OK", &[session], None)?
+            }
+            _ => self.respond(&request, "501 Not Implemented", &[], None)?,
+        }
+
+        Ok(true)
+    }
+
+    fn timestamp(&self) -> u32 {
+        (self.started_at.elapsed().as_millis() as u64 * RTP_CLOCK_RATE / 1000) as u32
+    }
+
+    fn send_frame(&mut self, image: &[u8]) -> io::Result<()> {
+        let channel = match self.channel {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let frame = match parse_jpeg(image) {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let timestamp = self.timestamp();
+
+        for packet in packetize(&frame, &mut self.sequence_number, timestamp, self.ssrc) {
+            let mut interleaved = Vec::with_capacity(packet.len() + 4);
+            interleaved.push(b'$');
+            interleaved.push(channel);
+            interleaved.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            interleaved.extend_from_slice(&packet);
+            self.stream.write_all(&interleaved)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The camera's latest image, reusing the one already sent if it hasn't changed.
+fn latest_image(camera_id: &uuid::Uuid, last: &mut Option<(u64, Vec<u8>)>) {
+    let image_id = match cached_latest_image_id(camera_id) {
+        Some(image_id) => image_id,
+        None => return,
+    };
+    if matches!(last, Some((last_id, _)) if *last_id == image_id) {
+        return;
+    }
+
+    let mut image = Vec::new();
+    match media_store()
+        .open_image(camera_id, image_id)
+        .and_then(|mut file| file.read_to_end(&mut image))
+    {
+        Ok(_) => *last = Some((image_id, image)),
+        Err(error) => warn!(
+            "Failed to read image {} from camera {} for RTSP! The error was {}",
+            image_id, camera_id, error
+        ),
+    }
+}
+
+fn serve(stream: TcpStream, connection: &PgConnection) -> io::Result<()> {
+    // Short, so frames still go out on time while waiting for requests
+    stream.set_read_timeout(Some(Duration::from_millis(50)))?;
+
+    let mut session = Session {
+        stream,
+        nonce: uuid::Uuid::new_v4().simple().to_string(),
+        session_id: uuid::Uuid::new_v4().simple().to_string()[..16].to_string(),
+        camera: None,
+        channel: None,
+        playing: false,
+        usage: None,
+        sequence_number: 0,
+        ssrc: rand_u32(),
+        started_at: Instant::now(),
+        last_request_at: Instant::now(),
+    };
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    let mut last_image = None;
+    let mut next_frame_at = Instant::now();
+
+    loop {
+        match session.stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {
+            }
+            Err(error) => return Err(error),
+        }
+
+        while let Some(message) = next_message(&mut buffer)? {
+            session.last_request_at = Instant::now();
+            if let Message::Request(request) = message {
+                if !session.handle(request, connection)? {
+                    return Ok(());
+                }
+            }
+        }
+
+        if session.last_request_at.elapsed() > Duration::from_secs(SESSION_TIMEOUT_SECONDS) {
+            return Ok(());
+        }
+
+        if session.playing && Instant::now() >= next_frame_at {
+            next_frame_at = Instant::now() + frame_interval();
+            let camera_id = session
+                .camera
+                .as_ref()
+                .expect("Sessions only play once they're set up")
+                .camera_id;
+            latest_image(&camera_id, &mut last_image);
+
+            if let Some((_, image)) = &last_image {
+                session.send_frame(image)?;
+            }
+        }
+    }
+}
+
+/// An SSRC that's unlikely to clash with another stream's, from a random UUID.
+fn rand_u32() -> u32 {
+    let bytes = uuid::Uuid::new_v4();
+    let bytes = bytes.as_bytes();
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Starts serving RTSP on rtsp_port in [onvif], if it's set. Each client gets its own thread and database
+/// connection, like the realtime server's.
+pub fn spawn_rtsp_server(database_url: String) {
+    let port = match rtsp_port() {
+        Some(port) => port,
+        None => return,
+    };
+    let listener = TcpListener::bind(("0.0.0.0", port)).expect("Failed to bind RTSP server!");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    error!("Failed to accept RTSP connection! The error was {}", error);
+                    continue;
+                }
+            };
+            let database_url = database_url.clone();
+
+            thread::spawn(move || {
+                let connection = match PgConnection::establish(&database_url) {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!(
+                            "Failed to connect to the database for RTSP! The error was {}",
+                            error
+                        );
+                        return;
+                    }
+                };
+
+                if let Err(error) = serve(stream, &connection) {
+                    debug!("RTSP connection closed with {}", error);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCAN: [u8; 4] = [0x12, 0x34, 0xFF, 0x00];
+
+    /// A 4:2:0 640x480 baseline JPEG, with `extra` segments after the quantization tables and SCAN as the scan.
+    fn jpeg(frame_marker: u8, extra: &[u8]) -> Vec<u8> {
+        let mut image = vec![0xFF, 0xD8];
+
+        image.extend_from_slice(&[0xFF, 0xDB, 0x00, 0x84, 0x00]);
+        image.extend_from_slice(&[1; 64]);
+        image.push(0x01);
+        image.extend_from_slice(&[2; 64]);
+
+        image.extend_from_slice(extra);
+
+        image.extend_from_slice(&[0xFF, frame_marker, 0x00, 0x11, 0x08, 0x01, 0xE0, 0x02, 0x80]);
+        image.extend_from_slice(&[3, 1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1]);
+
+        image.extend_from_slice(&[
+            0xFF, 0xDA, 0x00, 0x0C, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0,
+        ]);
+        image.extend_from_slice(&SCAN);
+        image.extend_from_slice(&[0xFF, 0xD9]);
+
+        image
+    }
+
+    #[test]
+    fn parses_a_baseline_jpeg() {
+        let image = jpeg(0xC0, &[]);
+        let frame = parse_jpeg(&image).expect("Failed to parse the JPEG!");
+
+        assert_eq!((frame.width, frame.height), (640, 480));
+        assert_eq!(frame.kind, 1);
+        assert_eq!(frame.restart_interval, 0);
+        assert_eq!(&frame.quantization_tables[..64], &[1; 64][..]);
+        assert_eq!(&frame.quantization_tables[64..], &[2; 64][..]);
+        assert_eq!(frame.scan, &SCAN[..]);
+    }
+
+    #[test]
+    fn reads_the_restart_interval() {
+        let image = jpeg(0xC0, &[0xFF, 0xDD, 0x00, 0x04, 0x00, 0x10]);
+
+        assert_eq!(
+            parse_jpeg(&image).map(|frame| frame.restart_interval),
+            Some(16)
+        );
+    }
+
+    #[test]
+    fn skips_fill_bytes_before_markers() {
+        let image = jpeg(0xC0, &[0xFF, 0xFF, 0xFF, 0xFE, 0x00, 0x02]);
+
+        assert!(parse_jpeg(&image).is_some());
+    }
+
+    #[test]
+    fn rejects_progressive_jpegs() {
+        assert!(parse_jpeg(&jpeg(0xC2, &[])).is_none());
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_jpeg() {
+        assert!(parse_jpeg(b"not a jpeg").is_none());
+        assert!(parse_jpeg(&[]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_jpegs_without_panicking() {
+        let image = jpeg(0xC0, &[]);
+
+        for length in 0..image.len() - 2 {
+            assert!(parse_jpeg(&image[..length]).is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_segment_lengths_shorter_than_the_length_itself() {
+        let image = jpeg(0xC0, &[0xFF, 0xFE, 0x00, 0x01]);
+
+        assert!(parse_jpeg(&image).is_none());
+    }
+
+    #[test]
+    fn splits_frames_into_packets() {
+        let scan = vec![0xAB; MAX_PAYLOAD_BYTES * 2 + 10];
+        let frame = JpegFrame {
+            width: 640,
+            height: 480,
+            kind: 1,
+            restart_interval: 0,
+            quantization_tables: vec![1; 128],
+            scan: &scan,
+        };
+        let mut sequence_number = u16::MAX;
+
+        let packets = packetize(&frame, &mut sequence_number, 90_000, 7);
+
+        assert_eq!(packets.len(), 3);
+        assert_eq!(sequence_number, 2);
+        // Only the last packet has the marker bit set
+        assert_eq!(packets[0][1], JPEG_PAYLOAD_TYPE);
+        assert_eq!(packets[2][1], 0x80 | JPEG_PAYLOAD_TYPE);
+        // The RTP header, the JPEG header and the quantization tables come before the first fragment
+        assert_eq!(packets[0].len(), 12 + 8 + 4 + 128 + MAX_PAYLOAD_BYTES);
+        assert_eq!(packets[2].len(), 12 + 8 + 10);
+        // Fragment offsets are 24-bit
+        assert_eq!(
+            &packets[1][13..16],
+            &(MAX_PAYLOAD_BYTES as u32).to_be_bytes()[1..]
+        );
+        // Sizes are in 8 pixel blocks
+        assert_eq!((packets[0][18], packets[0][19]), (80, 60));
+    }
+
+    #[test]
+    fn waits_for_whole_requests() {
+        let mut buffer = b"OPTIONS rtsp://localhost/ RTSP/1.0\r\nCSeq: 1\r\n".to_vec();
+        assert!(next_message(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(b"\r\nDESCRIBE");
+        match next_message(&mut buffer).unwrap() {
+            Some(Message::Request(request)) => {
+                assert_eq!(request.method, "OPTIONS");
+                assert_eq!(request.uri, "rtsp://localhost/");
+                assert_eq!(request.header("cseq"), Some("1"));
+            }
+            _ => panic!("Expected a request!"),
+        }
+        assert_eq!(buffer, b"DESCRIBE");
+    }
+
+    #[test]
+    fn skips_interleaved_packets() {
+        let mut buffer = vec![b'$', 1, 0, 2, 0xAA];
+        assert!(next_message(&mut buffer).unwrap().is_none());
+
+        buffer.push(0xBB);
+        assert!(matches!(
+            next_message(&mut buffer).unwrap(),
+            Some(Message::Interleaved)
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_request_heads_that_are_too_big() {
+        let mut buffer = vec![b'A'; MAX_REQUEST_HEAD_BYTES + 1];
+
+        assert!(next_message(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn hashes_digest_credentials() {
+        assert_eq!(ha1("admin", "secret"), "87413a95930286aee3d119f0965cf9c2");
+        assert!(hashes_match(
+            &ha1("admin", "secret"),
+            "87413a95930286aee3d119f0965cf9c2"
+        ));
+        assert!(!hashes_match(
+            &ha1("admin", "wrong"),
+            "87413a95930286aee3d119f0965cf9c2"
+        ));
+        assert!(!hashes_match(
+            "87413a95",
+            "87413a95930286aee3d119f0965cf9c2"
+        ));
+    }
+
+    #[test]
+    fn reads_digest_parameters() {
+        let parameters = digest_parameters(
+            r#"username="admin", realm="camera-server", uri="rtsp://host/a", response=abc"#,
+        );
+
+        assert_eq!(
+            parameters.get("username").map(String::as_str),
+            Some("admin")
+        );
+        assert_eq!(
+            parameters.get("uri").map(String::as_str),
+            Some("rtsp://host/a")
+        );
+        assert_eq!(parameters.get("response").map(String::as_str), Some("abc"));
+    }
+
+    #[test]
+    fn finds_the_camera_in_uris() {
+        let camera_id = uuid::Uuid::new_v4();
+
+        for uri in &[
+            format!("rtsp://localhost:8554/{}", camera_id),
+            format!("rtsp://localhost:8554/{}/track1", camera_id),
+            format!("/{}?profile=main", camera_id),
+        ] {
+            assert_eq!(camera_id_from_uri(uri), Some(camera_id));
+        }
+        assert_eq!(camera_id_from_uri("rtsp://localhost:8554/nope"), None);
+        assert_eq!(camera_id_from_uri("rtsp://localhost:8554"), None);
+    }
+}
@@ -0,0 +1,699 @@
+use crate::{
+    api_error::ApiError,
+    camera::parse_camera_id,
+    event::{
+        default_severity, meets_severity, validate_severity, Event, EVENT_TYPES, INFO_SEVERITY,
+    },
+    mode::{current_mode, validate_mode},
+    notification::{EMAIL_CHANNEL, PUSH_CHANNEL, SMS_CHANNEL, TRIGGER_CHANNEL},
+    page::parse_updated_since,
+    patch,
+    trigger::validate_trigger_url,
+    user_tokens::UserToken,
+    users_cameras::{check_if_user_has_access_to_camera, in_owners_tenant},
+    CameraServerDbConn,
+};
+
+use super::schema::{rules, users_cameras};
+use chrono::{DateTime, NaiveTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, get, patch, post, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How long rules group events for if the user doesn't say, long enough to cover someone walking around a garden.
+pub const DEFAULT_COOLDOWN_SECONDS: i32 = 300;
+
+/// "If <event type> on <camera> between <start> and <end>, then notify me on <channels>".
+/// Rules are checked for every event, on top of the user's notification preferences.
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "rules"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct Rule {
+    pub rule_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    /// None means every camera the user has access to.
+    #[schemars(with = "Option<String>")]
+    pub camera_id: Option<uuid::Uuid>,
+    pub event_types: Vec<String>,
+    pub min_confidence: f32,
+    /// The time window (UTC) the rule applies in. Windows where end_time is before start_time wrap over midnight.
+    /// None means all day.
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    /// Notification channels to send on when the rule matches (push, email and/or sms).
+    pub channels: Vec<String>,
+    pub enabled: bool,
+    /// The modes the rule applies in. Empty means every mode.
+    pub modes: Vec<String>,
+    /// Only events at least this severe match.
+    pub min_severity: String,
+    /// Events that match within this many seconds of the rule's last notification are grouped into it. 0 turns grouping off.
+    pub cooldown_seconds: i32,
+    /// A URL (e.g. an IFTTT Webhooks or Zapier catch hook URL) that a flat JSON summary of the event is POSTed to
+    /// when the rule matches, see TriggerPayload. Cooldowns apply to it like any other channel.
+    pub trigger_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Kept up to date by a trigger. Pass it as ?updated_since= to only get what has changed since.
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "rules"]
+pub struct InsertableRule {
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub camera_id: Option<uuid::Uuid>,
+    pub event_types: Vec<String>,
+    pub min_confidence: f32,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    pub channels: Vec<String>,
+    pub enabled: bool,
+    pub modes: Vec<String>,
+    pub min_severity: String,
+    pub cooldown_seconds: i32,
+    pub trigger_url: Option<String>,
+}
+
+/// A rule as sent by the user when creating or updating it. Times are HH:MM:SS.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewRule {
+    pub name: String,
+    pub camera_id: Option<String>,
+    pub event_types: Vec<String>,
+    pub min_confidence: f32,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    pub channels: Vec<String>,
+    pub enabled: bool,
+    pub modes: Vec<String>,
+    /// Defaults to info, i.e. every event.
+    pub min_severity: Option<String>,
+    /// Defaults to DEFAULT_COOLDOWN_SECONDS.
+    pub cooldown_seconds: Option<i32>,
+    pub trigger_url: Option<String>,
+}
+
+/// A partial update to a rule, sent with PATCH /Rules/<rule_id>. Missing fields are left as they are,
+/// and camera_id, start_time, end_time and trigger_url can be cleared by sending null.
+#[derive(Deserialize, JsonSchema)]
+pub struct UpdateRule {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "patch::double_option")]
+    pub camera_id: Option<Option<String>>,
+    pub event_types: Option<Vec<String>>,
+    pub min_confidence: Option<f32>,
+    #[serde(default, deserialize_with = "patch::double_option")]
+    pub start_time: Option<Option<NaiveTime>>,
+    #[serde(default, deserialize_with = "patch::double_option")]
+    pub end_time: Option<Option<NaiveTime>>,
+    pub channels: Option<Vec<String>>,
+    pub enabled: Option<bool>,
+    pub modes: Option<Vec<String>>,
+    pub min_severity: Option<String>,
+    pub cooldown_seconds: Option<i32>,
+    #[serde(default, deserialize_with = "patch::double_option")]
+    pub trigger_url: Option<Option<String>>,
+}
+
+/// The columns an UpdateRule changes. None fields are left alone.
+#[derive(AsChangeset)]
+#[table_name = "rules"]
+pub struct RuleChangeset {
+    pub name: Option<String>,
+    pub camera_id: Option<Option<uuid::Uuid>>,
+    pub event_types: Option<Vec<String>>,
+    pub min_confidence: Option<f32>,
+    pub start_time: Option<Option<NaiveTime>>,
+    pub end_time: Option<Option<NaiveTime>>,
+    pub channels: Option<Vec<String>>,
+    pub enabled: Option<bool>,
+    pub modes: Option<Vec<String>>,
+    pub min_severity: Option<String>,
+    pub cooldown_seconds: Option<i32>,
+    pub trigger_url: Option<Option<String>>,
+}
+
+impl UpdateRule {
+    /// Returns the rule as it would be after the update, so it can be validated as a whole.
+    pub fn apply_to(&self, rule: &Rule) -> NewRule {
+        NewRule {
+            name: self.name.clone().unwrap_or_else(|| rule.name.clone()),
+            camera_id: self
+                .camera_id
+                .clone()
+                .unwrap_or_else(|| rule.camera_id.map(|camera_id| camera_id.to_string())),
+            event_types: self
+                .event_types
+                .clone()
+                .unwrap_or_else(|| rule.event_types.clone()),
+            min_confidence: self.min_confidence.unwrap_or(rule.min_confidence),
+            start_time: self.start_time.unwrap_or(rule.start_time),
+            end_time: self.end_time.unwrap_or(rule.end_time),
+            channels: self
+                .channels
+                .clone()
+                .unwrap_or_else(|| rule.channels.clone()),
+            enabled: self.enabled.unwrap_or(rule.enabled),
+            modes: self.modes.clone().unwrap_or_else(|| rule.modes.clone()),
+            min_severity: Some(
+                self.min_severity
+                    .clone()
+                    .unwrap_or_else(|| rule.min_severity.clone()),
+            ),
+            cooldown_seconds: Some(self.cooldown_seconds.unwrap_or(rule.cooldown_seconds)),
+            trigger_url: self
+                .trigger_url
+                .clone()
+                .unwrap_or_else(|| rule.trigger_url.clone()),
+        }
+    }
+
+    /// `camera_id` is the rule's camera once validated, only used if the update changes it.
+    pub fn into_changeset(self, camera_id: Option<uuid::Uuid>) -> RuleChangeset {
+        RuleChangeset {
+            name: self.name,
+            camera_id: self.camera_id.map(|_| camera_id),
+            event_types: self.event_types,
+            min_confidence: self.min_confidence,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            channels: self.channels,
+            enabled: self.enabled,
+            modes: self.modes,
+            min_severity: self.min_severity,
+            cooldown_seconds: self.cooldown_seconds,
+            trigger_url: self.trigger_url,
+        }
+    }
+}
+
+/// A made up event to check a rule against with POST /Rules/<rule_id>/Test.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct TestEvent {
+    pub camera_id: String,
+    pub event_type: String,
+    pub occurred_at: DateTime<Utc>,
+    pub confidence: f32,
+    /// Defaults to the default severity for the event type.
+    pub severity: Option<String>,
+    /// The mode to pretend the user is in. Defaults to their current mode.
+    pub mode: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct RuleTestResult {
+    pub matched: bool,
+    /// The channels that would have been notified, including trigger if the rule has a trigger URL.
+    /// Empty if the rule didn't match.
+    pub channels: Vec<String>,
+}
+
+impl Rule {
+    pub fn in_time_window(&self, time: NaiveTime) -> bool {
+        match (self.start_time, self.end_time) {
+            (Some(start_time), Some(end_time)) if start_time <= end_time => {
+                start_time <= time && time < end_time
+            }
+            (Some(start_time), Some(end_time)) => time >= start_time || time < end_time,
+            _ => true,
+        }
+    }
+
+    /// Checks the event against the rule, with the rule's user in the given mode.
+    /// Doesn't check whether the rule's user has access to the event's camera.
+    pub fn matches(&self, event: &Event, mode: &str) -> bool {
+        self.enabled
+            && (self.modes.len() == 0 || self.modes.iter().any(|rule_mode| rule_mode == mode))
+            && self
+                .camera_id
+                .map_or(true, |camera_id| camera_id == event.camera_id)
+            && self.event_types.contains(&event.event_type)
+            && event.confidence >= self.min_confidence
+            && meets_severity(&event.severity, &self.min_severity)
+            && self.in_time_window(event.occurred_at.time())
+    }
+}
+
+impl InsertableRule {
+    pub fn from_new_rule(
+        user_id: uuid::Uuid,
+        camera_id: Option<uuid::Uuid>,
+        rule: NewRule,
+    ) -> InsertableRule {
+        InsertableRule {
+            user_id,
+            name: rule.name,
+            camera_id,
+            event_types: rule.event_types,
+            min_confidence: rule.min_confidence,
+            start_time: rule.start_time,
+            end_time: rule.end_time,
+            channels: rule.channels,
+            enabled: rule.enabled,
+            modes: rule.modes,
+            min_severity: rule
+                .min_severity
+                .unwrap_or_else(|| INFO_SEVERITY.to_string()),
+            cooldown_seconds: rule.cooldown_seconds.unwrap_or(DEFAULT_COOLDOWN_SECONDS),
+            trigger_url: rule.trigger_url,
+        }
+    }
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Rule>> {
+    rules::table.load::<Rule>(&*connection)
+}
+
+pub fn get(rule_id: i32, connection: &PgConnection) -> QueryResult<Rule> {
+    rules::table.find(rule_id).get_result::<Rule>(connection)
+}
+
+pub fn insert(rule: InsertableRule, connection: &PgConnection) -> QueryResult<Rule> {
+    diesel::insert_into(rules::table)
+        .values(rule)
+        .get_result(connection)
+}
+
+pub fn update(rule_id: i32, rule: Rule, connection: &PgConnection) -> QueryResult<Rule> {
+    diesel::update(rules::table.find(rule_id))
+        .set(&rule)
+        .get_result(connection)
+}
+
+/// Updates only the columns the changeset sets.
+pub fn update_partial(
+    rule_id: i32,
+    changeset: RuleChangeset,
+    connection: &PgConnection,
+) -> QueryResult<Rule> {
+    patch::or_unchanged(
+        diesel::update(rules::table.find(rule_id))
+            .set(&changeset)
+            .get_result(connection),
+        || get(rule_id, connection),
+    )
+}
+
+pub fn delete(rule_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(rules::table.find(rule_id)).execute(connection)
+}
+
+pub fn get_users_rules(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<Vec<Rule>> {
+    rules::table
+        .filter(rules::user_id.eq(user_id))
+        .order(rules::rule_id)
+        .load::<Rule>(connection)
+}
+
+/// Returns the given rule, but only if it belongs to the user.
+pub fn get_users_rule(
+    user_id: uuid::Uuid,
+    rule_id: i32,
+    connection: &PgConnection,
+) -> Result<Rule, ApiError> {
+    rules::table
+        .filter(rules::rule_id.eq(rule_id))
+        .filter(rules::user_id.eq(user_id))
+        .first::<Rule>(connection)
+        .map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Rule not found",
+                code: "rule_not_found",
+                status: Status::NotFound,
+                field: None,
+            },
+            _ => {
+                error!("Failed to get rule {}! The error was {}", rule_id, error);
+                ApiError {
+                    error: "Failed to get rule",
+                    code: "get_rule_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            }
+        })
+}
+
+/// Returns every rule that matches the event, from users who have access to the event's camera.
+pub fn get_matching_rules(event: &Event, connection: &PgConnection) -> QueryResult<Vec<Rule>> {
+    let candidate_rules = rules::table
+        .inner_join(users_cameras::table.on(users_cameras::user_id.eq(rules::user_id)))
+        .filter(users_cameras::camera_id.eq(event.camera_id))
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .filter(rules::enabled.eq(true))
+        .filter(
+            rules::camera_id
+                .eq(event.camera_id)
+                .or(rules::camera_id.is_null()),
+        )
+        .filter(rules::event_types.contains(vec![event.event_type.clone()]))
+        .select(rules::all_columns)
+        .distinct()
+        .load::<Rule>(connection)?;
+
+    let mut matching_rules = Vec::new();
+
+    for rule in candidate_rules {
+        if rule.matches(event, &current_mode(rule.user_id, connection)?) {
+            matching_rules.push(rule);
+        }
+    }
+
+    Ok(matching_rules)
+}
+
+/// Checks that a rule makes sense, and that the user has access to the camera it's for.
+/// Returns the rule's parsed camera ID.
+pub fn validate_new_rule(
+    conn: &CameraServerDbConn,
+    user_token: &UserToken,
+    new_rule: &NewRule,
+) -> Result<Option<uuid::Uuid>, ApiError> {
+    if new_rule.event_types.len() == 0 {
+        return Err(ApiError {
+            error: "Rule must match at least one event type",
+            code: "rule_event_types_required",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    if new_rule
+        .event_types
+        .iter()
+        .any(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        return Err(ApiError {
+            error: "Unknown event type",
+            code: "unknown_event_type",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    if new_rule.channels.len() == 0 && new_rule.trigger_url.is_none() {
+        return Err(ApiError {
+            error: "Rule must notify on at least one channel or have a trigger URL",
+            code: "rule_action_required",
+            status: Status::UnprocessableEntity,
+            field: Some("channels"),
+        });
+    }
+
+    if new_rule.channels.iter().any(|channel| {
+        channel != PUSH_CHANNEL && channel != EMAIL_CHANNEL && channel != SMS_CHANNEL
+    }) {
+        return Err(ApiError {
+            error: "Channels must be push, email or sms",
+            code: "invalid_channel",
+            status: Status::UnprocessableEntity,
+            field: Some("channels"),
+        });
+    }
+
+    if let Some(trigger_url) = &new_rule.trigger_url {
+        validate_trigger_url(trigger_url)?;
+    }
+
+    if new_rule
+        .cooldown_seconds
+        .map_or(false, |cooldown| cooldown < 0)
+    {
+        return Err(ApiError {
+            error: "Cooldown can't be negative",
+            code: "invalid_cooldown",
+            status: Status::UnprocessableEntity,
+            field: Some("cooldown_seconds"),
+        });
+    }
+
+    if !(0.0..=1.0).contains(&new_rule.min_confidence) {
+        return Err(ApiError {
+            error: "Minimum confidence must be between 0 and 1",
+            code: "invalid_min_confidence",
+            status: Status::UnprocessableEntity,
+            field: Some("min_confidence"),
+        });
+    }
+
+    for rule_mode in &new_rule.modes {
+        validate_mode(rule_mode)?;
+    }
+
+    if let Some(min_severity) = &new_rule.min_severity {
+        validate_severity(min_severity)?;
+    }
+
+    if new_rule.start_time.is_some() != new_rule.end_time.is_some() {
+        return Err(ApiError {
+            error: "Rule must have both a start and end time, or neither",
+            code: "invalid_rule_schedule",
+            status: Status::UnprocessableEntity,
+            field: Some("start_time"),
+        });
+    }
+
+    match &new_rule.camera_id {
+        Some(camera_id_string) => {
+            let camera_id = parse_camera_id(camera_id_string)?;
+            check_if_user_has_access_to_camera(conn, user_token, camera_id)?;
+            Ok(Some(camera_id))
+        }
+        None => Ok(None),
+    }
+}
+
+#[openapi]
+#[post("/Rules", format = "json", data = "<new_rule>")]
+pub fn add_rule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_rule: Json<NewRule>,
+) -> Result<Json<Rule>, ApiError> {
+    let new_rule = new_rule.into_inner();
+
+    let camera_id = validate_new_rule(&conn, &user_token, &new_rule)?;
+
+    insert(
+        InsertableRule::from_new_rule(user_token.user_id, camera_id, new_rule),
+        &conn,
+    )
+    .map(|rule| Json(rule))
+    .map_err(|error| {
+        error!(
+            "Failed to add rule for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to add rule",
+            code: "add_rule_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+#[openapi]
+#[get("/Rules?<updated_since>")]
+pub fn list_rules(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    updated_since: Option<String>,
+) -> Result<Json<Vec<Rule>>, ApiError> {
+    let updated_since = parse_updated_since(&updated_since)?;
+
+    get_users_rules(user_token.user_id, &conn)
+        .map(|mut rules| {
+            rules.retain(|rule| updated_since.map_or(true, |since| rule.updated_at > since));
+            Json(rules)
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to get rules for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get rules",
+                code: "get_rules_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[put("/Rules/<rule_id>", format = "json", data = "<updated_rule>")]
+pub fn update_rule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    rule_id: i32,
+    updated_rule: Json<NewRule>,
+) -> Result<Json<Rule>, ApiError> {
+    let updated_rule = updated_rule.into_inner();
+
+    let rule = get_users_rule(user_token.user_id, rule_id, &conn)?;
+
+    let camera_id = validate_new_rule(&conn, &user_token, &updated_rule)?;
+
+    update(
+        rule_id,
+        Rule {
+            rule_id,
+            user_id: user_token.user_id,
+            name: updated_rule.name,
+            camera_id,
+            event_types: updated_rule.event_types,
+            min_confidence: updated_rule.min_confidence,
+            start_time: updated_rule.start_time,
+            end_time: updated_rule.end_time,
+            channels: updated_rule.channels,
+            enabled: updated_rule.enabled,
+            modes: updated_rule.modes,
+            min_severity: updated_rule
+                .min_severity
+                .unwrap_or_else(|| INFO_SEVERITY.to_string()),
+            cooldown_seconds: updated_rule
+                .cooldown_seconds
+                .unwrap_or(DEFAULT_COOLDOWN_SECONDS),
+            trigger_url: updated_rule.trigger_url,
+            created_at: rule.created_at,
+            updated_at: rule.updated_at,
+        },
+        &conn,
+    )
+    .map(|rule| Json(rule))
+    .map_err(|error| {
+        error!("Failed to update rule {}! The error was {}", rule_id, error);
+        ApiError {
+            error: "Failed to update rule",
+            code: "update_rule_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Changes only the fields that are sent. The rule is checked as a whole after the update,
+/// the same way as when it's created.
+#[openapi]
+#[patch("/Rules/<rule_id>", format = "json", data = "<update>")]
+pub fn patch_rule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    rule_id: i32,
+    update: Json<UpdateRule>,
+) -> Result<Json<Rule>, ApiError> {
+    let update = update.into_inner();
+
+    let rule = get_users_rule(user_token.user_id, rule_id, &conn)?;
+
+    let camera_id = validate_new_rule(&conn, &user_token, &update.apply_to(&rule))?;
+
+    update_partial(rule_id, update.into_changeset(camera_id), &conn)
+        .map(|rule| Json(rule))
+        .map_err(|error| {
+            error!("Failed to update rule {}! The error was {}", rule_id, error);
+            ApiError {
+                error: "Failed to update rule",
+                code: "update_rule_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[delete("/Rules/<rule_id>")]
+pub fn delete_rule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    rule_id: i32,
+) -> Result<(), ApiError> {
+    get_users_rule(user_token.user_id, rule_id, &conn)?;
+
+    delete(rule_id, &conn).map(|_| ()).map_err(|error| {
+        error!("Failed to delete rule {}! The error was {}", rule_id, error);
+        ApiError {
+            error: "Failed to delete rule",
+            code: "delete_rule_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Dry run of a rule: checks whether the given event would trigger the rule, without notifying anyone.
+#[openapi]
+#[post("/Rules/<rule_id>/Test", format = "json", data = "<test_event>")]
+pub fn test_rule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    rule_id: i32,
+    test_event: Json<TestEvent>,
+) -> Result<Json<RuleTestResult>, ApiError> {
+    let test_event = test_event.into_inner();
+
+    let rule = get_users_rule(user_token.user_id, rule_id, &conn)?;
+
+    let camera_id = parse_camera_id(&test_event.camera_id)?;
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let event = Event {
+        event_id: 0,
+        camera_id,
+        severity: match test_event.severity {
+            Some(severity) => {
+                validate_severity(&severity)?;
+                severity
+            }
+            None => default_severity(&test_event.event_type).to_string(),
+        },
+        event_type: test_event.event_type,
+        occurred_at: test_event.occurred_at,
+        confidence: test_event.confidence,
+        image_id: None,
+        anonymised_at: None,
+        audio_id: None,
+        tamper_reason: None,
+        created_at: Utc::now(),
+        updated_at: Utc::now(),
+    };
+
+    let mode = match test_event.mode {
+        Some(mode) => {
+            validate_mode(&mode)?;
+            mode
+        }
+        None => current_mode(user_token.user_id, &conn).map_err(|error| {
+            error!(
+                "Failed to get mode for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get mode",
+                code: "get_mode_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?,
+    };
+
+    let matched = rule.matches(&event, &mode);
+
+    let mut channels = if matched { rule.channels } else { Vec::new() };
+
+    if matched && rule.trigger_url.is_some() {
+        channels.push(TRIGGER_CHANNEL.to_string());
+    }
+
+    Ok(Json(RuleTestResult { matched, channels }))
+}
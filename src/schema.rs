@@ -1,3 +1,182 @@
+table! {
+    account_exports (export_id) {
+        export_id -> Int4,
+        user_id -> Uuid,
+        include_media -> Bool,
+        status -> Text,
+        size_bytes -> Nullable<Int8>,
+        created_at -> Timestamptz,
+        completed_at -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+        blur_faces -> Bool,
+    }
+}
+
+table! {
+    active_streams (stream_id) {
+        stream_id -> Uuid,
+        user_id -> Uuid,
+        kind -> Text,
+        started_at -> Timestamptz,
+        seen_at -> Timestamptz,
+    }
+}
+
+table! {
+    activity_baselines (camera_id, hour) {
+        camera_id -> Uuid,
+        hour -> Int2,
+        mean -> Float8,
+        standard_deviation -> Float8,
+        days -> Int4,
+        computed_at -> Timestamptz,
+    }
+}
+
+table! {
+    analysis_jobs (job_id) {
+        job_id -> Int4,
+        camera_id -> Uuid,
+        image_id -> Int8,
+        attempts -> Int4,
+        done -> Bool,
+        next_attempt_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    announcement_reads (announcement_id, user_id) {
+        announcement_id -> Int4,
+        user_id -> Uuid,
+        read_at -> Timestamptz,
+    }
+}
+
+table! {
+    announcements (announcement_id) {
+        announcement_id -> Int4,
+        title -> Text,
+        body -> Text,
+        starts_at -> Timestamptz,
+        ends_at -> Nullable<Timestamptz>,
+        created_by -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    audio_clips (audio_id) {
+        audio_id -> Int4,
+        camera_id -> Uuid,
+        content_type -> Text,
+        size_bytes -> Int8,
+        recorded_at -> Timestamptz,
+    }
+}
+
+table! {
+    audit_log (audit_id) {
+        audit_id -> Int8,
+        occurred_at -> Timestamptz,
+        user_id -> Nullable<Uuid>,
+        client -> Nullable<Text>,
+        method -> Text,
+        path -> Text,
+        route -> Nullable<Text>,
+        status -> Int2,
+        request_id -> Nullable<Text>,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        impersonated_by -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    bandwidth_daily (camera_id, day) {
+        camera_id -> Uuid,
+        day -> Date,
+        upload_bytes -> Int8,
+        download_bytes -> Int8,
+    }
+}
+
+table! {
+    bootstrap_devices (hardware_id) {
+        hardware_id -> Text,
+        claim_token_hash -> Text,
+        imported_at -> Timestamptz,
+        user_id -> Nullable<Uuid>,
+        name -> Nullable<Text>,
+        camera_id -> Nullable<Uuid>,
+        exchanged_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    camera_clocks (camera_id) {
+        camera_id -> Uuid,
+        drift_ms -> Int8,
+        drifted -> Bool,
+        checked_at -> Timestamptz,
+    }
+}
+
+table! {
+    camera_commands (command_id) {
+        command_id -> Int4,
+        camera_id -> Uuid,
+        command -> Text,
+        delivered -> Bool,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        image_id -> Nullable<Int8>,
+    }
+}
+
+table! {
+    camera_firmware (camera_id) {
+        camera_id -> Uuid,
+        model -> Text,
+        version -> Text,
+        firmware_id -> Nullable<Int4>,
+        status -> Nullable<Text>,
+        bytes_downloaded -> Nullable<Int8>,
+        error -> Nullable<Text>,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    camera_logs (log_id) {
+        log_id -> Int8,
+        camera_id -> Uuid,
+        logged_at -> Timestamptz,
+        level -> Text,
+        message -> Text,
+        received_at -> Timestamptz,
+    }
+}
+
+table! {
+    camera_offline_periods (period_id) {
+        period_id -> Int4,
+        camera_id -> Uuid,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    camera_streams (camera_id) {
+        camera_id -> Uuid,
+        url -> Text,
+        protocol -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
 table! {
     camera_tokens (camera_token) {
         camera_token -> Uuid,
@@ -9,6 +188,13 @@ table! {
     cameras (camera_id) {
         camera_id -> Uuid,
         name -> Text,
+        online -> Bool,
+        last_seen_at -> Nullable<Timestamptz>,
+        deleted_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        location -> Nullable<Text>,
+        tags -> Array<Text>,
     }
 }
 
@@ -19,6 +205,513 @@ table! {
     }
 }
 
+table! {
+    detections (detection_id) {
+        detection_id -> Int4,
+        camera_id -> Uuid,
+        event_id -> Nullable<Int4>,
+        image_id -> Nullable<Int8>,
+        label -> Text,
+        confidence -> Float4,
+        x -> Float4,
+        y -> Float4,
+        width -> Float4,
+        height -> Float4,
+    }
+}
+
+table! {
+    digest_settings (user_id) {
+        user_id -> Uuid,
+        enabled -> Bool,
+        recipient -> Text,
+        send_hour -> Int2,
+        replaces_alerts -> Bool,
+        last_sent_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    email_alerts (user_id, camera_id) {
+        user_id -> Uuid,
+        camera_id -> Uuid,
+        recipients -> Array<Text>,
+        event_types -> Array<Text>,
+        attach_snapshot -> Bool,
+        throttle_minutes -> Int4,
+        last_alerted_at -> Nullable<Timestamptz>,
+        min_severity -> Text,
+    }
+}
+
+table! {
+    event_acknowledgements (user_id, event_id) {
+        user_id -> Uuid,
+        event_id -> Int4,
+        acknowledged_at -> Timestamptz,
+    }
+}
+
+table! {
+    event_holds (hold_id) {
+        hold_id -> Int4,
+        event_id -> Int4,
+        user_id -> Uuid,
+        reason -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    event_media (event_id, image_id) {
+        event_id -> Int4,
+        image_id -> Int8,
+    }
+}
+
+table! {
+    feature_flags (name) {
+        name -> Text,
+        enabled -> Bool,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    events (event_id) {
+        event_id -> Int4,
+        camera_id -> Uuid,
+        event_type -> Text,
+        occurred_at -> Timestamptz,
+        confidence -> Float4,
+        image_id -> Nullable<Int8>,
+        severity -> Text,
+        anonymised_at -> Nullable<Timestamptz>,
+        audio_id -> Nullable<Int4>,
+        tamper_reason -> Nullable<Text>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    federation_peers (peer_id) {
+        peer_id -> Int4,
+        name -> Text,
+        url -> Text,
+        inbound_token_hash -> Text,
+        outbound_token -> Nullable<Text>,
+        status -> Text,
+        created_at -> Timestamptz,
+        activated_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    firmware_releases (firmware_id) {
+        firmware_id -> Int4,
+        model -> Text,
+        version -> Text,
+        size_bytes -> Int8,
+        sha256 -> Text,
+        rollout_percent -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    idempotency_keys (client, idempotency_key) {
+        client -> Text,
+        idempotency_key -> Text,
+        method -> Text,
+        uri -> Text,
+        status -> Nullable<Int2>,
+        content_type -> Nullable<Text>,
+        body -> Nullable<Bytea>,
+        created_at -> Timestamptz,
+        body_hash -> Nullable<Text>,
+    }
+}
+
+table! {
+    impersonation_tokens (impersonation_token) {
+        impersonation_token -> Uuid,
+        user_id -> Uuid,
+        admin_id -> Nullable<Uuid>,
+        reason -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    jobs (job_id) {
+        job_id -> Int4,
+        kind -> Text,
+        payload -> Jsonb,
+        status -> Text,
+        attempts -> Int4,
+        max_attempts -> Int4,
+        run_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    media_holds (hold_id) {
+        hold_id -> Int4,
+        camera_id -> Uuid,
+        user_id -> Nullable<Uuid>,
+        placed_by_admin -> Bool,
+        media_type -> Nullable<Text>,
+        media_id -> Nullable<Int8>,
+        starts_at -> Nullable<Timestamptz>,
+        ends_at -> Nullable<Timestamptz>,
+        reason -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    mode_schedules (schedule_id) {
+        schedule_id -> Int4,
+        user_id -> Uuid,
+        mode -> Text,
+        at_time -> Time,
+        days_of_week -> Array<Int4>,
+    }
+}
+
+table! {
+    mqtt_clients (client_id) {
+        client_id -> Text,
+        camera_id -> Uuid,
+    }
+}
+
+table! {
+    notes (note_id) {
+        note_id -> Int4,
+        user_id -> Uuid,
+        camera_id -> Uuid,
+        event_id -> Nullable<Int4>,
+        noted_at -> Nullable<Timestamptz>,
+        body -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    notification_preferences (user_id, camera_id) {
+        user_id -> Uuid,
+        camera_id -> Uuid,
+        push_enabled -> Bool,
+        event_types -> Array<Text>,
+        offline_alerts -> Bool,
+        armed_modes -> Array<Text>,
+        min_severity -> Text,
+        tamper_overrides -> Bool,
+    }
+}
+
+table! {
+    notifications (notification_id) {
+        notification_id -> Int4,
+        user_id -> Uuid,
+        camera_id -> Uuid,
+        event_id -> Nullable<Int4>,
+        channel -> Text,
+        title -> Text,
+        body -> Text,
+        sent -> Bool,
+        attempts -> Int4,
+        next_attempt_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        rule_id -> Nullable<Int4>,
+        event_count -> Int4,
+        last_event_at -> Timestamptz,
+    }
+}
+
+table! {
+    oauth_codes (code_hash) {
+        code_hash -> Text,
+        user_id -> Uuid,
+        assistant -> Text,
+        redirect_uri -> Text,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    oauth_links (link_id) {
+        link_id -> Int4,
+        user_id -> Uuid,
+        assistant -> Text,
+        refresh_token_hash -> Text,
+        access_token_hash -> Text,
+        access_token_expires_at -> Timestamptz,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    onvif_credentials (user_id) {
+        user_id -> Uuid,
+        username -> Text,
+        created_at -> Timestamptz,
+        ha1 -> Text,
+    }
+}
+
+table! {
+    plans (plan_id) {
+        plan_id -> Int4,
+        name -> Text,
+        max_cameras -> Nullable<Int4>,
+        max_storage_gb -> Nullable<Int4>,
+        retention_days -> Nullable<Int4>,
+        max_streams -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    push_tokens (push_token_id) {
+        push_token_id -> Int4,
+        user_id -> Uuid,
+        platform -> Text,
+        token -> Text,
+    }
+}
+
+table! {
+    recordings (recording_id) {
+        recording_id -> Int4,
+        camera_id -> Uuid,
+        source -> Text,
+        content_type -> Text,
+        size_bytes -> Int8,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    remote_cameras (remote_camera_id) {
+        remote_camera_id -> Int4,
+        peer_id -> Int4,
+        share_id -> Int4,
+        user_id -> Uuid,
+        camera_id -> Uuid,
+        name -> Text,
+        access_token -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    remote_shares (share_id) {
+        share_id -> Int4,
+        camera_id -> Uuid,
+        peer_id -> Int4,
+        username -> Text,
+        shared_by -> Uuid,
+        access_token_hash -> Text,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+        blur_faces -> Bool,
+    }
+}
+
+table! {
+    replicated_events (origin, origin_event_id) {
+        origin -> Text,
+        origin_event_id -> Int4,
+        event_id -> Int4,
+    }
+}
+
+table! {
+    replication_cursors (stream) {
+        stream -> Text,
+        last_updated_at -> Nullable<Timestamptz>,
+        last_id -> Nullable<Text>,
+        synced_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Text>,
+        last_attempt_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    rules (rule_id) {
+        rule_id -> Int4,
+        user_id -> Uuid,
+        name -> Text,
+        camera_id -> Nullable<Uuid>,
+        event_types -> Array<Text>,
+        min_confidence -> Float4,
+        start_time -> Nullable<Time>,
+        end_time -> Nullable<Time>,
+        channels -> Array<Text>,
+        enabled -> Bool,
+        modes -> Array<Text>,
+        min_severity -> Text,
+        cooldown_seconds -> Int4,
+        trigger_url -> Nullable<Text>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    scheduled_snapshots (snapshot_id) {
+        snapshot_id -> Int4,
+        schedule_id -> Int4,
+        camera_id -> Uuid,
+        command_id -> Nullable<Int4>,
+        scheduled_for -> Timestamptz,
+        requested_at -> Timestamptz,
+        status -> Text,
+        image_id -> Nullable<Int8>,
+        checked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    schema_compatibility (id) {
+        id -> Bool,
+        min_compatible_version -> Text,
+    }
+}
+
+table! {
+    sms_settings (user_id) {
+        user_id -> Uuid,
+        phone_number -> Nullable<Text>,
+        enabled -> Bool,
+        critical_alerts -> Bool,
+        offline_alerts -> Bool,
+        monthly_cap -> Int4,
+    }
+}
+
+table! {
+    snapshot_schedules (schedule_id) {
+        schedule_id -> Int4,
+        camera_id -> Uuid,
+        expression -> Text,
+        enabled -> Bool,
+        next_run_at -> Timestamptz,
+        last_run_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    srt_passphrases (camera_id) {
+        camera_id -> Uuid,
+        passphrase -> Text,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    storage_daily (camera_id, media_type, day) {
+        camera_id -> Uuid,
+        media_type -> Text,
+        day -> Date,
+        bytes -> Int8,
+        files -> Int8,
+    }
+}
+
+table! {
+    storage_recounts (camera_id) {
+        camera_id -> Uuid,
+        recounted_at -> Timestamptz,
+    }
+}
+
+table! {
+    stream_credentials (credential_id) {
+        credential_id -> Int4,
+        camera_id -> Uuid,
+        username -> Text,
+        password -> Text,
+        status -> Text,
+        error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        activated_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    stream_keys (camera_id) {
+        camera_id -> Uuid,
+        stream_key_hash -> Text,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    tenants (tenant_id) {
+        tenant_id -> Int4,
+        name -> Text,
+        slug -> Text,
+        hostname -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    token_usage_daily (token_hash, day) {
+        token_hash -> Text,
+        day -> Date,
+        scope -> Text,
+        user_id -> Nullable<Uuid>,
+        camera_id -> Nullable<Uuid>,
+        requests -> Int8,
+        bytes -> Int8,
+    }
+}
+
+table! {
+    usage_daily (user_id, day) {
+        user_id -> Uuid,
+        day -> Date,
+        api_calls -> Int8,
+        upload_bytes -> Int8,
+        stream_seconds -> Int8,
+    }
+}
+
+table! {
+    user_modes (user_id) {
+        user_id -> Uuid,
+        mode -> Text,
+        changed_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_presence (user_id) {
+        user_id -> Uuid,
+        home -> Bool,
+        changed_at -> Timestamptz,
+    }
+}
+
 table! {
     user_tokens (user_token) {
         user_token -> Uuid,
@@ -31,6 +724,14 @@ table! {
         user_id -> Uuid,
         username -> Text,
         password -> Text,
+        deleted_at -> Nullable<Timestamptz>,
+        disabled_at -> Nullable<Timestamptz>,
+        plan_id -> Nullable<Int4>,
+        suspended_at -> Nullable<Timestamptz>,
+        suspension_reason -> Nullable<Text>,
+        tenant_id -> Int4,
+        timezone -> Nullable<Text>,
+        locale -> Nullable<Text>,
     }
 }
 
@@ -39,14 +740,115 @@ table! {
         users_cameras_id -> Int4,
         camera_id -> Uuid,
         user_id -> Uuid,
+        deleted_at -> Nullable<Timestamptz>,
+        can_talk -> Bool,
+    }
+}
+
+table! {
+    webhook_deliveries (delivery_id) {
+        delivery_id -> Int4,
+        webhook_id -> Int4,
+        event_id -> Int4,
+        attempts -> Int4,
+        delivered -> Bool,
+        next_attempt_at -> Nullable<Timestamptz>,
+        last_status_code -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    webhooks (webhook_id) {
+        webhook_id -> Int4,
+        user_id -> Uuid,
+        url -> Text,
+        secret -> Text,
+        event_types -> Array<Text>,
+        min_severity -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    zones (zone_id) {
+        zone_id -> Int4,
+        camera_id -> Uuid,
+        kind -> Text,
+        points -> Jsonb,
     }
 }
 
 allow_tables_to_appear_in_same_query!(
+    account_exports,
+    active_streams,
+    activity_baselines,
+    analysis_jobs,
+    announcement_reads,
+    announcements,
+    audio_clips,
+    audit_log,
+    bandwidth_daily,
+    bootstrap_devices,
+    camera_clocks,
+    camera_commands,
+    camera_firmware,
+    camera_logs,
+    camera_offline_periods,
+    camera_streams,
     camera_tokens,
     cameras,
     configs,
+    detections,
+    digest_settings,
+    email_alerts,
+    event_acknowledgements,
+    event_holds,
+    event_media,
+    events,
+    feature_flags,
+    federation_peers,
+    firmware_releases,
+    idempotency_keys,
+    impersonation_tokens,
+    jobs,
+    media_holds,
+    mode_schedules,
+    mqtt_clients,
+    notes,
+    notification_preferences,
+    notifications,
+    oauth_codes,
+    oauth_links,
+    onvif_credentials,
+    plans,
+    push_tokens,
+    recordings,
+    remote_cameras,
+    remote_shares,
+    replicated_events,
+    replication_cursors,
+    rules,
+    scheduled_snapshots,
+    schema_compatibility,
+    sms_settings,
+    snapshot_schedules,
+    srt_passphrases,
+    storage_daily,
+    storage_recounts,
+    stream_credentials,
+    stream_keys,
+    tenants,
+    token_usage_daily,
+    usage_daily,
+    user_modes,
+    user_presence,
     user_tokens,
     users,
     users_cameras,
+    webhook_deliveries,
+    webhooks,
+    zones,
 );
@@ -0,0 +1,212 @@
+use crate::{
+    api_error::{error_code, ErrorBody},
+    api_version::{API_PREFIX, UNVERSIONED_PATHS},
+    request_id,
+    settings::settings,
+    shutdown, worker,
+};
+
+use super::schema::schema_compatibility;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use once_cell::sync::Lazy;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Method, Status};
+use rocket::{Data, Request, Response};
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The newest migration compiled into the server, from build.rs. Diesel's version for a migration is its
+/// directory name up to the first underscore, without the dashes.
+pub const EMBEDDED_SCHEMA_VERSION: &str = env!("EMBEDDED_SCHEMA_VERSION");
+
+/// How often the schema is checked again while the server runs, so instances notice a contract migration
+/// another instance has run.
+pub const SCHEMA_CHECK_INTERVAL_SECONDS: u64 = 60;
+
+pub const REFUSE: &str = "refuse";
+pub const READ_ONLY: &str = "read_only";
+
+/// Refused requests are routed here instead. Nothing is mounted at it.
+const SCHEMA_MISMATCH_PATH: &str = "/SchemaMismatch";
+
+/// What to do when the database's schema doesn't match the server's, set with schema_mismatch in [database].
+/// refuse (the default) answers every request with a 503 and fails /readyz, read_only only refuses requests
+/// that could write (anything but GET, HEAD and OPTIONS) and stays ready.
+pub fn schema_mismatch() -> &'static str {
+    &settings().database.schema_mismatch
+}
+
+/// Why the schema doesn't match, or None if it does (or hasn't been checked yet).
+static MISMATCH: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn mismatch() -> Option<String> {
+    MISMATCH
+        .lock()
+        .expect("Schema mismatch lock poisoned!")
+        .clone()
+}
+
+#[derive(QueryableByName)]
+struct AppliedVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+/// The newest migration that has been run against the database, or an empty string if none have.
+pub fn applied_version(connection: &PgConnection) -> QueryResult<String> {
+    diesel::sql_query(
+        "SELECT COALESCE(MAX(version), '') AS version FROM __diesel_schema_migrations",
+    )
+    .get_result::<AppliedVersion>(connection)
+    .map(|applied| applied.version)
+}
+
+/// Compares the database's schema with the server's, and remembers whether they match.
+///
+/// Migrations are written expand/contract so rolling deploys don't race the schema:
+/// - Expand migrations only add things (tables, nullable or defaulted columns, indexes), so servers a few
+///   versions older keep working against them. This is what most migrations should be.
+/// - Contract migrations drop or rename what older servers still use, and go in a later release, once every
+///   instance runs a version that doesn't need it. They must also set min_compatible_version in
+///   schema_compatibility to their own version.
+///
+/// So the schema matches if it's exactly the server's version, or newer but with min_compatible_version no newer
+/// than the server's. An older schema never matches, which only happens with skip_migrations.
+pub fn check(connection: &PgConnection) -> QueryResult<()> {
+    let applied = applied_version(connection)?;
+
+    let problem = if applied.as_str() < EMBEDDED_SCHEMA_VERSION {
+        Some(format!(
+            "The database is at schema version {}, but this server needs {}. The migrations need running",
+            applied, EMBEDDED_SCHEMA_VERSION
+        ))
+    } else if applied.as_str() > EMBEDDED_SCHEMA_VERSION {
+        let min_compatible_version = schema_compatibility::table
+            .select(schema_compatibility::min_compatible_version)
+            .get_result::<String>(connection)?;
+
+        if min_compatible_version.as_str() > EMBEDDED_SCHEMA_VERSION {
+            Some(format!(
+                "The database is at schema version {}, which needs at least version {}, but this server has {}",
+                applied, min_compatible_version, EMBEDDED_SCHEMA_VERSION
+            ))
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    let mut mismatch = MISMATCH.lock().expect("Schema mismatch lock poisoned!");
+
+    if *mismatch != problem {
+        match &problem {
+            Some(problem) => error!(
+                "{}! Refusing {} until it's fixed",
+                problem,
+                if schema_mismatch() == READ_ONLY {
+                    "writes"
+                } else {
+                    "every request"
+                }
+            ),
+            None => info!(
+                "The database's schema version {} is compatible with this server's {}",
+                applied, EMBEDDED_SCHEMA_VERSION
+            ),
+        }
+    }
+
+    *mismatch = problem;
+
+    Ok(())
+}
+
+/// Checks the schema every SCHEMA_CHECK_INTERVAL_SECONDS. Every instance needs to know for itself,
+/// so this runs on all of them at once.
+pub fn spawn_schema_check(database_url: String) {
+    worker::spawn_concurrent_worker(
+        "Schema check",
+        Duration::from_secs(SCHEMA_CHECK_INTERVAL_SECONDS),
+        database_url,
+        |connection| {
+            if let Err(error) = check(connection) {
+                error!(
+                    "Failed to check the schema version! The error was {}",
+                    error
+                );
+            }
+        },
+    );
+}
+
+/// Stored in a request's local cache when it's refused.
+struct Refused(bool);
+
+/// Refuses requests with a 503 while the schema doesn't match, as schema_mismatch() says.
+/// Health checks are still answered, so /readyz can say why.
+pub struct SchemaGuard;
+
+impl Fairing for SchemaGuard {
+    fn info(&self) -> Info {
+        Info {
+            name: "Schema guard",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        // ShutdownDraining has already refused it
+        if shutdown::shutting_down()
+            || mismatch().is_none()
+            || UNVERSIONED_PATHS.contains(&request.uri().path())
+        {
+            return;
+        }
+
+        let reads_only = match request.method() {
+            Method::Get | Method::Head | Method::Options => true,
+            _ => false,
+        };
+
+        if reads_only && schema_mismatch() == READ_ONLY {
+            return;
+        }
+
+        match Origin::parse_owned(format!("{}{}", API_PREFIX, SCHEMA_MISMATCH_PATH)) {
+            Ok(origin) => request.set_uri(origin),
+            Err(error) => error!(
+                "Failed to reroute request while the schema doesn't match! The error was {}",
+                error
+            ),
+        }
+
+        request.local_cache(|| Refused(true));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Refused(true) = request.local_cache(|| Refused(false)) {
+            let body = ErrorBody {
+                code: error_code(Status::ServiceUnavailable),
+                message: "The database is being upgraded, try again shortly",
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request);
+
+            response.set_status(Status::ServiceUnavailable);
+            response.set_header(ContentType::JSON);
+            response.set_header(Header::new(
+                "Retry-After",
+                SCHEMA_CHECK_INTERVAL_SECONDS.to_string(),
+            ));
+            response.set_sized_body(Cursor::new(
+                serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+            ));
+        }
+    }
+}
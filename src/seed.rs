@@ -0,0 +1,209 @@
+use crate::{
+    camera::{self, InsertableCamera},
+    event::{self, InsertableEvent, TAMPER_EVENT_TYPE, TAMPER_REASONS},
+    media_store::{media_store, MediaStore},
+    tenant,
+    user::{self, InsertableUser},
+    user_tokens::{self, InsertableUserToken},
+    users_cameras::{self, InsertableUsersCamera},
+};
+
+use chrono::{TimeZone, Utc};
+use diesel::pg::PgConnection;
+
+/// Every seeded user has this password.
+pub const SEED_PASSWORD: &str = "camera-server-demo";
+
+/// Owns every seeded camera. Seeding stops if this user already exists, so it's only done once.
+pub const OWNER_USERNAME: &str = "demo-owner";
+
+/// Has the first two seeded cameras shared with them.
+pub const VIEWER_USERNAME: &str = "demo-viewer";
+
+pub const CAMERA_NAMES: [&str; 3] = ["Front door", "Back garden", "Garage"];
+
+/// How far back seeded events go.
+pub const SEED_DAYS: i64 = 7;
+
+pub const EVENTS_PER_CAMERA: usize = 50;
+
+/// An 8x8 grey baseline JPEG, stored as every seeded event's image.
+#[rustfmt::skip]
+const PLACEHOLDER_JPEG: [u8; 141] = [
+    // SOI
+    0xFF, 0xD8,
+    // DQT, every quantisation value is 1
+    0xFF, 0xDB, 0x00, 0x43, 0x00,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    // SOF0, 8x8 greyscale
+    0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00,
+    // DHT, a DC table with one code, for a difference of 0
+    0xFF, 0xC4, 0x00, 0x14, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // DHT, an AC table with one code, for end of block
+    0xFF, 0xC4, 0x00, 0x14, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // SOS
+    0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00,
+    // The only block: no DC difference, then end of block, padded with 1s
+    0x3F,
+    // EOI
+    0xFF, 0xD9,
+];
+
+/// What seed() created, for printing the credentials a frontend or test needs.
+pub struct Seeded {
+    pub owner_id: uuid::Uuid,
+    pub owner_token: uuid::Uuid,
+    pub viewer_id: uuid::Uuid,
+    pub viewer_token: uuid::Uuid,
+    /// Each camera's ID, name and token, so images and events can be uploaded as it.
+    pub cameras: Vec<(uuid::Uuid, String, uuid::Uuid)>,
+    pub events: usize,
+}
+
+/// A small deterministic generator, so every seeded database has the same events.
+struct Sequence(u64);
+
+impl Sequence {
+    fn next(&mut self, below: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % below
+    }
+}
+
+fn add_user(username: &str, connection: &PgConnection) -> Result<(uuid::Uuid, uuid::Uuid), String> {
+    let password = user::hash_password(SEED_PASSWORD).map_err(|error| error.to_string())?;
+    let new_user = user::insert(
+        InsertableUser {
+            username: username.to_string(),
+            password,
+        },
+        tenant::DEFAULT_TENANT_ID,
+        connection,
+    )
+    .map_err(|error| format!("Failed to add {}! The error was {}", username, error))?;
+
+    let token = user_tokens::insert(
+        InsertableUserToken {
+            user_id: new_user.user_id,
+        },
+        connection,
+    )
+    .map_err(|error| {
+        format!(
+            "Failed to add a token for {}! The error was {}",
+            username, error
+        )
+    })?;
+
+    Ok((new_user.user_id, token.user_token))
+}
+
+/// Fills an empty database with two users, three cameras shared between them and a week of events with images,
+/// so there's something realistic to develop and test against. Returns an error if it has already been seeded.
+pub fn seed(connection: &PgConnection) -> Result<Seeded, String> {
+    if user::get_by_username(
+        OWNER_USERNAME.to_string(),
+        tenant::DEFAULT_TENANT_ID,
+        connection,
+    )
+    .is_ok()
+    {
+        return Err(format!(
+            "{} already exists, so the database has already been seeded",
+            OWNER_USERNAME
+        ));
+    }
+
+    let (owner_id, owner_token) = add_user(OWNER_USERNAME, connection)?;
+    let (viewer_id, viewer_token) = add_user(VIEWER_USERNAME, connection)?;
+
+    let mut cameras = Vec::new();
+
+    for (index, name) in CAMERA_NAMES.iter().enumerate() {
+        let camera_token = camera::register_camera(
+            InsertableCamera {
+                name: name.to_string(),
+            },
+            owner_id,
+            connection,
+        )
+        .map_err(|error| error.error.to_string())?;
+
+        if index < 2 {
+            users_cameras::insert(
+                InsertableUsersCamera {
+                    camera_id: camera_token.camera_id,
+                    user_id: viewer_id,
+                },
+                connection,
+            )
+            .map_err(|error| format!("Failed to share {}! The error was {}", name, error))?;
+        }
+
+        cameras.push((
+            camera_token.camera_id,
+            name.to_string(),
+            camera_token.camera_token,
+        ));
+    }
+
+    let mut sequence = Sequence(1);
+    let mut new_events = Vec::new();
+    let now = Utc::now().timestamp();
+    let event_types = &event::REPORTED_EVENT_TYPES;
+
+    for (camera_id, _, _) in &cameras {
+        for _ in 0..EVENTS_PER_CAMERA {
+            // Image IDs are seconds since epoch, like uploads, so each event gets the image taken as it happened
+            let image_id = now - sequence.next(SEED_DAYS as u64 * 24 * 60 * 60) as i64;
+            let occurred_at = Utc.timestamp(image_id, 0);
+            let event_type = event_types[sequence.next(event_types.len() as u64) as usize];
+
+            media_store()
+                .store_image(camera_id, image_id as u64, &mut &PLACEHOLDER_JPEG[..])
+                .map_err(|error| format!("Failed to store an image! The error was {}", error))?;
+
+            new_events.push((
+                InsertableEvent {
+                    camera_id: *camera_id,
+                    event_type: event_type.to_string(),
+                    occurred_at,
+                    confidence: 0.5 + sequence.next(50) as f32 / 100.0,
+                    image_id: Some(image_id),
+                    severity: event::default_severity(event_type).to_string(),
+                    audio_id: None,
+                    tamper_reason: if event_type == TAMPER_EVENT_TYPE {
+                        Some(
+                            TAMPER_REASONS[sequence.next(TAMPER_REASONS.len() as u64) as usize]
+                                .to_string(),
+                        )
+                    } else {
+                        None
+                    },
+                },
+                Vec::new(),
+            ));
+        }
+    }
+
+    let events = event::insert_with_detections(new_events, connection)
+        .map_err(|error| format!("Failed to add events! The error was {}", error))?
+        .len();
+
+    Ok(Seeded {
+        owner_id,
+        owner_token,
+        viewer_id,
+        viewer_token,
+        cameras,
+        events,
+    })
+}
@@ -0,0 +1,1507 @@
+use crate::schema_check;
+
+use chrono::NaiveDate;
+use jsonwebtoken::EncodingKey;
+use lettre::message::Mailbox;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use toml::value::{Table, Value};
+use uuid::Uuid;
+
+/// Read if CAMERA_SERVER_CONFIG isn't set. It's fine for it not to exist.
+pub const DEFAULT_SETTINGS_PATH: &str = "camera-server.toml";
+
+/// The server's settings, from the TOML file at CAMERA_SERVER_CONFIG (camera-server.toml by default).
+/// Every setting can be overridden with CAMERA_SERVER_<SECTION>_<KEY>, e.g. CAMERA_SERVER_SMTP_HOST for host in [smtp].
+/// The environment variables settings were read from before the file existed (e.g. SMTP_HOST) still work,
+/// but CAMERA_SERVER_ ones win.
+///
+/// The only environment variables not in here are CAMERA_SERVER_CONFIG itself, and RUST_LOG for what gets logged.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub database: DatabaseSettings,
+    pub storage: StorageSettings,
+    pub smtp: SmtpSettings,
+    pub mqtt: MqttSettings,
+    pub limits: LimitSettings,
+    pub features: FeatureSettings,
+    pub scaling: ScalingSettings,
+    pub tls: TlsSettings,
+    pub server: ServerSettings,
+    pub maintenance: MaintenanceSettings,
+    pub i18n: I18nSettings,
+    pub replication: ReplicationSettings,
+    pub federation: FederationSettings,
+    pub voice_assistants: VoiceAssistantSettings,
+    pub onvif: OnvifSettings,
+    pub ingest: IngestSettings,
+    pub privacy: PrivacySettings,
+    pub admin: AdminSettings,
+    pub cache: CacheSettings,
+    pub cors: CorsSettings,
+    pub push: PushSettings,
+    pub sms: SmsSettings,
+    pub logging: LoggingSettings,
+    pub jobs: JobSettings,
+    pub analysis: AnalysisSettings,
+    pub anomaly: AnomalySettings,
+    pub home_assistant: HomeAssistantSettings,
+    pub event_media: EventMediaSettings,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseSettings {
+    /// Replaces the url of camera-server-db in Rocket.toml, if set.
+    pub url: Option<String>,
+    /// How long (in seconds) to keep trying to reach the database at startup before exiting. 0 keeps trying forever.
+    pub startup_timeout_seconds: u64,
+    /// Queries taking longer than this many milliseconds are logged. 0 turns the logging off.
+    pub slow_query_ms: u64,
+    /// Run migrations some other way, e.g. with the diesel CLI before deploying.
+    pub skip_migrations: bool,
+    /// What to do when the database's schema doesn't match the server's: refuse or read_only.
+    pub schema_mismatch: String,
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> DatabaseSettings {
+        DatabaseSettings {
+            url: None,
+            startup_timeout_seconds: 300,
+            slow_query_ms: 500,
+            skip_migrations: false,
+            schema_mismatch: String::from(schema_check::REFUSE),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    /// Where images are stored. Required.
+    pub images_directory: Option<String>,
+    /// Where images are moved to once they're older than cold_storage_after_days. A directory, usually cheaper
+    /// storage mounted there.
+    pub cold_images_directory: Option<String>,
+    /// Images are never moved to cold storage if this isn't set.
+    pub cold_storage_after_days: Option<u64>,
+    pub audio_directory: String,
+    /// Where video pushed over RTMP is recorded.
+    pub recordings_directory: String,
+    /// Where backups are written. Backups can't be made if this isn't set.
+    pub backup_directory: Option<String>,
+    /// Where users' account exports are written until they expire.
+    pub export_directory: String,
+    /// The Ed25519 key account export manifests are signed with, made here if it doesn't exist yet.
+    pub export_signing_key_path: String,
+    /// Where firmware uploaded with POST /Admin/Firmware is kept.
+    pub firmware_directory: String,
+}
+
+impl Default for StorageSettings {
+    fn default() -> StorageSettings {
+        StorageSettings {
+            images_directory: None,
+            cold_images_directory: None,
+            cold_storage_after_days: None,
+            audio_directory: String::from("audio"),
+            recordings_directory: String::from("recordings"),
+            backup_directory: None,
+            export_directory: String::from("exports"),
+            export_signing_key_path: String::from("export-signing.key"),
+            firmware_directory: String::from("firmware"),
+        }
+    }
+}
+
+/// Email alerts are never sent if host isn't set.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSettings {
+    pub host: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The address alerts are sent from. Required if host is set.
+    pub from: Option<String>,
+}
+
+/// Nothing is published if host isn't set.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    pub host: Option<String>,
+    pub port: u16,
+    /// Every connection needs its own client ID, so each one adds a suffix to this.
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Every topic starts with this.
+    pub topic_prefix: String,
+    /// Take events and snapshots from cameras over MQTT too, see mqtt_ingest::spawn_ingest_bridge().
+    pub ingest: bool,
+}
+
+impl Default for MqttSettings {
+    fn default() -> MqttSettings {
+        MqttSettings {
+            host: None,
+            port: 1883,
+            client_id: String::from("camera-server"),
+            username: None,
+            password: None,
+            topic_prefix: String::from("cameraserver"),
+            ingest: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// How many requests each client can make per minute.
+    pub rate_limit_per_minute: u32,
+    /// How long (in seconds) a camera has to upload a snapshot it was asked for before it times out.
+    pub snapshot_timeout_seconds: u64,
+    /// How long (in seconds) a camera can go without contacting the server before it counts as offline.
+    pub offline_after_seconds: i64,
+    /// Events are kept forever if this isn't set.
+    pub event_retention_days: Option<i64>,
+    /// How long deleted cameras and users are kept for before they're purged.
+    pub soft_delete_retention_days: i64,
+    /// How long audit log entries are kept for.
+    pub audit_retention_days: i64,
+    /// How long (in hours) account exports can be downloaded for.
+    pub export_expiry_hours: i64,
+    /// A batch of events or camera contacts is written straight away once it has this many rows.
+    pub ingest_batch_size: usize,
+    /// The longest (in milliseconds) a queued row waits before being written. 0 turns batching off.
+    pub ingest_batch_latency_ms: u64,
+    /// How many uploads the instance stores at once, from every camera.
+    pub max_concurrent_uploads: usize,
+    /// How many uploads one camera can have in progress or queued at once.
+    pub max_concurrent_uploads_per_camera: usize,
+    /// How long (in seconds) an upload waits for a free slot before it's turned away.
+    pub upload_queue_seconds: u64,
+    /// How many connections the realtime server (WebSockets, event streams and talk) takes at once. Each has its
+    /// own thread, so more than this are turned away with a 503.
+    pub max_realtime_connections: usize,
+    /// How far (in seconds) a camera's clock can be from the server's before its users are alerted.
+    pub max_clock_drift_seconds: i64,
+    /// How long (in hours) log lines sent with POST /Device/Logs are kept for.
+    pub camera_log_retention_hours: i64,
+    /// How many requests each user token can make per day (UTC). 0 turns the quota off.
+    pub owner_daily_requests: u64,
+    /// How many megabytes each user token can send and be sent per day. 0 turns the quota off.
+    pub owner_daily_megabytes: u64,
+    /// Like owner_daily_requests, for each camera token.
+    pub camera_daily_requests: u64,
+    pub camera_daily_megabytes: u64,
+}
+
+impl Default for LimitSettings {
+    fn default() -> LimitSettings {
+        LimitSettings {
+            rate_limit_per_minute: 300,
+            snapshot_timeout_seconds: 30,
+            offline_after_seconds: 300,
+            event_retention_days: None,
+            soft_delete_retention_days: 30,
+            audit_retention_days: 365,
+            export_expiry_hours: 72,
+            ingest_batch_size: 200,
+            ingest_batch_latency_ms: 20,
+            max_concurrent_uploads: 16,
+            max_concurrent_uploads_per_camera: 2,
+            upload_queue_seconds: 10,
+            max_realtime_connections: 1000,
+            max_clock_drift_seconds: 30,
+            camera_log_retention_hours: 72,
+            owner_daily_requests: 100_000,
+            owner_daily_megabytes: 0,
+            camera_daily_requests: 100_000,
+            camera_daily_megabytes: 0,
+        }
+    }
+}
+
+/// Whether each subsystem starts off on. Admins can turn them on and off while the server runs, see feature_flags.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureSettings {
+    /// The realtime server's websockets and event streams.
+    pub streaming: bool,
+    /// Queueing and delivering webhooks.
+    pub webhooks: bool,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> FeatureSettings {
+        FeatureSettings {
+            streaming: true,
+            webhooks: true,
+        }
+    }
+}
+
+/// Where and how the API and the servers beside it listen, apart from Rocket's own address and port.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// A unix socket to also listen on, for a reverse proxy on the same machine.
+    pub unix_socket: Option<String>,
+    /// The socket's permissions, in octal.
+    pub unix_socket_mode: String,
+    /// Comma separated addresses of reverse proxies, whose X-Real-IP header is trusted to say who the client is.
+    /// Requests from anywhere else are told apart by the address they connected from.
+    pub trusted_proxies: String,
+    /// Rocket 0.4 only speaks HTTP/1, so gRPC has its own port.
+    pub grpc_port: u16,
+    /// Rocket can't upgrade connections, so WebSockets and event streams have their own port.
+    pub websocket_port: u16,
+    /// The UDP port for CoAP. Turns CoAP on when set.
+    pub coap_port: Option<u16>,
+    /// Serves /graphql, and GraphiQL at GET /graphql/explorer.
+    pub graphql: bool,
+    /// When v0 paths will stop working, as YYYY-MM-DD. Sent as a Sunset header if set.
+    pub legacy_sunset: Option<String>,
+    /// How long to wait for in-flight requests and background work on SIGTERM before exiting anyway.
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> ServerSettings {
+        ServerSettings {
+            unix_socket: None,
+            unix_socket_mode: String::from("660"),
+            trusted_proxies: String::new(),
+            grpc_port: 50051,
+            websocket_port: 8001,
+            coap_port: None,
+            graphql: false,
+            legacy_sunset: None,
+            shutdown_timeout_seconds: 30,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Each address in trusted_proxies, or what it was if it isn't one.
+    pub fn trusted_proxies(&self) -> impl Iterator<Item = Result<IpAddr, &str>> {
+        self.trusted_proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| proxy.parse().map_err(|_| proxy))
+    }
+
+    /// legacy_sunset, or what it was if it isn't a date.
+    pub fn legacy_sunset(&self) -> Option<Result<NaiveDate, &str>> {
+        self.legacy_sunset
+            .as_deref()
+            .map(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| date))
+    }
+}
+
+/// Serving HTTPS without a reverse proxy in front, see tls::launch().
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// A PEM certificate chain. Turns on HTTPS for the API and gRPC when set with key_path.
+    pub cert_path: Option<String>,
+    /// The certificate's PEM private key.
+    pub key_path: Option<String>,
+}
+
+/// Taking the database down for a short while without losing footage, see maintenance.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceSettings {
+    /// Starts the server in maintenance mode. Admins can also turn it on with PUT /Admin/Maintenance.
+    pub enabled: bool,
+    /// Sent as Retry-After with the 503s for requests refused during maintenance.
+    pub retry_after_seconds: u64,
+    /// Where images uploaded during maintenance wait until it's over.
+    pub buffer_directory: String,
+    /// Images are refused once the buffer holds this much.
+    pub max_buffer_megabytes: u64,
+    /// Images from a camera are refused once it has this much in the buffer.
+    pub max_buffer_megabytes_per_camera: u64,
+    /// The database may be down during maintenance, so camera tokens are only checked against the cache. This many
+    /// images a minute are taken from each address with tokens that aren't cached.
+    pub unverified_images_per_minute: u64,
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> MaintenanceSettings {
+        MaintenanceSettings {
+            enabled: false,
+            retry_after_seconds: 60,
+            buffer_directory: String::from("maintenance-buffer"),
+            max_buffer_megabytes: 1024,
+            max_buffer_megabytes_per_camera: 64,
+            unverified_images_per_minute: 10,
+        }
+    }
+}
+
+/// Translations of error messages, notifications and emails, see i18n.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct I18nSettings {
+    /// Holds a <locale>.toml file per language, like fr.toml. English is built in.
+    pub locale_directory: String,
+}
+
+impl Default for I18nSettings {
+    fn default() -> I18nSettings {
+        I18nSettings {
+            locale_directory: String::from("locales"),
+        }
+    }
+}
+
+/// Mirroring cameras, events and images to a second server, see replication.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationSettings {
+    /// The secondary server, like https://offsite.example.com. Turns replication on when set.
+    pub target_url: Option<String>,
+    /// Sent to the secondary as replication_token. On the secondary, turns on the /Replication routes and is what
+    /// they check for.
+    pub token: Option<String>,
+    /// Sent with events, so a secondary can take events from more than one server.
+    pub origin: String,
+    /// Replicates images as well as cameras and events.
+    pub media: bool,
+    pub interval_seconds: u64,
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> ReplicationSettings {
+        ReplicationSettings {
+            target_url: None,
+            token: None,
+            origin: String::from("primary"),
+            media: false,
+            interval_seconds: 30,
+        }
+    }
+}
+
+/// Sharing cameras with users on other servers, see federation.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationSettings {
+    /// Where other servers can reach this one, like https://cameras.example.com. Turns federation on when set.
+    pub public_url: Option<String>,
+}
+
+/// Linking accounts to Google Assistant and Alexa, see oauth and voice_assistant.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoiceAssistantSettings {
+    /// Where the assistants can reach this server, for snapshot links. Defaults to public_url in [federation].
+    pub public_url: Option<String>,
+    /// The client ID and secret given to Google in the Actions console. Turns Google Assistant on when set.
+    pub google_client_id: Option<String>,
+    pub google_client_secret: Option<String>,
+    /// The client ID and secret given to Amazon for the skill's account linking. Turns Alexa on when set.
+    pub alexa_client_id: Option<String>,
+    pub alexa_client_secret: Option<String>,
+}
+
+/// Serving cameras back out to NVRs as ONVIF devices, see onvif and rtsp.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnvifSettings {
+    /// The port RTSP streams are served on. Turns the ONVIF facade on when set.
+    pub rtsp_port: Option<u16>,
+    /// How often a stream sends the camera's latest image, whether or not a newer one has arrived.
+    pub frame_interval_milliseconds: u64,
+}
+
+/// Cameras and encoders pushing video to the server, see rtmp, srt and recording.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestSettings {
+    /// The port to take RTMP on. Turns RTMP ingest on when set.
+    pub rtmp_port: Option<u16>,
+    /// Recordings are split into segments about this long, at the next keyframe after it.
+    pub segment_seconds: u64,
+    /// The UDP port to take SRT on. Turns SRT ingest on when set.
+    pub srt_port: Option<u16>,
+    /// How long lost SRT packets are waited for before they're skipped. Callers asking for longer get it.
+    pub srt_latency_milliseconds: u64,
+    /// How long a camera's segment is kept open after its SRT connection drops, so it carries on from where it left off
+    /// if the camera reconnects in time.
+    pub reconnect_seconds: u64,
+    /// RTMP and SRT streams that haven't sent any audio or video for this long are disconnected and their cameras
+    /// are asked to restart them, see ingest_watchdog.
+    pub stall_seconds: u64,
+}
+
+impl Default for IngestSettings {
+    fn default() -> IngestSettings {
+        IngestSettings {
+            rtmp_port: None,
+            segment_seconds: 60,
+            srt_port: None,
+            srt_latency_milliseconds: 500,
+            reconnect_seconds: 10,
+            stall_seconds: 15,
+        }
+    }
+}
+
+impl Default for OnvifSettings {
+    fn default() -> OnvifSettings {
+        OnvifSettings {
+            rtsp_port: None,
+            frame_interval_milliseconds: 1000,
+        }
+    }
+}
+
+/// Blurring faces on media in account exports and remote shares, see face_blur.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacySettings {
+    /// A face detector images are POSTed to as image/jpeg, responding like an analysis service. Every detection it
+    /// responds with is blurred.
+    pub face_detection_url: Option<String>,
+    /// A local program to run instead, with the image on stdin. Ignored if face_detection_url is set.
+    pub face_detection_command: Option<String>,
+}
+
+/// Running more than one instance behind a load balancer, see cluster::multiple_instances().
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScalingSettings {
+    /// Requires redis_url in [cache], so every instance shares caches, rate limits and realtime messages.
+    pub multiple_instances: bool,
+}
+
+/// Who can use the /Admin routes, see admin.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    /// Comma separated user IDs. Nobody is an admin if this isn't set.
+    pub user_ids: String,
+}
+
+impl AdminSettings {
+    /// Each ID in user_ids, or what it was if it isn't one.
+    pub fn user_ids(&self) -> impl Iterator<Item = Result<Uuid, &str>> {
+        self.user_ids
+            .split(',')
+            .map(str::trim)
+            .filter(|user_id| !user_id.is_empty())
+            .map(|user_id| Uuid::parse_str(user_id).map_err(|_| user_id))
+    }
+}
+
+/// Caching tokens, cameras and the like, see cache.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Where Redis is, like redis://localhost:6379. Caches stay in the process if this isn't set.
+    pub redis_url: Option<String>,
+    /// How many connections to keep to Redis.
+    pub redis_pool_size: u32,
+    /// How long entries are kept for. Anything revoked on another instance stays usable on this one for up to this
+    /// long with the in-process cache.
+    pub ttl_seconds: u64,
+    /// How long each user's camera list is kept for ListCameras. Kept short, as cameras checking in don't clear it.
+    /// 0 turns it off.
+    pub camera_list_ttl_seconds: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> CacheSettings {
+        CacheSettings {
+            redis_url: None,
+            redis_pool_size: 16,
+            ttl_seconds: 60,
+            camera_list_ttl_seconds: 5,
+        }
+    }
+}
+
+/// Letting browser frontends on other origins call the API, see cors.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsSettings {
+    /// Comma separated origins, like https://dashboard.example.com, or * for any. Nothing is allowed cross-origin if this
+    /// isn't set.
+    pub allowed_origins: String,
+    /// Lets browsers send cookies and auth headers. Can't be used with *.
+    pub allow_credentials: bool,
+}
+
+impl CorsSettings {
+    /// Each origin in allowed_origins, without a trailing slash.
+    pub fn allowed_origins(&self) -> impl Iterator<Item = &str> {
+        self.allowed_origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/'))
+            .filter(|origin| !origin.is_empty())
+    }
+}
+
+/// Push notifications, see push. Platforms that aren't set up are skipped.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushSettings {
+    /// Turns on FCM, for Android.
+    pub fcm_server_key: Option<String>,
+    /// The .p8 key for APNs, for iOS. Turns APNs on when set, along with apns_key_id, apns_team_id and apns_topic.
+    pub apns_key_path: Option<String>,
+    pub apns_key_id: Option<String>,
+    pub apns_team_id: Option<String>,
+    /// The app's bundle ID.
+    pub apns_topic: Option<String>,
+    /// Sends to APNs' development environment, for builds that aren't from the App Store.
+    pub apns_sandbox: bool,
+}
+
+/// Text message alerts, see sms. They're never sent if neither Twilio nor a gateway is set up.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmsSettings {
+    /// The number messages are sent from. Required if Twilio or a gateway is set up.
+    pub from: Option<String>,
+    /// Turns Twilio on when set, along with twilio_auth_token.
+    pub twilio_account_sid: Option<String>,
+    pub twilio_auth_token: Option<String>,
+    pub twilio_api_url: String,
+    /// A gateway messages are POSTed to as JSON instead. Ignored if twilio_account_sid is set.
+    pub gateway_url: Option<String>,
+    /// Sent to the gateway as the Authorization header.
+    pub gateway_authorization: Option<String>,
+}
+
+impl Default for SmsSettings {
+    fn default() -> SmsSettings {
+        SmsSettings {
+            from: None,
+            twilio_account_sid: None,
+            twilio_auth_token: None,
+            twilio_api_url: String::from("https://api.twilio.com"),
+            gateway_url: None,
+            gateway_authorization: None,
+        }
+    }
+}
+
+/// How logs are written, see logging. What gets logged is still set with RUST_LOG.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    /// text for human-readable lines, or json for one JSON object per line, for shipping logs to Loki or
+    /// Elasticsearch.
+    pub format: String,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> LoggingSettings {
+        LoggingSettings {
+            format: String::from("text"),
+        }
+    }
+}
+
+/// The background job queue, see jobs.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobSettings {
+    /// How many jobs each instance runs at once.
+    pub workers: usize,
+}
+
+impl Default for JobSettings {
+    fn default() -> JobSettings {
+        JobSettings { workers: 2 }
+    }
+}
+
+/// Object detection on uploaded images, see analysis.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisSettings {
+    /// An analysis service images are POSTed to as image/jpeg. Turns analysis on when set.
+    pub url: Option<String>,
+    /// A local program to run instead, with the image on stdin. Ignored if url is set.
+    pub command: Option<String>,
+    /// Raises an event for detections whose label is an event type, like person.
+    pub raise_events: bool,
+    /// How confident (0 to 1) the analyser has to be before a detection raises an event.
+    pub event_min_confidence: f32,
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> AnalysisSettings {
+        AnalysisSettings {
+            url: None,
+            command: None,
+            raise_events: false,
+            event_min_confidence: 0.6,
+        }
+    }
+}
+
+/// Raising events when a camera is much busier than usual, see anomaly.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalySettings {
+    pub detection: bool,
+    /// How many days of events baselines are worked out from.
+    pub baseline_days: i64,
+    /// How many standard deviations above the mean an hour has to be to count as unusual.
+    pub threshold: f64,
+}
+
+impl Default for AnomalySettings {
+    fn default() -> AnomalySettings {
+        AnomalySettings {
+            detection: false,
+            baseline_days: 28,
+            threshold: 3.0,
+        }
+    }
+}
+
+/// Cameras showing up in Home Assistant through MQTT discovery, see home_assistant. Needs host in [mqtt].
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeAssistantSettings {
+    pub discovery: bool,
+    /// The topic prefix Home Assistant listens for discovery on. homeassistant is also Home Assistant's default.
+    pub discovery_prefix: String,
+}
+
+impl Default for HomeAssistantSettings {
+    fn default() -> HomeAssistantSettings {
+        HomeAssistantSettings {
+            discovery: false,
+            discovery_prefix: String::from("homeassistant"),
+        }
+    }
+}
+
+/// Which images count as footage of an event, see event_media.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventMediaSettings {
+    /// How long before an event an image can be taken and still count.
+    pub window_before_seconds: i64,
+    /// How long after an event an image can be taken and still count.
+    pub window_after_seconds: i64,
+}
+
+impl Default for EventMediaSettings {
+    fn default() -> EventMediaSettings {
+        EventMediaSettings {
+            window_before_seconds: 10,
+            window_after_seconds: 30,
+        }
+    }
+}
+
+enum Kind {
+    Text,
+    Number,
+    Float,
+    Bool,
+}
+
+/// Every setting, with the environment variable it was read from before there was a settings file.
+/// Flags like SKIP_MIGRATIONS only had to be set, so any value (even an empty one) turns them on.
+const OVERRIDES: &[(&str, &str, Kind, Option<&str>)] = &[
+    ("database", "url", Kind::Text, None),
+    (
+        "database",
+        "startup_timeout_seconds",
+        Kind::Number,
+        Some("DATABASE_STARTUP_TIMEOUT_SECONDS"),
+    ),
+    (
+        "database",
+        "slow_query_ms",
+        Kind::Number,
+        Some("SLOW_QUERY_MS"),
+    ),
+    (
+        "database",
+        "skip_migrations",
+        Kind::Bool,
+        Some("SKIP_MIGRATIONS"),
+    ),
+    ("database", "schema_mismatch", Kind::Text, None),
+    (
+        "storage",
+        "images_directory",
+        Kind::Text,
+        Some("IMAGES_DIRECTORY"),
+    ),
+    (
+        "storage",
+        "cold_images_directory",
+        Kind::Text,
+        Some("COLD_IMAGES_DIRECTORY"),
+    ),
+    (
+        "storage",
+        "cold_storage_after_days",
+        Kind::Number,
+        Some("COLD_STORAGE_AFTER_DAYS"),
+    ),
+    (
+        "storage",
+        "audio_directory",
+        Kind::Text,
+        Some("AUDIO_DIRECTORY"),
+    ),
+    ("storage", "recordings_directory", Kind::Text, None),
+    ("storage", "backup_directory", Kind::Text, None),
+    ("storage", "export_directory", Kind::Text, None),
+    ("storage", "export_signing_key_path", Kind::Text, None),
+    ("storage", "firmware_directory", Kind::Text, None),
+    ("smtp", "host", Kind::Text, Some("SMTP_HOST")),
+    ("smtp", "username", Kind::Text, Some("SMTP_USERNAME")),
+    ("smtp", "password", Kind::Text, Some("SMTP_PASSWORD")),
+    ("smtp", "from", Kind::Text, Some("SMTP_FROM")),
+    ("mqtt", "host", Kind::Text, Some("MQTT_HOST")),
+    ("mqtt", "port", Kind::Number, Some("MQTT_PORT")),
+    ("mqtt", "client_id", Kind::Text, Some("MQTT_CLIENT_ID")),
+    ("mqtt", "username", Kind::Text, Some("MQTT_USERNAME")),
+    ("mqtt", "password", Kind::Text, Some("MQTT_PASSWORD")),
+    (
+        "mqtt",
+        "topic_prefix",
+        Kind::Text,
+        Some("MQTT_TOPIC_PREFIX"),
+    ),
+    ("mqtt", "ingest", Kind::Bool, Some("MQTT_INGEST")),
+    (
+        "limits",
+        "rate_limit_per_minute",
+        Kind::Number,
+        Some("RATE_LIMIT_PER_MINUTE"),
+    ),
+    (
+        "limits",
+        "snapshot_timeout_seconds",
+        Kind::Number,
+        Some("SNAPSHOT_TIMEOUT"),
+    ),
+    (
+        "limits",
+        "offline_after_seconds",
+        Kind::Number,
+        Some("OFFLINE_AFTER_SECONDS"),
+    ),
+    (
+        "limits",
+        "event_retention_days",
+        Kind::Number,
+        Some("EVENT_RETENTION_DAYS"),
+    ),
+    (
+        "limits",
+        "soft_delete_retention_days",
+        Kind::Number,
+        Some("SOFT_DELETE_RETENTION_DAYS"),
+    ),
+    ("limits", "audit_retention_days", Kind::Number, None),
+    ("limits", "export_expiry_hours", Kind::Number, None),
+    (
+        "limits",
+        "ingest_batch_size",
+        Kind::Number,
+        Some("INGEST_BATCH_SIZE"),
+    ),
+    (
+        "limits",
+        "ingest_batch_latency_ms",
+        Kind::Number,
+        Some("INGEST_BATCH_LATENCY_MS"),
+    ),
+    ("limits", "max_concurrent_uploads", Kind::Number, None),
+    (
+        "limits",
+        "max_concurrent_uploads_per_camera",
+        Kind::Number,
+        None,
+    ),
+    ("limits", "upload_queue_seconds", Kind::Number, None),
+    ("limits", "max_realtime_connections", Kind::Number, None),
+    ("limits", "max_clock_drift_seconds", Kind::Number, None),
+    ("limits", "camera_log_retention_hours", Kind::Number, None),
+    ("limits", "owner_daily_requests", Kind::Number, None),
+    ("limits", "owner_daily_megabytes", Kind::Number, None),
+    ("limits", "camera_daily_requests", Kind::Number, None),
+    ("limits", "camera_daily_megabytes", Kind::Number, None),
+    ("features", "streaming", Kind::Bool, None),
+    ("features", "webhooks", Kind::Bool, None),
+    ("scaling", "multiple_instances", Kind::Bool, None),
+    ("tls", "cert_path", Kind::Text, None),
+    ("tls", "key_path", Kind::Text, None),
+    ("server", "unix_socket", Kind::Text, None),
+    ("server", "unix_socket_mode", Kind::Text, None),
+    ("server", "trusted_proxies", Kind::Text, None),
+    ("server", "grpc_port", Kind::Number, Some("GRPC_PORT")),
+    (
+        "server",
+        "websocket_port",
+        Kind::Number,
+        Some("WEBSOCKET_PORT"),
+    ),
+    ("server", "coap_port", Kind::Number, Some("COAP_PORT")),
+    ("server", "graphql", Kind::Bool, Some("GRAPHQL_ENABLED")),
+    ("server", "legacy_sunset", Kind::Text, Some("LEGACY_SUNSET")),
+    (
+        "server",
+        "shutdown_timeout_seconds",
+        Kind::Number,
+        Some("SHUTDOWN_TIMEOUT_SECONDS"),
+    ),
+    ("maintenance", "enabled", Kind::Bool, None),
+    ("maintenance", "retry_after_seconds", Kind::Number, None),
+    ("maintenance", "buffer_directory", Kind::Text, None),
+    ("maintenance", "max_buffer_megabytes", Kind::Number, None),
+    (
+        "maintenance",
+        "max_buffer_megabytes_per_camera",
+        Kind::Number,
+        None,
+    ),
+    (
+        "maintenance",
+        "unverified_images_per_minute",
+        Kind::Number,
+        None,
+    ),
+    ("i18n", "locale_directory", Kind::Text, None),
+    ("replication", "target_url", Kind::Text, None),
+    ("replication", "token", Kind::Text, None),
+    ("replication", "origin", Kind::Text, None),
+    ("replication", "media", Kind::Bool, None),
+    ("replication", "interval_seconds", Kind::Number, None),
+    ("federation", "public_url", Kind::Text, None),
+    ("voice_assistants", "public_url", Kind::Text, None),
+    ("voice_assistants", "google_client_id", Kind::Text, None),
+    ("voice_assistants", "google_client_secret", Kind::Text, None),
+    ("voice_assistants", "alexa_client_id", Kind::Text, None),
+    ("voice_assistants", "alexa_client_secret", Kind::Text, None),
+    ("onvif", "rtsp_port", Kind::Number, None),
+    ("onvif", "frame_interval_milliseconds", Kind::Number, None),
+    ("ingest", "rtmp_port", Kind::Number, None),
+    ("ingest", "segment_seconds", Kind::Number, None),
+    ("ingest", "srt_port", Kind::Number, None),
+    ("ingest", "srt_latency_milliseconds", Kind::Number, None),
+    ("ingest", "reconnect_seconds", Kind::Number, None),
+    ("ingest", "stall_seconds", Kind::Number, None),
+    ("privacy", "face_detection_url", Kind::Text, None),
+    ("privacy", "face_detection_command", Kind::Text, None),
+    ("admin", "user_ids", Kind::Text, Some("ADMIN_USER_IDS")),
+    ("cache", "redis_url", Kind::Text, Some("REDIS_URL")),
+    (
+        "cache",
+        "redis_pool_size",
+        Kind::Number,
+        Some("REDIS_POOL_SIZE"),
+    ),
+    (
+        "cache",
+        "ttl_seconds",
+        Kind::Number,
+        Some("CACHE_TTL_SECONDS"),
+    ),
+    (
+        "cache",
+        "camera_list_ttl_seconds",
+        Kind::Number,
+        Some("CAMERA_LIST_CACHE_SECONDS"),
+    ),
+    (
+        "cors",
+        "allowed_origins",
+        Kind::Text,
+        Some("CORS_ALLOWED_ORIGINS"),
+    ),
+    (
+        "cors",
+        "allow_credentials",
+        Kind::Bool,
+        Some("CORS_ALLOW_CREDENTIALS"),
+    ),
+    ("push", "fcm_server_key", Kind::Text, Some("FCM_SERVER_KEY")),
+    ("push", "apns_key_path", Kind::Text, Some("APNS_KEY_PATH")),
+    ("push", "apns_key_id", Kind::Text, Some("APNS_KEY_ID")),
+    ("push", "apns_team_id", Kind::Text, Some("APNS_TEAM_ID")),
+    ("push", "apns_topic", Kind::Text, Some("APNS_TOPIC")),
+    ("push", "apns_sandbox", Kind::Bool, Some("APNS_SANDBOX")),
+    ("sms", "from", Kind::Text, Some("SMS_FROM")),
+    (
+        "sms",
+        "twilio_account_sid",
+        Kind::Text,
+        Some("TWILIO_ACCOUNT_SID"),
+    ),
+    (
+        "sms",
+        "twilio_auth_token",
+        Kind::Text,
+        Some("TWILIO_AUTH_TOKEN"),
+    ),
+    ("sms", "twilio_api_url", Kind::Text, Some("TWILIO_API_URL")),
+    ("sms", "gateway_url", Kind::Text, Some("SMS_GATEWAY_URL")),
+    (
+        "sms",
+        "gateway_authorization",
+        Kind::Text,
+        Some("SMS_GATEWAY_AUTHORIZATION"),
+    ),
+    ("logging", "format", Kind::Text, Some("LOG_FORMAT")),
+    ("jobs", "workers", Kind::Number, Some("JOB_WORKERS")),
+    ("analysis", "url", Kind::Text, Some("ANALYSIS_URL")),
+    ("analysis", "command", Kind::Text, Some("ANALYSIS_COMMAND")),
+    (
+        "analysis",
+        "raise_events",
+        Kind::Bool,
+        Some("ANALYSIS_RAISE_EVENTS"),
+    ),
+    (
+        "analysis",
+        "event_min_confidence",
+        Kind::Float,
+        Some("ANALYSIS_EVENT_MIN_CONFIDENCE"),
+    ),
+    (
+        "anomaly",
+        "detection",
+        Kind::Bool,
+        Some("ANOMALY_DETECTION"),
+    ),
+    (
+        "anomaly",
+        "baseline_days",
+        Kind::Number,
+        Some("ANOMALY_BASELINE_DAYS"),
+    ),
+    (
+        "anomaly",
+        "threshold",
+        Kind::Float,
+        Some("ANOMALY_THRESHOLD"),
+    ),
+    (
+        "home_assistant",
+        "discovery",
+        Kind::Bool,
+        Some("HOME_ASSISTANT_DISCOVERY"),
+    ),
+    (
+        "home_assistant",
+        "discovery_prefix",
+        Kind::Text,
+        Some("HOME_ASSISTANT_DISCOVERY_PREFIX"),
+    ),
+    (
+        "event_media",
+        "window_before_seconds",
+        Kind::Number,
+        Some("EVENT_MEDIA_WINDOW_BEFORE_SECONDS"),
+    ),
+    (
+        "event_media",
+        "window_after_seconds",
+        Kind::Number,
+        Some("EVENT_MEDIA_WINDOW_AFTER_SECONDS"),
+    ),
+];
+
+/// The environment variable that overrides `key` in `[section]`.
+pub fn override_name(section: &str, key: &str) -> String {
+    format!(
+        "CAMERA_SERVER_{}_{}",
+        section.to_uppercase(),
+        key.to_uppercase()
+    )
+}
+
+/// Turns an environment variable into the TOML value the setting would have in the file.
+fn parse_override(name: &str, value: String, kind: &Kind, legacy: bool) -> Result<Value, String> {
+    match kind {
+        Kind::Text => Ok(Value::String(value)),
+        Kind::Number => value
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("{} must be a whole number", name)),
+        Kind::Float => value
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| format!("{} must be a number", name)),
+        Kind::Bool if legacy => Ok(Value::Boolean(true)),
+        Kind::Bool => value
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| format!("{} must be true or false", name)),
+    }
+}
+
+fn read_file() -> Result<Table, String> {
+    let path = env::var("CAMERA_SERVER_CONFIG").ok();
+
+    let contents = match &path {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|error| format!("Failed to read {}: {}", path, error))?,
+        None if Path::new(DEFAULT_SETTINGS_PATH).exists() => {
+            fs::read_to_string(DEFAULT_SETTINGS_PATH)
+                .map_err(|error| format!("Failed to read {}: {}", DEFAULT_SETTINGS_PATH, error))?
+        }
+        None => return Ok(Table::new()),
+    };
+
+    toml::from_str(&contents).map_err(|error| {
+        format!(
+            "Failed to parse {}: {}",
+            path.as_deref().unwrap_or(DEFAULT_SETTINGS_PATH),
+            error
+        )
+    })
+}
+
+/// Checks what the types alone can't.
+fn validate(settings: &Settings) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if ![schema_check::REFUSE, schema_check::READ_ONLY]
+        .contains(&settings.database.schema_mismatch.as_str())
+    {
+        errors.push(format!(
+            "schema_mismatch in [database] must be {} or {}",
+            schema_check::REFUSE,
+            schema_check::READ_ONLY
+        ));
+    }
+
+    if settings.storage.images_directory.is_none() {
+        errors.push(String::from("images_directory in [storage] must be set"));
+    }
+
+    if settings.smtp.host.is_some() {
+        match &settings.smtp.from {
+            Some(from) if from.parse::<Mailbox>().is_err() => errors.push(format!(
+                "from in [smtp] ({}) is not a valid email address",
+                from
+            )),
+            Some(_) => {}
+            None => errors.push(String::from(
+                "from in [smtp] must be set when host in [smtp] is set",
+            )),
+        }
+    }
+
+    for (section, username, password) in &[
+        ("smtp", &settings.smtp.username, &settings.smtp.password),
+        ("mqtt", &settings.mqtt.username, &settings.mqtt.password),
+    ] {
+        if username.is_some() != password.is_some() {
+            errors.push(format!(
+                "username and password in [{}] must be set together",
+                section
+            ));
+        }
+    }
+
+    if settings.tls.cert_path.is_some() != settings.tls.key_path.is_some() {
+        errors.push(String::from(
+            "cert_path and key_path in [tls] must be set together",
+        ));
+    }
+
+    for (key, path) in &[
+        ("cert_path", &settings.tls.cert_path),
+        ("key_path", &settings.tls.key_path),
+    ] {
+        if let Some(path) = path {
+            if !Path::new(path).is_file() {
+                errors.push(format!("{} in [tls] ({}) doesn't exist", key, path));
+            }
+        }
+    }
+
+    for proxy in settings.server.trusted_proxies() {
+        if let Err(proxy) = proxy {
+            errors.push(format!(
+                "trusted_proxies in [server] has {}, which isn't an IP address",
+                proxy
+            ));
+        }
+    }
+
+    if u32::from_str_radix(&settings.server.unix_socket_mode, 8).is_err() {
+        errors.push(format!(
+            "unix_socket_mode in [server] ({}) must be octal, e.g. 660",
+            settings.server.unix_socket_mode
+        ));
+    }
+
+    if let Some(target_url) = &settings.replication.target_url {
+        if !target_url.starts_with("http://") && !target_url.starts_with("https://") {
+            errors.push(format!(
+                "target_url in [replication] ({}) must be http or https",
+                target_url
+            ));
+        }
+        if settings.replication.token.is_none() {
+            errors.push(String::from(
+                "token in [replication] must be set when target_url in [replication] is set",
+            ));
+        }
+    }
+
+    if let Some(public_url) = &settings.federation.public_url {
+        if !public_url.starts_with("http://") && !public_url.starts_with("https://") {
+            errors.push(format!(
+                "public_url in [federation] ({}) must be http or https",
+                public_url
+            ));
+        }
+    }
+
+    let voice_assistants = &settings.voice_assistants;
+    if let Some(public_url) = &voice_assistants.public_url {
+        if !public_url.starts_with("http://") && !public_url.starts_with("https://") {
+            errors.push(format!(
+                "public_url in [voice_assistants] ({}) must be http or https",
+                public_url
+            ));
+        }
+    }
+    for (assistant, client_id, client_secret) in &[
+        (
+            "google",
+            &voice_assistants.google_client_id,
+            &voice_assistants.google_client_secret,
+        ),
+        (
+            "alexa",
+            &voice_assistants.alexa_client_id,
+            &voice_assistants.alexa_client_secret,
+        ),
+    ] {
+        if client_id.is_some() != client_secret.is_some() {
+            errors.push(format!(
+                "{0}_client_id and {0}_client_secret in [voice_assistants] must be set together",
+                assistant
+            ));
+        }
+    }
+    if voice_assistants.google_client_id.is_some()
+        && voice_assistants.google_client_id == voice_assistants.alexa_client_id
+    {
+        errors.push(String::from(
+            "google_client_id and alexa_client_id in [voice_assistants] must be different",
+        ));
+    }
+
+    if settings.ingest.segment_seconds == 0 {
+        errors.push(String::from(
+            "segment_seconds in [ingest] must be more than 0",
+        ));
+    }
+    if settings.ingest.rtmp_port.is_some() && settings.ingest.rtmp_port == settings.onvif.rtsp_port
+    {
+        errors.push(String::from(
+            "rtmp_port in [ingest] and rtsp_port in [onvif] must be different",
+        ));
+    }
+    if settings.ingest.srt_latency_milliseconds == 0 {
+        errors.push(String::from(
+            "srt_latency_milliseconds in [ingest] must be more than 0",
+        ));
+    }
+
+    if settings.ingest.stall_seconds == 0 {
+        errors.push(String::from(
+            "stall_seconds in [ingest] must be more than 0",
+        ));
+    }
+
+    if settings.onvif.frame_interval_milliseconds == 0 {
+        errors.push(String::from(
+            "frame_interval_milliseconds in [onvif] must be more than 0",
+        ));
+    }
+
+    if settings.replication.interval_seconds == 0 {
+        errors.push(String::from(
+            "interval_seconds in [replication] must be more than 0",
+        ));
+    }
+
+    if settings.mqtt.ingest && settings.mqtt.host.is_none() {
+        errors.push(String::from(
+            "host in [mqtt] must be set when ingest in [mqtt] is on",
+        ));
+    }
+
+    if settings.scaling.multiple_instances && settings.cache.redis_url.is_none() {
+        errors.push(String::from(
+            "redis_url in [cache] must be set when multiple_instances in [scaling] is on",
+        ));
+    }
+
+    if let Some(redis_url) = &settings.cache.redis_url {
+        if redis::Client::open(redis_url.as_str()).is_err() {
+            errors.push(format!(
+                "redis_url in [cache] ({}) is not a Redis URL",
+                redis_url
+            ));
+        }
+    }
+
+    if settings.cache.redis_pool_size == 0 {
+        errors.push(String::from(
+            "redis_pool_size in [cache] must be more than 0",
+        ));
+    }
+
+    for user_id in settings.admin.user_ids() {
+        if let Err(user_id) = user_id {
+            errors.push(format!(
+                "user_ids in [admin] has {}, which isn't a user ID",
+                user_id
+            ));
+        }
+    }
+
+    if settings.cors.allow_credentials
+        && settings.cors.allowed_origins().any(|origin| origin == "*")
+    {
+        errors.push(String::from(
+            "allow_credentials in [cors] can't be on when allowed_origins in [cors] is *",
+        ));
+    }
+
+    let push = &settings.push;
+    if let Some(key_path) = &push.apns_key_path {
+        match fs::read(key_path) {
+            Ok(key) if EncodingKey::from_ec_pem(&key).is_err() => errors.push(format!(
+                "apns_key_path in [push] ({}) is not a .p8 key",
+                key_path
+            )),
+            Ok(_) => {}
+            Err(error) => errors.push(format!(
+                "apns_key_path in [push] ({}) can't be read: {}",
+                key_path, error
+            )),
+        }
+        for (key, value) in &[
+            ("apns_key_id", &push.apns_key_id),
+            ("apns_team_id", &push.apns_team_id),
+            ("apns_topic", &push.apns_topic),
+        ] {
+            if value.is_none() {
+                errors.push(format!(
+                    "{} in [push] must be set when apns_key_path in [push] is set",
+                    key
+                ));
+            }
+        }
+    }
+
+    let sms = &settings.sms;
+    if sms.twilio_account_sid.is_some() != sms.twilio_auth_token.is_some() {
+        errors.push(String::from(
+            "twilio_account_sid and twilio_auth_token in [sms] must be set together",
+        ));
+    }
+    if (sms.twilio_account_sid.is_some() || sms.gateway_url.is_some()) && sms.from.is_none() {
+        errors.push(String::from(
+            "from in [sms] must be set when twilio_account_sid or gateway_url in [sms] is set",
+        ));
+    }
+    for (key, url) in &[
+        ("twilio_api_url", Some(&sms.twilio_api_url)),
+        ("gateway_url", sms.gateway_url.as_ref()),
+    ] {
+        if let Some(url) = url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                errors.push(format!("{} in [sms] ({}) must be http or https", key, url));
+            }
+        }
+    }
+
+    if let Some(Err(date)) = settings.server.legacy_sunset() {
+        errors.push(format!(
+            "legacy_sunset in [server] ({}) must be a date like 2022-01-31",
+            date
+        ));
+    }
+
+    if !["text", "json"].contains(&settings.logging.format.as_str()) {
+        errors.push(format!(
+            "format in [logging] ({}) must be text or json",
+            settings.logging.format
+        ));
+    }
+
+    if settings.jobs.workers == 0 {
+        errors.push(String::from("workers in [jobs] must be more than 0"));
+    }
+
+    if !(0.0..=1.0).contains(&settings.analysis.event_min_confidence) {
+        errors.push(String::from(
+            "event_min_confidence in [analysis] must be between 0 and 1",
+        ));
+    }
+
+    if settings.anomaly.baseline_days <= 0 {
+        errors.push(String::from(
+            "baseline_days in [anomaly] must be more than 0",
+        ));
+    }
+    if settings.anomaly.threshold <= 0.0 {
+        errors.push(String::from("threshold in [anomaly] must be more than 0"));
+    }
+
+    for (key, seconds) in &[
+        (
+            "window_before_seconds",
+            settings.event_media.window_before_seconds,
+        ),
+        (
+            "window_after_seconds",
+            settings.event_media.window_after_seconds,
+        ),
+    ] {
+        if *seconds < 0 {
+            errors.push(format!("{} in [event_media] can't be negative", key));
+        }
+    }
+
+    if settings.limits.offline_after_seconds <= 0 {
+        errors.push(String::from(
+            "offline_after_seconds in [limits] must be more than 0",
+        ));
+    }
+
+    if settings.limits.export_expiry_hours <= 0 {
+        errors.push(String::from(
+            "export_expiry_hours in [limits] must be more than 0",
+        ));
+    }
+
+    for (key, value) in &[
+        ("ingest_batch_size", settings.limits.ingest_batch_size),
+        (
+            "max_concurrent_uploads",
+            settings.limits.max_concurrent_uploads,
+        ),
+        (
+            "max_concurrent_uploads_per_camera",
+            settings.limits.max_concurrent_uploads_per_camera,
+        ),
+        (
+            "max_realtime_connections",
+            settings.limits.max_realtime_connections,
+        ),
+    ] {
+        if *value == 0 {
+            errors.push(format!("{} in [limits] must be more than 0", key));
+        }
+    }
+
+    for (key, days) in &[
+        ("event_retention_days", settings.limits.event_retention_days),
+        (
+            "soft_delete_retention_days",
+            Some(settings.limits.soft_delete_retention_days),
+        ),
+        (
+            "audit_retention_days",
+            Some(settings.limits.audit_retention_days),
+        ),
+        (
+            "camera_log_retention_hours",
+            Some(settings.limits.camera_log_retention_hours),
+        ),
+    ] {
+        if days.map_or(false, |days| days < 0) {
+            errors.push(format!("{} in [limits] can't be negative", key));
+        }
+    }
+
+    errors
+}
+
+/// Reads the file, applies the environment variables and validates the result.
+/// Returns every problem found, rather than just the first.
+pub fn load() -> Result<Settings, Vec<String>> {
+    let mut file = read_file().map_err(|error| vec![error])?;
+    let mut errors = Vec::new();
+
+    for (section, key, kind, legacy_name) in OVERRIDES {
+        let name = override_name(section, key);
+
+        let value = match (env::var(&name), legacy_name) {
+            (Ok(value), _) => parse_override(&name, value, kind, false),
+            (Err(_), Some(legacy_name)) => match env::var(legacy_name) {
+                Ok(value) => parse_override(legacy_name, value, kind, true),
+                Err(_) => continue,
+            },
+            (Err(_), None) => continue,
+        };
+
+        match value {
+            Ok(value) => {
+                let table = file
+                    .entry(section.to_string())
+                    .or_insert(Value::Table(Table::new()));
+
+                match table.as_table_mut() {
+                    Some(table) => {
+                        table.insert(key.to_string(), value);
+                    }
+                    None => errors.push(format!("[{}] must be a table", section)),
+                }
+            }
+            Err(error) => errors.push(error),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let settings = Value::Table(file)
+        .try_into::<Settings>()
+        .map_err(|error| vec![format!("Invalid settings: {}", error)])?;
+
+    match validate(&settings) {
+        errors if errors.is_empty() => Ok(settings),
+        errors => Err(errors),
+    }
+}
+
+static SETTINGS: Lazy<Settings> = Lazy::new(|| {
+    load().unwrap_or_else(|errors| {
+        panic!("The server's settings are invalid!\n{}", errors.join("\n"))
+    })
+});
+
+/// The settings, loaded the first time they're needed. main() loads them first thing, so problems stop the server
+/// before it starts rather than when a setting is first used.
+pub fn settings() -> &'static Settings {
+    &SETTINGS
+}
@@ -0,0 +1,183 @@
+use crate::{
+    api_error::{error_code, ErrorBody},
+    api_version::{API_PREFIX, UNVERSIONED_PATHS},
+    request_id,
+    settings::settings,
+};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Header, Status};
+use rocket::{Data, Request, Response};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io::Cursor;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Requests that arrive while shutting down are routed here instead. Nothing is mounted at it.
+const SHUTTING_DOWN_PATH: &str = "/ShuttingDown";
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Requests that have been routed but haven't had their response sent yet.
+static IN_FLIGHT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Background work (a worker run, or a job) that has started but not finished.
+static RUNNING_WORK: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether SIGTERM or SIGINT has been received. Workers stop starting new work once it has.
+pub fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// How long to wait for in-flight requests and background work before exiting anyway,
+/// set with shutdown_timeout_seconds in [server]. Defaults to 30.
+pub fn shutdown_timeout() -> Duration {
+    Duration::from_secs(settings().server.shutdown_timeout_seconds)
+}
+
+/// Held while a piece of background work runs, so shutdown waits for it.
+pub struct RunningWork;
+
+impl Drop for RunningWork {
+    fn drop(&mut self) {
+        RUNNING_WORK.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns None once shutting down, so no new work is started.
+pub fn start_work() -> Option<RunningWork> {
+    RUNNING_WORK.fetch_add(1, Ordering::SeqCst);
+
+    // Checked after counting the work, so shutdown can't miss work that started just as it began
+    if shutting_down() {
+        RUNNING_WORK.fetch_sub(1, Ordering::SeqCst);
+        None
+    } else {
+        Some(RunningWork)
+    }
+}
+
+/// Waits for SIGTERM or SIGINT, then shuts down with drain_and_exit().
+pub fn spawn_signal_handler() {
+    let mut signals = Signals::new(&[SIGTERM, SIGINT]).expect("Failed to listen for signals!");
+
+    thread::spawn(move || {
+        if signals.forever().next().is_none() {
+            return;
+        }
+
+        drain_and_exit();
+    });
+}
+
+/// Stops taking requests and starting background work, waits for what's already running to finish
+/// (or for shutdown_timeout()), and exits. Exiting closes the database connections.
+/// Only returns if another thread is already shutting down.
+pub fn drain_and_exit() {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    info!("Shutting down, waiting for in-flight requests and background work to finish");
+
+    let deadline = Instant::now() + shutdown_timeout();
+
+    loop {
+        let requests = IN_FLIGHT_REQUESTS.load(Ordering::SeqCst);
+        let work = RUNNING_WORK.load(Ordering::SeqCst);
+
+        if requests == 0 && work == 0 {
+            info!("Finished everything in flight, exiting");
+            process::exit(0);
+        }
+
+        if Instant::now() >= deadline {
+            warn!(
+                "Exiting with {} requests and {} background tasks still running",
+                requests, work
+            );
+            process::exit(1);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Stored in a request's local cache. Whether the request was counted as in flight, or was turned away.
+struct Draining {
+    counted: bool,
+    refused: bool,
+}
+
+/// Counts requests in flight, and once shutting down refuses new ones with a 503 and Connection: close,
+/// so load balancers and clients go elsewhere. Health checks are still answered so /readyz can say it's shutting down.
+///
+/// Requests are counted until their response is handed back to Rocket, so uploads are stored before the server
+/// exits, but a large download can still be cut off if it's still being sent.
+pub struct ShutdownDraining;
+
+impl Fairing for ShutdownDraining {
+    fn info(&self) -> Info {
+        Info {
+            name: "Shutdown draining",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if shutting_down() && !UNVERSIONED_PATHS.contains(&request.uri().path()) {
+            match Origin::parse_owned(format!("{}{}", API_PREFIX, SHUTTING_DOWN_PATH)) {
+                Ok(origin) => request.set_uri(origin),
+                Err(error) => error!(
+                    "Failed to reroute request while shutting down! The error was {}",
+                    error
+                ),
+            }
+
+            request.local_cache(|| Draining {
+                counted: false,
+                refused: true,
+            });
+            return;
+        }
+
+        IN_FLIGHT_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        request.local_cache(|| Draining {
+            counted: true,
+            refused: false,
+        });
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let draining = request.local_cache(|| Draining {
+            counted: false,
+            refused: false,
+        });
+
+        if draining.counted {
+            IN_FLIGHT_REQUESTS.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        if draining.refused {
+            let body = ErrorBody {
+                code: error_code(Status::ServiceUnavailable),
+                message: "Server is shutting down, try again shortly",
+                details: Vec::new(),
+                request_id: request_id::current(),
+            }
+            .localized(request);
+
+            response.set_status(Status::ServiceUnavailable);
+            response.set_header(ContentType::JSON);
+            response.set_header(Header::new("Retry-After", "5"));
+            response.set_header(Header::new("Connection", "close"));
+            response.set_sized_body(Cursor::new(
+                serde_json::to_vec(&body).expect("Failed to serialize error body somehow?"),
+            ));
+        }
+    }
+}
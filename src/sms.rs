@@ -0,0 +1,320 @@
+use crate::{
+    api_error::ApiError, notification::SMS_CHANNEL, settings::settings, user_tokens::UserToken,
+    CameraServerDbConn,
+};
+
+use super::schema::{notifications, sms_settings};
+use chrono::{Datelike, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub const DEFAULT_MONTHLY_CAP: i32 = 50;
+
+/// Sends text messages through some SMS gateway.
+pub trait SmsProvider: Send {
+    fn send(&self, to: &str, body: &str) -> Result<(), String>;
+}
+
+/// Sends messages through Twilio's Messages API, or anything that speaks the same API.
+pub struct TwilioSmsProvider {
+    pub client: reqwest::blocking::Client,
+    /// Defaults to https://api.twilio.com, can be changed for Twilio-compatible providers.
+    pub api_url: String,
+    pub account_sid: String,
+    pub auth_token: String,
+    /// The number messages are sent from.
+    pub from: String,
+}
+
+impl SmsProvider for TwilioSmsProvider {
+    fn send(&self, to: &str, body: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .post(&format!(
+                "{}/2010-04-01/Accounts/{}/Messages.json",
+                self.api_url, self.account_sid
+            ))
+            .basic_auth(&self.account_sid, Some(&self.auth_token))
+            .form(&[("To", to), ("From", self.from.as_str()), ("Body", body)])
+            .send()
+            .map_err(|error| error.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Twilio responded with {}", response.status()))
+        }
+    }
+}
+
+/// POSTs {"to", "from", "body"} as JSON to a URL, for SMS gateways that aren't Twilio-compatible.
+pub struct HttpSmsProvider {
+    pub client: reqwest::blocking::Client,
+    pub url: String,
+    /// Sent as the Authorization header, if set.
+    pub authorization: Option<String>,
+    pub from: String,
+}
+
+impl SmsProvider for HttpSmsProvider {
+    fn send(&self, to: &str, body: &str) -> Result<(), String> {
+        let mut request = self.client.post(&self.url).json(&serde_json::json!({
+            "to": to,
+            "from": self.from,
+            "body": body,
+        }));
+
+        if let Some(authorization) = &self.authorization {
+            request = request.header("Authorization", authorization.as_str());
+        }
+
+        let response = request.send().map_err(|error| error.to_string())?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("SMS gateway responded with {}", response.status()))
+        }
+    }
+}
+
+/// Builds the SMS provider from [sms]. twilio_account_sid, twilio_auth_token and from (plus optionally twilio_api_url)
+/// configure Twilio, gateway_url, gateway_authorization and from configure a generic gateway. The settings make sure
+/// from is set with either.
+/// Returns None if neither is set, in which case SMS alerts are never sent.
+pub fn sms_provider() -> Option<Box<dyn SmsProvider>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build SMS HTTP client!");
+
+    let sms = &settings().sms;
+    let from = sms.from.clone().unwrap_or_default();
+
+    if let Some(account_sid) = sms.twilio_account_sid.clone() {
+        return Some(Box::new(TwilioSmsProvider {
+            client,
+            api_url: sms.twilio_api_url.clone(),
+            account_sid,
+            auth_token: sms.twilio_auth_token.clone().unwrap_or_default(),
+            from,
+        }));
+    }
+
+    sms.gateway_url.clone().map(|url| {
+        Box::new(HttpSmsProvider {
+            client,
+            url,
+            authorization: sms.gateway_authorization.clone(),
+            from,
+        }) as Box<dyn SmsProvider>
+    })
+}
+
+/// Where and when a user wants text messages. SMS costs money, so it is only used for alerts that really matter,
+/// and at most monthly_cap messages are sent to a user each calendar month (UTC).
+#[derive(Queryable, AsChangeset, Insertable, Deserialize, Serialize, JsonSchema)]
+#[table_name = "sms_settings"]
+#[primary_key(user_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct SmsSettings {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    /// In E.164 format, e.g. +447700900123.
+    pub phone_number: Option<String>,
+    pub enabled: bool,
+    /// Whether critical events are texted.
+    pub critical_alerts: bool,
+    /// Whether cameras going offline are texted.
+    pub offline_alerts: bool,
+    pub monthly_cap: i32,
+}
+
+impl SmsSettings {
+    pub fn default_for(user_id: uuid::Uuid) -> SmsSettings {
+        SmsSettings {
+            user_id,
+            phone_number: None,
+            enabled: false,
+            critical_alerts: true,
+            offline_alerts: true,
+            monthly_cap: DEFAULT_MONTHLY_CAP,
+        }
+    }
+}
+
+/// SMS settings as sent by the user.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct UpdatedSmsSettings {
+    pub phone_number: Option<String>,
+    pub enabled: bool,
+    pub critical_alerts: bool,
+    pub offline_alerts: bool,
+    pub monthly_cap: i32,
+}
+
+/// Returns the user's SMS settings, or the defaults (SMS turned off) if they haven't set any.
+pub fn get_settings(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<SmsSettings> {
+    sms_settings::table
+        .find(user_id)
+        .get_result::<SmsSettings>(connection)
+        .optional()
+        .map(|settings| settings.unwrap_or_else(|| SmsSettings::default_for(user_id)))
+}
+
+pub fn upsert_settings(
+    settings: SmsSettings,
+    connection: &PgConnection,
+) -> QueryResult<SmsSettings> {
+    diesel::insert_into(sms_settings::table)
+        .values(&settings)
+        .on_conflict(sms_settings::user_id)
+        .do_update()
+        .set(&settings)
+        .get_result(connection)
+}
+
+/// Counts the text messages queued for the user since the start of this month (UTC).
+pub fn count_sent_this_month(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<i64> {
+    let now = Utc::now();
+    let month_start = Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0);
+
+    notifications::table
+        .filter(notifications::user_id.eq(user_id))
+        .filter(notifications::channel.eq(SMS_CHANNEL))
+        .filter(notifications::created_at.ge(month_start))
+        .count()
+        .get_result(connection)
+}
+
+/// Whether a text message can be queued for the user: they have SMS turned on with a phone number,
+/// and they haven't hit their monthly cap.
+pub fn can_send(settings: &SmsSettings, connection: &PgConnection) -> QueryResult<bool> {
+    if !settings.enabled || settings.phone_number.is_none() {
+        return Ok(false);
+    }
+
+    Ok(count_sent_this_month(settings.user_id, connection)? < settings.monthly_cap as i64)
+}
+
+/// Texts the notification to the user's phone number.
+pub fn send_notification(
+    provider: &dyn SmsProvider,
+    user_id: uuid::Uuid,
+    title: &str,
+    body: &str,
+    connection: &PgConnection,
+) -> Result<(), String> {
+    let settings = get_settings(user_id, connection)
+        .map_err(|error| format!("Failed to get SMS settings: {}", error))?;
+
+    match settings.phone_number {
+        // The user may have turned SMS off since the message was queued
+        Some(phone_number) if settings.enabled => {
+            provider.send(&phone_number, &format!("{}: {}", title, body))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that a phone number looks like E.164: a + followed by 8 to 15 digits.
+pub fn validate_phone_number(phone_number: &str) -> Result<(), ApiError> {
+    let digits = phone_number.strip_prefix('+').unwrap_or("");
+
+    if !(8..=15).contains(&digits.len()) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ApiError {
+            error: "Phone number must be in E.164 format, e.g. +447700900123",
+            code: "invalid_phone_number",
+            status: Status::UnprocessableEntity,
+            field: Some("phone_number"),
+        });
+    }
+
+    Ok(())
+}
+
+#[openapi]
+#[get("/SmsSettings")]
+pub fn get_sms_settings(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<SmsSettings>, ApiError> {
+    get_settings(user_token.user_id, &conn)
+        .map(|settings| Json(settings))
+        .map_err(|error| {
+            error!(
+                "Failed to get SMS settings for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get SMS settings",
+                code: "get_sms_settings_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[put("/SmsSettings", data = "<updated_settings>", format = "json")]
+pub fn update_sms_settings(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    updated_settings: Json<UpdatedSmsSettings>,
+) -> Result<Json<SmsSettings>, ApiError> {
+    let updated_settings = updated_settings.into_inner();
+
+    if let Some(phone_number) = &updated_settings.phone_number {
+        validate_phone_number(phone_number)?;
+    }
+
+    if updated_settings.enabled && updated_settings.phone_number.is_none() {
+        return Err(ApiError {
+            error: "A phone number is needed to turn SMS alerts on",
+            code: "phone_number_required",
+            status: Status::UnprocessableEntity,
+            field: Some("phone_number"),
+        });
+    }
+
+    if updated_settings.monthly_cap < 0 {
+        return Err(ApiError {
+            error: "Monthly cap can't be negative",
+            code: "invalid_monthly_cap",
+            status: Status::UnprocessableEntity,
+            field: Some("monthly_cap"),
+        });
+    }
+
+    upsert_settings(
+        SmsSettings {
+            user_id: user_token.user_id,
+            phone_number: updated_settings.phone_number,
+            enabled: updated_settings.enabled,
+            critical_alerts: updated_settings.critical_alerts,
+            offline_alerts: updated_settings.offline_alerts,
+            monthly_cap: updated_settings.monthly_cap,
+        },
+        &conn,
+    )
+    .map(|settings| Json(settings))
+    .map_err(|error| {
+        error!(
+            "Failed to update SMS settings for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to update SMS settings",
+            code: "update_sms_settings_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
@@ -0,0 +1,703 @@
+use crate::{
+    api_error::ApiError,
+    camera::CameraId,
+    camera_commands::{self, InsertableCameraCommand, SNAPSHOT_COMMAND},
+    jobs,
+    page::{offset_and_limit, Page},
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{
+    camera_commands as camera_commands_table, cameras, scheduled_snapshots, snapshot_schedules,
+};
+use chrono::{Date, DateTime, Datelike, Duration as ChronoDuration, Timelike, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{delete, get, post, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+/// Asked the camera for the snapshot, waiting for it to arrive.
+pub const PENDING_STATUS: &str = "pending";
+/// The camera uploaded an image after it was asked to.
+pub const CAPTURED_STATUS: &str = "captured";
+/// Nothing arrived within CAPTURE_GRACE_MINUTES, usually because the camera was offline.
+pub const MISSED_STATUS: &str = "missed";
+pub const SNAPSHOT_STATUSES: [&str; 3] = [PENDING_STATUS, CAPTURED_STATUS, MISSED_STATUS];
+
+/// How long a camera has to upload a scheduled snapshot before it's marked as missed.
+pub const CAPTURE_GRACE_MINUTES: i64 = 5;
+/// Scheduled snapshots are forgotten after this many days. The images themselves are kept like any other.
+pub const SNAPSHOT_HISTORY_DAYS: i64 = 30;
+pub const MAX_SCHEDULES_PER_CAMERA: i64 = 10;
+
+/// When a schedule takes snapshots, as a cron expression in UTC: minute, hour, day of the month, month and day of
+/// the week (0 or 7 is Sunday). Fields can be *, numbers, ranges like 9-17, steps like */15 and lists of those,
+/// and @hourly, @daily and @weekly can be used instead.
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+/// Parses one field into a bit for each value it matches.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, &'static str> {
+    let mut values = 0;
+
+    for part in field.split(',') {
+        let mut pieces = part.splitn(2, '/');
+        let range = pieces.next().unwrap_or("");
+        let step = match pieces.next() {
+            Some(step) => step.parse::<u32>().map_err(|_| "Invalid step")?,
+            None => 1,
+        };
+        if step == 0 {
+            return Err("Steps can't be 0");
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let start = bounds
+                .next()
+                .unwrap_or("")
+                .parse::<u32>()
+                .map_err(|_| "Invalid value")?;
+            match bounds.next() {
+                Some(end) => (start, end.parse::<u32>().map_err(|_| "Invalid value")?),
+                None if step > 1 => (start, max),
+                None => (start, start),
+            }
+        };
+        if start < min || end > max || start > end {
+            return Err("Value out of range");
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            values |= 1 << value;
+        }
+    }
+
+    Ok(values)
+}
+
+fn matches(values: u64, value: u32) -> bool {
+    values & (1 << value) != 0
+}
+
+impl CronExpression {
+    pub fn parse(expression: &str) -> Result<CronExpression, &'static str> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            expression => expression,
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err("Expressions need 5 fields");
+        }
+
+        let weekdays = parse_field(fields[4], 0, 7)?;
+
+        Ok(CronExpression {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    /// Like cron, a day matches if either its day of the month or day of the week does, when both are given.
+    fn matches_date(&self, date: Date<Utc>) -> bool {
+        if !matches(self.months, date.month()) {
+            return false;
+        }
+
+        let day = matches(self.days, date.day());
+        let weekday = matches(self.weekdays, date.weekday().num_days_from_sunday());
+
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            (true, false) => day,
+            (false, true) => weekday,
+            (false, false) => true,
+        }
+    }
+
+    /// The first minute after `after` the expression matches. None if it never does, like 0 0 30 2 *.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+
+        // Long enough for the expression to have come round to every day, including leap days
+        for days_ahead in 0..(4 * 366) {
+            let date = start.date() + ChronoDuration::days(days_ahead);
+            if !self.matches_date(date) {
+                continue;
+            }
+
+            let first_hour = if days_ahead == 0 { start.hour() } else { 0 };
+            for hour in (first_hour..24).filter(|hour| matches(self.hours, *hour)) {
+                let first_minute = if days_ahead == 0 && hour == start.hour() {
+                    start.minute()
+                } else {
+                    0
+                };
+
+                if let Some(minute) =
+                    (first_minute..60).find(|minute| matches(self.minutes, *minute))
+                {
+                    return date.and_hms_opt(hour, minute, 0);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Works out when a schedule next takes a snapshot, turning invalid expressions away.
+fn next_run(expression: &str, after: DateTime<Utc>) -> Result<DateTime<Utc>, ApiError> {
+    CronExpression::parse(expression)
+        .ok()
+        .and_then(|cron| cron.next_after(after))
+        .ok_or(ApiError {
+            error: "Expression must be 5 cron fields (minute hour day month weekday) that can match, like 0 * * * *",
+            code: "invalid_cron_expression",
+            status: Status::UnprocessableEntity,
+            field: Some("expression"),
+        })
+}
+
+/// Takes a snapshot whenever its cron expression matches, for stills on a timetable rather than when there's
+/// motion.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct SnapshotSchedule {
+    pub schedule_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub expression: String,
+    pub enabled: bool,
+    pub next_run_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What the user sends to add or change a schedule.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewSnapshotSchedule {
+    pub expression: String,
+    /// Defaults to true.
+    pub enabled: Option<bool>,
+}
+
+/// One snapshot a schedule asked for.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct ScheduledSnapshot {
+    pub snapshot_id: i32,
+    pub schedule_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    #[serde(skip)]
+    pub command_id: Option<i32>,
+    pub scheduled_for: DateTime<Utc>,
+    pub requested_at: DateTime<Utc>,
+    /// pending, captured or missed.
+    pub status: String,
+    /// The image the camera uploaded, for GET /Cameras/<camera_id>/Images/<image_id>.
+    pub image_id: Option<i64>,
+    pub checked_at: Option<DateTime<Utc>>,
+}
+
+/// Query string for GET /Cameras/<camera_id>/SnapshotSchedules/<schedule_id>/Snapshots.
+#[derive(FromForm, JsonSchema)]
+pub struct ScheduledSnapshotQuery {
+    /// Only snapshots with this status, e.g. missed.
+    pub status: Option<String>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to update snapshot schedule! The error was {}",
+        error
+    );
+    ApiError {
+        error: "Failed to update snapshot schedule",
+        code: "update_snapshot_schedule_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn schedule_not_found() -> ApiError {
+    ApiError {
+        error: "Snapshot schedule not found",
+        code: "snapshot_schedule_not_found",
+        status: Status::NotFound,
+        field: None,
+    }
+}
+
+/// Marks pending snapshots as captured once the camera has uploaded the image for their command, see
+/// camera_commands::record_snapshot(), or as missed once CAPTURE_GRACE_MINUTES have passed without one. Missed
+/// snapshots have their command withdrawn if the camera never picked it up. Returns how many were missed.
+fn check_pending(connection: &PgConnection) -> QueryResult<usize> {
+    let now = Utc::now();
+    let mut missed = 0;
+
+    let pending = scheduled_snapshots::table
+        .filter(scheduled_snapshots::status.eq(PENDING_STATUS))
+        .load::<ScheduledSnapshot>(connection)?;
+
+    for snapshot in pending {
+        let image_id = match snapshot.command_id {
+            Some(command_id) => camera_commands_table::table
+                .find(command_id)
+                .select(camera_commands_table::image_id)
+                .first::<Option<i64>>(connection)
+                .optional()?
+                .flatten(),
+            None => None,
+        };
+
+        let status = if image_id.is_some() {
+            CAPTURED_STATUS
+        } else if snapshot.requested_at + ChronoDuration::minutes(CAPTURE_GRACE_MINUTES) < now {
+            missed += 1;
+            warn!(
+                "Camera {} missed its scheduled snapshot for {}",
+                snapshot.camera_id, snapshot.scheduled_for
+            );
+
+            if let Some(command_id) = snapshot.command_id {
+                diesel::delete(
+                    camera_commands_table::table
+                        .find(command_id)
+                        .filter(camera_commands_table::delivered.eq(false)),
+                )
+                .execute(connection)?;
+            }
+
+            MISSED_STATUS
+        } else {
+            continue;
+        };
+
+        diesel::update(scheduled_snapshots::table.find(snapshot.snapshot_id))
+            .set((
+                scheduled_snapshots::status.eq(status),
+                scheduled_snapshots::image_id.eq(image_id),
+                scheduled_snapshots::checked_at.eq(now),
+            ))
+            .execute(connection)?;
+    }
+
+    Ok(missed)
+}
+
+/// Asks cameras for every snapshot their schedules are due, then checks on earlier ones with check_pending(). A
+/// schedule that fell behind, say while the server was down, only takes one snapshot to catch up. Returns how many
+/// snapshots were asked for and how many were missed.
+pub fn run_due(connection: &PgConnection) -> QueryResult<(usize, usize)> {
+    let now = Utc::now();
+
+    let due = snapshot_schedules::table
+        .filter(snapshot_schedules::enabled.eq(true))
+        .filter(snapshot_schedules::next_run_at.le(now))
+        .filter(
+            snapshot_schedules::camera_id.eq_any(
+                cameras::table
+                    .filter(cameras::deleted_at.is_null())
+                    .select(cameras::camera_id),
+            ),
+        )
+        .load::<SnapshotSchedule>(connection)?;
+
+    for schedule in &due {
+        connection.transaction(|| {
+            let command = camera_commands::insert(
+                InsertableCameraCommand {
+                    camera_id: schedule.camera_id,
+                    command: SNAPSHOT_COMMAND.to_string(),
+                    expires_at: Some(now + ChronoDuration::minutes(CAPTURE_GRACE_MINUTES)),
+                },
+                connection,
+            )?;
+
+            diesel::insert_into(scheduled_snapshots::table)
+                .values((
+                    scheduled_snapshots::schedule_id.eq(schedule.schedule_id),
+                    scheduled_snapshots::camera_id.eq(schedule.camera_id),
+                    scheduled_snapshots::command_id.eq(command.command_id),
+                    scheduled_snapshots::scheduled_for.eq(schedule.next_run_at),
+                    scheduled_snapshots::requested_at.eq(now),
+                ))
+                .execute(connection)?;
+
+            // The expression was checked when it was saved, so it always has a next run
+            let next_run_at = CronExpression::parse(&schedule.expression)
+                .ok()
+                .and_then(|cron| cron.next_after(now))
+                .unwrap_or(now + ChronoDuration::days(365));
+
+            diesel::update(snapshot_schedules::table.find(schedule.schedule_id))
+                .set((
+                    snapshot_schedules::next_run_at.eq(next_run_at),
+                    snapshot_schedules::last_run_at.eq(now),
+                ))
+                .execute(connection)
+        })?;
+    }
+
+    let missed = check_pending(connection)?;
+
+    diesel::delete(scheduled_snapshots::table.filter(
+        scheduled_snapshots::requested_at.lt(now - ChronoDuration::days(SNAPSHOT_HISTORY_DAYS)),
+    ))
+    .execute(connection)?;
+
+    Ok((due.len(), missed))
+}
+
+/// Starts the thread that queues a job to run snapshot schedules every minute, see jobs::SNAPSHOT_SCHEDULES_JOB.
+pub fn spawn_schedule_worker(database_url: String) {
+    worker::spawn_worker(
+        "Snapshot schedules",
+        Duration::from_secs(60),
+        database_url,
+        |connection| {
+            if let Err(error) = jobs::enqueue_unless_pending(
+                jobs::SNAPSHOT_SCHEDULES_JOB,
+                json!({}),
+                Utc::now(),
+                connection,
+            ) {
+                error!(
+                    "Failed to queue snapshot schedules! The error was {}",
+                    error
+                );
+            }
+        },
+    );
+}
+
+fn get_schedule(
+    camera_id: uuid::Uuid,
+    schedule_id: i32,
+    connection: &PgConnection,
+) -> Result<SnapshotSchedule, ApiError> {
+    snapshot_schedules::table
+        .find(schedule_id)
+        .filter(snapshot_schedules::camera_id.eq(camera_id))
+        .get_result::<SnapshotSchedule>(connection)
+        .optional()
+        .map_err(database_error)?
+        .ok_or_else(schedule_not_found)
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>/SnapshotSchedules")]
+pub fn list_snapshot_schedules(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Vec<SnapshotSchedule>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    snapshot_schedules::table
+        .filter(snapshot_schedules::camera_id.eq(camera_id))
+        .order(snapshot_schedules::schedule_id)
+        .load::<SnapshotSchedule>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Adds a schedule that has the camera take a snapshot whenever its cron expression matches, like 0 * * * * for
+/// every hour. Cameras can have up to 10.
+#[openapi]
+#[post(
+    "/Cameras/<camera_id>/SnapshotSchedules",
+    format = "json",
+    data = "<new_schedule>"
+)]
+pub fn add_snapshot_schedule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_schedule: Json<NewSnapshotSchedule>,
+) -> Result<Json<SnapshotSchedule>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    let new_schedule = new_schedule.into_inner();
+    let expression = new_schedule.expression.trim();
+    let next_run_at = next_run(expression, Utc::now())?;
+
+    let schedules = snapshot_schedules::table
+        .filter(snapshot_schedules::camera_id.eq(camera_id))
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+    if schedules >= MAX_SCHEDULES_PER_CAMERA {
+        return Err(ApiError {
+            error: "Cameras can have at most 10 snapshot schedules",
+            code: "too_many_snapshot_schedules",
+            status: Status::Conflict,
+            field: None,
+        });
+    }
+
+    diesel::insert_into(snapshot_schedules::table)
+        .values((
+            snapshot_schedules::camera_id.eq(camera_id),
+            snapshot_schedules::expression.eq(expression),
+            snapshot_schedules::enabled.eq(new_schedule.enabled.unwrap_or(true)),
+            snapshot_schedules::next_run_at.eq(next_run_at),
+        ))
+        .get_result::<SnapshotSchedule>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Changes a schedule's expression, or pauses and resumes it. Its next run is worked out again from now, so a
+/// resumed schedule doesn't make up for the snapshots it skipped.
+#[openapi]
+#[put(
+    "/Cameras/<camera_id>/SnapshotSchedules/<schedule_id>",
+    format = "json",
+    data = "<new_schedule>"
+)]
+pub fn update_snapshot_schedule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    schedule_id: i32,
+    new_schedule: Json<NewSnapshotSchedule>,
+) -> Result<Json<SnapshotSchedule>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+    get_schedule(camera_id, schedule_id, &conn)?;
+
+    let new_schedule = new_schedule.into_inner();
+    let expression = new_schedule.expression.trim();
+    let next_run_at = next_run(expression, Utc::now())?;
+
+    diesel::update(snapshot_schedules::table.find(schedule_id))
+        .set((
+            snapshot_schedules::expression.eq(expression),
+            snapshot_schedules::enabled.eq(new_schedule.enabled.unwrap_or(true)),
+            snapshot_schedules::next_run_at.eq(next_run_at),
+        ))
+        .get_result::<SnapshotSchedule>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+#[openapi]
+#[delete("/Cameras/<camera_id>/SnapshotSchedules/<schedule_id>")]
+pub fn delete_snapshot_schedule(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    schedule_id: i32,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    diesel::delete(
+        snapshot_schedules::table
+            .find(schedule_id)
+            .filter(snapshot_schedules::camera_id.eq(camera_id)),
+    )
+    .execute(&*conn)
+    .map_err(database_error)
+    .and_then(|deleted| match deleted {
+        0 => Err(schedule_not_found()),
+        _ => Ok(()),
+    })
+}
+
+/// The snapshots a schedule has asked for over the last 30 days, newest first. Pass ?status=missed to see when
+/// the camera didn't deliver.
+#[openapi]
+#[get("/Cameras/<camera_id>/SnapshotSchedules/<schedule_id>/Snapshots?<query..>")]
+pub fn list_scheduled_snapshots(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    schedule_id: i32,
+    query: Form<ScheduledSnapshotQuery>,
+) -> Result<Json<Page<ScheduledSnapshot>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+    get_schedule(camera_id, schedule_id, &conn)?;
+
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+    if let Some(status) = &query.status {
+        if !SNAPSHOT_STATUSES.contains(&status.as_str()) {
+            return Err(ApiError {
+                error: "Status must be pending, captured or missed",
+                code: "invalid_status",
+                status: Status::UnprocessableEntity,
+                field: Some("status"),
+            });
+        }
+    }
+
+    let filtered = || {
+        let mut filtered = scheduled_snapshots::table
+            .filter(scheduled_snapshots::schedule_id.eq(schedule_id))
+            .into_boxed();
+
+        if let Some(status) = &query.status {
+            filtered = filtered.filter(scheduled_snapshots::status.eq(status));
+        }
+
+        filtered
+    };
+
+    let items = filtered()
+        .order(scheduled_snapshots::snapshot_id.desc())
+        .limit(limit)
+        .offset(offset)
+        .load::<ScheduledSnapshot>(&*conn)
+        .map_err(database_error)?;
+
+    let total = filtered()
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn next(expression: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        CronExpression::parse(expression)
+            .expect("Failed to parse the expression!")
+            .next_after(after)
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(parse_field("*", 0, 3), Ok(0b1111));
+        assert_eq!(parse_field("2", 0, 59), Ok(1 << 2));
+        assert_eq!(parse_field("1-3", 0, 59), Ok(0b1110));
+        assert_eq!(
+            parse_field("*/15", 0, 59),
+            Ok(1 | 1 << 15 | 1 << 30 | 1 << 45)
+        );
+        assert_eq!(parse_field("10/20", 0, 59), Ok(1 << 10 | 1 << 30 | 1 << 50));
+        assert_eq!(parse_field("1,5-6", 0, 59), Ok(1 << 1 | 1 << 5 | 1 << 6));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+        assert!(parse_field("", 0, 59).is_err());
+        assert!(parse_field("1-", 0, 59).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(CronExpression::parse("* * * *").is_err());
+        assert!(CronExpression::parse("* * * * * *").is_err());
+        assert!(CronExpression::parse("@yearly").is_err());
+        assert!(CronExpression::parse("* 24 * * *").is_err());
+    }
+
+    #[test]
+    fn finds_the_next_minute() {
+        let after = at(2021, 6, 1, 12, 30);
+
+        assert_eq!(next("* * * * *", after), Some(at(2021, 6, 1, 12, 31)));
+        assert_eq!(next("*/15 * * * *", after), Some(at(2021, 6, 1, 12, 45)));
+        assert_eq!(next("30 * * * *", after), Some(at(2021, 6, 1, 13, 30)));
+        assert_eq!(next("@hourly", after), Some(at(2021, 6, 1, 13, 0)));
+        assert_eq!(next("@daily", after), Some(at(2021, 6, 2, 0, 0)));
+        assert_eq!(
+            next("0 9-17 * * *", at(2021, 6, 1, 17, 0)),
+            Some(at(2021, 6, 2, 9, 0))
+        );
+    }
+
+    #[test]
+    fn ignores_seconds_in_the_start() {
+        let after = Utc.ymd(2021, 6, 1).and_hms(12, 30, 59);
+
+        assert_eq!(next("* * * * *", after), Some(at(2021, 6, 1, 12, 31)));
+    }
+
+    #[test]
+    fn treats_7_as_sunday() {
+        // 2021-06-06 was a Sunday
+        assert_eq!(
+            next("0 0 * * 7", at(2021, 6, 1, 0, 0)),
+            Some(at(2021, 6, 6, 0, 0))
+        );
+        assert_eq!(
+            next("@weekly", at(2021, 6, 1, 0, 0)),
+            Some(at(2021, 6, 6, 0, 0))
+        );
+    }
+
+    #[test]
+    fn matches_either_day_when_both_are_given() {
+        // The 15th, or any Monday, whichever comes first. 2021-06-07 was a Monday
+        assert_eq!(
+            next("0 0 15 * 1", at(2021, 6, 1, 0, 0)),
+            Some(at(2021, 6, 7, 0, 0))
+        );
+        assert_eq!(
+            next("0 0 15 * *", at(2021, 6, 1, 0, 0)),
+            Some(at(2021, 6, 15, 0, 0))
+        );
+    }
+
+    #[test]
+    fn finds_leap_days() {
+        assert_eq!(
+            next("0 0 29 2 *", at(2021, 3, 1, 0, 0)),
+            Some(at(2024, 2, 29, 0, 0))
+        );
+    }
+
+    #[test]
+    fn gives_up_on_days_that_never_come() {
+        assert_eq!(next("0 0 30 2 *", at(2021, 1, 1, 0, 0)), None);
+        assert_eq!(next("0 0 31 4 *", at(2021, 1, 1, 0, 0)), None);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audio,
+    camera::{self, Camera, CameraId},
+    jobs,
+    media_store::{media_store, MediaStore},
+    recording,
+    settings::settings,
+    user::{self, UserInfo},
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{cameras, media_holds, users};
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{delete, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use serde_json::json;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+/// How many days deleted cameras and users are kept for before they're purged, set with
+/// soft_delete_retention_days in [limits]. Defaults to 30.
+pub fn soft_delete_retention_days() -> i64 {
+    settings().limits.soft_delete_retention_days
+}
+
+/// Deletes the camera's images, audio clips and recordings from storage.
+fn delete_footage(camera_id: &uuid::Uuid) -> io::Result<()> {
+    let store = media_store();
+
+    for image_id in store.list_images(camera_id)? {
+        store.delete_image(camera_id, image_id)?;
+    }
+
+    for directory in &[audio::audio_directory(), recording::recordings_directory()] {
+        match fs::remove_dir_all(format!("{}/{}", directory, camera_id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes cameras and users that were deleted more than `retention_days` ago for good, along with the cameras' footage.
+/// Returns how many cameras and users were purged. Cameras whose footage can't be deleted are left for the next run,
+/// and cameras with footage under a hold are left until every hold is released, see media_hold.
+pub fn purge_deleted(
+    retention_days: i64,
+    connection: &PgConnection,
+) -> QueryResult<(usize, usize)> {
+    let cutoff = Utc::now() - ChronoDuration::days(retention_days);
+
+    let camera_ids = cameras::table
+        .filter(cameras::deleted_at.lt(cutoff))
+        .filter(cameras::camera_id.ne_all(media_holds::table.select(media_holds::camera_id)))
+        .select(cameras::camera_id)
+        .load::<uuid::Uuid>(connection)?;
+
+    let mut purged_cameras = 0;
+
+    for camera_id in camera_ids {
+        if let Err(error) = delete_footage(&camera_id) {
+            error!(
+                "Failed to delete footage from deleted camera {}! The error was {}",
+                camera_id, error
+            );
+            continue;
+        }
+
+        // Everything else the camera had goes with it, see the migrations' ON DELETE CASCADEs
+        purged_cameras += camera::delete(camera_id, connection)?;
+    }
+
+    let purged_users =
+        diesel::delete(users::table.filter(users::deleted_at.lt(cutoff))).execute(connection)?;
+
+    Ok((purged_cameras, purged_users))
+}
+
+/// Queues purging deleted cameras and users every hour.
+pub fn spawn_purge_worker(database_url: String) {
+    let retention_days = soft_delete_retention_days();
+
+    worker::spawn_worker(
+        "Deleted purging",
+        Duration::from_secs(60 * 60),
+        database_url,
+        move |connection| {
+            if let Err(error) = jobs::enqueue_unless_pending(
+                jobs::PURGE_DELETED_JOB,
+                json!({ "retention_days": retention_days }),
+                Utc::now(),
+                connection,
+            ) {
+                error!(
+                    "Failed to queue purging deleted cameras and users! The error was {}",
+                    error
+                );
+            }
+        },
+    );
+}
+
+/// Turns a query's error into a 404 if nothing was found, or else a 500. `not_found` and `failed` are the code and
+/// message for each.
+pub fn not_found_or_database_error(
+    error: diesel::result::Error,
+    not_found: (&'static str, &'static str),
+    failed: (&'static str, &'static str),
+) -> ApiError {
+    match error {
+        diesel::result::Error::NotFound => ApiError {
+            error: not_found.1,
+            code: not_found.0,
+            status: Status::NotFound,
+            field: None,
+        },
+        error => {
+            error!("{}! The error was {}", failed.1, error);
+            ApiError {
+                error: failed.1,
+                code: failed.0,
+                status: Status::InternalServerError,
+                field: None,
+            }
+        }
+    }
+}
+
+/// Brings back a camera that was deleted with DELETE /Cameras/<camera_id> and hasn't been purged yet.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Cameras/<camera_id>/Undelete")]
+pub fn undelete_camera(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    camera_id: CameraId,
+) -> Result<Json<Camera>, ApiError> {
+    camera::undelete(camera_id.into_inner(), &conn)
+        .map(|camera| Json(camera))
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("camera_not_found", "Camera not found"),
+                ("undelete_camera_failed", "Failed to undelete camera"),
+            )
+        })
+}
+
+/// Deletes the user and logs them out. They can be brought back until they're purged.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Users/<user_id>")]
+pub fn delete_user(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<(), ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    match user::soft_delete(user_id, &conn) {
+        Ok(0) => Err(ApiError {
+            error: "User not found",
+            code: "user_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        Ok(_) => Ok(()),
+        Err(error) => Err(not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("delete_user_failed", "Failed to delete user"),
+        )),
+    }
+}
+
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Users/<user_id>/Undelete")]
+pub fn undelete_user(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<UserInfo>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    user::undelete(user_id, &conn)
+        .map(|user| Json(UserInfo::from_user(user)))
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("undelete_user_failed", "Failed to undelete user"),
+            )
+        })
+}
+
+pub fn parse_user_id(user_id: &str) -> Result<uuid::Uuid, ApiError> {
+    uuid::Uuid::parse_str(user_id).map_err(|_| ApiError {
+        error: "Failed to parse user ID string",
+        code: "invalid_user_id",
+        status: Status::UnprocessableEntity,
+        field: None,
+    })
+}
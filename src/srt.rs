@@ -0,0 +1,1147 @@
+use crate::{
+    camera::{self, record_camera_contact},
+    ingest_watchdog::{self, WatchedStream},
+    recording::{RecordingWriter, SRT_SOURCE},
+    rtmp::segment_seconds,
+    settings::settings,
+    stream_keys,
+};
+
+use aes::{Aes128, Aes192, Aes256, Block, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use hmac::Hmac;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Recordings from SRT are kept as the MPEG-TS callers send, which plays from wherever it's cut.
+pub const TS_CONTENT_TYPE: &str = "video/mp2t";
+
+/// Callers that send nothing, not even keepalives, for this long are disconnected. The same as SRT's own peer idle
+/// timeout, so callers notice at about the same time and reconnect.
+pub const PEER_IDLE_SECONDS: u64 = 5;
+
+/// How often received packets are acknowledged, and timers checked.
+const TICK: Duration = Duration::from_millis(10);
+
+/// How often packets that are still missing are asked for again.
+const NAK_INTERVAL: Duration = Duration::from_millis(20);
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far past the next packet to deliver packets are buffered, while waiting for lost ones.
+const RECEIVE_BUFFER_PACKETS: u32 = 8192;
+
+/// The most lost packets put in one NAK, so it fits in a datagram.
+const MAX_NAK_ENTRIES: usize = 300;
+
+const MAX_DATAGRAM_BYTES: usize = 1500;
+const TS_PACKET_BYTES: usize = 188;
+const MAX_SEQ: u32 = 0x7FFF_FFFF;
+
+const SRT_VERSION: u32 = 0x0001_0401;
+const SRT_MAGIC: u16 = 0x4A17;
+const HEADER_BYTES: usize = 16;
+const HANDSHAKE_BYTES: usize = 48;
+
+// Handshake types
+const INDUCTION: u32 = 1;
+const CONCLUSION: u32 = 0xFFFF_FFFF;
+
+// Control packet types, see the SRT specification
+const HANDSHAKE: u16 = 0;
+const KEEPALIVE: u16 = 1;
+const ACK: u16 = 2;
+const NAK: u16 = 3;
+const SHUTDOWN: u16 = 5;
+const USER_DEFINED: u16 = 0x7FFF;
+
+// Handshake extensions. KMREQ and KMRSP are also the subtypes of user defined packets refreshing keys
+const HSREQ: u16 = 1;
+const HSRSP: u16 = 2;
+const KMREQ: u16 = 3;
+const KMRSP: u16 = 4;
+const SID: u16 = 5;
+
+// The HSREQ and KMREQ extension flags
+const EXTENSION_FLAGS: u16 = 1 | 2;
+
+// TSBPD receiving, encryption, dropping late packets, periodic NAKs and the retransmission flag
+const SRT_FLAGS: u32 = 0x02 | 0x04 | 0x08 | 0x10 | 0x20;
+
+// Rejection reasons, sent as the handshake type
+const REJECT_SYSTEM: u32 = 1001;
+const REJECT_VERSION: u32 = 1008;
+const REJECT_BAD_SECRET: u32 = 1010;
+const REJECT_UNSECURE: u32 = 1011;
+const REJECT_FORBIDDEN: u32 = 2403;
+
+/// The UDP port to take SRT on, set with srt_port in [ingest]. Defaults to none, which turns SRT ingest off.
+pub fn srt_port() -> Option<u16> {
+    settings().ingest.srt_port
+}
+
+/// How long lost packets are waited for, set with srt_latency_milliseconds in [ingest]. Defaults to 500.
+pub fn latency() -> Duration {
+    Duration::from_millis(settings().ingest.srt_latency_milliseconds)
+}
+
+/// How long a camera has to reconnect and carry on its segment, set with reconnect_seconds in [ingest]. Defaults
+/// to 10.
+pub fn reconnect_seconds() -> u64 {
+    settings().ingest.reconnect_seconds
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// How far a is after b, allowing for sequence numbers wrapping at 31 bits.
+fn seq_diff(a: u32, b: u32) -> i32 {
+    (a.wrapping_sub(b) << 1) as i32 >> 1
+}
+
+fn seq_add(seq: u32, offset: u32) -> u32 {
+    seq.wrapping_add(offset) & MAX_SEQ
+}
+
+fn random_u32() -> u32 {
+    let bytes = uuid::Uuid::new_v4();
+    let bytes = bytes.as_bytes();
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+enum Cipher {
+    Aes128(Aes128),
+    Aes192(Aes192),
+    Aes256(Aes256),
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Option<Cipher> {
+        match key.len() {
+            16 => Aes128::new_from_slice(key).ok().map(Cipher::Aes128),
+            24 => Aes192::new_from_slice(key).ok().map(Cipher::Aes192),
+            32 => Aes256::new_from_slice(key).ok().map(Cipher::Aes256),
+            _ => None,
+        }
+    }
+
+    fn encrypt(&self, block: &mut Block) {
+        match self {
+            Cipher::Aes128(cipher) => cipher.encrypt_block(block),
+            Cipher::Aes192(cipher) => cipher.encrypt_block(block),
+            Cipher::Aes256(cipher) => cipher.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut Block) {
+        match self {
+            Cipher::Aes128(cipher) => cipher.decrypt_block(block),
+            Cipher::Aes192(cipher) => cipher.decrypt_block(block),
+            Cipher::Aes256(cipher) => cipher.decrypt_block(block),
+        }
+    }
+}
+
+/// RFC 3394 key unwrapping, which is how callers send the keys they encrypt with. None if the wrapping key is wrong,
+/// which means the caller's passphrase is.
+fn unwrap_keys(kek: &Cipher, wrapped: &[u8]) -> Option<Vec<u8>> {
+    if wrapped.len() < 24 || wrapped.len() % 8 != 0 {
+        return None;
+    }
+
+    let blocks = wrapped.len() / 8 - 1;
+    let mut integrity = [0; 8];
+    integrity.copy_from_slice(&wrapped[..8]);
+    let mut keys = wrapped[8..].to_vec();
+
+    for round in (0..6).rev() {
+        for index in (1..=blocks).rev() {
+            let step = (blocks * round + index) as u64;
+            let mut block = Block::default();
+            for (byte, (value, step)) in block
+                .iter_mut()
+                .zip(integrity.iter().zip(step.to_be_bytes().iter()))
+            {
+                *byte = value ^ step;
+            }
+            block[8..].copy_from_slice(&keys[(index - 1) * 8..index * 8]);
+
+            kek.decrypt(&mut block);
+            integrity.copy_from_slice(&block[..8]);
+            keys[(index - 1) * 8..index * 8].copy_from_slice(&block[8..]);
+        }
+    }
+
+    match integrity == [0xA6; 8] {
+        true => Some(keys),
+        false => None,
+    }
+}
+
+/// The keys a caller encrypts its packets with. Callers switch between the even and odd keys when they refresh them.
+struct Keys {
+    salt: Vec<u8>,
+    even: Option<Cipher>,
+    odd: Option<Cipher>,
+}
+
+impl Keys {
+    /// Decrypts a packet's payload in place, with the key its flags say. False if the packet isn't encrypted or the
+    /// key is missing.
+    fn decrypt(&self, key_flags: u32, seq: u32, payload: &mut [u8]) -> bool {
+        let cipher = match key_flags {
+            1 => self.even.as_ref(),
+            2 => self.odd.as_ref(),
+            _ => None,
+        };
+        let cipher = match cipher {
+            Some(cipher) => cipher,
+            None => return false,
+        };
+
+        // AES-CTR, with the counter made from the salt and the packet's sequence number
+        let mut counter = [0; 16];
+        counter[10..14].copy_from_slice(&seq.to_be_bytes());
+        for (byte, salt) in counter.iter_mut().zip(&self.salt[..14]) {
+            *byte ^= salt;
+        }
+
+        for (index, chunk) in payload.chunks_mut(16).enumerate() {
+            counter[14..].copy_from_slice(&(index as u16).to_be_bytes());
+            let mut block = Block::clone_from_slice(&counter);
+            cipher.encrypt(&mut block);
+            for (byte, key) in chunk.iter_mut().zip(block.iter()) {
+                *byte ^= key;
+            }
+        }
+
+        true
+    }
+}
+
+/// Unwraps the keys in a key material message with the camera's passphrase. Errs with the reason to reject the
+/// caller.
+fn unwrap_key_material(message: &[u8], passphrase: &str) -> Result<Keys, u32> {
+    if message.len() < 16 || message[1..3] != [0x20, 0x29] {
+        return Err(REJECT_UNSECURE);
+    }
+
+    let key_flags = message[3] & 3;
+    let key_count = (key_flags & 1) as usize + (key_flags >> 1) as usize;
+    let salt_length = message[14] as usize * 4;
+    let key_length = message[15] as usize * 4;
+    // Only AES-CTR, which is all SRT encoders use
+    if message[8] != 2 || key_count == 0 || salt_length != 16 {
+        return Err(REJECT_UNSECURE);
+    }
+
+    let salt = message.get(16..32).ok_or(REJECT_UNSECURE)?;
+    let wrapped = message
+        .get(32..32 + 8 + key_length * key_count)
+        .ok_or(REJECT_UNSECURE)?;
+
+    let mut kek = vec![0; key_length];
+    pbkdf2::pbkdf2::<Hmac<Sha1>>(passphrase.as_bytes(), &salt[8..], 2048, &mut kek);
+    let kek = Cipher::new(&kek).ok_or(REJECT_UNSECURE)?;
+    let keys = unwrap_keys(&kek, wrapped).ok_or(REJECT_BAD_SECRET)?;
+
+    let mut keys = keys.chunks(key_length);
+    let even = match key_flags & 1 {
+        0 => None,
+        _ => keys.next().and_then(Cipher::new),
+    };
+    let odd = match key_flags & 2 {
+        0 => None,
+        _ => keys.next().and_then(Cipher::new),
+    };
+
+    Ok(Keys {
+        salt: salt.to_vec(),
+        even,
+        odd,
+    })
+}
+
+fn control_packet(
+    control_type: u16,
+    subtype: u16,
+    type_information: u32,
+    timestamp: u32,
+    destination: u32,
+    content: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(HEADER_BYTES + content.len());
+    packet.extend_from_slice(
+        &(0x8000_0000 | (control_type as u32) << 16 | subtype as u32).to_be_bytes(),
+    );
+    packet.extend_from_slice(&type_information.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&destination.to_be_bytes());
+    packet.extend_from_slice(content);
+    packet
+}
+
+struct Handshake<'a> {
+    version: u32,
+    initial_seq: u32,
+    mtu: u32,
+    flow_window: u32,
+    handshake_type: u32,
+    socket_id: u32,
+    cookie: u32,
+    extensions: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Handshake<'a> {
+    fn parse(cif: &'a [u8]) -> Option<Handshake<'a>> {
+        if cif.len() < HANDSHAKE_BYTES {
+            return None;
+        }
+
+        let mut extensions = Vec::new();
+        let mut offset = HANDSHAKE_BYTES;
+        while offset + 4 <= cif.len() {
+            let length = u16_at(cif, offset + 2) as usize * 4;
+            extensions.push((
+                u16_at(cif, offset),
+                cif.get(offset + 4..offset + 4 + length)?,
+            ));
+            offset += 4 + length;
+        }
+
+        Some(Handshake {
+            version: u32_at(cif, 0),
+            initial_seq: u32_at(cif, 8) & MAX_SEQ,
+            mtu: u32_at(cif, 12),
+            flow_window: u32_at(cif, 16),
+            handshake_type: u32_at(cif, 20),
+            socket_id: u32_at(cif, 24),
+            cookie: u32_at(cif, 28),
+            extensions,
+        })
+    }
+
+    fn extension(&self, extension_type: u16) -> Option<&'a [u8]> {
+        self.extensions
+            .iter()
+            .find(|(found, _)| *found == extension_type)
+            .map(|(_, content)| *content)
+    }
+
+    /// The reply to this handshake, to be sent to the caller.
+    fn reply(
+        &self,
+        extension_field: u16,
+        handshake_type: u32,
+        socket_id: u32,
+        cookie: u32,
+        peer: SocketAddr,
+        extensions: &[(u16, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut cif = Vec::with_capacity(HANDSHAKE_BYTES);
+        cif.extend_from_slice(&5u32.to_be_bytes());
+        // Encryption isn't advertised, callers set it up from their own passphrase
+        cif.extend_from_slice(&0u16.to_be_bytes());
+        cif.extend_from_slice(&extension_field.to_be_bytes());
+        cif.extend_from_slice(&self.initial_seq.to_be_bytes());
+        cif.extend_from_slice(&self.mtu.min(MAX_DATAGRAM_BYTES as u32).to_be_bytes());
+        cif.extend_from_slice(&self.flow_window.to_be_bytes());
+        cif.extend_from_slice(&handshake_type.to_be_bytes());
+        cif.extend_from_slice(&socket_id.to_be_bytes());
+        cif.extend_from_slice(&cookie.to_be_bytes());
+
+        // Each 32 bit word of the peer's address is sent little endian, like libsrt does
+        let octets = match peer.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        let mut address = [0; 16];
+        for (word, octets) in address.chunks_mut(4).zip(octets.chunks(4)) {
+            for (index, octet) in octets.iter().enumerate() {
+                word[3 - index] = *octet;
+            }
+        }
+        cif.extend_from_slice(&address);
+
+        for (extension_type, content) in extensions {
+            cif.extend_from_slice(&extension_type.to_be_bytes());
+            cif.extend_from_slice(&((content.len() / 4) as u16).to_be_bytes());
+            cif.extend_from_slice(content);
+        }
+
+        control_packet(HANDSHAKE, 0, 0, 0, self.socket_id, &cif)
+    }
+}
+
+/// Stream IDs arrive with the bytes of each 32 bit word reversed, padded with zeros.
+fn decode_stream_id(content: &[u8]) -> String {
+    let bytes = content
+        .chunks(4)
+        .flat_map(|word| word.iter().rev().copied())
+        .filter(|byte| *byte != 0)
+        .collect::<Vec<u8>>();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// The camera a stream ID is for. Either just the camera's ID, or SRT's access control syntax, like
+/// #!::r=<camera_id>,m=publish.
+fn stream_camera_id(stream_id: &str) -> Option<uuid::Uuid> {
+    let resource = match stream_id.strip_prefix("#!::") {
+        Some(fields) => fields
+            .split(',')
+            .find_map(|field| field.strip_prefix("r="))?,
+        None => stream_id,
+    };
+    uuid::Uuid::parse_str(resource.trim()).ok()
+}
+
+/// A caller pushing a camera's video.
+struct Session {
+    camera_id: uuid::Uuid,
+    peer: SocketAddr,
+    peer_socket_id: u32,
+    passphrase: String,
+    keys: Keys,
+    latency: Duration,
+    /// The conclusion the caller was accepted with, sent again if it didn't get it.
+    conclusion: Vec<u8>,
+    started_at: Instant,
+    last_received_at: Instant,
+    last_sent_at: Instant,
+    last_nak_at: Instant,
+    /// The next packet to give to the recording.
+    next_seq: u32,
+    newest_seq: u32,
+    /// Packets after a lost one, waiting for it, with when they arrived.
+    buffer: HashMap<u32, (Instant, Vec<u8>)>,
+    losses: HashSet<u32>,
+    ack_number: u32,
+    unacknowledged: bool,
+    watched: WatchedStream,
+}
+
+impl Session {
+    fn timestamp(&self) -> u32 {
+        self.started_at.elapsed().as_micros() as u32
+    }
+
+    fn send(&mut self, socket: &UdpSocket, packet: &[u8]) {
+        if let Err(error) = socket.send_to(packet, self.peer) {
+            debug!(
+                "Failed to send SRT packet to {}! The error was {}",
+                self.peer, error
+            );
+        }
+        self.last_sent_at = Instant::now();
+    }
+
+    fn send_control(
+        &mut self,
+        socket: &UdpSocket,
+        control_type: u16,
+        type_information: u32,
+        content: &[u8],
+    ) {
+        let packet = control_packet(
+            control_type,
+            0,
+            type_information,
+            self.timestamp(),
+            self.peer_socket_id,
+            content,
+        );
+        self.send(socket, &packet);
+    }
+
+    /// Asks for lost packets again, as ranges where they're next to each other.
+    fn send_nak(&mut self, socket: &UdpSocket, lost: &[u32]) {
+        let next_seq = self.next_seq;
+        let mut lost = lost.to_vec();
+        lost.sort_by_key(|seq| seq_diff(*seq, next_seq));
+
+        let mut entries = Vec::new();
+        let mut index = 0;
+        while index < lost.len() && entries.len() < MAX_NAK_ENTRIES {
+            let start = lost[index];
+            let mut end = start;
+            while index + 1 < lost.len() && lost[index + 1] == seq_add(end, 1) {
+                index += 1;
+                end = lost[index];
+            }
+            match start == end {
+                true => entries.push(start),
+                false => {
+                    entries.push(start | 0x8000_0000);
+                    entries.push(end);
+                }
+            }
+            index += 1;
+        }
+
+        let content = entries
+            .iter()
+            .flat_map(|entry| entry.to_be_bytes().to_vec())
+            .collect::<Vec<u8>>();
+        self.send_control(socket, NAK, 0, &content);
+        self.last_nak_at = Instant::now();
+    }
+
+    fn send_ack(&mut self, socket: &UdpSocket) {
+        self.ack_number = self.ack_number.wrapping_add(1);
+        let available = RECEIVE_BUFFER_PACKETS.saturating_sub(self.buffer.len() as u32);
+
+        // Round trip times aren't measured, the sender only uses them to pace retransmissions
+        let mut content = Vec::with_capacity(28);
+        for field in &[self.next_seq, 100_000, 50_000, available, 0, 0, 0] {
+            content.extend_from_slice(&field.to_be_bytes());
+        }
+        self.send_control(socket, ACK, self.ack_number, &content);
+        self.unacknowledged = false;
+    }
+
+    /// Takes a decrypted data packet. Returns the payloads that can now be recorded, in order.
+    fn receive_data(&mut self, socket: &UdpSocket, seq: u32, payload: Vec<u8>) -> Vec<Vec<u8>> {
+        let offset = seq_diff(seq, self.next_seq);
+        // Retransmissions of packets already recorded or skipped, and packets too far ahead to buffer
+        if offset < 0 || offset as u32 >= RECEIVE_BUFFER_PACKETS {
+            return Vec::new();
+        }
+
+        self.losses.remove(&seq);
+        let ahead = seq_diff(seq, self.newest_seq);
+        if ahead > 1 {
+            // Everything between the newest packet so far and this one was lost, ask for it straight away
+            let lost = (1..ahead as u32)
+                .map(|offset| seq_add(self.newest_seq, offset))
+                .collect::<Vec<u32>>();
+            self.losses.extend(&lost);
+            self.send_nak(socket, &lost);
+        }
+        if ahead > 0 {
+            self.newest_seq = seq;
+        }
+
+        self.buffer.insert(seq, (Instant::now(), payload));
+        self.unacknowledged = true;
+        self.deliver()
+    }
+
+    fn deliver(&mut self) -> Vec<Vec<u8>> {
+        let mut delivered = Vec::new();
+        while let Some((_, payload)) = self.buffer.remove(&self.next_seq) {
+            delivered.push(payload);
+            self.next_seq = seq_add(self.next_seq, 1);
+        }
+        delivered
+    }
+
+    /// Acknowledges what has arrived, asks for what hasn't again, and gives up on packets that have been lost for
+    /// longer than the latency. Returns the payloads that can now be recorded, in order.
+    fn tick(&mut self, socket: &UdpSocket) -> Vec<Vec<u8>> {
+        let next_seq = self.next_seq;
+        let mut delivered = Vec::new();
+
+        let first = self
+            .buffer
+            .keys()
+            .copied()
+            .min_by_key(|seq| seq_diff(*seq, next_seq));
+        if let Some(first) = first {
+            if self.buffer[&first].0.elapsed() >= self.latency {
+                debug!(
+                    "Skipped {} lost SRT packets from camera {}",
+                    seq_diff(first, next_seq),
+                    self.camera_id
+                );
+                self.next_seq = first;
+                delivered = self.deliver();
+            }
+        }
+
+        let next_seq = self.next_seq;
+        self.losses.retain(|seq| seq_diff(*seq, next_seq) >= 0);
+        if !self.losses.is_empty() && self.last_nak_at.elapsed() >= NAK_INTERVAL {
+            let lost = self.losses.iter().copied().collect::<Vec<u32>>();
+            self.send_nak(socket, &lost);
+        }
+
+        if self.unacknowledged {
+            self.send_ack(socket);
+        } else if self.last_sent_at.elapsed() >= KEEPALIVE_INTERVAL {
+            self.send_control(socket, KEEPALIVE, 0, &[]);
+        }
+
+        delivered
+    }
+}
+
+fn pat_pmt_pids(packet: &[u8]) -> Vec<u16> {
+    // PATs are only a few bytes, so they always start and end in the one packet
+    if packet[1] & 0x40 == 0 {
+        return Vec::new();
+    }
+
+    let mut offset = 4;
+    if packet[3] & 0x20 != 0 {
+        offset += 1 + packet[4] as usize;
+    }
+    let section = match packet.get(offset) {
+        Some(pointer) => packet.get(offset + 1 + *pointer as usize..),
+        None => None,
+    };
+    let section = match section {
+        Some(section) if section.len() >= 8 && section[0] == 0 => section,
+        _ => return Vec::new(),
+    };
+
+    // Programs follow the 8 byte header, and the CRC takes the last 4 bytes
+    let section_length = ((section[1] & 0x0F) as usize) << 8 | section[2] as usize;
+    let end = (3 + section_length).saturating_sub(4).min(section.len());
+    section
+        .get(8..end)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        // Program 0 is the network information table, not a program
+        .filter(|program| program[0..2] != [0, 0])
+        .map(|program| ((program[2] & 0x1F) as u16) << 8 | program[3] as u16)
+        .collect()
+}
+
+/// Turns a camera's MPEG-TS into segments, each starting with the stream's PAT and PMTs so it plays on its own. Kept
+/// while the camera reconnects, so its segment carries on where it left off.
+struct TsRecorder {
+    camera_id: uuid::Uuid,
+    writer: Option<RecordingWriter>,
+    pending: Vec<u8>,
+    pat: Option<Vec<u8>>,
+    pmts: HashMap<u16, Option<Vec<u8>>>,
+    /// When the camera's last connection dropped. None while it's connected.
+    disconnected_at: Option<Instant>,
+}
+
+impl TsRecorder {
+    fn new(camera_id: uuid::Uuid) -> TsRecorder {
+        TsRecorder {
+            camera_id,
+            writer: None,
+            pending: Vec::new(),
+            pat: None,
+            pmts: HashMap::new(),
+            disconnected_at: None,
+        }
+    }
+
+    fn finish(&mut self, connection: &PgConnection) {
+        if let Some(writer) = self.writer.take() {
+            let recording_id = writer.recording.recording_id;
+            if let Err(error) = writer.finish(connection) {
+                error!(
+                    "Failed to finish recording {}! The error was {}",
+                    recording_id, error
+                );
+            }
+        }
+    }
+
+    fn start_segment(&mut self, connection: &PgConnection) -> io::Result<()> {
+        self.finish(connection);
+
+        let mut writer =
+            RecordingWriter::start(self.camera_id, SRT_SOURCE, TS_CONTENT_TYPE, connection)
+                .map_err(|error| io::Error::new(ErrorKind::Other, error.error))?;
+        record_camera_contact(self.camera_id, connection);
+
+        if let Some(pat) = &self.pat {
+            writer.write(pat)?;
+        }
+        for pmt in self.pmts.values().flatten() {
+            writer.write(pmt)?;
+        }
+
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    /// Records a payload, which is usually 7 TS packets but doesn't have to line up with them.
+    fn write(&mut self, data: &[u8], connection: &PgConnection) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+
+        let mut offset = 0;
+        let mut packet = [0; TS_PACKET_BYTES];
+        while self.pending.len() - offset >= TS_PACKET_BYTES {
+            // Skips to the next sync byte after anything that was lost part way through a packet
+            if self.pending[offset] != 0x47 {
+                offset += 1;
+                continue;
+            }
+            packet.copy_from_slice(&self.pending[offset..offset + TS_PACKET_BYTES]);
+            offset += TS_PACKET_BYTES;
+            self.packet(&packet, connection)?;
+        }
+
+        self.pending.drain(..offset);
+        Ok(())
+    }
+
+    fn packet(&mut self, packet: &[u8], connection: &PgConnection) -> io::Result<()> {
+        let pid = ((packet[1] & 0x1F) as u16) << 8 | packet[2] as u16;
+        let is_table = pid == 0 || self.pmts.contains_key(&pid);
+
+        if pid == 0 {
+            let pmt_pids = pat_pmt_pids(packet);
+            if !pmt_pids.is_empty() {
+                self.pmts.retain(|pid, _| pmt_pids.contains(pid));
+                for pmt_pid in pmt_pids {
+                    self.pmts.entry(pmt_pid).or_insert(None);
+                }
+                self.pat = Some(packet.to_vec());
+            }
+        } else if let Some(pmt) = self.pmts.get_mut(&pid) {
+            *pmt = Some(packet.to_vec());
+        }
+
+        let random_access = packet[3] & 0x20 != 0 && packet[4] > 0 && packet[5] & 0x40 != 0;
+        let starts_segment = match &self.writer {
+            // Cut at keyframes, or regardless once it's long overdue, for encoders that don't mark them
+            Some(writer) => {
+                let elapsed = writer.elapsed_seconds();
+                (random_access && elapsed >= segment_seconds()) || elapsed >= segment_seconds() * 2
+            }
+            // Players skip to the first keyframe, so the first segment starts as soon as the tables are known
+            None => self.pat.is_some() && self.pmts.values().all(Option::is_some),
+        };
+
+        if starts_segment {
+            self.start_segment(connection)?;
+            // The segment already starts with the newest tables
+            if is_table {
+                return Ok(());
+            }
+        }
+
+        match &mut self.writer {
+            Some(writer) => writer.write(packet),
+            None => Ok(()),
+        }
+    }
+}
+
+fn connect<'a>(
+    connection: &'a mut Option<PgConnection>,
+    database_url: &str,
+) -> Option<&'a PgConnection> {
+    if connection.is_none() {
+        *connection = PgConnection::establish(database_url)
+            .map_err(|error| {
+                error!(
+                    "SRT server failed to connect to the database! The error was {}",
+                    error
+                )
+            })
+            .ok();
+    }
+    connection.as_ref()
+}
+
+struct SrtServer {
+    socket: UdpSocket,
+    database_url: String,
+    connection: Option<PgConnection>,
+    /// Keyed by the socket ID the server gave the caller, which the caller's packets are sent to.
+    sessions: HashMap<u32, Session>,
+    recorders: HashMap<uuid::Uuid, TsRecorder>,
+    secret: [u8; 16],
+    started_at: Instant,
+    last_tick_at: Instant,
+}
+
+impl SrtServer {
+    /// Cookies stop callers that can't receive at the address they claim from making sessions. They're made from
+    /// the caller's address and the current minute, so they don't need storing.
+    fn cookie(&self, peer: SocketAddr, minutes_ago: u64) -> u32 {
+        let minute = (self.started_at.elapsed().as_secs() / 60).wrapping_sub(minutes_ago);
+        let digest = Sha256::new()
+            .chain(&self.secret)
+            .chain(peer.to_string())
+            .chain(minute.to_be_bytes())
+            .finalize();
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    fn receive(&mut self, data: &[u8], source: SocketAddr) {
+        if data.len() < HEADER_BYTES {
+            return;
+        }
+
+        let first = u32_at(data, 0);
+        let is_control = first & 0x8000_0000 != 0;
+        let control_type = (first >> 16 & 0x7FFF) as u16;
+        let destination = u32_at(data, 12);
+
+        if is_control && control_type == HANDSHAKE {
+            return self.handshake(data, source);
+        }
+
+        let session = match self.sessions.get_mut(&destination) {
+            Some(session) if session.peer == source => session,
+            _ => return,
+        };
+        session.last_received_at = Instant::now();
+
+        if !is_control {
+            let seq = first & MAX_SEQ;
+            let key_flags = u32_at(data, 4) >> 27 & 3;
+            let mut payload = data[HEADER_BYTES..].to_vec();
+            // Callers always have to encrypt, see accept()
+            if !session.keys.decrypt(key_flags, seq, &mut payload) {
+                return;
+            }
+
+            session.watched.frame();
+            let camera_id = session.camera_id;
+            let delivered = session.receive_data(&self.socket, seq, payload);
+            return self.record(camera_id, delivered);
+        }
+
+        let subtype = (first & 0xFFFF) as u16;
+        match control_type {
+            SHUTDOWN => self.close(destination),
+            USER_DEFINED if subtype == KMREQ => {
+                let key_material = &data[HEADER_BYTES..];
+                match unwrap_key_material(key_material, &session.passphrase) {
+                    Ok(keys) => {
+                        session.keys = keys;
+                        let packet = control_packet(
+                            USER_DEFINED,
+                            KMRSP,
+                            0,
+                            session.timestamp(),
+                            session.peer_socket_id,
+                            key_material,
+                        );
+                        session.send(&self.socket, &packet);
+                    }
+                    Err(reason) => warn!(
+                        "Camera {} refreshed its SRT keys with ones that couldn't be unwrapped, reason {}",
+                        session.camera_id, reason
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handshake(&mut self, data: &[u8], source: SocketAddr) {
+        let request = match Handshake::parse(&data[HEADER_BYTES..]) {
+            Some(request) => request,
+            None => return,
+        };
+
+        match request.handshake_type {
+            INDUCTION => {
+                let reply = request.reply(
+                    SRT_MAGIC,
+                    INDUCTION,
+                    random_u32(),
+                    self.cookie(source, 0),
+                    source,
+                    &[],
+                );
+                if let Err(error) = self.socket.send_to(&reply, source) {
+                    debug!(
+                        "Failed to send SRT induction to {}! The error was {}",
+                        source, error
+                    );
+                }
+            }
+            CONCLUSION => {
+                // Cookies from this minute or the last
+                if request.cookie != self.cookie(source, 0)
+                    && request.cookie != self.cookie(source, 1)
+                {
+                    return;
+                }
+
+                // Callers sending their conclusion again didn't get the reply
+                let accepted = self.sessions.values_mut().find(|session| {
+                    session.peer == source && session.peer_socket_id == request.socket_id
+                });
+                if let Some(session) = accepted {
+                    let conclusion = session.conclusion.clone();
+                    return session.send(&self.socket, &conclusion);
+                }
+
+                if let Err(reason) = self.accept(&request, source) {
+                    info!("Rejected SRT caller {}, reason {}", source, reason);
+                    let reply = request.reply(SRT_MAGIC, reason, 0, request.cookie, source, &[]);
+                    if let Err(error) = self.socket.send_to(&reply, source) {
+                        debug!(
+                            "Failed to send SRT rejection to {}! The error was {}",
+                            source, error
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Accepts a caller's conclusion, if its stream ID is a camera with a passphrase and the caller's keys were
+    /// wrapped with it. Errs with the reason to reject the caller.
+    fn accept(&mut self, request: &Handshake, source: SocketAddr) -> Result<(), u32> {
+        if request.version != 5 {
+            return Err(REJECT_VERSION);
+        }
+        let hsreq = request
+            .extension(HSREQ)
+            .filter(|content| content.len() >= 12)
+            .ok_or(REJECT_VERSION)?;
+        let camera_id = request
+            .extension(SID)
+            .map(decode_stream_id)
+            .as_deref()
+            .and_then(stream_camera_id)
+            .ok_or(REJECT_FORBIDDEN)?;
+        let key_material = request.extension(KMREQ).ok_or(REJECT_UNSECURE)?;
+
+        let connection = connect(&mut self.connection, &self.database_url).ok_or(REJECT_SYSTEM)?;
+        let passphrase = match camera::get(camera_id, connection).optional() {
+            Ok(Some(_)) => stream_keys::srt_passphrase(camera_id, connection),
+            Ok(None) => Ok(None),
+            Err(error) => Err(error),
+        }
+        .map_err(|error| {
+            error!(
+                "Failed to get SRT passphrase for camera {}! The error was {}",
+                camera_id, error
+            );
+            REJECT_SYSTEM
+        })?
+        .ok_or(REJECT_FORBIDDEN)?;
+        let keys = unwrap_key_material(key_material, &passphrase)?;
+
+        // Whichever is longest of the delays the caller asked for and srt_latency_milliseconds. Both halves of the
+        // reply get it, since only the receiver's is used
+        let requested = u32_at(hsreq, 8);
+        let latency_milliseconds = (requested >> 16)
+            .max(requested & 0xFFFF)
+            .max(latency().as_millis() as u32)
+            .min(0xFFFF);
+        let mut hsrsp = Vec::with_capacity(12);
+        hsrsp.extend_from_slice(&SRT_VERSION.to_be_bytes());
+        hsrsp.extend_from_slice(&SRT_FLAGS.to_be_bytes());
+        hsrsp.extend_from_slice(&(latency_milliseconds << 16 | latency_milliseconds).to_be_bytes());
+
+        let mut socket_id = random_u32();
+        while socket_id == 0 || self.sessions.contains_key(&socket_id) {
+            socket_id = random_u32();
+        }
+        let conclusion = request.reply(
+            EXTENSION_FLAGS,
+            CONCLUSION,
+            socket_id,
+            request.cookie,
+            source,
+            &[(HSRSP, hsrsp), (KMRSP, key_material.to_vec())],
+        );
+
+        // A camera reconnecting before its last connection timed out, like after changing networks, takes over from it
+        self.sessions
+            .retain(|_, session| session.camera_id != camera_id);
+        let recorder = self
+            .recorders
+            .entry(camera_id)
+            .or_insert_with(|| TsRecorder::new(camera_id));
+        recorder.disconnected_at = None;
+        recorder.pending.clear();
+
+        info!("Camera {} started pushing SRT from {}", camera_id, source);
+        record_camera_contact(camera_id, connection);
+
+        let now = Instant::now();
+        let mut session = Session {
+            camera_id,
+            peer: source,
+            peer_socket_id: request.socket_id,
+            passphrase,
+            keys,
+            latency: Duration::from_millis(latency_milliseconds as u64),
+            conclusion: conclusion.clone(),
+            started_at: now,
+            last_received_at: now,
+            last_sent_at: now,
+            last_nak_at: now,
+            next_seq: request.initial_seq,
+            newest_seq: seq_add(request.initial_seq, MAX_SEQ),
+            buffer: HashMap::new(),
+            losses: HashSet::new(),
+            ack_number: 0,
+            unacknowledged: false,
+            watched: ingest_watchdog::watch(camera_id, SRT_SOURCE, None::<fn()>),
+        };
+        session.send(&self.socket, &conclusion);
+        self.sessions.insert(socket_id, session);
+        Ok(())
+    }
+
+    /// Ends a session. Its recording is kept for reconnect_seconds, in case the camera comes back.
+    fn close(&mut self, socket_id: u32) {
+        if let Some(session) = self.sessions.remove(&socket_id) {
+            info!("Camera {} stopped pushing SRT", session.camera_id);
+            if let Some(recorder) = self.recorders.get_mut(&session.camera_id) {
+                recorder.disconnected_at = Some(Instant::now());
+            }
+        }
+    }
+
+    fn record(&mut self, camera_id: uuid::Uuid, payloads: Vec<Vec<u8>>) {
+        if payloads.is_empty() {
+            return;
+        }
+        let connection = match connect(&mut self.connection, &self.database_url) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let recorder = match self.recorders.get_mut(&camera_id) {
+            Some(recorder) => recorder,
+            None => return,
+        };
+
+        for payload in payloads {
+            if let Err(error) = recorder.write(&payload, connection) {
+                // Like running out of storage. The caller is disconnected, since there's nowhere to put its video
+                error!(
+                    "Failed to record SRT from camera {}! The error was {}",
+                    camera_id, error
+                );
+                recorder.finish(connection);
+                self.recorders.remove(&camera_id);
+
+                let socket = &self.socket;
+                self.sessions.retain(|_, session| {
+                    if session.camera_id != camera_id {
+                        return true;
+                    }
+                    session.send_control(socket, SHUTDOWN, 0, &[]);
+                    false
+                });
+                return;
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.last_tick_at.elapsed() < TICK {
+            return;
+        }
+        self.last_tick_at = Instant::now();
+
+        let mut idle = Vec::new();
+        let mut delivered = Vec::new();
+        for (socket_id, session) in &mut self.sessions {
+            if session.last_received_at.elapsed() >= Duration::from_secs(PEER_IDLE_SECONDS) {
+                idle.push(*socket_id);
+                continue;
+            }
+            // Still connected but not sending anything, so it's told to go away and can connect again
+            if session.watched.stalled() {
+                session.send_control(&self.socket, SHUTDOWN, 0, &[]);
+                idle.push(*socket_id);
+                continue;
+            }
+            let payloads = session.tick(&self.socket);
+            if !payloads.is_empty() {
+                delivered.push((session.camera_id, payloads));
+            }
+        }
+
+        for socket_id in idle {
+            self.close(socket_id);
+        }
+        for (camera_id, payloads) in delivered {
+            self.record(camera_id, payloads);
+        }
+
+        // Cameras that didn't reconnect in time have their segment finished
+        let reconnect = Duration::from_secs(reconnect_seconds());
+        let expired = self
+            .recorders
+            .iter()
+            .filter(|(_, recorder)| match recorder.disconnected_at {
+                Some(disconnected_at) => disconnected_at.elapsed() >= reconnect,
+                None => false,
+            })
+            .map(|(camera_id, _)| *camera_id)
+            .collect::<Vec<uuid::Uuid>>();
+        for camera_id in expired {
+            if let Some(mut recorder) = self.recorders.remove(&camera_id) {
+                if let Some(connection) = connect(&mut self.connection, &self.database_url) {
+                    recorder.finish(connection);
+                }
+            }
+        }
+    }
+}
+
+/// Starts taking SRT on srt_port in [ingest], if it's set. Callers are always in live mode and always encrypted, with
+/// the camera's passphrase. Every caller is handled on the one thread, with one database connection, like CoAP. The
+/// server is restarted if it panics, see ingest_watchdog::supervise(), though callers have to connect again.
+pub fn spawn_srt_server(database_url: String) {
+    let port = match srt_port() {
+        Some(port) => port,
+        None => return,
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", port)).expect("Failed to bind SRT server!");
+    socket
+        .set_read_timeout(Some(TICK))
+        .expect("Failed to set SRT server timeout!");
+
+    ingest_watchdog::supervise(SRT_SOURCE, move || {
+        let socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(error) => {
+                error!("Failed to clone the SRT socket! The error was {}", error);
+                return;
+            }
+        };
+        let now = Instant::now();
+        let mut server = SrtServer {
+            socket,
+            database_url: database_url.clone(),
+            connection: None,
+            sessions: HashMap::new(),
+            recorders: HashMap::new(),
+            secret: *uuid::Uuid::new_v4().as_bytes(),
+            started_at: now,
+            last_tick_at: now,
+        };
+        let mut buffer = [0; MAX_DATAGRAM_BYTES];
+
+        loop {
+            match server.socket.recv_from(&mut buffer) {
+                Ok((length, source)) => server.receive(&buffer[..length], source),
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut => {}
+                Err(error) => error!("Failed to receive SRT packet! The error was {}", error),
+            }
+            server.tick();
+        }
+    });
+}
@@ -0,0 +1,142 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    cache::{self, cache},
+    camera,
+    media_store::{media_store, MediaStore},
+    metrics::{self, QueueDepths},
+    user, CameraServerDbConn,
+};
+
+use super::schema::{audio_clips, cameras, events};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use rocket::get;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::io;
+use std::time::Duration;
+
+/// How long image totals are cached for, as counting them means listing every camera's images.
+pub const IMAGE_STATS_CACHE_SECONDS: u64 = 5 * 60;
+
+/// Returned by GET /Admin/Stats.
+#[derive(Serialize, JsonSchema)]
+pub struct Stats {
+    /// Users that haven't been deleted, including disabled ones.
+    pub users: i64,
+    /// Cameras that haven't been deleted.
+    pub cameras: i64,
+    pub online_cameras: i64,
+    /// Images in the media store, hot and cold, including deleted cameras' until they're purged.
+    pub images: u64,
+    pub image_bytes: u64,
+    /// When the image totals were counted, which can be up to IMAGE_STATS_CACHE_SECONDS ago.
+    pub images_counted_at: DateTime<Utc>,
+    pub audio_clips: i64,
+    pub audio_bytes: i64,
+    pub events_last_24_hours: i64,
+    pub queue_depths: QueueDepths,
+}
+
+/// How many images there are and how many bytes they use, and when that was counted.
+fn image_totals() -> io::Result<(u64, u64, DateTime<Utc>)> {
+    let key = cache::image_stats_key();
+
+    let cached = cache().get(&key).and_then(|value| {
+        let mut parts = value.split(',');
+        let images = parts.next()?.parse().ok()?;
+        let bytes = parts.next()?.parse().ok()?;
+        let counted_at = DateTime::parse_from_rfc3339(parts.next()?).ok()?;
+        Some((images, bytes, counted_at.with_timezone(&Utc)))
+    });
+
+    if let Some(totals) = cached {
+        return Ok(totals);
+    }
+
+    let store = media_store();
+    let mut images = 0;
+    let mut bytes = 0;
+
+    for camera_id in store.list_cameras()? {
+        images += store.list_images(&camera_id)?.len() as u64;
+        bytes += store.storage_used(&camera_id)?;
+    }
+
+    let counted_at = Utc::now();
+    cache().set(
+        &key,
+        &format!("{},{},{}", images, bytes, counted_at.to_rfc3339()),
+        Duration::from_secs(IMAGE_STATS_CACHE_SECONDS),
+    );
+
+    Ok((images, bytes, counted_at))
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to count stats! The error was {}", error);
+    ApiError {
+        error: "Failed to count stats",
+        code: "count_stats_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Totals for a status dashboard. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Stats")]
+pub fn get_stats(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Stats>, ApiError> {
+    let (images, image_bytes, images_counted_at) = image_totals().map_err(|error| {
+        error!("Failed to count images! The error was {}", error);
+        ApiError {
+            error: "Failed to count images",
+            code: "count_images_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    Ok(Json(Stats {
+        users: user::not_deleted()
+            .count()
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        cameras: camera::not_deleted()
+            .count()
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        online_cameras: camera::not_deleted()
+            .filter(cameras::online)
+            .count()
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        images,
+        image_bytes,
+        images_counted_at,
+        audio_clips: audio_clips::table
+            .count()
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        // SUM() of a BIGINT is a NUMERIC, which Diesel can't read without bigdecimal
+        audio_bytes: audio_clips::table
+            .select(sql::<BigInt>("COALESCE(SUM(size_bytes), 0)::BIGINT"))
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        events_last_24_hours: events::table
+            .filter(events::occurred_at.ge(Utc::now() - ChronoDuration::days(1)))
+            .count()
+            .get_result::<i64>(&*conn)
+            .map_err(database_error)?,
+        queue_depths: metrics::queue_depths(&conn).map_err(database_error)?,
+    }))
+}
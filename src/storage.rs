@@ -0,0 +1,381 @@
+use crate::{
+    api_error::ApiError,
+    camera::CameraId,
+    media_store::{media_store, MediaStore},
+    user_tokens::UserToken,
+    users_cameras::{check_if_user_has_access_to_camera, get_owned_camera_ids},
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{cameras, storage_daily, storage_recounts};
+use chrono::{Duration as ChronoDuration, NaiveDate, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Date, Text, Uuid as SqlUuid};
+use once_cell::sync::Lazy;
+use rocket::get;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Images from the media store, by the day they were taken.
+pub const IMAGE: &str = "image";
+
+/// Audio clips, by the day they were recorded.
+pub const AUDIO: &str = "audio";
+
+/// Recordings of video pushed by cameras, by the day their segment started.
+pub const VIDEO: &str = "video";
+
+/// How often each server adds the uploads it has counted to storage_daily, and recounts the cameras that need it.
+pub const STORAGE_FLUSH_SECONDS: u64 = 60;
+
+/// Every camera is recounted from what's actually stored this often, which corrects anything the counts from
+/// uploads got wrong, e.g. an image replaced by another with the same ID.
+pub const RECOUNT_AFTER_HOURS: i64 = 24;
+
+/// How many cameras are recounted each flush, so a server with lots of cameras catches up a bit at a time.
+pub const RECOUNT_BATCH_SIZE: i64 = 20;
+
+/// The days in GET /Cameras/<camera_id>/Storage and GET /Account/Storage cover this many days if they aren't given
+/// a range. Totals always cover everything.
+pub const DEFAULT_STORAGE_DAYS: i64 = 30;
+
+/// What's been stored since the last flush, as camera, media type and day to bytes and files.
+/// Cameras whose footage has been deleted are recounted instead.
+#[derive(Default)]
+struct Pending {
+    stored: HashMap<(uuid::Uuid, &'static str, NaiveDate), (i64, i64)>,
+    recount: HashSet<uuid::Uuid>,
+}
+
+static PENDING: Lazy<Mutex<Pending>> = Lazy::new(|| Mutex::new(Pending::default()));
+
+fn pending() -> std::sync::MutexGuard<'static, Pending> {
+    PENDING.lock().expect("Storage lock poisoned!")
+}
+
+/// The day an image was taken, from its ID.
+pub fn image_day(image_id: u64) -> NaiveDate {
+    NaiveDateTime::from_timestamp(image_id as i64, 0).date()
+}
+
+/// Counts a stored file towards its camera's storage on `day`.
+pub fn record_stored(camera_id: uuid::Uuid, media_type: &'static str, day: NaiveDate, bytes: u64) {
+    let mut pending = pending();
+    let counts = pending
+        .stored
+        .entry((camera_id, media_type, day))
+        .or_insert((0, 0));
+
+    counts.0 += bytes as i64;
+    counts.1 += 1;
+}
+
+/// Recounts the camera's storage at the next flush, for after its footage has been deleted.
+pub fn recount_later(camera_id: uuid::Uuid) {
+    pending().recount.insert(camera_id);
+}
+
+/// Replaces the camera's rows in storage_daily with what's actually stored.
+pub fn recount(camera_id: uuid::Uuid, connection: &PgConnection) -> io::Result<()> {
+    let mut images = HashMap::<NaiveDate, (i64, i64)>::new();
+    for (image_id, bytes) in media_store().image_sizes(&camera_id)? {
+        let counts = images.entry(image_day(image_id)).or_insert((0, 0));
+        counts.0 += bytes as i64;
+        counts.1 += 1;
+    }
+
+    connection.transaction(|| {
+        diesel::delete(storage_daily::table.filter(storage_daily::camera_id.eq(camera_id)))
+            .execute(connection)?;
+
+        let rows = images
+            .into_iter()
+            .map(|(day, (bytes, files))| {
+                (
+                    storage_daily::camera_id.eq(camera_id),
+                    storage_daily::media_type.eq(IMAGE),
+                    storage_daily::day.eq(day),
+                    storage_daily::bytes.eq(bytes),
+                    storage_daily::files.eq(files),
+                )
+            })
+            .collect::<Vec<_>>();
+        if !rows.is_empty() {
+            diesel::insert_into(storage_daily::table)
+                .values(&rows)
+                .execute(connection)?;
+        }
+
+        diesel::sql_query(
+            "INSERT INTO storage_daily (camera_id, media_type, day, bytes, files)
+            SELECT camera_id, $2, (recorded_at AT TIME ZONE 'UTC')::DATE, SUM(size_bytes)::BIGINT, COUNT(*)
+            FROM audio_clips WHERE camera_id = $1
+            GROUP BY camera_id, (recorded_at AT TIME ZONE 'UTC')::DATE",
+        )
+        .bind::<SqlUuid, _>(camera_id)
+        .bind::<Text, _>(AUDIO)
+        .execute(connection)?;
+
+        diesel::sql_query(
+            "INSERT INTO storage_daily (camera_id, media_type, day, bytes, files)
+            SELECT camera_id, $2, (started_at AT TIME ZONE 'UTC')::DATE, SUM(size_bytes)::BIGINT, COUNT(*)
+            FROM recordings WHERE camera_id = $1
+            GROUP BY camera_id, (started_at AT TIME ZONE 'UTC')::DATE",
+        )
+        .bind::<SqlUuid, _>(camera_id)
+        .bind::<Text, _>(VIDEO)
+        .execute(connection)?;
+
+        diesel::insert_into(storage_recounts::table)
+            .values((
+                storage_recounts::camera_id.eq(camera_id),
+                storage_recounts::recounted_at.eq(Utc::now()),
+            ))
+            .on_conflict(storage_recounts::camera_id)
+            .do_update()
+            .set(storage_recounts::recounted_at.eq(Utc::now()))
+            .execute(connection)?;
+
+        Ok(())
+    })
+    .map_err(|error: diesel::result::Error| io::Error::new(io::ErrorKind::Other, error))
+}
+
+/// Adds what's been stored since the last flush to storage_daily, then recounts the cameras that need it: those
+/// whose footage was deleted, and up to RECOUNT_BATCH_SIZE that haven't been recounted for RECOUNT_AFTER_HOURS.
+/// Anything that can't be written is kept for next time.
+pub fn flush(connection: &PgConnection) -> QueryResult<()> {
+    let (stored, recounts) = {
+        let mut pending = pending();
+        (
+            std::mem::take(&mut pending.stored),
+            std::mem::take(&mut pending.recount),
+        )
+    };
+
+    // Uploads from cameras that have since been purged are dropped
+    let result = connection.transaction(|| {
+        for ((camera_id, media_type, day), (bytes, files)) in &stored {
+            diesel::sql_query(
+                "INSERT INTO storage_daily (camera_id, media_type, day, bytes, files)
+                SELECT $1, $2, $3, $4, $5 WHERE EXISTS (SELECT 1 FROM cameras WHERE camera_id = $1)
+                ON CONFLICT (camera_id, media_type, day) DO UPDATE SET
+                    bytes = storage_daily.bytes + EXCLUDED.bytes,
+                    files = storage_daily.files + EXCLUDED.files",
+            )
+            .bind::<SqlUuid, _>(camera_id)
+            .bind::<Text, _>(*media_type)
+            .bind::<Date, _>(day)
+            .bind::<BigInt, _>(bytes)
+            .bind::<BigInt, _>(files)
+            .execute(connection)?;
+        }
+
+        Ok(())
+    });
+
+    if result.is_err() {
+        let mut pending = pending();
+
+        for (key, (bytes, files)) in stored {
+            let counts = pending.stored.entry(key).or_insert((0, 0));
+            counts.0 += bytes;
+            counts.1 += files;
+        }
+        pending.recount.extend(recounts);
+
+        return result;
+    }
+
+    let stale = cameras::table
+        .left_join(storage_recounts::table.on(storage_recounts::camera_id.eq(cameras::camera_id)))
+        .filter(cameras::deleted_at.is_null())
+        .filter(
+            storage_recounts::recounted_at
+                .is_null()
+                .or(storage_recounts::recounted_at
+                    .lt(Utc::now() - ChronoDuration::hours(RECOUNT_AFTER_HOURS))),
+        )
+        .select(cameras::camera_id)
+        .limit(RECOUNT_BATCH_SIZE)
+        .load::<uuid::Uuid>(connection)?;
+
+    for camera_id in recounts.into_iter().chain(stale) {
+        if let Err(error) = recount(camera_id, connection) {
+            error!(
+                "Failed to recount storage used by camera {}! The error was {}",
+                camera_id, error
+            );
+            recount_later(camera_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts flushing storage counts every STORAGE_FLUSH_SECONDS. Every instance counts its own uploads,
+/// so this runs on all of them.
+pub fn spawn_storage_flusher(database_url: String) {
+    worker::spawn_concurrent_worker(
+        "Storage breakdown",
+        Duration::from_secs(STORAGE_FLUSH_SECONDS),
+        database_url,
+        |connection| {
+            if let Err(error) = flush(connection) {
+                error!("Failed to write storage counts! The error was {}", error);
+            }
+        },
+    );
+}
+
+/// What one camera stored of one media type on one day, in UTC.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct DailyStorage {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    /// image, audio or video.
+    pub media_type: String,
+    pub day: NaiveDate,
+    pub bytes: i64,
+    pub files: i64,
+}
+
+/// Everything one camera has stored.
+#[derive(QueryableByName, Serialize, JsonSchema)]
+pub struct CameraStorage {
+    #[sql_type = "SqlUuid"]
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    #[sql_type = "BigInt"]
+    pub bytes: i64,
+    #[sql_type = "BigInt"]
+    pub files: i64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct StorageBreakdown {
+    /// Everything stored, whatever day it's from.
+    pub total_bytes: i64,
+    /// Each camera's total, biggest first.
+    pub cameras: Vec<CameraStorage>,
+    /// Each camera's storage by media type and day, for the days asked for, oldest first.
+    pub days: Vec<DailyStorage>,
+}
+
+/// Query string for GET /Cameras/<camera_id>/Storage and GET /Account/Storage.
+#[derive(FromForm, JsonSchema)]
+pub struct StorageQuery {
+    /// The first day to include in days, as YYYY-MM-DD. Defaults to DEFAULT_STORAGE_DAYS ago.
+    pub from: Option<String>,
+    /// The last day to include in days, as YYYY-MM-DD. Defaults to today.
+    pub until: Option<String>,
+}
+
+impl StorageQuery {
+    fn days(&self) -> Result<(NaiveDate, NaiveDate), ApiError> {
+        let parse = |day: &String, field: &'static str| {
+            NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| ApiError {
+                error: "Failed to parse day, days must be YYYY-MM-DD",
+                code: "invalid_day",
+                status: Status::UnprocessableEntity,
+                field: Some(field),
+            })
+        };
+
+        let until = match &self.until {
+            Some(until) => parse(until, "until")?,
+            None => Utc::now().naive_utc().date(),
+        };
+        let from = match &self.from {
+            Some(from) => parse(from, "from")?,
+            None => until - ChronoDuration::days(DEFAULT_STORAGE_DAYS - 1),
+        };
+
+        Ok((from, until))
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get storage breakdown! The error was {}", error);
+    ApiError {
+        error: "Failed to get storage breakdown",
+        code: "get_storage_breakdown_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn breakdown(
+    camera_ids: Vec<uuid::Uuid>,
+    query: &StorageQuery,
+    connection: &PgConnection,
+) -> Result<StorageBreakdown, ApiError> {
+    let (from, until) = query.days()?;
+
+    // SUM() of a BIGINT is a NUMERIC, which Diesel can't read without bigdecimal
+    let cameras = diesel::sql_query(
+        "SELECT camera_id, SUM(bytes)::BIGINT AS bytes, SUM(files)::BIGINT AS files
+        FROM storage_daily WHERE camera_id = ANY($1)
+        GROUP BY camera_id
+        ORDER BY bytes DESC, camera_id",
+    )
+    .bind::<Array<SqlUuid>, _>(&camera_ids)
+    .load::<CameraStorage>(connection)
+    .map_err(database_error)?;
+
+    let days = storage_daily::table
+        .filter(storage_daily::camera_id.eq_any(camera_ids))
+        .filter(storage_daily::day.between(from, until))
+        .order((
+            storage_daily::day,
+            storage_daily::camera_id,
+            storage_daily::media_type,
+        ))
+        .load::<DailyStorage>(connection)
+        .map_err(database_error)?;
+
+    Ok(StorageBreakdown {
+        total_bytes: cameras.iter().map(|camera| camera.bytes).sum(),
+        cameras,
+        days,
+    })
+}
+
+/// How much the camera has stored, by media type and day. Uploads are counted within STORAGE_FLUSH_SECONDS,
+/// deleted footage within RECOUNT_AFTER_HOURS at the latest.
+#[openapi]
+#[get("/Cameras/<camera_id>/Storage?<query..>")]
+pub fn get_camera_storage(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    query: Form<StorageQuery>,
+) -> Result<Json<StorageBreakdown>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    breakdown(vec![camera_id], &query, &conn).map(Json)
+}
+
+/// How much each of the user's cameras has stored, by media type and day, so they can see what's using up their
+/// plan's max_storage_gb. Cameras shared with the user aren't included.
+#[openapi]
+#[get("/Account/Storage?<query..>")]
+pub fn get_account_storage(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    query: Form<StorageQuery>,
+) -> Result<Json<StorageBreakdown>, ApiError> {
+    let camera_ids = get_owned_camera_ids(user_token.user_id, &conn).map_err(database_error)?;
+
+    breakdown(camera_ids, &query, &conn).map(Json)
+}
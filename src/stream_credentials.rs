@@ -0,0 +1,313 @@
+use crate::{
+    api_error::ApiError,
+    camera::{record_camera_contact, CameraId},
+    camera_commands::{self, InsertableCameraCommand, ROTATE_STREAM_CREDENTIALS_COMMAND},
+    camera_tokens::CameraToken,
+    device_format::{Device, DeviceBody},
+    user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera,
+    CameraServerDbConn,
+};
+
+use super::schema::stream_credentials;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::{get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Sent to the camera, waiting for it to confirm it has switched to them.
+pub const PENDING_STATUS: &str = "pending";
+/// What the camera's stream currently accepts.
+pub const ACTIVE_STATUS: &str = "active";
+/// The camera couldn't switch to them, or didn't confirm in time. Whatever was active before still is.
+pub const FAILED_STATUS: &str = "failed";
+/// Replaced by a newer rotation.
+pub const REVOKED_STATUS: &str = "revoked";
+
+/// How long a camera has to confirm a rotation before it's marked as failed.
+pub const ROTATION_TIMEOUT_MINUTES: i64 = 60;
+
+/// A username and password for a camera's RTSP stream. Passwords are wiped once the credentials are failed or
+/// revoked, so only ones the camera might still accept are kept.
+#[derive(Queryable, Deserialize, Serialize, JsonSchema)]
+pub struct StreamCredential {
+    pub credential_id: i32,
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub username: String,
+    pub password: String,
+    /// One of pending, active, failed or revoked.
+    pub status: String,
+    /// Why the rotation failed, if it did.
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub activated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+#[table_name = "stream_credentials"]
+struct InsertableStreamCredential {
+    camera_id: uuid::Uuid,
+    username: String,
+    password: String,
+}
+
+/// What GET /Device/StreamCredentials returns.
+#[derive(Serialize, JsonSchema)]
+pub struct DeviceStreamCredentials {
+    /// What the stream should accept now.
+    pub active: Option<StreamCredential>,
+    /// What it should switch to, then confirm with POST /Device/StreamCredentials/<credential_id>/Confirm.
+    pub pending: Option<StreamCredential>,
+}
+
+/// What a camera sends once it has tried to switch to pending credentials.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct RotationResult {
+    /// True once the stream accepts the new credentials and no longer accepts the old ones.
+    pub applied: bool,
+    pub error: Option<String>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!(
+        "Failed to update stream credentials! The error was {}",
+        error
+    );
+    ApiError {
+        error: "Failed to update stream credentials",
+        code: "update_stream_credentials_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Ends rotations that have been given up on: ones the camera hasn't confirmed in ROTATION_TIMEOUT_MINUTES, or
+/// every pending one if `superseded`.
+fn fail_pending(
+    camera_id: uuid::Uuid,
+    superseded: bool,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    let (cutoff, error) = if superseded {
+        (Utc::now(), "Superseded by a newer rotation")
+    } else {
+        (
+            Utc::now() - Duration::minutes(ROTATION_TIMEOUT_MINUTES),
+            "The camera didn't confirm the rotation in time",
+        )
+    };
+
+    diesel::update(
+        stream_credentials::table
+            .filter(stream_credentials::camera_id.eq(camera_id))
+            .filter(stream_credentials::status.eq(PENDING_STATUS))
+            .filter(stream_credentials::created_at.le(cutoff)),
+    )
+    .set((
+        stream_credentials::status.eq(FAILED_STATUS),
+        stream_credentials::error.eq(error),
+        stream_credentials::password.eq(""),
+    ))
+    .execute(connection)
+}
+
+fn get_with_status(
+    camera_id: uuid::Uuid,
+    status: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<StreamCredential>> {
+    stream_credentials::table
+        .filter(stream_credentials::camera_id.eq(camera_id))
+        .filter(stream_credentials::status.eq(status))
+        .order(stream_credentials::credential_id.desc())
+        .first::<StreamCredential>(connection)
+        .optional()
+}
+
+/// Generates new stream credentials for the camera and asks it to switch to them. Any rotation still pending is
+/// failed first, so the camera only ever has one set to switch to.
+pub fn start_rotation(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<StreamCredential> {
+    connection.transaction(|| {
+        fail_pending(camera_id, true, connection)?;
+
+        let credential = diesel::insert_into(stream_credentials::table)
+            .values(InsertableStreamCredential {
+                camera_id,
+                username: format!("stream_{}", &uuid::Uuid::new_v4().simple().to_string()[..8]),
+                password: uuid::Uuid::new_v4().simple().to_string(),
+            })
+            .get_result::<StreamCredential>(connection)?;
+
+        camera_commands::insert(
+            InsertableCameraCommand {
+                camera_id,
+                command: ROTATE_STREAM_CREDENTIALS_COMMAND.to_string(),
+                expires_at: None,
+            },
+            connection,
+        )?;
+
+        Ok(credential)
+    })
+}
+
+/// Records what the camera said about a pending rotation. If it applied the new credentials they become active
+/// and the old ones are revoked, otherwise the rotation fails and the old ones stay active.
+pub fn finish_rotation(
+    camera_id: uuid::Uuid,
+    credential_id: i32,
+    result: &RotationResult,
+    connection: &PgConnection,
+) -> Result<StreamCredential, ApiError> {
+    connection
+        .transaction(|| {
+            fail_pending(camera_id, false, connection)?;
+
+            let pending = stream_credentials::table
+                .filter(stream_credentials::camera_id.eq(camera_id))
+                .filter(stream_credentials::credential_id.eq(credential_id))
+                .filter(stream_credentials::status.eq(PENDING_STATUS))
+                .first::<StreamCredential>(connection)
+                .optional()?;
+            if pending.is_none() {
+                return Ok(None);
+            }
+
+            let now = Utc::now();
+            let credential = stream_credentials::table.find(credential_id);
+
+            if result.applied {
+                diesel::update(
+                    stream_credentials::table
+                        .filter(stream_credentials::camera_id.eq(camera_id))
+                        .filter(stream_credentials::status.eq(ACTIVE_STATUS)),
+                )
+                .set((
+                    stream_credentials::status.eq(REVOKED_STATUS),
+                    stream_credentials::revoked_at.eq(now),
+                    stream_credentials::password.eq(""),
+                ))
+                .execute(connection)?;
+
+                diesel::update(credential)
+                    .set((
+                        stream_credentials::status.eq(ACTIVE_STATUS),
+                        stream_credentials::activated_at.eq(now),
+                    ))
+                    .get_result::<StreamCredential>(connection)
+                    .map(Some)
+            } else {
+                diesel::update(credential)
+                    .set((
+                        stream_credentials::status.eq(FAILED_STATUS),
+                        stream_credentials::error.eq(result
+                            .error
+                            .as_deref()
+                            .unwrap_or("The camera couldn't apply the new credentials")),
+                        stream_credentials::password.eq(""),
+                    ))
+                    .get_result::<StreamCredential>(connection)
+                    .map(Some)
+            }
+        })
+        .map_err(database_error)?
+        .ok_or(ApiError {
+            error: "Stream credentials aren't waiting to be confirmed",
+            code: "no_pending_stream_credentials",
+            status: Status::Conflict,
+            field: None,
+        })
+}
+
+/// Starts rotating the camera's stream credentials: new ones are generated and sent to the camera, and once it
+/// confirms it has switched to them the old ones are revoked. Follow its progress with
+/// GET /Cameras/<camera_id>/StreamCredentials.
+#[openapi]
+#[post("/Cameras/<camera_id>/StreamCredentials/Rotate")]
+pub fn rotate_stream_credentials(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<StreamCredential>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    start_rotation(camera_id, &conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Every set of stream credentials the camera has had, newest first, so the ones it's using now are the first
+/// active ones and a rotation's progress is its status.
+#[openapi]
+#[get("/Cameras/<camera_id>/StreamCredentials")]
+pub fn list_stream_credentials(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Vec<StreamCredential>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    fail_pending(camera_id, false, &conn).map_err(database_error)?;
+
+    stream_credentials::table
+        .filter(stream_credentials::camera_id.eq(camera_id))
+        .order(stream_credentials::credential_id.desc())
+        .load::<StreamCredential>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// The credentials the camera's stream should accept, and any it's been asked to switch to. Cameras fetch this
+/// when they get a rotate_stream_credentials command, and on start up.
+#[openapi]
+#[get("/Device/StreamCredentials")]
+pub fn get_device_stream_credentials(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+) -> Result<Device<DeviceStreamCredentials>, ApiError> {
+    let camera_id = camera_token.camera_id;
+    record_camera_contact(camera_id, &conn);
+
+    fail_pending(camera_id, false, &conn).map_err(database_error)?;
+
+    Ok(Device(DeviceStreamCredentials {
+        active: get_with_status(camera_id, ACTIVE_STATUS, &conn).map_err(database_error)?,
+        pending: get_with_status(camera_id, PENDING_STATUS, &conn).map_err(database_error)?,
+    }))
+}
+
+/// Tells the server whether the camera switched to pending credentials. Cameras should only say they've been
+/// applied once the stream accepts them, as the old ones are revoked straight away.
+#[openapi]
+#[post(
+    "/Device/StreamCredentials/<credential_id>/Confirm",
+    data = "<rotation_result>"
+)]
+pub fn confirm_stream_credentials(
+    conn: CameraServerDbConn,
+    camera_token: CameraToken,
+    credential_id: i32,
+    rotation_result: DeviceBody<RotationResult>,
+) -> Result<Device<StreamCredential>, ApiError> {
+    record_camera_contact(camera_token.camera_id, &conn);
+
+    finish_rotation(
+        camera_token.camera_id,
+        credential_id,
+        &rotation_result.into_inner(),
+        &conn,
+    )
+    .map(Device)
+}
@@ -0,0 +1,211 @@
+use crate::{
+    api_error::ApiError, camera::CameraId, settings::settings, user_tokens::UserToken,
+    users_cameras::check_if_user_owns_camera, CameraServerDbConn,
+};
+
+use super::schema::{srt_passphrases, stream_keys};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::{delete, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// A camera's stream key, only ever returned when it's made.
+#[derive(Serialize, JsonSchema)]
+pub struct NewStreamKey {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub stream_key: String,
+    /// Where to push RTMP to, with the key on the end. None if RTMP ingest is off.
+    pub rtmp_path: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A camera's SRT passphrase, only ever returned when it's made.
+#[derive(Serialize, JsonSchema)]
+pub struct NewSrtPassphrase {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub passphrase: String,
+    /// The SRT stream ID to push with, which is the camera's ID.
+    pub stream_id: String,
+    /// The port to push SRT to. None if SRT ingest is off.
+    pub srt_port: Option<u16>,
+    pub created_at: DateTime<Utc>,
+}
+
+fn hash_stream_key(stream_key: &str) -> String {
+    hex::encode(Sha256::digest(stream_key.trim().as_bytes()))
+}
+
+/// The camera the stream key is for, and marks the key as used.
+pub fn camera_for_key(
+    stream_key: &str,
+    connection: &PgConnection,
+) -> QueryResult<Option<uuid::Uuid>> {
+    diesel::update(
+        stream_keys::table.filter(stream_keys::stream_key_hash.eq(hash_stream_key(stream_key))),
+    )
+    .set(stream_keys::last_used_at.eq(Utc::now()))
+    .returning(stream_keys::camera_id)
+    .get_result(connection)
+    .optional()
+}
+
+/// The camera's SRT passphrase, and marks it as used.
+pub fn srt_passphrase(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Option<String>> {
+    diesel::update(srt_passphrases::table.find(camera_id))
+        .set(srt_passphrases::last_used_at.eq(Utc::now()))
+        .returning(srt_passphrases::passphrase)
+        .get_result(connection)
+        .optional()
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to update stream key! The error was {}", error);
+    ApiError {
+        error: "Failed to update stream key",
+        code: "update_stream_key_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Makes the camera a new stream key for pushing video, replacing its old one, which stops working straight away.
+/// The key is only shown this once. Only for the camera's owner.
+#[openapi]
+#[post("/Cameras/<camera_id>/StreamKey")]
+pub fn create_stream_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<NewStreamKey>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    let stream_key = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now();
+
+    diesel::insert_into(stream_keys::table)
+        .values((
+            stream_keys::camera_id.eq(camera_id),
+            stream_keys::stream_key_hash.eq(hash_stream_key(&stream_key)),
+        ))
+        .on_conflict(stream_keys::camera_id)
+        .do_update()
+        .set((
+            stream_keys::stream_key_hash.eq(hash_stream_key(&stream_key)),
+            stream_keys::created_at.eq(now),
+            stream_keys::last_used_at.eq(None::<DateTime<Utc>>),
+        ))
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(NewStreamKey {
+        camera_id,
+        rtmp_path: settings()
+            .ingest
+            .rtmp_port
+            .map(|_| format!("/live/{}", stream_key)),
+        stream_key,
+        created_at: now,
+    }))
+}
+
+/// Removes the camera's stream key, so nothing can push video for it. Only for the camera's owner.
+#[openapi]
+#[delete("/Cameras/<camera_id>/StreamKey")]
+pub fn delete_stream_key(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    match diesel::delete(stream_keys::table.find(camera_id))
+        .execute(&*conn)
+        .map_err(database_error)?
+    {
+        0 => Err(ApiError {
+            error: "The camera doesn't have a stream key",
+            code: "stream_key_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Makes the camera a new SRT passphrase, replacing its old one. Callers already connected stay connected, new
+/// connections need the new passphrase. The passphrase is only shown this once. Only for the camera's owner.
+#[openapi]
+#[post("/Cameras/<camera_id>/SrtPassphrase")]
+pub fn create_srt_passphrase(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<NewSrtPassphrase>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    // SRT passphrases have to be between 10 and 79 characters
+    let passphrase = uuid::Uuid::new_v4().simple().to_string();
+    let now = Utc::now();
+
+    diesel::insert_into(srt_passphrases::table)
+        .values((
+            srt_passphrases::camera_id.eq(camera_id),
+            srt_passphrases::passphrase.eq(&passphrase),
+        ))
+        .on_conflict(srt_passphrases::camera_id)
+        .do_update()
+        .set((
+            srt_passphrases::passphrase.eq(&passphrase),
+            srt_passphrases::created_at.eq(now),
+            srt_passphrases::last_used_at.eq(None::<DateTime<Utc>>),
+        ))
+        .execute(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(NewSrtPassphrase {
+        camera_id,
+        passphrase,
+        stream_id: camera_id.to_string(),
+        srt_port: settings().ingest.srt_port,
+        created_at: now,
+    }))
+}
+
+/// Removes the camera's SRT passphrase, so nothing can push SRT for it. Only for the camera's owner.
+#[openapi]
+#[delete("/Cameras/<camera_id>/SrtPassphrase")]
+pub fn delete_srt_passphrase(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    match diesel::delete(srt_passphrases::table.find(camera_id))
+        .execute(&*conn)
+        .map_err(database_error)?
+    {
+        0 => Err(ApiError {
+            error: "The camera doesn't have an SRT passphrase",
+            code: "srt_passphrase_not_found",
+            status: Status::NotFound,
+            field: None,
+        }),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera_commands::{self, InsertableCameraCommand, TALK_COMMAND},
+    realtime::is_timeout,
+    users_cameras,
+};
+
+use diesel::pg::PgConnection;
+use once_cell::sync::Lazy;
+use rocket::http::Status;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error as WebSocketError, Message, WebSocket};
+
+/// How long either side waits for the other, after which it's disconnected. Cameras are sent TALK_COMMAND when a
+/// viewer connects, and have this long to connect too.
+pub const PEER_WAIT_SECONDS: u64 = 20;
+
+/// Messages bigger than this close the connection. Audio frames are a few hundred bytes.
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// How many messages can wait for a side that has fallen behind. Audio is only worth playing while it's current,
+/// so anything more is dropped.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// How long the relay's WebSocket reads wait before queued messages are sent on, which is about how much latency the
+/// relay adds.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The talk routes on the realtime server. They're served with and without the API prefix, like the event stream.
+pub enum Route {
+    /// /Cameras/<camera_id>/Talk, for users talking through the camera.
+    Viewer(uuid::Uuid),
+    /// /Device/Talk, for cameras.
+    Camera,
+}
+
+pub fn route(path: &str) -> Option<Route> {
+    let path = path.strip_prefix(API_PREFIX).unwrap_or(path);
+    if path == "/Device/Talk" {
+        return Some(Route::Camera);
+    }
+
+    let camera_id = path.strip_prefix("/Cameras/")?.strip_suffix("/Talk")?;
+    uuid::Uuid::parse_str(camera_id).ok().map(Route::Viewer)
+}
+
+/// Sent by the relay itself, as JSON text messages. Everything else either side sends is passed on as it is, so
+/// apps and cameras can agree on a codec between themselves.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TalkStatus {
+    CameraConnected,
+    CameraDisconnected,
+    ViewerConnected { user_id: String },
+    ViewerDisconnected,
+}
+
+impl TalkStatus {
+    fn message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+struct Peer {
+    id: u64,
+    sender: SyncSender<Message>,
+}
+
+/// Both ends of a camera's talk relay.
+#[derive(Default)]
+struct Channel {
+    camera: Option<Peer>,
+    viewer: Option<(uuid::Uuid, Peer)>,
+}
+
+/// Every camera someone is talking through, or waiting to. Only on this instance, so the viewer and the camera
+/// have to reach the same one.
+static CHANNELS: Lazy<Mutex<HashMap<uuid::Uuid, Channel>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_PEER_ID: AtomicU64 = AtomicU64::new(0);
+
+fn send(peer: &Peer, message: Message) {
+    // Full queues drop the message rather than holding up the other side
+    let _ = peer.sender.try_send(message);
+}
+
+/// One side's place in a camera's relay. Dropping it leaves the relay and tells the other side.
+pub struct Membership {
+    camera_id: uuid::Uuid,
+    is_camera: bool,
+    id: u64,
+    receiver: Receiver<Message>,
+}
+
+impl Membership {
+    fn other_connected(&self) -> bool {
+        let channels = CHANNELS.lock().expect("Talk channels were poisoned");
+        match channels.get(&self.camera_id) {
+            Some(channel) if self.is_camera => channel.viewer.is_some(),
+            Some(channel) => channel.camera.is_some(),
+            None => false,
+        }
+    }
+
+    fn forward(&self, message: Message) {
+        let channels = CHANNELS.lock().expect("Talk channels were poisoned");
+        if let Some(channel) = channels.get(&self.camera_id) {
+            let other = match self.is_camera {
+                true => channel.viewer.as_ref().map(|(_, viewer)| viewer),
+                false => channel.camera.as_ref(),
+            };
+            if let Some(other) = other {
+                send(other, message);
+            }
+        }
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let mut channels = CHANNELS.lock().expect("Talk channels were poisoned");
+        let channel = match channels.get_mut(&self.camera_id) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        // Cameras that reconnected have already replaced this membership
+        if self.is_camera && channel.camera.as_ref().map(|camera| camera.id) == Some(self.id) {
+            channel.camera = None;
+            if let Some((_, viewer)) = &channel.viewer {
+                send(viewer, TalkStatus::CameraDisconnected.message());
+            }
+        } else if !self.is_camera
+            && channel.viewer.as_ref().map(|(_, viewer)| viewer.id) == Some(self.id)
+        {
+            info!("Stopped talking through camera {}", self.camera_id);
+            channel.viewer = None;
+            if let Some(camera) = &channel.camera {
+                send(camera, TalkStatus::ViewerDisconnected.message());
+            }
+        }
+
+        if channel.camera.is_none() && channel.viewer.is_none() {
+            channels.remove(&self.camera_id);
+        }
+    }
+}
+
+fn new_peer() -> (Peer, u64, Receiver<Message>) {
+    let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+    let id = NEXT_PEER_ID.fetch_add(1, Ordering::SeqCst);
+    (Peer { id, sender }, id, receiver)
+}
+
+/// Joins the camera's relay to talk through it, if the user owns it or it was shared with them with can_talk, and
+/// nobody else is already talking. Asks the camera to connect if it hasn't.
+pub fn join_as_viewer(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> Result<Membership, ApiError> {
+    let can_talk = users_cameras::can_talk(user_id, camera_id, connection).map_err(|error| {
+        error!(
+            "Failed to check if user {} can talk through camera {}! The error was {}",
+            user_id, camera_id, error
+        );
+        ApiError {
+            error: "Failed to check talk permission",
+            code: "check_talk_permission_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+    if !can_talk {
+        return Err(ApiError {
+            error: "You can't talk through this camera",
+            code: "talk_not_allowed",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    let (peer, id, receiver) = new_peer();
+    let camera_connected = {
+        let mut channels = CHANNELS.lock().expect("Talk channels were poisoned");
+        let channel = channels.entry(camera_id).or_default();
+        if channel.viewer.is_some() {
+            return Err(ApiError {
+                error: "Someone is already talking through the camera",
+                code: "talk_in_use",
+                status: Status::Conflict,
+                field: None,
+            });
+        }
+
+        if let Some(camera) = &channel.camera {
+            send(
+                camera,
+                TalkStatus::ViewerConnected {
+                    user_id: user_id.to_string(),
+                }
+                .message(),
+            );
+            send(&peer, TalkStatus::CameraConnected.message());
+        }
+        channel.viewer = Some((user_id, peer));
+        channel.camera.is_some()
+    };
+    let membership = Membership {
+        camera_id,
+        is_camera: false,
+        id,
+        receiver,
+    };
+
+    info!(
+        "User {} started talking through camera {}",
+        user_id, camera_id
+    );
+
+    if !camera_connected {
+        camera_commands::insert(
+            InsertableCameraCommand {
+                camera_id,
+                command: TALK_COMMAND.to_string(),
+                expires_at: None,
+            },
+            connection,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to queue talk command for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to ask the camera to connect",
+                code: "request_camera_connection_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+    }
+
+    Ok(membership)
+}
+
+/// Joins the camera's relay as the camera, replacing its last connection if it had one.
+pub fn join_as_camera(camera_id: uuid::Uuid) -> Membership {
+    let (peer, id, receiver) = new_peer();
+
+    let mut channels = CHANNELS.lock().expect("Talk channels were poisoned");
+    let channel = channels.entry(camera_id).or_default();
+    if let Some((user_id, viewer)) = &channel.viewer {
+        send(viewer, TalkStatus::CameraConnected.message());
+        send(
+            &peer,
+            TalkStatus::ViewerConnected {
+                user_id: user_id.to_string(),
+            }
+            .message(),
+        );
+    }
+    channel.camera = Some(peer);
+
+    Membership {
+        camera_id,
+        is_camera: true,
+        id,
+        receiver,
+    }
+}
+
+/// Passes messages between one side's WebSocket and the other side, until either closes, or the other side has been
+/// gone for PEER_WAIT_SECONDS. The WebSocket's reads should time out after POLL_INTERVAL.
+pub fn relay<S: Read + Write>(mut websocket: WebSocket<S>, membership: Membership) {
+    let mut last_ping = Instant::now();
+    let mut alone_since = Instant::now();
+
+    loop {
+        loop {
+            match membership.receiver.try_recv() {
+                Ok(message) => {
+                    if websocket.write_message(message).is_err() {
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // Replaced by the camera reconnecting
+                Err(TryRecvError::Disconnected) => {
+                    let _ = websocket.close(None);
+                    return;
+                }
+            }
+        }
+
+        if membership.other_connected() {
+            alone_since = Instant::now();
+        } else if alone_since.elapsed() >= Duration::from_secs(PEER_WAIT_SECONDS) {
+            let _ = websocket.close(None);
+            return;
+        }
+
+        if last_ping.elapsed() >= PING_INTERVAL {
+            if websocket.write_message(Message::Ping(Vec::new())).is_err() {
+                return;
+            }
+            last_ping = Instant::now();
+        }
+
+        match websocket.read_message() {
+            Ok(message @ Message::Binary(_)) | Ok(message @ Message::Text(_)) => {
+                membership.forward(message)
+            }
+            Ok(Message::Close(_)) => return,
+            Ok(_) => {}
+            Err(WebSocketError::Io(error)) if is_timeout(&error) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Limits the relay's WebSockets to MAX_MESSAGE_BYTES.
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_BYTES),
+        max_frame_size: Some(MAX_MESSAGE_BYTES),
+        ..WebSocketConfig::default()
+    }
+}
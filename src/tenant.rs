@@ -0,0 +1,400 @@
+use crate::{
+    admin::AdminToken,
+    api_error::{self, ApiError},
+    audit,
+    cache::{self, cache},
+    enums::token_error::{TokenError, TokenRejection},
+    soft_delete::not_found_or_database_error,
+    users_cameras, CameraServerDbConn,
+};
+
+use super::schema::{tenants, users};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{delete, get, post, Outcome, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+
+/// Everyone who signed up before there were tenants, and every request that doesn't say which tenant it's for.
+pub const DEFAULT_TENANT_ID: i32 = 1;
+
+/// Requests can name their tenant's slug with this header, if they can't be sent to the tenant's hostname.
+pub const TENANT_HEADER: &str = "tenant";
+
+/// A household or company sharing the deployment with others. Every user belongs to one, and can only log in and
+/// use their tokens in it, so they can't see or find anyone in another. Cameras belong to their owner's tenant.
+/// Admins in ADMIN_USER_IDS run the whole deployment, and can see every tenant.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct Tenant {
+    pub tenant_id: i32,
+    pub name: String,
+    /// Sent in the tenant header.
+    pub slug: String,
+    /// Requests to this hostname are for the tenant, without needing the tenant header.
+    pub hostname: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Sent with POST /Admin/Tenants.
+#[derive(Insertable, Deserialize, JsonSchema)]
+#[table_name = "tenants"]
+pub struct NewTenant {
+    pub name: String,
+    /// Lowercase letters, numbers and dashes.
+    pub slug: String,
+    pub hostname: Option<String>,
+}
+
+/// Looks `key` up as a tenant ID, caching what `load` returns if it wasn't there. Misses are cached too,
+/// as most requests aren't sent to a tenant's hostname.
+fn cached_tenant_id<E>(
+    key: &str,
+    load: impl FnOnce() -> Result<Option<i32>, E>,
+) -> Result<Option<i32>, E> {
+    if let Some(value) = cache().get(key) {
+        return Ok(value.parse().ok());
+    }
+
+    let value = load()?;
+    cache().set(
+        key,
+        &value
+            .map(|tenant_id| tenant_id.to_string())
+            .unwrap_or_default(),
+        cache::cache_ttl(),
+    );
+    Ok(value)
+}
+
+fn connect(request: &Request) -> Result<CameraServerDbConn, Status> {
+    CameraServerDbConn::from_request(request)
+        .succeeded()
+        .ok_or(Status::ServiceUnavailable)
+}
+
+fn lookup_error(error: diesel::result::Error) -> Status {
+    error!("Failed to look up tenant! The error was {}", error);
+    Status::ServiceUnavailable
+}
+
+/// The Host header without its port.
+fn hostname(host: &str) -> String {
+    match host.rfind(']') {
+        // An IPv6 address
+        Some(end) => host[..=end].to_string(),
+        None => host.split(':').next().unwrap_or(host).to_lowercase(),
+    }
+}
+
+/// The tenant a client is for: the one whose slug it named, or else the one whose hostname it connected to, or else
+/// DEFAULT_TENANT_ID. Fails with a 404 if the slug doesn't name a tenant. Every transport that takes tokens works
+/// its tenant out with this, from whatever it has in place of the tenant and Host headers.
+pub fn resolve_tenant<C: Deref<Target = PgConnection>>(
+    slug: Option<&str>,
+    host: Option<&str>,
+    connect: impl Fn() -> Result<C, Status>,
+) -> Result<i32, Status> {
+    if let Some(slug) = slug {
+        return cached_tenant_id(&cache::tenant_slug_key(slug), || {
+            tenants::table
+                .filter(tenants::slug.eq(slug))
+                .select(tenants::tenant_id)
+                .get_result::<i32>(&*connect()?)
+                .optional()
+                .map_err(lookup_error)
+        })?
+        .ok_or(Status::NotFound);
+    }
+
+    let hostname = match host {
+        Some(host) => hostname(host),
+        None => return Ok(DEFAULT_TENANT_ID),
+    };
+
+    cached_tenant_id(&cache::tenant_hostname_key(&hostname), || {
+        tenants::table
+            .filter(tenants::hostname.eq(&hostname))
+            .select(tenants::tenant_id)
+            .get_result::<i32>(&*connect()?)
+            .optional()
+            .map_err(lookup_error)
+    })
+    .map(|tenant_id| tenant_id.unwrap_or(DEFAULT_TENANT_ID))
+}
+
+fn resolve(request: &Request) -> Result<i32, Status> {
+    resolve_tenant(
+        request.headers().get_one(TENANT_HEADER),
+        request.headers().get_one("Host"),
+        || connect(request),
+    )
+}
+
+/// Stored in a request's local cache, so its tenant is only worked out once.
+struct RequestTenant(Result<i32, Status>);
+
+/// The tenant the request is for: the one whose slug is in the tenant header, or else the one whose hostname it was
+/// sent to, or else DEFAULT_TENANT_ID. Fails with a 404 if the tenant header doesn't name a tenant.
+pub fn request_tenant(request: &Request) -> Result<i32, Status> {
+    request.local_cache(|| RequestTenant(resolve(request))).0
+}
+
+/// The tenant a request is for, for routes used before there's a token to go by, like POST /Users and POST /Login.
+pub struct RequestTenantId(pub i32);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RequestTenantId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request_tenant(request) {
+            Ok(tenant_id) => Outcome::Success(RequestTenantId(tenant_id)),
+            Err(status) => {
+                if status == Status::NotFound {
+                    api_error::record_guard_failure(request, "tenant_not_found", "No such tenant");
+                }
+                Outcome::Failure((status, ()))
+            }
+        }
+    }
+}
+
+/// Turns a token away as if it didn't exist unless it belongs to the tenant the client is for.
+fn check_tenant(
+    client_tenant_id: Result<i32, Status>,
+    tenant_id: Result<i32, Status>,
+) -> Result<(), TokenRejection> {
+    let client_tenant_id = client_tenant_id.map_err(|status| {
+        if status == Status::NotFound {
+            TokenRejection::because(
+                status,
+                TokenError::NotFound,
+                "tenant_not_found",
+                "No such tenant",
+            )
+        } else {
+            TokenRejection::new(status, TokenError::NotFound)
+        }
+    })?;
+
+    match tenant_id {
+        Ok(tenant_id) if tenant_id == client_tenant_id => Ok(()),
+        Ok(_) => Err(TokenRejection::new(
+            Status::Unauthorized,
+            TokenError::NotFound,
+        )),
+        Err(status) => Err(TokenRejection::new(status, TokenError::NotFound)),
+    }
+}
+
+/// The tenant the user belongs to.
+pub fn users_tenant<C: Deref<Target = PgConnection>>(
+    user_id: uuid::Uuid,
+    connect: impl Fn() -> Result<C, Status>,
+) -> Result<i32, Status> {
+    cached_tenant_id(&cache::user_tenant_key(user_id), || {
+        users::table
+            .find(user_id)
+            .select(users::tenant_id)
+            .get_result::<i32>(&*connect()?)
+            .optional()
+            .map_err(lookup_error)
+    })
+    .and_then(|tenant_id| tenant_id.ok_or(Status::Unauthorized))
+}
+
+/// The tenant the camera's owner belongs to. Cameras nobody owns count as the default tenant's.
+pub fn cameras_tenant<C: Deref<Target = PgConnection>>(
+    camera_id: uuid::Uuid,
+    connect: impl Fn() -> Result<C, Status>,
+) -> Result<i32, Status> {
+    cached_tenant_id(&cache::camera_tenant_key(camera_id), || {
+        let connection = connect()?;
+
+        match users_cameras::get_owners(vec![camera_id], &connection)
+            .map_err(lookup_error)?
+            .remove(&camera_id)
+        {
+            Some(owner_id) => users::table
+                .find(owner_id)
+                .select(users::tenant_id)
+                .get_result::<i32>(&*connection)
+                .optional()
+                .map_err(lookup_error),
+            None => Ok(None),
+        }
+    })
+    .map(|tenant_id| tenant_id.unwrap_or(DEFAULT_TENANT_ID))
+}
+
+/// Checked for every user token, so they only work in the user's own tenant, see user_tokens::authorise_user_token().
+pub fn check_user<C: Deref<Target = PgConnection>>(
+    user_id: uuid::Uuid,
+    client_tenant_id: Result<i32, Status>,
+    connect: impl Fn() -> Result<C, Status>,
+) -> Result<(), TokenRejection> {
+    check_tenant(client_tenant_id, users_tenant(user_id, connect))
+}
+
+/// Checked for every camera token, see camera_tokens::authorise_camera_token().
+pub fn check_camera<C: Deref<Target = PgConnection>>(
+    camera_id: uuid::Uuid,
+    client_tenant_id: Result<i32, Status>,
+    connect: impl Fn() -> Result<C, Status>,
+) -> Result<(), TokenRejection> {
+    check_tenant(client_tenant_id, cameras_tenant(camera_id, connect))
+}
+
+/// For after a camera is given to someone else, who may be in another tenant.
+pub fn forget_camera_tenant(camera_id: uuid::Uuid) {
+    cache().delete(&cache::camera_tenant_key(camera_id));
+}
+
+fn tenant_error(error: diesel::result::Error) -> ApiError {
+    match error {
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        ) => ApiError {
+            error: "Another tenant already has that slug or hostname",
+            code: "tenant_slug_taken",
+            status: Status::Conflict,
+            field: None,
+        },
+        _ => not_found_or_database_error(
+            error,
+            ("tenant_not_found", "Tenant not found"),
+            ("save_tenant_failed", "Failed to save tenant"),
+        ),
+    }
+}
+
+/// Every tenant, oldest first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Tenants")]
+pub fn get_tenants(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+) -> Result<Json<Vec<Tenant>>, ApiError> {
+    tenants::table
+        .order(tenants::tenant_id)
+        .load::<Tenant>(&*conn)
+        .map(Json)
+        .map_err(|error| {
+            error!("Failed to get tenants! The error was {}", error);
+            ApiError {
+                error: "Failed to get tenants",
+                code: "get_tenants_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Adds a tenant, which people can then sign up to with POST /Users. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Tenants", format = "json", data = "<new_tenant>")]
+pub fn create_tenant(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    new_tenant: Json<NewTenant>,
+) -> Result<Json<Tenant>, ApiError> {
+    let mut new_tenant = new_tenant.into_inner();
+
+    if new_tenant.name.trim().is_empty() {
+        return Err(ApiError {
+            error: "Tenants need a name",
+            code: "tenant_name_required",
+            status: Status::UnprocessableEntity,
+            field: Some("name"),
+        });
+    }
+    if new_tenant.slug.is_empty()
+        || !new_tenant
+            .slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(ApiError {
+            error: "Slugs can only have lowercase letters, numbers and dashes",
+            code: "invalid_slug",
+            status: Status::UnprocessableEntity,
+            field: Some("slug"),
+        });
+    }
+    new_tenant.hostname = new_tenant
+        .hostname
+        .map(|hostname| hostname.trim().to_lowercase())
+        .filter(|hostname| !hostname.is_empty());
+
+    let tenant = diesel::insert_into(tenants::table)
+        .values(new_tenant)
+        .get_result::<Tenant>(&*conn)
+        .map_err(tenant_error)?;
+
+    // Requests may have been sent to the slug or hostname before it was a tenant's
+    cache().delete(&cache::tenant_slug_key(&tenant.slug));
+    if let Some(hostname) = &tenant.hostname {
+        cache().delete(&cache::tenant_hostname_key(hostname));
+    }
+
+    info!("Tenant {} ({}) created", tenant.tenant_id, tenant.slug);
+    audit::record_after(&tenant);
+
+    Ok(Json(tenant))
+}
+
+/// Deletes a tenant that nobody belongs to, not even users who've been deleted but not purged.
+/// The default tenant can't be deleted. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Tenants/<tenant_id>")]
+pub fn delete_tenant(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    tenant_id: i32,
+) -> Result<(), ApiError> {
+    if tenant_id == DEFAULT_TENANT_ID {
+        return Err(ApiError {
+            error: "The default tenant can't be deleted",
+            code: "default_tenant",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    let tenant = tenants::table
+        .find(tenant_id)
+        .get_result::<Tenant>(&*conn)
+        .map_err(tenant_error)?;
+
+    let user_count = users::table
+        .filter(users::tenant_id.eq(tenant_id))
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(tenant_error)?;
+    if user_count > 0 {
+        return Err(ApiError {
+            error: "Tenants can only be deleted once nobody belongs to them",
+            code: "tenant_not_empty",
+            status: Status::Conflict,
+            field: None,
+        });
+    }
+
+    audit::record_before(&tenant);
+    diesel::delete(tenants::table.find(tenant_id))
+        .execute(&*conn)
+        .map_err(tenant_error)?;
+
+    cache().delete(&cache::tenant_slug_key(&tenant.slug));
+    if let Some(hostname) = &tenant.hostname {
+        cache().delete(&cache::tenant_hostname_key(hostname));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! Spins up the server against a schema of its own, so integration tests can call routes without setting up
+//! a database by hand. Only built with the test-support feature, e.g. `cargo test --features test-support`.
+//!
+//! The server connects to the database it's configured with (Rocket.toml, camera-server.toml or ROCKET_DATABASES),
+//! but every table is made in a new schema that's dropped when the TestServer is. Tests can run in parallel,
+//! and never see the database's own tables. The workers and the realtime, gRPC, CoAP and MQTT servers aren't started.
+
+use crate::{
+    api_version::API_PREFIX,
+    camera::{self, InsertableCamera},
+    database::{self, DATABASE_NAME},
+    health,
+    impersonation::{self, Impersonation, InsertableImpersonation},
+    tenant::{self, NewTenant},
+    user::{self, InsertableUser},
+    user_tokens::{self, InsertableUserToken},
+    users_cameras::{self, InsertableUsersCamera},
+    worker,
+};
+
+use super::schema::{impersonation_tokens, tenants};
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::config::Value;
+use rocket::http::Header;
+use rocket::local::Client;
+use rocket::Config;
+
+/// Every fixture user has this password, so tests can log in as them.
+pub const TEST_PASSWORD: &str = "camera-server-test";
+
+pub struct TestUser {
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub user_token: uuid::Uuid,
+}
+
+impl TestUser {
+    /// Authenticates a request as the user.
+    pub fn header(&self) -> Header<'static> {
+        Header::new("user_token", self.user_token.to_string())
+    }
+}
+
+pub struct TestCamera {
+    pub camera_id: uuid::Uuid,
+    pub camera_token: uuid::Uuid,
+}
+
+impl TestCamera {
+    /// Authenticates a request as the camera.
+    pub fn header(&self) -> Header<'static> {
+        Header::new("camera_token", self.camera_token.to_string())
+    }
+}
+
+pub struct TestServer {
+    pub client: Client,
+    schema: String,
+    /// The configured database URL, which isn't limited to the schema, for dropping it.
+    database_url: String,
+    /// The same URL with the schema first in search_path, which the server and fixtures use.
+    schema_url: String,
+}
+
+/// Puts `schema` first in search_path, so that's where the migrations make the tables. public stays in the path
+/// for the uuid-ossp functions. with_timeouts() leaves the URL ending in an options parameter, so the schema is
+/// added to it rather than given its own, which Postgres would read instead.
+fn with_search_path(url: &str, schema: &str) -> String {
+    format!("{}%20-c%20search_path%3D{}%2Cpublic", url, schema)
+}
+
+fn set_database_url(config: &mut Config, url: &str) {
+    if let Some(database) = config
+        .extras
+        .get_mut("databases")
+        .and_then(Value::as_table_mut)
+        .and_then(|databases| databases.get_mut(DATABASE_NAME))
+        .and_then(Value::as_table_mut)
+    {
+        database.insert(String::from("url"), Value::String(url.to_string()));
+    }
+}
+
+impl TestServer {
+    /// Makes a new schema, runs the migrations in it and builds the server. Panics if any of that fails,
+    /// which fails the test.
+    pub fn new() -> TestServer {
+        let mut config = database::with_timeouts(rocket::ignite().config().clone());
+        let database_url = worker::database_url(&config);
+        let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+        let schema_url = with_search_path(&database_url, &schema);
+
+        let connection = PgConnection::establish(&database_url)
+            .expect("Failed to connect to the test database!");
+        diesel::sql_query(format!("CREATE SCHEMA {}", schema))
+            .execute(&connection)
+            .expect("Failed to create the test schema!");
+
+        let connection =
+            PgConnection::establish(&schema_url).expect("Failed to connect to the test schema!");
+        health::run_migrations(&connection).expect("Failed to migrate the test schema!");
+
+        set_database_url(&mut config, &schema_url);
+        let client = Client::new(crate::rocket(config)).expect("Failed to build the test server!");
+
+        TestServer {
+            client,
+            schema,
+            database_url,
+            schema_url,
+        }
+    }
+
+    /// A connection to the test schema, for fixtures the builders don't cover and for checking what a route stored.
+    pub fn connection(&self) -> PgConnection {
+        PgConnection::establish(&self.schema_url).expect("Failed to connect to the test schema!")
+    }
+
+    /// Adds a user with TEST_PASSWORD, logged in with one token.
+    pub fn add_user(&self, username: &str) -> TestUser {
+        self.add_user_to(username, tenant::DEFAULT_TENANT_ID)
+    }
+
+    /// Like add_user(), in another tenant than the default one, see add_tenant().
+    pub fn add_user_to(&self, username: &str, tenant_id: i32) -> TestUser {
+        let connection = self.connection();
+        let password = user::hash_password(TEST_PASSWORD).expect("Failed to hash the password!");
+
+        let new_user = user::insert(
+            InsertableUser {
+                username: username.to_string(),
+                password,
+            },
+            tenant_id,
+            &connection,
+        )
+        .expect("Failed to add the user!");
+
+        TestUser {
+            user_id: new_user.user_id,
+            username: new_user.username,
+            user_token: self.add_user_token(new_user.user_id),
+        }
+    }
+
+    /// Logs the user in again, e.g. to test that other sessions are logged out.
+    pub fn add_user_token(&self, user_id: uuid::Uuid) -> uuid::Uuid {
+        user_tokens::insert(InsertableUserToken { user_id }, &self.connection())
+            .expect("Failed to add the user token!")
+            .user_token
+    }
+
+    /// Adds a tenant, which requests name with the tenant header. Tenants are cached by slug for every server in
+    /// the process, so each test needs a slug of its own.
+    pub fn add_tenant(&self, slug: &str) -> i32 {
+        diesel::insert_into(tenants::table)
+            .values(NewTenant {
+                name: slug.to_string(),
+                slug: slug.to_string(),
+                hostname: None,
+            })
+            .returning(tenants::tenant_id)
+            .get_result(&self.connection())
+            .expect("Failed to add the tenant!")
+    }
+
+    /// Suspends the user, as POST /Admin/Users/<user_id>/Suspend does.
+    pub fn suspend(&self, user: &TestUser) {
+        user::suspend(user.user_id, String::from("Testing"), &self.connection())
+            .expect("Failed to suspend the user!");
+    }
+
+    /// Lets `admin` impersonate `user`, as POST /Admin/Users/<user_id>/Impersonate does. Returns the user with the
+    /// impersonation token in place of their own.
+    pub fn impersonate(&self, user: &TestUser, admin: &TestUser) -> TestUser {
+        let impersonation = diesel::insert_into(impersonation_tokens::table)
+            .values(InsertableImpersonation {
+                user_id: user.user_id,
+                admin_id: Some(admin.user_id),
+                reason: String::from("Testing"),
+                expires_at: Utc::now()
+                    + ChronoDuration::minutes(impersonation::DEFAULT_IMPERSONATION_MINUTES),
+            })
+            .get_result::<Impersonation>(&self.connection())
+            .expect("Failed to impersonate the user!");
+
+        TestUser {
+            user_id: user.user_id,
+            username: user.username.clone(),
+            user_token: impersonation.impersonation_token,
+        }
+    }
+
+    /// Registers a camera owned by `owner`.
+    pub fn add_camera(&self, owner: &TestUser, name: &str) -> TestCamera {
+        let camera_token = camera::register_camera(
+            InsertableCamera {
+                name: name.to_string(),
+            },
+            owner.user_id,
+            &self.connection(),
+        )
+        .unwrap_or_else(|error| panic!("Failed to add the camera! The error was {}", error.error));
+
+        TestCamera {
+            camera_id: camera_token.camera_id,
+            camera_token: camera_token.camera_token,
+        }
+    }
+
+    /// Gives `user` access to a camera someone else owns.
+    pub fn share(&self, camera: &TestCamera, user: &TestUser) {
+        users_cameras::insert(
+            InsertableUsersCamera {
+                camera_id: camera.camera_id,
+                user_id: user.user_id,
+            },
+            &self.connection(),
+        )
+        .expect("Failed to share the camera!");
+    }
+
+    /// Puts the API prefix in front of a route's path, e.g. "/Camera" becomes "/api/v1/Camera".
+    pub fn path(&self, path: &str) -> String {
+        format!("{}{}", API_PREFIX, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let result = PgConnection::establish(&self.database_url)
+            .map_err(|error| error.to_string())
+            .and_then(|connection| {
+                diesel::sql_query(format!("DROP SCHEMA {} CASCADE", self.schema))
+                    .execute(&connection)
+                    .map_err(|error| error.to_string())
+            });
+
+        if let Err(error) = result {
+            error!(
+                "Failed to drop test schema {}! The error was {}",
+                self.schema, error
+            );
+        }
+    }
+}
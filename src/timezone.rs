@@ -0,0 +1,131 @@
+use crate::{api_error::ApiError, user_tokens::UserToken, CameraServerDbConn};
+
+use super::schema::users;
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::{get, put, Request};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Formats a timestamp the way the JSON API does: RFC 3339 in UTC, ending in Z. For anything written by hand,
+/// like CSV and webhooks, so every timestamp the server sends looks the same.
+pub fn rfc3339(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// The zone things made for a user to read are shown in. Every timestamp in the API itself is UTC whatever it is.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct AccountTimezone {
+    /// An IANA name like Europe/London. None is UTC.
+    pub timezone: Option<String>,
+}
+
+pub fn parse_timezone(timezone: &str) -> Result<Tz, ApiError> {
+    timezone.parse::<Tz>().map_err(|_| ApiError {
+        error: "Timezone must be an IANA name like Europe/London",
+        code: "invalid_timezone",
+        status: Status::UnprocessableEntity,
+        field: Some("timezone"),
+    })
+}
+
+/// The user's display timezone, or UTC if they haven't set one. Falls back to UTC rather than failing, as it's
+/// only used for display.
+pub fn user_timezone(user_id: uuid::Uuid, connection: &PgConnection) -> Tz {
+    match users::table
+        .find(user_id)
+        .select(users::timezone)
+        .get_result::<Option<String>>(connection)
+    {
+        Ok(timezone) => timezone
+            .and_then(|timezone| timezone.parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC),
+        Err(error) => {
+            error!(
+                "Failed to get the timezone of user {}! The error was {}",
+                user_id, error
+            );
+            Tz::UTC
+        }
+    }
+}
+
+/// Formats a timestamp in `timezone` with a strftime format, e.g. "%d %b %H:%M %Z" for "17 Jun 14:30 BST".
+pub fn display(timestamp: DateTime<Utc>, timezone: Tz, format: &str) -> String {
+    timestamp
+        .with_timezone(&timezone)
+        .format(format)
+        .to_string()
+}
+
+/// A timestamp for a file name, like 2021-06-17_1430, in the user's timezone so it matches their clock.
+pub fn file_name_time(timestamp: DateTime<Utc>, timezone: Tz) -> String {
+    display(timestamp, timezone, "%Y-%m-%d_%H%M")
+}
+
+/// Sends a response as a download called `file_name`.
+pub struct Attachment<R> {
+    pub file_name: String,
+    pub response: R,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Attachment<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        Response::build_from(self.response.respond_to(req)?)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            )
+            .ok()
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get timezone! The error was {}", error);
+    ApiError {
+        error: "Failed to get timezone",
+        code: "get_timezone_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+#[openapi]
+#[get("/Account/Timezone")]
+pub fn get_timezone(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+) -> Result<Json<AccountTimezone>, ApiError> {
+    users::table
+        .find(user_token.user_id)
+        .select(users::timezone)
+        .get_result::<Option<String>>(&*conn)
+        .map(|timezone| Json(AccountTimezone { timezone }))
+        .map_err(database_error)
+}
+
+/// Sets the timezone digests and download file names use.
+#[openapi]
+#[put("/Account/Timezone", format = "json", data = "<account_timezone>")]
+pub fn update_timezone(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    account_timezone: Json<AccountTimezone>,
+) -> Result<Json<AccountTimezone>, ApiError> {
+    let timezone = match &account_timezone.timezone {
+        Some(timezone) => Some(parse_timezone(timezone.trim())?.name().to_string()),
+        None => None,
+    };
+
+    diesel::update(users::table.find(user_token.user_id))
+        .set(users::timezone.eq(&timezone))
+        .execute(&*conn)
+        .map(|_| Json(AccountTimezone { timezone }))
+        .map_err(database_error)
+}
@@ -0,0 +1,258 @@
+use crate::{
+    method_routing,
+    settings::settings,
+    unix_socket::{self, SharedRocket},
+};
+
+use rocket::http::hyper;
+use rocket::http::tls::TlsServer;
+use rocket::Rocket;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tonic::transport::ServerTlsConfig;
+
+/// How often the certificate and key are checked for having been renewed.
+pub const RENEWAL_CHECK_SECONDS: u64 = 60;
+
+/// The PEM certificate chain to serve HTTPS with, set with cert_path in [tls]. Unset serves plain HTTP.
+pub fn cert_path() -> Option<&'static str> {
+    settings().tls.cert_path.as_deref()
+}
+
+/// The certificate's PEM private key, set with key_path in [tls].
+pub fn key_path() -> Option<&'static str> {
+    settings().tls.key_path.as_deref()
+}
+
+/// The certificate and key, if TLS is on. The settings make sure they're set together.
+pub fn paths() -> Option<(&'static str, &'static str)> {
+    Some((cert_path()?, key_path()?))
+}
+
+/// A certificate that can be swapped while the server runs, for one version of rustls. Every handshake is given
+/// whichever was loaded last. Rocket and tonic are built with different versions, so each has its own copy, loaded
+/// from the same files.
+macro_rules! swappable_certificate {
+    () => {
+        use once_cell::sync::Lazy;
+        use std::sync::{Arc, RwLock};
+
+        static CERTIFIED_KEY: Lazy<RwLock<Option<rustls::sign::CertifiedKey>>> =
+            Lazy::new(|| RwLock::new(None));
+
+        struct Resolver;
+
+        impl rustls::ResolvesServerCert for Resolver {
+            fn resolve(&self, _: rustls::ClientHello) -> Option<rustls::sign::CertifiedKey> {
+                CERTIFIED_KEY.read().ok()?.clone()
+            }
+        }
+
+        /// Parses the PEM certificate chain and private key, and serves them from the next handshake on.
+        pub fn load(cert: &[u8], key: &[u8]) -> Result<(), String> {
+            let certs = rustls::internal::pemfile::certs(&mut &cert[..])
+                .map_err(|_| String::from("the certificate isn't PEM"))?;
+            if certs.is_empty() {
+                return Err(String::from("there's no certificate"));
+            }
+
+            let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut &key[..])
+                .map_err(|_| String::from("the key isn't PEM"))?;
+            if keys.is_empty() {
+                keys = rustls::internal::pemfile::rsa_private_keys(&mut &key[..])
+                    .map_err(|_| String::from("the key isn't PEM"))?;
+            }
+            let key = keys
+                .first()
+                .ok_or_else(|| String::from("there's no private key"))?;
+            let signing_key = rustls::sign::any_supported_type(key)
+                .map_err(|_| String::from("the private key isn't a supported type"))?;
+
+            *CERTIFIED_KEY
+                .write()
+                .expect("TLS certificate lock poisoned!") = Some(rustls::sign::CertifiedKey::new(
+                certs,
+                Arc::new(signing_key),
+            ));
+            Ok(())
+        }
+
+        /// A config serving the loaded certificate, offering the protocols with ALPN.
+        pub fn server_config(protocols: &[&[u8]]) -> rustls::ServerConfig {
+            let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+            config.cert_resolver = Arc::new(Resolver);
+            config.set_persistence(rustls::ServerSessionMemoryCache::new(1024));
+            config.ticketer = rustls::Ticketer::new();
+            config.set_protocols(
+                &protocols
+                    .iter()
+                    .map(|protocol| protocol.to_vec())
+                    .collect::<Vec<_>>(),
+            );
+            config
+        }
+    };
+}
+
+/// The API's certificate, for the rustls Rocket serves HTTPS with.
+mod api_certificate {
+    swappable_certificate!();
+}
+
+/// The gRPC server's certificate, for the rustls tonic is built with.
+mod grpc_certificate {
+    use tokio_rustls::rustls;
+
+    swappable_certificate!();
+}
+
+/// Reads the certificate and key, and serves them from the API's and gRPC's next handshakes on.
+fn load_certificate(cert_path: &str, key_path: &str) -> Result<(), String> {
+    let read =
+        |path: &str| fs::read(path).map_err(|error| format!("failed to read {}: {}", path, error));
+    let (cert, key) = (read(cert_path)?, read(key_path)?);
+
+    api_certificate::load(&cert, &key)?;
+    grpc_certificate::load(&cert, &key)
+}
+
+/// Loads the certificate and key if TLS is on, so ones that can't be served stop the server before it starts.
+pub fn init() {
+    if let Some((cert_path, key_path)) = paths() {
+        if let Err(error) = load_certificate(cert_path, key_path) {
+            panic!(
+                "Failed to load the TLS certificate {}! The error was {}",
+                cert_path, error
+            );
+        }
+    }
+}
+
+/// Serves the API, over HTTPS if TLS is on, and on the unix socket if there is one. Only returns if the server fails
+/// to start.
+///
+/// Rocket 0.4 only reads a certificate once, when it launches, and only listens on TCP, so with TLS on or a unix
+/// socket the API is served by Rocket's own hyper server, see unix_socket::spawn_unix_listener(). For TLS its rustls
+/// config has a certificate that can be swapped, see spawn_renewal_watcher(). Rocket 0.4 only speaks HTTP/1.1, so
+/// HTTP/2 is only served by the gRPC server, which cameras can upload through instead.
+pub fn launch(rocket: Rocket) {
+    if cert_path().is_none() && unix_socket::unix_socket().is_none() {
+        rocket.launch();
+        return;
+    }
+
+    let address = format!("{}:{}", rocket.config().address, rocket.config().port);
+    let workers = rocket.config().workers as usize;
+    let keep_alive = rocket
+        .config()
+        .keep_alive
+        .map(|seconds| Duration::from_secs(seconds as u64));
+
+    // Launch fairings only run from Rocket's own launch()
+    method_routing::record_routes(&rocket);
+
+    let rocket = SharedRocket(Arc::new(rocket));
+    unix_socket::spawn_unix_listener(rocket.clone(), workers, keep_alive);
+
+    let served = match cert_path() {
+        Some(cert_path) => {
+            let tls = TlsServer {
+                cfg: Arc::new(api_certificate::server_config(&[b"http/1.1"])),
+            };
+            let mut server = hyper::Server::https(address.as_str(), tls).unwrap_or_else(|error| {
+                panic!("Failed to listen on {}! The error was {}", address, error)
+            });
+            server.keep_alive(keep_alive);
+
+            info!(
+                "Serving HTTPS on {} with the certificate at {}",
+                address, cert_path
+            );
+            server.handle_threads(rocket, workers)
+        }
+        None => {
+            let mut server = hyper::Server::http(address.as_str()).unwrap_or_else(|error| {
+                panic!("Failed to listen on {}! The error was {}", address, error)
+            });
+            server.keep_alive(keep_alive);
+
+            info!("Serving HTTP on {}", address);
+            server.handle_threads(rocket, workers)
+        }
+    };
+
+    match served {
+        // Waits for the server's threads when it's dropped, which is never, as they don't stop
+        Ok(_listening) => {}
+        Err(error) => error!("Failed to serve the API! The error was {}", error),
+    }
+}
+
+/// The realtime server's TLS config, or None if TLS is off. It serves the API's certificate, so renewals are picked
+/// up the same way. WebSockets and event streams are HTTP/1.1.
+pub fn realtime_tls_config() -> Option<Arc<rustls::ServerConfig>> {
+    paths()?;
+
+    Some(Arc::new(api_certificate::server_config(&[b"http/1.1"])))
+}
+
+/// The gRPC server's TLS config, or None if TLS is off. gRPC is always HTTP/2, negotiated with ALPN over TLS.
+pub fn grpc_tls_config() -> Option<ServerTlsConfig> {
+    paths()?;
+
+    let mut tls_config = ServerTlsConfig::new();
+    tls_config.rustls_server_config(grpc_certificate::server_config(&[b"h2"]));
+    Some(tls_config)
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Watches for the certificate or key being renewed (e.g. by certbot), and then serves the new ones from the
+/// API's, gRPC's and the realtime server's next handshakes on, without restarting. Connections already open keep
+/// the certificate they were made with. If the new ones can't be loaded, the old ones are kept. Does nothing if TLS
+/// is off.
+///
+/// A renewal isn't acted on until the files have stopped changing for RENEWAL_CHECK_SECONDS, so a certificate
+/// isn't loaded without its new key.
+pub fn spawn_renewal_watcher() {
+    let (cert_path, key_path) = match paths() {
+        Some(paths) => paths,
+        None => return,
+    };
+
+    thread::spawn(move || {
+        let mut served = (modified_at(cert_path), modified_at(key_path));
+        let mut last_seen = served;
+
+        loop {
+            thread::sleep(Duration::from_secs(RENEWAL_CHECK_SECONDS));
+
+            let current = (modified_at(cert_path), modified_at(key_path));
+
+            // A file that's missing part way through being replaced can't be served yet
+            let readable = current.0.is_some() && current.1.is_some();
+
+            if readable && current != served && current == last_seen {
+                match load_certificate(cert_path, key_path) {
+                    Ok(()) => info!(
+                        "The TLS certificate at {} has been renewed, now serving it",
+                        cert_path
+                    ),
+                    Err(error) => error!(
+                        "Failed to load the renewed TLS certificate at {}, still serving the old one! The error was {}",
+                        cert_path, error
+                    ),
+                }
+                served = current;
+            }
+
+            last_seen = current;
+        }
+    });
+}
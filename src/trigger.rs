@@ -0,0 +1,159 @@
+use crate::{
+    api_error::ApiError,
+    camera, event,
+    notification::Notification,
+    rule, timezone,
+    webhook::{destination_client, resolve_destination, DestinationError},
+};
+
+use diesel::prelude::*;
+use rocket::http::Status;
+use serde::Serialize;
+
+/// What gets POSTed to a rule's trigger URL. Everything is at the top level so that no-code tools can pick fields
+/// out without parsing anything. IFTTT Webhooks only passes value1 to value3 on, so those repeat the useful parts.
+/// Unlike webhooks, trigger payloads aren't signed.
+#[derive(Serialize)]
+pub struct TriggerPayload {
+    /// The camera's name.
+    pub value1: String,
+    /// The event type.
+    pub value2: String,
+    /// When the event happened, RFC 3339.
+    pub value3: String,
+    pub rule: String,
+    pub camera: String,
+    pub camera_id: String,
+    pub event_id: i32,
+    pub event_type: String,
+    pub severity: String,
+    pub confidence: f32,
+    pub occurred_at: String,
+    /// How many events the rule has grouped into this trigger during its cooldown.
+    pub event_count: i32,
+}
+
+/// Trigger URLs are checked like webhook URLs, so rules can't be used to reach the server itself or something on its
+/// network, see webhook::resolve_destination().
+pub fn validate_trigger_url(trigger_url: &str) -> Result<(), ApiError> {
+    if !trigger_url.starts_with("http://") && !trigger_url.starts_with("https://") {
+        return Err(ApiError {
+            error: "Trigger URL must be http or https",
+            code: "invalid_trigger_url",
+            status: Status::UnprocessableEntity,
+            field: Some("trigger_url"),
+        });
+    }
+
+    resolve_destination(trigger_url)
+        .map(|_| ())
+        .map_err(|error| ApiError {
+            error: match error {
+                DestinationError::InvalidUrl => "Trigger URL isn't valid",
+                DestinationError::Unresolvable(_) => "Trigger URL's host couldn't be resolved",
+                DestinationError::NotPublic(_) => {
+                    "Trigger URL must not go to a loopback, link-local or private address"
+                }
+            },
+            code: match error {
+                DestinationError::InvalidUrl => "invalid_trigger_url",
+                DestinationError::Unresolvable(_) => "unresolvable_trigger_url",
+                DestinationError::NotPublic(_) => "private_trigger_url",
+            },
+            status: Status::UnprocessableEntity,
+            field: Some("trigger_url"),
+        })
+}
+
+pub fn build_payload(
+    notification: &Notification,
+    connection: &PgConnection,
+) -> Result<(String, TriggerPayload), String> {
+    let rule_id = notification
+        .rule_id
+        .ok_or_else(|| String::from("The rule has been deleted"))?;
+    let event_id = notification
+        .event_id
+        .ok_or_else(|| String::from("The event has been deleted"))?;
+
+    let rule = rule::get(rule_id, connection).map_err(|error| error.to_string())?;
+    let trigger_url = rule
+        .trigger_url
+        .ok_or_else(|| String::from("The rule no longer has a trigger URL"))?;
+    let event = event::get(event_id, connection).map_err(|error| error.to_string())?;
+    let camera = camera::get(event.camera_id, connection).map_err(|error| error.to_string())?;
+
+    let occurred_at = timezone::rfc3339(event.occurred_at);
+
+    Ok((
+        trigger_url,
+        TriggerPayload {
+            value1: camera.name.clone(),
+            value2: event.event_type.clone(),
+            value3: occurred_at.clone(),
+            rule: rule.name,
+            camera: camera.name,
+            camera_id: camera.camera_id.to_string(),
+            event_id: event.event_id,
+            event_type: event.event_type,
+            severity: event.severity,
+            confidence: event.confidence,
+            occurred_at,
+            event_count: notification.event_count,
+        },
+    ))
+}
+
+/// POSTs the notification's event to its rule's trigger URL. The URL's host is resolved and checked again first, as
+/// what it resolves to can change, and only that address is connected to, see webhook::destination_client().
+pub fn send_trigger(notification: &Notification, connection: &PgConnection) -> Result<(), String> {
+    let (trigger_url, payload) = build_payload(notification, connection)?;
+
+    let client = resolve_destination(&trigger_url)
+        .map_err(|error| error.to_string())
+        .and_then(|destination| {
+            destination_client(&destination).map_err(|error| error.to_string())
+        })?;
+
+    let response = client
+        .post(&trigger_url)
+        .json(&payload)
+        .send()
+        .map_err(|error| error.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Trigger URL responded with {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_trigger_urls_with_private_hosts() {
+        for url in &[
+            "http://127.0.0.1/trigger",
+            "http://[::1]:8080/trigger",
+            "http://169.254.169.254/latest/meta-data",
+            "http://10.0.0.5/trigger",
+            "http://localhost/trigger",
+        ] {
+            let error = validate_trigger_url(url).err().expect(url);
+            assert_eq!(
+                error.error, "Trigger URL must not go to a loopback, link-local or private address",
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_trigger_urls_that_arent_http() {
+        for url in &["ftp://example.com/trigger", "javascript:alert(1)"] {
+            assert!(validate_trigger_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::settings::settings;
+
+use rocket::http::hyper::{self, net::NetworkListener, net::NetworkStream};
+use rocket::Rocket;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Where to also serve the API, set with unix_socket in [server]. Unset only listens on TCP.
+/// This lets a reverse proxy on the same machine reach the server without it listening on a TCP port the proxy
+/// has to be the only thing to reach, and access is controlled with file permissions instead.
+pub fn unix_socket() -> Option<&'static str> {
+    settings().server.unix_socket.as_deref()
+}
+
+/// The socket's permissions in octal, set with unix_socket_mode in [server]. Defaults to 660, so only the
+/// server's user and group (e.g. the one nginx runs as) can connect.
+pub fn unix_socket_mode() -> u32 {
+    u32::from_str_radix(&settings().server.unix_socket_mode, 8)
+        .expect("The settings make sure unix_socket_mode is octal")
+}
+
+/// Rocket, shared by the hyper servers for TCP and the unix socket, which each want their own handler.
+#[derive(Clone)]
+pub struct SharedRocket(pub Arc<Rocket>);
+
+impl hyper::Handler for SharedRocket {
+    fn handle<'a, 'k>(
+        &'a self,
+        request: hyper::Request<'a, 'k>,
+        response: hyper::Response<'a, hyper::net::Fresh>,
+    ) {
+        hyper::Handler::handle(&*self.0, request, response)
+    }
+}
+
+/// The socket, for hyper, which gives each of its threads a clone to accept connections with.
+#[derive(Clone)]
+struct SocketListener(Arc<UnixListener>);
+
+/// A connection to the socket. hyper clones it to read and write at once.
+#[derive(Clone)]
+struct SocketStream(Arc<UnixStream>);
+
+impl NetworkListener for SocketListener {
+    type Stream = SocketStream;
+
+    fn accept(&mut self) -> Result<SocketStream, hyper::Error> {
+        let (stream, _) = self.0.accept()?;
+        Ok(SocketStream(Arc::new(stream)))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(loopback())
+    }
+}
+
+/// Connections to the socket don't have an address, so requests through it come from 127.0.0.1 as far as rate
+/// limits are concerned, unless 127.0.0.1 is in trusted_proxies and the proxy sets X-Real-IP.
+fn loopback() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 0))
+}
+
+impl Read for SocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for SocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl NetworkStream for SocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(loopback())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(timeout)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
+
+/// Serves the API on unix_socket() if it's set, in plain HTTP, with its own pool of as many workers as Rocket has.
+/// Requests are handled by the same Rocket as TCP, without a hop over loopback.
+/// Panics if the socket can't be made, as the proxy in front would otherwise have nothing to send requests to.
+pub fn spawn_unix_listener(rocket: SharedRocket, workers: usize, keep_alive: Option<Duration>) {
+    let path = match unix_socket() {
+        Some(path) => path,
+        None => return,
+    };
+
+    // Left behind if the server didn't exit cleanly, and binding fails while it's there
+    if Path::new(path).exists() {
+        fs::remove_file(path).unwrap_or_else(|error| {
+            panic!(
+                "Failed to remove the old socket at {}! The error was {}",
+                path, error
+            )
+        });
+    }
+
+    let listener = UnixListener::bind(path)
+        .unwrap_or_else(|error| panic!("Failed to listen on {}! The error was {}", path, error));
+    fs::set_permissions(path, fs::Permissions::from_mode(unix_socket_mode())).unwrap_or_else(
+        |error| {
+            panic!(
+                "Failed to set the permissions of {}! The error was {}",
+                path, error
+            )
+        },
+    );
+
+    let mut server = hyper::Server::new(SocketListener(Arc::new(listener)));
+    server.keep_alive(keep_alive);
+
+    info!("Serving HTTP on {}", path);
+
+    thread::spawn(move || match server.handle_threads(rocket, workers) {
+        // Waits for the server's threads when it's dropped, which is never, as they don't stop
+        Ok(_listening) => {}
+        Err(error) => error!("Failed to serve on {}! The error was {}", path, error),
+    });
+}
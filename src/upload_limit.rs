@@ -0,0 +1,177 @@
+use crate::{api_error::ApiError, camera_tokens::CameraToken, settings::settings};
+
+use once_cell::sync::Lazy;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, Response};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Sent as Retry-After when an upload is turned away.
+pub const RETRY_AFTER_SECONDS: u64 = 5;
+
+/// How many uploads the instance stores at once, set with max_concurrent_uploads in [limits]. Defaults to 16.
+/// Uploads past this wait for a slot, so storage bandwidth and database connections are left for everything else.
+pub fn max_concurrent_uploads() -> usize {
+    settings().limits.max_concurrent_uploads
+}
+
+/// How many uploads one camera can have stored or waiting at once, set with max_concurrent_uploads_per_camera
+/// in [limits]. Defaults to 2. More are refused with a 429 straight away, so one camera can't fill the queue.
+pub fn max_concurrent_uploads_per_camera() -> usize {
+    settings().limits.max_concurrent_uploads_per_camera
+}
+
+/// How long (in seconds) an upload waits for a slot before it's refused with a 503, set with upload_queue_seconds
+/// in [limits]. Defaults to 10.
+pub fn upload_queue_seconds() -> u64 {
+    settings().limits.upload_queue_seconds
+}
+
+#[derive(Default)]
+struct Uploads {
+    /// Uploads being stored.
+    running: usize,
+    /// Camera ID to how many uploads it has being stored or waiting.
+    cameras: HashMap<uuid::Uuid, usize>,
+}
+
+impl Uploads {
+    fn release_camera(&mut self, camera_id: &uuid::Uuid) {
+        if let Some(count) = self.cameras.get_mut(camera_id) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.cameras.remove(camera_id);
+            }
+        }
+    }
+}
+
+/// Waiting uploads sleep on the condvar until a slot is given back.
+static UPLOADS: Lazy<(Mutex<Uploads>, Condvar)> =
+    Lazy::new(|| (Mutex::new(Uploads::default()), Condvar::new()));
+
+/// Lets an upload be stored. The slot is given back when it's dropped.
+pub struct UploadSlot {
+    camera_id: uuid::Uuid,
+}
+
+impl Drop for UploadSlot {
+    fn drop(&mut self) {
+        let (uploads, condvar) = &*UPLOADS;
+        let mut uploads = uploads.lock().expect("Uploads lock poisoned!");
+
+        uploads.running -= 1;
+        uploads.release_camera(&self.camera_id);
+
+        condvar.notify_one();
+    }
+}
+
+/// Waits up to upload_queue_seconds() for a slot to store an upload from the camera. Fails with a 429 if the camera
+/// already has max_concurrent_uploads_per_camera() uploads going, or a 503 if no slot came free in time.
+/// Used by every way of uploading, not just the REST API.
+pub fn acquire(camera_id: uuid::Uuid) -> Result<UploadSlot, ApiError> {
+    acquire_within(camera_id, Duration::from_secs(upload_queue_seconds()))
+}
+
+/// acquire(), but only waiting up to `wait`. Servers that handle one message at a time (CoAP and the MQTT bridge)
+/// don't wait at all, as waiting would hold up every other camera's messages too.
+pub fn acquire_within(camera_id: uuid::Uuid, wait: Duration) -> Result<UploadSlot, ApiError> {
+    let (uploads, condvar) = &*UPLOADS;
+    let mut uploads = uploads.lock().expect("Uploads lock poisoned!");
+
+    let camera_uploads = uploads.cameras.entry(camera_id).or_insert(0);
+
+    if *camera_uploads >= max_concurrent_uploads_per_camera() {
+        return Err(ApiError {
+            error: "This camera is already uploading, try again after Retry-After",
+            code: "upload_in_progress",
+            status: Status::TooManyRequests,
+            field: None,
+        });
+    }
+
+    // Counted while it waits, so a camera can't queue up more than its share
+    *camera_uploads += 1;
+
+    let limit = max_concurrent_uploads();
+    let (mut uploads, _) = condvar
+        .wait_timeout_while(uploads, wait, |uploads| uploads.running >= limit)
+        .expect("Uploads lock poisoned!");
+
+    if uploads.running >= limit {
+        uploads.release_camera(&camera_id);
+        warn!(
+            "Refused an upload from camera {}, as {} uploads were already being stored",
+            camera_id, uploads.running
+        );
+
+        return Err(ApiError {
+            error: "The server is busy storing other uploads, try again after Retry-After",
+            code: "uploads_busy",
+            status: Status::ServiceUnavailable,
+            field: None,
+        });
+    }
+
+    uploads.running += 1;
+
+    Ok(UploadSlot { camera_id })
+}
+
+/// Set in the request's local cache when an upload is refused, so UploadLimits adds Retry-After.
+struct Refused(bool);
+
+/// The camera's slot for an upload through the REST API. Comes after the camera_token is checked and before
+/// the body is read, so refused uploads aren't read at all.
+impl<'a, 'r> FromRequest<'a, 'r> for UploadSlot {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let camera_token = match request.guard::<CameraToken>() {
+            Outcome::Success(camera_token) => camera_token,
+            Outcome::Failure((status, _)) => {
+                return Outcome::Failure((
+                    status,
+                    ApiError {
+                        error: "Missing or invalid token",
+                        code: "invalid_token",
+                        status,
+                        field: None,
+                    },
+                ))
+            }
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
+
+        match acquire(camera_token.camera_id) {
+            Ok(slot) => Outcome::Success(slot),
+            Err(error) => {
+                request.local_cache(|| Refused(true));
+                Outcome::Failure((error.status, error))
+            }
+        }
+    }
+}
+
+/// Adds Retry-After to refused uploads. The body comes from the 429 or 503 catcher.
+pub struct UploadLimits;
+
+impl Fairing for UploadLimits {
+    fn info(&self) -> Info {
+        Info {
+            name: "Upload limits",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Refused(true) = request.local_cache(|| Refused(false)) {
+            response.set_header(Header::new("Retry-After", RETRY_AFTER_SECONDS.to_string()));
+        }
+    }
+}
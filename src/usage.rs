@@ -0,0 +1,486 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    page::{offset_and_limit, Page},
+    quota::QuotaToken,
+    user_tokens::UserToken,
+    users_cameras, worker, CameraServerDbConn,
+};
+
+use super::schema::{active_streams, usage_daily};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Date, Nullable, Text, Uuid as SqlUuid};
+use once_cell::sync::{Lazy, OnceCell};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{get, Request, Response};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often each server adds what it has counted to usage_daily. Counts since the last flush are lost if the
+/// server dies, so usage is a slight undercount rather than ever an overcount.
+pub const USAGE_FLUSH_SECONDS: u64 = 60;
+
+/// GET /Account/Usage and GET /Admin/Usage cover this many days if they aren't given a range.
+pub const DEFAULT_USAGE_DAYS: i64 = 30;
+
+/// Streams that haven't been marked as seen for this long were left behind by an instance that died, and stop
+/// counting towards max_streams.
+pub const STREAM_STALE_SECONDS: i64 = 3 * USAGE_FLUSH_SECONDS as i64;
+
+pub const WEBSOCKET_STREAM: &str = "websocket";
+pub const EVENT_STREAM: &str = "event_stream";
+pub const TALK_STREAM: &str = "talk";
+pub const RTSP_STREAM: &str = "rtsp";
+
+#[derive(Default)]
+struct Counts {
+    api_calls: i64,
+    upload_bytes: i64,
+    stream_seconds: i64,
+}
+
+#[derive(Default)]
+struct TokenCounts {
+    scope: &'static str,
+    user_id: Option<uuid::Uuid>,
+    camera_id: Option<uuid::Uuid>,
+    requests: i64,
+    bytes: i64,
+}
+
+/// What's been counted since the last flush. Uploads are counted by camera, as who they're billed to is only
+/// looked up when they're flushed.
+#[derive(Default)]
+struct Pending {
+    users: HashMap<(uuid::Uuid, NaiveDate), Counts>,
+    cameras: HashMap<(uuid::Uuid, NaiveDate), i64>,
+    /// Requests and bytes by token hash, for the quotas, see quota.rs.
+    tokens: HashMap<(String, NaiveDate), TokenCounts>,
+    /// Streams that are still connected, by stream ID, with their user and when their time was last counted.
+    streams: HashMap<uuid::Uuid, (uuid::Uuid, Instant)>,
+    /// Streams that have closed but couldn't be taken out of active_streams straight away.
+    ended_streams: Vec<uuid::Uuid>,
+}
+
+static PENDING: Lazy<Mutex<Pending>> = Lazy::new(|| Mutex::new(Pending::default()));
+
+/// Set by spawn_usage_flusher(), for taking closed streams out of active_streams.
+static DATABASE_URL: OnceCell<String> = OnceCell::new();
+
+fn pending() -> std::sync::MutexGuard<'static, Pending> {
+    PENDING.lock().expect("Usage lock poisoned!")
+}
+
+fn today() -> NaiveDate {
+    Utc::now().naive_utc().date()
+}
+
+/// Counts an upload towards the camera's owner, the user who registered it (or was given it with
+/// POST /Admin/Cameras/<camera_id>/Reassign). Users it's shared with aren't charged for it.
+pub fn record_upload(camera_id: uuid::Uuid, bytes: u64) {
+    *pending().cameras.entry((camera_id, today())).or_insert(0) += bytes as i64;
+}
+
+/// Counts a request made with a token, and the bytes it sent and was sent, towards token_usage_daily.
+pub fn record_token(token: &QuotaToken, bytes: u64) {
+    let mut pending = pending();
+    let counts = pending
+        .tokens
+        .entry((token.token_hash.clone(), today()))
+        .or_default();
+
+    counts.scope = token.scope;
+    counts.user_id = token.user_id;
+    counts.camera_id = token.camera_id;
+    counts.requests += 1;
+    counts.bytes += bytes as i64;
+}
+
+/// A realtime connection or RTSP stream being counted towards its user's stream time, and towards max_streams in
+/// active_streams. Its time is counted every USAGE_FLUSH_SECONDS while it's connected, and the rest when it's
+/// dropped. Start one with plan::start_stream(), which checks the user's plan first.
+pub struct StreamUsage {
+    stream_id: uuid::Uuid,
+}
+
+/// Adds the stream to active_streams, where every instance counts it.
+pub fn stream_started(
+    user_id: uuid::Uuid,
+    kind: &str,
+    connection: &PgConnection,
+) -> QueryResult<StreamUsage> {
+    let stream_id = uuid::Uuid::new_v4();
+
+    diesel::insert_into(active_streams::table)
+        .values((
+            active_streams::stream_id.eq(stream_id),
+            active_streams::user_id.eq(user_id),
+            active_streams::kind.eq(kind),
+        ))
+        .execute(connection)?;
+
+    pending()
+        .streams
+        .insert(stream_id, (user_id, Instant::now()));
+
+    Ok(StreamUsage { stream_id })
+}
+
+fn stale_streams_cutoff() -> DateTime<Utc> {
+    Utc::now() - ChronoDuration::seconds(STREAM_STALE_SECONDS)
+}
+
+/// How many streams the user has open, to every instance.
+pub fn active_streams(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<i64> {
+    active_streams::table
+        .filter(active_streams::user_id.eq(user_id))
+        .filter(active_streams::seen_at.gt(stale_streams_cutoff()))
+        .count()
+        .get_result(connection)
+}
+
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        {
+            let mut pending = pending();
+
+            if let Some((user_id, counted_at)) = pending.streams.remove(&self.stream_id) {
+                pending
+                    .users
+                    .entry((user_id, today()))
+                    .or_default()
+                    .stream_seconds += counted_at.elapsed().as_secs() as i64;
+            }
+        }
+
+        // Taken out straight away, so the user can open another one in its place. The connection it was opened with
+        // has usually been handed back by now, so this makes its own
+        let deleted = DATABASE_URL
+            .get()
+            .and_then(|database_url| PgConnection::establish(database_url).ok())
+            .map_or(false, |connection| {
+                diesel::delete(active_streams::table.find(self.stream_id))
+                    .execute(&connection)
+                    .is_ok()
+            });
+
+        if !deleted {
+            pending().ended_streams.push(self.stream_id);
+        }
+    }
+}
+
+/// Counts every request made with a user token towards that user, whatever it was for and however it went.
+pub struct UsageMetering;
+
+impl Fairing for UsageMetering {
+    fn info(&self) -> Info {
+        Info {
+            name: "Usage metering",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        if request.headers().get_one("user_token").is_none() {
+            return;
+        }
+
+        // The token is cached, so this doesn't usually touch the database again
+        if let Some(user_token) = request.guard::<UserToken>().succeeded() {
+            pending()
+                .users
+                .entry((user_token.user_id, today()))
+                .or_default()
+                .api_calls += 1;
+        }
+    }
+}
+
+/// Adds what's been counted since the last flush to usage_daily. If it can't be written, it's kept for next time.
+pub fn flush(connection: &PgConnection) -> QueryResult<()> {
+    let (mut users, cameras, tokens, open_streams, ended_streams) = {
+        let mut guard = pending();
+        let pending = &mut *guard;
+
+        for (user_id, counted_at) in pending.streams.values_mut() {
+            let seconds = counted_at.elapsed().as_secs();
+            *counted_at += Duration::from_secs(seconds);
+
+            pending
+                .users
+                .entry((*user_id, today()))
+                .or_default()
+                .stream_seconds += seconds as i64;
+        }
+
+        (
+            std::mem::take(&mut pending.users),
+            std::mem::take(&mut pending.cameras),
+            std::mem::take(&mut pending.tokens),
+            pending.streams.keys().copied().collect::<Vec<_>>(),
+            std::mem::take(&mut pending.ended_streams),
+        )
+    };
+
+    let result = connection.transaction(|| {
+        if cameras.len() > 0 {
+            let camera_ids = cameras.keys().map(|(camera_id, _)| *camera_id).collect();
+            let owners = users_cameras::get_owners(camera_ids, connection)?;
+
+            // Uploads from cameras nobody has are dropped, there's nobody to bill them to
+            for ((camera_id, day), bytes) in &cameras {
+                if let Some(user_id) = owners.get(camera_id) {
+                    users.entry((*user_id, *day)).or_default().upload_bytes += bytes;
+                }
+            }
+        }
+
+        for ((user_id, day), counts) in &users {
+            diesel::sql_query(
+                "INSERT INTO usage_daily (user_id, day, api_calls, upload_bytes, stream_seconds)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (user_id, day) DO UPDATE SET
+                    api_calls = usage_daily.api_calls + EXCLUDED.api_calls,
+                    upload_bytes = usage_daily.upload_bytes + EXCLUDED.upload_bytes,
+                    stream_seconds = usage_daily.stream_seconds + EXCLUDED.stream_seconds",
+            )
+            .bind::<SqlUuid, _>(user_id)
+            .bind::<Date, _>(day)
+            .bind::<BigInt, _>(counts.api_calls)
+            .bind::<BigInt, _>(counts.upload_bytes)
+            .bind::<BigInt, _>(counts.stream_seconds)
+            .execute(connection)?;
+        }
+
+        // Tokens whose user or camera has been deleted since are dropped, rather than failing the flush
+        for ((token_hash, day), counts) in &tokens {
+            diesel::sql_query(
+                "INSERT INTO token_usage_daily (token_hash, day, scope, user_id, camera_id, requests, bytes)
+                SELECT $1, $2, $3, $4, $5, $6, $7
+                WHERE EXISTS (SELECT 1 FROM users WHERE user_id = $4)
+                    OR EXISTS (SELECT 1 FROM cameras WHERE camera_id = $5)
+                ON CONFLICT (token_hash, day) DO UPDATE SET
+                    requests = token_usage_daily.requests + EXCLUDED.requests,
+                    bytes = token_usage_daily.bytes + EXCLUDED.bytes",
+            )
+            .bind::<Text, _>(token_hash)
+            .bind::<Date, _>(day)
+            .bind::<Text, _>(counts.scope)
+            .bind::<Nullable<SqlUuid>, _>(counts.user_id)
+            .bind::<Nullable<SqlUuid>, _>(counts.camera_id)
+            .bind::<BigInt, _>(counts.requests)
+            .bind::<BigInt, _>(counts.bytes)
+            .execute(connection)?;
+        }
+
+        // Marks this instance's streams as still open, and clears out closed ones and any left behind by an instance
+        // that died
+        diesel::update(active_streams::table.filter(active_streams::stream_id.eq_any(&open_streams)))
+            .set(active_streams::seen_at.eq(Utc::now()))
+            .execute(connection)?;
+        diesel::delete(
+            active_streams::table.filter(
+                active_streams::stream_id
+                    .eq_any(&ended_streams)
+                    .or(active_streams::seen_at.lt(stale_streams_cutoff())),
+            ),
+        )
+        .execute(connection)?;
+
+        Ok(())
+    });
+
+    // Upload bytes already added to users are left out, as they're put back by camera
+    if result.is_err() {
+        let mut pending = pending();
+
+        for (key, counts) in users {
+            let kept = pending.users.entry(key).or_default();
+            kept.api_calls += counts.api_calls;
+            kept.stream_seconds += counts.stream_seconds;
+        }
+        for (key, bytes) in cameras {
+            *pending.cameras.entry(key).or_insert(0) += bytes;
+        }
+        for (key, counts) in tokens {
+            let kept = pending.tokens.entry(key).or_default();
+            kept.scope = counts.scope;
+            kept.user_id = counts.user_id;
+            kept.camera_id = counts.camera_id;
+            kept.requests += counts.requests;
+            kept.bytes += counts.bytes;
+        }
+        pending.ended_streams.extend(ended_streams);
+    }
+
+    result
+}
+
+/// Starts flushing usage every USAGE_FLUSH_SECONDS. Every instance counts its own requests, so this runs on all of them.
+pub fn spawn_usage_flusher(database_url: String) {
+    let _ = DATABASE_URL.set(database_url.clone());
+
+    worker::spawn_concurrent_worker(
+        "Usage metering",
+        Duration::from_secs(USAGE_FLUSH_SECONDS),
+        database_url,
+        |connection| {
+            if let Err(error) = flush(connection) {
+                error!("Failed to write usage! The error was {}", error);
+            }
+        },
+    );
+}
+
+/// One user's usage on one day, in UTC.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct DailyUsage {
+    #[serde(skip)]
+    pub user_id: uuid::Uuid,
+    pub day: NaiveDate,
+    /// Requests made with the user's token.
+    pub api_calls: i64,
+    /// Bytes of images and audio uploaded by cameras the user owns.
+    pub upload_bytes: i64,
+    /// How long the user had the realtime WebSocket or event stream open for.
+    pub stream_seconds: i64,
+}
+
+/// Query string for GET /Account/Usage and GET /Admin/Usage.
+#[derive(FromForm, JsonSchema)]
+pub struct UsageQuery {
+    /// The first day to include, as YYYY-MM-DD. Defaults to DEFAULT_USAGE_DAYS ago.
+    pub from: Option<String>,
+    /// The last day to include, as YYYY-MM-DD. Defaults to today.
+    pub until: Option<String>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+impl UsageQuery {
+    fn days(&self) -> Result<(NaiveDate, NaiveDate), ApiError> {
+        let parse = |day: &String, field: &'static str| {
+            NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| ApiError {
+                error: "Failed to parse day, days must be YYYY-MM-DD",
+                code: "invalid_day",
+                status: Status::UnprocessableEntity,
+                field: Some(field),
+            })
+        };
+
+        let until = match &self.until {
+            Some(until) => parse(until, "until")?,
+            None => today(),
+        };
+        let from = match &self.from {
+            Some(from) => parse(from, "from")?,
+            None => until - ChronoDuration::days(DEFAULT_USAGE_DAYS - 1),
+        };
+
+        Ok((from, until))
+    }
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get usage! The error was {}", error);
+    ApiError {
+        error: "Failed to get usage",
+        code: "get_usage_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// The user's usage for each day in the range that they used anything, oldest first. Today's is up to
+/// USAGE_FLUSH_SECONDS behind.
+#[openapi]
+#[get("/Account/Usage?<query..>")]
+pub fn get_usage(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    query: Form<UsageQuery>,
+) -> Result<Json<Vec<DailyUsage>>, ApiError> {
+    let (from, until) = query.days()?;
+
+    usage_daily::table
+        .filter(usage_daily::user_id.eq(user_token.user_id))
+        .filter(usage_daily::day.between(from, until))
+        .order(usage_daily::day)
+        .load::<DailyUsage>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// One user's usage added up over a range of days.
+#[derive(QueryableByName, Serialize, JsonSchema)]
+pub struct UserUsage {
+    #[sql_type = "SqlUuid"]
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    #[sql_type = "Text"]
+    pub username: String,
+    #[sql_type = "BigInt"]
+    pub api_calls: i64,
+    #[sql_type = "BigInt"]
+    pub upload_bytes: i64,
+    #[sql_type = "BigInt"]
+    pub stream_seconds: i64,
+}
+
+#[derive(QueryableByName)]
+struct UsersCount {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Every user's usage added up over the range, heaviest users (by API calls) first. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Usage?<query..>")]
+pub fn get_all_usage(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<UsageQuery>,
+) -> Result<Json<Page<UserUsage>>, ApiError> {
+    let (from, until) = query.days()?;
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+
+    // SUM() of a BIGINT is a NUMERIC, which Diesel can't read without bigdecimal
+    let items = diesel::sql_query(
+        "SELECT usage_daily.user_id, users.username,
+            SUM(api_calls)::BIGINT AS api_calls,
+            SUM(upload_bytes)::BIGINT AS upload_bytes,
+            SUM(stream_seconds)::BIGINT AS stream_seconds
+        FROM usage_daily JOIN users ON users.user_id = usage_daily.user_id
+        WHERE day BETWEEN $1 AND $2
+        GROUP BY usage_daily.user_id, users.username
+        ORDER BY api_calls DESC, usage_daily.user_id
+        LIMIT $3 OFFSET $4",
+    )
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(until)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load::<UserUsage>(&*conn)
+    .map_err(database_error)?;
+
+    let total = diesel::sql_query(
+        "SELECT COUNT(DISTINCT user_id) AS count FROM usage_daily WHERE day BETWEEN $1 AND $2",
+    )
+    .bind::<Date, _>(from)
+    .bind::<Date, _>(until)
+    .get_result::<UsersCount>(&*conn)
+    .map_err(database_error)?
+    .count;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
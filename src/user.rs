@@ -1,11 +1,16 @@
 use crate::{
     api_error::ApiError,
+    cache::{self, cache},
+    database, oauth, onvif,
+    tenant::RequestTenantId,
     user_tokens::{self, UserToken},
+    users_cameras,
 };
 
 use super::schema::users;
 use super::CameraServerDbConn;
 use bcrypt;
+use chrono::{DateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{self};
@@ -13,7 +18,10 @@ use rocket::http::Status;
 use rocket::post;
 
 use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::ops::Deref;
 use user_tokens::InsertableUserToken;
 
 #[derive(Queryable, AsChangeset, Deserialize, Serialize)]
@@ -22,9 +30,25 @@ pub struct User {
     pub user_id: uuid::Uuid,
     pub username: String,
     pub password: String,
+    /// Set when the user is deleted. Deleted users can't log in, and are kept until they're purged.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Set when an admin disables the user. Disabled users can't log in, but keep their cameras and events.
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// What the user is allowed, see plan::Plan. None is unlimited.
+    pub plan_id: Option<i32>,
+    /// Set when an admin suspends the user, e.g. for abuse. Suspended users and their cameras get a 403 for
+    /// everything until they're reinstated, but nothing of theirs is deleted.
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspension_reason: Option<String>,
+    /// See tenant::Tenant.
+    pub tenant_id: i32,
+    /// See timezone::AccountTimezone.
+    pub timezone: Option<String>,
+    /// See i18n::AccountLocale.
+    pub locale: Option<String>,
 }
 
-#[derive(Insertable, Deserialize, Serialize)]
+#[derive(Insertable, Deserialize, Serialize, JsonSchema)]
 #[table_name = "users"]
 pub struct InsertableUser {
     pub username: String,
@@ -39,9 +63,10 @@ impl InsertableUser {
         }
     }
 }
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct UserInfo {
     pub username: String,
+    #[schemars(with = "String")]
     pub user_id: uuid::Uuid,
 }
 
@@ -54,23 +79,43 @@ impl UserInfo {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct AuthentiationResult {
     pub user_info: UserInfo,
+    #[schemars(with = "String")]
     pub user_token: uuid::Uuid,
 }
 
+pub type NotDeleted = diesel::dsl::Filter<users::table, diesel::dsl::IsNull<users::deleted_at>>;
+
+/// Users that haven't been deleted. Queries should start from this rather than users::table,
+/// unless they're meant to see deleted users too.
+pub fn not_deleted() -> NotDeleted {
+    users::table.filter(users::deleted_at.is_null())
+}
+
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<User>> {
-    users::table.load::<User>(&*connection)
+    not_deleted().load::<User>(&*connection)
 }
 
 pub fn get(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<User> {
+    not_deleted()
+        .filter(users::user_id.eq(id))
+        .get_result::<User>(connection)
+}
+
+/// Like get(), but finds deleted users too.
+pub fn get_including_deleted(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<User> {
     users::table.find(id).get_result::<User>(connection)
 }
 
-pub fn insert(user: InsertableUser, connection: &PgConnection) -> QueryResult<User> {
+pub fn insert(
+    user: InsertableUser,
+    tenant_id: i32,
+    connection: &PgConnection,
+) -> QueryResult<User> {
     diesel::insert_into(users::table)
-        .values(user)
+        .values((user, users::tenant_id.eq(tenant_id)))
         .get_result(connection)
 }
 
@@ -84,15 +129,164 @@ pub fn delete(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
     diesel::delete(users::table.find(id)).execute(connection)
 }
 
-pub fn get_by_username(username: String, connection: &PgConnection) -> QueryResult<User> {
+/// Hides the user and their access to cameras until they're undeleted or purged, and logs them out everywhere.
+/// Returns how many users were deleted, 0 if they already were.
+pub fn soft_delete(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    let deleted_at = Utc::now();
+
+    let (deleted, camera_ids) = connection.transaction::<_, diesel::result::Error, _>(|| {
+        let deleted = diesel::update(not_deleted().filter(users::user_id.eq(id)))
+            .set(users::deleted_at.eq(deleted_at))
+            .execute(connection)?;
+        let camera_ids = users_cameras::soft_delete_users_access(id, deleted_at, connection)?;
+        user_tokens::delete_users_tokens(id, connection)?;
+        Ok((deleted, camera_ids))
+    })?;
+
+    for camera_id in camera_ids {
+        cache().delete(&cache::camera_access_key(id, camera_id));
+    }
+
+    Ok(deleted)
+}
+
+/// Brings back a deleted user, along with the access that was deleted with them. They have to log in again.
+pub fn undelete(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<User> {
+    connection.transaction(|| {
+        let user = get_including_deleted(id, connection)?;
+
+        if let Some(deleted_at) = user.deleted_at {
+            users_cameras::undelete_users_access(id, deleted_at, connection)?;
+        }
+
+        diesel::update(users::table.find(id))
+            .set(users::deleted_at.eq(None::<DateTime<Utc>>))
+            .get_result(connection)
+    })
+}
+
+/// Stops the user logging in and logs them out everywhere, until they're enabled again. Their ONVIF credential and
+/// voice assistant links are deleted too, so NVRs and assistants lose access, and have to be set up again once
+/// they're enabled.
+/// Returns how many users were disabled, 0 if they already were.
+pub fn disable(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    connection.transaction(|| {
+        let disabled = diesel::update(
+            not_deleted()
+                .filter(users::user_id.eq(id))
+                .filter(users::disabled_at.is_null()),
+        )
+        .set(users::disabled_at.eq(Utc::now()))
+        .execute(connection)?;
+        user_tokens::delete_users_tokens(id, connection)?;
+        onvif::delete_users_credential(id, connection)?;
+        oauth::unlink_users_assistants(id, connection)?;
+        Ok(disabled)
+    })
+}
+
+/// Lets a disabled user log in again.
+pub fn enable(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<User> {
+    diesel::update(not_deleted().filter(users::user_id.eq(id)))
+        .set(users::disabled_at.eq(None::<DateTime<Utc>>))
+        .get_result(connection)
+}
+
+/// Suspends the user with the reason given, see User::suspended_at. Returns the user as they are now.
+pub fn suspend(id: uuid::Uuid, reason: String, connection: &PgConnection) -> QueryResult<User> {
+    let suspended = diesel::update(not_deleted().filter(users::user_id.eq(id)))
+        .set((
+            users::suspended_at.eq(Utc::now()),
+            users::suspension_reason.eq(reason),
+        ))
+        .get_result(connection)?;
+    forget_suspension(id, connection);
+    Ok(suspended)
+}
+
+pub fn reinstate(id: uuid::Uuid, connection: &PgConnection) -> QueryResult<User> {
+    let reinstated = diesel::update(not_deleted().filter(users::user_id.eq(id)))
+        .set((
+            users::suspended_at.eq(None::<DateTime<Utc>>),
+            users::suspension_reason.eq(None::<String>),
+        ))
+        .get_result(connection)?;
+    forget_suspension(id, connection);
+    Ok(reinstated)
+}
+
+/// Removes the cached suspension checks for the user and their cameras, so a change takes effect straight away.
+fn forget_suspension(id: uuid::Uuid, connection: &PgConnection) {
+    cache().delete(&cache::user_suspended_key(id));
+
+    match users_cameras::get_owned_camera_ids(id, connection) {
+        Ok(camera_ids) => {
+            for camera_id in camera_ids {
+                cache().delete(&cache::camera_suspended_key(camera_id));
+            }
+        }
+        Err(error) => error!(
+            "Failed to get user {}'s cameras to update their suspension! The error was {}",
+            id, error
+        ),
+    }
+}
+
+/// Whether the user is suspended. Cached for cache_ttl(), and `connect` is only called if it isn't cached, so the
+/// token guards don't take a second connection from the pool for every request.
+pub fn is_suspended<C: Deref<Target = PgConnection>>(
+    id: uuid::Uuid,
+    connect: impl FnOnce() -> C,
+) -> QueryResult<bool> {
+    cache::cached_bool(&cache::user_suspended_key(id), || {
+        users::table
+            .find(id)
+            .select(users::suspended_at.is_not_null())
+            .get_result::<bool>(&*connect())
+    })
+}
+
+/// Like is_suspended(), for the camera's owner, see users_cameras::get_owners().
+pub fn is_cameras_owner_suspended<C: Deref<Target = PgConnection>>(
+    camera_id: uuid::Uuid,
+    connect: impl FnOnce() -> C,
+) -> QueryResult<bool> {
+    cache::cached_bool(&cache::camera_suspended_key(camera_id), || {
+        let connection = connect();
+
+        match users_cameras::get_owners(vec![camera_id], &connection)?.remove(&camera_id) {
+            Some(owner_id) => users::table
+                .find(owner_id)
+                .select(users::suspended_at.is_not_null())
+                .get_result::<bool>(&*connection),
+            None => Ok(false),
+        }
+    })
+}
+
+/// Finds deleted users too, since their usernames stay taken until they're purged.
+/// Usernames are only unique within a tenant.
+pub fn get_by_username(
+    username: String,
+    tenant_id: i32,
+    connection: &PgConnection,
+) -> QueryResult<User> {
     return users::table
         .filter(users::username.eq_all(username))
+        .filter(users::tenant_id.eq(tenant_id))
         .first::<User>(connection);
 }
 
-pub fn is_login_valid(username: String, password: String, connection: &PgConnection) -> bool {
-    let query = users::table
+pub fn is_login_valid(
+    username: String,
+    password: String,
+    tenant_id: i32,
+    connection: &PgConnection,
+) -> bool {
+    let query = not_deleted()
         .filter(users::username.eq(username))
+        .filter(users::tenant_id.eq(tenant_id))
+        .filter(users::disabled_at.is_null())
         .first::<User>(connection);
     match query {
         Ok(result) => match bcrypt::verify(password, &result.password) {
@@ -103,24 +297,37 @@ pub fn is_login_valid(username: String, password: String, connection: &PgConnect
     }
 }
 
-#[post("/AddUser", format = "json", data = "<new_user>")]
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Hashes a password the way it's stored in the users table.
+pub fn hash_password(password: &str) -> bcrypt::BcryptResult<String> {
+    bcrypt::hash(password, bcrypt::DEFAULT_COST)
+}
+
+#[openapi]
+#[post("/Users", format = "json", data = "<new_user>")]
 pub fn add_user(
     conn: CameraServerDbConn,
+    tenant: RequestTenantId,
     new_user: Json<InsertableUser>,
 ) -> Result<Json<AuthentiationResult>, ApiError> {
-    if new_user.password.chars().count() < 8 {
+    if new_user.password.chars().count() < MIN_PASSWORD_LENGTH {
         return Err(ApiError {
             error: "Password must be at least 8 characters long",
+            code: "password_too_short",
             status: Status::UnprocessableEntity,
+            field: Some("password"),
         });
     }
 
     // Tries a DB request with the new username. If something comes back, return an error saying the username already exists
-    match get_by_username(new_user.username.clone(), &conn) {
+    match get_by_username(new_user.username.clone(), tenant.0, &conn) {
         Ok(_) => {
             return Err(ApiError {
                 error: "Username already exists",
+                code: "username_taken",
                 status: Status::Conflict,
+                field: None,
             });
         }
         Err(_) => {}
@@ -128,36 +335,43 @@ pub fn add_user(
 
     let new_user_insertable = InsertableUser {
         username: new_user.username.clone(),
-        password: bcrypt::hash(new_user.password.clone(), bcrypt::DEFAULT_COST).unwrap(),
+        password: hash_password(&new_user.password).unwrap(),
     };
 
-    // Inserts the new username/pass into the db. Returns a User object, which included the new UUID.
-    let new_user_inserted = insert(new_user_insertable, &conn).map_err(|error| {
-        println!("Failed to insert user into table! The error was: {}", error);
-        ApiError {
-            error: "Failed to insert user into table",
-            status: Status::InternalServerError,
-        }
-    })?;
+    // The user and their first token are inserted together, so a user is never left without a way to log in
+    let (new_user_inserted, new_user_token) = database::transaction(&conn, || {
+        // Inserts the new username/pass into the db. Returns a User object, which included the new UUID.
+        let new_user_inserted = insert(new_user_insertable, tenant.0, &conn).map_err(|error| {
+            error!("Failed to insert user into table! The error was: {}", error);
+            ApiError {
+                error: "Failed to insert user into table",
+                code: "add_user_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
 
-    // Inserts the new user into the token table in order to get a token. If this fails, try to undo what we've done.
-    let new_user_token = user_tokens::insert(
-        InsertableUserToken {
-            user_id: new_user_inserted.user_id,
-        },
-        &conn,
-    )
-    .map_err(|error| {
-        println!(
-            "Failed to get new token for user {} (id: {}). The error was {}",
-            new_user.username, new_user_inserted.user_id, error
-        );
-        delete(new_user_inserted.user_id, &conn)
-            .expect("Failed to delete user id while handling token insert error!");
-        ApiError {
-            error: "Failed to generate token",
-            status: Status::InternalServerError,
-        }
+        // Inserts the new user into the token table in order to get a token.
+        let new_user_token = user_tokens::insert(
+            InsertableUserToken {
+                user_id: new_user_inserted.user_id,
+            },
+            &conn,
+        )
+        .map_err(|error| {
+            error!(
+                "Failed to get new token for user {} (id: {}). The error was {}",
+                new_user.username, new_user_inserted.user_id, error
+            );
+            ApiError {
+                error: "Failed to generate token",
+                code: "generate_token_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?;
+
+        Ok((new_user_inserted, new_user_token))
     })?;
 
     Ok(Json(AuthentiationResult {
@@ -170,30 +384,37 @@ pub fn add_user(
 }
 
 /// Generates a new token for the given user. Actual login checking is handled in the UserLogin request guard.
+#[openapi]
 #[post("/Login", format = "json", data = "<user_login>")]
 pub fn login(
     conn: CameraServerDbConn,
+    tenant: RequestTenantId,
     user_login: Json<InsertableUser>,
 ) -> Result<Json<AuthentiationResult>, ApiError> {
     if !is_login_valid(
         user_login.username.clone(),
         user_login.password.clone(),
+        tenant.0,
         &conn,
     ) {
         return Err(ApiError {
             error: "Invalid username or password",
+            code: "invalid_credentials",
             status: Status::Unauthorized,
+            field: None,
         });
     }
 
-    let user = get_by_username(user_login.username.clone(), &conn).map_err(|error| {
-        println!(
+    let user = get_by_username(user_login.username.clone(), tenant.0, &conn).map_err(|error| {
+        error!(
             "Failed to get user id from username {}. The error was: {}",
             user_login.username, error
         );
         ApiError {
             error: "Failed to get user id from username",
+            code: "get_user_failed",
             status: Status::InternalServerError,
+            field: None,
         }
     })?;
 
@@ -204,13 +425,15 @@ pub fn login(
         &conn,
     )
     .map_err(|error| {
-        println!(
+        error!(
             "Failed to create token for user {}. The error was {}",
             user_login.username, error
         );
         ApiError {
             error: "Failed to create token",
+            code: "create_token_failed",
             status: Status::InternalServerError,
+            field: None,
         }
     })?;
 
@@ -0,0 +1,408 @@
+use crate::{
+    admin::AdminToken,
+    api_error::ApiError,
+    audit,
+    media_store::{media_store, MediaStore},
+    page::{offset_and_limit, Page},
+    soft_delete::{not_found_or_database_error, parse_user_id},
+    user::{self, User},
+    user_tokens, users_cameras, CameraServerDbConn,
+};
+
+use super::schema::{user_tokens as user_tokens_table, users};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A user as admins see them, without their password hash.
+#[derive(Serialize, JsonSchema)]
+pub struct AdminUser {
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// See GET /Admin/Plans.
+    pub plan_id: Option<i32>,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspension_reason: Option<String>,
+    /// See GET /Admin/Tenants.
+    pub tenant_id: i32,
+}
+
+impl AdminUser {
+    pub fn from_user(user: User) -> AdminUser {
+        AdminUser {
+            user_id: user.user_id,
+            username: user.username,
+            disabled_at: user.disabled_at,
+            deleted_at: user.deleted_at,
+            plan_id: user.plan_id,
+            suspended_at: user.suspended_at,
+            suspension_reason: user.suspension_reason,
+            tenant_id: user.tenant_id,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct AdminUsersCamera {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub name: String,
+    /// How many bytes of images the camera has stored, hot and cold. None if the store couldn't be read.
+    pub storage_used_bytes: Option<u64>,
+}
+
+/// Returned by GET /Admin/Users/<user_id>.
+#[derive(Serialize, JsonSchema)]
+pub struct AdminUserDetails {
+    pub user: AdminUser,
+    /// Every camera the user has access to.
+    pub cameras: Vec<AdminUsersCamera>,
+    /// The total across the user's cameras. Shared cameras count towards everyone they're shared with.
+    pub storage_used_bytes: u64,
+    /// How many tokens the user has, i.e. how many places they're logged in.
+    pub sessions: i64,
+}
+
+/// Returned by DELETE /Admin/Users/<user_id>/Tokens.
+#[derive(Serialize, JsonSchema)]
+pub struct TokensReset {
+    /// How many tokens were deleted.
+    pub logged_out: usize,
+}
+
+/// Query string for GET /Admin/Users.
+#[derive(FromForm, JsonSchema)]
+pub struct AdminUserQuery {
+    /// Only users whose username contains this, ignoring case.
+    pub q: Option<String>,
+    /// Only disabled users, or only users who aren't.
+    pub disabled: Option<bool>,
+    /// Include deleted users that haven't been purged yet. Defaults to false.
+    pub deleted: Option<bool>,
+    /// Only users in this tenant.
+    pub tenant_id: Option<i32>,
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get users! The error was {}", error);
+    ApiError {
+        error: "Failed to get users",
+        code: "get_users_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+/// Escapes LIKE's wildcards, so a search for "a_b" only matches "a_b".
+fn like_pattern(text: &str) -> String {
+    format!(
+        "%{}%",
+        text.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// Lists and searches users, ordered by username. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Users?<query..>")]
+pub fn list_users(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    query: Form<AdminUserQuery>,
+) -> Result<Json<Page<AdminUser>>, ApiError> {
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+
+    let filtered = || {
+        let mut filtered = users::table.into_boxed();
+
+        if !query.deleted.unwrap_or(false) {
+            filtered = filtered.filter(users::deleted_at.is_null());
+        }
+        if let Some(text) = query.q.as_ref().filter(|text| text.trim().len() > 0) {
+            filtered = filtered.filter(users::username.ilike(like_pattern(text.trim())));
+        }
+        if let Some(tenant_id) = query.tenant_id {
+            filtered = filtered.filter(users::tenant_id.eq(tenant_id));
+        }
+        match query.disabled {
+            Some(true) => filtered = filtered.filter(users::disabled_at.is_not_null()),
+            Some(false) => filtered = filtered.filter(users::disabled_at.is_null()),
+            None => {}
+        }
+
+        filtered
+    };
+
+    let items = filtered()
+        .order((users::username, users::user_id))
+        .limit(limit)
+        .offset(offset)
+        .load::<User>(&*conn)
+        .map_err(database_error)?
+        .into_iter()
+        .map(AdminUser::from_user)
+        .collect();
+
+    let total = filtered()
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(Page::new(items, offset, total)))
+}
+
+/// The user, their cameras and how much storage those cameras use. Deleted users can be seen until they're purged.
+/// Reading the storage used walks every camera's images, so this can be slow for users with a lot of footage.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[get("/Admin/Users/<user_id>")]
+pub fn get_user(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<AdminUserDetails>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    let found = user::get_including_deleted(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("get_user_failed", "Failed to get user"),
+        )
+    })?;
+
+    let cameras = users_cameras::get_users_cameras(user_id, &conn)
+        .map_err(database_error)?
+        .into_iter()
+        .map(|camera| AdminUsersCamera {
+            storage_used_bytes: media_store()
+                .storage_used(&camera.camera_id)
+                .map_err(|error| {
+                    error!(
+                        "Failed to get storage used by camera {}! The error was {}",
+                        camera.camera_id, error
+                    )
+                })
+                .ok(),
+            camera_id: camera.camera_id,
+            name: camera.name,
+        })
+        .collect::<Vec<AdminUsersCamera>>();
+
+    let sessions = user_tokens_table::table
+        .filter(user_tokens_table::user_id.eq(user_id))
+        .count()
+        .get_result::<i64>(&*conn)
+        .map_err(database_error)?;
+
+    Ok(Json(AdminUserDetails {
+        user: AdminUser::from_user(found),
+        storage_used_bytes: cameras
+            .iter()
+            .filter_map(|camera| camera.storage_used_bytes)
+            .sum(),
+        cameras,
+        sessions,
+    }))
+}
+
+/// Stops the user logging in and logs them out everywhere. Their cameras keep uploading, and they can be enabled
+/// again with POST /Admin/Users/<user_id>/Enable. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Users/<user_id>/Disable")]
+pub fn disable_user(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<AdminUser>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    if user_id == admin_token.user_id {
+        return Err(ApiError {
+            error: "Admins can't disable themselves",
+            code: "cant_disable_self",
+            status: Status::UnprocessableEntity,
+            field: None,
+        });
+    }
+
+    if let Ok(before) = user::get(user_id, &conn) {
+        audit::record_before(&AdminUser::from_user(before));
+    }
+
+    user::disable(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("disable_user_failed", "Failed to disable user"),
+        )
+    })?;
+
+    user::get(user_id, &conn)
+        .map(|user| {
+            info!("User {} disabled by {}", user_id, admin_token.user_id);
+            let user = AdminUser::from_user(user);
+            audit::record_after(&user);
+            Json(user)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("disable_user_failed", "Failed to disable user"),
+            )
+        })
+}
+
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Users/<user_id>/Enable")]
+pub fn enable_user(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<AdminUser>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    if let Ok(before) = user::get(user_id, &conn) {
+        audit::record_before(&AdminUser::from_user(before));
+    }
+
+    user::enable(user_id, &conn)
+        .map(|user| {
+            let user = AdminUser::from_user(user);
+            audit::record_after(&user);
+            Json(user)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("enable_user_failed", "Failed to enable user"),
+            )
+        })
+}
+
+/// Sent with POST /Admin/Users/<user_id>/Suspend.
+#[derive(Deserialize, JsonSchema)]
+pub struct Suspension {
+    /// Why, for other admins. The user isn't shown it.
+    pub reason: String,
+}
+
+/// Stops the user and their cameras doing anything, e.g. for abuse, until they're reinstated with
+/// POST /Admin/Users/<user_id>/Reinstate. Unlike disabling, they stay logged in and their cameras are turned away
+/// too, and both get a 403 with the code account_suspended. Nothing is deleted. Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post(
+    "/Admin/Users/<user_id>/Suspend",
+    format = "json",
+    data = "<suspension>"
+)]
+pub fn suspend_user(
+    conn: CameraServerDbConn,
+    admin_token: AdminToken,
+    user_id: String,
+    suspension: Json<Suspension>,
+) -> Result<Json<AdminUser>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    if user_id == admin_token.user_id {
+        return Err(ApiError {
+            error: "Admins can't suspend themselves",
+            code: "cant_suspend_self",
+            status: Status::UnprocessableEntity,
+            field: None,
+        });
+    }
+
+    if let Ok(before) = user::get(user_id, &conn) {
+        audit::record_before(&AdminUser::from_user(before));
+    }
+
+    user::suspend(user_id, suspension.into_inner().reason, &conn)
+        .map(|user| {
+            info!("User {} suspended by {}", user_id, admin_token.user_id);
+            let user = AdminUser::from_user(user);
+            audit::record_after(&user);
+            Json(user)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("suspend_user_failed", "Failed to suspend user"),
+            )
+        })
+}
+
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[post("/Admin/Users/<user_id>/Reinstate")]
+pub fn reinstate_user(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<AdminUser>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    if let Ok(before) = user::get(user_id, &conn) {
+        audit::record_before(&AdminUser::from_user(before));
+    }
+
+    user::reinstate(user_id, &conn)
+        .map(|user| {
+            let user = AdminUser::from_user(user);
+            audit::record_after(&user);
+            Json(user)
+        })
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("reinstate_user_failed", "Failed to reinstate user"),
+            )
+        })
+}
+
+/// Logs the user out everywhere, e.g. after a lost phone. They can log in again straight away.
+/// Only for users in ADMIN_USER_IDS.
+#[openapi]
+#[delete("/Admin/Users/<user_id>/Tokens")]
+pub fn reset_user_tokens(
+    conn: CameraServerDbConn,
+    _admin_token: AdminToken,
+    user_id: String,
+) -> Result<Json<TokensReset>, ApiError> {
+    let user_id = parse_user_id(&user_id)?;
+
+    user::get_including_deleted(user_id, &conn).map_err(|error| {
+        not_found_or_database_error(
+            error,
+            ("user_not_found", "User not found"),
+            ("reset_tokens_failed", "Failed to reset tokens"),
+        )
+    })?;
+
+    user_tokens::delete_users_tokens(user_id, &conn)
+        .map(|logged_out| Json(TokensReset { logged_out }))
+        .map_err(|error| {
+            not_found_or_database_error(
+                error,
+                ("user_not_found", "User not found"),
+                ("reset_tokens_failed", "Failed to reset tokens"),
+            )
+        })
+}
@@ -1,4 +1,11 @@
-use crate::{enums::token_error::TokenError, CameraServerDbConn};
+use crate::{
+    audit,
+    cache::{self, cache},
+    database,
+    enums::token_error::{TokenError, TokenRejection},
+    impersonation::{self, ImpersonatedBy},
+    request_id, tenant, user, CameraServerDbConn,
+};
 
 use super::schema::user_tokens;
 
@@ -6,11 +13,12 @@ use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::{self};
 use rocket::{
-    http::Status,
+    http::{Method, Status},
     request::{self, FromRequest},
     Outcome, Request,
 };
 use serde::{Deserialize, Serialize};
+use std::ops::Deref;
 
 #[derive(Queryable, AsChangeset, Deserialize, Serialize, Debug)]
 #[table_name = "user_tokens"]
@@ -33,13 +41,32 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserToken {
                         return Outcome::Failure((Status::BadRequest, TokenError::ParseError))
                     }
                 };
-                let connection = CameraServerDbConn::from_request(&request).unwrap();
-                match get(parsed_token, &connection) {
-                    Ok(user_token) => return Outcome::Success(user_token),
+                let reads_only = matches!(
+                    request.method(),
+                    Method::Get | Method::Head | Method::Options
+                );
 
-                    Err(_) => {
-                        return Outcome::Failure((Status::Unauthorized, TokenError::NotFound))
+                match authorise_user_token(
+                    parsed_token,
+                    tenant::request_tenant(request),
+                    reads_only,
+                    || {
+                        CameraServerDbConn::from_request(&request)
+                            .expect("Failed to get DB connection on UserToken request guard")
+                    },
+                ) {
+                    Ok(authorised) => {
+                        if let Some(admin_id) = authorised.impersonated_by {
+                            request.local_cache(|| ImpersonatedBy(Some(admin_id)));
+                        }
+                        request_id::record_user(authorised.user_id);
+                        audit::record_user(authorised.user_id);
+                        Outcome::Success(UserToken {
+                            user_token: parsed_token,
+                            user_id: authorised.user_id,
+                        })
                     }
+                    Err(rejection) => rejection.fail(request),
                 }
             }
             // Token does not exist
@@ -48,6 +75,89 @@ impl<'a, 'r> FromRequest<'a, 'r> for UserToken {
     }
 }
 
+/// Who a user token is for, once it's been through authorise_user_token().
+pub struct AuthorisedUser {
+    pub user_id: uuid::Uuid,
+    /// The admin impersonating the user, if it's an impersonation token.
+    pub impersonated_by: Option<uuid::Uuid>,
+}
+
+/// The checks the UserToken guard makes, for every transport that takes user tokens (HTTP, gRPC and the realtime
+/// server): that the token exists, that it's used in the user's own tenant, that impersonation tokens are only used
+/// to read, and that the user isn't suspended. `tenant_id` is the tenant the client is for, see
+/// tenant::resolve_tenant(). Only connects to the database if what it needs isn't cached.
+pub fn authorise_user_token<C: Deref<Target = PgConnection>>(
+    user_token: uuid::Uuid,
+    tenant_id: Result<i32, Status>,
+    reads_only: bool,
+    connect: impl Fn() -> C,
+) -> Result<AuthorisedUser, TokenRejection> {
+    let (user_id, impersonation) = lookup_with(user_token, &connect)
+        .map_err(|_| TokenRejection::new(Status::Unauthorized, TokenError::NotFound))?;
+
+    tenant::check_user(user_id, tenant_id, || Ok(connect()))?;
+
+    // Impersonation tokens can only read, so admins can't change anything as the user
+    if impersonation.is_some() && !reads_only {
+        return Err(TokenRejection::new(Status::Forbidden, TokenError::ReadOnly));
+    }
+
+    match user::is_suspended(user_id, &connect) {
+        Ok(false) => {}
+        Ok(true) => {
+            return Err(TokenRejection::because(
+                Status::Forbidden,
+                TokenError::Suspended,
+                "account_suspended",
+                "Account suspended",
+            ))
+        }
+        Err(error) => {
+            error!(
+                "Failed to check if user {} is suspended! The error was {}",
+                user_id, error
+            );
+            return Err(TokenRejection::new(
+                Status::ServiceUnavailable,
+                TokenError::NotFound,
+            ));
+        }
+    }
+
+    Ok(AuthorisedUser {
+        user_id,
+        impersonated_by: impersonation.flatten(),
+    })
+}
+
+/// Returns who the token is for, and the admin impersonating them if it's an impersonation token. Only connects to
+/// the database if the token isn't cached.
+pub fn lookup(
+    user_token: uuid::Uuid,
+    request: &Request,
+) -> QueryResult<(uuid::Uuid, Option<Option<uuid::Uuid>>)> {
+    lookup_with(user_token, || {
+        CameraServerDbConn::from_request(&request).unwrap()
+    })
+}
+
+/// Like lookup(), connecting with `connect`. Impersonations have an admin_id of None once their admin is deleted,
+/// but those aren't active, so aren't returned.
+fn lookup_with<C: Deref<Target = PgConnection>>(
+    user_token: uuid::Uuid,
+    connect: impl Fn() -> C,
+) -> QueryResult<(uuid::Uuid, Option<Option<uuid::Uuid>>)> {
+    cache::cached_uuid(&cache::user_token_key(user_token), || {
+        get(user_token, &connect()).map(|user_token| user_token.user_id)
+    })
+    .map(|user_id| (user_id, None))
+    // Impersonation tokens aren't cached, so they stop working as soon as they expire or are revoked
+    .or_else(|_| {
+        impersonation::get_active(user_token, &connect())
+            .map(|impersonation| (impersonation.user_id, Some(impersonation.admin_id)))
+    })
+}
+
 #[derive(Insertable, Deserialize, Serialize)]
 #[table_name = "user_tokens"]
 pub struct InsertableUserToken {
@@ -67,9 +177,11 @@ pub fn all(connection: &PgConnection) -> QueryResult<Vec<UserToken>> {
 }
 
 pub fn get(user_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<UserToken> {
-    user_tokens::table
-        .find(user_token)
-        .get_result::<UserToken>(connection)
+    database::timed(
+        "user_token_get",
+        user_tokens::table.find(user_token),
+        |query| query.get_result::<UserToken>(connection),
+    )
 }
 
 pub fn insert(
@@ -86,11 +198,29 @@ pub fn update(
     user_token: UserToken,
     connection: &PgConnection,
 ) -> QueryResult<UserToken> {
-    diesel::update(user_tokens::table.find(user_id))
+    let updated = diesel::update(user_tokens::table.find(user_id))
         .set(&user_token)
-        .get_result(connection)
+        .get_result(connection);
+    cache().delete(&cache::user_token_key(user_id));
+    updated
 }
 
 pub fn delete(user_token: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
-    diesel::delete(user_tokens::table.find(user_token)).execute(connection)
+    let deleted = diesel::delete(user_tokens::table.find(user_token)).execute(connection);
+    cache().delete(&cache::user_token_key(user_token));
+    deleted
+}
+
+/// Deletes every token the user has, logging them out everywhere.
+pub fn delete_users_tokens(user_id: uuid::Uuid, connection: &PgConnection) -> QueryResult<usize> {
+    let deleted_tokens =
+        diesel::delete(user_tokens::table.filter(user_tokens::user_id.eq(user_id)))
+            .returning(user_tokens::user_token)
+            .get_results::<uuid::Uuid>(connection)?;
+
+    for user_token in &deleted_tokens {
+        cache().delete(&cache::user_token_key(*user_token));
+    }
+
+    Ok(deleted_tokens.len())
 }
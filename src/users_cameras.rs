@@ -1,19 +1,55 @@
-use super::schema::{cameras, users_cameras};
-use super::CameraServerDbConn;
-use crate::{api_error::ApiError, camera::Camera, user_tokens};
+use super::schema::{cameras, users, users_cameras};
+use crate::{
+    api_error::ApiError,
+    cache::{self, cache},
+    camera::{self, Camera, CameraId},
+    database::{self, ReadDbConn},
+    fields::{parse_fields, Sparse},
+    page::{offset_and_limit, parse_updated_since, Page},
+    soft_delete::parse_user_id,
+    tenant, user, user_tokens, CameraServerDbConn,
+};
+use chrono::{DateTime, Utc};
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
 use diesel::prelude::*;
+use diesel::sql_types::Bool;
 use diesel::{self};
-use rocket::get;
 use rocket::http::Status;
+use rocket::request::Form;
+use rocket::{get, put};
 use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
 #[table_name = "users_cameras"]
 pub struct UsersCamera {
     pub users_cameras_id: i32,
+    #[schemars(with = "String")]
     pub camera_id: uuid::Uuid,
+    #[schemars(with = "String")]
     pub user_id: uuid::Uuid,
+    /// Set when the camera or the user is deleted, see camera::soft_delete() and user::soft_delete().
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Whether the user can talk through the camera's speaker, see talk. The camera's owner always can.
+    pub can_talk: bool,
+}
+
+/// The user owns the camera, see get_owners().
+pub const OWNED: &str = "owned";
+/// The camera was shared with the user by its owner.
+pub const SHARED: &str = "shared";
+
+/// A camera in GET /Cameras, with how the user came to have it.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct ListedCamera {
+    #[serde(flatten)]
+    pub camera: Camera,
+    /// owned or shared.
+    pub relationship: String,
 }
 
 #[derive(Insertable, Deserialize, Serialize)]
@@ -23,8 +59,31 @@ pub struct InsertableUsersCamera {
     pub user_id: uuid::Uuid,
 }
 
+pub type NotDeleted =
+    diesel::dsl::Filter<users_cameras::table, diesel::dsl::IsNull<users_cameras::deleted_at>>;
+
+/// Access that hasn't been deleted along with its camera or user. Queries should start from this rather than
+/// users_cameras::table, or filter on users_cameras::deleted_at when joining it.
+pub fn not_deleted() -> NotDeleted {
+    users_cameras::table.filter(users_cameras::deleted_at.is_null())
+}
+
+/// Only access that's in the tenant of the camera's owner, see get_owners(), so cameras and what they've recorded
+/// stay in their tenant even if someone in another was given access to one, e.g. by hand in the database.
+/// For queries on users_cameras, or that join it, see in_owners_tenant().
+pub const IN_OWNERS_TENANT: &str = "(SELECT owner_user.tenant_id FROM users_cameras owner \
+     INNER JOIN users owner_user ON owner_user.user_id = owner.user_id \
+     WHERE owner.camera_id = users_cameras.camera_id AND owner.deleted_at IS NULL \
+     ORDER BY owner.users_cameras_id LIMIT 1) = \
+     (SELECT member.tenant_id FROM users member WHERE member.user_id = users_cameras.user_id)";
+
+/// IN_OWNERS_TENANT, to filter with.
+pub fn in_owners_tenant() -> SqlLiteral<Bool> {
+    sql::<Bool>(IN_OWNERS_TENANT)
+}
+
 pub fn all(connection: &PgConnection) -> QueryResult<Vec<UsersCamera>> {
-    users_cameras::table.load::<UsersCamera>(&*connection)
+    not_deleted().load::<UsersCamera>(&*connection)
 }
 
 pub fn get(users_cameras_id: i32, connection: &PgConnection) -> QueryResult<UsersCamera> {
@@ -37,9 +96,12 @@ pub fn insert(
     users_camera: InsertableUsersCamera,
     connection: &PgConnection,
 ) -> QueryResult<UsersCamera> {
-    diesel::insert_into(users_cameras::table)
+    let user_id = users_camera.user_id;
+    let inserted = diesel::insert_into(users_cameras::table)
         .values(users_camera)
-        .get_result(connection)
+        .get_result(connection);
+    cache().delete(&cache::camera_list_key(user_id));
+    inserted
 }
 
 pub fn update(
@@ -47,52 +109,297 @@ pub fn update(
     users_camera: UsersCamera,
     connection: &PgConnection,
 ) -> QueryResult<UsersCamera> {
-    diesel::update(users_cameras::table.find(users_cameras_id))
+    let old_users_camera = get(users_cameras_id, connection);
+    let updated = diesel::update(users_cameras::table.find(users_cameras_id))
         .set(&users_camera)
-        .get_result(connection)
+        .get_result(connection);
+    forget_access(old_users_camera);
+    cache().delete(&cache::camera_list_key(users_camera.user_id));
+    updated
 }
 
 pub fn delete(users_cameras_id: i32, connection: &PgConnection) -> QueryResult<usize> {
-    diesel::delete(users_cameras::table.find(users_cameras_id)).execute(connection)
+    let old_users_camera = get(users_cameras_id, connection);
+    let deleted = diesel::delete(users_cameras::table.find(users_cameras_id)).execute(connection);
+    // Removing the owner's access makes whoever has had it longest the owner, which changes their list too
+    if let Ok(old_users_camera) = &old_users_camera {
+        forget_camera_lists(&[old_users_camera.camera_id], connection);
+    }
+    forget_access(old_users_camera);
+    deleted
+}
+
+/// Marks who has access to the camera as deleted, at the same time as the camera so undelete_cameras_access() can tell
+/// it apart from access deleted with a user.
+pub fn soft_delete_cameras_access(
+    camera_id: uuid::Uuid,
+    deleted_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(not_deleted().filter(users_cameras::camera_id.eq(camera_id)))
+        .set(users_cameras::deleted_at.eq(deleted_at))
+        .execute(connection)
+}
+
+/// Restores the access that was deleted with the camera at `deleted_at`, except for users who are still deleted.
+pub fn undelete_cameras_access(
+    camera_id: uuid::Uuid,
+    deleted_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(
+        users_cameras::table
+            .filter(users_cameras::camera_id.eq(camera_id))
+            .filter(users_cameras::deleted_at.eq(deleted_at))
+            .filter(users_cameras::user_id.eq_any(user::not_deleted().select(users::user_id))),
+    )
+    .set(users_cameras::deleted_at.eq(None::<DateTime<Utc>>))
+    .execute(connection)
+}
+
+/// Like soft_delete_cameras_access(), for everything the user has access to.
+pub fn soft_delete_users_access(
+    user_id: uuid::Uuid,
+    deleted_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    diesel::update(not_deleted().filter(users_cameras::user_id.eq(user_id)))
+        .set(users_cameras::deleted_at.eq(deleted_at))
+        .returning(users_cameras::camera_id)
+        .get_results(connection)
+}
+
+/// Like undelete_cameras_access(), except for cameras that are still deleted.
+pub fn undelete_users_access(
+    user_id: uuid::Uuid,
+    deleted_at: DateTime<Utc>,
+    connection: &PgConnection,
+) -> QueryResult<usize> {
+    let restored = diesel::update(
+        users_cameras::table
+            .filter(users_cameras::user_id.eq(user_id))
+            .filter(users_cameras::deleted_at.eq(deleted_at))
+            .filter(
+                users_cameras::camera_id.eq_any(camera::not_deleted().select(cameras::camera_id)),
+            ),
+    )
+    .set(users_cameras::deleted_at.eq(None::<DateTime<Utc>>))
+    .execute(connection);
+    cache().delete(&cache::camera_list_key(user_id));
+    restored
+}
+
+/// Takes away everyone's access to the camera, including access deleted with a user that could otherwise be
+/// restored, and gives it to `user_id` alone.
+pub fn reassign(
+    camera_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<UsersCamera> {
+    let (previous_user_ids, users_camera) = connection.transaction(|| {
+        let previous_user_ids =
+            diesel::delete(users_cameras::table.filter(users_cameras::camera_id.eq(camera_id)))
+                .returning(users_cameras::user_id)
+                .get_results::<uuid::Uuid>(connection)?;
+        let users_camera = insert(InsertableUsersCamera { camera_id, user_id }, connection)?;
+        Ok((previous_user_ids, users_camera))
+    })?;
+
+    for previous_user_id in previous_user_ids {
+        cache().delete(&cache::camera_access_key(previous_user_id, camera_id));
+        cache().delete(&cache::camera_list_key(previous_user_id));
+    }
+    cache().delete(&cache::camera_list_key(user_id));
+    tenant::forget_camera_tenant(camera_id);
+
+    Ok(users_camera)
+}
+
+/// Cameras that haven't been deleted, but that nobody has access to, not even a deleted user who could be brought
+/// back. They're left behind when access is removed by hand, and can only be reached with their camera token.
+pub fn get_orphaned_cameras(connection: &PgConnection) -> QueryResult<Vec<Camera>> {
+    camera::not_deleted()
+        .filter(cameras::camera_id.ne_all(users_cameras::table.select(users_cameras::camera_id)))
+        .order(cameras::created_at)
+        .load(connection)
+}
+
+/// Removes a cached access check, and the user's cached camera list, once the access it allowed has changed.
+fn forget_access(users_camera: QueryResult<UsersCamera>) {
+    if let Ok(users_camera) = users_camera {
+        cache().delete(&cache::camera_access_key(
+            users_camera.user_id,
+            users_camera.camera_id,
+        ));
+        cache().delete(&cache::camera_list_key(users_camera.user_id));
+    }
+}
+
+/// Removes the cached camera lists of everyone with access to the cameras, after the cameras themselves change.
+/// If who has access can't be loaded, the lists are left to expire.
+pub fn forget_camera_lists(camera_ids: &[uuid::Uuid], connection: &PgConnection) {
+    let user_ids = not_deleted()
+        .filter(users_cameras::camera_id.eq_any(camera_ids))
+        .select(users_cameras::user_id)
+        .distinct()
+        .load::<uuid::Uuid>(connection);
+
+    match user_ids {
+        Ok(user_ids) => {
+            for user_id in user_ids {
+                cache().delete(&cache::camera_list_key(user_id));
+            }
+        }
+        Err(error) => warn!(
+            "Failed to get who has access to cameras to clear their camera lists! The error was {}",
+            error
+        ),
+    }
 }
 
 pub fn get_users_cameras(
     user_id: uuid::Uuid,
     connection: &PgConnection,
 ) -> QueryResult<Vec<Camera>> {
-    users_cameras::table
-        .filter(users_cameras::user_id.eq(user_id))
-        .inner_join(cameras::table.on(cameras::camera_id.eq(users_cameras::camera_id)))
-        .select((cameras::camera_id, cameras::name))
+    database::timed(
+        "users_cameras_list",
+        not_deleted()
+            .filter(users_cameras::user_id.eq(user_id))
+            .filter(in_owners_tenant())
+            .inner_join(cameras::table.on(cameras::camera_id.eq(users_cameras::camera_id)))
+            .select(cameras::all_columns),
+        |query| query.load(connection),
+    )
+}
+
+/// Like get_users_cameras(), with whether the user owns each camera, see get_owners(), worked out in the same query.
+pub fn get_users_cameras_with_relationship(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<ListedCamera>> {
+    database::timed(
+        "users_cameras_list_with_relationship",
+        not_deleted()
+            .filter(users_cameras::user_id.eq(user_id))
+            .filter(in_owners_tenant())
+            .inner_join(cameras::table.on(cameras::camera_id.eq(users_cameras::camera_id)))
+            .select((
+                cameras::all_columns,
+                sql::<Bool>(
+                    "users_cameras.users_cameras_id = (SELECT MIN(owner.users_cameras_id) \
+                     FROM users_cameras owner WHERE owner.camera_id = users_cameras.camera_id \
+                     AND owner.deleted_at IS NULL)",
+                ),
+            )),
+        |query| query.load::<(Camera, bool)>(connection),
+    )
+    .map(|cameras| {
+        cameras
+            .into_iter()
+            .map(|(camera, owned)| ListedCamera {
+                camera,
+                relationship: String::from(if owned { OWNED } else { SHARED }),
+            })
+            .collect()
+    })
+}
+
+/// Like get_users_cameras_with_relationship(), but kept in the cache for camera_list_ttl(), as apps poll
+/// ListCameras much more often than cameras change. Changing a camera or who has access to it clears the lists it's in.
+pub fn get_users_cameras_cached(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<ListedCamera>> {
+    let key = cache::camera_list_key(user_id);
+    let ttl = cache::camera_list_ttl();
+
+    if ttl.as_secs() > 0 {
+        if let Some(cameras) = cache()
+            .get(&key)
+            .and_then(|cameras| serde_json::from_str::<Vec<ListedCamera>>(&cameras).ok())
+        {
+            return Ok(cameras);
+        }
+    }
+
+    let cameras = get_users_cameras_with_relationship(user_id, connection)?;
+    if ttl.as_secs() > 0 {
+        if let Ok(serialized) = serde_json::to_string(&cameras) {
+            cache().set(&key, &serialized, ttl);
+        }
+    }
+
+    Ok(cameras)
+}
+
+/// Returns the IDs of every user who has access to the camera.
+pub fn get_cameras_users(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    not_deleted()
+        .filter(users_cameras::camera_id.eq(camera_id))
+        .filter(in_owners_tenant())
+        .select(users_cameras::user_id)
+        .distinct()
         .load(connection)
 }
 
-/// Checks if the user in user_token has access to the camera with an ID of camera_id_string.
+/// Each camera's owner, the user it was first added for that still has it.
+pub fn get_owners(
+    camera_ids: Vec<uuid::Uuid>,
+    connection: &PgConnection,
+) -> QueryResult<HashMap<uuid::Uuid, uuid::Uuid>> {
+    not_deleted()
+        .filter(users_cameras::camera_id.eq_any(camera_ids))
+        .order((users_cameras::camera_id, users_cameras::users_cameras_id))
+        .distinct_on(users_cameras::camera_id)
+        .select((users_cameras::camera_id, users_cameras::user_id))
+        .load::<(uuid::Uuid, uuid::Uuid)>(connection)
+        .map(|owners| owners.into_iter().collect())
+}
+
+/// Returns the IDs of the cameras the user owns, see get_owners(), rather than has been given access to.
+pub fn get_owned_camera_ids(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<uuid::Uuid>> {
+    let camera_ids = not_deleted()
+        .filter(users_cameras::user_id.eq(user_id))
+        .select(users_cameras::camera_id)
+        .load::<uuid::Uuid>(connection)?;
+
+    Ok(get_owners(camera_ids, connection)?
+        .into_iter()
+        .filter(|(_, owner_id)| *owner_id == user_id)
+        .map(|(camera_id, _)| camera_id)
+        .collect())
+}
+
+/// Checks if the user in user_token has access to the camera.
 /// Returns an empty Ok() if access is allowed, returns ApiError if the user isn't allowed or if something else goes wrong.
+/// Only allowed access is cached, so a camera the user has just been given can be used straight away.
 pub fn check_if_user_has_access_to_camera(
-    conn: &CameraServerDbConn,
+    conn: &PgConnection,
     user_token: &user_tokens::UserToken,
-    camera_id_string: &String,
+    camera_id: uuid::Uuid,
 ) -> Result<(), ApiError> {
-    let camera_id = uuid::Uuid::parse_str(camera_id_string).map_err(|error| {
-        println!(
-            "Failed to parse camera id into UUID: Input was {}, error was {}",
-            camera_id_string, error
-        );
-        ApiError {
-            error: "Failed to parse camera ID string",
-            status: Status::UnprocessableEntity,
-        }
-    })?;
+    let access_key = cache::camera_access_key(user_token.user_id, camera_id);
+
+    if cache().get(&access_key).is_some() {
+        return Ok(());
+    }
 
     let users_cameras_list = get_users_cameras(user_token.user_id, conn).map_err(|error| {
-        println!(
+        error!(
             "Failed to get list of user's cameras! The error was {}",
             error
         );
         ApiError {
             error: "Failed to get list of owned cameras",
+            code: "get_owned_cameras_failed",
             status: Status::InternalServerError,
+            field: None,
         }
     })?;
 
@@ -103,29 +410,184 @@ pub fn check_if_user_has_access_to_camera(
     {
         return Err(ApiError {
             error: "User does not have access to camera",
+            code: "no_camera_access",
             status: Status::Unauthorized,
+            field: None,
         });
     }
 
+    cache().set(&access_key, "1", cache::cache_ttl());
     Ok(())
 }
 
-/// Returns a list of camera IDs for a user's cameras
-#[get("/ListCameras")]
-pub fn list_cameras(
+/// Like check_if_user_has_access_to_camera(), but only lets the camera's owner through, see get_owners().
+pub fn check_if_user_owns_camera(
+    conn: &PgConnection,
+    user_token: &user_tokens::UserToken,
+    camera_id: uuid::Uuid,
+) -> Result<(), ApiError> {
+    check_if_user_has_access_to_camera(conn, user_token, camera_id)?;
+
+    let owner_id = get_owners(vec![camera_id], conn)
+        .map_err(|error| {
+            error!(
+                "Failed to get the owner of camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to get the camera's owner",
+                code: "get_camera_owner_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })?
+        .remove(&camera_id);
+
+    if owner_id != Some(user_token.user_id) {
+        return Err(ApiError {
+            error: "Only the camera's owner can do that",
+            code: "not_camera_owner",
+            status: Status::Forbidden,
+            field: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Whether the user can talk through the camera, because they own it or it was shared with them with can_talk.
+pub fn can_talk(
+    user_id: uuid::Uuid,
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<bool> {
+    if get_owners(vec![camera_id], connection)?.get(&camera_id) == Some(&user_id) {
+        return Ok(true);
+    }
+
+    diesel::select(diesel::dsl::exists(
+        not_deleted()
+            .filter(users_cameras::user_id.eq(user_id))
+            .filter(users_cameras::camera_id.eq(camera_id))
+            .filter(users_cameras::can_talk.eq(true)),
+    ))
+    .get_result(connection)
+}
+
+/// Sent with PUT /Cameras/<camera_id>/Users/<user_id>/Capabilities.
+#[derive(Deserialize, JsonSchema)]
+pub struct Capabilities {
+    /// Whether the user can talk through the camera's speaker.
+    pub can_talk: bool,
+}
+
+/// Changes what a user the camera is shared with can do besides watching. Only for the camera's owner, who can
+/// always do everything.
+#[openapi]
+#[put(
+    "/Cameras/<camera_id>/Users/<user_id>/Capabilities",
+    format = "json",
+    data = "<capabilities>"
+)]
+pub fn set_capabilities(
     conn: CameraServerDbConn,
     user_token: user_tokens::UserToken,
-) -> Result<Json<Vec<Camera>>, ApiError> {
-    let camera_list = get_users_cameras(user_token.user_id, &conn).map_err(|error| {
-        println!(
+    camera_id: CameraId,
+    user_id: String,
+    capabilities: Json<Capabilities>,
+) -> Result<Json<UsersCamera>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    let user_id = parse_user_id(&user_id)?;
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    diesel::update(
+        not_deleted()
+            .filter(users_cameras::camera_id.eq(camera_id))
+            .filter(users_cameras::user_id.eq(user_id)),
+    )
+    .set(users_cameras::can_talk.eq(capabilities.can_talk))
+    .get_result::<UsersCamera>(&*conn)
+    .optional()
+    .map_err(|error| {
+        error!(
+            "Failed to update user {}'s capabilities for camera {}! The error was {}",
+            user_id, camera_id, error
+        );
+        ApiError {
+            error: "Failed to update capabilities",
+            code: "update_capabilities_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?
+    .map(Json)
+    .ok_or(ApiError {
+        error: "The camera isn't shared with that user",
+        code: "share_not_found",
+        status: Status::NotFound,
+        field: None,
+    })
+}
+
+/// Query string for GET /Cameras.
+#[derive(FromForm, JsonSchema)]
+pub struct CameraQuery {
+    pub cursor: Option<String>,
+    pub page_size: Option<i64>,
+    /// Comma separated fields to return for each camera, e.g. camera_id,name. Defaults to every field.
+    pub fields: Option<String>,
+    /// Only cameras that have changed since this RFC 3339 timestamp, for syncing incrementally.
+    pub updated_since: Option<String>,
+    /// owned for only the user's own cameras, or shared for only the ones shared with them.
+    pub relationship: Option<String>,
+}
+
+/// Returns a page of the user's cameras, each saying whether the user owns it or it was shared with them
+#[openapi]
+#[get("/Cameras?<query..>")]
+pub fn list_cameras(
+    conn: ReadDbConn,
+    user_token: user_tokens::UserToken,
+    query: Form<CameraQuery>,
+) -> Result<Json<Page<Sparse<ListedCamera>>>, ApiError> {
+    let (offset, limit) = offset_and_limit(&query.cursor, None, query.page_size)?;
+    let fields = parse_fields(&query.fields)?;
+    let updated_since = parse_updated_since(&query.updated_since)?;
+
+    let relationship = query.relationship.as_deref().map(str::trim);
+    if let Some(relationship) = relationship {
+        if relationship != OWNED && relationship != SHARED {
+            return Err(ApiError {
+                error: "Relationship must be owned or shared",
+                code: "invalid_relationship",
+                status: Status::UnprocessableEntity,
+                field: Some("relationship"),
+            });
+        }
+    }
+
+    let camera_list = get_users_cameras_cached(user_token.user_id, &conn).map_err(|error| {
+        error!(
             "Failed to get user's cameras for user ID {}. The error was {}",
             user_token.user_id, error
         );
         ApiError {
             error: "Database failed to get list of cameras",
+            code: "get_cameras_failed",
             status: Status::InternalServerError,
+            field: None,
         }
     })?;
 
-    Ok(Json(camera_list))
+    let camera_list = camera_list
+        .into_iter()
+        .filter(|listed| updated_since.map_or(true, |since| listed.camera.updated_at > since))
+        .filter(|listed| {
+            relationship.map_or(true, |relationship| listed.relationship == relationship)
+        })
+        .collect();
+
+    Ok(Json(
+        Page::from_vec(camera_list, offset, limit).sparse(&fields),
+    ))
 }
@@ -0,0 +1,675 @@
+use crate::{
+    api_error::ApiError,
+    api_version::API_PREFIX,
+    camera::{latest_image_id, open_image, Camera, CameraId},
+    federation,
+    oauth::{self, OAuthLink, OAuthToken, ACCESS_TOKEN_SECONDS, ALEXA, GOOGLE_ASSISTANT},
+    settings::settings,
+    timezone::rfc3339,
+    user_tokens::UserToken,
+    users_cameras::{check_if_user_owns_camera, get_users_cameras},
+    CameraServerDbConn,
+};
+
+use super::schema::camera_streams;
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rocket::http::uri::Uri;
+use rocket::http::Status;
+use rocket::response::Stream;
+use rocket::{delete, get, post, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Read;
+
+pub const HLS_PROTOCOL: &str = "hls";
+pub const DASH_PROTOCOL: &str = "dash";
+pub const PROGRESSIVE_MP4_PROTOCOL: &str = "progressive_mp4";
+pub const RTSP_PROTOCOL: &str = "rtsp";
+
+/// Every protocol a stream can be, and which assistants can play it. Google can't play RTSP, Alexa can only play
+/// RTSP and HLS.
+pub const STREAM_PROTOCOLS: &[(&str, &[&str])] = &[
+    (HLS_PROTOCOL, &[GOOGLE_ASSISTANT, ALEXA]),
+    (DASH_PROTOCOL, &[GOOGLE_ASSISTANT]),
+    (PROGRESSIVE_MP4_PROTOCOL, &[GOOGLE_ASSISTANT]),
+    (RTSP_PROTOCOL, &[ALEXA]),
+];
+
+/// Alexa needs a resolution for every stream, but cameras don't report theirs, so every stream claims 1080p.
+pub const ALEXA_RESOLUTION: (u32, u32) = (1920, 1080);
+
+/// Where the camera's live video can be played from, for voice assistants. The camera or something in front of it
+/// serves the stream, this server only tells the assistant where it is.
+#[derive(Queryable, Serialize, JsonSchema)]
+pub struct CameraStream {
+    #[schemars(with = "String")]
+    pub camera_id: uuid::Uuid,
+    pub url: String,
+    /// hls, dash, progressive_mp4 or rtsp.
+    pub protocol: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Sent to PUT /Cameras/<camera_id>/Stream.
+#[derive(Deserialize, JsonSchema)]
+pub struct NewCameraStream {
+    /// https for hls, dash and progressive_mp4, rtsp or rtsps for rtsp. Assistants play it straight from here, so
+    /// it needs to be reachable from the internet.
+    pub url: String,
+    pub protocol: String,
+}
+
+/// Where the assistants can reach this server, set with public_url in [voice_assistants], or in [federation] if
+/// it's only set there. Snapshots aren't linked to without one.
+pub fn public_url() -> Option<String> {
+    match &settings().voice_assistants.public_url {
+        Some(public_url) => Some(public_url.trim().trim_end_matches('/').to_string()),
+        None => federation::public_url(),
+    }
+}
+
+fn plays(protocol: &str, assistant: &str) -> bool {
+    STREAM_PROTOCOLS
+        .iter()
+        .any(|(name, assistants)| *name == protocol && assistants.contains(&assistant))
+}
+
+fn database_error(error: diesel::result::Error) -> ApiError {
+    error!("Failed to get camera streams! The error was {}", error);
+    ApiError {
+        error: "Failed to get camera streams",
+        code: "get_camera_streams_failed",
+        status: Status::InternalServerError,
+        field: None,
+    }
+}
+
+fn stream_not_found() -> ApiError {
+    ApiError {
+        error: "The camera doesn't have a stream",
+        code: "stream_not_found",
+        status: Status::NotFound,
+        field: None,
+    }
+}
+
+/// The user's cameras that have a stream one of the assistant's can play, which is all the assistant sees.
+pub fn linked_cameras(
+    link: &OAuthLink,
+    connection: &PgConnection,
+) -> QueryResult<Vec<(Camera, CameraStream)>> {
+    let cameras = get_users_cameras(link.user_id, connection)?;
+    let mut streams = camera_streams::table
+        .filter(
+            camera_streams::camera_id.eq_any(
+                cameras
+                    .iter()
+                    .map(|camera| camera.camera_id)
+                    .collect::<Vec<_>>(),
+            ),
+        )
+        .load::<CameraStream>(connection)?
+        .into_iter()
+        .filter(|stream| plays(&stream.protocol, &link.assistant))
+        .map(|stream| (stream.camera_id, stream))
+        .collect::<HashMap<_, _>>();
+
+    Ok(cameras
+        .into_iter()
+        .filter_map(|camera| {
+            let stream = streams.remove(&camera.camera_id)?;
+            Some((camera, stream))
+        })
+        .collect())
+}
+
+/// The camera's stream. Only for the camera's owner, since the URL can have the stream's password in it.
+#[openapi]
+#[get("/Cameras/<camera_id>/Stream")]
+pub fn get_stream(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<CameraStream>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    camera_streams::table
+        .find(camera_id)
+        .get_result::<CameraStream>(&*conn)
+        .optional()
+        .map_err(database_error)?
+        .map(Json)
+        .ok_or_else(stream_not_found)
+}
+
+/// Sets where voice assistants can play the camera's live video from. Cameras only show up in an assistant once
+/// they have a stream it can play. Only for the camera's owner.
+#[openapi]
+#[put("/Cameras/<camera_id>/Stream", format = "json", data = "<new_stream>")]
+pub fn set_stream(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_stream: Json<NewCameraStream>,
+) -> Result<Json<CameraStream>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    let new_stream = new_stream.into_inner();
+    let url = new_stream.url.trim();
+    let protocol = new_stream.protocol.trim().to_lowercase();
+
+    let schemes: &[&str] = match protocol.as_str() {
+        RTSP_PROTOCOL => &["rtsp://", "rtsps://"],
+        HLS_PROTOCOL | DASH_PROTOCOL | PROGRESSIVE_MP4_PROTOCOL => &["https://"],
+        _ => {
+            return Err(ApiError {
+                error: "Protocol must be hls, dash, progressive_mp4 or rtsp",
+                code: "invalid_protocol",
+                status: Status::UnprocessableEntity,
+                field: Some("protocol"),
+            })
+        }
+    };
+    if !schemes.iter().any(|scheme| url.starts_with(scheme)) {
+        return Err(ApiError {
+            error: "URL must be https, or rtsp or rtsps for rtsp streams",
+            code: "invalid_stream_url",
+            status: Status::UnprocessableEntity,
+            field: Some("url"),
+        });
+    }
+
+    diesel::insert_into(camera_streams::table)
+        .values((
+            camera_streams::camera_id.eq(camera_id),
+            camera_streams::url.eq(url),
+            camera_streams::protocol.eq(&protocol),
+        ))
+        .on_conflict(camera_streams::camera_id)
+        .do_update()
+        .set((
+            camera_streams::url.eq(url),
+            camera_streams::protocol.eq(&protocol),
+            camera_streams::updated_at.eq(Utc::now()),
+        ))
+        .get_result::<CameraStream>(&*conn)
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Removes the camera's stream, so it stops showing up in voice assistants. Only for the camera's owner.
+#[openapi]
+#[delete("/Cameras/<camera_id>/Stream")]
+pub fn delete_stream(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<(), ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_owns_camera(&conn, &user_token, camera_id)?;
+
+    match diesel::delete(camera_streams::table.find(camera_id))
+        .execute(&*conn)
+        .map_err(database_error)?
+    {
+        0 => Err(stream_not_found()),
+        _ => Ok(()),
+    }
+}
+
+/// Where an assistant can fetch the camera's latest image, with the access token it already has. It's in the query
+/// string since assistants fetch images without sending any headers.
+fn snapshot_url(camera_id: uuid::Uuid, access_token: &str) -> Option<String> {
+    Some(format!(
+        "{}{}/VoiceAssistants/Cameras/{}/Snapshot?access_token={}",
+        public_url()?,
+        API_PREFIX,
+        camera_id,
+        Uri::percent_encode(access_token)
+    ))
+}
+
+/// The camera's latest image, for an assistant to show while the stream starts. access_token is the one the
+/// assistant got from POST /OAuth/Token.
+#[openapi(skip)]
+#[get(
+    "/VoiceAssistants/Cameras/<camera_id>/Snapshot?<access_token>",
+    format = "image/jpeg"
+)]
+pub fn get_snapshot(
+    conn: CameraServerDbConn,
+    camera_id: CameraId,
+    access_token: String,
+) -> Result<Stream<Box<dyn Read + Send>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    let not_found = || ApiError {
+        error: "Camera not found",
+        code: "camera_not_found",
+        status: Status::NotFound,
+        field: None,
+    };
+
+    let link = oauth::authenticate(&access_token, &conn).map_err(|_| ApiError {
+        error: "Invalid access token",
+        code: "invalid_access_token",
+        status: Status::Unauthorized,
+        field: None,
+    })?;
+    if !linked_cameras(&link, &conn)
+        .map_err(database_error)?
+        .iter()
+        .any(|(camera, _)| camera.camera_id == camera_id)
+    {
+        return Err(not_found());
+    }
+
+    open_image(
+        &camera_id,
+        latest_image_id(&camera_id).ok_or_else(not_found)?,
+    )
+}
+
+/// A request from Google to the smart home fulfillment URL.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleRequest {
+    pub request_id: String,
+    pub inputs: Vec<GoogleInput>,
+}
+
+#[derive(Deserialize)]
+pub struct GoogleInput {
+    pub intent: String,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+fn google_device_ids(devices: &serde_json::Value) -> Vec<String> {
+    devices
+        .as_array()
+        .map(|devices| {
+            devices
+                .iter()
+                .filter_map(|device| Some(device["id"].as_str()?.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The result of a GetCameraStream command for one of the cameras it was for.
+fn google_stream_command(
+    camera: Option<&(Camera, CameraStream)>,
+    device_id: &str,
+) -> serde_json::Value {
+    match camera {
+        None => json!({ "ids": [device_id], "status": "ERROR", "errorCode": "deviceNotFound" }),
+        Some((camera, _)) if !camera.online => {
+            json!({ "ids": [device_id], "status": "OFFLINE", "errorCode": "deviceOffline" })
+        }
+        Some((_, stream)) => json!({
+            "ids": [device_id],
+            "status": "SUCCESS",
+            "states": {
+                "online": true,
+                "cameraStreamAccessUrl": stream.url,
+                "cameraStreamProtocol": stream.protocol,
+            },
+        }),
+    }
+}
+
+/// The fulfillment URL for a Google smart home Action with account linking through /OAuth/Authorize. Answers SYNC
+/// with the user's cameras, QUERY with whether they're online, and EXECUTE with where to play their streams from.
+/// DISCONNECT unlinks the account.
+#[openapi(skip)]
+#[post(
+    "/VoiceAssistants/Google/Fulfillment",
+    format = "json",
+    data = "<request>"
+)]
+pub fn google_fulfillment(
+    conn: CameraServerDbConn,
+    oauth_token: OAuthToken,
+    request: Json<GoogleRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let link = oauth_token.0;
+    if link.assistant != GOOGLE_ASSISTANT {
+        return Err(ApiError {
+            error: "The access token isn't for Google Assistant",
+            code: "wrong_assistant",
+            status: Status::Unauthorized,
+            field: None,
+        });
+    }
+
+    let request = request.into_inner();
+    let input = request.inputs.into_iter().next().ok_or(ApiError {
+        error: "The request has no inputs",
+        code: "inputs_required",
+        status: Status::BadRequest,
+        field: Some("inputs"),
+    })?;
+
+    if input.intent == "action.devices.DISCONNECT" {
+        info!("User {} unlinked Google Assistant", link.user_id);
+        oauth::unlink(link.link_id, &conn).map_err(database_error)?;
+        return Ok(Json(json!({})));
+    }
+
+    let cameras = linked_cameras(&link, &conn).map_err(database_error)?;
+    let find = |device_id: &str| {
+        cameras
+            .iter()
+            .find(|(camera, _)| camera.camera_id.to_string() == device_id)
+    };
+
+    let payload = match input.intent.as_str() {
+        "action.devices.SYNC" => json!({
+            "agentUserId": link.user_id.to_string(),
+            "devices": cameras.iter().map(|(camera, stream)| json!({
+                "id": camera.camera_id.to_string(),
+                "type": "action.devices.types.CAMERA",
+                "traits": ["action.devices.traits.CameraStream"],
+                "name": { "name": camera.name },
+                "willReportState": false,
+                "attributes": {
+                    "cameraStreamSupportedProtocols": [stream.protocol],
+                    "cameraStreamNeedAuthToken": false,
+                },
+            })).collect::<Vec<_>>(),
+        }),
+        "action.devices.QUERY" => {
+            let devices = google_device_ids(&input.payload["devices"])
+                .into_iter()
+                .map(|device_id| {
+                    let state = match find(&device_id) {
+                        Some((camera, _)) => {
+                            json!({ "online": camera.online, "status": "SUCCESS" })
+                        }
+                        None => json!({ "status": "ERROR", "errorCode": "deviceNotFound" }),
+                    };
+                    (device_id, state)
+                })
+                .collect::<serde_json::Map<_, _>>();
+
+            json!({ "devices": devices })
+        }
+        "action.devices.EXECUTE" => {
+            let mut commands = Vec::new();
+            for command in input.payload["commands"].as_array().into_iter().flatten() {
+                let gets_stream =
+                    command["execution"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .any(|execution| {
+                            execution["command"] == "action.devices.commands.GetCameraStream"
+                        });
+
+                for device_id in google_device_ids(&command["devices"]) {
+                    commands.push(match gets_stream {
+                        true => google_stream_command(find(&device_id), &device_id),
+                        false => json!({
+                            "ids": [device_id],
+                            "status": "ERROR",
+                            "errorCode": "functionNotSupported",
+                        }),
+                    });
+                }
+            }
+
+            json!({ "commands": commands })
+        }
+        _ => {
+            return Err(ApiError {
+                error: "Unknown intent",
+                code: "unknown_intent",
+                status: Status::BadRequest,
+                field: Some("intent"),
+            })
+        }
+    };
+
+    Ok(Json(json!({
+        "requestId": request.request_id,
+        "payload": payload,
+    })))
+}
+
+/// A directive from an Alexa smart home skill, passed on by its Lambda function as it was sent.
+#[derive(Deserialize)]
+pub struct AlexaRequest {
+    pub directive: AlexaDirective,
+}
+
+#[derive(Deserialize)]
+pub struct AlexaDirective {
+    pub header: AlexaHeader,
+    #[serde(default)]
+    pub endpoint: serde_json::Value,
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlexaHeader {
+    pub namespace: String,
+    pub name: String,
+    pub correlation_token: Option<String>,
+}
+
+fn alexa_header(
+    namespace: &str,
+    name: &str,
+    correlation_token: &Option<String>,
+) -> serde_json::Value {
+    let mut header = json!({
+        "namespace": namespace,
+        "name": name,
+        "payloadVersion": "3",
+        "messageId": uuid::Uuid::new_v4().to_string(),
+    });
+    if let Some(correlation_token) = correlation_token {
+        header["correlationToken"] = json!(correlation_token);
+    }
+    header
+}
+
+/// An Alexa.ErrorResponse, which Alexa expects with a 200 rather than an error status.
+fn alexa_error(
+    directive: &AlexaDirective,
+    error_type: &str,
+    message: &str,
+) -> Json<serde_json::Value> {
+    let mut event = json!({
+        "header": alexa_header("Alexa", "ErrorResponse", &directive.header.correlation_token),
+        "payload": { "type": error_type, "message": message },
+    });
+    if !directive.endpoint.is_null() {
+        event["endpoint"] = json!({ "endpointId": directive.endpoint["endpointId"] });
+    }
+    Json(json!({ "event": event }))
+}
+
+fn alexa_connectivity(camera: &Camera) -> serde_json::Value {
+    json!({
+        "namespace": "Alexa.EndpointHealth",
+        "name": "connectivity",
+        "value": { "value": if camera.online { "OK" } else { "UNREACHABLE" } },
+        "timeOfSample": rfc3339(Utc::now()),
+        "uncertaintyInMilliseconds": 0,
+    })
+}
+
+fn alexa_stream_configuration(stream: &CameraStream) -> serde_json::Value {
+    json!({
+        "protocols": [stream.protocol.to_uppercase()],
+        "resolutions": [{ "width": ALEXA_RESOLUTION.0, "height": ALEXA_RESOLUTION.1 }],
+        "authorizationTypes": ["NONE"],
+        "videoCodecs": ["H264"],
+        "audioCodecs": ["AAC"],
+    })
+}
+
+/// Where an Alexa smart home skill's Lambda function sends directives, with account linking through
+/// /OAuth/Authorize. The access token is in the directive rather than a header, as Alexa sends it. Answers
+/// Discover with the user's cameras, InitializeCameraStreams with where to play one from and its latest image, and
+/// ReportState with whether it's online.
+#[openapi(skip)]
+#[post(
+    "/VoiceAssistants/Alexa/Directives",
+    format = "json",
+    data = "<request>"
+)]
+pub fn alexa_directive(
+    conn: CameraServerDbConn,
+    request: Json<AlexaRequest>,
+) -> Json<serde_json::Value> {
+    let directive = request.into_inner().directive;
+    let header = &directive.header;
+
+    let access_token = match header.namespace.as_str() {
+        "Alexa.Discovery" => directive.payload["scope"]["token"].as_str(),
+        "Alexa.Authorization" => directive.payload["grantee"]["token"].as_str(),
+        _ => directive.endpoint["scope"]["token"].as_str(),
+    };
+    let link = match access_token.map(|access_token| oauth::authenticate(access_token, &conn)) {
+        Some(Ok(link)) if link.assistant == ALEXA => link,
+        _ => {
+            return alexa_error(
+                &directive,
+                "INVALID_AUTHORIZATION_CREDENTIAL",
+                "The access token is invalid or has expired",
+            )
+        }
+    };
+
+    let cameras = match linked_cameras(&link, &conn) {
+        Ok(cameras) => cameras,
+        Err(error) => {
+            error!("Failed to get cameras for Alexa! The error was {}", error);
+            return alexa_error(&directive, "INTERNAL_ERROR", "Failed to get cameras");
+        }
+    };
+
+    match (header.namespace.as_str(), header.name.as_str()) {
+        ("Alexa.Discovery", "Discover") => {
+            let endpoints = cameras
+                .iter()
+                .map(|(camera, stream)| {
+                    json!({
+                        "endpointId": camera.camera_id.to_string(),
+                        "manufacturerName": "camera-server",
+                        "friendlyName": camera.name,
+                        "description": "Camera",
+                        "displayCategories": ["CAMERA"],
+                        "capabilities": [
+                            {
+                                "type": "AlexaInterface",
+                                "interface": "Alexa.CameraStreamController",
+                                "version": "3",
+                                "cameraStreamConfigurations": [alexa_stream_configuration(stream)],
+                            },
+                            {
+                                "type": "AlexaInterface",
+                                "interface": "Alexa.EndpointHealth",
+                                "version": "3",
+                                "properties": {
+                                    "supported": [{ "name": "connectivity" }],
+                                    "proactivelyReported": false,
+                                    "retrievable": true,
+                                },
+                            },
+                            { "type": "AlexaInterface", "interface": "Alexa", "version": "3" },
+                        ],
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            Json(json!({
+                "event": {
+                    "header": alexa_header("Alexa.Discovery", "Discover.Response", &None),
+                    "payload": { "endpoints": endpoints },
+                },
+            }))
+        }
+        ("Alexa.Authorization", "AcceptGrant") => Json(json!({
+            "event": {
+                "header": alexa_header("Alexa.Authorization", "AcceptGrant.Response", &None),
+                "payload": {},
+            },
+        })),
+        ("Alexa.CameraStreamController", "InitializeCameraStreams") | ("Alexa", "ReportState") => {
+            let endpoint_id = directive.endpoint["endpointId"]
+                .as_str()
+                .unwrap_or_default();
+            let (camera, stream) = match cameras
+                .iter()
+                .find(|(camera, _)| camera.camera_id.to_string() == endpoint_id)
+            {
+                Some(camera) => camera,
+                None => return alexa_error(&directive, "NO_SUCH_ENDPOINT", "Camera not found"),
+            };
+
+            let context = json!({ "properties": [alexa_connectivity(camera)] });
+            let endpoint = json!({ "endpointId": endpoint_id });
+
+            if header.name == "ReportState" {
+                return Json(json!({
+                    "context": context,
+                    "event": {
+                        "header": alexa_header("Alexa", "StateReport", &header.correlation_token),
+                        "endpoint": endpoint,
+                        "payload": {},
+                    },
+                }));
+            }
+
+            if !camera.online {
+                return alexa_error(&directive, "ENDPOINT_UNREACHABLE", "The camera is offline");
+            }
+
+            let mut payload = json!({
+                "cameraStreams": [{
+                    "uri": stream.url,
+                    "expirationTime": rfc3339(Utc::now() + Duration::seconds(ACCESS_TOKEN_SECONDS)),
+                    "idleTimeoutSeconds": 30,
+                    "protocol": stream.protocol.to_uppercase(),
+                    "resolution": { "width": ALEXA_RESOLUTION.0, "height": ALEXA_RESOLUTION.1 },
+                    "authorizationType": "NONE",
+                    "videoCodec": "H264",
+                    "audioCodec": "AAC",
+                }],
+            });
+            if let Some(image_uri) = access_token
+                .filter(|_| latest_image_id(&camera.camera_id).is_some())
+                .and_then(|access_token| snapshot_url(camera.camera_id, access_token))
+            {
+                payload["imageUri"] = json!(image_uri);
+            }
+
+            Json(json!({
+                "context": context,
+                "event": {
+                    "header": alexa_header(
+                        "Alexa.CameraStreamController",
+                        "Response",
+                        &header.correlation_token,
+                    ),
+                    "endpoint": endpoint,
+                    "payload": payload,
+                },
+            }))
+        }
+        _ => alexa_error(&directive, "INVALID_DIRECTIVE", "Unsupported directive"),
+    }
+}
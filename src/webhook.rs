@@ -0,0 +1,784 @@
+use crate::{
+    api_error::ApiError,
+    event::{severities_at_most, validate_severity, Event, EVENT_TYPES, INFO_SEVERITY},
+    feature_flags,
+    page::parse_updated_since,
+    user_tokens::UserToken,
+    users_cameras::in_owners_tenant,
+    worker, CameraServerDbConn,
+};
+
+use super::schema::{events, users_cameras, webhook_deliveries, webhooks};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::{self};
+use hmac::{Hmac, Mac, NewMac};
+use rocket::http::Status;
+use rocket::{delete, get, post};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How many times a delivery is attempted before giving up on it.
+pub const MAX_DELIVERY_ATTEMPTS: i32 = 6;
+/// How long to wait after the first failed attempt. Doubles after every failed attempt.
+pub const INITIAL_RETRY_DELAY_SECONDS: i64 = 30;
+/// How long a webhook has to respond to a delivery.
+pub const DELIVERY_TIMEOUT_SECONDS: u64 = 10;
+/// How many due deliveries each run of the delivery worker attempts.
+pub const DELIVERIES_PER_RUN: i64 = 100;
+/// How many hosts are delivered to at once. Each host's deliveries are sent one after another, so a slow
+/// receiver only holds up its own.
+pub const MAX_CONCURRENT_HOSTS: usize = 10;
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "webhooks"]
+pub struct Webhook {
+    pub webhook_id: i32,
+    #[schemars(with = "String")]
+    pub user_id: uuid::Uuid,
+    pub url: String,
+    /// Used to sign every payload sent to the webhook. Only shown to the user who owns the webhook.
+    pub secret: String,
+    pub event_types: Vec<String>,
+    /// Only events at least this severe are delivered.
+    pub min_severity: String,
+    pub created_at: DateTime<Utc>,
+    /// Kept up to date by a trigger. Pass it as ?updated_since= to only get what has changed since.
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "webhooks"]
+pub struct InsertableWebhook {
+    pub user_id: uuid::Uuid,
+    pub url: String,
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub min_severity: String,
+}
+
+/// What a user sends when registering a webhook. The secret is generated by the server.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct NewWebhook {
+    pub url: String,
+    pub event_types: Vec<String>,
+    /// Defaults to info, i.e. every event.
+    pub min_severity: Option<String>,
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize, JsonSchema)]
+#[table_name = "webhook_deliveries"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct WebhookDelivery {
+    pub delivery_id: i32,
+    pub webhook_id: i32,
+    pub event_id: i32,
+    pub attempts: i32,
+    pub delivered: bool,
+    /// None once the delivery has succeeded or run out of attempts.
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "webhook_deliveries"]
+pub struct InsertableWebhookDelivery {
+    pub webhook_id: i32,
+    pub event_id: i32,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<Webhook>> {
+    webhooks::table.load::<Webhook>(&*connection)
+}
+
+pub fn get(webhook_id: i32, connection: &PgConnection) -> QueryResult<Webhook> {
+    webhooks::table
+        .find(webhook_id)
+        .get_result::<Webhook>(connection)
+}
+
+pub fn insert(webhook: InsertableWebhook, connection: &PgConnection) -> QueryResult<Webhook> {
+    diesel::insert_into(webhooks::table)
+        .values(webhook)
+        .get_result(connection)
+}
+
+pub fn update(
+    webhook_id: i32,
+    webhook: Webhook,
+    connection: &PgConnection,
+) -> QueryResult<Webhook> {
+    diesel::update(webhooks::table.find(webhook_id))
+        .set(&webhook)
+        .get_result(connection)
+}
+
+pub fn delete(webhook_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(webhooks::table.find(webhook_id)).execute(connection)
+}
+
+pub fn get_users_webhooks(
+    user_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<Webhook>> {
+    webhooks::table
+        .filter(webhooks::user_id.eq(user_id))
+        .order(webhooks::webhook_id)
+        .load::<Webhook>(connection)
+}
+
+/// Returns the given webhook, but only if it belongs to the user. Used so users can't see or delete each other's webhooks.
+pub fn get_users_webhook(
+    user_id: uuid::Uuid,
+    webhook_id: i32,
+    connection: &PgConnection,
+) -> Result<Webhook, ApiError> {
+    webhooks::table
+        .filter(webhooks::webhook_id.eq(webhook_id))
+        .filter(webhooks::user_id.eq(user_id))
+        .first::<Webhook>(connection)
+        .map_err(|error| match error {
+            diesel::result::Error::NotFound => ApiError {
+                error: "Webhook not found",
+                code: "webhook_not_found",
+                status: Status::NotFound,
+                field: None,
+            },
+            _ => {
+                error!(
+                    "Failed to get webhook {}! The error was {}",
+                    webhook_id, error
+                );
+                ApiError {
+                    error: "Failed to get webhook",
+                    code: "get_webhook_failed",
+                    status: Status::InternalServerError,
+                    field: None,
+                }
+            }
+        })
+}
+
+/// Queues a delivery of the event to every webhook that wants it.
+/// A webhook wants an event if its owner has access to the event's camera and it subscribed to the event's type.
+/// Nothing is queued while webhooks are turned off, see feature_flags::WEBHOOKS.
+pub fn queue_deliveries(event: &Event, connection: &PgConnection) -> QueryResult<usize> {
+    if !feature_flags::is_enabled(feature_flags::WEBHOOKS, connection) {
+        return Ok(0);
+    }
+
+    let webhook_ids = webhooks::table
+        .inner_join(users_cameras::table.on(users_cameras::user_id.eq(webhooks::user_id)))
+        .filter(users_cameras::camera_id.eq(event.camera_id))
+        .filter(users_cameras::deleted_at.is_null())
+        .filter(in_owners_tenant())
+        .filter(webhooks::event_types.contains(vec![event.event_type.clone()]))
+        .filter(webhooks::min_severity.eq_any(severities_at_most(&event.severity)))
+        .select(webhooks::webhook_id)
+        .distinct()
+        .load::<i32>(connection)?;
+
+    let deliveries: Vec<InsertableWebhookDelivery> = webhook_ids
+        .into_iter()
+        .map(|webhook_id| InsertableWebhookDelivery {
+            webhook_id,
+            event_id: event.event_id,
+            next_attempt_at: Some(Utc::now()),
+        })
+        .collect();
+
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&deliveries)
+        .execute(connection)
+}
+
+/// Signs a payload with the webhook's secret. Receivers should compute the same HMAC-SHA256 and compare it
+/// with the X-Signature-256 header to check that the payload came from this server.
+pub fn sign_payload(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn is_public_ipv4(address: Ipv4Addr) -> bool {
+    let [first, second, ..] = address.octets();
+
+    !(address.is_unspecified()
+        || address.is_loopback()
+        || address.is_private()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || address.is_multicast()
+        // This network, shared address space (carrier-grade NAT) and reserved
+        || first == 0
+        || (first == 100 && second & 0xC0 == 64)
+        || first >= 240)
+}
+
+fn is_public_ipv6(address: Ipv6Addr) -> bool {
+    // IPv4-mapped and compatible addresses, which also covers :: and ::1
+    if let Some(address) = address.to_ipv4() {
+        return is_public_ipv4(address);
+    }
+
+    let [first, second, ..] = address.segments();
+
+    !(address.is_multicast()
+        // Unique local, link-local and site-local
+        || first & 0xFE00 == 0xFC00
+        || first & 0xFFC0 == 0xFE80
+        || first & 0xFFC0 == 0xFEC0
+        // Documentation
+        || (first == 0x2001 && second == 0x0DB8))
+}
+
+/// Whether the address is out on the internet, rather than the server itself or something on its network, like
+/// the database or a cloud metadata service.
+pub fn is_public_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_ipv4(address),
+        IpAddr::V6(address) => is_public_ipv6(address),
+    }
+}
+
+/// Why a webhook URL can't be sent to.
+pub enum DestinationError {
+    InvalidUrl,
+    Unresolvable(String),
+    NotPublic(IpAddr),
+}
+
+impl fmt::Display for DestinationError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DestinationError::InvalidUrl => {
+                write!(formatter, "The URL isn't a valid http or https URL")
+            }
+            DestinationError::Unresolvable(error) => {
+                write!(formatter, "Failed to resolve the URL's host: {}", error)
+            }
+            DestinationError::NotPublic(address) => write!(
+                formatter,
+                "The URL's host resolves to {}, which isn't a public address",
+                address
+            ),
+        }
+    }
+}
+
+/// Where a webhook's requests go. Every address its host resolved to is public.
+pub struct Destination {
+    /// The URL's host, without the brackets round IPv6 addresses.
+    pub host: String,
+    pub address: SocketAddr,
+}
+
+/// Resolves the URL's host, and checks that none of the addresses it resolves to are private, see
+/// is_public_address(). Checked when a webhook is added and again before every delivery, as what a host resolves
+/// to can change.
+pub fn resolve_destination(url: &str) -> Result<Destination, DestinationError> {
+    let url = reqwest::Url::parse(url).map_err(|_| DestinationError::InvalidUrl)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(DestinationError::InvalidUrl);
+    }
+
+    let host = url
+        .host_str()
+        .ok_or(DestinationError::InvalidUrl)?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .ok_or(DestinationError::InvalidUrl)?;
+
+    let addresses = match host.parse::<IpAddr>() {
+        Ok(address) => vec![SocketAddr::new(address, port)],
+        Err(_) => (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|error| DestinationError::Unresolvable(error.to_string()))?
+            .collect(),
+    };
+
+    if let Some(address) = addresses
+        .iter()
+        .find(|address| !is_public_address(address.ip()))
+    {
+        return Err(DestinationError::NotPublic(address.ip()));
+    }
+
+    match addresses.first() {
+        Some(address) => Ok(Destination {
+            host,
+            address: *address,
+        }),
+        None => Err(DestinationError::Unresolvable(String::from(
+            "it has no addresses",
+        ))),
+    }
+}
+
+/// A client that only connects to the destination's checked address, so the host can't be made to resolve to
+/// somewhere else between the check and the request. Redirects aren't followed, as they could go anywhere.
+pub fn destination_client(destination: &Destination) -> reqwest::Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECONDS))
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&destination.host, destination.address)
+        .build()
+}
+
+/// Sends the event to the webhook. Returns the response's status code if the webhook responded with a 2xx,
+/// returns the status code (if any) and a description of what went wrong otherwise. The client should only
+/// connect to the webhook's checked address, see destination_client().
+pub fn send_event(
+    client: &reqwest::blocking::Client,
+    webhook: &Webhook,
+    event: &Event,
+) -> Result<i32, (Option<i32>, String)> {
+    let payload = serde_json::to_string(event).expect("Failed to serialize event somehow?");
+
+    let response = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Event", event.event_type.as_str())
+        .header("X-Signature-256", sign_payload(&webhook.secret, &payload))
+        .body(payload)
+        .send()
+        .map_err(|error| (None, error.to_string()))?;
+
+    let status_code = response.status().as_u16() as i32;
+
+    if response.status().is_success() {
+        Ok(status_code)
+    } else {
+        Err((
+            Some(status_code),
+            format!("Webhook responded with {}", response.status()),
+        ))
+    }
+}
+
+/// Sends one delivery, checking where the webhook's URL goes first. Clients are kept in `clients` by URL host and
+/// port, so each destination is only resolved once per run.
+fn attempt_delivery(
+    mut delivery: WebhookDelivery,
+    webhook: &Webhook,
+    event: &Event,
+    clients: &mut HashMap<String, Result<reqwest::blocking::Client, String>>,
+) -> WebhookDelivery {
+    let client = clients.entry(host_key(&webhook.url)).or_insert_with(|| {
+        resolve_destination(&webhook.url)
+            .map_err(|error| error.to_string())
+            .and_then(|destination| {
+                destination_client(&destination).map_err(|error| error.to_string())
+            })
+    });
+
+    delivery.attempts += 1;
+
+    let result = match client {
+        Ok(client) => send_event(client, webhook, event),
+        Err(error) => Err((None, error.clone())),
+    };
+
+    match result {
+        Ok(status_code) => {
+            delivery.delivered = true;
+            delivery.next_attempt_at = None;
+            delivery.last_status_code = Some(status_code);
+            delivery.last_error = None;
+        }
+        Err((status_code, error)) => {
+            delivery.last_status_code = status_code;
+            delivery.last_error = Some(error);
+            delivery.next_attempt_at = worker::next_attempt_at(
+                delivery.attempts,
+                MAX_DELIVERY_ATTEMPTS,
+                INITIAL_RETRY_DELAY_SECONDS,
+            );
+        }
+    }
+
+    delivery
+}
+
+/// The URL's host and port, which deliveries are grouped by. URLs that can't be parsed are grouped together, and
+/// fail to resolve.
+fn host_key(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            Some(format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        })
+        .unwrap_or_default()
+}
+
+/// Attempts every delivery that is due, and schedules a retry with exponential backoff for ones that fail.
+/// Up to MAX_CONCURRENT_HOSTS hosts are delivered to at once.
+pub fn deliver_due(connection: &PgConnection) -> QueryResult<()> {
+    let due_deliveries = webhook_deliveries::table
+        .filter(webhook_deliveries::delivered.eq(false))
+        .filter(webhook_deliveries::next_attempt_at.le(Utc::now()))
+        .order(webhook_deliveries::next_attempt_at)
+        .limit(DELIVERIES_PER_RUN)
+        .load::<WebhookDelivery>(connection)?;
+
+    let mut by_host: HashMap<String, Vec<(WebhookDelivery, Webhook, Event)>> = HashMap::new();
+    for delivery in due_deliveries {
+        let webhook = get(delivery.webhook_id, connection)?;
+        let event = events::table
+            .find(delivery.event_id)
+            .get_result::<Event>(connection)?;
+
+        by_host
+            .entry(host_key(&webhook.url))
+            .or_default()
+            .push((delivery, webhook, event));
+    }
+
+    let thread_count = by_host.len().min(MAX_CONCURRENT_HOSTS);
+    let hosts = Arc::new(Mutex::new(
+        by_host.into_iter().map(|(_, due)| due).collect::<Vec<_>>(),
+    ));
+    let (sender, attempted) = mpsc::channel();
+
+    let threads = (0..thread_count)
+        .map(|_| {
+            let hosts = hosts.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                let mut clients = HashMap::new();
+
+                loop {
+                    let due = match hosts.lock().expect("Webhook hosts lock poisoned!").pop() {
+                        Some(due) => due,
+                        None => break,
+                    };
+
+                    for (delivery, webhook, event) in due {
+                        let delivery = attempt_delivery(delivery, &webhook, &event, &mut clients);
+                        // Only fails if the run has stopped over a database error
+                        if sender.send(delivery).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    // Saved as they finish, so a host that times out doesn't hold up recording the others
+    for delivery in attempted {
+        diesel::update(webhook_deliveries::table.find(delivery.delivery_id))
+            .set(&delivery)
+            .execute(connection)?;
+    }
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+
+    Ok(())
+}
+
+/// Starts the thread that sends queued webhook deliveries.
+pub fn spawn_delivery_worker(database_url: String) {
+    worker::spawn_worker(
+        "Webhook delivery",
+        Duration::from_secs(5),
+        database_url,
+        move |connection| {
+            // Deliveries queued before webhooks were turned off wait until they're turned back on
+            if !feature_flags::is_enabled(feature_flags::WEBHOOKS, connection) {
+                return;
+            }
+
+            if let Err(error) = deliver_due(connection) {
+                error!("Failed to deliver webhooks! The error was {}", error);
+            }
+        },
+    );
+}
+
+pub fn validate_new_webhook(new_webhook: &NewWebhook) -> Result<(), ApiError> {
+    if !new_webhook.url.starts_with("http://") && !new_webhook.url.starts_with("https://") {
+        return Err(ApiError {
+            error: "Webhook URL must be http or https",
+            code: "invalid_webhook_url",
+            status: Status::UnprocessableEntity,
+            field: Some("url"),
+        });
+    }
+
+    if let Err(error) = resolve_destination(&new_webhook.url) {
+        return Err(ApiError {
+            error: match error {
+                DestinationError::InvalidUrl => "Webhook URL isn't valid",
+                DestinationError::Unresolvable(_) => "Webhook URL's host couldn't be resolved",
+                DestinationError::NotPublic(_) => {
+                    "Webhook URL must not go to a loopback, link-local or private address"
+                }
+            },
+            code: match error {
+                DestinationError::InvalidUrl => "invalid_webhook_url",
+                DestinationError::Unresolvable(_) => "unresolvable_webhook_url",
+                DestinationError::NotPublic(_) => "private_webhook_url",
+            },
+            status: Status::UnprocessableEntity,
+            field: Some("url"),
+        });
+    }
+
+    if new_webhook.event_types.len() == 0 {
+        return Err(ApiError {
+            error: "Webhook must subscribe to at least one event type",
+            code: "webhook_event_types_required",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    if new_webhook
+        .event_types
+        .iter()
+        .any(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        return Err(ApiError {
+            error: "Unknown event type",
+            code: "unknown_event_type",
+            status: Status::UnprocessableEntity,
+            field: Some("event_types"),
+        });
+    }
+
+    if let Some(min_severity) = &new_webhook.min_severity {
+        validate_severity(min_severity)?;
+    }
+
+    Ok(())
+}
+
+/// Registers a new webhook for the user. Returns the webhook, including the secret used to sign payloads.
+#[openapi]
+#[post("/Webhooks", format = "json", data = "<new_webhook>")]
+pub fn add_webhook(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    new_webhook: Json<NewWebhook>,
+) -> Result<Json<Webhook>, ApiError> {
+    let new_webhook = new_webhook.into_inner();
+
+    validate_new_webhook(&new_webhook)?;
+
+    insert(
+        InsertableWebhook {
+            user_id: user_token.user_id,
+            url: new_webhook.url,
+            secret: uuid::Uuid::new_v4().simple().to_string(),
+            event_types: new_webhook.event_types,
+            min_severity: new_webhook
+                .min_severity
+                .unwrap_or_else(|| INFO_SEVERITY.to_string()),
+        },
+        &conn,
+    )
+    .map(|webhook| Json(webhook))
+    .map_err(|error| {
+        error!(
+            "Failed to add webhook for user {}! The error was {}",
+            user_token.user_id, error
+        );
+        ApiError {
+            error: "Failed to add webhook",
+            code: "add_webhook_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+#[openapi]
+#[get("/Webhooks?<updated_since>")]
+pub fn list_webhooks(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    updated_since: Option<String>,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    let updated_since = parse_updated_since(&updated_since)?;
+
+    get_users_webhooks(user_token.user_id, &conn)
+        .map(|mut webhooks| {
+            webhooks
+                .retain(|webhook| updated_since.map_or(true, |since| webhook.updated_at > since));
+            Json(webhooks)
+        })
+        .map_err(|error| {
+            error!(
+                "Failed to get webhooks for user {}! The error was {}",
+                user_token.user_id, error
+            );
+            ApiError {
+                error: "Failed to get webhooks",
+                code: "get_webhooks_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[openapi]
+#[delete("/Webhooks/<webhook_id>")]
+pub fn delete_webhook(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    webhook_id: i32,
+) -> Result<(), ApiError> {
+    get_users_webhook(user_token.user_id, webhook_id, &conn)?;
+
+    delete(webhook_id, &conn).map(|_| ()).map_err(|error| {
+        error!(
+            "Failed to delete webhook {}! The error was {}",
+            webhook_id, error
+        );
+        ApiError {
+            error: "Failed to delete webhook",
+            code: "delete_webhook_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })
+}
+
+/// Returns the webhook's 100 most recent deliveries, newest first, for debugging webhook receivers.
+#[openapi]
+#[get("/Webhooks/<webhook_id>/Deliveries")]
+pub fn list_deliveries(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    webhook_id: i32,
+) -> Result<Json<Vec<WebhookDelivery>>, ApiError> {
+    get_users_webhook(user_token.user_id, webhook_id, &conn)?;
+
+    webhook_deliveries::table
+        .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+        .order(webhook_deliveries::delivery_id.desc())
+        .limit(100)
+        .load::<WebhookDelivery>(&*conn)
+        .map(|deliveries| Json(deliveries))
+        .map_err(|error| {
+            error!(
+                "Failed to get deliveries for webhook {}! The error was {}",
+                webhook_id, error
+            );
+            ApiError {
+                error: "Failed to get webhook deliveries",
+                code: "get_webhook_deliveries_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_public(address: &str) -> bool {
+        is_public_address(address.parse().expect("Failed to parse the address!"))
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for address in &[
+            "1.1.1.1",
+            "93.184.216.34",
+            "100.128.0.1",
+            "2606:4700:4700::1111",
+        ] {
+            assert!(is_public(address), "{}", address);
+        }
+    }
+
+    #[test]
+    fn refuses_private_addresses() {
+        for address in &[
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+        ] {
+            assert!(!is_public(address), "{}", address);
+        }
+    }
+
+    #[test]
+    fn refuses_urls_with_private_hosts() {
+        for url in &[
+            "http://127.0.0.1/hook",
+            "http://[::1]:8080/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "http://localhost/hook",
+        ] {
+            assert!(
+                matches!(
+                    resolve_destination(url),
+                    Err(DestinationError::NotPublic(_))
+                ),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_urls_that_arent_http() {
+        for url in &["ftp://example.com/hook", "not a url", "http://"] {
+            assert!(
+                matches!(resolve_destination(url), Err(DestinationError::InvalidUrl)),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn groups_deliveries_by_host_and_port() {
+        assert_eq!(host_key("https://example.com/a"), "example.com:443");
+        assert_eq!(host_key("http://example.com:8080/b"), "example.com:8080");
+        assert_eq!(host_key("not a url"), "");
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{cluster, database, shutdown};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use once_cell::sync::Lazy;
+use rocket::Config;
+use rocket_contrib::databases::database_config;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// A worker counts as stuck if it hasn't finished a run in this long on top of twice its interval.
+pub const HEARTBEAT_GRACE_SECONDS: i64 = 60;
+
+struct Heartbeat {
+    interval: Duration,
+    started_at: DateTime<Utc>,
+    last_run_at: Option<DateTime<Utc>>,
+}
+
+/// Worker name to when it last finished a run, so health checks can tell if it's stuck or has died.
+static HEARTBEATS: Lazy<Mutex<HashMap<&'static str, Heartbeat>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize)]
+pub struct WorkerStatus {
+    pub name: &'static str,
+    pub alive: bool,
+    pub started_at: DateTime<Utc>,
+    /// None if the worker hasn't finished a run yet.
+    pub last_run_at: Option<DateTime<Utc>>,
+}
+
+/// Records that a worker has started. Until it finishes its first run it counts as alive from when it started.
+pub fn register_worker(name: &'static str, interval: Duration) {
+    HEARTBEATS.lock().expect("Heartbeat lock poisoned!").insert(
+        name,
+        Heartbeat {
+            interval,
+            started_at: Utc::now(),
+            last_run_at: None,
+        },
+    );
+}
+
+/// Records that a worker has finished a run.
+pub fn record_heartbeat(name: &'static str) {
+    if let Some(heartbeat) = HEARTBEATS
+        .lock()
+        .expect("Heartbeat lock poisoned!")
+        .get_mut(name)
+    {
+        heartbeat.last_run_at = Some(Utc::now());
+    }
+}
+
+/// Returns every worker that has been started, sorted by name.
+pub fn worker_statuses() -> Vec<WorkerStatus> {
+    let now = Utc::now();
+    let heartbeats = HEARTBEATS.lock().expect("Heartbeat lock poisoned!");
+
+    let mut statuses: Vec<WorkerStatus> = heartbeats
+        .iter()
+        .map(|(name, heartbeat)| {
+            let deadline = ChronoDuration::seconds(
+                heartbeat.interval.as_secs() as i64 * 2 + HEARTBEAT_GRACE_SECONDS,
+            );
+
+            WorkerStatus {
+                name,
+                alive: now - heartbeat.last_run_at.unwrap_or(heartbeat.started_at) <= deadline,
+                started_at: heartbeat.started_at,
+                last_run_at: heartbeat.last_run_at,
+            }
+        })
+        .collect();
+
+    statuses.sort_by_key(|status| status.name);
+    statuses
+}
+
+/// Returns the URL of the database used for CameraServerDbConn, so background threads can connect to the same database.
+pub fn database_url(config: &Config) -> String {
+    database_config(database::DATABASE_NAME, config)
+        .expect("camera-server-db is not configured!")
+        .url
+        .to_string()
+}
+
+/// Starts a thread that calls `work` every `interval` with its own database connection.
+/// A new connection is made for every run so that a database restart doesn't kill the worker.
+/// Once the server is shutting down, a run that has started is finished but no new one is started.
+/// Each run takes an advisory lock named after the worker, so with more than one instance only one of them
+/// runs it at a time. The others skip that run.
+pub fn spawn_worker<F>(name: &'static str, interval: Duration, database_url: String, work: F)
+where
+    F: Fn(&PgConnection) + Send + 'static,
+{
+    spawn(name, interval, database_url, true, work);
+}
+
+/// spawn_worker() without the lock, for work that's safe to run on every instance at once,
+/// e.g. because it claims rows with SKIP LOCKED.
+pub fn spawn_concurrent_worker<F>(
+    name: &'static str,
+    interval: Duration,
+    database_url: String,
+    work: F,
+) where
+    F: Fn(&PgConnection) + Send + 'static,
+{
+    spawn(name, interval, database_url, false, work);
+}
+
+fn spawn<F>(name: &'static str, interval: Duration, database_url: String, exclusive: bool, work: F)
+where
+    F: Fn(&PgConnection) + Send + 'static,
+{
+    register_worker(name, interval);
+
+    thread::spawn(move || loop {
+        {
+            let _span = info_span!("worker", name).entered();
+
+            // Stops once shutting down, and shutdown waits for a run that's already started
+            let _running = match shutdown::start_work() {
+                Some(running) => running,
+                None => return,
+            };
+
+            match PgConnection::establish(&database_url) {
+                Ok(connection) => {
+                    let locked = if exclusive {
+                        cluster::try_lock(name, &connection)
+                    } else {
+                        Ok(true)
+                    };
+
+                    match locked {
+                        Ok(true) => {
+                            work(&connection);
+
+                            if exclusive {
+                                if let Err(error) = cluster::unlock(name, &connection) {
+                                    error!(
+                                        "{} worker failed to release its lock! The error was {}",
+                                        name, error
+                                    );
+                                }
+                            }
+                        }
+                        // Another instance is doing this run, which is as good as this one doing it
+                        Ok(false) => debug!("{} worker is running on another instance", name),
+                        Err(error) => error!(
+                            "{} worker failed to take its lock! The error was {}",
+                            name, error
+                        ),
+                    }
+
+                    record_heartbeat(name);
+                }
+                Err(error) => error!(
+                    "{} worker failed to connect to the database! The error was {}",
+                    name, error
+                ),
+            }
+        }
+
+        thread::sleep(interval);
+    });
+}
+
+/// Works out when something that failed should next be attempted, doubling the delay after every attempt.
+/// Returns None once `max_attempts` attempts have been made.
+pub fn next_attempt_at(
+    attempts: i32,
+    max_attempts: i32,
+    initial_delay_seconds: i64,
+) -> Option<DateTime<Utc>> {
+    if attempts >= max_attempts {
+        None
+    } else {
+        Some(Utc::now() + ChronoDuration::seconds(initial_delay_seconds << (attempts - 1)))
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{
+    api_error::ApiError, camera::CameraId, camera_commands, user_tokens::UserToken,
+    users_cameras::check_if_user_has_access_to_camera, CameraServerDbConn,
+};
+
+use super::schema::zones;
+use diesel::prelude::*;
+use diesel::{self};
+use rocket::http::Status;
+use rocket::{get, put};
+use rocket_contrib::json::Json;
+use rocket_okapi::openapi;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Events inside an include zone are kept. If a camera has no include zones, the whole frame is included.
+pub const INCLUDE_ZONE: &str = "include";
+/// Events inside an exclude zone are dropped, even if they are also inside an include zone.
+pub const EXCLUDE_ZONE: &str = "exclude";
+/// Privacy zones are blacked out on every image the camera stores, and don't affect which events are kept.
+pub const PRIVACY_ZONE: &str = "privacy";
+
+/// A point in a camera's frame. Coordinates are normalised, so (0, 0) is the top left and (1, 1) is the bottom right.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// A rectangle in a camera's frame, using the same normalised coordinates as Point.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    pub fn centre(&self) -> Point {
+        Point {
+            x: self.x + self.width / 2.0,
+            y: self.y + self.height / 2.0,
+        }
+    }
+}
+
+/// A zone as sent to and from clients and cameras.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Zone {
+    pub kind: String,
+    pub points: Vec<Point>,
+}
+
+impl Zone {
+    pub fn from_camera_zone(camera_zone: CameraZone) -> Result<Zone, serde_json::Error> {
+        Ok(Zone {
+            kind: camera_zone.kind,
+            points: serde_json::from_value(camera_zone.points)?,
+        })
+    }
+
+    /// Checks if a point is inside the zone's polygon using ray casting.
+    pub fn contains(&self, point: &Point) -> bool {
+        let mut inside = false;
+        let mut previous = match self.points.last() {
+            Some(previous) => previous,
+            None => return false,
+        };
+
+        for current in &self.points {
+            if (current.y > point.y) != (previous.y > point.y)
+                && point.x
+                    < (previous.x - current.x) * (point.y - current.y) / (previous.y - current.y)
+                        + current.x
+            {
+                inside = !inside;
+            }
+
+            previous = current;
+        }
+
+        inside
+    }
+}
+
+#[derive(Queryable, AsChangeset, Deserialize, Serialize)]
+#[table_name = "zones"]
+pub struct CameraZone {
+    pub zone_id: i32,
+    pub camera_id: uuid::Uuid,
+    pub kind: String,
+    pub points: serde_json::Value,
+}
+
+#[derive(Insertable, Deserialize, Serialize)]
+#[table_name = "zones"]
+pub struct InsertableCameraZone {
+    pub camera_id: uuid::Uuid,
+    pub kind: String,
+    pub points: serde_json::Value,
+}
+
+pub fn all(connection: &PgConnection) -> QueryResult<Vec<CameraZone>> {
+    zones::table.load::<CameraZone>(&*connection)
+}
+
+pub fn get(zone_id: i32, connection: &PgConnection) -> QueryResult<CameraZone> {
+    zones::table
+        .find(zone_id)
+        .get_result::<CameraZone>(connection)
+}
+
+pub fn insert(zone: InsertableCameraZone, connection: &PgConnection) -> QueryResult<CameraZone> {
+    diesel::insert_into(zones::table)
+        .values(zone)
+        .get_result(connection)
+}
+
+pub fn update(
+    zone_id: i32,
+    zone: CameraZone,
+    connection: &PgConnection,
+) -> QueryResult<CameraZone> {
+    diesel::update(zones::table.find(zone_id))
+        .set(&zone)
+        .get_result(connection)
+}
+
+pub fn delete(zone_id: i32, connection: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(zones::table.find(zone_id)).execute(connection)
+}
+
+pub fn get_cameras_zones(
+    camera_id: uuid::Uuid,
+    connection: &PgConnection,
+) -> QueryResult<Vec<CameraZone>> {
+    zones::table
+        .filter(zones::camera_id.eq(camera_id))
+        .order(zones::zone_id)
+        .load::<CameraZone>(connection)
+}
+
+/// Returns a camera's zones, converted from their database representation.
+/// Returns an ApiError if the zones can't be read.
+pub fn load_zones(camera_id: uuid::Uuid, connection: &PgConnection) -> Result<Vec<Zone>, ApiError> {
+    let camera_zones = get_cameras_zones(camera_id, connection).map_err(|error| {
+        error!(
+            "Failed to get zones for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to get zones",
+            code: "get_zones_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    camera_zones
+        .into_iter()
+        .map(Zone::from_camera_zone)
+        .collect::<Result<Vec<Zone>, serde_json::Error>>()
+        .map_err(|error| {
+            error!(
+                "Failed to deserialize zones for camera {}! The error was {}",
+                camera_id, error
+            );
+            ApiError {
+                error: "Failed to read zones",
+                code: "read_zones_failed",
+                status: Status::InternalServerError,
+                field: None,
+            }
+        })
+}
+
+/// Replaces all of a camera's zones with the given zones.
+pub fn replace_cameras_zones(
+    camera_id: uuid::Uuid,
+    new_zones: &Vec<Zone>,
+    connection: &PgConnection,
+) -> QueryResult<Vec<CameraZone>> {
+    connection.transaction(|| {
+        diesel::delete(zones::table.filter(zones::camera_id.eq(camera_id))).execute(connection)?;
+
+        new_zones
+            .iter()
+            .map(|zone| {
+                insert(
+                    InsertableCameraZone {
+                        camera_id,
+                        kind: zone.kind.clone(),
+                        points: serde_json::to_value(&zone.points)
+                            .expect("Failed to serialize zone points somehow?"),
+                    },
+                    connection,
+                )
+            })
+            .collect()
+    })
+}
+
+/// Decides whether an event with the given bounding box should be kept, based on the camera's zones.
+/// A bounding box counts as being inside a zone if its centre is.
+pub fn is_in_zones(bounding_box: &BoundingBox, zones: &Vec<Zone>) -> bool {
+    let centre = bounding_box.centre();
+
+    if zones
+        .iter()
+        .any(|zone| zone.kind == EXCLUDE_ZONE && zone.contains(&centre))
+    {
+        return false;
+    }
+
+    let mut include_zones = zones
+        .iter()
+        .filter(|zone| zone.kind == INCLUDE_ZONE)
+        .peekable();
+
+    include_zones.peek().is_none() || include_zones.any(|zone| zone.contains(&centre))
+}
+
+/// Checks that every zone has a known kind and is a valid polygon inside the frame.
+pub fn validate_zones(zones: &Vec<Zone>) -> Result<(), ApiError> {
+    for zone in zones {
+        if zone.kind != INCLUDE_ZONE && zone.kind != EXCLUDE_ZONE && zone.kind != PRIVACY_ZONE {
+            return Err(ApiError {
+                error: "Zone kind must be include, exclude or privacy",
+                code: "invalid_zone_kind",
+                status: Status::UnprocessableEntity,
+                field: Some("kind"),
+            });
+        }
+
+        if zone.points.len() < 3 {
+            return Err(ApiError {
+                error: "Zones must have at least 3 points",
+                code: "zone_too_small",
+                status: Status::UnprocessableEntity,
+                field: Some("points"),
+            });
+        }
+
+        if zone
+            .points
+            .iter()
+            .any(|point| !(0.0..=1.0).contains(&point.x) || !(0.0..=1.0).contains(&point.y))
+        {
+            return Err(ApiError {
+                error: "Zone points must be between 0 and 1",
+                code: "invalid_zone_points",
+                status: Status::UnprocessableEntity,
+                field: Some("points"),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[openapi]
+#[get("/Cameras/<camera_id>/Zones")]
+pub fn get_zones(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+) -> Result<Json<Vec<Zone>>, ApiError> {
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    load_zones(camera_id, &conn).map(|zones| Json(zones))
+}
+
+/// Replaces a camera's motion zones, and tells the camera to fetch its config again so it picks them up.
+#[openapi]
+#[put("/Cameras/<camera_id>/Zones", data = "<new_zones>", format = "json")]
+pub fn update_zones(
+    conn: CameraServerDbConn,
+    user_token: UserToken,
+    camera_id: CameraId,
+    new_zones: Json<Vec<Zone>>,
+) -> Result<Json<Vec<Zone>>, ApiError> {
+    let new_zones = new_zones.into_inner();
+    let camera_id = camera_id.into_inner();
+    check_if_user_has_access_to_camera(&conn, &user_token, camera_id)?;
+
+    validate_zones(&new_zones)?;
+
+    replace_cameras_zones(camera_id, &new_zones, &conn).map_err(|error| {
+        error!(
+            "Failed to update zones for camera {}! The error was {}",
+            camera_id, error
+        );
+        ApiError {
+            error: "Failed to update zones",
+            code: "update_zones_failed",
+            status: Status::InternalServerError,
+            field: None,
+        }
+    })?;
+
+    camera_commands::notify_config_updated(camera_id, &conn);
+
+    Ok(Json(new_zones))
+}
@@ -0,0 +1,138 @@
+//! Checks the checks every user and camera token goes through: suspension, tenants and impersonation. Needs a
+//! database, see test_support, and the feature: cargo test --features test-support
+
+#![cfg(feature = "test-support")]
+
+use camera_server::test_support::TestServer;
+use rocket::http::{ContentType, Header, Status};
+
+#[test]
+fn turns_suspended_users_away() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    let response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(user.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    server.suspend(&user);
+
+    let mut response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(user.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body = response.body_string().expect("The response had no body!");
+    assert!(body.contains("account_suspended"));
+}
+
+#[test]
+fn turns_suspended_owners_cameras_away() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let camera = server.add_camera(&owner, "Front door");
+
+    server.suspend(&owner);
+
+    let mut response = server
+        .client
+        .get(server.path("/Device/Config"))
+        .header(camera.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body = response.body_string().expect("The response had no body!");
+    assert!(body.contains("account_suspended"));
+}
+
+#[test]
+fn only_takes_tokens_in_their_own_tenant() {
+    let server = TestServer::new();
+    let slug = format!("tenant-{}", uuid::Uuid::new_v4().simple());
+    let tenant_id = server.add_tenant(&slug);
+    let tenant_user = server.add_user_to("tenant-user", tenant_id);
+    let default_user = server.add_user("default-user");
+
+    let response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(tenant_user.header())
+        .header(Header::new("tenant", slug.clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // Without the header, the request is for the default tenant
+    let response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(tenant_user.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(default_user.header())
+        .header(Header::new("tenant", slug))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn refuses_tenants_that_dont_exist() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    let response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(user.header())
+        .header(Header::new(
+            "tenant",
+            format!("missing-{}", uuid::Uuid::new_v4().simple()),
+        ))
+        .dispatch();
+
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn only_lets_impersonation_tokens_read() {
+    let server = TestServer::new();
+    let admin = server.add_user("admin");
+    let user = server.add_user("user");
+    let camera = server.add_camera(&user, "Front door");
+    let impersonated = server.impersonate(&user, &admin);
+
+    let mut response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(impersonated.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().expect("The response had no body!");
+    assert!(body.contains(&camera.camera_id.to_string()));
+
+    let response = server
+        .client
+        .post(server.path("/Cameras"))
+        .header(impersonated.header())
+        .header(ContentType::JSON)
+        .body(r#"{"name": "Back door"}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let path = server.path(&format!("/Cameras/{}", camera.camera_id));
+    let response = server
+        .client
+        .delete(&path)
+        .header(impersonated.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = server.client.get(&path).header(user.header()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
@@ -0,0 +1,74 @@
+//! Checks that retrying a POST with the same Idempotency-Key replays the first response instead of running it
+//! again. Needs a database, see test_support, and the feature: cargo test --features test-support
+
+#![cfg(feature = "test-support")]
+
+use camera_server::test_support::{TestServer, TestUser};
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::LocalResponse;
+
+fn add_camera<'c>(
+    server: &'c TestServer,
+    user: &TestUser,
+    idempotency_key: &str,
+    name: &str,
+) -> LocalResponse<'c> {
+    server
+        .client
+        .post(server.path("/Cameras"))
+        .header(user.header())
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", idempotency_key.to_string()))
+        .body(format!(r#"{{"name": "{}"}}"#, name))
+        .dispatch()
+}
+
+#[test]
+fn replays_the_first_response() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    let mut first = add_camera(&server, &user, "add-front-door", "Front door");
+    assert_eq!(first.status(), Status::Ok);
+    assert_eq!(first.headers().get_one("Idempotent-Replayed"), None);
+    let first_body = first.body_string().expect("The response had no body!");
+
+    let mut retry = add_camera(&server, &user, "add-front-door", "Front door");
+    assert_eq!(retry.status(), Status::Ok);
+    assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+    assert_eq!(retry.body_string(), Some(first_body));
+
+    let mut response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(user.header())
+        .dispatch();
+    let body = response.body_string().expect("The response had no body!");
+    assert_eq!(body.matches("Front door").count(), 1);
+}
+
+#[test]
+fn refuses_retries_with_a_different_body() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    let response = add_camera(&server, &user, "add-camera", "Front door");
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = add_camera(&server, &user, "add-camera", "Back door");
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[test]
+fn keeps_each_users_keys_apart() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+    let other = server.add_user("other");
+
+    let response = add_camera(&server, &user, "add-camera", "Front door");
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = add_camera(&server, &other, "add-camera", "Front door");
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Idempotent-Replayed"), None);
+}
@@ -0,0 +1,103 @@
+//! Checks that footage under a hold isn't purged, and that holds are only placed by the camera's owner. Needs a
+//! database, see test_support, and the feature: cargo test --features test-support
+
+#![cfg(feature = "test-support")]
+
+use camera_server::soft_delete;
+use camera_server::test_support::{TestCamera, TestServer, TestUser};
+use rocket::http::{ContentType, Status};
+
+const RANGE_HOLD: &str = r#"{
+    "starts_at": "2021-01-01T00:00:00Z",
+    "ends_at": "2021-02-01T00:00:00Z",
+    "reason": "Court order"
+}"#;
+
+fn place_hold(server: &TestServer, user: &TestUser, camera: &TestCamera, hold: &str) -> Status {
+    server
+        .client
+        .post(server.path(&format!("/Cameras/{}/Holds", camera.camera_id)))
+        .header(user.header())
+        .header(ContentType::JSON)
+        .body(hold)
+        .dispatch()
+        .status()
+}
+
+fn delete_camera(server: &TestServer, user: &TestUser, camera: &TestCamera) {
+    let response = server
+        .client
+        .delete(server.path(&format!("/Cameras/{}", camera.camera_id)))
+        .header(user.header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn doesnt_purge_held_cameras() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let held = server.add_camera(&owner, "Front door");
+    let not_held = server.add_camera(&owner, "Back door");
+
+    assert_eq!(place_hold(&server, &owner, &held, RANGE_HOLD), Status::Ok);
+    delete_camera(&server, &owner, &held);
+    delete_camera(&server, &owner, &not_held);
+
+    // A negative retention purges everything deleted up to a day from now
+    let (purged_cameras, _) = soft_delete::purge_deleted(-1, &server.connection())
+        .expect("Failed to purge deleted cameras!");
+    assert_eq!(purged_cameras, 1);
+
+    let (purged_cameras, _) = soft_delete::purge_deleted(-1, &server.connection())
+        .expect("Failed to purge deleted cameras!");
+    assert_eq!(purged_cameras, 0);
+}
+
+#[test]
+fn only_lets_owners_place_holds() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let other = server.add_user("other");
+    let camera = server.add_camera(&owner, "Front door");
+    server.share(&camera, &other);
+
+    assert_ne!(place_hold(&server, &other, &camera, RANGE_HOLD), Status::Ok);
+    assert_eq!(place_hold(&server, &owner, &camera, RANGE_HOLD), Status::Ok);
+
+    let mut response = server
+        .client
+        .get(server.path("/Holds"))
+        .header(other.header())
+        .dispatch();
+    assert_eq!(response.body_string(), Some(String::from("[]")));
+}
+
+#[test]
+fn refuses_holds_on_nothing() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let camera = server.add_camera(&owner, "Front door");
+
+    let backwards = r#"{
+        "starts_at": "2021-02-01T00:00:00Z",
+        "ends_at": "2021-01-01T00:00:00Z",
+        "reason": "Court order"
+    }"#;
+    assert_eq!(
+        place_hold(&server, &owner, &camera, backwards),
+        Status::UnprocessableEntity
+    );
+
+    let missing_image = r#"{"media_type": "image", "media_id": 1, "reason": "Court order"}"#;
+    assert_eq!(
+        place_hold(&server, &owner, &camera, missing_image),
+        Status::NotFound
+    );
+
+    let no_reason = r#"{"media_type": "image", "media_id": 1, "reason": " "}"#;
+    assert_eq!(
+        place_hold(&server, &owner, &camera, no_reason),
+        Status::UnprocessableEntity
+    );
+}
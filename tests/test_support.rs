@@ -0,0 +1,49 @@
+//! Checks that test_support can start the server against a schema of its own. Needs a database, see
+//! test_support, and the feature: cargo test --features test-support
+
+#![cfg(feature = "test-support")]
+
+use camera_server::test_support::TestServer;
+use rocket::http::Status;
+
+#[test]
+fn lists_the_owners_camera() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let camera = server.add_camera(&owner, "Front door");
+
+    let mut response = server
+        .client
+        .get(server.path("/Cameras"))
+        .header(owner.header())
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().expect("The response had no body!");
+    assert!(body.contains(&camera.camera_id.to_string()));
+}
+
+#[test]
+fn only_shows_cameras_to_who_they_are_shared_with() {
+    let server = TestServer::new();
+    let owner = server.add_user("owner");
+    let other = server.add_user("other");
+    let camera = server.add_camera(&owner, "Front door");
+
+    let path = server.path(&format!("/Cameras/{}", camera.camera_id));
+    let response = server.client.get(&path).header(other.header()).dispatch();
+    assert_ne!(response.status(), Status::Ok);
+
+    server.share(&camera, &other);
+    let response = server.client.get(&path).header(other.header()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn needs_a_user_token() {
+    let server = TestServer::new();
+
+    let response = server.client.get(server.path("/Cameras")).dispatch();
+
+    assert_eq!(response.status(), Status::Unauthorized);
+}
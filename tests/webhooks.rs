@@ -0,0 +1,54 @@
+//! Checks that webhooks can't be pointed at the server's own network. Needs a database, see test_support, and the
+//! feature: cargo test --features test-support
+
+#![cfg(feature = "test-support")]
+
+use camera_server::test_support::{TestServer, TestUser};
+use rocket::http::{ContentType, Status};
+
+fn add_webhook(server: &TestServer, user: &TestUser, url: &str) -> Status {
+    server
+        .client
+        .post(server.path("/Webhooks"))
+        .header(user.header())
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{"url": "{}", "event_types": ["motion"]}}"#,
+            url
+        ))
+        .dispatch()
+        .status()
+}
+
+#[test]
+fn refuses_private_addresses() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    for url in &[
+        "http://127.0.0.1/hook",
+        "http://localhost:8000/hook",
+        "http://10.0.0.1/hook",
+        "http://192.168.1.1/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+    ] {
+        assert_eq!(
+            add_webhook(&server, &user, url),
+            Status::UnprocessableEntity,
+            "{} was allowed",
+            url
+        );
+    }
+}
+
+#[test]
+fn refuses_other_schemes() {
+    let server = TestServer::new();
+    let user = server.add_user("user");
+
+    assert_eq!(
+        add_webhook(&server, &user, "file:///etc/passwd"),
+        Status::UnprocessableEntity
+    );
+}